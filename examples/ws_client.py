@@ -0,0 +1,166 @@
+#!/usr/bin/env python3
+"""Tunnels a TCP connection through a thin_proxy websocket listener.
+
+    ws_client.py ws://proxy:8080 db.internal:5432
+    ws_client.py wss://proxy:8443 host:22 --listen 127.0.0.1:2222
+    ssh -o ProxyCommand='ws_client.py ws://proxy:8080 %h:%p' host
+
+Without --listen it relays stdin and stdout, the way ssh's ProxyCommand
+wants; with it each connection accepted there gets a tunnel of its own.
+The destination goes in the X-Target header. Python 3 standard library
+only.
+"""
+
+import argparse
+import base64
+import hashlib
+import os
+import socket
+import ssl
+import struct
+import sys
+import threading
+import urllib.parse
+
+GUID = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11"
+BINARY, CLOSE, PING, PONG = 0x2, 0x8, 0x9, 0xA
+
+
+def frame(opcode, payload):
+    """A client frame, masked as RFC 6455 has clients send them."""
+    mask = os.urandom(4)
+    n = len(payload)
+    if n < 126:
+        head = struct.pack("!BB", 0x80 | opcode, 0x80 | n)
+    elif n < 1 << 16:
+        head = struct.pack("!BBH", 0x80 | opcode, 0x80 | 126, n)
+    else:
+        head = struct.pack("!BBQ", 0x80 | opcode, 0x80 | 127, n)
+    return head + mask + bytes(b ^ mask[i % 4] for i, b in enumerate(payload))
+
+
+def read_exact(sock, n):
+    buf = b""
+    while len(buf) < n:
+        chunk = sock.recv(n - len(buf))
+        if not chunk:
+            raise EOFError
+        buf += chunk
+    return buf
+
+
+def read_frame(sock):
+    """The next server frame as (fin, opcode, payload); servers do not mask."""
+    b0, b1 = read_exact(sock, 2)
+    n = b1 & 0x7F
+    if n == 126:
+        (n,) = struct.unpack("!H", read_exact(sock, 2))
+    elif n == 127:
+        (n,) = struct.unpack("!Q", read_exact(sock, 8))
+    return b0 & 0x80, b0 & 0x0F, read_exact(sock, n)
+
+
+def connect(url, target, user):
+    """Dials the listener and upgrades, returning the socket once the
+    proxy answered 101, its tunnel to `target` up."""
+    u = urllib.parse.urlsplit(url)
+    port = u.port or (443 if u.scheme == "wss" else 80)
+    sock = socket.create_connection((u.hostname, port))
+    if u.scheme == "wss":
+        sock = ssl.create_default_context().wrap_socket(sock, server_hostname=u.hostname)
+    key = base64.b64encode(os.urandom(16)).decode()
+    head = [
+        "GET %s HTTP/1.1" % (u.path or "/"),
+        "Host: %s:%d" % (u.hostname, port),
+        "Upgrade: websocket",
+        "Connection: Upgrade",
+        "Sec-WebSocket-Key: " + key,
+        "Sec-WebSocket-Version: 13",
+        "X-Target: " + target,
+    ]
+    if user:
+        head.append("Proxy-Authorization: Basic " + base64.b64encode(user.encode()).decode())
+    sock.sendall(("\r\n".join(head) + "\r\n\r\n").encode())
+    answer = b""
+    while b"\r\n\r\n" not in answer:
+        chunk = sock.recv(1)
+        if not chunk:
+            raise OSError("proxy closed before answering")
+        answer += chunk
+    status = answer.split(b"\r\n", 1)[0].decode(errors="replace")
+    if " 101 " not in status + " ":
+        raise OSError("proxy refused: " + status)
+    accept = base64.b64encode(hashlib.sha1(key.encode() + GUID).digest())
+    if b"sec-websocket-accept: " + accept.lower() not in answer.lower():
+        raise OSError("proxy answered with the wrong Sec-WebSocket-Accept")
+    return sock
+
+
+def relay(ws, read, write):
+    """Pipes `read` (a callable returning b"" at its end) into binary
+    frames on `ws`, and the frames coming back into `write`."""
+    lock = threading.Lock()
+
+    def upstream():
+        while True:
+            data = read()
+            with lock:
+                if not data:
+                    ws.sendall(frame(CLOSE, struct.pack("!H", 1000)))
+                    return
+                ws.sendall(frame(BINARY, data))
+
+    threading.Thread(target=upstream, daemon=True).start()
+    try:
+        while True:
+            _, opcode, payload = read_frame(ws)
+            if opcode == CLOSE:
+                code = struct.unpack("!H", payload[:2])[0] if len(payload) >= 2 else 1005
+                if code not in (1000, 1001, 1005):
+                    print("tunnel closed with %d %s" % (code, payload[2:].decode(errors="replace")), file=sys.stderr)
+                return
+            if opcode == PING:
+                with lock:
+                    ws.sendall(frame(PONG, payload))
+            elif opcode == BINARY or opcode == 0:
+                write(payload)
+    except (EOFError, OSError):
+        pass
+    finally:
+        ws.close()
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.split("\n")[0])
+    parser.add_argument("url", help="ws:// or wss:// URL of the listener")
+    parser.add_argument("target", help="host:port to tunnel to")
+    parser.add_argument("--listen", help="host:port to accept connections on instead of stdio")
+    parser.add_argument("--user", help="user:password for the proxy's auth_file")
+    args = parser.parse_args()
+    if not args.listen:
+        out = sys.stdout.buffer
+
+        def write(data):
+            out.write(data)
+            out.flush()
+
+        relay(connect(args.url, args.target, args.user), lambda: os.read(0, 16384), write)
+        return
+    host, _, port = args.listen.rpartition(":")
+    server = socket.create_server((host, int(port)))
+    while True:
+        client, _ = server.accept()
+
+        def serve(client=client):
+            try:
+                relay(connect(args.url, args.target, args.user), lambda: client.recv(16384), client.sendall)
+            except OSError as e:
+                print(e, file=sys.stderr)
+            finally:
+                client.close()
+
+        threading.Thread(target=serve, daemon=True).start()
+
+
+if __name__ == "__main__":
+    main()
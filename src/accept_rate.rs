@@ -0,0 +1,101 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::warn;
+
+use crate::config::Config;
+
+/// How often a line about the exhausted bucket is logged at most.
+const LOG_WINDOW: Duration = Duration::from_secs(10);
+
+/// Shortest pause of a listener, so a bucket refilling slowly does not
+/// have the loops wake for every token.
+const MIN_PAUSE: Duration = Duration::from_millis(5);
+
+/// Accepts over every listener and worker, and the acceptor thread.
+static BUCKET: Mutex<Option<Bucket>> = Mutex::new(None);
+
+/// What becomes of a connection with the bucket empty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Excess {
+    /// accept and close it, `Config::accept_rate_shed`
+    Shed,
+    /// leave it in the backlog, and the listener alone for this long
+    Pause(Duration),
+}
+
+/// A token bucket, refilled at `Config::accept_rate` per second up to
+/// `Config::accept_rate_burst`.
+struct Bucket {
+    per_sec: f64,
+    burst: f64,
+    shed: bool,
+    tokens: f64,
+    at: Instant,
+    /// when exhaustion was last logged, and how often it happened since
+    logged: Option<(Instant, u64)>,
+}
+
+/// Takes the limit from `config`, no `accept_rate` turns it off. The
+/// tokens left are kept over a reload, at most the new burst.
+pub fn configure(config: &Config) {
+    let mut bucket = BUCKET.lock().unwrap();
+    let Some(per_sec) = config.accept_rate else {
+        *bucket = None;
+        return;
+    };
+    let burst = f64::from(config.accept_rate_burst);
+    let b = bucket.get_or_insert_with(|| Bucket {
+        per_sec,
+        burst,
+        shed: false,
+        tokens: burst,
+        at: Instant::now(),
+        logged: None,
+    });
+    b.per_sec = per_sec;
+    b.burst = burst;
+    b.shed = config.accept_rate_shed;
+    b.tokens = b.tokens.min(burst);
+}
+
+/// Takes a token for one accept, or says what to do without one.
+pub fn take(now: Instant) -> Result<(), Excess> {
+    let mut bucket = BUCKET.lock().unwrap();
+    let Some(b) = bucket.as_mut() else {
+        return Ok(());
+    };
+    b.tokens = (b.tokens + now.saturating_duration_since(b.at).as_secs_f64() * b.per_sec).min(b.burst);
+    b.at = now;
+    if b.tokens >= 1.0 {
+        b.tokens -= 1.0;
+        return Ok(());
+    }
+    match &mut b.logged {
+        Some((at, held)) if now.duration_since(*at) < LOG_WINDOW => *held += 1,
+        logged => {
+            let held = logged.map_or(0, |(_, held)| held);
+            warn!(
+                "accept_rate {}/s burst {} used up, {} connections; {} more times since the last line",
+                b.per_sec,
+                b.burst,
+                if b.shed { "shedding" } else { "holding off" },
+                held
+            );
+            *logged = Some((now, 0));
+        }
+    }
+    if b.shed {
+        return Err(Excess::Shed);
+    }
+    Err(Excess::Pause(Duration::from_secs_f64((1.0 - b.tokens) / b.per_sec).max(MIN_PAUSE)))
+}
+
+/// Returns the token of a `take` that found nothing to accept.
+pub fn give_back() {
+    if let Some(b) = BUCKET.lock().unwrap().as_mut() {
+        b.tokens = (b.tokens + 1.0).min(b.burst);
+    }
+}
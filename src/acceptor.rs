@@ -0,0 +1,136 @@
+use std::{
+    io::{self, ErrorKind},
+    sync::{mpsc::Receiver, Arc},
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, info};
+use mio::{Events, Interest, Poll};
+
+use crate::{
+    accept_rate::{self, Excess},
+    client::{ClientListener, ClientStream, Peer},
+    command::{Command, CommandSender},
+    stats::WorkerStats,
+    systemd::WatchdogSlot,
+    token::{TokenKind, TokenSpace},
+    worker,
+};
+
+/// The acceptor's view of one worker.
+pub struct Target {
+    pub commands: CommandSender,
+    pub stats: Arc<WorkerStats>,
+}
+
+/// Accepts on `listeners` and dispatches every socket to the worker with
+/// the lowest load, until it receives `Command::Shutdown` or `Command::Drain`.
+/// With a `watchdog` the poll wakes up in time to check in, and with
+/// listeners paused by `accept_rate` in time to take them back.
+pub fn run(
+    mut poll: Poll,
+    mut listeners: Vec<ClientListener>,
+    targets: Vec<Target>,
+    commands: Receiver<Command>,
+    watchdog: Option<WatchdogSlot>,
+) -> io::Result<()> {
+    for (n, l) in listeners.iter_mut().enumerate() {
+        poll.registry()
+            .register(l, TokenSpace::listener(n), Interest::READABLE)?;
+    }
+    let mut events = Events::with_capacity(64);
+    let mut poll_failures = 0;
+    let mut check_in = watchdog.as_ref().map(|w| Instant::now() + w.interval);
+    // deregistered listeners and when to register them again
+    let mut paused: Vec<(usize, Instant)> = Vec::new();
+    loop {
+        let wake = paused.iter().map(|&(_, at)| at).chain(check_in).min();
+        let timeout = wake.map(|at| at.saturating_duration_since(Instant::now()));
+        if !worker::poll_events(&mut poll, &mut events, timeout, &mut poll_failures)? {
+            continue;
+        }
+        if let (Some(w), Some(at)) = (&watchdog, check_in) {
+            if Instant::now() >= at {
+                w.alive();
+                check_in = Some(Instant::now() + w.interval);
+            }
+        }
+        let now = Instant::now();
+        for (n, _) in paused.extract_if(.., |&mut (_, at)| at <= now) {
+            poll.registry()
+                .register(&mut listeners[n], TokenSpace::listener(n), Interest::READABLE)?;
+        }
+        for evt in events.iter() {
+            if evt.token() == TokenSpace::WAKER {
+                if commands
+                    .try_iter()
+                    .any(|c| matches!(c, Command::Shutdown | Command::Drain { .. }))
+                {
+                    info!("acceptor shutting down");
+                    return Ok(());
+                }
+                continue;
+            }
+            let n = match TokenSpace::classify(evt.token()) {
+                TokenKind::Listener(n) if n < listeners.len() => n,
+                _ => continue,
+            };
+            loop {
+                let over = accept_rate::take(Instant::now()).err();
+                if let Some(Excess::Pause(wait)) = over {
+                    pause(&mut poll, &mut listeners[n], wait, &targets)?;
+                    paused.push((n, Instant::now() + wait));
+                    break;
+                }
+                match listeners[n].accept() {
+                    Ok((_, addr)) if over.is_some() => {
+                        debug!("acceptor shed connection from {}, accept_rate used up", addr);
+                        if let Some(t) = targets.first() {
+                            t.stats.accept_shed();
+                        }
+                    }
+                    Ok((sock, addr)) => dispatch(&targets, sock, addr, n),
+                    Err(e) => {
+                        if over.is_none() {
+                            accept_rate::give_back();
+                        }
+                        if e.kind() != ErrorKind::WouldBlock {
+                            error!("accept err {:?}", e);
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Deregisters `listener` for `wait`. The acceptor has no stats of its own,
+/// its counts go to the first worker's, they are only ever read summed.
+fn pause(poll: &mut Poll, listener: &mut ClientListener, wait: Duration, targets: &[Target]) -> io::Result<()> {
+    debug!("acceptor pausing a listener for {:?}, accept_rate used up", wait);
+    if let Some(t) = targets.first() {
+        t.stats.accept_paused();
+    }
+    poll.registry().deregister(listener)
+}
+
+fn dispatch(targets: &[Target], sock: ClientStream, addr: Peer, listener: usize) {
+    let (id, target) = match targets.iter().enumerate().min_by_key(|(_, t)| t.stats.load()) {
+        Some(t) => t,
+        None => return,
+    };
+    debug!("dispatch sock {} to worker {}", addr, id);
+    target.stats.handoff_sent();
+    if let Err(e) = target.commands.send(Command::Adopt {
+        sock,
+        addr,
+        listener,
+    }) {
+        // a worker that is gone never adopts, the socket closed with the command
+        if e.kind() == ErrorKind::BrokenPipe {
+            target.stats.handoff_done();
+        }
+        error!("dispatch sock {} to worker {} err {:?}", addr, id, e);
+    }
+}
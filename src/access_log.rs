@@ -0,0 +1,164 @@
+use std::{
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use crate::{
+    client::Peer,
+    config::Config,
+    session::{CloseReason, Outcome, Session},
+    syslog::{self, Severity, Stream},
+};
+
+/// Bytes of access lines buffered before they go to the file, unless the
+/// stats tick flushed them earlier.
+const BUFFER: usize = 64 * 1024;
+
+/// `Config::access_log` once opened, None writes no access lines.
+static FILE: Mutex<Option<AccessLog>> = Mutex::new(None);
+
+struct AccessLog {
+    path: PathBuf,
+    out: BufWriter<File>,
+}
+
+impl AccessLog {
+    fn open(path: &Path) -> io::Result<AccessLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AccessLog {
+            path: path.to_owned(),
+            out: BufWriter::with_capacity(BUFFER, file),
+        })
+    }
+}
+
+/// Opens `config.access_log`, or stops writing one when unset. Runs at
+/// startup and again on reload; on error the previous file stays.
+pub fn configure(config: &Config) -> io::Result<()> {
+    let file = config.access_log.as_deref().map(AccessLog::open).transpose()?;
+    if let Some(mut old) = std::mem::replace(&mut *FILE.lock().unwrap(), file) {
+        let _ = old.out.flush();
+    }
+    Ok(())
+}
+
+/// Whether an access log is written, which SIGUSR1 then reopens.
+pub fn enabled() -> bool {
+    FILE.lock().unwrap().is_some()
+}
+
+/// Starts over on the file at its configured path, after logrotate moved
+/// it away.
+pub fn reopen() -> io::Result<()> {
+    let mut file = FILE.lock().unwrap();
+    let Some(log) = file.as_mut() else {
+        return Ok(());
+    };
+    log.out.flush()?;
+    *log = AccessLog::open(&log.path)?;
+    Ok(())
+}
+
+/// Writes out buffered lines. Called on the stats tick and at shutdown.
+pub fn flush() {
+    if let Some(log) = FILE.lock().unwrap().as_mut() {
+        let _ = log.out.flush();
+    }
+}
+
+/// The line for `session`, closed for `reason`, to `Config::access_log`
+/// and with `Config::access_log_facility` to syslog. Fields are separated
+/// by one space, in this order, `-` where nothing is known:
+///
+/// 1. time the session closed, RFC 3339 UTC with milliseconds
+/// 2. client `ip:port`, `local` for unix socket clients
+/// 3. request method, CONNECT or the forwarded request's; SOCKS4 or SOCKS5
+///    for a SOCKS client, SOCKS5-UDP for a UDP association, TRANSPARENT
+///    for a redirected connection
+/// 4. destination `host:port`
+/// 5. outcome: `established`, `denied`, `failed` or `failed-<status>`
+///    when the client was answered with that status
+/// 6. bytes client to upstream
+/// 7. bytes upstream to client
+/// 8. session duration in seconds, three decimals
+/// 9. close reason, see `CloseReason`
+/// 10. session id, as in the logs and `/sessions`
+/// 11. user the client authenticated as, see `Config::auth_file`; else
+///     the user id of a SOCKS4 request, which nothing checks
+///
+/// New fields only ever go at the end.
+pub fn session(session: &Session, reason: CloseReason) {
+    let method = session.method();
+    let destination = session.authority();
+    write(
+        &session.client,
+        (method.as_deref(), destination.as_deref()),
+        session.outcome(),
+        (session.bytes_up, session.bytes_down),
+        session.created.elapsed().as_secs_f64(),
+        reason,
+        (Some(session.id), session.user.as_deref().or(session.ident.as_deref())),
+    );
+}
+
+/// The line for a client turned away before it had a session.
+pub fn denied(client: &Peer, reason: CloseReason) {
+    write(client, (None, None), Outcome::Denied, (0, 0), 0.0, reason, (None, None));
+}
+
+fn write(
+    client: &Peer,
+    (method, destination): (Option<&str>, Option<&str>),
+    outcome: Outcome,
+    (up, down): (u64, u64),
+    secs: f64,
+    reason: CloseReason,
+    (id, user): (Option<u64>, Option<&str>),
+) {
+    let mut file = FILE.lock().unwrap();
+    let to_syslog = syslog::wants(Stream::Access);
+    if file.is_none() && !to_syslog {
+        return;
+    }
+    let mut line = String::with_capacity(128);
+    let _ = write!(
+        line,
+        "{} {} {} {} {} {} {} {:.3} {} {} {}",
+        humantime::format_rfc3339_millis(SystemTime::now()),
+        client,
+        field(method),
+        field(destination),
+        outcome,
+        up,
+        down,
+        secs,
+        reason,
+        field(id.map(|id| id.to_string()).as_deref()),
+        field(user)
+    );
+    if to_syslog {
+        syslog::send(Stream::Access, Severity::Info, &line);
+    }
+    if let Some(log) = file.as_mut() {
+        line.push('\n');
+        if let Err(e) = log.out.write_all(line.as_bytes()) {
+            eprintln!("write access log {} err {}", log.path.display(), e);
+        }
+    }
+}
+
+/// `-` for unknown values; anything that would split or break the line
+/// becomes `?`, these come from what the client sent.
+pub fn field(value: Option<&str>) -> String {
+    match value {
+        Some(v) if !v.is_empty() => v
+            .chars()
+            .map(|c| if c.is_ascii_graphic() { c } else { '?' })
+            .collect(),
+        _ => "-".to_owned(),
+    }
+}
@@ -0,0 +1,521 @@
+use std::{fmt::Display, net::IpAddr, str::FromStr};
+
+use serde::Serialize;
+
+use crate::{
+    audit_log::Denial,
+    bandwidth::Bandwidth,
+    geoip::{Country, Place},
+    host_pattern::{HostPattern, HostRegex},
+    internal_addrs::Cidr,
+    schedule::{Days, Hours, WallTime},
+    ser,
+};
+
+/// Whether a destination may be reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclAction {
+    Allow,
+    Deny,
+    /// a deny on trial: what it matches is logged as a would-be denial
+    /// and goes on to the entries after it
+    Log,
+}
+
+impl FromStr for AclAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(AclAction::Allow),
+            "deny" => Ok(AclAction::Deny),
+            "log" => Ok(AclAction::Log),
+            _ => Err(format!("unknown acl action {:?}, expected allow, deny or log", s)),
+        }
+    }
+}
+
+impl Display for AclAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AclAction::Allow => "allow",
+            AclAction::Deny => "deny",
+            AclAction::Log => "log",
+        })
+    }
+}
+
+/// Destination ports, `443, 80, 8000-8100`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortSet {
+    ranges: Vec<(u16, u16)>,
+}
+
+impl PortSet {
+    pub fn contains(&self, port: u16) -> bool {
+        self.ranges.iter().any(|&(lo, hi)| (lo..=hi).contains(&port))
+    }
+}
+
+impl FromStr for PortSet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let port = |p: &str| {
+            p.trim()
+                .parse::<u16>()
+                .ok()
+                .filter(|&p| p > 0)
+                .ok_or_else(|| format!("invalid port {:?} in port set {:?}", p.trim(), s))
+        };
+        let ranges = s
+            .split(',')
+            .map(|item| match item.split_once('-') {
+                Some((lo, hi)) => {
+                    let (lo, hi) = (port(lo)?, port(hi)?);
+                    if lo > hi {
+                        return Err(format!("port range {}-{} in {:?} is backwards", lo, hi, s));
+                    }
+                    Ok((lo, hi))
+                }
+                None => port(item).map(|p| (p, p)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(PortSet { ranges })
+    }
+}
+
+impl Display for PortSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, &(lo, hi)) in self.ranges.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            if lo == hi {
+                write!(f, "{}", lo)?;
+            } else {
+                write!(f, "{}-{}", lo, hi)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One `[[acl]]` entry: destinations matching any of `hosts`, `regex`,
+/// `countries` or `asns`, on one of `ports` when it has them, get
+/// `action`. An allowing entry with `ports` also lets its destinations
+/// past `Config::allowed_ports`. With `hours` or `days` it applies in
+/// that window of `Config::acl_timezone` only. With `limit`, the sessions
+/// it allows share that bandwidth, see `bandwidth::Limit`.
+#[derive(Debug, Clone, Serialize)]
+pub struct AclRule {
+    /// where it is in the config, `acl[2]` or `acl_set.staff.rule[0]`
+    #[serde(skip)]
+    pub label: String,
+    pub hosts: Vec<HostPattern>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub regex: Vec<HostRegex>,
+    /// of the destination address, see `GeoIp::lookup`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub countries: Vec<Country>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub asns: Vec<u32>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "ser::display_opt")]
+    pub ports: Option<PortSet>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "ser::display_opt")]
+    pub hours: Option<Hours>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub days: Option<Days>,
+    #[serde(serialize_with = "ser::display")]
+    pub action: AclAction,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "ser::display_opt")]
+    pub limit: Option<Bandwidth>,
+}
+
+impl AclRule {
+    /// `countries` and `asns` match nothing without `place`.
+    fn matched(&self, host: &str, port: u16, place: Option<&Place>, now: WallTime) -> Option<Matched<'_>> {
+        if self.ports.as_ref().is_some_and(|p| !p.contains(port)) || !self.applies_at(now) {
+            return None;
+        }
+        if let Some(p) = self.hosts.iter().find(|p| p.matches(host, port)) {
+            return Some(Matched::Glob(p));
+        }
+        if let Some(r) = self.regex.iter().find(|r| r.matches(host)) {
+            return Some(Matched::Regex(r));
+        }
+        let place = place?;
+        if let Some(c) = place.country.filter(|c| self.countries.contains(c)) {
+            return Some(Matched::Country(c));
+        }
+        place.asn.filter(|a| self.asns.contains(a)).map(Matched::Asn)
+    }
+
+    pub fn has_geo(&self) -> bool {
+        !self.countries.is_empty() || !self.asns.is_empty()
+    }
+
+    pub fn has_window(&self) -> bool {
+        self.hours.is_some() || self.days.is_some()
+    }
+
+    /// Whether `now` is in the entry's window, always without one.
+    fn applies_at(&self, now: WallTime) -> bool {
+        self.hours.is_none_or(|h| h.contains(now.minute)) && self.days.is_none_or(|d| d.contains(now.day))
+    }
+}
+
+/// A `[acl_set.<name>]` table: rules and default that replace `acl` and
+/// `acl_default` for the users naming it, see `UserPolicy::acl_set`.
+#[derive(Debug, Clone, Serialize)]
+pub struct AclSet {
+    #[serde(rename = "rule")]
+    pub rules: Vec<AclRule>,
+    #[serde(serialize_with = "ser::display")]
+    pub default: AclAction,
+}
+
+/// The pattern an `AclRule` matched by: the glob as written, a regex
+/// between slashes, `country:XX` or `asn:N` of the destination address.
+#[derive(Debug, Clone, Copy)]
+pub enum Matched<'a> {
+    Glob(&'a HostPattern),
+    Regex(&'a HostRegex),
+    Country(Country),
+    Asn(u32),
+}
+
+impl Display for Matched<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Matched::Glob(p) => write!(f, "{}", p),
+            Matched::Regex(r) => write!(f, "/{}/", r),
+            Matched::Country(c) => write!(f, "country:{}", c),
+            Matched::Asn(a) => write!(f, "asn:{}", a),
+        }
+    }
+}
+
+/// Why a destination was refused.
+#[derive(Debug, Clone, Copy)]
+pub enum Refusal<'a> {
+    /// by the acl entry with this pattern, by `acl_default` on None
+    Acl(Option<Matched<'a>>),
+    /// its port is not in `allowed_ports`
+    Port,
+}
+
+impl Refusal<'_> {
+    pub fn denial(self) -> Denial {
+        match self {
+            Refusal::Acl(_) => Denial::Acl,
+            Refusal::Port => Denial::Port,
+        }
+    }
+}
+
+/// The setting that refused, for the audit log.
+impl Display for Refusal<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Refusal::Acl(Some(m)) => write!(f, "acl:{}", m),
+            Refusal::Acl(None) => f.write_str("acl_default"),
+            Refusal::Port => f.write_str("allowed_ports"),
+        }
+    }
+}
+
+/// Whether `host:port` may be reached at `now` by `acl`, the rules and
+/// default `Config::acl_for` gives: the first rule matching it decides,
+/// the default when none does, and unless that rule carries its own ports
+/// the port must be in `allowed_ports`, when set. `place` is where the
+/// destination address is, None before it is resolved; see `needs_place`.
+/// Allowed, it is the entry that allowed, None for the default. `hit`
+/// sees each rule that matched on the way: the `log` ones passed over,
+/// then the one that decided.
+pub fn check<'a>(
+    (rules, default): (&'a [AclRule], AclAction),
+    allowed_ports: Option<&PortSet>,
+    host: &str,
+    port: u16,
+    place: Option<&Place>,
+    now: WallTime,
+    mut hit: impl FnMut(&'a AclRule, Matched<'a>),
+) -> Result<Option<&'a AclRule>, Refusal<'a>> {
+    let (action, rule) = rules
+        .iter()
+        .filter_map(|r| r.matched(host, port, place, now).map(|m| (r, m)))
+        .inspect(|&(r, m)| hit(r, m))
+        .find(|(r, _)| r.action != AclAction::Log)
+        .map_or((default, None), |(r, m)| (r.action, Some((r, m))));
+    match (action, rule) {
+        (AclAction::Deny | AclAction::Log, rule) => Err(Refusal::Acl(rule.map(|(_, m)| m))),
+        (AclAction::Allow, Some((r, _))) if r.ports.is_some() => Ok(Some(r)),
+        _ if allowed_ports.is_some_and(|p| !p.contains(port)) => Err(Refusal::Port),
+        (_, rule) => Ok(rule.map(|(r, _)| r)),
+    }
+}
+
+/// Whether `check` can only decide for `host:port` with the place of its
+/// address: a rule with `countries` or `asns` comes before the first
+/// that matches it by name, `log` ones passed over.
+pub fn needs_place(rules: &[AclRule], host: &str, port: u16, now: WallTime) -> bool {
+    for r in rules {
+        if r.action != AclAction::Log && r.matched(host, port, None, now).is_some() {
+            return false;
+        }
+        if r.has_geo() && r.ports.as_ref().is_none_or(|p| p.contains(port)) && r.applies_at(now) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Why a client was turned away at accept.
+#[derive(Debug, Clone, Copy)]
+pub enum ClientRefusal {
+    /// its address is in this `deny_clients` block
+    Denied(Cidr),
+    /// `allow_clients` is set and has no block with its address
+    NotAllowed,
+}
+
+/// The setting that refused, for the audit log.
+impl Display for ClientRefusal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientRefusal::Denied(c) => write!(f, "deny_clients:{}", c),
+            ClientRefusal::NotAllowed => f.write_str("allow_clients"),
+        }
+    }
+}
+
+/// Whether a client at `ip` may connect: not when in a `deny` block,
+/// else when `allow` is empty or has a block with it.
+pub fn check_client(deny: &[Cidr], allow: &[Cidr], ip: IpAddr) -> Result<(), ClientRefusal> {
+    if let Some(&c) = deny.iter().find(|c| c.contains(ip)) {
+        return Err(ClientRefusal::Denied(c));
+    }
+    if allow.is_empty() || allow.iter().any(|c| c.contains(ip)) {
+        Ok(())
+    } else {
+        Err(ClientRefusal::NotAllowed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOON: WallTime = WallTime { day: 0, minute: 12 * 60 };
+
+    fn rule(hosts: &[&str], action: AclAction) -> AclRule {
+        AclRule {
+            label: format!("acl[{}]", hosts.join(",")),
+            hosts: hosts.iter().map(|h| h.parse().unwrap()).collect(),
+            regex: Vec::new(),
+            countries: Vec::new(),
+            asns: Vec::new(),
+            ports: None,
+            hours: None,
+            days: None,
+            action,
+            limit: None,
+        }
+    }
+
+    /// What `check` decides: the label of the entry that allowed, `-`
+    /// for the default, or the refusal as the audit log says it.
+    fn decide(rules: &[AclRule], default: AclAction, allowed: Option<&PortSet>, host: &str, port: u16) -> String {
+        match check((rules, default), allowed, host, port, None, NOON, |_, _| {}) {
+            Ok(Some(r)) => r.label.clone(),
+            Ok(None) => "-".to_owned(),
+            Err(refusal) => refusal.to_string(),
+        }
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let rules = [rule(&["evil.example.com"], AclAction::Deny), rule(&["*.example.com"], AclAction::Allow)];
+        assert_eq!(decide(&rules, AclAction::Deny, None, "evil.example.com", 443), "acl:evil.example.com");
+        assert_eq!(decide(&rules, AclAction::Deny, None, "a.example.com", 443), "acl[*.example.com]");
+        // in the other order the allow comes first
+        let rules = [rules[1].clone(), rules[0].clone()];
+        assert_eq!(decide(&rules, AclAction::Deny, None, "evil.example.com", 443), "acl[*.example.com]");
+    }
+
+    #[test]
+    fn default_decides_what_nothing_matches() {
+        let rules = [rule(&["*.example.com"], AclAction::Allow)];
+        // the wildcard does not take the bare domain, a second rule must
+        assert_eq!(decide(&rules, AclAction::Deny, None, "example.com", 443), "acl_default");
+        let rules = [rules[0].clone(), rule(&["example.com"], AclAction::Allow)];
+        assert_eq!(decide(&rules, AclAction::Deny, None, "example.com", 443), "acl[example.com]");
+        assert_eq!(decide(&[], AclAction::Allow, None, "anything", 443), "-");
+    }
+
+    #[test]
+    fn log_entries_pass_on_to_the_next() {
+        let rules = [rule(&["*.example.com"], AclAction::Log), rule(&["a.example.com"], AclAction::Allow)];
+        let mut hits = Vec::new();
+        let allowed = check((&rules, AclAction::Deny), None, "a.example.com", 443, None, NOON, |r, m| {
+            hits.push(format!("{} {}", r.action, m))
+        });
+        assert_eq!(allowed.unwrap().unwrap().label, "acl[a.example.com]");
+        assert_eq!(hits, ["log *.example.com", "allow a.example.com"]);
+        // with no entry after it deciding, the default does
+        assert_eq!(decide(&rules, AclAction::Deny, None, "b.example.com", 443), "acl_default");
+        assert_eq!(decide(&rules, AclAction::Allow, None, "b.example.com", 443), "-");
+    }
+
+
+    #[test]
+    fn regex_entries_take_their_place_in_order() {
+        let mut foreign = rule(&[], AclAction::Deny);
+        foreign.regex = vec![r"^.*\.(ru|cn)$".parse().unwrap()];
+        let mut literals = rule(&[], AclAction::Deny);
+        literals.regex = vec![r"^[0-9.]+$".parse().unwrap()];
+        let rules = [rule(&["partner.cn"], AclAction::Allow), foreign, literals];
+        assert_eq!(decide(&rules, AclAction::Allow, None, "partner.cn", 443), "acl[partner.cn]");
+        assert_eq!(decide(&rules, AclAction::Allow, None, "www.baidu.cn", 443), r"acl:/^.*\.(ru|cn)$/");
+        assert_eq!(decide(&rules, AclAction::Allow, None, "10.1.2.3", 443), "acl:/^[0-9.]+$/");
+        assert_eq!(decide(&rules, AclAction::Allow, None, "example.com", 443), "-");
+    }
+
+    #[test]
+    fn ports_of_an_entry_and_allowed_ports() {
+        let allowed: PortSet = "443, 8000-8100".parse().unwrap();
+        let mut ssh = rule(&["git.example.com"], AclAction::Allow);
+        ssh.ports = Some("22".parse().unwrap());
+        let rules = [ssh, rule(&["*.example.com"], AclAction::Allow)];
+        // the entry's own ports let 22 past allowed_ports
+        assert_eq!(decide(&rules, AclAction::Deny, Some(&allowed), "git.example.com", 22), "acl[git.example.com]");
+        assert_eq!(decide(&rules, AclAction::Deny, Some(&allowed), "a.example.com", 22), "allowed_ports");
+        assert_eq!(decide(&rules, AclAction::Deny, Some(&allowed), "a.example.com", 8080), "acl[*.example.com]");
+        assert_eq!(decide(&rules, AclAction::Allow, Some(&allowed), "other.org", 25), "allowed_ports");
+        // off the entry's ports, the next entry decides
+        assert_eq!(decide(&rules, AclAction::Deny, Some(&allowed), "git.example.com", 443), "acl[*.example.com]");
+    }
+
+    #[test]
+    fn port_sets() {
+        let set: PortSet = "443, 80, 8000-8100".parse().unwrap();
+        assert!(set.contains(80) && set.contains(8000) && set.contains(8100));
+        assert!(!set.contains(8101) && !set.contains(22));
+        assert_eq!(set.to_string(), "443, 80, 8000-8100");
+        assert!("0".parse::<PortSet>().is_err());
+        assert!("90-80".parse::<PortSet>().is_err());
+        assert!("443,".parse::<PortSet>().is_err());
+    }
+
+    /// `rule` allowing `*.example.com` in a window.
+    fn windowed(hours: Option<&str>, days: Option<&[&str]>) -> [AclRule; 1] {
+        let mut r = rule(&["*.example.com"], AclAction::Allow);
+        r.hours = hours.map(|h| h.parse().unwrap());
+        r.days = days.map(|d| Days::parse(d).unwrap());
+        [r]
+    }
+
+    fn allowed_at(rules: &[AclRule], day: u8, hour: u16, minute: u16) -> bool {
+        let now = WallTime { day, minute: hour * 60 + minute };
+        check((rules, AclAction::Deny), None, "a.example.com", 443, None, now, |_, _| {}).is_ok()
+    }
+
+    const MON: u8 = 0;
+    const FRI: u8 = 4;
+    const SAT: u8 = 5;
+    const SUN: u8 = 6;
+
+    #[test]
+    fn hours_inside_and_outside() {
+        let rules = windowed(Some("08:00-18:00"), None);
+        assert!(allowed_at(&rules, MON, 8, 0));
+        assert!(allowed_at(&rules, MON, 12, 30));
+        assert!(allowed_at(&rules, MON, 17, 59));
+        // the end is not part of the window
+        assert!(!allowed_at(&rules, MON, 18, 0));
+        assert!(!allowed_at(&rules, MON, 7, 59));
+        assert!(!allowed_at(&rules, SUN, 3, 0));
+    }
+
+    #[test]
+    fn hours_wrapping_over_midnight() {
+        let rules = windowed(Some("22:00-06:00"), None);
+        assert!(allowed_at(&rules, MON, 22, 0));
+        assert!(allowed_at(&rules, MON, 23, 59));
+        assert!(allowed_at(&rules, MON, 0, 0));
+        assert!(allowed_at(&rules, MON, 5, 59));
+        assert!(!allowed_at(&rules, MON, 6, 0));
+        assert!(!allowed_at(&rules, MON, 21, 59));
+        let to_midnight = windowed(Some("18:00-24:00"), None);
+        assert!(allowed_at(&to_midnight, MON, 23, 59));
+        assert!(!allowed_at(&to_midnight, MON, 0, 0));
+    }
+
+    #[test]
+    fn day_of_week_edges() {
+        let weekdays = windowed(None, Some(&["mon-fri"]));
+        assert!(allowed_at(&weekdays, MON, 0, 0));
+        assert!(allowed_at(&weekdays, FRI, 23, 59));
+        assert!(!allowed_at(&weekdays, SAT, 0, 0));
+        assert!(!allowed_at(&weekdays, SUN, 23, 59));
+        // a range over the weekend
+        let long_weekend = windowed(None, Some(&["fri-mon"]));
+        for (day, want) in (0..7).map(|d| (d, [MON, FRI, SAT, SUN].contains(&d))) {
+            assert_eq!(allowed_at(&long_weekend, day, 12, 0), want, "day {}", day);
+        }
+        // days are those of the moment: Friday night past midnight is
+        // Saturday already
+        let friday_nights = windowed(Some("22:00-02:00"), Some(&["fri"]));
+        assert!(allowed_at(&friday_nights, FRI, 23, 0));
+        assert!(!allowed_at(&friday_nights, SAT, 1, 0));
+        assert!(allowed_at(&friday_nights, FRI, 1, 0));
+    }
+
+    #[test]
+    fn outside_its_window_an_entry_is_passed_over() {
+        // working hours deny, the rest of the time the default allows
+        let mut office = rule(&["*.example.com"], AclAction::Deny);
+        office.hours = Some("08:00-18:00".parse().unwrap());
+        office.days = Some(Days::parse(&["mon-fri"]).unwrap());
+        let rules = [office];
+        let at = |day: u8, hour: u16| {
+            let now = WallTime { day, minute: hour * 60 };
+            check((&rules, AclAction::Allow), None, "a.example.com", 443, None, now, |_, _| {}).is_ok()
+        };
+        assert!(!at(MON, 9));
+        assert!(at(MON, 19));
+        assert!(at(SAT, 9));
+    }
+
+    /// What one `check` costs with a hundred and with a few hundred
+    /// entries, half of them regexes, for hosts that match none: every
+    /// entry is tried.
+    /// `cargo test --release acl::tests::bench -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_check() {
+        let hosts = ["www.nomatch.example.org", "api.partner.io", "10.20.30.40"];
+        for n in [100, 300] {
+            let rules: Vec<_> = (0..n)
+                .map(|i| {
+                    if i % 2 == 0 {
+                        rule(&[&format!("*.host{}.example.com:443", i)], AclAction::Deny)
+                    } else {
+                        let mut r = rule(&[], AclAction::Deny);
+                        r.regex = vec![format!(r"^(.*\.)?site{}\.(com|net|org)$", i).parse().unwrap()];
+                        r
+                    }
+                })
+                .collect();
+            let rounds = 30_000;
+            let start = std::time::Instant::now();
+            for i in 0..rounds {
+                let host = std::hint::black_box(hosts[i % hosts.len()]);
+                let decided = check((&rules, AclAction::Allow), None, host, 443, None, NOON, |_, _| {});
+                assert!(matches!(decided, Ok(None)));
+            }
+            println!("{} rules: {:?} per check", n, start.elapsed() / rounds as u32);
+        }
+    }
+}
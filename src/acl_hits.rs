@@ -0,0 +1,91 @@
+use std::{collections::BTreeMap, fmt::Write as _, sync::Mutex, time::SystemTime};
+
+use crate::{
+    acl::{AclAction, AclRule},
+    admin::json_str,
+    config::Config,
+};
+
+/// How often each acl entry decided a request, by `AclRule::label`,
+/// shared by every worker.
+static HITS: Mutex<BTreeMap<String, Hits>> = Mutex::new(BTreeMap::new());
+
+struct Hits {
+    /// where the entry is among all of them, to list them in config order
+    order: usize,
+    /// the entry as written, an inline TOML table
+    text: String,
+    action: AclAction,
+    hits: u64,
+    last: Option<SystemTime>,
+    /// `host:port` of the last request it matched
+    example: String,
+}
+
+/// The entry as `/acl` shows it and `configure` compares it.
+fn text(rule: &AclRule) -> String {
+    toml::Value::try_from(rule).map_or_else(|e| format!("<{}>", e), |v| v.to_string())
+}
+
+/// Takes the acl entries of `config`, sets first like `Config::validate`
+/// lists them. The counts of an entry with the same label and text are
+/// kept over a reload, an entry that changed starts from zero.
+pub fn configure(config: &Config) {
+    let mut sets = config.acl_sets.iter().collect::<Vec<_>>();
+    sets.sort_by_key(|(name, _)| *name);
+    let rules = sets.into_iter().flat_map(|(_, s)| s.rules.iter()).chain(&config.acl);
+    let mut hits = HITS.lock().unwrap();
+    let mut old = std::mem::take(&mut *hits);
+    for (order, rule) in rules.enumerate() {
+        let text = text(rule);
+        let kept = old.remove(&rule.label).filter(|h| h.text == text);
+        let entry = kept.unwrap_or(Hits {
+            order,
+            text,
+            action: rule.action,
+            hits: 0,
+            last: None,
+            example: String::new(),
+        });
+        hits.insert(rule.label.clone(), Hits { order, ..entry });
+    }
+}
+
+/// Counts a request for `host:port` that `rule` matched, see `acl::check`.
+pub fn hit(rule: &AclRule, host: &str, port: u16) {
+    let mut hits = HITS.lock().unwrap();
+    // an entry of the config before a reload, gone or changed since
+    let Some(h) = hits.get_mut(&rule.label) else {
+        return;
+    };
+    h.hits += 1;
+    h.last = Some(SystemTime::now());
+    h.example.clear();
+    let _ = write!(h.example, "{}:{}", host, port);
+}
+
+/// The `/acl` body: every acl entry in config order, with how often it
+/// matched, when last and for what.
+pub fn json() -> String {
+    let hits = HITS.lock().unwrap();
+    let mut list = hits.iter().collect::<Vec<_>>();
+    list.sort_by_key(|(_, h)| h.order);
+    let mut out = String::from(r#"{"rules":["#);
+    for (i, (label, h)) in list.into_iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(
+            out,
+            r#"{{"rule":{},"text":{},"action":"{}","hits":{},"last_hit":{},"last_authority":{}}}"#,
+            json_str(label),
+            json_str(&h.text),
+            h.action,
+            h.hits,
+            h.last.map_or("null".to_owned(), |t| format!(r#""{}""#, humantime::format_rfc3339_seconds(t))),
+            if h.hits == 0 { "null".to_owned() } else { json_str(&h.example) }
+        );
+    }
+    out.push_str("]}");
+    out
+}
@@ -0,0 +1,1253 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Write as _},
+    io::{self, ErrorKind, Read, Write},
+    net::{IpAddr, Shutdown},
+    path::PathBuf,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, info, warn, LevelFilter};
+use mio::{
+    event::Event,
+    net::{TcpListener, TcpStream},
+    Interest, Registry,
+};
+
+use crate::{
+    acl_hits, bandwidth, bans,
+    capture::{self, CaptureError},
+    client::Peer,
+    command::{Command, CommandSender},
+    config::{self, Config},
+    dns::CacheInfo,
+    failing_hosts, logging, loop_sampler, metrics, parent_health, quota,
+    stats::{Summary, TrafficClass, WorkerStats},
+    token::TokenSpace,
+    top_hosts, usage,
+};
+
+/// Request heads larger than this are answered with 400.
+const MAX_HEAD: usize = 4096;
+
+/// Cap on a `/metrics` body; past it the response is cut at the last
+/// whole line, which only a very large listener count could reach.
+const MAX_METRICS: usize = 256 * 1024;
+
+/// Hosts listed by `/top-hosts`, the rest is summed up as `other`.
+const TOP_HOSTS: usize = 100;
+
+/// Hosts listed by `/failing-hosts`.
+const FAILING_HOSTS: usize = 100;
+
+/// Sessions in one `/sessions` page, and the most `limit` may ask for.
+const SESSIONS_PAGE: usize = 1000;
+
+/// Longest host name `/dns-cache` takes.
+const MAX_HOST: usize = 255;
+
+const JSON: &str = "application/json";
+const TEXT: &str = "text/plain";
+const PROMETHEUS: &str = "text/plain; version=0.0.4";
+
+/// One active session as listed by `/sessions`.
+pub struct SessionInfo {
+    pub worker: usize,
+    /// `Session::id`
+    pub id: u64,
+    pub client: Peer,
+    /// the listener the client connected to, see `Config::listener_names`
+    pub listener: String,
+    /// name of the profile the session runs with
+    pub profile: String,
+    pub host: String,
+    pub state: String,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub age: Duration,
+    /// since bytes last moved, see `Session::last_active`
+    pub idle: Duration,
+    /// `Session::pending`
+    pub pending_up: usize,
+    pub pending_down: usize,
+    /// the client's fd, which is also its token, and the upstream's once
+    /// the connect is issued
+    pub fd: usize,
+    pub up_fd: Option<usize>,
+    /// `Session::mptcp_negotiated`
+    pub mptcp: Option<bool>,
+}
+
+/// The line a SIGUSR1 dump logs for the session.
+impl Display for SessionInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "worker {} session {} client {} listener {} profile {} host [{}] state {} age {:.3}s idle {:.3}s \
+             bytes up {} down {} pending up {} down {} fd {} up fd ",
+            self.worker,
+            self.id,
+            self.client,
+            self.listener,
+            self.profile,
+            self.host,
+            self.state,
+            self.age.as_secs_f64(),
+            self.idle.as_secs_f64(),
+            self.bytes_up,
+            self.bytes_down,
+            self.pending_up,
+            self.pending_down,
+            self.fd
+        )?;
+        match self.up_fd {
+            Some(fd) => write!(f, "{}", fd)?,
+            None => f.write_str("-")?,
+        }
+        match self.mptcp {
+            Some(true) => f.write_str(" mptcp yes"),
+            Some(false) => f.write_str(" mptcp no"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Minimal HTTP/1.0 stats endpoint living in one worker's loop: read the
+/// request head, write one response, close. Everything is non-blocking and
+/// driven by the hosting worker's events; `/sessions` asks every worker
+/// for a page of its sessions over the command channels and answers once
+/// all replied, `POST /sessions/<id>/capture` asks them all to start one
+/// and answers with the reply of the worker that has the session.
+/// `/dns-cache` lists and `DELETE /dns-cache/<host>` evicts the workers'
+/// cached answers the same way.
+/// `/loglevel` shows the log filter, `PUT` sets levels on top of it and
+/// `DELETE` drops them again, see `logging::override_level`.
+/// `/stats`, `/metrics`, `/top-hosts`, `/failing-hosts`, `/loop-samples`,
+/// `/quota`, `/limits`, `/acl`, `/parents`, `/healthz` and `/readyz` read
+/// the shared counters directly.
+/// `/bans` lists the clients banned for failed logins or by `PUT
+/// /bans/<ip>?duration=1h`, which with `&close=true` also has the workers
+/// close the sessions the client has; `DELETE /bans` lifts every ban and
+/// `DELETE /bans/<ip>` that one, see `bans::lift`. A wedged
+/// hosting worker answers nothing at all, which probes with a timeout take
+/// as failing too.
+pub struct Admin {
+    listener: TcpListener,
+    started: Instant,
+    /// worker whose loop hosts the endpoint, replies are sent to it
+    host: usize,
+    workers: Vec<CommandSender>,
+    stats: Vec<Arc<WorkerStats>>,
+    /// `Config::listener_labels`, to label the per-listener counters
+    listen: Vec<(String, String)>,
+    /// the fd limit, for `fd_budget_used`
+    nofile: u64,
+    /// `Config::metrics` includes Prometheus, else `/metrics` is a 404
+    prometheus: bool,
+    /// `Config::capture_dir`, captures are refused with 403 without
+    capture_dir: Option<PathBuf>,
+    /// `Config::loop_stall`, for `/healthz`
+    loop_stall: Duration,
+    /// `Config::max_sessions`, `/readyz` fails at it
+    max_sessions: Option<usize>,
+    /// `Config::log_level_revert`, for a `PUT /loglevel` without `for`
+    log_level_revert: Option<Duration>,
+    /// `Config::loop_sample_every`, for `/loop-samples`
+    loop_sample_every: Option<u64>,
+    /// `Config::dns_cache_page`
+    dns_cache_page: usize,
+    /// `UserPolicy::daily_quota` of the users that have one, for `/quota`
+    quotas: BTreeMap<String, u64>,
+    /// the workers drain, see `start_drain`
+    draining: bool,
+    conns: Vec<Option<Conn>>,
+    next_request: u64,
+}
+
+struct Conn {
+    sock: TcpStream,
+    head: Vec<u8>,
+    out: Vec<u8>,
+    sent: usize,
+    pending: Option<Pending>,
+}
+
+/// A request waiting for worker replies.
+struct Pending {
+    request: u64,
+    remaining: usize,
+    work: Work,
+}
+
+enum Work {
+    /// `/sessions`
+    Sessions {
+        limit: usize,
+        sessions: Vec<SessionInfo>,
+        /// a worker had more than `limit` to list
+        more: bool,
+    },
+    /// `/sessions/<id>/capture`, answered by the first worker that had
+    /// the session or with 404 once none had
+    Capture { id: u64, limit: u64 },
+    /// `/dns-cache`, `limit` counting hosts, which several workers may have
+    DnsCache {
+        limit: usize,
+        entries: Vec<CacheInfo>,
+        more: bool,
+    },
+    /// `DELETE /dns-cache/<host>`, 404 once no worker had it
+    EvictDns { host: String, evicted: usize },
+    /// `PUT /bans/<ip>?close=true`, answered once every worker closed the
+    /// sessions of `ip`
+    Ban { ip: IpAddr, ban: bans::Ban, closed: usize },
+}
+
+/// The users with a `UserPolicy::daily_quota`, with it.
+fn daily_quotas(config: &Config) -> BTreeMap<String, u64> {
+    config
+        .users
+        .values()
+        .filter_map(|u| Some((u.name.clone(), u.daily_quota?)))
+        .collect()
+}
+
+impl Admin {
+    pub fn new(
+        listener: TcpListener,
+        host: usize,
+        workers: Vec<CommandSender>,
+        stats: Vec<Arc<WorkerStats>>,
+        nofile: u64,
+        config: &Config,
+    ) -> Admin {
+        Admin {
+            listener,
+            started: Instant::now(),
+            host,
+            workers,
+            stats,
+            listen: config.listener_labels(),
+            nofile,
+            prometheus: config.metrics.prometheus(),
+            capture_dir: config.capture_dir.clone(),
+            loop_stall: config.loop_stall,
+            max_sessions: config.max_sessions,
+            log_level_revert: config.log_level_revert,
+            loop_sample_every: config.loop_sample_every,
+            dns_cache_page: config.dns_cache_page,
+            quotas: daily_quotas(config),
+            draining: false,
+            conns: (0..TokenSpace::MAX_ADMIN_CONNS).map(|_| None).collect(),
+            next_request: 1,
+        }
+    }
+
+    /// Takes over the reloaded settings it serves with.
+    pub fn reload(&mut self, config: &Config) {
+        self.prometheus = config.metrics.prometheus();
+        self.capture_dir = config.capture_dir.clone();
+        self.loop_stall = config.loop_stall;
+        self.max_sessions = config.max_sessions;
+        self.log_level_revert = config.log_level_revert;
+        self.loop_sample_every = config.loop_sample_every;
+        self.dns_cache_page = config.dns_cache_page;
+        self.quotas = daily_quotas(config);
+        // a reload may give listeners another profile
+        self.listen = config.listener_labels();
+    }
+
+    /// The workers stopped accepting and finish their sessions: `/readyz`
+    /// fails from now on, so load balancers send new clients elsewhere.
+    pub fn start_drain(&mut self) {
+        self.draining = true;
+    }
+
+    pub fn register(&mut self, registry: &Registry) -> io::Result<()> {
+        registry.register(&mut self.listener, TokenSpace::ADMIN, Interest::READABLE)
+    }
+
+    /// Deregisters the listener and drops all connections.
+    pub fn close(mut self, registry: &Registry) {
+        let _ = registry.deregister(&mut self.listener);
+        for slot in 0..self.conns.len() {
+            self.close_conn(registry, slot);
+        }
+    }
+
+    pub fn accept(&mut self, registry: &Registry) {
+        loop {
+            let (mut sock, addr) = match self.listener.accept() {
+                Ok(s) => s,
+                Err(e) => {
+                    if e.kind() != ErrorKind::WouldBlock {
+                        error!("admin accept err {:?}", e);
+                    }
+                    return;
+                }
+            };
+            let slot = match self.conns.iter().position(Option::is_none) {
+                Some(slot) => slot,
+                None => {
+                    warn!("admin connections exhausted, reject {}", addr);
+                    continue;
+                }
+            };
+            if let Err(e) = registry.register(
+                &mut sock,
+                TokenSpace::admin_conn(slot),
+                Interest::READABLE | Interest::WRITABLE,
+            ) {
+                error!("admin register {} err {:?}", addr, e);
+                continue;
+            }
+            debug!("admin conn {} in slot {}", addr, slot);
+            self.conns[slot] = Some(Conn {
+                sock,
+                head: Vec::new(),
+                out: Vec::new(),
+                sent: 0,
+                pending: None,
+            });
+        }
+    }
+
+    pub fn handle(&mut self, registry: &Registry, slot: usize, evt: &Event) {
+        if let Err(e) = self.drive(slot, evt.is_readable()) {
+            if e.kind() != ErrorKind::WouldBlock {
+                debug!("admin conn {} err {:?}", slot, e);
+                self.close_conn(registry, slot);
+                return;
+            }
+        }
+        if evt.is_error() || self.conns[slot].as_ref().is_some_and(Conn::done) {
+            self.close_conn(registry, slot);
+        }
+    }
+
+    /// A worker's answer to `Command::ListSessions`.
+    pub fn session_list(&mut self, registry: &Registry, request: u64, mut list: Vec<SessionInfo>, more: bool) {
+        let Some(slot) = self.pending_slot(request) else {
+            debug!("admin request {} gone, drop its session list", request);
+            return;
+        };
+        let conn = self.conns[slot].as_mut().unwrap();
+        let pending = conn.pending.as_mut().unwrap();
+        pending.remaining -= 1;
+        let remaining = pending.remaining;
+        let Work::Sessions { limit, sessions, more: any_more } = &mut pending.work else {
+            return;
+        };
+        sessions.append(&mut list);
+        *any_more |= more;
+        if remaining == 0 {
+            sessions.sort_by_key(|s| s.id);
+            if sessions.len() > *limit {
+                sessions.truncate(*limit);
+                *any_more = true;
+            }
+            let body = sessions_json(sessions, *any_more);
+            conn.pending = None;
+            conn.respond(200, JSON, &body);
+            self.flush(registry, slot);
+        }
+    }
+
+    /// A worker's answer to `Command::Capture`, None when it does not have
+    /// the session.
+    pub fn capture_reply(&mut self, registry: &Registry, request: u64, result: Option<Result<PathBuf, CaptureError>>) {
+        let Some(slot) = self.pending_slot(request) else {
+            debug!("admin request {} gone, drop its capture reply", request);
+            return;
+        };
+        let conn = self.conns[slot].as_mut().unwrap();
+        let pending = conn.pending.as_mut().unwrap();
+        pending.remaining -= 1;
+        let Work::Capture { id, limit } = pending.work else {
+            return;
+        };
+        match result {
+            Some(Ok(path)) => conn.respond(
+                200,
+                JSON,
+                &format!(
+                    r#"{{"session":{},"path":{},"limit":{}}}"#,
+                    id,
+                    json_str(&path.to_string_lossy()),
+                    limit
+                ),
+            ),
+            Some(Err(CaptureError::Running(path))) => conn.respond(
+                409,
+                JSON,
+                &format!(r#"{{"error":"capture running","path":{}}}"#, json_str(&path.to_string_lossy())),
+            ),
+            Some(Err(CaptureError::Io(e))) => {
+                conn.respond(500, JSON, &format!(r#"{{"error":{}}}"#, json_str(&e)))
+            }
+            None if pending.remaining == 0 => conn.respond(404, JSON, r#"{"error":"no such session"}"#),
+            None => return,
+        }
+        conn.pending = None;
+        self.flush(registry, slot);
+    }
+
+    /// A worker's answer to `Command::ListDnsCache`.
+    pub fn dns_cache_list(&mut self, registry: &Registry, request: u64, mut list: Vec<CacheInfo>, more: bool) {
+        let Some(slot) = self.pending_slot(request) else {
+            debug!("admin request {} gone, drop its dns cache list", request);
+            return;
+        };
+        let conn = self.conns[slot].as_mut().unwrap();
+        let pending = conn.pending.as_mut().unwrap();
+        pending.remaining -= 1;
+        let remaining = pending.remaining;
+        let Work::DnsCache { limit, entries, more: any_more } = &mut pending.work else {
+            return;
+        };
+        entries.append(&mut list);
+        *any_more |= more;
+        if remaining == 0 {
+            entries.sort_by(|a, b| a.host.cmp(&b.host).then(a.worker.cmp(&b.worker)));
+            // each worker sent its first `limit` hosts, so the first
+            // `limit` of them all are complete
+            let mut hosts = 0;
+            let keep = entries
+                .iter()
+                .enumerate()
+                .position(|(i, e)| {
+                    if i == 0 || entries[i - 1].host != e.host {
+                        hosts += 1;
+                    }
+                    hosts > *limit
+                })
+                .unwrap_or(entries.len());
+            if keep < entries.len() {
+                entries.truncate(keep);
+                *any_more = true;
+            }
+            let body = dns_cache_json(entries, *any_more);
+            conn.pending = None;
+            conn.respond(200, JSON, &body);
+            self.flush(registry, slot);
+        }
+    }
+
+    /// A worker's answer to `Command::EvictDns`.
+    pub fn dns_evicted(&mut self, registry: &Registry, request: u64, found: bool) {
+        let Some(slot) = self.pending_slot(request) else {
+            debug!("admin request {} gone, drop its dns evict reply", request);
+            return;
+        };
+        let conn = self.conns[slot].as_mut().unwrap();
+        let pending = conn.pending.as_mut().unwrap();
+        pending.remaining -= 1;
+        let remaining = pending.remaining;
+        let Work::EvictDns { host, evicted } = &mut pending.work else {
+            return;
+        };
+        if found {
+            *evicted += 1;
+        }
+        if remaining == 0 {
+            let (host, evicted) = (std::mem::take(host), *evicted);
+            conn.pending = None;
+            if evicted == 0 {
+                conn.respond(404, JSON, r#"{"error":"host not cached"}"#);
+            } else {
+                conn.respond(200, JSON, &format!(r#"{{"host":{},"workers":{}}}"#, json_str(&host), evicted));
+            }
+            self.flush(registry, slot);
+        }
+    }
+
+    /// A worker's answer to `Command::CloseClient`.
+    pub fn client_closed(&mut self, registry: &Registry, request: u64, closed: usize) {
+        let Some(slot) = self.pending_slot(request) else {
+            debug!("admin request {} gone, drop its close client reply", request);
+            return;
+        };
+        let conn = self.conns[slot].as_mut().unwrap();
+        let pending = conn.pending.as_mut().unwrap();
+        pending.remaining -= 1;
+        let remaining = pending.remaining;
+        let Work::Ban { ip, ban, closed: total } = &mut pending.work else {
+            return;
+        };
+        *total += closed;
+        if remaining == 0 {
+            let body = ban_json(*ip, ban, Some(*total));
+            conn.pending = None;
+            conn.respond(200, JSON, &body);
+            self.flush(registry, slot);
+        }
+    }
+
+    /// The connection waiting for the replies to `request`.
+    fn pending_slot(&self, request: u64) -> Option<usize> {
+        self.conns.iter().position(|c| {
+            c.as_ref()
+                .and_then(|c| c.pending.as_ref())
+                .is_some_and(|p| p.request == request)
+        })
+    }
+
+    fn drive(&mut self, slot: usize, readable: bool) -> io::Result<()> {
+        let conn = match self.conns[slot].as_mut() {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        if readable && conn.out.is_empty() && conn.pending.is_none() {
+            if let Some((method, target)) = conn.read_head()? {
+                self.route(slot, &method, &target);
+            }
+        }
+        match self.conns[slot].as_mut() {
+            Some(conn) => conn.write_out(),
+            None => Ok(()),
+        }
+    }
+
+    fn bans(&mut self, slot: usize, method: &str, path: &str, query: &str) {
+        let conn = self.conns[slot].as_mut().unwrap();
+        let ip = match path.strip_prefix("/bans/") {
+            None => None,
+            Some(ip) => match ip.parse::<IpAddr>() {
+                Ok(ip) => Some(ip),
+                Err(_) => return conn.respond(400, JSON, r#"{"error":"expected /bans/<ip>"}"#),
+            },
+        };
+        match (method, ip) {
+            ("GET" | "", None) => conn.respond(200, JSON, &bans::json(&bans::list())),
+            ("PUT", Some(ip)) => {
+                let Some((duration, close)) = ban_request(query) else {
+                    return conn.respond(400, JSON, r#"{"error":"expected duration=<duration>[&close=true]"}"#);
+                };
+                let ban = match bans::ban(ip, duration) {
+                    Ok(ban) => ban,
+                    Err(e) => return conn.respond(503, JSON, &format!(r#"{{"error":{}}}"#, json_str(&e))),
+                };
+                warn!(client:% = ip; "admin banned client for {:?}", duration);
+                if close {
+                    self.close_client(slot, ip, ban);
+                } else {
+                    conn.respond(200, JSON, &ban_json(ip, &ban, None));
+                }
+            }
+            ("DELETE", ip) => {
+                let lifted = bans::lift(ip);
+                match ip {
+                    Some(ip) if lifted == 0 => {
+                        return conn.respond(404, JSON, &format!(r#"{{"error":"{} is not banned"}}"#, ip))
+                    }
+                    Some(ip) => info!(client:% = ip; "admin lifted ban"),
+                    None => info!("admin lifted all {} bans", lifted),
+                }
+                conn.respond(200, JSON, &format!(r#"{{"lifted":{}}}"#, lifted))
+            }
+            _ => conn.respond(405, JSON, r#"{"error":"method not allowed"}"#),
+        }
+    }
+
+    fn route(&mut self, slot: usize, method: &str, target: &str) {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let capture = path
+            .strip_prefix("/sessions/")
+            .and_then(|p| p.strip_suffix("/capture"))
+            .and_then(|id| id.parse::<u64>().ok());
+        if path == "/loglevel" {
+            return self.log_level(slot, method, query);
+        }
+        if path == "/bans" || path.starts_with("/bans/") {
+            return self.bans(slot, method, path, query);
+        }
+        if let Some(host) = path.strip_prefix("/dns-cache/") {
+            let conn = self.conns[slot].as_mut().unwrap();
+            return match method {
+                "DELETE" if !host.is_empty() && host.len() <= MAX_HOST => self.evict_dns(slot, host),
+                "DELETE" => conn.respond(400, JSON, r#"{"error":"expected /dns-cache/<host>"}"#),
+                _ => conn.respond(405, JSON, r#"{"error":"method not allowed"}"#),
+            };
+        }
+        match (method, capture) {
+            ("POST", Some(id)) => return self.capture(slot, id, query),
+            ("GET", None) | ("", _) => {}
+            _ => {
+                let conn = self.conns[slot].as_mut().unwrap();
+                return conn.respond(405, JSON, r#"{"error":"method not allowed"}"#);
+            }
+        }
+        match path {
+            "/stats" => {
+                let body = self.stats_json();
+                self.conns[slot].as_mut().unwrap().respond(200, JSON, &body);
+            }
+            "/healthz" => {
+                let stalled = self
+                    .stats
+                    .iter()
+                    .enumerate()
+                    .find_map(|(n, s)| s.tick_age().filter(|&age| age >= self.loop_stall).map(|age| (n, age)));
+                let conn = self.conns[slot].as_mut().unwrap();
+                match stalled {
+                    Some((n, age)) => {
+                        warn!("admin /healthz: worker {} loop stalled for {:.1}s", n, age.as_secs_f64());
+                        conn.respond(503, TEXT, &format!("worker {} stalled {:.1}s\n", n, age.as_secs_f64()))
+                    }
+                    None => conn.respond(200, TEXT, "ok\n"),
+                }
+            }
+            "/readyz" => {
+                let active: usize = self.stats.iter().map(|s| s.active_sessions.load(Ordering::Relaxed)).sum();
+                let conn = self.conns[slot].as_mut().unwrap();
+                if self.draining {
+                    conn.respond(503, TEXT, "draining\n");
+                } else if self.max_sessions.is_some_and(|max| active >= max) {
+                    conn.respond(503, TEXT, "at max sessions\n");
+                } else {
+                    conn.respond(200, TEXT, "ok\n");
+                }
+            }
+            "/top-hosts" => {
+                let (hosts, other) = top_hosts::top(&self.stats, TOP_HOSTS);
+                let body = top_hosts::json(&hosts, &other);
+                self.conns[slot].as_mut().unwrap().respond(200, JSON, &body);
+            }
+            "/loop-samples" => {
+                let body = loop_sampler::json(&self.stats, self.loop_sample_every);
+                self.conns[slot].as_mut().unwrap().respond(200, JSON, &body);
+            }
+            "/quota" => {
+                let body = quota::json(&self.quotas);
+                self.conns[slot].as_mut().unwrap().respond(200, JSON, &body);
+            }
+            "/limits" => {
+                let body = bandwidth::json();
+                self.conns[slot].as_mut().unwrap().respond(200, JSON, &body);
+            }
+            "/acl" => {
+                let body = acl_hits::json();
+                self.conns[slot].as_mut().unwrap().respond(200, JSON, &body);
+            }
+            "/parents" => {
+                let body = parent_health::json();
+                self.conns[slot].as_mut().unwrap().respond(200, JSON, &body);
+            }
+            "/failing-hosts" => {
+                let hosts = failing_hosts::worst(&self.stats, failing_hosts::minute(), FAILING_HOSTS);
+                self.conns[slot].as_mut().unwrap().respond(200, JSON, &failing_hosts::json(&hosts));
+            }
+            "/metrics" if self.prometheus => {
+                let summary = Summary::merge(self.stats.iter().map(|s| s.as_ref()));
+                let mut body = metrics::render(&summary, &usage::sample(&summary), self.nofile, &self.listen);
+                if body.len() > MAX_METRICS {
+                    warn!("admin /metrics is {} bytes, cut to {}", body.len(), MAX_METRICS);
+                    let end = body[..MAX_METRICS].rfind('\n').map_or(0, |i| i + 1);
+                    body.truncate(end);
+                }
+                self.conns[slot].as_mut().unwrap().respond(200, PROMETHEUS, &body);
+            }
+            "/dns-cache" => match dns_page(query, self.dns_cache_page) {
+                Some((after, limit)) => self.list_dns_cache(slot, after, limit),
+                None => self.conns[slot].as_mut().unwrap().respond(
+                    400,
+                    JSON,
+                    &format!(r#"{{"error":"expected after=<host>&limit=<1..{}>"}}"#, self.dns_cache_page),
+                ),
+            },
+            "/sessions" => match page(query) {
+                Some((after, limit)) => self.list_sessions(slot, after, limit),
+                None => self.conns[slot].as_mut().unwrap().respond(
+                    400,
+                    JSON,
+                    &format!(r#"{{"error":"expected after=<session id>&limit=<1..{}>"}}"#, SESSIONS_PAGE),
+                ),
+            },
+            "" => self.conns[slot]
+                .as_mut()
+                .unwrap()
+                .respond(400, JSON, r#"{"error":"bad request"}"#),
+            _ => self.conns[slot]
+                .as_mut()
+                .unwrap()
+                .respond(404, JSON, r#"{"error":"not found"}"#),
+        }
+    }
+
+    /// Asks every worker for its first `limit` sessions with an id above
+    /// `after`; the page is the lowest `limit` ids of all the answers.
+    fn list_sessions(&mut self, slot: usize, after: u64, limit: usize) {
+        let request = self.next_request;
+        self.next_request += 1;
+        let reply = self.workers[self.host].clone();
+        let mut remaining = 0;
+        for (id, w) in self.workers.iter().enumerate() {
+            let cmd = Command::ListSessions {
+                request,
+                after,
+                limit,
+                reply: reply.clone(),
+            };
+            match w.send(cmd) {
+                Ok(()) => remaining += 1,
+                Err(e) => debug!("list sessions of worker {} err {:?}", id, e),
+            }
+        }
+        let conn = self.conns[slot].as_mut().unwrap();
+        if remaining == 0 {
+            conn.respond(200, JSON, &sessions_json(&[], false));
+        } else {
+            conn.pending = Some(Pending {
+                request,
+                remaining,
+                work: Work::Sessions {
+                    limit,
+                    sessions: Vec::new(),
+                    more: false,
+                },
+            });
+        }
+    }
+
+    /// Asks every worker for its first `limit` cached hosts sorting after
+    /// `after`; the page is the first `limit` hosts of all the answers.
+    fn list_dns_cache(&mut self, slot: usize, after: String, limit: usize) {
+        let request = self.next_request;
+        self.next_request += 1;
+        let reply = self.workers[self.host].clone();
+        let mut remaining = 0;
+        for (id, w) in self.workers.iter().enumerate() {
+            let cmd = Command::ListDnsCache {
+                request,
+                after: after.clone(),
+                limit,
+                reply: reply.clone(),
+            };
+            match w.send(cmd) {
+                Ok(()) => remaining += 1,
+                Err(e) => debug!("list dns cache of worker {} err {:?}", id, e),
+            }
+        }
+        let conn = self.conns[slot].as_mut().unwrap();
+        if remaining == 0 {
+            conn.respond(200, JSON, &dns_cache_json(&[], false));
+        } else {
+            conn.pending = Some(Pending {
+                request,
+                remaining,
+                work: Work::DnsCache {
+                    limit,
+                    entries: Vec::new(),
+                    more: false,
+                },
+            });
+        }
+    }
+
+    /// Has every worker forget its answer for `host`.
+    fn evict_dns(&mut self, slot: usize, host: &str) {
+        let request = self.next_request;
+        self.next_request += 1;
+        let reply = self.workers[self.host].clone();
+        let mut remaining = 0;
+        for (id, w) in self.workers.iter().enumerate() {
+            let cmd = Command::EvictDns {
+                request,
+                host: host.to_owned(),
+                reply: reply.clone(),
+            };
+            match w.send(cmd) {
+                Ok(()) => remaining += 1,
+                Err(e) => debug!("evict dns on worker {} err {:?}", id, e),
+            }
+        }
+        let conn = self.conns[slot].as_mut().unwrap();
+        if remaining == 0 {
+            conn.respond(404, JSON, r#"{"error":"host not cached"}"#);
+        } else {
+            conn.pending = Some(Pending {
+                request,
+                remaining,
+                work: Work::EvictDns {
+                    host: host.to_owned(),
+                    evicted: 0,
+                },
+            });
+        }
+    }
+
+    /// Has every worker close the sessions of the client `ip` banned.
+    fn close_client(&mut self, slot: usize, ip: IpAddr, ban: bans::Ban) {
+        let request = self.next_request;
+        self.next_request += 1;
+        let reply = self.workers[self.host].clone();
+        let mut remaining = 0;
+        for (id, w) in self.workers.iter().enumerate() {
+            match w.send(Command::CloseClient {
+                request,
+                ip,
+                reply: reply.clone(),
+            }) {
+                Ok(()) => remaining += 1,
+                Err(e) => debug!("close client on worker {} err {:?}", id, e),
+            }
+        }
+        let conn = self.conns[slot].as_mut().unwrap();
+        if remaining == 0 {
+            conn.respond(200, JSON, &ban_json(ip, &ban, Some(0)));
+        } else {
+            conn.pending = Some(Pending {
+                request,
+                remaining,
+                work: Work::Ban { ip, ban, closed: 0 },
+            });
+        }
+    }
+
+    /// Has the worker with session `id` start capturing it, see
+    /// `Session::start_capture`. One session per request: there is no way
+    /// to capture more than that.
+    fn capture(&mut self, slot: usize, id: u64, query: &str) {
+        let Some(dir) = self.capture_dir.clone() else {
+            let conn = self.conns[slot].as_mut().unwrap();
+            return conn.respond(403, JSON, r#"{"error":"captures are off, see capture_dir"}"#);
+        };
+        let Some(limit) = capture_limit(query) else {
+            let conn = self.conns[slot].as_mut().unwrap();
+            return conn.respond(
+                400,
+                JSON,
+                &format!(r#"{{"error":"expected limit=<size up to {}>"}}"#, capture::MAX_LIMIT),
+            );
+        };
+        let request = self.next_request;
+        self.next_request += 1;
+        let reply = self.workers[self.host].clone();
+        let mut remaining = 0;
+        for (worker, w) in self.workers.iter().enumerate() {
+            let cmd = Command::Capture {
+                request,
+                id,
+                dir: dir.clone(),
+                limit,
+                reply: reply.clone(),
+            };
+            match w.send(cmd) {
+                Ok(()) => remaining += 1,
+                Err(e) => debug!("capture on worker {} err {:?}", worker, e),
+            }
+        }
+        let conn = self.conns[slot].as_mut().unwrap();
+        if remaining == 0 {
+            conn.respond(404, JSON, r#"{"error":"no such session"}"#);
+        } else {
+            conn.pending = Some(Pending {
+                request,
+                remaining,
+                work: Work::Capture { id, limit },
+            });
+        }
+    }
+
+    /// `GET` shows the filter, `PUT` with `level=<off..trace>` and
+    /// optionally `target=<module path>`, `for=<duration>` (else
+    /// `Config::log_level_revert`) and `persist=true` sets a level on top
+    /// of it, `DELETE` goes back to the configured one.
+    fn log_level(&mut self, slot: usize, method: &str, query: &str) {
+        let conn = self.conns[slot].as_mut().unwrap();
+        let filter = match method {
+            "GET" => logging::filter(),
+            "PUT" => match level_change(query) {
+                Some(c) => logging::override_level(
+                    c.target.as_deref(),
+                    c.level,
+                    c.revert_after.or(self.log_level_revert),
+                    c.persist,
+                ),
+                None => {
+                    return conn.respond(
+                        400,
+                        JSON,
+                        r#"{"error":"expected level=<off|error|warn|info|debug|trace>[&target=<module path>][&for=<duration>][&persist=true]"}"#,
+                    )
+                }
+            },
+            "DELETE" => {
+                logging::clear_override();
+                logging::filter()
+            }
+            _ => return conn.respond(405, JSON, r#"{"error":"method not allowed"}"#),
+        };
+        let body = format!(
+            r#"{{"filter":{},"revert_in_secs":{},"persist":{}}}"#,
+            json_str(&filter.directives),
+            filter
+                .revert_in
+                .map_or("null".to_owned(), |d| d.as_secs().to_string()),
+            filter.persist
+        );
+        conn.respond(200, JSON, &body);
+    }
+
+    fn flush(&mut self, registry: &Registry, slot: usize) {
+        let Some(conn) = self.conns[slot].as_mut() else {
+            return;
+        };
+        match conn.write_out() {
+            Ok(()) if conn.done() => self.close_conn(registry, slot),
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => {
+                debug!("admin conn {} err {:?}", slot, e);
+                self.close_conn(registry, slot);
+            }
+        }
+    }
+
+    fn close_conn(&mut self, registry: &Registry, slot: usize) {
+        if let Some(mut conn) = self.conns[slot].take() {
+            let _ = registry.deregister(&mut conn.sock);
+            let _ = conn.sock.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn stats_json(&self) -> String {
+        let s = Summary::merge(self.stats.iter().map(|s| s.as_ref()));
+        let listeners = self
+            .listen
+            .iter()
+            .enumerate()
+            .map(|(i, (addr, profile))| {
+                let l = s.listeners.get(i).copied().unwrap_or_default();
+                format!(
+                    concat!(
+                        r#"{{"addr":{},"profile":{},"accepted":{},"active_sessions":{},"#,
+                        r#""bytes_up":{},"bytes_down":{},"denied":{}}}"#
+                    ),
+                    json_str(addr),
+                    json_str(profile),
+                    l.accepted,
+                    l.active_sessions,
+                    l.bytes_up,
+                    l.bytes_down,
+                    l.denied
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let traffic = TrafficClass::ALL
+            .iter()
+            .map(|&c| {
+                format!(
+                    r#""{}":{{"sessions":{},"bytes_up":{},"bytes_down":{}}}"#,
+                    c.name(),
+                    s.class_sessions[c as usize],
+                    s.class_bytes_up[c as usize],
+                    s.class_bytes_down[c as usize]
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            concat!(
+                r#"{{"uptime_secs":{},"workers":{},"active_sessions":{},"#,
+                r#""sessions_opened":{},"sessions_closed":{},"bytes_up":{},"bytes_down":{},"#,
+                r#""dns":{{"hits":{},"misses":{},"failures":{}}},"traffic":{{{}}},"#,
+                r#""listeners":[{}],"polls":{},"events":{},"full_polls":{},"accept_cap_hits":{},"#,
+                r#""accepts_shed":{},"accept_pauses":{},"#,
+                r#""clients_refused":{},"conn_rate_limited":{},"banned_dropped":{},"sni_mismatches":{}}}"#
+            ),
+            self.started.elapsed().as_secs(),
+            self.stats.len(),
+            s.active_sessions,
+            s.sessions_opened,
+            s.sessions_closed,
+            s.bytes_up,
+            s.bytes_down,
+            s.dns_hits,
+            s.dns_misses,
+            s.dns_failures,
+            traffic,
+            listeners,
+            s.polls,
+            s.events,
+            s.full_polls,
+            s.accept_cap_hits,
+            s.accepts_shed,
+            s.accept_pauses,
+            s.clients_refused,
+            s.conn_rate_limited,
+            s.banned_dropped,
+            s.sni_mismatches
+        )
+    }
+}
+
+impl Conn {
+    /// Reads until the end of the request head. Returns the method and
+    /// request target then, an empty target for a request that cannot be
+    /// served.
+    fn read_head(&mut self) -> io::Result<Option<(String, String)>> {
+        let mut buf = [0u8; 1024];
+        loop {
+            match self.sock.read(&mut buf) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "closed")),
+                Ok(n) => self.head.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+            if self.head.len() > MAX_HEAD {
+                return Ok(Some(Default::default()));
+            }
+        }
+        let complete = self.head.windows(4).any(|w| w == b"\r\n\r\n")
+            || self.head.windows(2).any(|w| w == b"\n\n");
+        if !complete {
+            return Ok(None);
+        }
+        let line = self.head.split(|&b| b == b'\n').next().unwrap_or_default();
+        let line = String::from_utf8_lossy(line);
+        let mut parts = line.split_whitespace();
+        Ok(Some(match (parts.next(), parts.next()) {
+            (Some(method @ ("GET" | "POST" | "PUT" | "DELETE")), Some(target)) => {
+                (method.to_owned(), target.to_owned())
+            }
+            _ => Default::default(),
+        }))
+    }
+
+    fn respond(&mut self, status: u16, content_type: &str, body: &str) {
+        let reason = match status {
+            200 => "OK",
+            400 => "Bad Request",
+            403 => "Forbidden",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            500 => "Internal Server Error",
+            503 => "Service Unavailable",
+            _ => "Not Found",
+        };
+        self.out = format!(
+            "HTTP/1.0 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            reason,
+            content_type,
+            body.len(),
+            body
+        )
+        .into_bytes();
+        self.sent = 0;
+    }
+
+    fn write_out(&mut self) -> io::Result<()> {
+        while self.sent < self.out.len() {
+            match self.sock.write(&self.out[self.sent..]) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "write zero")),
+                Ok(n) => self.sent += n,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn done(&self) -> bool {
+        !self.out.is_empty() && self.sent == self.out.len()
+    }
+}
+
+/// `after=<id>&limit=<n>` of a `/sessions` request, either may be left
+/// out; None for anything else.
+fn page(query: &str) -> Option<(u64, usize)> {
+    let mut after = 0;
+    let mut limit = SESSIONS_PAGE;
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        match pair.split_once('=')? {
+            ("after", v) => after = v.parse().ok()?,
+            ("limit", v) => limit = v.parse().ok().filter(|n| (1..=SESSIONS_PAGE).contains(n))?,
+            _ => return None,
+        }
+    }
+    Some((after, limit))
+}
+
+/// `after=<host>&limit=<n>` of a `/dns-cache` request, `limit` up to
+/// `max` and `max` when left out; None for anything else.
+fn dns_page(query: &str, max: usize) -> Option<(String, usize)> {
+    let mut after = String::new();
+    let mut limit = max;
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        match pair.split_once('=')? {
+            ("after", v) if v.len() <= MAX_HOST => after = v.to_owned(),
+            ("limit", v) => limit = v.parse().ok().filter(|n| (1..=max).contains(n))?,
+            _ => return None,
+        }
+    }
+    Some((after, limit))
+}
+
+/// `limit=<size>` of a capture request, `parse_size` with an optional
+/// trailing `B` such as `1MB`, up to `capture::MAX_LIMIT`. Left out it is
+/// `capture::DEFAULT_LIMIT`; None for anything else.
+fn capture_limit(query: &str) -> Option<u64> {
+    let mut limit = capture::DEFAULT_LIMIT;
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        match pair.split_once('=')? {
+            ("limit", v) => {
+                let v = v.strip_suffix('B').filter(|v| !v.is_empty()).unwrap_or(v);
+                limit = config::parse_size(v).ok().filter(|&n| n <= capture::MAX_LIMIT)?;
+            }
+            _ => return None,
+        }
+    }
+    Some(limit)
+}
+
+/// The query of a `PUT /bans/<ip>`: how long, and whether to close the
+/// sessions the client has. None unless it has a valid `duration`.
+fn ban_request(query: &str) -> Option<(Duration, bool)> {
+    let (mut duration, mut close) = (None, false);
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        match pair.split_once('=')? {
+            ("duration", v) => duration = Some(config::parse_duration(v).ok().filter(|d| !d.is_zero())?),
+            ("close", v) => close = v.parse().ok()?,
+            _ => return None,
+        }
+    }
+    Some((duration?, close))
+}
+
+/// The answer to a `PUT /bans/<ip>`; `closed` counts the sessions closed,
+/// null when it did not ask to.
+fn ban_json(ip: IpAddr, ban: &bans::Ban, closed: Option<usize>) -> String {
+    let remaining = ban.until.duration_since(ban.since).unwrap_or_default();
+    format!(
+        r#"{{"client":"{}","since":"{}","remaining_secs":{},"closed":{}}}"#,
+        ip,
+        humantime::format_rfc3339_seconds(ban.since),
+        remaining.as_secs(),
+        closed.map_or("null".to_owned(), |n| n.to_string())
+    )
+}
+
+/// What a `PUT /loglevel` asks for.
+struct LevelChange {
+    target: Option<String>,
+    level: LevelFilter,
+    revert_after: Option<Duration>,
+    persist: bool,
+}
+
+/// The query of a `PUT /loglevel`, None unless it has a valid `level`.
+/// Targets are module paths such as `thin_proxy::session`.
+fn level_change(query: &str) -> Option<LevelChange> {
+    let module = |t: &str| !t.is_empty() && t.len() <= 128 && t.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':');
+    let (mut level, mut target, mut revert_after, mut persist) = (None, None, None, false);
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        match pair.split_once('=')? {
+            ("level", v) => level = Some(v.parse().ok()?),
+            ("target", v) => target = Some(Some(v).filter(|t| module(t))?.to_owned()),
+            ("for", v) => revert_after = Some(config::parse_duration(v).ok().filter(|d| !d.is_zero())?),
+            ("persist", v) => persist = v.parse().ok()?,
+            _ => return None,
+        }
+    }
+    Some(LevelChange {
+        target,
+        level: level?,
+        revert_after,
+        persist,
+    })
+}
+
+/// The `/sessions` body; `next_after` is the `after` of the following
+/// page, null on the last.
+fn sessions_json(sessions: &[SessionInfo], more: bool) -> String {
+    let mut out = String::from(r#"{"sessions":["#);
+    for (i, s) in sessions.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(
+            out,
+            concat!(
+                r#"{{"worker":{},"id":{},"client":{},"listener":{},"profile":{},"host":{},"state":{},"#,
+                r#""bytes_up":{},"bytes_down":{},"age_secs":{:.3},"idle_secs":{:.3},"#,
+                r#""pending_up":{},"pending_down":{},"fd":{},"up_fd":{},"mptcp":{}}}"#
+            ),
+            s.worker,
+            s.id,
+            json_str(&s.client.to_string()),
+            json_str(&s.listener),
+            json_str(&s.profile),
+            json_str(&s.host),
+            json_str(&s.state),
+            s.bytes_up,
+            s.bytes_down,
+            s.age.as_secs_f64(),
+            s.idle.as_secs_f64(),
+            s.pending_up,
+            s.pending_down,
+            s.fd,
+            s.up_fd.map_or("null".to_owned(), |fd| fd.to_string()),
+            s.mptcp.map_or("null".to_owned(), |m| m.to_string())
+        );
+    }
+    match sessions.last() {
+        Some(last) if more => {
+            let _ = write!(out, r#"],"next_after":{}}}"#, last.id);
+        }
+        _ => out.push_str(r#"],"next_after":null}"#),
+    }
+    out
+}
+
+/// The `/dns-cache` body; `next_after` is the `after` of the following
+/// page, null on the last. getaddrinfo tells no TTL and the cache holds
+/// nothing but its answers, hence the fixed `ttl_remaining_secs` and
+/// `source`.
+fn dns_cache_json(entries: &[CacheInfo], more: bool) -> String {
+    let mut out = String::from(r#"{"entries":["#);
+    for (i, e) in entries.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let ips = e.ips.iter().map(|ip| json_str(&ip.to_string())).collect::<Vec<_>>().join(",");
+        let _ = write!(
+            out,
+            concat!(
+                r#"{{"worker":{},"host":{},"ips":[{}],"age_secs":{:.3},"#,
+                r#""ttl_remaining_secs":null,"hits":{},"source":"resolver"}}"#
+            ),
+            e.worker,
+            json_str(&e.host),
+            ips,
+            e.age.as_secs_f64(),
+            e.hits
+        );
+    }
+    match entries.last() {
+        Some(last) if more => {
+            let _ = write!(out, r#"],"next_after":{}}}"#, json_str(&last.host));
+        }
+        _ => out.push_str(r#"],"next_after":null}"#),
+    }
+    out
+}
+
+pub fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,106 @@
+use std::{fmt::Display, str::FromStr};
+
+use log::{info, warn};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Affinity {
+    /// leave placement to the scheduler
+    Off,
+    /// worker n goes to the n-th core the process may run on, wrapping
+    Auto,
+    /// worker n goes to `cores[n % cores.len()]`
+    Cores(Vec<usize>),
+}
+
+impl FromStr for Affinity {
+    type Err = String;
+
+    /// `off`, `auto` or a comma separated core list such as `0,2,4`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Affinity::Off),
+            "auto" => Ok(Affinity::Auto),
+            _ => s
+                .split(',')
+                .map(|c| c.trim().parse::<usize>())
+                .collect::<Result<Vec<_>, _>>()
+                .map(Affinity::Cores)
+                .map_err(|_| format!("invalid affinity {:?}, expected off, auto or a core list like 0,2,4", s)),
+        }
+    }
+}
+
+impl Display for Affinity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Affinity::Off => f.write_str("off"),
+            Affinity::Auto => f.write_str("auto"),
+            Affinity::Cores(cores) => {
+                let cores = cores.iter().map(usize::to_string).collect::<Vec<_>>();
+                f.write_str(&cores.join(","))
+            }
+        }
+    }
+}
+
+/// Core worker `n` should be pinned to, if any.
+pub fn core_for(affinity: &Affinity, n: usize) -> Option<usize> {
+    match affinity {
+        Affinity::Off => None,
+        Affinity::Cores(cores) if cores.is_empty() => None,
+        Affinity::Cores(cores) => Some(cores[n % cores.len()]),
+        Affinity::Auto => {
+            let allowed = allowed_cores();
+            if allowed.is_empty() {
+                return None;
+            }
+            Some(allowed[n % allowed.len()])
+        }
+    }
+}
+
+/// Pins the calling thread to `core`. Failures are logged, never fatal.
+#[cfg(target_os = "linux")]
+pub fn pin_current(name: &str, core: usize) {
+    use nix::{
+        sched::{sched_setaffinity, CpuSet},
+        unistd::Pid,
+    };
+
+    let mut set = CpuSet::new();
+    let r = set
+        .set(core)
+        .and_then(|_| sched_setaffinity(Pid::from_raw(0), &set));
+    match r {
+        Ok(()) => info!("{} pinned to core {}", name, core),
+        Err(e) => warn!("cannot pin {} to core {}: {}", name, core, e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current(name: &str, core: usize) {
+    warn!("cpu affinity unsupported on this platform, {} not pinned to core {}", name, core);
+}
+
+#[cfg(target_os = "linux")]
+fn allowed_cores() -> Vec<usize> {
+    use nix::{
+        sched::{sched_getaffinity, CpuSet},
+        unistd::Pid,
+    };
+
+    match sched_getaffinity(Pid::from_raw(0)) {
+        Ok(set) => (0..CpuSet::count())
+            .filter(|&c| set.is_set(c).unwrap_or(false))
+            .collect(),
+        Err(e) => {
+            warn!("sched_getaffinity err {}", e);
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allowed_cores() -> Vec<usize> {
+    Vec::new()
+}
@@ -0,0 +1,148 @@
+use std::{
+    fmt::{self, Display},
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use crate::{
+    access_log::field,
+    client::Peer,
+    config::Config,
+    syslog::{self, Severity, Stream},
+};
+
+/// `Config::audit_log` once opened, None writes no audit lines.
+static FILE: Mutex<Option<AuditLog>> = Mutex::new(None);
+
+struct AuditLog {
+    path: PathBuf,
+    /// unbuffered, each line is written as it happens
+    out: File,
+}
+
+impl AuditLog {
+    fn open(path: &Path) -> io::Result<AuditLog> {
+        let out = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog {
+            path: path.to_owned(),
+            out,
+        })
+    }
+}
+
+/// Why a request was refused. New policy checks add theirs here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denial {
+    /// turned away at accept, `max_sessions` was reached
+    MaxSessions,
+    /// the request head did not parse or named no usable destination
+    BadRequest,
+    /// the destination is not allowed by `Config::acl`
+    Acl,
+    /// the destination port is not in `Config::allowed_ports`
+    Port,
+    /// the destination address is internal, see `Config::block_internal`
+    Internal,
+    /// turned away at accept by `Config::deny_clients` or `allow_clients`
+    Client,
+    /// no or wrong credentials with `Config::auth_file` set, or a client
+    /// certificate a TLS listener refused, see `Config::listener_client_ca`
+    Auth,
+    /// the user moved their `UserPolicy::daily_quota` today
+    Quota,
+    /// the tunnel's ClientHello named another host than its CONNECT, see
+    /// `Config::sni_check`
+    Sni,
+    /// the request target named another destination than its Host
+    /// header, see `Config::host_check`
+    Host,
+}
+
+impl Denial {
+    pub fn name(self) -> &'static str {
+        match self {
+            Denial::MaxSessions => "max-sessions",
+            Denial::BadRequest => "bad-request",
+            Denial::Acl => "acl",
+            Denial::Port => "port",
+            Denial::Internal => "internal",
+            Denial::Client => "client",
+            Denial::Auth => "auth",
+            Denial::Quota => "quota",
+            Denial::Sni => "sni",
+            Denial::Host => "host",
+        }
+    }
+}
+
+impl Display for Denial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Opens `config.audit_log`, or stops writing one when unset. Runs at
+/// startup and again on reload; on error the previous file stays.
+pub fn configure(config: &Config) -> io::Result<()> {
+    let file = config.audit_log.as_deref().map(AuditLog::open).transpose()?;
+    *FILE.lock().unwrap() = file;
+    Ok(())
+}
+
+/// Whether an audit log is written, which SIGUSR1 then reopens.
+pub fn enabled() -> bool {
+    FILE.lock().unwrap().is_some()
+}
+
+/// Starts over on the file at its configured path, after logrotate moved
+/// it away.
+pub fn reopen() -> io::Result<()> {
+    let mut file = FILE.lock().unwrap();
+    let Some(log) = file.as_mut() else {
+        return Ok(());
+    };
+    *log = AuditLog::open(&log.path)?;
+    Ok(())
+}
+
+/// The line for a request refused for `denial`, separate from the access
+/// log and written out right away; with `Config::audit_log_facility` it
+/// goes to syslog too. Fields are separated by one space, in this order,
+/// `-` where nothing is known:
+///
+/// 1. time of the refusal, RFC 3339 UTC with milliseconds
+/// 2. client `ip:port`, `local` for unix socket clients
+/// 3. requested `host:port`
+/// 4. reason, see `Denial`
+/// 5. the setting or rule that refused it
+/// 6. session id, as in the logs and the access log
+///
+/// New fields only ever go at the end.
+pub fn denied(client: &Peer, authority: Option<&str>, denial: Denial, rule: Option<&str>, id: Option<u64>) {
+    let mut file = FILE.lock().unwrap();
+    let to_syslog = syslog::wants(Stream::Audit);
+    if file.is_none() && !to_syslog {
+        return;
+    }
+    let mut line = format!(
+        "{} {} {} {} {} {}",
+        humantime::format_rfc3339_millis(SystemTime::now()),
+        client,
+        field(authority),
+        denial,
+        field(rule),
+        field(id.map(|id| id.to_string()).as_deref())
+    );
+    if to_syslog {
+        syslog::send(Stream::Audit, Severity::Notice, &line);
+    }
+    if let Some(log) = file.as_mut() {
+        line.push('\n');
+        if let Err(e) = log.out.write_all(line.as_bytes()) {
+            eprintln!("write audit log {} err {}", log.path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, BufRead, IsTerminal},
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use nix::sys::termios::{self, LocalFlags, SetArg};
+use sha2::{Digest, Sha256};
+
+/// A user's password hash as the credentials file has it.
+enum Hash {
+    /// PHC string, `$argon2id$v=19$...`
+    Argon2(String),
+    /// `$2b$<cost>$...`, 60 characters
+    Bcrypt(String),
+}
+
+impl Hash {
+    fn parse(s: &str) -> Option<Hash> {
+        if s.starts_with("$argon2") {
+            PasswordHash::new(s).ok()?;
+            Some(Hash::Argon2(s.to_owned()))
+        } else if ["$2a$", "$2b$", "$2y$"].iter().any(|p| s.starts_with(p)) && s.len() == 60 {
+            Some(Hash::Bcrypt(s.to_owned()))
+        } else {
+            None
+        }
+    }
+
+    /// Both libraries compare the hashes in constant time.
+    fn verify(&self, password: &[u8]) -> bool {
+        match self {
+            Hash::Argon2(h) => {
+                PasswordHash::new(h).is_ok_and(|h| Argon2::default().verify_password(password, &h).is_ok())
+            }
+            Hash::Bcrypt(h) => bcrypt::verify(password, h).unwrap_or(false),
+        }
+    }
+}
+
+/// The users of `Config::auth_file`, loaded at startup and on reload.
+pub struct Credentials {
+    path: PathBuf,
+    users: HashMap<String, Hash>,
+    /// verified for users without one of their own, so that an unknown
+    /// name takes as long as a wrong password
+    decoy: Hash,
+    /// SHA-256 of the password each user last got through with. Hashing
+    /// the password again runs into tens of milliseconds by design, too
+    /// long for a worker loop to spend on every request
+    verified: RwLock<HashMap<String, [u8; 32]>>,
+}
+
+impl Credentials {
+    /// Reads `username:hash` lines, argon2 or bcrypt hashes only; blank
+    /// lines and lines starting with `#` are skipped.
+    pub fn load(path: &Path) -> Result<Credentials, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("cannot read auth file {}: {}", path.display(), e))?;
+        let mut users = HashMap::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let at = || format!("auth file {} line {}", path.display(), n + 1);
+            let Some((user, hash)) = line.split_once(':').filter(|(u, _)| !u.is_empty()) else {
+                return Err(format!("{}: expected username:hash", at()));
+            };
+            let hash = Hash::parse(hash).ok_or_else(|| {
+                format!(
+                    "{}: the password of {:?} is not an argon2 or bcrypt hash, plaintext is refused; \
+                     make one with --hash-password {}",
+                    at(),
+                    user,
+                    user
+                )
+            })?;
+            if users.insert(user.to_owned(), hash).is_some() {
+                return Err(format!("{}: {:?} is listed twice", at(), user));
+            }
+        }
+        Ok(Credentials {
+            path: path.to_owned(),
+            users,
+            decoy: Hash::Argon2(hash_password(b"").map_err(|e| format!("cannot hash decoy password: {}", e))?),
+            verified: RwLock::new(HashMap::new()),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    /// The user a `Proxy-Authorization` value names when its password is
+    /// theirs, None for anything else: not Basic, badly encoded, an
+    /// unknown user or a wrong password.
+    pub fn verify(&self, authorization: &str) -> Option<&str> {
+        let (scheme, encoded) = authorization.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = base64_decode(encoded.trim())?;
+        let split = decoded.iter().position(|&b| b == b':')?;
+        self.check(std::str::from_utf8(&decoded[..split]).ok()?, &decoded[split + 1..])
+    }
+
+    /// The user named `user` when `password` is theirs, however the client
+    /// sent them: Basic credentials or a SOCKS5 username/password request.
+    pub fn check(&self, user: &str, password: &[u8]) -> Option<&str> {
+        let Some((name, hash)) = self.users.get_key_value(user) else {
+            self.decoy.verify(password);
+            return None;
+        };
+        let digest: [u8; 32] = Sha256::digest(password).into();
+        let seen = self.verified.read().unwrap().get(user).is_some_and(|d| constant_time_eq(d, &digest));
+        if seen {
+            return Some(name);
+        }
+        if !hash.verify(password) {
+            return None;
+        }
+        self.verified.write().unwrap().insert(user.to_owned(), digest);
+        Some(name)
+    }
+}
+
+/// Not the hashes, only how many users and from where.
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Credentials({} users from {})", self.users.len(), self.path.display())
+    }
+}
+
+/// An argon2id PHC string for `password`, with a fresh salt.
+pub fn hash_password(password: &[u8]) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password, &salt)
+        .map(|h| h.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// `--hash-password`: reads one password line from stdin, without echo
+/// on a terminal, and returns the auth file line for `user`.
+pub fn entry_from_stdin(user: &str) -> Result<String, String> {
+    if user.is_empty() || user.contains(':') {
+        return Err(format!("invalid user name {:?}, it cannot be empty or hold ':'", user));
+    }
+    let stdin = io::stdin();
+    let tty = stdin.is_terminal();
+    let saved = if tty {
+        eprint!("password for {}: ", user);
+        let saved = termios::tcgetattr(&stdin).map_err(|e| format!("cannot read terminal settings: {}", e))?;
+        let mut quiet = saved.clone();
+        quiet.local_flags.remove(LocalFlags::ECHO);
+        termios::tcsetattr(&stdin, SetArg::TCSANOW, &quiet).map_err(|e| format!("cannot turn off echo: {}", e))?;
+        Some(saved)
+    } else {
+        None
+    };
+    let mut line = String::new();
+    let read = stdin.lock().read_line(&mut line);
+    if let Some(saved) = saved {
+        let _ = termios::tcsetattr(&stdin, SetArg::TCSANOW, &saved);
+        eprintln!();
+    }
+    read.map_err(|e| format!("cannot read password: {}", e))?;
+    let password = line.strip_suffix('\n').map(|l| l.strip_suffix('\r').unwrap_or(l)).unwrap_or(&line);
+    if password.is_empty() {
+        return Err("empty password".to_owned());
+    }
+    Ok(format!("{}:{}", user, hash_password(password.as_bytes())?))
+}
+
+fn constant_time_eq(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Standard alphabet with padding, the form Basic credentials take.
+pub fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let s = s.as_bytes();
+    if !s.len().is_multiple_of(4) {
+        return None;
+    }
+    let value = |c: u8| match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    };
+    let mut out = Vec::with_capacity(s.len() / 4 * 3);
+    for (i, chunk) in s.chunks(4).enumerate() {
+        let last = i == s.len() / 4 - 1;
+        let pad = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if pad > 2 || (pad > 0 && !last) {
+            return None;
+        }
+        let mut n = 0u32;
+        for &c in &chunk[..4 - pad] {
+            n = n << 6 | u32::from(value(c)?);
+        }
+        n <<= 6 * pad;
+        out.extend_from_slice(&n.to_be_bytes()[1..4 - pad]);
+    }
+    Some(out)
+}
@@ -0,0 +1,243 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Write as _},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{
+    acl::AclRule,
+    admin::json_str,
+    config::{parse_size, Config},
+    stats::since_epoch,
+};
+
+/// Smallest burst of a limit, one splice's worth: less and every pump
+/// would overdraw the bucket.
+const MIN_BURST: i64 = 8 * 1024;
+
+/// Shortest wait of a throttled direction, so a slow limit does not have
+/// the loop wake for every few bytes.
+const MIN_WAIT: Duration = Duration::from_millis(5);
+
+/// The limits of the acl entries that have one, by `AclRule::label`,
+/// shared by every worker.
+static LIMITS: Mutex<BTreeMap<String, Arc<Limit>>> = Mutex::new(BTreeMap::new());
+
+/// The `limit` of an acl entry in bytes per second, `500KBps` or `2MBps`
+/// (powers of 1024 like every size).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bandwidth(pub u64);
+
+impl FromStr for Bandwidth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let size = s
+            .strip_suffix("Bps")
+            .or_else(|| s.strip_suffix("B/s"))
+            .ok_or_else(|| format!("invalid limit {:?}, expected bytes per second like 500KBps or 2MBps", s))?;
+        parse_size(size).map(Bandwidth)
+    }
+}
+
+impl Display for Bandwidth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (n, unit) = [(30, "G"), (20, "M"), (10, "K")]
+            .into_iter()
+            .find(|&(shift, _)| self.0.is_multiple_of(1 << shift))
+            .map_or((self.0, ""), |(shift, unit)| (self.0 >> shift, unit));
+        write!(f, "{}{}Bps", n, unit)
+    }
+}
+
+/// A token bucket of bytes one acl entry's sessions draw from together,
+/// in every worker: the tokens are an atomic counter rather than behind
+/// a lock, it is taken for every pump of a limited session.
+pub struct Limit {
+    per_sec: AtomicU64,
+    /// bytes that may move now; below zero when pumps moved more than
+    /// there was, which the next ones wait off
+    tokens: AtomicI64,
+    /// `since_epoch` in nanoseconds up to which time became tokens
+    refilled: AtomicU64,
+    /// sessions drawing from it, see `Throttle`
+    sessions: AtomicU64,
+    moved: AtomicU64,
+    /// bytes moved in the second `second` and in the one before it, for
+    /// the usage on `/limits`
+    second: AtomicU64,
+    this_second: AtomicU64,
+    last_second: AtomicU64,
+}
+
+impl Limit {
+    fn new(per_sec: u64) -> Limit {
+        Limit {
+            per_sec: AtomicU64::new(per_sec),
+            tokens: AtomicI64::new(burst(per_sec)),
+            refilled: AtomicU64::new(since_epoch().as_nanos() as u64),
+            sessions: AtomicU64::new(0),
+            moved: AtomicU64::new(0),
+            second: AtomicU64::new(0),
+            this_second: AtomicU64::new(0),
+            last_second: AtomicU64::new(0),
+        }
+    }
+
+    /// Turns the time since the last refill into tokens, up to the burst.
+    /// Only whole bytes are taken off the clock, the rest is left for
+    /// the next call, so frequent pumps do not round the rate down.
+    fn refill(&self, now: u64) {
+        let per_sec = self.per_sec.load(Ordering::Relaxed);
+        let last = self.refilled.load(Ordering::Relaxed);
+        let elapsed = u128::from(now.saturating_sub(last));
+        let add = elapsed * u128::from(per_sec) / 1_000_000_000;
+        if add == 0 {
+            return;
+        }
+        let used = (add * 1_000_000_000 / u128::from(per_sec)) as u64;
+        // another worker refilling for the same time wins, once is enough
+        if self
+            .refilled
+            .compare_exchange(last, last + used, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            return;
+        }
+        let burst = burst(per_sec);
+        let add = i64::try_from(add).unwrap_or(i64::MAX);
+        let _ = self
+            .tokens
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |t| Some(t.saturating_add(add).min(burst)));
+    }
+
+    /// How many bytes a pump may move now, or how long to wait for some.
+    /// It may move a little more, `spend` takes what it did.
+    pub fn allowance(&self) -> Result<usize, Duration> {
+        self.refill(since_epoch().as_nanos() as u64);
+        let tokens = self.tokens.load(Ordering::Relaxed);
+        if tokens > 0 {
+            return Ok(tokens as usize);
+        }
+        let per_sec = self.per_sec.load(Ordering::Relaxed);
+        let want = (MIN_BURST - tokens) as f64;
+        Err(Duration::from_secs_f64(want / per_sec as f64).max(MIN_WAIT))
+    }
+
+    /// Takes `bytes` a pump moved out of the bucket.
+    pub fn spend(&self, bytes: usize) {
+        let bytes = bytes as u64;
+        self.tokens.fetch_sub(bytes as i64, Ordering::Relaxed);
+        self.moved.fetch_add(bytes, Ordering::Relaxed);
+        let now = since_epoch().as_secs();
+        let second = self.second.load(Ordering::Relaxed);
+        if second != now
+            && self
+                .second
+                .compare_exchange(second, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            let done = self.this_second.swap(0, Ordering::Relaxed);
+            self.last_second.store(if second + 1 == now { done } else { 0 }, Ordering::Relaxed);
+        }
+        self.this_second.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Bytes moved in the last whole second.
+    fn usage(&self) -> u64 {
+        let now = since_epoch().as_secs();
+        let second = self.second.load(Ordering::Relaxed);
+        if second == now {
+            self.last_second.load(Ordering::Relaxed)
+        } else if second + 1 == now {
+            self.this_second.load(Ordering::Relaxed)
+        } else {
+            0
+        }
+    }
+}
+
+/// A tenth of a second at `per_sec`, at least `MIN_BURST`.
+fn burst(per_sec: u64) -> i64 {
+    i64::try_from(per_sec / 10).unwrap_or(i64::MAX).max(MIN_BURST)
+}
+
+/// A session's hold on the limit of the acl entry that let it through,
+/// counted in `/limits` until the session ends.
+pub struct Throttle(Arc<Limit>);
+
+impl Throttle {
+    pub fn limit(&self) -> &Limit {
+        &self.0
+    }
+}
+
+impl Drop for Throttle {
+    fn drop(&mut self) {
+        self.0.sessions.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Takes the limits of the acl entries in `config`. The bucket of an
+/// entry with the same label is kept over a reload, with the new rate;
+/// sessions of an entry that is gone keep to its old limit until they
+/// end.
+pub fn configure(config: &Config) {
+    let rules = config.acl_sets.values().flat_map(|s| s.rules.iter()).chain(&config.acl);
+    let wanted = rules
+        .filter_map(|r| Some((r.label.as_str(), r.limit?.0)))
+        .collect::<BTreeMap<_, _>>();
+    let mut limits = LIMITS.lock().unwrap();
+    limits.retain(|label, _| wanted.contains_key(label.as_str()));
+    for (label, per_sec) in wanted {
+        match limits.get(label) {
+            Some(l) => {
+                l.per_sec.store(per_sec, Ordering::Relaxed);
+                let burst = burst(per_sec);
+                let _ = l
+                    .tokens
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |t| Some(t.min(burst)));
+            }
+            None => {
+                limits.insert(label.to_owned(), Arc::new(Limit::new(per_sec)));
+            }
+        }
+    }
+}
+
+/// A hold on the limit of `rule` for a session it let through, None
+/// when it has none.
+pub fn throttle(rule: &AclRule) -> Option<Throttle> {
+    rule.limit?;
+    let limit = Arc::clone(LIMITS.lock().unwrap().get(&rule.label)?);
+    limit.sessions.fetch_add(1, Ordering::Relaxed);
+    Some(Throttle(limit))
+}
+
+/// The `/limits` body: each limited acl entry by label, with its rate,
+/// the sessions drawing from it and what they moved.
+pub fn json() -> String {
+    let limits = LIMITS.lock().unwrap();
+    let mut out = String::from(r#"{"limits":["#);
+    for (i, (label, l)) in limits.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(
+            out,
+            r#"{{"rule":{},"limit":{},"bytes_per_sec":{},"sessions":{},"bytes":{}}}"#,
+            json_str(label),
+            l.per_sec.load(Ordering::Relaxed),
+            l.usage(),
+            l.sessions.load(Ordering::Relaxed),
+            l.moved.load(Ordering::Relaxed)
+        );
+    }
+    out.push_str("]}");
+    out
+}
@@ -0,0 +1,285 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write as _,
+    fs,
+    io::{self, Write},
+    net::IpAddr,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+
+use crate::{config::Config, internal_addrs::Cidr};
+
+/// Sources whose failures are tracked at most; past it the one that
+/// failed least recently is forgotten.
+const MAX_TRACKED: usize = 65536;
+
+/// Bans held at most. A ban from the admin endpoint past it is refused,
+/// one for failed authentication replaces the ban ending soonest.
+pub const MAX_BANS: usize = 10_000;
+
+/// Clients failing authentication, the ones banned for it and the ones
+/// banned on the admin endpoint, shared by every worker. Kept over a
+/// reload, lost on restart unless `Config::auth_ban_state` is set.
+static BANS: Mutex<Bans> = Mutex::new(Bans {
+    failures: None,
+    banned: None,
+    limit: None,
+    window: Duration::ZERO,
+    ban_for: Duration::ZERO,
+    exempt: Vec::new(),
+});
+
+struct Bans {
+    /// times of the failures within `window`, per source
+    failures: Option<HashMap<IpAddr, VecDeque<Instant>>>,
+    banned: Option<HashMap<IpAddr, Ban>>,
+    /// `Config::auth_ban_failures`, None bans nobody
+    limit: Option<u32>,
+    window: Duration,
+    ban_for: Duration,
+    exempt: Vec<Cidr>,
+}
+
+/// A source refused at accept until `until`.
+#[derive(Debug, Clone, Copy)]
+pub struct Ban {
+    pub since: SystemTime,
+    pub until: SystemTime,
+    /// the failures that got it banned, 0 for a ban from the admin
+    /// endpoint
+    pub failures: u32,
+}
+
+/// Takes the limits from `config`; existing bans run to their end.
+pub fn configure(config: &Config) {
+    let mut bans = BANS.lock().unwrap();
+    bans.limit = config.auth_ban_failures;
+    bans.window = config.auth_ban_window;
+    bans.ban_for = config.auth_ban_for;
+    bans.exempt = config.auth_ban_exempt.clone();
+    if bans.limit.is_none() {
+        bans.failures = None;
+    }
+}
+
+/// Whether connections from `ip` are to be dropped.
+pub fn banned(ip: IpAddr) -> bool {
+    let mut bans = BANS.lock().unwrap();
+    let Some(banned) = bans.banned.as_mut() else {
+        return false;
+    };
+    match banned.get(&ip) {
+        Some(ban) if ban.until > SystemTime::now() => true,
+        Some(_) => {
+            info!(client:% = ip; "ban expired");
+            banned.remove(&ip);
+            false
+        }
+        None => false,
+    }
+}
+
+/// Counts a failed authentication from `ip`, banning it once it failed
+/// `Config::auth_ban_failures` times within `auth_ban_window`.
+pub fn failed(ip: IpAddr, now: Instant) {
+    let mut bans = BANS.lock().unwrap();
+    let Some(limit) = bans.limit else {
+        return;
+    };
+    if bans.exempt.iter().any(|c| c.contains(ip)) {
+        return;
+    }
+    let (window, ban_for) = (bans.window, bans.ban_for);
+    let failures = bans.failures.get_or_insert_with(HashMap::new);
+    if !failures.contains_key(&ip) && failures.len() >= MAX_TRACKED {
+        failures.retain(|_, times| times.back().is_some_and(|&t| now.duration_since(t) < window));
+        if failures.len() >= MAX_TRACKED {
+            let stalest = failures.iter().min_by_key(|(_, times)| times.back().copied()).map(|(&ip, _)| ip);
+            if let Some(ip) = stalest {
+                failures.remove(&ip);
+            }
+        }
+    }
+    let times = failures.entry(ip).or_default();
+    times.push_back(now);
+    while times.front().is_some_and(|&t| now.duration_since(t) >= window) {
+        times.pop_front();
+    }
+    let count = times.len() as u32;
+    if count < limit {
+        return;
+    }
+    failures.remove(&ip);
+    let since = SystemTime::now();
+    warn!(
+        client:% = ip, failures = count;
+        "banning client for {:?}, {} authentication failures within {:?}", ban_for, count, window
+    );
+    let banned = bans.banned.get_or_insert_with(HashMap::new);
+    if !banned.contains_key(&ip) && banned.len() >= MAX_BANS {
+        banned.retain(|_, ban| ban.until > since);
+        if banned.len() >= MAX_BANS {
+            let soonest = banned.iter().min_by_key(|(_, ban)| ban.until).map(|(&ip, _)| ip);
+            if let Some(ip) = soonest {
+                banned.remove(&ip);
+            }
+        }
+    }
+    banned.insert(
+        ip,
+        Ban {
+            since,
+            until: since + ban_for,
+            failures: count,
+        },
+    );
+}
+
+/// Bans `ip` for `duration` from now, replacing a ban it has, as the
+/// admin `PUT /bans/<ip>` does. Err when `MAX_BANS` others are running.
+pub fn ban(ip: IpAddr, duration: Duration) -> Result<Ban, String> {
+    let mut bans = BANS.lock().unwrap();
+    let since = SystemTime::now();
+    let banned = bans.banned.get_or_insert_with(HashMap::new);
+    if !banned.contains_key(&ip) && banned.len() >= MAX_BANS {
+        banned.retain(|_, ban| ban.until > since);
+        if banned.len() >= MAX_BANS {
+            return Err(format!("{} bans running, lift some first", MAX_BANS));
+        }
+    }
+    let until = since.checked_add(duration).ok_or("ban duration too long")?;
+    let ban = Ban {
+        since,
+        until,
+        failures: 0,
+    };
+    banned.insert(ip, ban);
+    Ok(ban)
+}
+
+/// Forgets the bans that ended by `now`, with a line for each; `banned`
+/// only finds them for their client's next connection. Called from a
+/// worker's heartbeat.
+pub fn expire(now: SystemTime) {
+    let mut bans = BANS.lock().unwrap();
+    if let Some(banned) = bans.banned.as_mut() {
+        banned.retain(|ip, ban| {
+            let running = ban.until > now;
+            if !running {
+                info!(client:% = ip; "ban expired");
+            }
+            running
+        });
+    }
+}
+
+/// The bans still running, soonest to end first.
+pub fn list() -> Vec<(IpAddr, Ban)> {
+    let mut bans = BANS.lock().unwrap();
+    let now = SystemTime::now();
+    let Some(banned) = bans.banned.as_mut() else {
+        return Vec::new();
+    };
+    banned.retain(|_, ban| ban.until > now);
+    let mut list = banned.iter().map(|(&ip, &ban)| (ip, ban)).collect::<Vec<_>>();
+    list.sort_by_key(|&(ip, ban)| (ban.until, ip));
+    list
+}
+
+/// Lifts the ban of `ip`, every ban on None. Returns how many there were.
+pub fn lift(ip: Option<IpAddr>) -> usize {
+    let mut bans = BANS.lock().unwrap();
+    let Some(banned) = bans.banned.as_mut() else {
+        return 0;
+    };
+    match ip {
+        Some(ip) => usize::from(banned.remove(&ip).is_some()),
+        None => {
+            let n = banned.len();
+            banned.clear();
+            n
+        }
+    }
+}
+
+/// The `/bans` body.
+pub fn json(bans: &[(IpAddr, Ban)]) -> String {
+    let now = SystemTime::now();
+    let mut out = String::from(r#"{"bans":["#);
+    for (i, (ip, ban)) in bans.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(
+            out,
+            r#"{{"client":"{}","since":"{}","remaining_secs":{},"source":"{}","failures":{}}}"#,
+            ip,
+            humantime::format_rfc3339_seconds(ban.since),
+            ban.until.duration_since(now).unwrap_or_default().as_secs(),
+            if ban.failures == 0 { "admin" } else { "auth" },
+            ban.failures
+        );
+    }
+    out.push_str("]}");
+    out
+}
+
+/// Writes the running bans to `path`, through a temporary file: one
+/// `<ip> <since> <until> <failures>` line each, times in unix seconds.
+pub fn save(path: &Path) -> io::Result<()> {
+    let mut text = String::new();
+    for (ip, ban) in list() {
+        let secs = |t: SystemTime| t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let _ = writeln!(text, "{} {} {} {}", ip, secs(ban.since), secs(ban.until), ban.failures);
+    }
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(text.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+/// Takes back the bans `save` wrote that have not ended since; a missing
+/// file is a first start.
+pub fn restore(path: &Path) {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+        Err(e) => {
+            warn!("cannot read ban state {}, start without bans: {}", path.display(), e);
+            return;
+        }
+    };
+    let now = SystemTime::now();
+    let at = |secs: &str| secs.parse().ok().map(|s| UNIX_EPOCH + Duration::from_secs(s));
+    let mut bans = BANS.lock().unwrap();
+    let banned = bans.banned.get_or_insert_with(HashMap::new);
+    let mut restored = 0;
+    for line in text.lines() {
+        let fields = line.split(' ').collect::<Vec<_>>();
+        let ban = match fields[..] {
+            [ip, since, until, failures] => (|| {
+                let ban = Ban {
+                    since: at(since)?,
+                    until: at(until)?,
+                    failures: failures.parse().ok()?,
+                };
+                Some((ip.parse::<IpAddr>().ok()?, ban))
+            })(),
+            _ => None,
+        };
+        match ban {
+            Some((ip, ban)) if ban.until > now => {
+                banned.insert(ip, ban);
+                restored += 1;
+            }
+            Some(_) => {}
+            None => warn!("ban state {}: skipping bad line {:?}", path.display(), line),
+        }
+    }
+    info!("ban state: {} running bans from {}", restored, path.display());
+}
@@ -0,0 +1,78 @@
+use std::{fmt::Display, hint, str::FromStr, thread};
+
+use log::warn;
+use mio::net::TcpStream;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PollMode {
+    /// sleep in the poll until an event or the next timer deadline
+    Block,
+    /// poll with a zero timeout in a tight loop, burning the worker's core
+    /// to save the wakeup latency; timers are still checked every round.
+    /// `yield_cpu` calls sched_yield between empty polls instead of a PAUSE
+    /// hint, which is kinder to other threads sharing the core
+    Spin { yield_cpu: bool },
+}
+
+impl FromStr for PollMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(PollMode::Block),
+            "spin" => Ok(PollMode::Spin { yield_cpu: false }),
+            "spin-yield" => Ok(PollMode::Spin { yield_cpu: true }),
+            _ => Err(format!("unknown poll mode {:?}, expected block, spin or spin-yield", s)),
+        }
+    }
+}
+
+impl Display for PollMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PollMode::Block => "block",
+            PollMode::Spin { yield_cpu: false } => "spin",
+            PollMode::Spin { yield_cpu: true } => "spin-yield",
+        })
+    }
+}
+
+/// Called after an empty poll in `PollMode::Spin`.
+pub fn spin_hint(yield_cpu: bool) {
+    if yield_cpu {
+        thread::yield_now();
+    } else {
+        hint::spin_loop();
+    }
+}
+
+/// Sets SO_BUSY_POLL so blocking receives on `sock` busy-wait the device
+/// queue for up to `usecs`. Needs CAP_NET_ADMIN to raise it above the
+/// sysctl net.core.busy_read default; failures are logged, never fatal.
+#[cfg(target_os = "linux")]
+pub fn set_socket_busy_poll(sock: &TcpStream, usecs: u32) {
+    use std::{io, os::fd::AsRawFd};
+
+    use nix::libc;
+
+    let val = usecs as libc::c_int;
+    // SAFETY: valid fd for the lifetime of `sock`, the option value is a
+    // c_int living on the stack for the duration of the call
+    let r = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BUSY_POLL,
+            &val as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if r != 0 {
+        warn!("SO_BUSY_POLL {}us on fd {} err {}", usecs, sock.as_raw_fd(), io::Error::last_os_error());
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_socket_busy_poll(_sock: &TcpStream, usecs: u32) {
+    warn!("SO_BUSY_POLL unsupported on this platform, ignoring {}us", usecs);
+}
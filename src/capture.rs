@@ -0,0 +1,112 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+
+use crate::client::Peer;
+
+/// What `POST /sessions/<id>/capture` records without a `limit`.
+pub const DEFAULT_LIMIT: u64 = 1 << 20;
+
+/// The largest `limit` a capture may ask for.
+pub const MAX_LIMIT: u64 = 64 << 20;
+
+/// Why a session could not start a capture.
+#[derive(Debug)]
+pub enum CaptureError {
+    /// one is running already, into this file
+    Running(PathBuf),
+    /// the file could not be created
+    Io(String),
+}
+
+/// The bytes one session moved, teed into a file under `Config::capture_dir`
+/// by its buffered copy. The file starts with text lines naming the
+/// session, ended by an empty line:
+///
+/// ```text
+/// thin_proxy capture 1
+/// session 1742
+/// client 10.0.0.7:50834
+/// host example.com:443
+/// started 2026-10-14T13:37:01.017Z
+/// limit 1048576
+/// ```
+///
+/// then one record per read: `>` for client to upstream or `<` for
+/// upstream to client, the time as big-endian u64 µs since the epoch, the
+/// length as big-endian u32, and the bytes. Recording stops at `limit`
+/// payload bytes; the file is flushed and closed when the capture is
+/// dropped, with its session at the latest.
+pub struct Capture {
+    path: PathBuf,
+    out: BufWriter<File>,
+    /// payload bytes recorded, at most `limit`
+    written: u64,
+    limit: u64,
+}
+
+impl Capture {
+    /// Creates `session-<id>-<unix time>.cap` in `dir`, readable by the
+    /// proxy's user only; an existing file is never overwritten.
+    pub fn start(dir: &Path, id: u64, client: &Peer, host: &str, limit: u64) -> io::Result<Capture> {
+        let now = SystemTime::now();
+        let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let path = dir.join(format!("session-{}-{}.cap", id, secs));
+        let file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path)?;
+        let mut out = BufWriter::new(file);
+        write!(
+            out,
+            "thin_proxy capture 1\nsession {}\nclient {}\nhost {}\nstarted {}\nlimit {}\n\n",
+            id,
+            client,
+            if host.is_empty() { "-" } else { host },
+            humantime::format_rfc3339_millis(now),
+            limit
+        )?;
+        info!("capture of session {} to {}, {} bytes at most", id, path.display(), limit);
+        Ok(Capture {
+            path,
+            out,
+            written: 0,
+            limit,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Records `data`, read from the client when `up`. False once the
+    /// capture is over, at its limit or on a write error; drop it then.
+    pub fn record(&mut self, up: bool, data: &[u8]) -> bool {
+        let take = data.len().min((self.limit - self.written) as usize);
+        let micros = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+        let written = self
+            .out
+            .write_all(if up { b">" } else { b"<" })
+            .and_then(|()| self.out.write_all(&micros.to_be_bytes()))
+            .and_then(|()| self.out.write_all(&(take as u32).to_be_bytes()))
+            .and_then(|()| self.out.write_all(&data[..take]));
+        if let Err(e) = written {
+            warn!("capture {} write err {}, stopping", self.path.display(), e);
+            return false;
+        }
+        self.written += take as u64;
+        self.written < self.limit
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        if let Err(e) = self.out.flush() {
+            warn!("capture {} flush err {}", self.path.display(), e);
+        }
+        info!("capture {} done, {} bytes", self.path.display(), self.written);
+    }
+}
@@ -0,0 +1,285 @@
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use clap::Parser;
+
+use crate::{
+    affinity::Affinity,
+    busy_poll::PollMode,
+    config::{parse_duration, parse_mode, parse_size, AcceptMode, Config, ConfigFormat},
+    logging::LogFormat,
+    metrics::Export,
+    syslog::{self, Facility},
+};
+
+/// Zero-copy HTTP CONNECT proxy.
+///
+/// Every option defaults to the built-in value, so running without any
+/// keeps the behavior of earlier releases: listen on 0.0.0.0:7788 with one
+/// worker per core.
+#[derive(Parser, Debug, Clone)]
+#[command(version, about)]
+pub struct Cli {
+    /// Address to accept clients on, repeat to listen on several
+    /// [default: 0.0.0.0:7788]
+    #[arg(long, value_name = "ADDR:PORT")]
+    pub listen: Vec<SocketAddr>,
+
+    /// Set IPV6_V6ONLY on IPv6 listeners instead of taking IPv4 clients
+    /// on them too
+    #[arg(long)]
+    pub ipv6_only: bool,
+
+    /// Also accept local clients on this unix socket
+    #[arg(long, value_name = "PATH")]
+    pub listen_unix: Option<PathBuf>,
+
+    /// Octal permission bits for the unix socket, e.g. 0660
+    #[arg(long, value_name = "MODE", value_parser = parse_mode)]
+    pub listen_unix_mode: Option<u32>,
+
+    /// Owner of the unix socket: user, user:group or :group
+    #[arg(long, value_name = "OWNER")]
+    pub listen_unix_owner: Option<String>,
+
+    /// Worker event loops [default: number of cores]
+    #[arg(long, value_name = "N")]
+    pub workers: Option<usize>,
+
+    /// How clients reach the workers: reuseport or acceptor
+    #[arg(long, value_name = "MODE")]
+    pub accept_mode: Option<AcceptMode>,
+
+    /// Cap on concurrent sessions [default: derived from the fd limit]
+    #[arg(long, value_name = "N")]
+    pub max_sessions: Option<usize>,
+
+    /// RLIMIT_NOFILE to request [default: the hard limit]
+    #[arg(long, value_name = "N")]
+    pub nofile: Option<u64>,
+
+    /// Close sessions idle for this long, e.g. 300s or 5m
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub idle_timeout: Option<Duration>,
+
+    /// Time the old process serves its sessions after an upgrade
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub drain_timeout: Option<Duration>,
+
+    /// Serve /stats, /sessions, /top-hosts and /metrics here, keep it on
+    /// loopback
+    #[arg(long, value_name = "ADDR:PORT")]
+    pub admin: Option<SocketAddr>,
+
+    /// Metrics export: prometheus (/metrics on --admin), statsd, both or
+    /// none [default: prometheus]
+    #[arg(long, value_name = "EXPORT")]
+    pub metrics: Option<Export>,
+
+    /// Send statsd datagrams here every stats tick (host:port)
+    #[arg(long, value_name = "HOST:PORT")]
+    pub statsd: Option<String>,
+
+    /// Pin workers: off, auto, or a core list like 0,2,4
+    #[arg(long, value_name = "CORES")]
+    pub worker_affinity: Option<Affinity>,
+
+    /// Core for the acceptor thread in acceptor mode
+    #[arg(long, value_name = "CORE")]
+    pub acceptor_core: Option<usize>,
+
+    /// Waiting for events: block, spin, or spin-yield
+    #[arg(long, value_name = "MODE")]
+    pub poll_mode: Option<PollMode>,
+
+    /// SO_BUSY_POLL in microseconds for accepted sockets
+    #[arg(long, value_name = "USECS")]
+    pub so_busy_poll: Option<u32>,
+
+    /// Detach from the terminal and run in the background; stdio goes to
+    /// the log file or /dev/null
+    #[arg(long)]
+    pub daemon: bool,
+
+    /// Write the pid here, refuse to start while a live process holds it
+    #[arg(long, value_name = "PATH")]
+    pub pidfile: Option<PathBuf>,
+
+    /// Route `via-parent` destinations through this HTTP proxy (host:port);
+    /// credentials come from the config file or environment
+    #[arg(long, value_name = "HOST:PORT")]
+    pub parent_proxy: Option<String>,
+
+    /// Route `via-socks-parent` destinations through this SOCKS5 proxy
+    /// (host:port); credentials come from the config file or environment
+    #[arg(long, value_name = "HOST:PORT")]
+    pub parent_socks: Option<String>,
+
+    /// Drop to this user (name or uid) once the listeners are bound;
+    /// needs starting as root
+    #[arg(long, value_name = "USER")]
+    pub user: Option<String>,
+
+    /// Group to drop to with --user, default the user's primary group
+    #[arg(long, value_name = "GROUP")]
+    pub group: Option<String>,
+
+    /// Log to this file instead of stderr; SIGUSR1 reopens it
+    #[arg(long, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+
+    /// Log to syslog at this facility (daemon, local0, ...) instead of
+    /// stderr
+    #[arg(long, value_name = "FACILITY")]
+    pub log_facility: Option<Facility>,
+
+    /// Syslog target: a socket path, udp://host:port or tcp://host:port
+    /// [default: /dev/log]
+    #[arg(long, value_name = "TARGET")]
+    pub syslog: Option<syslog::Target>,
+
+    /// Log line format: text or json
+    #[arg(long, value_name = "FORMAT")]
+    pub log_format: Option<LogFormat>,
+
+    /// Rotate the log file at this size, e.g. 100M
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub log_max_size: Option<u64>,
+
+    /// Rotated log files to keep [default: 5]
+    #[arg(long, value_name = "N")]
+    pub log_keep: Option<usize>,
+
+    /// Write one line per finished session to this file
+    #[arg(long, value_name = "PATH")]
+    pub access_log: Option<PathBuf>,
+
+    /// Write one line per refused request to this file
+    #[arg(long, value_name = "PATH")]
+    pub audit_log: Option<PathBuf>,
+
+    /// Log filter in RUST_LOG syntax, e.g. info or thin_proxy=debug;
+    /// overrides RUST_LOG
+    #[arg(long, value_name = "FILTER")]
+    pub log_level: Option<String>,
+
+    /// Read settings from this TOML file, flags override it
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Validate the settings from every layer, list all problems and exit
+    /// non-zero if there are any
+    #[arg(long)]
+    pub check_config: bool,
+
+    /// Print the effective settings from every layer as toml or json,
+    /// secrets redacted, then exit like --check-config
+    #[arg(long, value_name = "FORMAT", num_args = 0..=1, default_missing_value = "toml")]
+    pub print_config: Option<ConfigFormat>,
+
+    /// Read a password from stdin and print the auth_file line for this
+    /// user, with an argon2id hash, then exit
+    #[arg(long, value_name = "USER")]
+    pub hash_password: Option<String>,
+}
+
+impl Cli {
+    /// Overrides the fields of `config` that were given on the command line.
+    pub fn apply(self, config: &mut Config) {
+        if !self.listen.is_empty() {
+            config.listen = self.listen;
+        }
+        if self.ipv6_only {
+            config.ipv6_only = true;
+        }
+        if let Some(p) = self.listen_unix {
+            config.listen_unix = Some(p);
+        }
+        if let Some(m) = self.listen_unix_mode {
+            config.listen_unix_mode = Some(m);
+        }
+        if let Some(o) = self.listen_unix_owner {
+            config.listen_unix_owner = Some(o);
+        }
+        if let Some(n) = self.workers {
+            config.workers = n;
+        }
+        if let Some(m) = self.accept_mode {
+            config.accept_mode = m;
+        }
+        if let Some(n) = self.max_sessions {
+            config.max_sessions = Some(n);
+        }
+        if let Some(n) = self.nofile {
+            config.nofile = Some(n);
+        }
+        if let Some(d) = self.idle_timeout {
+            config.timeouts.idle = d;
+        }
+        if let Some(d) = self.drain_timeout {
+            config.drain_timeout = d;
+        }
+        if let Some(a) = self.admin {
+            config.admin_listen = Some(a);
+        }
+        if let Some(m) = self.metrics {
+            config.metrics = m;
+        }
+        if let Some(a) = self.statsd {
+            config.statsd = Some(a);
+        }
+        if let Some(a) = self.worker_affinity {
+            config.worker_affinity = a;
+        }
+        if let Some(c) = self.acceptor_core {
+            config.acceptor_core = Some(c);
+        }
+        if let Some(m) = self.poll_mode {
+            config.poll_mode = m;
+        }
+        if let Some(us) = self.so_busy_poll {
+            config.so_busy_poll = Some(us);
+        }
+        if self.daemon {
+            config.daemon = true;
+        }
+        if let Some(p) = self.pidfile {
+            config.pidfile = Some(p);
+        }
+        if let Some(p) = self.parent_proxy {
+            config.parent_proxy = Some(p);
+        }
+        if let Some(p) = self.parent_socks {
+            config.parent_socks = Some(p);
+        }
+        if let Some(u) = self.user {
+            config.user = Some(u);
+        }
+        if let Some(g) = self.group {
+            config.group = Some(g);
+        }
+        if let Some(p) = self.log_file {
+            config.log_file = Some(p);
+        }
+        if let Some(f) = self.log_facility {
+            config.log_facility = Some(f);
+        }
+        if let Some(t) = self.syslog {
+            config.syslog = t;
+        }
+        if let Some(f) = self.log_format {
+            config.log_format = f;
+        }
+        if let Some(n) = self.log_max_size {
+            config.log_max_size = Some(n);
+        }
+        if let Some(n) = self.log_keep {
+            config.log_keep = n;
+        }
+        if let Some(p) = self.access_log {
+            config.access_log = Some(p);
+        }
+        if let Some(p) = self.audit_log {
+            config.audit_log = Some(p);
+        }
+    }
+}
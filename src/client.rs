@@ -0,0 +1,340 @@
+use std::{
+    fmt::Display,
+    io::{self, Read, Write},
+    mem::MaybeUninit,
+    net::{IpAddr, SocketAddr},
+    os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd},
+    time::Duration,
+};
+
+use mio::{
+    event::Source,
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    Interest, Registry, Token,
+};
+
+#[cfg(feature = "tls")]
+use crate::tls::{ServerContext, TlsStream};
+use crate::websocket::WsStream;
+
+/// Who is on the other end of a client connection. Unix socket clients
+/// have no address worth keeping, they all share the `Local` bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Peer {
+    Ip(SocketAddr),
+    Local,
+}
+
+impl Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Peer::Ip(addr) => addr.fmt(f),
+            Peer::Local => f.write_str("local"),
+        }
+    }
+}
+
+/// A socket clients connect to, `Config::listen` or `Config::listen_unix`.
+pub enum ClientListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl ClientListener {
+    pub fn accept(&self) -> io::Result<(ClientStream, Peer)> {
+        match self {
+            ClientListener::Tcp(l) => {
+                let (sock, addr) = l.accept()?;
+                // IPv4 clients of a dual-stack listener show up as ::ffff:a.b.c.d
+                let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+                Ok((ClientStream::Tcp(sock), Peer::Ip(addr)))
+            }
+            ClientListener::Unix(l) => {
+                let (sock, _) = l.accept()?;
+                Ok((ClientStream::Unix(sock), Peer::Local))
+            }
+        }
+    }
+}
+
+/// How long a client of a TLS listener has to complete its handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The downstream side of a session. The plain kinds are stream fds the
+/// data path splices alike, a TLS one is copied through its session and
+/// a websocket one through its framing.
+pub enum ClientStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+    /// the client of a `protocol = "websocket"` listener, over any of the
+    /// others
+    Ws(Box<WsStream>),
+}
+
+impl ClientStream {
+    pub fn as_tcp(&self) -> Option<&TcpStream> {
+        match self {
+            ClientStream::Tcp(s) => Some(s),
+            ClientStream::Unix(_) => None,
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(s) => Some(s.tcp()),
+            ClientStream::Ws(s) => s.sock().as_tcp(),
+        }
+    }
+
+    /// Where the connection was headed before a REDIRECT rule sent it to
+    /// us, SO_ORIGINAL_DST or its IPv6 counterpart by the family it came
+    /// in on. NotFound when it was not redirected: it came for us, and
+    /// dialing that would loop.
+    pub fn original_dst(&self) -> io::Result<SocketAddr> {
+        let ClientStream::Tcp(s) = self else {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "unix sockets are never redirected"));
+        };
+        let local = s.local_addr()?;
+        let sock = socket2::SockRef::from(s);
+        let original = match local.ip().to_canonical() {
+            IpAddr::V4(_) => sock.original_dst()?,
+            IpAddr::V6(_) => sock.original_dst_ipv6()?,
+        };
+        let original = original
+            .as_socket()
+            .ok_or_else(|| io::Error::other("original destination not an IP address"))?;
+        let original = SocketAddr::new(original.ip().to_canonical(), original.port());
+        if original == SocketAddr::new(local.ip().to_canonical(), local.port()) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "not redirected"));
+        }
+        Ok(original)
+    }
+
+    /// Where the connection was headed when a TPROXY rule handed it to a
+    /// listener on `listen`: its own local address, which TPROXY leaves
+    /// alone. NotFound when that is `listen` itself, a client that came
+    /// for us.
+    pub fn tproxy_dst(&self, listen: SocketAddr) -> io::Result<SocketAddr> {
+        let ClientStream::Tcp(s) = self else {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "unix sockets are never redirected"));
+        };
+        let local = s.local_addr()?;
+        let local = SocketAddr::new(local.ip().to_canonical(), local.port());
+        let ours = listen.ip().is_unspecified() || listen.ip().to_canonical() == local.ip();
+        if ours && listen.port() == local.port() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "not redirected"));
+        }
+        Ok(local)
+    }
+
+    /// The next byte to read, left in the socket for the read after; None
+    /// at end of file.
+    pub fn peek_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut buf = [0u8];
+        match self.peek(&mut buf)? {
+            0 => Ok(None),
+            _ => Ok(Some(buf[0])),
+        }
+    }
+
+    /// Copies what is there to read into `buf`, as much as fits, leaving
+    /// it in the socket; 0 at end of file. Decrypted, past a TLS handshake.
+    pub fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(feature = "tls")]
+        if let ClientStream::Tls(s) = self {
+            return s.peek(buf);
+        }
+        if let ClientStream::Ws(s) = self {
+            return s.peek(buf);
+        }
+        // SAFETY: same layout, and recv only ever writes into the buffer
+        let uninit = unsafe { &mut *(buf as *mut [u8] as *mut [MaybeUninit<u8>]) };
+        socket2::SockRef::from(&*self).peek(uninit)
+    }
+
+    /// Whether the client speaks TLS to us, the data path copying through
+    /// the TLS session rather than splicing.
+    pub fn is_tls(&self) -> bool {
+        match self {
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(_) => true,
+            ClientStream::Ws(s) => s.sock().is_tls(),
+            _ => false,
+        }
+    }
+
+    /// Whether the client came in on a websocket listener, its bytes
+    /// framed once its upgrade is answered.
+    pub fn is_websocket(&self) -> bool {
+        matches!(self, ClientStream::Ws(_))
+    }
+
+    /// Whether the client settled on h2 in its TLS handshake, see `h2`.
+    #[cfg(feature = "tls")]
+    pub fn is_h2(&self) -> bool {
+        match self {
+            ClientStream::Tls(s) => s.is_h2(),
+            ClientStream::Ws(s) => s.sock().is_h2(),
+            _ => false,
+        }
+    }
+
+    /// Who the certificate a TLS client presented says it is, see
+    /// `TlsStream::peer_identity`.
+    #[cfg(feature = "tls")]
+    pub fn peer_identity(&self) -> Option<String> {
+        match self {
+            ClientStream::Tls(s) => s.peer_identity(),
+            ClientStream::Ws(s) => s.sock().peer_identity(),
+            _ => None,
+        }
+    }
+
+    /// Takes a TLS client's handshake as far as the socket lets it, Ok
+    /// once it is complete or for a client without TLS.
+    pub fn handshake(&mut self) -> io::Result<()> {
+        match self {
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(s) => s.handshake(),
+            ClientStream::Ws(s) => s.sock_mut().handshake(),
+            _ => Ok(()),
+        }
+    }
+
+    /// The same TCP connection with TLS on top, for a TLS listener.
+    #[cfg(feature = "tls")]
+    pub fn into_tls(self, ctx: &ServerContext) -> io::Result<ClientStream> {
+        match self {
+            ClientStream::Tcp(s) => Ok(ClientStream::Tls(Box::new(TlsStream::new(ctx, s)?))),
+            other => Ok(other),
+        }
+    }
+
+    /// The same connection for a websocket listener, framed once `start`
+    /// says the upgrade is answered; frames of clients past `max_frame`
+    /// bytes close it.
+    pub fn into_websocket(self, max_frame: usize) -> ClientStream {
+        ClientStream::Ws(Box::new(WsStream::new(self, max_frame)))
+    }
+
+    /// Starts the framing of a websocket client behind our `switching`
+    /// 101.
+    pub fn start_websocket(&mut self, switching: &[u8]) {
+        if let ClientStream::Ws(s) = self {
+            s.start(switching);
+        }
+    }
+
+    /// Sends a websocket client the close frame with `code`, if it was
+    /// upgraded and has not had one.
+    pub fn close_websocket(&mut self, code: u16) {
+        if let ClientStream::Ws(s) = self {
+            s.close(code);
+        }
+    }
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Tcp(s) => s.read(buf),
+            ClientStream::Unix(s) => s.read(buf),
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(s) => s.read(buf),
+            ClientStream::Ws(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Tcp(s) => s.write(buf),
+            ClientStream::Unix(s) => s.write(buf),
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(s) => s.write(buf),
+            ClientStream::Ws(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(s) => s.flush(),
+            ClientStream::Unix(s) => s.flush(),
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(s) => s.flush(),
+            ClientStream::Ws(s) => s.flush(),
+        }
+    }
+}
+
+impl AsFd for ClientStream {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            ClientStream::Tcp(s) => s.as_fd(),
+            ClientStream::Unix(s) => s.as_fd(),
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(s) => s.tcp().as_fd(),
+            ClientStream::Ws(s) => s.sock().as_fd(),
+        }
+    }
+}
+
+impl AsRawFd for ClientStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.as_fd().as_raw_fd()
+    }
+}
+
+impl Source for ClientListener {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            ClientListener::Tcp(s) => s.register(registry, token, interests),
+            ClientListener::Unix(s) => s.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            ClientListener::Tcp(s) => s.reregister(registry, token, interests),
+            ClientListener::Unix(s) => s.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            ClientListener::Tcp(s) => s.deregister(registry),
+            ClientListener::Unix(s) => s.deregister(registry),
+        }
+    }
+}
+
+impl Source for ClientStream {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(s) => s.register(registry, token, interests),
+            ClientStream::Unix(s) => s.register(registry, token, interests),
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(s) => s.tcp_mut().register(registry, token, interests),
+            ClientStream::Ws(s) => s.sock_mut().register(registry, token, interests),
+        }
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(s) => s.reregister(registry, token, interests),
+            ClientStream::Unix(s) => s.reregister(registry, token, interests),
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(s) => s.tcp_mut().reregister(registry, token, interests),
+            ClientStream::Ws(s) => s.sock_mut().reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(s) => s.deregister(registry),
+            ClientStream::Unix(s) => s.deregister(registry),
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(s) => s.tcp_mut().deregister(registry),
+            ClientStream::Ws(s) => s.sock_mut().deregister(registry),
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    io,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+};
+
+use mio::{Poll, Waker};
+
+use crate::{
+    admin::SessionInfo,
+    capture::CaptureError,
+    client::{ClientStream, Peer},
+    config::Config,
+    dns::CacheInfo,
+    token::TokenSpace,
+};
+
+/// Work injected into an event loop from another thread. The loop drains
+/// its channel whenever it is woken under `TokenSpace::WAKER`.
+pub enum Command {
+    /// a socket the acceptor thread accepted on listener `listener`, now
+    /// owned by the worker
+    Adopt {
+        sock: ClientStream,
+        addr: Peer,
+        listener: usize,
+    },
+    /// log every active session, a chunk per loop iteration
+    DumpSessions,
+    /// answer with `SessionList` through `reply` (admin `/sessions`): up
+    /// to `limit` sessions with an id above `after`
+    ListSessions {
+        request: u64,
+        after: u64,
+        limit: usize,
+        reply: CommandSender,
+    },
+    /// one worker's sessions for the admin request `request`, `more` when
+    /// it had others past the limit
+    SessionList {
+        request: u64,
+        sessions: Vec<SessionInfo>,
+        more: bool,
+    },
+    /// start capturing session `id` into `dir` if this worker has it (admin
+    /// `POST /sessions/<id>/capture`), answer with `CaptureReply`
+    Capture {
+        request: u64,
+        id: u64,
+        dir: PathBuf,
+        limit: u64,
+        reply: CommandSender,
+    },
+    /// one worker's answer to `Capture`, None when the session is not its
+    CaptureReply {
+        request: u64,
+        result: Option<Result<PathBuf, CaptureError>>,
+    },
+    /// answer with `DnsCacheList` through `reply` (admin `/dns-cache`): up
+    /// to `limit` cached hosts sorting after `after`
+    ListDnsCache {
+        request: u64,
+        after: String,
+        limit: usize,
+        reply: CommandSender,
+    },
+    /// one worker's cached hosts for the admin request `request`, `more`
+    /// when it had others past the limit
+    DnsCacheList {
+        request: u64,
+        entries: Vec<CacheInfo>,
+        more: bool,
+    },
+    /// forget the cached answer for `host` (admin `DELETE
+    /// /dns-cache/<host>`), answer with `DnsEvicted`
+    EvictDns {
+        request: u64,
+        host: String,
+        reply: CommandSender,
+    },
+    /// one worker's answer to `EvictDns`, whether it had the host
+    DnsEvicted { request: u64, found: bool },
+    /// close the sessions of the client `ip`, just banned (admin `PUT
+    /// /bans/<ip>?close=true`), answer with `ClientClosed`
+    CloseClient {
+        request: u64,
+        ip: IpAddr,
+        reply: CommandSender,
+    },
+    /// one worker's answer to `CloseClient`, how many sessions it closed
+    ClientClosed { request: u64, closed: usize },
+    /// stop accepting and exit once the last session is gone, after SIGTERM,
+    /// an upgrade or another thread failing
+    Drain {
+        /// a successor process took over and binds the admin address
+        /// itself, close it too; else it stays up answering `/readyz` with
+        /// 503
+        handoff: bool,
+    },
+    /// settings reloaded on SIGHUP, for sessions accepted from now on;
+    /// the acceptor ignores it
+    Reload(Arc<Config>),
+    /// stop the loop after the current batch
+    Shutdown,
+}
+
+/// Cloneable handle for sending commands to one loop.
+#[derive(Clone)]
+pub struct CommandSender {
+    tx: Sender<Command>,
+    waker: Arc<Waker>,
+}
+
+impl CommandSender {
+    /// Queues `cmd` and wakes the loop. Fails once the loop is gone; the
+    /// command (and any socket it carries) is dropped then.
+    pub fn send(&self, cmd: Command) -> io::Result<()> {
+        self.tx
+            .send(cmd)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "loop gone"))?;
+        self.waker.wake()
+    }
+}
+
+/// Creates a command channel whose waker is registered with `poll`.
+pub fn channel(poll: &Poll) -> io::Result<(CommandSender, Receiver<Command>)> {
+    let waker = Arc::new(Waker::new(poll.registry(), TokenSpace::WAKER)?);
+    let (tx, rx) = mpsc::channel();
+    Ok((CommandSender { tx, waker }, rx))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use mio::Events;
+
+    use super::*;
+
+    #[test]
+    fn a_send_from_another_thread_wakes_the_loop() {
+        let mut poll = Poll::new().unwrap();
+        let (tx, rx) = channel(&poll).unwrap();
+        // the loop's own handle keeps the waker, and its eventfd, alive
+        let sender = tx.clone();
+        thread::spawn(move || {
+            sender.send(Command::DumpSessions).unwrap();
+            sender.send(Command::Shutdown).unwrap();
+        })
+        .join()
+        .unwrap();
+        let mut events = Events::with_capacity(4);
+        poll.poll(&mut events, Some(Duration::from_secs(10))).unwrap();
+        assert!(events.iter().any(|e| e.token() == TokenSpace::WAKER));
+        // one wake for both, taken in the order sent
+        assert!(matches!(rx.try_recv(), Ok(Command::DumpSessions)));
+        assert!(matches!(rx.try_recv(), Ok(Command::Shutdown)));
+        assert!(rx.try_recv().is_err());
+        drop(tx);
+    }
+
+    #[test]
+    fn a_send_fails_once_the_loop_is_gone() {
+        let poll = Poll::new().unwrap();
+        let (tx, rx) = channel(&poll).unwrap();
+        drop(rx);
+        assert_eq!(tx.send(Command::Shutdown).unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+}
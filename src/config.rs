@@ -0,0 +1,2496 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env,
+    fmt::Display,
+    fs,
+    net::SocketAddr,
+    num::{NonZeroU32, NonZeroU64, NonZeroUsize},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+use crate::{
+    acl::{AclAction, AclRule, AclSet, PortSet},
+    affinity::Affinity,
+    bandwidth::Bandwidth,
+    auth::Credentials,
+    busy_poll::PollMode,
+    detect::Protocol,
+    geoip::{Country, GeoIp},
+    host_check::HostCheck,
+    host_pattern::{HostPattern, HostRegex},
+    internal_addrs::Cidr,
+    lockdown::Lockdown,
+    logging::LogFormat,
+    metrics::Export,
+    parent::{ParentProxy, Route, Via},
+    privileges,
+    profile::{Profile, DEFAULT_PROFILE},
+    schedule::{Days, Hours, TimeZone},
+    secret::{Secret, SecretSource},
+    ser,
+    session::split_host_port,
+    sni::{SniCheck, TransparentSni},
+    stall::StallAction,
+    syslog::{self, Facility},
+    timeouts::{TimeoutOverride, Timeouts},
+    token::TokenSpace,
+    transparent::Transparent,
+    users::UserPolicy,
+    unix_socket, upgrade, worker,
+};
+#[cfg(feature = "tls")]
+use crate::tls::{ClientAuth, ClientContext, ServerContext};
+
+/// Environment variables `THIN_PROXY_<KEY>` set the file key `<key>`.
+const ENV_PREFIX: &str = "THIN_PROXY_";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptMode {
+    /// every worker binds the listen addresses with SO_REUSEPORT
+    ReusePort,
+    /// one thread accepts and hands each socket to the least-loaded worker,
+    /// which balances better when connection lifetimes vary a lot
+    Acceptor,
+}
+
+impl FromStr for AcceptMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reuseport" => Ok(AcceptMode::ReusePort),
+            "acceptor" => Ok(AcceptMode::Acceptor),
+            _ => Err(format!("unknown accept mode {:?}, expected reuseport or acceptor", s)),
+        }
+    }
+}
+
+impl Display for AcceptMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AcceptMode::ReusePort => "reuseport",
+            AcceptMode::Acceptor => "acceptor",
+        })
+    }
+}
+
+/// Output of `--print-config`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+}
+
+impl FromStr for ConfigFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "toml" => Ok(ConfigFormat::Toml),
+            "json" => Ok(ConfigFormat::Json),
+            _ => Err(format!("unknown config format {:?}, expected toml or json", s)),
+        }
+    }
+}
+
+/// The effective settings. Serialized for `--print-config` with the
+/// config file's key names; unset options are left out.
+#[derive(Serialize)]
+pub struct Config {
+    /// addresses to accept clients on, all feeding the same sessions; at
+    /// most `TokenSpace::MAX_LISTENERS`
+    pub listen: Vec<SocketAddr>,
+    /// IPV6_V6ONLY on IPv6 listeners. Off, `[::]:port` is dual-stack and
+    /// takes IPv4 clients too; on, list `0.0.0.0:port` next to it for them
+    pub ipv6_only: bool,
+    /// connections the kernel queues on each listener until they are
+    /// accepted, capped by net.core.somaxconn
+    pub listen_backlog: u32,
+    /// listener label (see `listener_names`) to its own `listen_backlog`,
+    /// printed in the `[[listener]]` entries like `listener_profiles`
+    #[serde(skip)]
+    pub listener_backlogs: HashMap<String, u32>,
+    /// labels of the listeners whose connections start with a PROXY
+    /// protocol header, v1 or v2, naming the client behind the load
+    /// balancer; printed in the `[[listener]]` entries too
+    #[serde(skip)]
+    pub listener_proxy_protocol: HashSet<String>,
+    /// labels of the TCP listeners taking connections an iptables REDIRECT
+    /// sent, dialed to their original destination with no request read;
+    /// printed in the `[[listener]]` entries too
+    #[serde(skip)]
+    pub listener_transparent: HashSet<String>,
+    /// labels of the TCP listeners taking connections an iptables TPROXY
+    /// rule delivered, bound with IP_TRANSPARENT and dialed to the address
+    /// the client connected to
+    #[serde(skip)]
+    pub listener_tproxy: HashSet<String>,
+    /// labels of the `listener_tproxy` listeners whose upstream connections
+    /// go out from the client's own address
+    #[serde(skip)]
+    pub listener_spoof_source: HashSet<String>,
+    /// listener label to the PEM certificate chain of a TCP listener its
+    /// clients reach over TLS, an `https://` proxy; printed in the
+    /// `[[listener]]` entries too, like `listener_tls_key`
+    #[serde(skip)]
+    pub listener_tls_cert: HashMap<String, PathBuf>,
+    /// listener label to the PEM private key of its `listener_tls_cert`
+    #[serde(skip)]
+    pub listener_tls_key: HashMap<String, PathBuf>,
+    /// labels of the TLS listeners offering h2 by ALPN as well, for the
+    /// CONNECT tunnels of HTTP/2 clients
+    #[serde(skip)]
+    pub listener_http2: HashSet<String>,
+    /// listener label to the PEM certificates a TLS listener's clients
+    /// may present certificates of; the client's certificate names its
+    /// user, see `tls::TlsStream::peer_identity`, in place of
+    /// Proxy-Authorization. Printed in the `[[listener]]` entries too
+    #[serde(skip)]
+    pub listener_client_ca: HashMap<String, PathBuf>,
+    /// labels of the `listener_client_ca` listeners whose clients' TLS
+    /// handshake fails without a certificate
+    #[serde(skip)]
+    pub listener_require_client_cert: HashSet<String>,
+    /// listener label to the SHA-256 fingerprints, in hex, of client
+    /// certificates refused though they chain to its `listener_client_ca`
+    #[serde(skip)]
+    pub listener_client_cert_deny: HashMap<String, Vec<String>>,
+    /// listener label to the `host:port` every connection of a tunnel
+    /// listener is dialed to, no request read; printed in the
+    /// `[[listener]]` entries too
+    #[serde(skip)]
+    pub listener_upstream: HashMap<String, String>,
+    /// listener label to what its clients may speak, told apart by their
+    /// first bytes; listeners without one go by `socks`. Printed in the
+    /// `[[listener]]` entries too
+    #[serde(skip)]
+    pub listener_protocol: HashMap<String, Protocol>,
+    /// both as loaded by `load_tls`, by listener label
+    #[cfg(feature = "tls")]
+    #[serde(skip)]
+    pub tls_contexts: HashMap<String, Arc<ServerContext>>,
+    /// what `wrap_tls` routes check certificates against, as loaded by
+    /// `load_tls`, by their `tls_ca`; None for the system's
+    #[cfg(feature = "tls")]
+    #[serde(skip)]
+    pub tls_clients: HashMap<Option<PathBuf>, Arc<ClientContext>>,
+    /// unix socket path for local clients, in addition to `listen`
+    pub listen_unix: Option<PathBuf>,
+    /// permission bits for `listen_unix`, None = from the umask
+    #[serde(serialize_with = "ser::mode_opt")]
+    pub listen_unix_mode: Option<u32>,
+    /// `user`, `user:group` or `:group` to own `listen_unix`
+    pub listen_unix_owner: Option<String>,
+    /// number of worker event loops; with more than one every worker binds
+    /// `listen` with SO_REUSEPORT and the kernel spreads the accepts
+    pub workers: usize,
+    #[serde(serialize_with = "ser::display")]
+    pub accept_mode: AcceptMode,
+    /// RLIMIT_NOFILE to request at startup, None = the hard limit
+    pub nofile: Option<u64>,
+    /// cap on concurrent sessions over all workers, None = derived from
+    /// the file descriptor limit actually obtained
+    pub max_sessions: Option<usize>,
+    /// readiness events fetched per poll; bursts larger than this are
+    /// delivered over several polls (see the full-poll counter in stats)
+    pub events_capacity: usize,
+    /// session timeouts, globally and per destination pattern
+    pub timeouts: Timeouts,
+    /// `host:port` of the HTTP proxy `Via::Parent` routes go through
+    pub parent_proxy: Option<String>,
+    /// Basic credentials for `parent_proxy`
+    pub parent_proxy_user: Option<String>,
+    /// where the password is, rendered by `render`
+    #[serde(skip)]
+    pub parent_proxy_password: Option<SecretSource>,
+    /// `parent_proxy_password` as read by `load_secrets`
+    #[serde(skip)]
+    pub parent_proxy_secret: Option<Secret>,
+    /// `host:port` of the SOCKS5 proxy `Via::SocksParent` routes go through
+    pub parent_socks: Option<String>,
+    /// username and password for `parent_socks`, RFC 1929
+    pub parent_socks_user: Option<String>,
+    #[serde(skip)]
+    pub parent_socks_password: Option<SecretSource>,
+    /// `parent_socks_password` as read by `load_secrets`
+    #[serde(skip)]
+    pub parent_socks_secret: Option<Secret>,
+    /// how long a parent proxy that failed is passed over for the next of
+    /// its route's `parents`, see `parent_health::failed`
+    #[serde(serialize_with = "ser::duration")]
+    pub parent_backoff: Duration,
+    /// how long a parent of a route with more `parents` has to take the
+    /// connection and answer our handshake before the next is tried
+    #[serde(serialize_with = "ser::duration")]
+    pub parent_timeout: Duration,
+    /// `host:port` the parents marked down are asked for a tunnel to every
+    /// `parent_probe_every`, one that opens it is back; None = a parent is
+    /// tried again once its backoff is over
+    pub parent_probe: Option<String>,
+    #[serde(serialize_with = "ser::duration")]
+    pub parent_probe_every: Duration,
+    /// dial upstream connections over multipath TCP, falling back to TCP
+    /// where the kernel or the peer has none; routes may say otherwise
+    pub upstream_mptcp: bool,
+    /// `[[route]]` entries in file order, see `parent::route_for`
+    #[serde(rename = "route")]
+    pub routes: Vec<Route>,
+    /// `[[acl]]` entries in file order, see `acl::check`
+    pub acl: Vec<AclRule>,
+    /// what destinations matching no `acl` entry get
+    #[serde(serialize_with = "ser::display")]
+    pub acl_default: AclAction,
+    /// the zone the `hours` and `days` of acl entries are read in
+    #[serde(serialize_with = "ser::display")]
+    pub acl_timezone: TimeZone,
+    /// sessions are checked against the acl again as time windows open
+    /// and close, and closed with `CloseReason::AclWindow` once refused
+    pub acl_window_close: bool,
+    /// `[acl_set.NAME]` tables by name, for `users` to refer to
+    #[serde(rename = "acl_set", serialize_with = "ser::sorted")]
+    pub acl_sets: HashMap<String, Arc<AclSet>>,
+    /// `[users.NAME]` tables by user name; not `user`, which is who the
+    /// proxy runs as
+    #[serde(serialize_with = "ser::sorted")]
+    pub users: HashMap<String, Arc<UserPolicy>>,
+    /// hour of the day, UTC, at which `UserPolicy::daily_quota` usage
+    /// starts over
+    pub quota_reset_hour: u8,
+    /// where the day's quota usage is kept over restarts, None = memory
+    /// only
+    pub quota_state: Option<PathBuf>,
+    /// destination ports reached, None for any; `acl` entries with ports
+    /// of their own make exceptions
+    #[serde(serialize_with = "ser::display_opt")]
+    pub allowed_ports: Option<PortSet>,
+    /// `username:hash` lines; when set every request needs Basic
+    /// credentials of one of these users
+    pub auth_file: Option<PathBuf>,
+    /// realm of the 407 challenge
+    pub auth_realm: String,
+    /// `auth_file` as loaded by `load_credentials`
+    #[serde(skip)]
+    pub credentials: Option<Arc<Credentials>>,
+    /// clients in these blocks are turned away at accept, see
+    /// `acl::check_client`; unix socket clients never are
+    pub deny_clients: Vec<Cidr>,
+    /// when not empty, the only blocks clients are accepted from
+    pub allow_clients: Vec<Cidr>,
+    /// answer turned away clients with a 403 before closing, for the ones
+    /// speaking HTTP to see why; see `Worker::speaks_http`
+    pub deny_clients_403: bool,
+    /// connections a second each source may open, sustained, None = no
+    /// limit; see `conn_rate::admit`
+    pub conn_rate: Option<f64>,
+    /// connections a source may open at once before `conn_rate` applies
+    pub conn_rate_burst: u32,
+    /// sources never limited, NAT gateways with many clients behind them
+    pub conn_rate_exempt: Vec<Cidr>,
+    /// answer limited connections with a 429 before closing, the ones
+    /// already speaking HTTP; see `Worker::speaks_http`
+    pub conn_rate_429: bool,
+    /// sources tracked at most, the least recently seen go first
+    pub conn_rate_table: usize,
+    /// accepts a second over all listeners, sustained, None = no limit;
+    /// see `accept_rate::take`
+    pub accept_rate: Option<f64>,
+    /// accepts at once before `accept_rate` applies
+    pub accept_rate_burst: u32,
+    /// close connections over `accept_rate` rather than leave them in
+    /// the backlog
+    pub accept_rate_shed: bool,
+    /// failed `auth_file` logins within `auth_ban_window` that get a
+    /// client banned for `auth_ban_for`, None = never; see `bans::failed`
+    pub auth_ban_failures: Option<u32>,
+    #[serde(serialize_with = "ser::duration")]
+    pub auth_ban_window: Duration,
+    #[serde(serialize_with = "ser::duration")]
+    pub auth_ban_for: Duration,
+    /// sources never banned, NAT gateways with many clients behind them
+    pub auth_ban_exempt: Vec<Cidr>,
+    /// where running bans are kept over restarts, None = memory only
+    pub auth_ban_state: Option<PathBuf>,
+    /// refuse destinations resolving to an internal address, see
+    /// `internal_addrs::internal`, unless in `allow_internal`; routes
+    /// via the parent proxy are not checked
+    pub block_internal: bool,
+    pub allow_internal: Vec<Cidr>,
+    /// whether the ClientHello of a CONNECT tunnel must name the host the
+    /// CONNECT did, see `Session::check_sni`
+    #[serde(serialize_with = "ser::display")]
+    pub sni_check: SniCheck,
+    /// a hello for a subdomain of the CONNECT host passes `sni_check`
+    pub sni_allow_subdomains: bool,
+    /// what a transparent listener makes of the server name in its
+    /// client's ClientHello, see `Session::transparent_hello`
+    #[serde(serialize_with = "ser::display")]
+    pub transparent_sni: TransparentSni,
+    /// whether a request's target and Host header must agree when it has
+    /// both, see `host_check::matches`
+    #[serde(serialize_with = "ser::display")]
+    pub host_check: HostCheck,
+    /// a client whose first byte is 0x05 speaks SOCKS5 rather than HTTP,
+    /// 0x04 SOCKS4 or 4a, see `socks`; on listeners without a
+    /// `listener_protocol`
+    pub socks: bool,
+    /// MaxMind country and ASN databases (mmdb) the `countries` and
+    /// `asns` of acl entries are looked up in
+    pub geoip_country_db: Option<PathBuf>,
+    pub geoip_asn_db: Option<PathBuf>,
+    /// both as loaded by `load_geoip`
+    #[serde(skip)]
+    pub geoip: Option<Arc<GeoIp>>,
+    /// `[profile.NAME]` tables by name
+    #[serde(rename = "profile", serialize_with = "ser::sorted")]
+    pub profiles: HashMap<String, Arc<Profile>>,
+    /// listener label (see `listener_names`) to the profile its sessions
+    /// run with; others get the `default` profile. Printed as the
+    /// `[[listener]]` entries, see `render`
+    #[serde(skip)]
+    pub listener_profiles: HashMap<String, String>,
+    /// bytes moved per direction for one readiness event, see
+    /// `session::splice_copy`
+    pub pipe_budget: usize,
+    /// bytes a frame of a websocket listener's client may carry at most,
+    /// larger ones close the session, see `websocket`
+    pub websocket_max_frame: usize,
+    /// keep resolved upstream addresses per worker (no TTL yet), off
+    /// resolves on every CONNECT
+    pub dns_cache: bool,
+    /// hosts in one admin `/dns-cache` page, and the most `limit` may ask
+    /// for
+    pub dns_cache_page: usize,
+    /// events taking at least this long are logged at info level
+    #[serde(serialize_with = "ser::duration")]
+    pub slow_event: Duration,
+    /// sessions taking at least this long from accept to an established
+    /// tunnel are logged with their phases at warn level
+    #[serde(serialize_with = "ser::duration")]
+    pub slow_establishment: Duration,
+    /// `/healthz` fails and `loop_stall_action` is taken once a worker
+    /// loop has not turned for this long
+    #[serde(serialize_with = "ser::duration")]
+    pub loop_stall: Duration,
+    #[serde(serialize_with = "ser::display")]
+    pub loop_stall_action: StallAction,
+    /// time every this many loop iterations by handler for
+    /// `/loop-samples`, None = never
+    pub loop_sample_every: Option<u64>,
+    /// a request head that fails to parse is logged as a hexdump of its
+    /// first `hexdump::HEAD_DUMP` bytes, credentials blanked out
+    pub dump_bad_heads: bool,
+    /// connections a worker accepts per wakeup before it gets back to the
+    /// events of established sessions
+    pub accept_batch: usize,
+    #[serde(serialize_with = "ser::display")]
+    pub worker_affinity: Affinity,
+    /// `PollMode::Spin` trades a full core per worker for wakeup latency,
+    /// best combined with `worker_affinity`
+    #[serde(serialize_with = "ser::display")]
+    pub poll_mode: PollMode,
+    /// SO_BUSY_POLL in µs for accepted sockets, None = leave unset
+    pub so_busy_poll: Option<u32>,
+    /// core for the acceptor thread in `AcceptMode::Acceptor`
+    pub acceptor_core: Option<usize>,
+    /// address of the HTTP stats endpoint (`/stats`, `/sessions`,
+    /// `/top-hosts`, `/metrics`, `/healthz`, `/readyz`), None = disabled. It has no
+    /// authentication, keep it on a loopback address such as
+    /// 127.0.0.1:9901
+    #[serde(rename = "admin")]
+    pub admin_listen: Option<SocketAddr>,
+    /// directory `POST /sessions/<id>/capture` writes to, None = captures
+    /// refused; one session per request, never all of them
+    pub capture_dir: Option<PathBuf>,
+    /// which of `/metrics` and the statsd push are on
+    #[serde(serialize_with = "ser::display")]
+    pub metrics: Export,
+    /// `host:port` to send statsd datagrams to, see `statsd::Statsd`
+    pub statsd: Option<String>,
+    /// prepended to every statsd name with a dot, empty for none
+    pub statsd_prefix: String,
+    /// `key:value` tags for every statsd line; set, even empty, switches
+    /// to DogStatsD with the metric labels as tags too
+    pub statsd_tags: Option<Vec<String>>,
+    /// `http://host:port[/path]` of an OTLP/HTTP collector to send one
+    /// trace per session to, see `otlp`; needs the `otlp` feature
+    pub otlp_endpoint: Option<String>,
+    /// share of the sessions traced, 0 to 1; a client `traceparent`
+    /// decides for its own session instead
+    pub otlp_sample_ratio: f64,
+    /// failed connects to one destination in the last minutes before it is
+    /// warned about, see `failing_hosts::Alerts`
+    pub failing_host_min_failures: u64,
+    /// share of its connects, 0 to 1, that must have failed as well
+    pub failing_host_ratio: f64,
+    /// how long the sessions may take to finish after SIGTERM, or after the
+    /// listeners went to a successor (SIGUSR2), before they are closed
+    #[serde(serialize_with = "ser::duration")]
+    pub drain_timeout: Duration,
+    /// detach from the terminal at startup, see `daemon::daemonize`
+    pub daemon: bool,
+    /// file to write the pid to while running
+    pub pidfile: Option<PathBuf>,
+    /// switch to this user once the listeners are bound and the pidfile
+    /// is written, None = keep running as started
+    pub user: Option<String>,
+    /// group to switch to with `user`, None = the user's primary group
+    pub group: Option<String>,
+    /// capabilities and file access given up once started, see `lockdown`
+    #[serde(serialize_with = "ser::display")]
+    pub lockdown: Lockdown,
+    /// confine the process to the syscalls it serves with once started,
+    /// see `seccomp`; needs the `seccomp` feature
+    pub seccomp: bool,
+    /// log here instead of stderr; SIGUSR1 reopens it for logrotate
+    pub log_file: Option<PathBuf>,
+    #[serde(serialize_with = "ser::display")]
+    pub log_format: LogFormat,
+    /// rotate `log_file` once it reaches this many bytes, None = never
+    #[serde(serialize_with = "ser::display_opt")]
+    pub log_max_size: Option<u64>,
+    /// rotated files to keep, `log_file.1` being the newest
+    pub log_keep: usize,
+    /// how long a level set with `PUT /loglevel` lasts unless the request
+    /// says, None = until reverted or a reload
+    #[serde(serialize_with = "ser::duration_opt")]
+    pub log_level_revert: Option<Duration>,
+    /// one line per finished session, see `access_log::session`; SIGUSR1
+    /// reopens it with `log_file`
+    pub access_log: Option<PathBuf>,
+    /// one line per refused request, see `audit_log::denied`; SIGUSR1
+    /// reopens it with `log_file`
+    pub audit_log: Option<PathBuf>,
+    /// where the streams given a facility below go, see `syslog::Target`
+    #[serde(serialize_with = "ser::display")]
+    pub syslog: syslog::Target,
+    /// APP-NAME of the syslog messages
+    pub syslog_app_name: String,
+    /// send the log to `syslog` at this facility instead of stderr
+    #[serde(serialize_with = "ser::display_opt")]
+    pub log_facility: Option<Facility>,
+    /// also send the access lines to `syslog`, at this facility
+    #[serde(serialize_with = "ser::display_opt")]
+    pub access_log_facility: Option<Facility>,
+    /// also send the audit lines to `syslog`, at this facility
+    #[serde(serialize_with = "ser::display_opt")]
+    pub audit_log_facility: Option<Facility>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: vec!["0.0.0.0:7788".parse().unwrap()],
+            ipv6_only: false,
+            listen_backlog: 1024,
+            listener_backlogs: HashMap::new(),
+            listener_proxy_protocol: HashSet::new(),
+            listener_transparent: HashSet::new(),
+            listener_tproxy: HashSet::new(),
+            listener_spoof_source: HashSet::new(),
+            listener_tls_cert: HashMap::new(),
+            listener_tls_key: HashMap::new(),
+            listener_http2: HashSet::new(),
+            listener_client_ca: HashMap::new(),
+            listener_require_client_cert: HashSet::new(),
+            listener_client_cert_deny: HashMap::new(),
+            listener_upstream: HashMap::new(),
+            listener_protocol: HashMap::new(),
+            #[cfg(feature = "tls")]
+            tls_contexts: HashMap::new(),
+            #[cfg(feature = "tls")]
+            tls_clients: HashMap::new(),
+            listen_unix: None,
+            listen_unix_mode: None,
+            listen_unix_owner: None,
+            workers: thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            accept_mode: AcceptMode::ReusePort,
+            nofile: None,
+            max_sessions: None,
+            events_capacity: 1024,
+            timeouts: Timeouts::default(),
+            parent_proxy: None,
+            parent_proxy_user: None,
+            parent_proxy_password: None,
+            parent_proxy_secret: None,
+            parent_socks: None,
+            parent_socks_user: None,
+            parent_socks_password: None,
+            parent_socks_secret: None,
+            parent_backoff: Duration::from_secs(30),
+            parent_timeout: Duration::from_secs(5),
+            parent_probe: None,
+            parent_probe_every: Duration::from_secs(10),
+            upstream_mptcp: false,
+            routes: Vec::new(),
+            acl: Vec::new(),
+            acl_default: AclAction::Allow,
+            acl_timezone: TimeZone::Utc,
+            acl_window_close: false,
+            acl_sets: HashMap::new(),
+            users: HashMap::new(),
+            quota_reset_hour: 0,
+            quota_state: None,
+            allowed_ports: None,
+            auth_file: None,
+            auth_realm: "thin_proxy".to_owned(),
+            credentials: None,
+            deny_clients: Vec::new(),
+            allow_clients: Vec::new(),
+            deny_clients_403: false,
+            conn_rate: None,
+            conn_rate_burst: 20,
+            conn_rate_exempt: Vec::new(),
+            conn_rate_429: false,
+            conn_rate_table: 65536,
+            accept_rate: None,
+            accept_rate_burst: 100,
+            accept_rate_shed: false,
+            auth_ban_failures: None,
+            auth_ban_window: Duration::from_secs(5 * 60),
+            auth_ban_for: Duration::from_secs(15 * 60),
+            auth_ban_exempt: Vec::new(),
+            auth_ban_state: None,
+            block_internal: true,
+            allow_internal: Vec::new(),
+            sni_check: SniCheck::Off,
+            sni_allow_subdomains: false,
+            transparent_sni: TransparentSni::Host,
+            host_check: HostCheck::Warn,
+            socks: true,
+            geoip_country_db: None,
+            geoip_asn_db: None,
+            geoip: None,
+            profiles: HashMap::new(),
+            listener_profiles: HashMap::new(),
+            pipe_budget: 256 * 1024,
+            websocket_max_frame: 1024 * 1024,
+            dns_cache: true,
+            dns_cache_page: 1000,
+            slow_event: Duration::from_millis(5),
+            slow_establishment: Duration::from_secs(1),
+            loop_stall: Duration::from_secs(5),
+            loop_stall_action: StallAction::Log,
+            loop_sample_every: None,
+            dump_bad_heads: true,
+            accept_batch: 64,
+            worker_affinity: Affinity::Off,
+            poll_mode: PollMode::Block,
+            so_busy_poll: None,
+            acceptor_core: None,
+            admin_listen: None,
+            capture_dir: None,
+            metrics: Export::Prometheus,
+            statsd: None,
+            statsd_prefix: "thin_proxy".to_owned(),
+            statsd_tags: None,
+            otlp_endpoint: None,
+            otlp_sample_ratio: 0.1,
+            failing_host_min_failures: 10,
+            failing_host_ratio: 0.5,
+            drain_timeout: Duration::from_secs(60),
+            daemon: false,
+            pidfile: None,
+            user: None,
+            group: None,
+            lockdown: Lockdown::Caps,
+            seccomp: false,
+            log_file: None,
+            log_format: LogFormat::Text,
+            log_max_size: None,
+            log_keep: 5,
+            log_level_revert: None,
+            access_log: None,
+            audit_log: None,
+            syslog: syslog::Target::default(),
+            syslog_app_name: "thin_proxy".to_owned(),
+            log_facility: None,
+            access_log_facility: None,
+            audit_log_facility: None,
+        }
+    }
+}
+
+impl Config {
+    /// Rejects settings the proxy cannot run with, all problems in one
+    /// message.
+    pub fn validate(&self) -> Result<(), String> {
+        let errors = self.errors();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+
+    /// The acl rules and default for `user`'s requests: their
+    /// `UserPolicy::acl_set` when they have one, else `acl` and
+    /// `acl_default`.
+    pub fn acl_for(&self, user: Option<&str>) -> (&[AclRule], AclAction) {
+        let set = user
+            .and_then(|u| self.users.get(u))
+            .and_then(|u| u.acl_set.as_ref())
+            .and_then(|s| self.acl_sets.get(s));
+        match set {
+            Some(set) => (&set.rules, set.default),
+            None => (&self.acl, self.acl_default),
+        }
+    }
+
+    /// Reads `auth_file` into `credentials`, at startup and on reload, so
+    /// an edited file takes effect on SIGHUP.
+    pub fn load_credentials(&mut self) -> Result<(), String> {
+        self.credentials = self.auth_file.as_deref().map(Credentials::load).transpose()?.map(Arc::new);
+        Ok(())
+    }
+
+    /// Reads the secrets from their files and variables, at startup and
+    /// on reload, so a rotated one takes effect on SIGHUP.
+    pub fn load_secrets(&mut self) -> Result<(), String> {
+        self.parent_proxy_secret = self
+            .parent_proxy_password
+            .as_ref()
+            .map(|s| s.read("parent_proxy_password"))
+            .transpose()?;
+        self.parent_socks_secret = self
+            .parent_socks_password
+            .as_ref()
+            .map(|s| s.read("parent_socks_password"))
+            .transpose()?;
+        Ok(())
+    }
+
+    /// The files `load_secrets` reads.
+    pub fn secret_files(&self) -> Vec<&Path> {
+        [&self.parent_proxy_password, &self.parent_socks_password]
+            .into_iter()
+            .flatten()
+            .filter_map(SecretSource::file)
+            .collect()
+    }
+
+    /// Loads the certificates and keys of the TLS listeners into
+    /// `tls_contexts`, and what `wrap_tls` routes check against into
+    /// `tls_clients`, at startup and on reload, so a renewed certificate
+    /// takes effect on SIGHUP.
+    #[cfg(feature = "tls")]
+    pub fn load_tls(&mut self) -> Result<(), String> {
+        let mut contexts = HashMap::new();
+        for (label, cert) in &self.listener_tls_cert {
+            let Some(key) = self.listener_tls_key.get(label) else {
+                continue;
+            };
+            let http2 = self.listener_http2.contains(label);
+            let client = self.listener_client_ca.get(label).map(|ca| ClientAuth {
+                ca,
+                required: self.listener_require_client_cert.contains(label),
+                deny: self.listener_client_cert_deny.get(label).map_or(&[], Vec::as_slice),
+            });
+            let ctx = ServerContext::load(cert, key, http2, client.as_ref())
+                .map_err(|e| format!("listener {}: {}", label, e))?;
+            contexts.insert(label.clone(), Arc::new(ctx));
+        }
+        self.tls_contexts = contexts;
+        let mut clients = HashMap::new();
+        for route in self.routes.iter().filter(|r| r.wrap_tls) {
+            if !clients.contains_key(&route.tls_ca) {
+                let ctx = ClientContext::load(route.tls_ca.as_deref())?;
+                clients.insert(route.tls_ca.clone(), Arc::new(ctx));
+            }
+        }
+        self.tls_clients = clients;
+        Ok(())
+    }
+
+    /// Reads `geoip_country_db` and `geoip_asn_db` into `geoip`, at
+    /// startup and on reload, so a fresh download takes effect on SIGHUP.
+    pub fn load_geoip(&mut self) -> Result<(), String> {
+        let found = GeoIp::load(self.geoip_country_db.as_deref(), self.geoip_asn_db.as_deref())?;
+        self.geoip = found.map(Arc::new);
+        Ok(())
+    }
+
+    /// Everything wrong with the settings, empty when the proxy can run
+    /// with them.
+    pub fn errors(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let listeners = self.listener_names().len();
+        if self.listen.is_empty() || listeners > TokenSpace::MAX_LISTENERS {
+            errors.push(format!(
+                "need 1 to {} listen addresses, got {}",
+                TokenSpace::MAX_LISTENERS,
+                listeners
+            ));
+        }
+        for (i, addr) in self.listen.iter().enumerate() {
+            if self.listen[..i].contains(addr) {
+                errors.push(format!("listen address {} given twice", addr));
+            }
+            // a dual-stack wildcard owns the port for IPv4 as well
+            let dual_stack = addr.is_ipv6() && addr.ip().is_unspecified() && !self.ipv6_only;
+            let covered = |a: &&SocketAddr| a.is_ipv4() && a.port() == addr.port();
+            if let Some(v4) = self.listen.iter().find(covered).filter(|_| dual_stack) {
+                errors.push(format!(
+                    "listen address {} is dual-stack and takes {} already, set ipv6_only to bind both",
+                    addr, v4
+                ));
+            }
+        }
+        if let Some(owner) = &self.listen_unix_owner {
+            if let Err(e) = unix_socket::resolve_owner(owner) {
+                errors.push(e);
+            }
+        }
+        match (&self.user, &self.group) {
+            (Some(user), group) => {
+                if let Err(e) = privileges::resolve(user, group.as_deref()) {
+                    errors.push(e);
+                }
+            }
+            (None, Some(_)) => errors.push("group is only used together with user".to_owned()),
+            (None, None) => {}
+        }
+        if self.seccomp && !cfg!(feature = "seccomp") {
+            errors.push("seccomp needs a build with the seccomp feature".to_owned());
+        }
+        if let Some(admin) = self.admin_listen {
+            if self.listen.contains(&admin) {
+                errors.push(format!("admin address {} is also a listen address", admin));
+            }
+        }
+        match (&self.capture_dir, self.admin_listen) {
+            (Some(dir), Some(_)) if !dir.is_dir() => {
+                errors.push(format!("capture dir {} is not a directory", dir.display()))
+            }
+            (Some(_), None) => errors.push("capture dir is only used together with admin".to_owned()),
+            _ => {}
+        }
+        match (&self.statsd, self.metrics.statsd()) {
+            (Some(target), true) => {
+                if !matches!(split_host_port(target), (h, Some(p)) if !h.is_empty() && p.parse::<u16>().is_ok()) {
+                    errors.push(format!("statsd {:?} needs host:port", target));
+                }
+            }
+            (None, true) => errors.push(format!("metrics = {} needs a statsd address", self.metrics)),
+            (Some(_), false) => errors.push(format!("statsd is set but metrics = {}, use statsd or both", self.metrics)),
+            (None, false) => {}
+        }
+        match &self.otlp_endpoint {
+            Some(_) if !cfg!(feature = "otlp") => {
+                errors.push("otlp endpoint needs a build with the otlp feature".to_owned())
+            }
+            Some(url) if split_http_url(url).is_none() => {
+                errors.push(format!("otlp endpoint {:?} needs http://host:port[/path]", url))
+            }
+            _ => {}
+        }
+        if !(0.0..=1.0).contains(&self.otlp_sample_ratio) {
+            errors.push(format!("otlp sample ratio {} must be within 0 and 1", self.otlp_sample_ratio));
+        }
+        if self.loop_sample_every == Some(0) {
+            errors.push("loop sample every must be at least 1".to_owned());
+        }
+        if self.failing_host_min_failures == 0 {
+            errors.push("failing host min failures must be at least 1".to_owned());
+        }
+        if !(0.0..=1.0).contains(&self.failing_host_ratio) {
+            errors.push(format!("failing host ratio {} must be within 0 and 1", self.failing_host_ratio));
+        }
+        if self.statsd_prefix.contains(|c: char| c == ':' || c == '|' || c.is_whitespace()) {
+            errors.push(format!("statsd prefix {:?} may not contain ':', '|' or spaces", self.statsd_prefix));
+        }
+        for tag in self.statsd_tags.iter().flatten() {
+            if tag.is_empty() || tag.contains(|c: char| c == ',' || c == '|' || c.is_whitespace()) {
+                errors.push(format!("statsd tag {:?} must be non-empty without ',', '|' or spaces", tag));
+            }
+        }
+        if self.log_facility.is_some() && self.log_file.is_some() {
+            errors.push("log facility and log file are both set, the log goes to one of them".to_owned());
+        }
+        if !syslog::valid_app_name(&self.syslog_app_name) {
+            errors.push(format!(
+                "syslog app name {:?} must be 1 to 48 printable ASCII characters without spaces",
+                self.syslog_app_name
+            ));
+        }
+        if self.loop_stall < 2 * worker::HEARTBEAT {
+            errors.push(format!("loop stall must be at least {:?}", 2 * worker::HEARTBEAT));
+        }
+        if self.workers == 0 {
+            errors.push("workers must be at least 1".to_owned());
+        }
+        if self.max_sessions == Some(0) {
+            errors.push("max sessions must be at least 1".to_owned());
+        }
+        if self.events_capacity == 0 {
+            errors.push("events capacity must be at least 1".to_owned());
+        }
+        self.timeouts.validate(&mut errors);
+        if let Err(e) = self.parent() {
+            errors.push(e);
+        }
+        if let Err(e) = self.socks_parent() {
+            errors.push(e);
+        }
+        for route in &self.routes {
+            let hosts = route.hosts.iter().map(HostPattern::to_string).collect::<Vec<_>>().join(", ");
+            let tls_keys = [
+                ("tls_sni", route.tls_sni.is_some()),
+                ("tls_verify", !route.tls_verify),
+                ("tls_ca", route.tls_ca.is_some()),
+            ];
+            let unwrapped = tls_keys.iter().find(|(_, set)| *set && !route.wrap_tls);
+            if route.hosts.is_empty() {
+                errors.push("a route needs at least one host pattern".to_owned());
+            } else if !route.parents.is_empty() && route.via == Via::Direct {
+                errors.push(format!(
+                    "route for {} goes direct but lists parents, which only routes via-parent or via-socks-parent take",
+                    hosts
+                ));
+            } else if route.via == Via::Parent && self.parent_proxy.is_none() && route.parents.is_empty() {
+                errors.push(format!(
+                    "route for {} goes via-parent but lists no parents and parent_proxy is not set",
+                    hosts
+                ));
+            } else if route.via == Via::SocksParent && self.parent_socks.is_none() && route.parents.is_empty() {
+                errors.push(format!(
+                    "route for {} goes via-socks-parent but lists no parents and parent_socks is not set",
+                    hosts
+                ));
+            } else if route.proxy_protocol && route.via != Via::Direct {
+                errors.push(format!(
+                    "route for {} goes {} but asks for proxy_protocol, which only direct routes send",
+                    hosts, route.via
+                ));
+            } else if route.unix_socket.is_some() && route.via != Via::Direct {
+                errors.push(format!(
+                    "route for {} goes {} but names a unix_socket, which only direct routes dial",
+                    hosts, route.via
+                ));
+            } else if route.wrap_tls && route.via != Via::Direct {
+                errors.push(format!(
+                    "route for {} goes {} but asks for wrap_tls, which only direct routes do",
+                    hosts, route.via
+                ));
+            } else if route.wrap_tls && route.unix_socket.is_some() {
+                errors.push(format!("route for {} names a unix_socket, wrap_tls only goes over TCP", hosts));
+            } else if route.wrap_tls && !cfg!(feature = "tls") {
+                errors.push(format!("route for {}: wrap_tls needs a build with the tls feature", hosts));
+            } else if let Some((key, _)) = unwrapped {
+                errors.push(format!("route for {} sets {} but not wrap_tls", hosts, key));
+            } else if route.tls_ca.is_some() && !route.tls_verify {
+                errors.push(format!("route for {} sets tls_ca, but with tls_verify = false nothing is checked", hosts));
+            } else if route.mptcp == Some(true) && route.unix_socket.is_some() {
+                errors.push(format!("route for {} names a unix_socket, mptcp only goes over TCP", hosts));
+            } else if let Err(e) = self.route_parents(route) {
+                errors.push(format!("route for {}: {}", hosts, e));
+            }
+        }
+        if self.parent_backoff.is_zero() || self.parent_timeout.is_zero() || self.parent_probe_every.is_zero() {
+            errors.push("parent backoff, parent timeout and parent probe every must be above zero".to_owned());
+        }
+        if let Some(probe) = &self.parent_probe {
+            let (host, port) = split_host_port(probe);
+            if host.is_empty() || port.and_then(|p| p.parse::<u16>().ok()).is_none() {
+                errors.push(format!("parent probe {:?} needs host:port", probe));
+            }
+        }
+        let mut sets = self.acl_sets.iter().collect::<Vec<_>>();
+        sets.sort_by_key(|(name, _)| *name);
+        let rules = sets.iter().map(|(name, set)| (Some(*name), &set.rules)).chain([(None, &self.acl)]);
+        for (set, rules) in rules {
+            let at = set.map_or(String::new(), |name| format!(" of acl set {}", name));
+            if rules.iter().any(|rule| rule.hosts.is_empty() && rule.regex.is_empty() && !rule.has_geo()) {
+                errors.push(format!("an acl entry{} needs at least one host pattern, regex, country or asn", at));
+            }
+            if self.geoip_country_db.is_none() && rules.iter().any(|rule| !rule.countries.is_empty()) {
+                errors.push(format!("an acl entry{} has countries, they need geoip_country_db", at));
+            }
+            if self.geoip_asn_db.is_none() && rules.iter().any(|rule| !rule.asns.is_empty()) {
+                errors.push(format!("an acl entry{} has asns, they need geoip_asn_db", at));
+            }
+            if rules.iter().any(|rule| rule.limit.is_some() && rule.action != AclAction::Allow) {
+                errors.push(format!(
+                    "an acl entry{} does not allow and has a limit, it lets nothing through to limit",
+                    at
+                ));
+            }
+        }
+        let defaults = sets.iter().map(|(name, set)| (Some(*name), set.default)).chain([(None, self.acl_default)]);
+        for (set, default) in defaults {
+            if default == AclAction::Log {
+                let key = set.map_or("acl_default".to_owned(), |name| format!("default of acl set {}", name));
+                errors.push(format!("{} is log, which only acl entries can be; it must allow or deny", key));
+            }
+        }
+        let mut users = self.users.values().collect::<Vec<_>>();
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+        for user in &users {
+            user.validate(&self.acl_sets, &mut errors);
+        }
+        if !users.is_empty() && self.auth_file.is_none() && self.listener_client_ca.is_empty() {
+            errors.push("user sections need auth_file or client_ca, nobody is authenticated without them".to_owned());
+        }
+        if self.conn_rate.is_some_and(|r| !(r.is_finite() && r > 0.0)) {
+            errors.push("conn rate must be a number above zero".to_owned());
+        }
+        if self.conn_rate_burst == 0 {
+            errors.push("conn rate burst must be at least 1".to_owned());
+        }
+        if self.accept_rate.is_some_and(|r| !(r.is_finite() && r > 0.0)) {
+            errors.push("accept rate must be a number above zero".to_owned());
+        }
+        if self.accept_rate_burst == 0 {
+            errors.push("accept rate burst must be at least 1".to_owned());
+        }
+        if self.auth_ban_failures == Some(0) {
+            errors.push("auth ban failures must be at least 1".to_owned());
+        }
+        if self.auth_ban_failures.is_some() && self.auth_file.is_none() {
+            errors.push("auth_ban_failures needs auth_file, no login can fail without it".to_owned());
+        }
+        if self.auth_ban_window.is_zero() || self.auth_ban_for.is_zero() {
+            errors.push("auth ban window and auth ban for must be above zero".to_owned());
+        }
+        if self.quota_reset_hour > 23 {
+            errors.push(format!("quota reset hour {} is not 0 to 23", self.quota_reset_hour));
+        }
+        let mut profiles = self.profiles.values().collect::<Vec<_>>();
+        profiles.sort_by(|a, b| a.name.cmp(&b.name));
+        for profile in profiles {
+            profile.validate(&mut errors);
+            if profile.auth == Some(true) && self.auth_file.is_none() {
+                errors.push(format!("profile {}: auth = true needs auth_file to check credentials", profile.name));
+            }
+        }
+        let mut references = self.listener_profiles.iter().collect::<Vec<_>>();
+        references.sort();
+        for (listener, profile) in references {
+            if !self.profiles.contains_key(profile) {
+                errors.push(format!("listener {} uses unknown profile {}", listener, profile));
+            }
+        }
+        let mut tls_labels = self.listener_tls_cert.keys().chain(self.listener_tls_key.keys()).collect::<Vec<_>>();
+        tls_labels.sort();
+        tls_labels.dedup();
+        for name in tls_labels {
+            let transparent = self.listener_transparent.contains(name) || self.listener_tproxy.contains(name);
+            if !self.listener_tls_key.contains_key(name) {
+                errors.push(format!("listener {} has tls_cert but no tls_key", name));
+            } else if !self.listener_tls_cert.contains_key(name) {
+                errors.push(format!("listener {} has tls_key but no tls_cert", name));
+            } else if !cfg!(feature = "tls") {
+                errors.push(format!("listener {}: tls_cert needs a build with the tls feature", name));
+            } else if name.starts_with("unix:") {
+                errors.push(format!("listener {} is a unix socket, only TCP listeners take TLS", name));
+            } else if transparent {
+                errors.push(format!("listener {} is transparent, its clients do not speak TLS to us", name));
+            } else if self.listener_upstream.contains_key(name) {
+                errors.push(format!("listener {} is a tunnel, its clients do not speak TLS to us", name));
+            }
+        }
+        let auth_file = self.auth_file.is_some();
+        let keeps_caps = self.user.is_none() && self.lockdown == Lockdown::Off;
+        for (name, profile) in self.listener_names().iter().zip(self.profiles_by_listener()) {
+            if self.listener_spoof_source.contains(name) && !self.listener_tproxy.contains(name) {
+                errors.push(format!("listener {} asks for spoof_source, which needs tproxy", name));
+            } else if self.listener_spoof_source.contains(name) && !keeps_caps {
+                errors.push(format!(
+                    "listener {} asks for spoof_source, which needs CAP_NET_ADMIN kept: no user, lockdown = \"off\"",
+                    name
+                ));
+            }
+            if self.listener_client_ca.contains_key(name) && !self.listener_tls_cert.contains_key(name) {
+                errors.push(format!("listener {} has client_ca, which needs tls_cert", name));
+            }
+            if self.listener_require_client_cert.contains(name) && !self.listener_client_ca.contains_key(name) {
+                errors.push(format!("listener {} asks for require_client_cert, which needs client_ca", name));
+            }
+            if self.listener_client_cert_deny.contains_key(name) && !self.listener_client_ca.contains_key(name) {
+                errors.push(format!("listener {} has client_cert_deny, which needs client_ca", name));
+            }
+            if self.listener_http2.contains(name) && !self.listener_tls_cert.contains_key(name) {
+                errors.push(format!("listener {} asks for http2, which needs tls_cert", name));
+            } else if self.listener_http2.contains(name)
+                && self.listener_protocol.get(name) == Some(&Protocol::WebSocket)
+            {
+                errors.push(format!("listener {} takes websocket clients, which upgrade HTTP/1.1: no http2", name));
+            }
+            let unrequested = if self.listener_upstream.contains_key(name) {
+                Some("a tunnel")
+            } else if self.listener_transparent.contains(name) || self.listener_tproxy.contains(name) {
+                Some("transparent")
+            } else {
+                None
+            };
+            if let (Some(protocol), Some(kind)) = (self.listener_protocol.get(name), unrequested) {
+                errors.push(format!("listener {} is {}, protocol {} has no request to look at", name, kind, protocol));
+            }
+            if let Some(upstream) = self.listener_upstream.get(name) {
+                if !matches!(split_host_port(upstream), (h, Some(p)) if !h.is_empty() && p.parse::<u16>().is_ok()) {
+                    errors.push(format!("listener {} upstream {:?} needs host:port", name, upstream));
+                } else if self.listener_transparent.contains(name) || self.listener_tproxy.contains(name) {
+                    errors.push(format!("listener {} takes upstream or transparent, not both", name));
+                } else if self.listener_proxy_protocol.contains(name) {
+                    errors.push(format!("listener {} is a tunnel, it cannot take proxy_protocol too", name));
+                } else if profile.auth_required(auth_file) {
+                    errors.push(format!(
+                        "listener {} is a tunnel, its clients cannot authenticate; give it a profile with auth = false",
+                        name
+                    ));
+                }
+            }
+            let tproxy = self.listener_tproxy.contains(name);
+            if !tproxy && !self.listener_transparent.contains(name) {
+                continue;
+            }
+            if name.starts_with("unix:") {
+                errors.push(format!("listener {} is transparent, only TCP listeners can be", name));
+            } else if tproxy && self.listener_transparent.contains(name) {
+                errors.push(format!("listener {} takes transparent or tproxy, not both", name));
+            } else if self.listener_proxy_protocol.contains(name) {
+                errors.push(format!("listener {} is transparent, it cannot take proxy_protocol too", name));
+            } else if profile.auth_required(auth_file) {
+                errors.push(format!(
+                    "listener {} is transparent, its clients cannot authenticate; give it a profile with auth = false",
+                    name
+                ));
+            }
+        }
+        if self.pipe_budget == 0 {
+            errors.push("pipe budget must be at least 1".to_owned());
+        }
+        if self.accept_batch == 0 {
+            errors.push("accept batch must be at least 1".to_owned());
+        }
+        if self.auth_realm.chars().any(|c| c == '"' || c == '\\' || c.is_control()) {
+            errors.push(format!(
+                "auth realm {:?} cannot hold quotes, backslashes or control characters",
+                self.auth_realm
+            ));
+        }
+        errors
+    }
+
+    /// The settings as `--print-config` shows them, secrets redacted, in
+    /// a form the config file takes back. `log_level` is the filter from
+    /// the layers, which lives outside `Config`.
+    pub fn render(&self, format: ConfigFormat, log_level: Option<&str>) -> Result<String, String> {
+        let mut value = toml::Value::try_from(self).map_err(|e| format!("cannot serialize config: {}", e))?;
+        let table = value.as_table_mut().expect("Config serializes to a table");
+        if let Some(level) = log_level {
+            table.insert("log_level".to_owned(), level.into());
+        }
+        if let Some(s) = &self.parent_proxy_password {
+            s.render("parent_proxy_password", table);
+        }
+        if let Some(s) = &self.parent_socks_password {
+            s.render("parent_socks_password", table);
+        }
+        // listener profiles, backlogs and flags only exist in [[listener]],
+        // which replaces listen and listen_unix
+        if !self.listener_profiles.is_empty()
+            || !self.listener_backlogs.is_empty()
+            || !self.listener_proxy_protocol.is_empty()
+            || !self.listener_transparent.is_empty()
+            || !self.listener_tproxy.is_empty()
+            || !self.listener_tls_cert.is_empty()
+            || !self.listener_tls_key.is_empty()
+            || !self.listener_upstream.is_empty()
+            || !self.listener_protocol.is_empty()
+        {
+            table.remove("listen");
+            table.remove("listen_unix");
+            let listeners = self
+                .listener_names()
+                .into_iter()
+                .map(|address| {
+                    let profile = self.listener_profiles.get(&address).cloned();
+                    let backlog = self.listener_backlogs.get(&address).copied();
+                    let flags = [
+                        ("proxy_protocol", &self.listener_proxy_protocol),
+                        ("transparent", &self.listener_transparent),
+                        ("tproxy", &self.listener_tproxy),
+                        ("spoof_source", &self.listener_spoof_source),
+                        ("http2", &self.listener_http2),
+                        ("require_client_cert", &self.listener_require_client_cert),
+                    ];
+                    let mut l = toml::Table::new();
+                    l.insert("address".to_owned(), address.clone().into());
+                    if let Some(profile) = profile {
+                        l.insert("profile".to_owned(), profile.into());
+                    }
+                    if let Some(backlog) = backlog {
+                        l.insert("backlog".to_owned(), i64::from(backlog).into());
+                    }
+                    if let Some(upstream) = self.listener_upstream.get(&address) {
+                        l.insert("upstream".to_owned(), upstream.clone().into());
+                    }
+                    if let Some(protocol) = self.listener_protocol.get(&address) {
+                        l.insert("protocol".to_owned(), protocol.to_string().into());
+                    }
+                    let files = [
+                        ("tls_cert", &self.listener_tls_cert),
+                        ("tls_key", &self.listener_tls_key),
+                        ("client_ca", &self.listener_client_ca),
+                    ];
+                    for (key, paths) in files {
+                        if let Some(path) = paths.get(&address) {
+                            l.insert(key.to_owned(), path.display().to_string().into());
+                        }
+                    }
+                    if let Some(deny) = self.listener_client_cert_deny.get(&address) {
+                        l.insert("client_cert_deny".to_owned(), deny.clone().into());
+                    }
+                    for (key, labels) in flags {
+                        if labels.contains(&address) {
+                            l.insert(key.to_owned(), true.into());
+                        }
+                    }
+                    toml::Value::Table(l)
+                })
+                .collect::<Vec<_>>();
+            table.insert("listener".to_owned(), listeners.into());
+        }
+        match format {
+            ConfigFormat::Toml => toml::to_string(&value).map_err(|e| format!("cannot serialize config: {}", e)),
+            ConfigFormat::Json => Ok(ser::to_json(&value) + "\n"),
+        }
+    }
+
+    /// `parent_proxy` with its credentials, None when unset.
+    pub fn parent(&self) -> Result<Option<ParentProxy>, String> {
+        self.parent_proxy
+            .as_deref()
+            .map(|addr| {
+                ParentProxy::new(
+                    addr,
+                    self.parent_proxy_user.as_deref(),
+                    self.parent_proxy_secret.as_ref(),
+                )
+            })
+            .transpose()
+    }
+
+    /// `parent_socks` with its credentials, None when unset.
+    pub fn socks_parent(&self) -> Result<Option<ParentProxy>, String> {
+        self.parent_socks
+            .as_deref()
+            .map(|addr| {
+                ParentProxy::socks(
+                    addr,
+                    self.parent_socks_user.as_deref(),
+                    self.parent_socks_secret.as_ref(),
+                )
+            })
+            .transpose()
+    }
+
+    /// The parent proxies `route` goes through, None for the route of a
+    /// destination matching none; empty for direct routes.
+    pub fn parents_for(&self, route: Option<&Route>) -> Vec<ParentProxy> {
+        route.and_then(|r| self.route_parents(r).ok()).unwrap_or_default()
+    }
+
+    /// The `parents` of `route` in the order listed, with the credentials
+    /// of `parent_proxy` or `parent_socks`; that one alone for a route
+    /// listing none.
+    fn route_parents(&self, route: &Route) -> Result<Vec<ParentProxy>, String> {
+        let default = match route.via {
+            Via::Direct => return Ok(Vec::new()),
+            Via::Parent => self.parent_proxy.as_deref(),
+            Via::SocksParent => self.parent_socks.as_deref(),
+        };
+        let addresses = match route.parents.is_empty() {
+            true => default.into_iter().collect(),
+            false => route.parents.iter().map(String::as_str).collect::<Vec<_>>(),
+        };
+        addresses
+            .into_iter()
+            .map(|address| match route.via {
+                Via::SocksParent => {
+                    ParentProxy::socks(address, self.parent_socks_user.as_deref(), self.parent_socks_secret.as_ref())
+                }
+                _ => ParentProxy::new(address, self.parent_proxy_user.as_deref(), self.parent_proxy_secret.as_ref()),
+            })
+            .collect()
+    }
+
+    /// Every parent proxy of the config once, `parent_proxy` and
+    /// `parent_socks` first, then the routes' in file order; see
+    /// `parent_health`.
+    pub fn all_parents(&self) -> Vec<ParentProxy> {
+        let globals = [self.parent(), self.socks_parent()].into_iter().filter_map(|p| p.ok().flatten());
+        let routed = self.routes.iter().flat_map(|r| self.route_parents(r).unwrap_or_default());
+        let mut all = Vec::new();
+        for parent in globals.chain(routed) {
+            if !all.contains(&parent) {
+                all.push(parent);
+            }
+        }
+        all
+    }
+
+    /// The `listen_backlog` of the listener labelled `label`.
+    pub fn backlog_of(&self, label: &str) -> u32 {
+        self.listener_backlogs.get(label).copied().unwrap_or(self.listen_backlog)
+    }
+
+    /// Whether each listener in token order is in `labels`, one of the
+    /// `listener_proxy_protocol` kind of sets.
+    pub fn listeners_in(&self, labels: &HashSet<String>) -> Vec<bool> {
+        self.listener_names().iter().map(|name| labels.contains(name)).collect()
+    }
+
+    /// How each listener in token order is transparent, None for those
+    /// clients address as a proxy.
+    pub fn transparent_by_listener(&self) -> Vec<Option<Transparent>> {
+        self.listener_names()
+            .iter()
+            .map(|name| {
+                if self.listener_tproxy.contains(name) {
+                    let spoof_source = self.listener_spoof_source.contains(name);
+                    Some(Transparent::Tproxy { spoof_source })
+                } else {
+                    self.listener_transparent.contains(name).then_some(Transparent::Redirect)
+                }
+            })
+            .collect()
+    }
+
+    /// The `listener_upstream` of each listener in token order as host and
+    /// port, None for those that are not tunnels.
+    pub fn upstream_by_listener(&self) -> Vec<Option<(String, u16)>> {
+        self.listener_names()
+            .iter()
+            .map(|name| match split_host_port(self.listener_upstream.get(name)?) {
+                (host, Some(port)) => Some((host.to_owned(), port.parse().ok()?)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The `listener_protocol` of each listener in token order, None for
+    /// those going by `socks`.
+    pub fn protocol_by_listener(&self) -> Vec<Option<Protocol>> {
+        self.listener_names().iter().map(|name| self.listener_protocol.get(name).copied()).collect()
+    }
+
+    /// The TLS context of each listener in token order, None for those
+    /// clients reach in plain TCP.
+    #[cfg(feature = "tls")]
+    pub fn tls_by_listener(&self) -> Vec<Option<Arc<ServerContext>>> {
+        self.listener_names().iter().map(|name| self.tls_contexts.get(name).cloned()).collect()
+    }
+
+    /// Labels of the listeners in token order: `listen`, then `listen_unix`.
+    pub fn listener_names(&self) -> Vec<String> {
+        let tcp = self.listen.iter().map(SocketAddr::to_string);
+        let unix = self.listen_unix.iter().map(|p| format!("unix:{}", p.display()));
+        tcp.chain(unix).collect()
+    }
+
+    /// Name and profile name of each listener in token order, what the
+    /// per-listener metrics are labelled with.
+    pub fn listener_labels(&self) -> Vec<(String, String)> {
+        self.listener_names()
+            .into_iter()
+            .zip(self.profiles_by_listener())
+            .map(|(name, profile)| (name, profile.name.clone()))
+            .collect()
+    }
+
+    /// The profile of each listener in token order, for sessions to be
+    /// resolved against at accept time.
+    pub fn profiles_by_listener(&self) -> Vec<Arc<Profile>> {
+        let default = self.profiles.get(DEFAULT_PROFILE).cloned().unwrap_or_else(|| {
+            Arc::new(Profile {
+                name: DEFAULT_PROFILE.to_owned(),
+                ..Default::default()
+            })
+        });
+        self.listener_names()
+            .iter()
+            .map(|name| {
+                self.listener_profiles
+                    .get(name)
+                    .and_then(|p| self.profiles.get(p))
+                    .cloned()
+                    .unwrap_or_else(|| Arc::clone(&default))
+            })
+            .collect()
+    }
+
+    /// Keys of the settings that differ from `running` but are fixed for
+    /// the life of the process: sockets, threads and limits set up at
+    /// startup. A reload changing any of them is refused.
+    pub fn restart_only_changes(&self, running: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        let mut check = |key, same: bool| {
+            if !same {
+                changed.push(key);
+            }
+        };
+        check("listen", self.listen == running.listen);
+        check("ipv6_only", self.ipv6_only == running.ipv6_only);
+        check(
+            "listen_backlog",
+            self.listen_backlog == running.listen_backlog && self.listener_backlogs == running.listener_backlogs,
+        );
+        check("listen_unix", self.listen_unix == running.listen_unix);
+        check("proxy_protocol", self.listener_proxy_protocol == running.listener_proxy_protocol);
+        check("transparent", self.listener_transparent == running.listener_transparent);
+        check("tproxy", self.listener_tproxy == running.listener_tproxy);
+        check("spoof_source", self.listener_spoof_source == running.listener_spoof_source);
+        check("listen_unix_mode", self.listen_unix_mode == running.listen_unix_mode);
+        check("listen_unix_owner", self.listen_unix_owner == running.listen_unix_owner);
+        check("workers", self.workers == running.workers);
+        check("accept_mode", self.accept_mode == running.accept_mode);
+        check("nofile", self.nofile == running.nofile);
+        check("events_capacity", self.events_capacity == running.events_capacity);
+        check("worker_affinity", self.worker_affinity == running.worker_affinity);
+        check("poll_mode", self.poll_mode == running.poll_mode);
+        check("acceptor_core", self.acceptor_core == running.acceptor_core);
+        check("admin", self.admin_listen == running.admin_listen);
+        check("daemon", self.daemon == running.daemon);
+        check("pidfile", self.pidfile == running.pidfile);
+        check("user", self.user == running.user);
+        check("group", self.group == running.group);
+        check("lockdown", self.lockdown == running.lockdown);
+        check("seccomp", self.seccomp == running.seccomp);
+        // the running filter has no room for an export thread it was built without
+        check("otlp_endpoint", !running.seccomp || running.otlp_endpoint.is_some() || self.otlp_endpoint.is_none());
+        changed
+    }
+}
+
+/// Parses a size like `4096`, `512K`, `100M` or `1G` (powers of 1024).
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: u64 = num.parse().map_err(|_| format!("invalid size {:?}", s))?;
+    let shift = match unit {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        _ => return Err(format!("invalid size unit {:?} in {:?}, use K, M or G", unit, s)),
+    };
+    num.checked_mul(1 << shift)
+        .filter(|&n| n > 0)
+        .ok_or_else(|| format!("size {:?} out of range", s))
+}
+
+/// `host:port` and path of an `http://host:port[/path]` URL, the path
+/// None when left out.
+pub fn split_http_url(url: &str) -> Option<(&str, Option<&str>)> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], Some(&rest[i..])),
+        None => (rest, None),
+    };
+    match split_host_port(authority) {
+        (h, Some(p)) if !h.is_empty() && p.parse::<u16>().is_ok() => Some((authority, path)),
+        _ => None,
+    }
+}
+
+/// Parses octal permission bits like `660` or `0o660`.
+pub fn parse_mode(s: &str) -> Result<u32, String> {
+    let digits = s.strip_prefix("0o").unwrap_or(s);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("invalid mode {:?}, expected octal like 0660", s)),
+    }
+}
+
+/// Parses a duration like `300`, `300s`, `500ms`, `5m` or `1h`; a bare
+/// number is seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let n: u64 = num
+        .parse()
+        .map_err(|_| format!("invalid duration {:?}, expected e.g. 300s or 5m", s))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(n)),
+        "" | "s" => Ok(Duration::from_secs(n)),
+        "m" => Ok(Duration::from_secs(n * 60)),
+        "h" => Ok(Duration::from_secs(n * 3600)),
+        _ => Err(format!("invalid duration unit {:?} in {:?}, use ms, s, m or h", unit, s)),
+    }
+}
+
+/// Settings read from a TOML file or the environment, every key optional.
+/// Keys are the long command line flags with `_` for `-`; see
+/// proxy.example.toml. Per-value checks happen while parsing so errors
+/// point at the offending line or variable.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    listen: Option<Vec<SocketAddr>>,
+    ipv6_only: Option<bool>,
+    listen_backlog: Option<NonZeroU32>,
+    listen_unix: Option<PathBuf>,
+    #[serde(default, deserialize_with = "mode_opt")]
+    listen_unix_mode: Option<u32>,
+    listen_unix_owner: Option<String>,
+    workers: Option<NonZeroUsize>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    accept_mode: Option<AcceptMode>,
+    max_sessions: Option<NonZeroUsize>,
+    nofile: Option<NonZeroU64>,
+    events_capacity: Option<NonZeroUsize>,
+    #[serde(default, deserialize_with = "duration_opt")]
+    idle_timeout: Option<Duration>,
+    pipe_budget: Option<NonZeroUsize>,
+    websocket_max_frame: Option<NonZeroUsize>,
+    dns_cache: Option<bool>,
+    dns_cache_page: Option<NonZeroUsize>,
+    #[serde(default, deserialize_with = "duration_opt")]
+    slow_event: Option<Duration>,
+    #[serde(default, deserialize_with = "duration_opt")]
+    slow_establishment: Option<Duration>,
+    #[serde(default, deserialize_with = "duration_opt")]
+    loop_stall: Option<Duration>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    loop_stall_action: Option<StallAction>,
+    loop_sample_every: Option<u64>,
+    dump_bad_heads: Option<bool>,
+    accept_batch: Option<NonZeroUsize>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    worker_affinity: Option<Affinity>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    poll_mode: Option<PollMode>,
+    so_busy_poll: Option<u32>,
+    acceptor_core: Option<usize>,
+    admin: Option<SocketAddr>,
+    capture_dir: Option<PathBuf>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    metrics: Option<Export>,
+    statsd: Option<String>,
+    statsd_prefix: Option<String>,
+    statsd_tags: Option<Vec<String>>,
+    otlp_endpoint: Option<String>,
+    otlp_sample_ratio: Option<f64>,
+    failing_host_min_failures: Option<u64>,
+    failing_host_ratio: Option<f64>,
+    #[serde(default, deserialize_with = "duration_opt")]
+    drain_timeout: Option<Duration>,
+    daemon: Option<bool>,
+    pidfile: Option<PathBuf>,
+    user: Option<String>,
+    group: Option<String>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    lockdown: Option<Lockdown>,
+    seccomp: Option<bool>,
+    log_file: Option<PathBuf>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    log_format: Option<LogFormat>,
+    #[serde(default, deserialize_with = "size_opt")]
+    log_max_size: Option<u64>,
+    log_keep: Option<usize>,
+    #[serde(default, deserialize_with = "duration_opt")]
+    log_level_revert: Option<Duration>,
+    access_log: Option<PathBuf>,
+    audit_log: Option<PathBuf>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    syslog: Option<syslog::Target>,
+    syslog_app_name: Option<String>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    log_facility: Option<Facility>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    access_log_facility: Option<Facility>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    audit_log_facility: Option<Facility>,
+    timeouts: Option<FileTimeouts>,
+    parent_proxy: Option<String>,
+    parent_proxy_user: Option<String>,
+    parent_proxy_password: Option<String>,
+    parent_proxy_password_file: Option<PathBuf>,
+    parent_proxy_password_env: Option<String>,
+    /// the one of the three above that is set, see `load`
+    #[serde(skip)]
+    parent_proxy_password_source: Option<SecretSource>,
+    parent_socks: Option<String>,
+    parent_socks_user: Option<String>,
+    parent_socks_password: Option<String>,
+    parent_socks_password_file: Option<PathBuf>,
+    parent_socks_password_env: Option<String>,
+    #[serde(skip)]
+    parent_socks_password_source: Option<SecretSource>,
+    #[serde(default, deserialize_with = "duration_opt")]
+    parent_backoff: Option<Duration>,
+    #[serde(default, deserialize_with = "duration_opt")]
+    parent_timeout: Option<Duration>,
+    parent_probe: Option<String>,
+    #[serde(default, deserialize_with = "duration_opt")]
+    parent_probe_every: Option<Duration>,
+    upstream_mptcp: Option<bool>,
+    route: Option<Vec<FileRoute>>,
+    acl: Option<Vec<FileAclRule>>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    acl_default: Option<AclAction>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    acl_timezone: Option<TimeZone>,
+    acl_window_close: Option<bool>,
+    acl_set: Option<BTreeMap<String, FileAclSet>>,
+    users: Option<BTreeMap<String, FileUser>>,
+    quota_reset_hour: Option<u8>,
+    quota_state: Option<PathBuf>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    allowed_ports: Option<PortSet>,
+    auth_file: Option<PathBuf>,
+    auth_realm: Option<String>,
+    #[serde(default, deserialize_with = "cidrs_opt")]
+    deny_clients: Option<Vec<Cidr>>,
+    #[serde(default, deserialize_with = "cidrs_opt")]
+    allow_clients: Option<Vec<Cidr>>,
+    deny_clients_403: Option<bool>,
+    conn_rate: Option<f64>,
+    conn_rate_burst: Option<u32>,
+    #[serde(default, deserialize_with = "cidrs_opt")]
+    conn_rate_exempt: Option<Vec<Cidr>>,
+    conn_rate_429: Option<bool>,
+    conn_rate_table: Option<NonZeroUsize>,
+    accept_rate: Option<f64>,
+    accept_rate_burst: Option<u32>,
+    accept_rate_shed: Option<bool>,
+    auth_ban_failures: Option<u32>,
+    #[serde(default, deserialize_with = "duration_opt")]
+    auth_ban_window: Option<Duration>,
+    #[serde(default, deserialize_with = "duration_opt")]
+    auth_ban_for: Option<Duration>,
+    #[serde(default, deserialize_with = "cidrs_opt")]
+    auth_ban_exempt: Option<Vec<Cidr>>,
+    auth_ban_state: Option<PathBuf>,
+    block_internal: Option<bool>,
+    #[serde(default, deserialize_with = "cidrs_opt")]
+    allow_internal: Option<Vec<Cidr>>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    sni_check: Option<SniCheck>,
+    sni_allow_subdomains: Option<bool>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    transparent_sni: Option<TransparentSni>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    host_check: Option<HostCheck>,
+    socks: Option<bool>,
+    geoip_country_db: Option<PathBuf>,
+    geoip_asn_db: Option<PathBuf>,
+    listener: Option<Vec<FileListener>>,
+    profile: Option<BTreeMap<String, FileProfile>>,
+    /// applied before anything is logged, `--log-level` still wins
+    pub log_level: Option<String>,
+}
+
+/// The `[timeouts]` section. Only the file has overrides, the environment
+/// and flags set the global values.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileTimeouts {
+    #[serde(default, deserialize_with = "duration_opt")]
+    idle: Option<Duration>,
+    /// `"none"` is Some(None), no cap
+    #[serde(default, deserialize_with = "lifetime_opt")]
+    lifetime: Option<Option<Duration>>,
+    #[serde(default, rename = "override")]
+    overrides: Option<Vec<FileTimeoutOverride>>,
+}
+
+/// One `[[listener]]` table, an alternative to `listen` and `listen_unix`
+/// that can name a profile.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileListener {
+    #[serde(deserialize_with = "from_str_req")]
+    address: ListenAddress,
+    profile: Option<String>,
+    backlog: Option<NonZeroU32>,
+    proxy_protocol: Option<bool>,
+    transparent: Option<bool>,
+    tproxy: Option<bool>,
+    spoof_source: Option<bool>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    http2: Option<bool>,
+    client_ca: Option<PathBuf>,
+    require_client_cert: Option<bool>,
+    client_cert_deny: Option<Vec<String>>,
+    upstream: Option<String>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    protocol: Option<Protocol>,
+}
+
+/// `host:port`, or `unix:/path` for the unix socket.
+#[derive(Debug)]
+enum ListenAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(ListenAddress::Unix(PathBuf::from(path))),
+            None => s
+                .parse()
+                .map(ListenAddress::Tcp)
+                .map_err(|_| format!("invalid listen address {:?}, expected host:port or unix:/path", s)),
+        }
+    }
+}
+
+/// One `[profile.NAME]` table; what it leaves unset comes from the top
+/// level settings.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileProfile {
+    auth: Option<bool>,
+    timeouts: Option<FileTimeouts>,
+}
+
+/// One `[[route]]` table.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileRoute {
+    #[serde(deserialize_with = "host_patterns")]
+    hosts: Vec<HostPattern>,
+    #[serde(deserialize_with = "from_str_req")]
+    action: Via,
+    #[serde(default)]
+    proxy_protocol: bool,
+    unix_socket: Option<PathBuf>,
+    #[serde(default)]
+    wrap_tls: bool,
+    tls_sni: Option<String>,
+    tls_verify: Option<bool>,
+    tls_ca: Option<PathBuf>,
+    mptcp: Option<bool>,
+    #[serde(default)]
+    parents: Vec<String>,
+}
+
+/// One `[[acl]]` table.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileAclRule {
+    #[serde(default, deserialize_with = "host_patterns")]
+    hosts: Vec<HostPattern>,
+    #[serde(default, deserialize_with = "host_regexes")]
+    regex: Vec<HostRegex>,
+    #[serde(default, deserialize_with = "countries")]
+    countries: Vec<Country>,
+    #[serde(default)]
+    asns: Vec<u32>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    ports: Option<PortSet>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    hours: Option<Hours>,
+    #[serde(default, deserialize_with = "days_opt")]
+    days: Option<Days>,
+    #[serde(deserialize_with = "from_str_req")]
+    action: AclAction,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    limit: Option<Bandwidth>,
+}
+
+/// One `[acl_set.NAME]` table.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileAclSet {
+    #[serde(default)]
+    rule: Vec<FileAclRule>,
+    #[serde(deserialize_with = "from_str_req")]
+    default: AclAction,
+}
+
+/// One `[users.NAME]` table.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileUser {
+    acl_set: Option<String>,
+    #[serde(default, deserialize_with = "size_opt")]
+    daily_quota: Option<u64>,
+}
+
+/// One `[[timeouts.override]]` table.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileTimeoutOverride {
+    #[serde(deserialize_with = "host_patterns")]
+    hosts: Vec<HostPattern>,
+    #[serde(default, deserialize_with = "duration_opt")]
+    idle: Option<Duration>,
+}
+
+impl FileConfig {
+    pub fn load(path: &Path) -> Result<FileConfig, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        let mut file: FileConfig = toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+        file.parent_proxy_password_source = SecretSource::pick(
+            "parent_proxy_password",
+            file.parent_proxy_password.take(),
+            file.parent_proxy_password_file.take(),
+            file.parent_proxy_password_env.take(),
+        )
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+        file.parent_socks_password_source = SecretSource::pick(
+            "parent_socks_password",
+            file.parent_socks_password.take(),
+            file.parent_socks_password_file.take(),
+            file.parent_socks_password_env.take(),
+        )
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+        if file.idle_timeout.is_some() && file.timeouts.as_ref().is_some_and(|t| t.idle.is_some()) {
+            return Err(format!("{}: idle_timeout and timeouts.idle both set, keep one", path.display()));
+        }
+        if let Some(listeners) = &file.listener {
+            if file.listen.is_some() {
+                return Err(format!("{}: listen and [[listener]] both set, keep one", path.display()));
+            }
+            let unix = listeners
+                .iter()
+                .filter(|l| matches!(l.address, ListenAddress::Unix(_)))
+                .count();
+            if unix + file.listen_unix.iter().count() > 1 {
+                return Err(format!("{}: only one unix socket listener is supported", path.display()));
+            }
+        }
+        Ok(file)
+    }
+
+    /// Reads `THIN_PROXY_<KEY>` variables, e.g. THIN_PROXY_IDLE_TIMEOUT=5m,
+    /// lists comma separated. `THIN_PROXY_<KEY>_FILE` names a file holding
+    /// the value instead, which keeps secrets out of the environment dump.
+    pub fn from_env() -> Result<FileConfig, String> {
+        let mut c = FileConfig::default();
+        for (name, raw) in env::vars() {
+            let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            if name == upgrade::LISTEN_FDS_ENV {
+                continue;
+            }
+            let (key, value, shown) = match key.strip_suffix("_FILE") {
+                Some(key) => {
+                    let value = fs::read_to_string(&raw)
+                        .map_err(|e| format!("{}: cannot read {}: {}", name, raw, e))?;
+                    (key, value.trim().to_owned(), format!("{} ({})", name, raw))
+                }
+                None => (key, raw.clone(), format!("{}={}", name, raw)),
+            };
+            let bad = |what: &str| format!("{} is not {}", shown, what);
+            let int = || bad("a positive integer");
+            let why = |e: String| format!("{}: {}", shown, e);
+            match key {
+                "LISTEN" => {
+                    c.listen = Some(
+                        value
+                            .split(',')
+                            .map(|a| a.trim().parse())
+                            .collect::<Result<_, _>>()
+                            .map_err(|_| bad("a comma separated list of addresses"))?,
+                    )
+                }
+                "IPV6_ONLY" => c.ipv6_only = Some(value.parse().map_err(|_| bad("true or false"))?),
+                "LISTEN_BACKLOG" => c.listen_backlog = Some(value.parse().map_err(|_| int())?),
+                "LISTEN_UNIX" => c.listen_unix = Some(PathBuf::from(value)),
+                "LISTEN_UNIX_MODE" => c.listen_unix_mode = Some(parse_mode(&value).map_err(why)?),
+                "LISTEN_UNIX_OWNER" => c.listen_unix_owner = Some(value),
+                "WORKERS" => c.workers = Some(value.parse().map_err(|_| int())?),
+                "ACCEPT_MODE" => c.accept_mode = Some(value.parse().map_err(why)?),
+                "MAX_SESSIONS" => c.max_sessions = Some(value.parse().map_err(|_| int())?),
+                "NOFILE" => c.nofile = Some(value.parse().map_err(|_| int())?),
+                "EVENTS_CAPACITY" => c.events_capacity = Some(value.parse().map_err(|_| int())?),
+                "IDLE_TIMEOUT" => {
+                    c.idle_timeout = Some(parse_duration(&value).map_err(|_| bad("a duration"))?)
+                }
+                "PIPE_BUDGET" => c.pipe_budget = Some(value.parse().map_err(|_| int())?),
+                "WEBSOCKET_MAX_FRAME" => c.websocket_max_frame = Some(value.parse().map_err(|_| int())?),
+                "DNS_CACHE" => c.dns_cache = Some(value.parse().map_err(|_| bad("true or false"))?),
+                "DNS_CACHE_PAGE" => c.dns_cache_page = Some(value.parse().map_err(|_| int())?),
+                "SLOW_EVENT" => {
+                    c.slow_event = Some(parse_duration(&value).map_err(|_| bad("a duration"))?)
+                }
+                "SLOW_ESTABLISHMENT" => {
+                    c.slow_establishment = Some(parse_duration(&value).map_err(|_| bad("a duration"))?)
+                }
+                "LOOP_STALL" => c.loop_stall = Some(parse_duration(&value).map_err(|_| bad("a duration"))?),
+                "LOOP_STALL_ACTION" => c.loop_stall_action = Some(value.parse().map_err(why)?),
+                "LOOP_SAMPLE_EVERY" => c.loop_sample_every = Some(value.parse().map_err(|_| int())?),
+                "DUMP_BAD_HEADS" => c.dump_bad_heads = Some(value.parse().map_err(|_| bad("true or false"))?),
+                "ACCEPT_BATCH" => c.accept_batch = Some(value.parse().map_err(|_| int())?),
+                "WORKER_AFFINITY" => {
+                    c.worker_affinity = Some(value.parse().map_err(why)?)
+                }
+                "POLL_MODE" => c.poll_mode = Some(value.parse().map_err(why)?),
+                "SO_BUSY_POLL" => {
+                    c.so_busy_poll =
+                        Some(value.parse().map_err(|_| bad("a number of microseconds"))?)
+                }
+                "ACCEPTOR_CORE" => {
+                    c.acceptor_core = Some(value.parse().map_err(|_| bad("a core number"))?)
+                }
+                "ADMIN" => c.admin = Some(value.parse().map_err(|_| bad("an address"))?),
+                "CAPTURE_DIR" => c.capture_dir = Some(PathBuf::from(value)),
+                "METRICS" => c.metrics = Some(value.parse().map_err(why)?),
+                "STATSD" => c.statsd = Some(value),
+                "STATSD_PREFIX" => c.statsd_prefix = Some(value),
+                "STATSD_TAGS" => {
+                    c.statsd_tags = Some(
+                        value
+                            .split(',')
+                            .map(str::trim)
+                            .filter(|t| !t.is_empty())
+                            .map(str::to_owned)
+                            .collect(),
+                    )
+                }
+                "OTLP_ENDPOINT" => c.otlp_endpoint = Some(value),
+                "OTLP_SAMPLE_RATIO" => c.otlp_sample_ratio = Some(value.parse().map_err(|_| bad("a number"))?),
+                "FAILING_HOST_MIN_FAILURES" => {
+                    c.failing_host_min_failures = Some(value.parse().map_err(|_| bad("a number"))?)
+                }
+                "FAILING_HOST_RATIO" => c.failing_host_ratio = Some(value.parse().map_err(|_| bad("a number"))?),
+                "DRAIN_TIMEOUT" => {
+                    c.drain_timeout = Some(parse_duration(&value).map_err(|_| bad("a duration"))?)
+                }
+                "DAEMON" => c.daemon = Some(value.parse().map_err(|_| bad("true or false"))?),
+                "PIDFILE" => c.pidfile = Some(PathBuf::from(value)),
+                "PARENT_PROXY" => c.parent_proxy = Some(value),
+                "PARENT_PROXY_USER" => c.parent_proxy_user = Some(value),
+                "PARENT_PROXY_PASSWORD" => {
+                    c.parent_proxy_password_source = Some(SecretSource::Inline(Secret::new(value)));
+                }
+                "PARENT_SOCKS" => c.parent_socks = Some(value),
+                "PARENT_SOCKS_USER" => c.parent_socks_user = Some(value),
+                "PARENT_SOCKS_PASSWORD" => {
+                    c.parent_socks_password_source = Some(SecretSource::Inline(Secret::new(value)));
+                }
+                "PARENT_BACKOFF" => c.parent_backoff = Some(parse_duration(&value).map_err(|_| bad("a duration"))?),
+                "PARENT_TIMEOUT" => c.parent_timeout = Some(parse_duration(&value).map_err(|_| bad("a duration"))?),
+                "PARENT_PROBE" => c.parent_probe = Some(value),
+                "PARENT_PROBE_EVERY" => {
+                    c.parent_probe_every = Some(parse_duration(&value).map_err(|_| bad("a duration"))?);
+                }
+                "UPSTREAM_MPTCP" => c.upstream_mptcp = Some(value.parse().map_err(|_| bad("true or false"))?),
+                "ACL_DEFAULT" => c.acl_default = Some(value.parse().map_err(why)?),
+                "ACL_TIMEZONE" => c.acl_timezone = Some(value.parse().map_err(why)?),
+                "ACL_WINDOW_CLOSE" => c.acl_window_close = Some(value.parse().map_err(|_| bad("true or false"))?),
+                "QUOTA_RESET_HOUR" => c.quota_reset_hour = Some(value.parse().map_err(|_| bad("an hour, 0 to 23"))?),
+                "QUOTA_STATE" => c.quota_state = Some(PathBuf::from(value)),
+                "ALLOWED_PORTS" => c.allowed_ports = Some(value.parse().map_err(why)?),
+                "AUTH_FILE" => c.auth_file = Some(PathBuf::from(value)),
+                "AUTH_REALM" => c.auth_realm = Some(value),
+                "DENY_CLIENTS" => c.deny_clients = Some(cidr_list(&value).map_err(why)?),
+                "ALLOW_CLIENTS" => c.allow_clients = Some(cidr_list(&value).map_err(why)?),
+                "DENY_CLIENTS_403" => c.deny_clients_403 = Some(value.parse().map_err(|_| bad("true or false"))?),
+                "CONN_RATE" => c.conn_rate = Some(value.parse().map_err(|_| bad("a number"))?),
+                "CONN_RATE_BURST" => c.conn_rate_burst = Some(value.parse().map_err(|_| int())?),
+                "CONN_RATE_EXEMPT" => c.conn_rate_exempt = Some(cidr_list(&value).map_err(why)?),
+                "CONN_RATE_429" => c.conn_rate_429 = Some(value.parse().map_err(|_| bad("true or false"))?),
+                "CONN_RATE_TABLE" => c.conn_rate_table = Some(value.parse().map_err(|_| int())?),
+                "ACCEPT_RATE" => c.accept_rate = Some(value.parse().map_err(|_| bad("a number"))?),
+                "ACCEPT_RATE_BURST" => c.accept_rate_burst = Some(value.parse().map_err(|_| int())?),
+                "ACCEPT_RATE_SHED" => c.accept_rate_shed = Some(value.parse().map_err(|_| bad("true or false"))?),
+                "AUTH_BAN_FAILURES" => c.auth_ban_failures = Some(value.parse().map_err(|_| int())?),
+                "AUTH_BAN_WINDOW" => {
+                    c.auth_ban_window = Some(parse_duration(&value).map_err(|_| bad("a duration"))?)
+                }
+                "AUTH_BAN_FOR" => c.auth_ban_for = Some(parse_duration(&value).map_err(|_| bad("a duration"))?),
+                "AUTH_BAN_EXEMPT" => c.auth_ban_exempt = Some(cidr_list(&value).map_err(why)?),
+                "AUTH_BAN_STATE" => c.auth_ban_state = Some(PathBuf::from(value)),
+                "BLOCK_INTERNAL" => c.block_internal = Some(value.parse().map_err(|_| bad("true or false"))?),
+                "ALLOW_INTERNAL" => c.allow_internal = Some(cidr_list(&value).map_err(why)?),
+                "GEOIP_COUNTRY_DB" => c.geoip_country_db = Some(PathBuf::from(value)),
+                "GEOIP_ASN_DB" => c.geoip_asn_db = Some(PathBuf::from(value)),
+                "SNI_CHECK" => c.sni_check = Some(value.parse().map_err(why)?),
+                "SNI_ALLOW_SUBDOMAINS" => {
+                    c.sni_allow_subdomains = Some(value.parse().map_err(|_| bad("true or false"))?)
+                }
+                "TRANSPARENT_SNI" => c.transparent_sni = Some(value.parse().map_err(why)?),
+                "HOST_CHECK" => c.host_check = Some(value.parse().map_err(why)?),
+                "SOCKS" => c.socks = Some(value.parse().map_err(|_| bad("true or false"))?),
+                "USER" => c.user = Some(value),
+                "GROUP" => c.group = Some(value),
+                "LOCKDOWN" => c.lockdown = Some(value.parse().map_err(why)?),
+                "SECCOMP" => c.seccomp = Some(value.parse().map_err(|_| bad("true or false"))?),
+                "LOG_LEVEL" => c.log_level = Some(value),
+                "LOG_FILE" => c.log_file = Some(PathBuf::from(value)),
+                "LOG_FORMAT" => c.log_format = Some(value.parse().map_err(why)?),
+                "LOG_MAX_SIZE" => c.log_max_size = Some(parse_size(&value).map_err(why)?),
+                "LOG_KEEP" => c.log_keep = Some(value.parse().map_err(|_| bad("a number of files"))?),
+                "LOG_LEVEL_REVERT" => {
+                    c.log_level_revert = Some(parse_duration(&value).map_err(|_| bad("a duration"))?)
+                }
+                "ACCESS_LOG" => c.access_log = Some(PathBuf::from(value)),
+                "AUDIT_LOG" => c.audit_log = Some(PathBuf::from(value)),
+                "SYSLOG" => c.syslog = Some(value.parse().map_err(why)?),
+                "SYSLOG_APP_NAME" => c.syslog_app_name = Some(value),
+                "LOG_FACILITY" => c.log_facility = Some(value.parse().map_err(why)?),
+                "ACCESS_LOG_FACILITY" => c.access_log_facility = Some(value.parse().map_err(why)?),
+                "AUDIT_LOG_FACILITY" => c.audit_log_facility = Some(value.parse().map_err(why)?),
+                _ => return Err(format!("unknown setting {}", name)),
+            }
+        }
+        Ok(c)
+    }
+
+    /// Overrides the fields of `config` the file sets.
+    pub fn apply(self, config: &mut Config) {
+        if let Some(v) = self.listen {
+            config.listen = v;
+        }
+        if let Some(v) = self.ipv6_only {
+            config.ipv6_only = v;
+        }
+        if let Some(v) = self.listen_backlog {
+            config.listen_backlog = v.get();
+        }
+        if let Some(v) = self.listen_unix {
+            config.listen_unix = Some(v);
+        }
+        if let Some(v) = self.listen_unix_mode {
+            config.listen_unix_mode = Some(v);
+        }
+        if let Some(v) = self.listen_unix_owner {
+            config.listen_unix_owner = Some(v);
+        }
+        if let Some(v) = self.workers {
+            config.workers = v.get();
+        }
+        if let Some(v) = self.accept_mode {
+            config.accept_mode = v;
+        }
+        if let Some(v) = self.max_sessions {
+            config.max_sessions = Some(v.get());
+        }
+        if let Some(v) = self.nofile {
+            config.nofile = Some(v.get());
+        }
+        if let Some(v) = self.events_capacity {
+            config.events_capacity = v.get();
+        }
+        if let Some(v) = self.idle_timeout {
+            config.timeouts.idle = v;
+        }
+        if let Some(t) = self.timeouts {
+            if let Some(v) = t.idle {
+                config.timeouts.idle = v;
+            }
+            if let Some(v) = t.lifetime {
+                config.timeouts.lifetime = v;
+            }
+            if let Some(v) = t.overrides {
+                config.timeouts.overrides = timeout_overrides(v);
+            }
+        }
+        if let Some(v) = self.listener {
+            config.listen.clear();
+            config.listener_profiles.clear();
+            config.listener_backlogs.clear();
+            config.listener_proxy_protocol.clear();
+            config.listener_transparent.clear();
+            config.listener_tproxy.clear();
+            config.listener_spoof_source.clear();
+            config.listener_tls_cert.clear();
+            config.listener_tls_key.clear();
+            config.listener_http2.clear();
+            config.listener_client_ca.clear();
+            config.listener_require_client_cert.clear();
+            config.listener_client_cert_deny.clear();
+            config.listener_upstream.clear();
+            config.listener_protocol.clear();
+            for l in v {
+                let label = match l.address {
+                    ListenAddress::Tcp(addr) => {
+                        config.listen.push(addr);
+                        addr.to_string()
+                    }
+                    ListenAddress::Unix(path) => {
+                        let label = format!("unix:{}", path.display());
+                        config.listen_unix = Some(path);
+                        label
+                    }
+                };
+                if let Some(backlog) = l.backlog {
+                    config.listener_backlogs.insert(label.clone(), backlog.get());
+                }
+                let flags = [
+                    (l.proxy_protocol, &mut config.listener_proxy_protocol),
+                    (l.transparent, &mut config.listener_transparent),
+                    (l.tproxy, &mut config.listener_tproxy),
+                    (l.spoof_source, &mut config.listener_spoof_source),
+                    (l.http2, &mut config.listener_http2),
+                    (l.require_client_cert, &mut config.listener_require_client_cert),
+                ];
+                for (_, labels) in flags.into_iter().filter(|(on, _)| *on == Some(true)) {
+                    labels.insert(label.clone());
+                }
+                if let Some(cert) = l.tls_cert {
+                    config.listener_tls_cert.insert(label.clone(), cert);
+                }
+                if let Some(key) = l.tls_key {
+                    config.listener_tls_key.insert(label.clone(), key);
+                }
+                if let Some(ca) = l.client_ca {
+                    config.listener_client_ca.insert(label.clone(), ca);
+                }
+                if let Some(deny) = l.client_cert_deny {
+                    config.listener_client_cert_deny.insert(label.clone(), deny);
+                }
+                if let Some(upstream) = l.upstream {
+                    config.listener_upstream.insert(label.clone(), upstream);
+                }
+                if let Some(protocol) = l.protocol {
+                    config.listener_protocol.insert(label.clone(), protocol);
+                }
+                if let Some(profile) = l.profile {
+                    config.listener_profiles.insert(label, profile);
+                }
+            }
+        }
+        if let Some(v) = self.profile {
+            config.profiles = v
+                .into_iter()
+                .map(|(name, p)| {
+                    let t = p.timeouts.unwrap_or_default();
+                    let profile = Profile {
+                        name: name.clone(),
+                        auth: p.auth,
+                        idle_timeout: t.idle,
+                        lifetime: t.lifetime,
+                        timeout_overrides: t.overrides.map(timeout_overrides),
+                    };
+                    (name, Arc::new(profile))
+                })
+                .collect();
+        }
+        if let Some(v) = self.pipe_budget {
+            config.pipe_budget = v.get();
+        }
+        if let Some(v) = self.websocket_max_frame {
+            config.websocket_max_frame = v.get();
+        }
+        if let Some(v) = self.dns_cache {
+            config.dns_cache = v;
+        }
+        if let Some(v) = self.dns_cache_page {
+            config.dns_cache_page = v.get();
+        }
+        if let Some(v) = self.slow_event {
+            config.slow_event = v;
+        }
+        if let Some(v) = self.slow_establishment {
+            config.slow_establishment = v;
+        }
+        if let Some(v) = self.loop_stall {
+            config.loop_stall = v;
+        }
+        if let Some(v) = self.loop_stall_action {
+            config.loop_stall_action = v;
+        }
+        if let Some(v) = self.loop_sample_every {
+            config.loop_sample_every = Some(v);
+        }
+        if let Some(v) = self.dump_bad_heads {
+            config.dump_bad_heads = v;
+        }
+        if let Some(v) = self.accept_batch {
+            config.accept_batch = v.get();
+        }
+        if let Some(v) = self.worker_affinity {
+            config.worker_affinity = v;
+        }
+        if let Some(v) = self.poll_mode {
+            config.poll_mode = v;
+        }
+        if let Some(v) = self.so_busy_poll {
+            config.so_busy_poll = Some(v);
+        }
+        if let Some(v) = self.acceptor_core {
+            config.acceptor_core = Some(v);
+        }
+        if let Some(v) = self.admin {
+            config.admin_listen = Some(v);
+        }
+        if let Some(v) = self.capture_dir {
+            config.capture_dir = Some(v);
+        }
+        if let Some(v) = self.metrics {
+            config.metrics = v;
+        }
+        if let Some(v) = self.statsd {
+            config.statsd = Some(v);
+        }
+        if let Some(v) = self.statsd_prefix {
+            config.statsd_prefix = v;
+        }
+        if let Some(v) = self.statsd_tags {
+            config.statsd_tags = Some(v);
+        }
+        if let Some(v) = self.otlp_endpoint {
+            config.otlp_endpoint = Some(v);
+        }
+        if let Some(v) = self.otlp_sample_ratio {
+            config.otlp_sample_ratio = v;
+        }
+        if let Some(v) = self.failing_host_min_failures {
+            config.failing_host_min_failures = v;
+        }
+        if let Some(v) = self.failing_host_ratio {
+            config.failing_host_ratio = v;
+        }
+        if let Some(v) = self.drain_timeout {
+            config.drain_timeout = v;
+        }
+        if let Some(v) = self.daemon {
+            config.daemon = v;
+        }
+        if let Some(v) = self.pidfile {
+            config.pidfile = Some(v);
+        }
+        if let Some(v) = self.parent_proxy {
+            config.parent_proxy = Some(v);
+        }
+        if let Some(v) = self.parent_proxy_user {
+            config.parent_proxy_user = Some(v);
+        }
+        if let Some(v) = self.parent_proxy_password_source {
+            config.parent_proxy_password = Some(v);
+        }
+        if let Some(v) = self.parent_socks {
+            config.parent_socks = Some(v);
+        }
+        if let Some(v) = self.parent_socks_user {
+            config.parent_socks_user = Some(v);
+        }
+        if let Some(v) = self.parent_socks_password_source {
+            config.parent_socks_password = Some(v);
+        }
+        if let Some(v) = self.parent_backoff {
+            config.parent_backoff = v;
+        }
+        if let Some(v) = self.parent_timeout {
+            config.parent_timeout = v;
+        }
+        if let Some(v) = self.parent_probe {
+            config.parent_probe = Some(v);
+        }
+        if let Some(v) = self.parent_probe_every {
+            config.parent_probe_every = v;
+        }
+        if let Some(v) = self.upstream_mptcp {
+            config.upstream_mptcp = v;
+        }
+        if let Some(v) = self.route {
+            config.routes = v
+                .into_iter()
+                .map(|r| Route {
+                    hosts: r.hosts,
+                    via: r.action,
+                    proxy_protocol: r.proxy_protocol,
+                    unix_socket: r.unix_socket,
+                    wrap_tls: r.wrap_tls,
+                    tls_sni: r.tls_sni,
+                    tls_verify: r.tls_verify.unwrap_or(true),
+                    tls_ca: r.tls_ca,
+                    mptcp: r.mptcp,
+                    parents: r.parents,
+                })
+                .collect();
+        }
+        if let Some(v) = self.acl {
+            config.acl = acl_rules(v, "acl");
+        }
+        if let Some(v) = self.acl_default {
+            config.acl_default = v;
+        }
+        if let Some(v) = self.acl_timezone {
+            config.acl_timezone = v;
+        }
+        if let Some(v) = self.acl_window_close {
+            config.acl_window_close = v;
+        }
+        if let Some(v) = self.acl_set {
+            config.acl_sets = v
+                .into_iter()
+                .map(|(name, s)| {
+                    let set = AclSet {
+                        rules: acl_rules(s.rule, &format!("acl_set.{}.rule", name)),
+                        default: s.default,
+                    };
+                    (name, Arc::new(set))
+                })
+                .collect();
+        }
+        if let Some(v) = self.users {
+            config.users = v
+                .into_iter()
+                .map(|(name, u)| {
+                    let user = UserPolicy {
+                        name: name.clone(),
+                        acl_set: u.acl_set,
+                        daily_quota: u.daily_quota,
+                    };
+                    (name, Arc::new(user))
+                })
+                .collect();
+        }
+        if let Some(v) = self.quota_reset_hour {
+            config.quota_reset_hour = v;
+        }
+        if let Some(v) = self.quota_state {
+            config.quota_state = Some(v);
+        }
+        if let Some(v) = self.allowed_ports {
+            config.allowed_ports = Some(v);
+        }
+        if let Some(v) = self.auth_file {
+            config.auth_file = Some(v);
+        }
+        if let Some(v) = self.auth_realm {
+            config.auth_realm = v;
+        }
+        if let Some(v) = self.deny_clients {
+            config.deny_clients = v;
+        }
+        if let Some(v) = self.allow_clients {
+            config.allow_clients = v;
+        }
+        if let Some(v) = self.deny_clients_403 {
+            config.deny_clients_403 = v;
+        }
+        if let Some(v) = self.conn_rate {
+            config.conn_rate = Some(v);
+        }
+        if let Some(v) = self.conn_rate_burst {
+            config.conn_rate_burst = v;
+        }
+        if let Some(v) = self.conn_rate_exempt {
+            config.conn_rate_exempt = v;
+        }
+        if let Some(v) = self.conn_rate_429 {
+            config.conn_rate_429 = v;
+        }
+        if let Some(v) = self.conn_rate_table {
+            config.conn_rate_table = v.get();
+        }
+        if let Some(v) = self.accept_rate {
+            config.accept_rate = Some(v);
+        }
+        if let Some(v) = self.accept_rate_burst {
+            config.accept_rate_burst = v;
+        }
+        if let Some(v) = self.accept_rate_shed {
+            config.accept_rate_shed = v;
+        }
+        if let Some(v) = self.auth_ban_failures {
+            config.auth_ban_failures = Some(v);
+        }
+        if let Some(v) = self.auth_ban_window {
+            config.auth_ban_window = v;
+        }
+        if let Some(v) = self.auth_ban_for {
+            config.auth_ban_for = v;
+        }
+        if let Some(v) = self.auth_ban_exempt {
+            config.auth_ban_exempt = v;
+        }
+        if let Some(v) = self.auth_ban_state {
+            config.auth_ban_state = Some(v);
+        }
+        if let Some(v) = self.block_internal {
+            config.block_internal = v;
+        }
+        if let Some(v) = self.allow_internal {
+            config.allow_internal = v;
+        }
+        if let Some(v) = self.sni_check {
+            config.sni_check = v;
+        }
+        if let Some(v) = self.sni_allow_subdomains {
+            config.sni_allow_subdomains = v;
+        }
+        if let Some(v) = self.transparent_sni {
+            config.transparent_sni = v;
+        }
+        if let Some(v) = self.host_check {
+            config.host_check = v;
+        }
+        if let Some(v) = self.socks {
+            config.socks = v;
+        }
+        if let Some(v) = self.geoip_country_db {
+            config.geoip_country_db = Some(v);
+        }
+        if let Some(v) = self.geoip_asn_db {
+            config.geoip_asn_db = Some(v);
+        }
+        if let Some(v) = self.user {
+            config.user = Some(v);
+        }
+        if let Some(v) = self.group {
+            config.group = Some(v);
+        }
+        if let Some(v) = self.lockdown {
+            config.lockdown = v;
+        }
+        if let Some(v) = self.seccomp {
+            config.seccomp = v;
+        }
+        if let Some(v) = self.log_file {
+            config.log_file = Some(v);
+        }
+        if let Some(v) = self.log_format {
+            config.log_format = v;
+        }
+        if let Some(v) = self.log_max_size {
+            config.log_max_size = Some(v);
+        }
+        if let Some(v) = self.log_keep {
+            config.log_keep = v;
+        }
+        if let Some(v) = self.log_level_revert {
+            config.log_level_revert = Some(v);
+        }
+        if let Some(v) = self.access_log {
+            config.access_log = Some(v);
+        }
+        if let Some(v) = self.audit_log {
+            config.audit_log = Some(v);
+        }
+        if let Some(v) = self.syslog {
+            config.syslog = v;
+        }
+        if let Some(v) = self.syslog_app_name {
+            config.syslog_app_name = v;
+        }
+        if let Some(v) = self.log_facility {
+            config.log_facility = Some(v);
+        }
+        if let Some(v) = self.access_log_facility {
+            config.access_log_facility = Some(v);
+        }
+        if let Some(v) = self.audit_log_facility {
+            config.audit_log_facility = Some(v);
+        }
+    }
+}
+
+fn timeout_overrides(file: Vec<FileTimeoutOverride>) -> Vec<TimeoutOverride> {
+    file.into_iter()
+        .map(|o| TimeoutOverride {
+            hosts: o.hosts,
+            idle: o.idle,
+        })
+        .collect()
+}
+
+/// The entries of a `[[acl]]` list, labelled `<path>[i]`.
+fn acl_rules(file: Vec<FileAclRule>, path: &str) -> Vec<AclRule> {
+    file.into_iter()
+        .enumerate()
+        .map(|(i, r)| AclRule {
+            label: format!("{}[{}]", path, i),
+            hosts: r.hosts,
+            regex: r.regex,
+            countries: r.countries,
+            asns: r.asns,
+            ports: r.ports,
+            hours: r.hours,
+            days: r.days,
+            action: r.action,
+            limit: r.limit,
+        })
+        .collect()
+}
+
+fn from_str_opt<'de, D, T>(d: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let s = String::deserialize(d)?;
+    s.parse().map(Some).map_err(de::Error::custom)
+}
+
+fn size_opt<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
+    let s = String::deserialize(d)?;
+    parse_size(&s).map(Some).map_err(de::Error::custom)
+}
+
+fn mode_opt<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u32>, D::Error> {
+    let s = String::deserialize(d)?;
+    parse_mode(&s).map(Some).map_err(de::Error::custom)
+}
+
+fn from_str_req<'de, D, T>(d: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let s = String::deserialize(d)?;
+    s.parse().map_err(de::Error::custom)
+}
+
+fn host_patterns<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<HostPattern>, D::Error> {
+    Vec::<String>::deserialize(d)?
+        .iter()
+        .map(|s| s.parse().map_err(de::Error::custom))
+        .collect()
+}
+
+fn host_regexes<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<HostRegex>, D::Error> {
+    Vec::<String>::deserialize(d)?
+        .iter()
+        .map(|s| s.parse().map_err(de::Error::custom))
+        .collect()
+}
+
+fn countries<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Country>, D::Error> {
+    Vec::<String>::deserialize(d)?
+        .iter()
+        .map(|s| s.parse().map_err(de::Error::custom))
+        .collect()
+}
+
+fn days_opt<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Days>, D::Error> {
+    Days::parse(&Vec::<String>::deserialize(d)?).map(Some).map_err(de::Error::custom)
+}
+
+/// Address blocks separated by commas, as the environment gives them.
+fn cidr_list(s: &str) -> Result<Vec<Cidr>, String> {
+    s.split(',').map(|c| c.trim().parse()).collect()
+}
+
+fn cidrs_opt<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<Cidr>>, D::Error> {
+    Vec::<String>::deserialize(d)?
+        .iter()
+        .map(|s| s.parse().map_err(de::Error::custom))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+fn duration_opt<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    let s = String::deserialize(d)?;
+    parse_duration(&s).map(Some).map_err(de::Error::custom)
+}
+
+fn lifetime_opt<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Option<Duration>>, D::Error> {
+    let s = String::deserialize(d)?;
+    match s.as_str() {
+        "none" => Ok(Some(None)),
+        _ => parse_duration(&s).map(|d| Some(Some(d))).map_err(de::Error::custom),
+    }
+}
@@ -0,0 +1,135 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::warn;
+
+use crate::{config::Config, internal_addrs::Cidr};
+
+/// How often a line about limited clients is logged at most.
+const LOG_WINDOW: Duration = Duration::from_secs(10);
+
+/// Accepts per source, shared by every worker: with SO_REUSEPORT one
+/// client's connections are spread over all of them.
+static LIMITER: Mutex<Option<Limiter>> = Mutex::new(None);
+
+/// A token bucket, refilled at `Config::conn_rate` per second up to
+/// `Config::conn_rate_burst`.
+struct Bucket {
+    tokens: f64,
+    at: Instant,
+    /// key into `Limiter::recent`
+    used: u64,
+}
+
+struct Limiter {
+    per_sec: f64,
+    burst: f64,
+    exempt: Vec<Cidr>,
+    capacity: usize,
+    buckets: HashMap<IpAddr, Bucket>,
+    /// sources by last accept, oldest first, the first to go when the
+    /// table is full
+    recent: BTreeMap<u64, IpAddr>,
+    next_use: u64,
+    /// when the last limited source was logged, and how many more were
+    /// limited since
+    logged: Option<(Instant, u64)>,
+}
+
+/// Takes the limits from `config`, no `conn_rate` turns limiting off.
+/// The buckets are kept over a reload, at most the new `conn_rate_table`
+/// of them.
+pub fn configure(config: &Config) {
+    let mut limiter = LIMITER.lock().unwrap();
+    let Some(per_sec) = config.conn_rate else {
+        *limiter = None;
+        return;
+    };
+    let l = limiter.get_or_insert_with(|| Limiter {
+        per_sec,
+        burst: 0.0,
+        exempt: Vec::new(),
+        capacity: 0,
+        buckets: HashMap::new(),
+        recent: BTreeMap::new(),
+        next_use: 0,
+        logged: None,
+    });
+    l.per_sec = per_sec;
+    l.burst = f64::from(config.conn_rate_burst);
+    l.exempt = config.conn_rate_exempt.clone();
+    l.capacity = config.conn_rate_table;
+    while l.buckets.len() > l.capacity {
+        l.evict_oldest();
+    }
+}
+
+/// Whether a connection from `ip` may be accepted now, taking a token
+/// from its bucket when it may. IPv6 sources share a bucket per /64, what
+/// a single host is usually given.
+pub fn admit(ip: IpAddr, now: Instant) -> bool {
+    let mut limiter = LIMITER.lock().unwrap();
+    let Some(l) = limiter.as_mut() else {
+        return true;
+    };
+    if l.exempt.iter().any(|c| c.contains(ip)) {
+        return true;
+    }
+    let key = source(ip);
+    let used = l.next_use;
+    l.next_use += 1;
+    if !l.buckets.contains_key(&key) && l.buckets.len() >= l.capacity {
+        l.evict_oldest();
+    }
+    let (burst, per_sec) = (l.burst, l.per_sec);
+    let bucket = l.buckets.entry(key).or_insert(Bucket {
+        tokens: burst,
+        at: now,
+        used,
+    });
+    l.recent.remove(&bucket.used);
+    l.recent.insert(used, key);
+    bucket.used = used;
+    bucket.tokens = (bucket.tokens + now.duration_since(bucket.at).as_secs_f64() * per_sec).min(burst);
+    bucket.at = now;
+    if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+        return true;
+    }
+    match &mut l.logged {
+        Some((at, held)) if now.duration_since(*at) < LOG_WINDOW => *held += 1,
+        logged => {
+            let held = logged.map_or(0, |(_, held)| held);
+            warn!(
+                client:% = ip;
+                "client over conn_rate {}/s burst {}, closing its connection; {} more limited since the last line",
+                per_sec, burst, held
+            );
+            *logged = Some((now, 0));
+        }
+    }
+    false
+}
+
+impl Limiter {
+    fn evict_oldest(&mut self) {
+        if let Some((_, ip)) = self.recent.pop_first() {
+            self.buckets.remove(&ip);
+        }
+    }
+}
+
+/// The bucket `ip` draws from.
+fn source(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6((u128::from(v6) & !(u128::MAX >> 64)).into()),
+        },
+        v4 => v4,
+    }
+}
@@ -0,0 +1,59 @@
+use std::{
+    fs::{File, OpenOptions},
+    os::fd::AsRawFd,
+    path::Path,
+    process,
+};
+
+use nix::unistd::{chdir, dup2, fork, setsid, ForkResult};
+
+use crate::err::Fatal;
+
+/// Detaches from the terminal the classic way: fork, setsid, fork again so
+/// the daemon can never reacquire a terminal, chdir /, and stdio to
+/// `log_file` (appended) or /dev/null. Only the final child returns; the
+/// processes in between exit 0 right away.
+///
+/// Must run while the process is still single threaded and before any
+/// poll, socket or pidfile exists, so everything the daemon uses belongs
+/// to the daemon.
+pub fn daemonize(log_file: Option<&Path>) -> Result<(), Fatal> {
+    let fail = |what: &str, e: nix::Error| Fatal::Runtime(format!("daemonize: {} failed: {}", what, e));
+    // SAFETY: no thread has been started yet, the child is a full copy
+    if let ForkResult::Parent { .. } = unsafe { fork() }.map_err(|e| fail("fork", e))? {
+        process::exit(0);
+    }
+    setsid().map_err(|e| fail("setsid", e))?;
+    // SAFETY: as above, still single threaded
+    if let ForkResult::Parent { .. } = unsafe { fork() }.map_err(|e| fail("second fork", e))? {
+        process::exit(0);
+    }
+    chdir("/").map_err(|e| fail("chdir /", e))?;
+
+    let null = File::open("/dev/null").map_err(|e| Fatal::Runtime(format!("daemonize: open /dev/null: {}", e)))?;
+    let out = match log_file {
+        Some(path) => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| Fatal::Config(format!("cannot open log file {}: {}", path.display(), e)))?,
+        None => OpenOptions::new()
+            .write(true)
+            .open("/dev/null")
+            .map_err(|e| Fatal::Runtime(format!("daemonize: open /dev/null: {}", e)))?,
+    };
+    dup2(null.as_raw_fd(), 0).map_err(|e| fail("redirect stdin", e))?;
+    dup2(out.as_raw_fd(), 1).map_err(|e| fail("redirect stdout", e))?;
+    dup2(out.as_raw_fd(), 2).map_err(|e| fail("redirect stderr", e))?;
+    Ok(())
+}
+
+/// Names of the `paths` that are relative. After the chdir they would
+/// resolve against /, and a reload or upgrade would read the wrong files.
+pub fn relative_paths<'a>(paths: impl IntoIterator<Item = (&'a str, Option<&'a Path>)>) -> Vec<&'a str> {
+    paths
+        .into_iter()
+        .filter(|(_, p)| p.is_some_and(Path::is_relative))
+        .map(|(key, _)| key)
+        .collect()
+}
@@ -0,0 +1,212 @@
+use std::{fmt, str::FromStr};
+
+use crate::{proxy_protocol, socks};
+
+/// Client bytes `classify` needs at most to tell what they are, the
+/// PROXY protocol v2 signature.
+pub const FIRST_BYTES: usize = proxy_protocol::V2_SIGNATURE.len();
+
+/// What the clients of a listener with `protocol` may speak, see
+/// `Config::listener_protocol`; without one `Config::socks` decides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Http,
+    /// SOCKS4, 4a or 5
+    Socks,
+    /// either, as the first bytes say
+    Auto,
+    /// an HTTP/1.1 websocket upgrade naming the destination, the tunnel
+    /// framed, see `websocket`
+    WebSocket,
+}
+
+impl Protocol {
+    fn takes(self, speaks: Speaks) -> bool {
+        matches!(
+            (self, speaks),
+            (Protocol::Auto, _)
+                | (Protocol::Http | Protocol::WebSocket, Speaks::Http)
+                | (Protocol::Socks, Speaks::Socks)
+        )
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http" => Ok(Protocol::Http),
+            "socks" => Ok(Protocol::Socks),
+            "auto" => Ok(Protocol::Auto),
+            "websocket" => Ok(Protocol::WebSocket),
+            _ => Err(format!("unknown listener protocol {:?}, expected http, socks, auto or websocket", s)),
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Protocol::Http => "http",
+            Protocol::Socks => "socks",
+            Protocol::Auto => "auto",
+            Protocol::WebSocket => "websocket",
+        })
+    }
+}
+
+/// What a client whose request is being read speaks, the sub-state of
+/// `session::State::Head`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Speaks {
+    /// its first bytes are not in yet
+    Detect,
+    Http,
+    /// at the handshake step of `Session::socks`
+    Socks,
+}
+
+/// What the first bytes a client sent are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirstBytes {
+    /// a letter, as request methods start with
+    Http,
+    Socks5,
+    Socks4,
+    /// a TLS handshake record, a client taking us for an https:// proxy
+    Tls,
+    /// a PROXY protocol header, v1 or v2, from a load balancer in front
+    /// of a listener without `proxy_protocol`
+    ProxyHeader,
+    Unknown,
+}
+
+/// What `first` starts a connection with, None while it is too short to
+/// tell: a prefix of a PROXY protocol header could still be one, or the
+/// request line of a method like PUT.
+pub fn classify(first: &[u8]) -> Option<FirstBytes> {
+    for header in [proxy_protocol::V1_PREFIX, proxy_protocol::V2_SIGNATURE] {
+        if first.starts_with(header) {
+            return Some(FirstBytes::ProxyHeader);
+        }
+        if header.starts_with(first) {
+            return None;
+        }
+    }
+    // 22 is a handshake record, then the major version, 3 for SSL 3.0 on;
+    // no method or SOCKS version starts like that
+    Some(match *first {
+        [] | [0x16] => return None,
+        [socks::VERSION_5, ..] => FirstBytes::Socks5,
+        [socks::VERSION_4, ..] => FirstBytes::Socks4,
+        [0x16, 0x03, ..] => FirstBytes::Tls,
+        [b, ..] if b.is_ascii_alphabetic() => FirstBytes::Http,
+        _ => FirstBytes::Unknown,
+    })
+}
+
+/// Where the first bytes a client of a listener taking `protocol` sent
+/// go: the parser that takes them, Err why none does. `tls_listener` is
+/// whether they came through our own TLS session.
+pub fn dispatch(protocol: Protocol, first: FirstBytes, tls_listener: bool) -> Result<Speaks, String> {
+    let speaks = match first {
+        FirstBytes::Http => Speaks::Http,
+        FirstBytes::Socks5 | FirstBytes::Socks4 => Speaks::Socks,
+        FirstBytes::Tls if !tls_listener => {
+            return Err("client speaks TLS to a plaintext listener, is its proxy URL https:// by mistake?".to_owned())
+        }
+        FirstBytes::ProxyHeader => {
+            return Err("client sends a PROXY protocol header, is proxy_protocol missing on the listener?".to_owned())
+        }
+        FirstBytes::Tls | FirstBytes::Unknown => return Err("client speaks neither HTTP nor SOCKS".to_owned()),
+    };
+    match protocol.takes(speaks) {
+        true => Ok(speaks),
+        false if speaks == Speaks::Http => Err(format!("client speaks HTTP to a {} listener", protocol)),
+        false if protocol == Protocol::WebSocket => Err("client speaks SOCKS to a websocket listener".to_owned()),
+        false => Err(format!("client speaks SOCKS to an {} listener", protocol)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The first bytes of each kind of client, as they come on the wire.
+    const SAMPLES: [(&[u8], FirstBytes); 12] = [
+        (b"\x05\x01\x00", FirstBytes::Socks5),
+        (b"\x05\x02\x00\x02", FirstBytes::Socks5),
+        (b"\x04\x01\x01\xbb\x7f\x00\x00\x01user\0", FirstBytes::Socks4),
+        (b"CONNECT example.com:443 HTTP/1.1\r\n", FirstBytes::Http),
+        (b"GET http://example.com/ HTTP/1.1\r\n", FirstBytes::Http),
+        (b"PUT http://example.com/x HTTP/1.1\r\n", FirstBytes::Http),
+        (b"PRI * HTTP/2.0\r\n", FirstBytes::Http),
+        (b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n", FirstBytes::Http),
+        (b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03", FirstBytes::Tls),
+        (b"PROXY TCP4 10.0.0.1 10.0.0.2 5555 7788\r\n", FirstBytes::ProxyHeader),
+        (b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c", FirstBytes::ProxyHeader),
+        (b"\x00\x01\x02", FirstBytes::Unknown),
+    ];
+
+    #[test]
+    fn classifies_each_first_byte_class() {
+        for (first, kind) in SAMPLES {
+            assert_eq!(classify(&first[..first.len().min(FIRST_BYTES)]), Some(kind), "{:?}", first);
+        }
+    }
+
+    /// A client sending its head a byte at a time: every prefix is either
+    /// too short to tell or what the whole says, never something else.
+    #[test]
+    fn prefixes_need_more_rather_than_mislead() {
+        for (first, kind) in SAMPLES {
+            let mut told = false;
+            for n in 0..=first.len().min(FIRST_BYTES) {
+                match classify(&first[..n]) {
+                    None => assert!(!told, "{:?}: undecided again at {} bytes", first, n),
+                    Some(k) => {
+                        assert_eq!(k, kind, "{:?} at {} bytes", first, n);
+                        told = true;
+                    }
+                }
+            }
+            assert!(told, "{:?}", first);
+        }
+        assert_eq!(classify(b""), None);
+        assert_eq!(classify(b"\x16"), None);
+        assert_eq!(classify(b"PROXY"), None);
+        assert_eq!(classify(b"\r\n\r\n\0\r\nQUI"), None);
+        // a letter past the PROXY prefix is a method after all
+        assert_eq!(classify(b"PU"), Some(FirstBytes::Http));
+        assert_eq!(classify(b"PROXYX"), Some(FirstBytes::Http));
+    }
+
+    #[test]
+    fn dispatch_by_listener_protocol() {
+        use FirstBytes::*;
+        let ok = |p, first| dispatch(p, first, false);
+        assert_eq!(ok(Protocol::Auto, Http), Ok(Speaks::Http));
+        assert_eq!(ok(Protocol::Auto, Socks5), Ok(Speaks::Socks));
+        assert_eq!(ok(Protocol::Auto, Socks4), Ok(Speaks::Socks));
+        assert_eq!(ok(Protocol::Http, Http), Ok(Speaks::Http));
+        assert_eq!(ok(Protocol::Socks, Socks5), Ok(Speaks::Socks));
+        assert_eq!(ok(Protocol::WebSocket, Http), Ok(Speaks::Http));
+        assert_eq!(ok(Protocol::Http, Socks5).unwrap_err(), "client speaks SOCKS to an http listener");
+        assert_eq!(ok(Protocol::Socks, Http).unwrap_err(), "client speaks HTTP to a socks listener");
+        assert_eq!(ok(Protocol::WebSocket, Socks4).unwrap_err(), "client speaks SOCKS to a websocket listener");
+        assert!(ok(Protocol::Auto, Tls).unwrap_err().contains("https:// by mistake"));
+        assert!(ok(Protocol::Auto, ProxyHeader).unwrap_err().contains("proxy_protocol"));
+        assert!(ok(Protocol::Auto, Unknown).is_err());
+        // TLS inside our own TLS session is no mistake of the proxy URL
+        assert_eq!(dispatch(Protocol::Auto, Tls, true).unwrap_err(), "client speaks neither HTTP nor SOCKS");
+    }
+
+    #[test]
+    fn protocols_parse_and_print() {
+        for p in [Protocol::Http, Protocol::Socks, Protocol::Auto, Protocol::WebSocket] {
+            assert_eq!(p.to_string().parse(), Ok(p));
+        }
+        assert!("https".parse::<Protocol>().is_err());
+    }
+}
@@ -1,26 +1,143 @@
-use std::{cell::RefCell, collections::HashMap, net::IpAddr, rc::Rc};
+use std::{
+    collections::HashMap,
+    io,
+    net::IpAddr,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
 
+use log::info;
+
+use crate::{
+    config::Config,
+    stats::{ConnectFailure, WorkerStats},
+};
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct DNS {
-    cache : HashMap<String,Vec<IpAddr>>
+    cache : HashMap<String,Entry>,
+    stats: Arc<WorkerStats>,
+    /// `Config::dns_cache`, off forgets every answer right after use
+    keep: bool,
+    /// the resolver, the system's but in tests
+    lookup: fn(&str) -> io::Result<Vec<IpAddr>>,
+}
+
+/// A cached answer of the system resolver. getaddrinfo tells no TTL, so
+/// an entry stays until evicted or the cache is turned off.
+struct Entry {
+    ips: Vec<IpAddr>,
+    added: Instant,
+    /// queries answered from the cache
+    hits: u64,
+}
+
+/// One cached host of one worker, as listed by `/dns-cache`.
+pub struct CacheInfo {
+    pub worker: usize,
+    pub host: String,
+    pub ips: Vec<IpAddr>,
+    pub age: Duration,
+    pub hits: u64,
+}
+
+/// Resolves `localhost` through the system resolver, to fail loudly at
+/// startup rather than on the first CONNECT when it is broken.
+pub fn probe() -> Result<IpAddr, String> {
+    match dns_lookup::lookup_host("localhost") {
+        Ok(ips) => ips.first().copied().ok_or_else(|| "no address for localhost".to_owned()),
+        Err(e) => Err(e.to_string()),
+    }
 }
 
 impl  DNS {
-    pub fn new() -> DNS {
-        DNS{cache: HashMap::new()}
+    pub fn new(config: &Config, stats: Arc<WorkerStats>) -> DNS {
+        DNS{cache: HashMap::new(), stats, keep: config.dns_cache, lookup: dns_lookup::lookup_host}
+    }
+
+    /// A `DNS` asking `lookup` instead of the system resolver.
+    #[cfg(test)]
+    pub fn with_lookup(config: &Config, stats: Arc<WorkerStats>, lookup: fn(&str) -> io::Result<Vec<IpAddr>>) -> DNS {
+        DNS { lookup, ..DNS::new(config, stats) }
     }
 
-    pub fn query(&mut self, host : &str) -> Option<IpAddr> {
-        self.cache.entry(host.to_owned()).or_insert_with_key(|h| dns_lookup::lookup_host(h).unwrap_or(Vec::new()));
-        match self.cache.get(host) {
+    /// Turns the cache on or off (reload), off forgets what it holds.
+    pub fn set_keep(&mut self, keep: bool) {
+        if !keep {
+            self.cache.clear();
+        }
+        self.keep = keep;
+    }
+
+    /// The cached hosts sorted by name, the first `limit` after `after`
+    /// and whether there are more.
+    pub fn entries(&self, worker: usize, after: &str, limit: usize) -> (Vec<CacheInfo>, bool) {
+        let mut hosts = self.cache.iter().filter(|(h, _)| h.as_str() > after).collect::<Vec<_>>();
+        hosts.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        let more = hosts.len() > limit;
+        let now = Instant::now();
+        let list = hosts
+            .into_iter()
+            .take(limit)
+            .map(|(host, e)| CacheInfo {
+                worker,
+                host: host.clone(),
+                ips: e.ips.clone(),
+                age: now - e.added,
+                hits: e.hits,
+            })
+            .collect();
+        (list, more)
+    }
+
+    /// Forgets the answer for `host`, the next query resolves it again.
+    /// False when there was none.
+    pub fn evict(&mut self, host: &str) -> bool {
+        self.cache.remove(host).is_some()
+    }
+
+    /// Every address `host` resolves to, in the resolver's order; None
+    /// when the lookup failed or found none.
+    pub fn query(&mut self, host : &str) -> Option<Vec<IpAddr>> {
+        match self.cache.get_mut(host) {
+            Some(e) => {
+                e.hits += 1;
+                self.stats.dns_hits.fetch_add(1, Ordering::Relaxed);
+            }
+            None => {
+                self.stats.dns_misses.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let (stats, lookup) = (&self.stats, self.lookup);
+        self.cache.entry(host.to_owned()).or_insert_with_key(|h| {
+            let st = Instant::now();
+            let ips = lookup(h);
+            stats.dns_latency.record(st.elapsed());
+            let ips = ips.unwrap_or_else(|e| {
+                info!(host = h.as_str(), err_kind = ConnectFailure::Dns.name(), err:% = e; "dns lookup failed");
+                Vec::new()
+            });
+            Entry {
+                ips,
+                added: Instant::now(),
+                hits: 0,
+            }
+        });
+        match self.cache.get(host).map(|e| &e.ips) {
             Some(ips) => {
                 if ips.is_empty() {
                     self.cache.remove(host);
+                    self.stats.dns_failures.fetch_add(1, Ordering::Relaxed);
                     return None;
                 }
 
-                ips.first().map(|x| x.to_owned())
+                let ips = ips.clone();
+                if !self.keep {
+                    self.cache.remove(host);
+                }
+                Some(ips)
             }
             None => None,
         }
     }
-}
\ No newline at end of file
+}
@@ -1,18 +1,321 @@
-use std::{cell::LazyCell, fmt::{Debug, Pointer}, io, path::Display};
-
-// pub struct ProxyError {
-//     pub err : String,
-//     a: LazyCell<>
-// }
-
-// impl From<io::Error> for ProxyError {
-//     fn from(value: io::Error) -> Self {
-//         Self {err: value.to_string()}
-//     }
-// }
-
-// impl Debug for ProxyError {
-//     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-//         write!(f,"ProxyError {}",&self.err)
-//     }
-// }
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    process::ExitCode,
+    time::{Duration, Instant},
+};
+
+use log::error;
+use nix::errno::Errno;
+
+use crate::stats::ConnectFailure;
+
+/// EX_CONFIG from sysexits.h, lets a service manager tell a broken
+/// configuration (no point restarting) from a failure at runtime.
+const EXIT_CONFIG: u8 = 78;
+
+/// How long `ErrorLog` keeps quiet about a category after logging it.
+const LOG_WINDOW: Duration = Duration::from_secs(60);
+
+/// Why the proxy stopped with an error.
+#[derive(Debug)]
+pub enum Fatal {
+    /// invalid settings or an environment they cannot work in
+    Config(String),
+    Runtime(String),
+}
+
+impl Fatal {
+    /// Turns a bind failure into an actionable message. An address in use
+    /// may clear up on its own, everything else needs a config change.
+    pub fn bind(addr: SocketAddr, e: io::Error) -> Fatal {
+        let hint = match e.kind() {
+            ErrorKind::PermissionDenied if addr.port() < 1024 => {
+                " (ports <1024 need CAP_NET_BIND_SERVICE)"
+            }
+            ErrorKind::AddrInUse => " (another process listens there, is an instance already running?)",
+            ErrorKind::AddrNotAvailable => " (address is not assigned to any local interface)",
+            _ => "",
+        };
+        let msg = format!("cannot bind {}: {}{}", addr, e, hint);
+        match e.kind() {
+            ErrorKind::AddrInUse => Fatal::Runtime(msg),
+            _ => Fatal::Config(msg),
+        }
+    }
+
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            Fatal::Config(_) => ExitCode::from(EXIT_CONFIG),
+            Fatal::Runtime(_) => ExitCode::FAILURE,
+        }
+    }
+}
+
+impl Display for Fatal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fatal::Config(m) => write!(f, "config error: {}", m),
+            Fatal::Runtime(m) => write!(f, "{}", m),
+        }
+    }
+}
+
+impl From<io::Error> for Fatal {
+    fn from(e: io::Error) -> Self {
+        Fatal::Runtime(e.to_string())
+    }
+}
+
+impl From<Box<dyn Error>> for Fatal {
+    fn from(e: Box<dyn Error>) -> Self {
+        Fatal::Runtime(e.to_string())
+    }
+}
+
+/// Which socket of a session something happened on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Upstream,
+}
+
+impl Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Side::Client => "client",
+            Side::Upstream => "upstream",
+        })
+    }
+}
+
+/// A failed splice, carried inside the `io::Error` so `classify` can tell
+/// whose socket reset.
+#[derive(Debug)]
+pub struct SpliceError {
+    /// the socket being read from or written to, never the pipe
+    pub side: Side,
+    pub errno: Errno,
+}
+
+impl SpliceError {
+    /// `errno` from splicing on `side`, as the io::Error handlers return.
+    pub fn io(side: Side, errno: Errno) -> io::Error {
+        io::Error::new(io::Error::from(errno).kind(), SpliceError { side, errno })
+    }
+}
+
+impl Display for SpliceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "splice {}: {}", self.side, self.errno)
+    }
+}
+
+impl Error for SpliceError {}
+
+/// What a session error counts as, for the `session_errors_total` counter
+/// and the rate-limited error lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    DnsFailure,
+    ConnectRefused,
+    ConnectTimeout,
+    /// splice failed other than by a peer resetting
+    SpliceError,
+    /// the client's request head is malformed
+    ParseError,
+    ClientReset,
+    UpstreamReset,
+    Other,
+}
+
+impl ErrorCategory {
+    pub const ALL: [ErrorCategory; 8] = [
+        ErrorCategory::DnsFailure,
+        ErrorCategory::ConnectRefused,
+        ErrorCategory::ConnectTimeout,
+        ErrorCategory::SpliceError,
+        ErrorCategory::ParseError,
+        ErrorCategory::ClientReset,
+        ErrorCategory::UpstreamReset,
+        ErrorCategory::Other,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorCategory::DnsFailure => "dns_failure",
+            ErrorCategory::ConnectRefused => "connect_refused",
+            ErrorCategory::ConnectTimeout => "connect_timeout",
+            ErrorCategory::SpliceError => "splice_error",
+            ErrorCategory::ParseError => "parse_error",
+            ErrorCategory::ClientReset => "client_reset",
+            ErrorCategory::UpstreamReset => "upstream_reset",
+            ErrorCategory::Other => "other",
+        }
+    }
+
+    /// The category of a connect failure that no handler error told of,
+    /// such as a connect running into the idle timeout.
+    pub fn from_connect(kind: ConnectFailure) -> ErrorCategory {
+        match kind {
+            ConnectFailure::Dns => ErrorCategory::DnsFailure,
+            ConnectFailure::Refused => ErrorCategory::ConnectRefused,
+            ConnectFailure::Timeout => ErrorCategory::ConnectTimeout,
+            ConnectFailure::Unreachable | ConnectFailure::Parent | ConnectFailure::Other => ErrorCategory::Other,
+        }
+    }
+
+    pub fn reset(side: Side) -> ErrorCategory {
+        match side {
+            Side::Client => ErrorCategory::ClientReset,
+            Side::Upstream => ErrorCategory::UpstreamReset,
+        }
+    }
+}
+
+/// The category of `e`, an error a session handler failed with while
+/// serving an event on its `side` socket. Splice errors name their side
+/// themselves; a failed name lookup is `NotFound`, which no socket call
+/// returns.
+pub fn classify(e: &io::Error, side: Side) -> ErrorCategory {
+    if let Some(splice) = e.get_ref().and_then(|p| p.downcast_ref::<SpliceError>()) {
+        return match splice.errno {
+            Errno::ECONNRESET | Errno::EPIPE | Errno::ECONNABORTED | Errno::ETIMEDOUT => {
+                ErrorCategory::reset(splice.side)
+            }
+            _ => ErrorCategory::SpliceError,
+        };
+    }
+    match e.kind() {
+        ErrorKind::NotFound => ErrorCategory::DnsFailure,
+        ErrorKind::ConnectionRefused => ErrorCategory::ConnectRefused,
+        ErrorKind::TimedOut => ErrorCategory::ConnectTimeout,
+        ErrorKind::InvalidData | ErrorKind::InvalidInput => ErrorCategory::ParseError,
+        ErrorKind::ConnectionReset | ErrorKind::BrokenPipe | ErrorKind::ConnectionAborted => {
+            ErrorCategory::reset(side)
+        }
+        _ => ErrorCategory::Other,
+    }
+}
+
+/// One worker's budget for error lines: the first error of a category is
+/// logged, the others of the next `LOG_WINDOW` are only counted and then
+/// reported in one line.
+#[derive(Default)]
+pub struct ErrorLog {
+    /// per category, when its line was logged and how many were held back
+    /// since
+    windows: [Option<(Instant, u64)>; ErrorCategory::ALL.len()],
+}
+
+impl ErrorLog {
+    /// Whether to log an error of `category` now; false counts it for the
+    /// window's summary instead.
+    pub fn admit(&mut self, category: ErrorCategory, now: Instant) -> bool {
+        self.expire(category, now);
+        match &mut self.windows[category as usize] {
+            Some((_, held)) => {
+                *held += 1;
+                false
+            }
+            w @ None => {
+                *w = Some((now, 0));
+                true
+            }
+        }
+    }
+
+    /// Reports and closes the windows that ran out.
+    pub fn flush(&mut self, now: Instant) {
+        for category in ErrorCategory::ALL {
+            self.expire(category, now);
+        }
+    }
+
+    fn expire(&mut self, category: ErrorCategory, now: Instant) {
+        let window = &mut self.windows[category as usize];
+        let Some((logged, held)) = *window else {
+            return;
+        };
+        if now.duration_since(logged) < LOG_WINDOW {
+            return;
+        }
+        if held > 0 {
+            error!(category = category.name(); "and {} more {} errors in the last minute", held, category.name());
+        }
+        *window = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_error_kinds() {
+        let table = [
+            (ErrorKind::NotFound, Side::Upstream, ErrorCategory::DnsFailure),
+            (ErrorKind::ConnectionRefused, Side::Upstream, ErrorCategory::ConnectRefused),
+            (ErrorKind::TimedOut, Side::Upstream, ErrorCategory::ConnectTimeout),
+            (ErrorKind::InvalidData, Side::Client, ErrorCategory::ParseError),
+            (ErrorKind::InvalidInput, Side::Client, ErrorCategory::ParseError),
+            (ErrorKind::ConnectionReset, Side::Client, ErrorCategory::ClientReset),
+            (ErrorKind::ConnectionReset, Side::Upstream, ErrorCategory::UpstreamReset),
+            (ErrorKind::BrokenPipe, Side::Upstream, ErrorCategory::UpstreamReset),
+            (ErrorKind::ConnectionAborted, Side::Client, ErrorCategory::ClientReset),
+            (ErrorKind::UnexpectedEof, Side::Client, ErrorCategory::Other),
+            (ErrorKind::PermissionDenied, Side::Upstream, ErrorCategory::Other),
+        ];
+        for (kind, side, category) in table {
+            assert_eq!(classify(&io::Error::new(kind, "test"), side), category, "{:?} on {}", kind, side);
+        }
+    }
+
+    #[test]
+    fn classifies_raw_errnos() {
+        let table = [
+            (Errno::ECONNREFUSED, Side::Upstream, ErrorCategory::ConnectRefused),
+            (Errno::ETIMEDOUT, Side::Upstream, ErrorCategory::ConnectTimeout),
+            // unreachable has no category of its own, see `from_connect`
+            (Errno::EHOSTUNREACH, Side::Upstream, ErrorCategory::Other),
+            (Errno::ENETUNREACH, Side::Upstream, ErrorCategory::Other),
+            (Errno::ECONNRESET, Side::Client, ErrorCategory::ClientReset),
+            (Errno::ECONNRESET, Side::Upstream, ErrorCategory::UpstreamReset),
+            (Errno::EPIPE, Side::Client, ErrorCategory::ClientReset),
+        ];
+        for (errno, side, category) in table {
+            let e = io::Error::from_raw_os_error(errno as i32);
+            assert_eq!(classify(&e, side), category, "{} on {}", errno, side);
+        }
+    }
+
+    #[test]
+    fn splice_errors_name_their_side() {
+        let table = [
+            (Side::Upstream, Errno::ECONNRESET, ErrorCategory::UpstreamReset),
+            (Side::Client, Errno::EPIPE, ErrorCategory::ClientReset),
+            (Side::Client, Errno::ETIMEDOUT, ErrorCategory::ClientReset),
+            (Side::Upstream, Errno::ENOMEM, ErrorCategory::SpliceError),
+            (Side::Client, Errno::EINVAL, ErrorCategory::SpliceError),
+        ];
+        for (side, errno, category) in table {
+            // the splice's own side wins over the socket the event was on
+            let other = if side == Side::Client { Side::Upstream } else { Side::Client };
+            assert_eq!(classify(&SpliceError::io(side, errno), other), category, "{} on {}", errno, side);
+        }
+    }
+
+    #[test]
+    fn error_log_holds_back_repeats_for_a_window() {
+        let mut log = ErrorLog::default();
+        let now = Instant::now();
+        assert!(log.admit(ErrorCategory::ConnectRefused, now));
+        assert!(!log.admit(ErrorCategory::ConnectRefused, now + Duration::from_secs(1)));
+        // categories have windows of their own
+        assert!(log.admit(ErrorCategory::DnsFailure, now + Duration::from_secs(1)));
+        assert!(!log.admit(ErrorCategory::ConnectRefused, now + LOG_WINDOW - Duration::from_millis(1)));
+        assert!(log.admit(ErrorCategory::ConnectRefused, now + LOG_WINDOW));
+    }
+}
@@ -0,0 +1,165 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write as _,
+    sync::Arc,
+};
+
+use log::{info, warn};
+
+use crate::{
+    admin::json_str,
+    stats::{self, WorkerStats},
+};
+
+/// Minutes a connect counts for, the window `/failing-hosts` covers.
+pub const WINDOW_MINUTES: usize = 5;
+
+/// Destinations a worker keeps apart; past it, hosts with nothing left in
+/// the window go first, then the one with the fewest failures. Clients
+/// failing against many made-up names only push out each other.
+pub const MAX_HOSTS: usize = 512;
+
+/// One minute of connects to a host.
+#[derive(Debug, Default, Clone, Copy)]
+struct Minute {
+    /// the `minute` this slot counts
+    at: u64,
+    attempts: u32,
+    failures: u32,
+}
+
+/// Connects to one destination within the window: sessions that reached
+/// it or failed to, clients that gave up first are not counted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HostFailures {
+    pub attempts: u64,
+    pub failures: u64,
+}
+
+/// One worker's connects per host in a ring of per-minute slots, behind a
+/// mutex in `WorkerStats` like `top_hosts::HostTable`.
+#[derive(Debug, Default)]
+pub struct FailureTable {
+    hosts: HashMap<String, [Minute; WINDOW_MINUTES]>,
+}
+
+impl FailureTable {
+    /// Counts a connect to `host` in `minute`, a failed one when `failed`.
+    pub fn record(&mut self, host: &str, failed: bool, minute: u64) {
+        if !self.hosts.contains_key(host) && self.hosts.len() >= MAX_HOSTS {
+            self.hosts.retain(|_, ring| window(ring, minute).attempts > 0);
+            if self.hosts.len() >= MAX_HOSTS {
+                let weakest = self
+                    .hosts
+                    .iter()
+                    .map(|(h, ring)| (window(ring, minute), h))
+                    .min_by_key(|(w, _)| (w.failures, w.attempts))
+                    .map(|(_, h)| h.clone());
+                if let Some(h) = weakest {
+                    self.hosts.remove(&h);
+                }
+            }
+        }
+        let ring = self.hosts.entry(host.to_owned()).or_default();
+        let slot = &mut ring[minute as usize % WINDOW_MINUTES];
+        if slot.at != minute {
+            *slot = Minute {
+                at: minute,
+                ..Default::default()
+            };
+        }
+        slot.attempts = slot.attempts.saturating_add(1);
+        if failed {
+            slot.failures = slot.failures.saturating_add(1);
+        }
+    }
+}
+
+/// Minutes since the stats epoch, what the slots are indexed by.
+pub fn minute() -> u64 {
+    stats::since_epoch().as_secs() / 60
+}
+
+/// What `ring` holds for the `WINDOW_MINUTES` up to `minute`.
+fn window(ring: &[Minute; WINDOW_MINUTES], minute: u64) -> HostFailures {
+    ring.iter()
+        .filter(|m| minute.saturating_sub(m.at) < WINDOW_MINUTES as u64 && m.attempts > 0)
+        .fold(HostFailures::default(), |acc, m| HostFailures {
+            attempts: acc.attempts + u64::from(m.attempts),
+            failures: acc.failures + u64::from(m.failures),
+        })
+}
+
+/// The workers' tables merged for the window up to `minute`: hosts with
+/// failures, the most first, at most `n`.
+pub fn worst(stats: &[Arc<WorkerStats>], minute: u64, n: usize) -> Vec<(String, HostFailures)> {
+    let mut merged: HashMap<String, HostFailures> = HashMap::new();
+    for s in stats {
+        let table = s.failing_hosts.lock().unwrap();
+        for (host, ring) in &table.hosts {
+            let w = window(ring, minute);
+            if w.failures > 0 {
+                let m = merged.entry(host.clone()).or_default();
+                m.attempts += w.attempts;
+                m.failures += w.failures;
+            }
+        }
+    }
+    let mut hosts = merged.into_iter().collect::<Vec<_>>();
+    hosts.sort_by(|a, b| b.1.failures.cmp(&a.1.failures).then_with(|| a.0.cmp(&b.0)));
+    hosts.truncate(n);
+    hosts
+}
+
+/// Hosts warned about as failing; a host is warned about again only after
+/// it dropped below the threshold.
+#[derive(Debug, Default)]
+pub struct Alerts {
+    warned: HashSet<String>,
+}
+
+impl Alerts {
+    /// Warns once for each host whose window has at least `min_failures`
+    /// failures making up at least `ratio` of its connects. Runs on the
+    /// stats tick.
+    pub fn check(&mut self, stats: &[Arc<WorkerStats>], minute: u64, min_failures: u64, ratio: f64) {
+        let failing = worst(stats, minute, stats.len() * MAX_HOSTS)
+            .into_iter()
+            .filter(|(_, w)| w.failures >= min_failures && w.failures as f64 >= ratio * w.attempts as f64)
+            .collect::<Vec<_>>();
+        for (host, w) in &failing {
+            if self.warned.insert(host.clone()) {
+                warn!(
+                    host = host.as_str(), failures = w.failures, attempts = w.attempts;
+                    "destination failing: {} of {} connects in the last {} minutes", w.failures, w.attempts, WINDOW_MINUTES
+                );
+            }
+        }
+        self.warned.retain(|host| {
+            let still = failing.iter().any(|(h, _)| h == host);
+            if !still {
+                info!(host = host.as_str(); "destination recovered");
+            }
+            still
+        });
+    }
+}
+
+/// The `/failing-hosts` body.
+pub fn json(hosts: &[(String, HostFailures)]) -> String {
+    let mut out = format!(r#"{{"window_secs":{},"hosts":["#, WINDOW_MINUTES * 60);
+    for (i, (host, w)) in hosts.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(
+            out,
+            r#"{{"host":{},"failures":{},"attempts":{}}}"#,
+            json_str(host),
+            w.failures,
+            w.attempts
+        );
+    }
+    out.push_str("]}");
+    out
+}
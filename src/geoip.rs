@@ -0,0 +1,112 @@
+use std::{
+    fmt::{self, Display},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use maxminddb::{geoip2, Reader};
+use serde::{Serialize, Serializer};
+
+/// An ISO 3166-1 alpha-2 country code, upper case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Country([u8; 2]);
+
+impl FromStr for Country {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.as_bytes() {
+            &[a, b] if a.is_ascii_alphabetic() && b.is_ascii_alphabetic() => {
+                Ok(Country([a.to_ascii_uppercase(), b.to_ascii_uppercase()]))
+            }
+            _ => Err(format!("invalid country {:?}, expected a two letter ISO code like DE", s)),
+        }
+    }
+}
+
+impl Display for Country {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", char::from(self.0[0]), char::from(self.0[1]))
+    }
+}
+
+impl Serialize for Country {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+/// What the databases know of an address, either may be missing.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Place {
+    pub country: Option<Country>,
+    pub asn: Option<u32>,
+}
+
+/// `Config::geoip_country_db` and `geoip_asn_db` as loaded by
+/// `Config::load_geoip`, held in memory whole.
+pub struct GeoIp {
+    country: Option<(PathBuf, Reader<Vec<u8>>)>,
+    asn: Option<(PathBuf, Reader<Vec<u8>>)>,
+}
+
+impl GeoIp {
+    /// None with neither database configured.
+    pub fn load(country: Option<&Path>, asn: Option<&Path>) -> Result<Option<GeoIp>, String> {
+        let open = |path: &Path, kind: &str| {
+            let reader = Reader::open_readfile(path)
+                .map_err(|e| format!("cannot load geoip {} db {}: {}", kind, path.display(), e))?;
+            Ok::<_, String>((path.to_owned(), reader))
+        };
+        if country.is_none() && asn.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(GeoIp {
+            country: country.map(|p| open(p, "country")).transpose()?,
+            asn: asn.map(|p| open(p, "asn")).transpose()?,
+        }))
+    }
+
+    /// The country of `ip`, the one MaxMind places it in over the one
+    /// its block is registered in, and its autonomous system. A record that
+    /// does not decode counts as unknown.
+    pub fn lookup(&self, ip: IpAddr) -> Place {
+        let country = self.country.as_ref().and_then(|(_, db)| {
+            let found = db.lookup(ip).ok()?.decode::<geoip2::Country>().ok()??;
+            let code = found.country.iso_code.or(found.registered_country.iso_code)?;
+            code.parse().ok()
+        });
+        let asn = self.asn.as_ref().and_then(|(_, db)| {
+            let found = db.lookup(ip).ok()?.decode::<geoip2::Asn>().ok()??;
+            found.autonomous_system_number
+        });
+        Place { country, asn }
+    }
+
+    /// For the startup and reload lines: each database with its type and
+    /// build date.
+    pub fn describe(&self) -> String {
+        [&self.country, &self.asn]
+            .into_iter()
+            .flatten()
+            .map(|(path, db)| {
+                let built = std::time::UNIX_EPOCH + std::time::Duration::from_secs(db.metadata().build_epoch);
+                format!(
+                    "{} {} built {}",
+                    db.metadata().database_type,
+                    path.display(),
+                    humantime::format_rfc3339_seconds(built)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Not the trees, only which databases.
+impl fmt::Debug for GeoIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "GeoIp({})", self.describe())
+    }
+}
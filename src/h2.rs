@@ -0,0 +1,1051 @@
+//! HTTP/2 (RFC 9113) for the clients of a TLS listener with `http2` that
+//! settle on h2 by ALPN, CONNECT only. Each CONNECT stream becomes a
+//! session of its own: the connection writes the request as HTTP/1.1 into
+//! one end of a socketpair, the session reads it from the other end as
+//! from any client, and what the session answers is framed back. The
+//! stream's DATA is the tunnel, flow control the socketpair filling up.
+//! A client's END_STREAM is not passed on, sessions know no half-close:
+//! the stream ends when its session does.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, ErrorKind, Read, Write},
+    os::fd::AsRawFd,
+    time::Instant,
+};
+
+use log::debug;
+use mio::{net::UnixStream, Interest, Registry, Token};
+
+use crate::{
+    client::{ClientStream, Peer},
+    hpack::{self, Header},
+    timer::TimerId,
+    token::TokenSpace,
+};
+
+/// What a client sends first, ahead of its SETTINGS.
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const FRAME_HEADER: usize = 9;
+/// `SETTINGS_MAX_FRAME_SIZE`, the default both ways: the most a frame we
+/// take or send carries.
+const MAX_FRAME: usize = 16 * 1024;
+/// `SETTINGS_MAX_CONCURRENT_STREAMS` we announce, refusing more.
+const MAX_STREAMS: usize = 100;
+/// The windows we give each stream and the connection as a whole; a
+/// stream's is what we buffer for it at most when its session lags.
+const STREAM_WINDOW: i64 = 256 * 1024;
+const CONNECTION_WINDOW: i64 = 4 * 1024 * 1024;
+/// The initial window by the protocol, before SETTINGS say otherwise.
+const DEFAULT_WINDOW: i64 = 65_535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+/// The largest header block taken, CONTINUATION frames and all.
+const MAX_HEADER_BLOCK: usize = 64 * 1024;
+/// Framed bytes waiting on the client taken at most before the streams
+/// are no longer read from.
+const OUTPUT_MAX: usize = 256 * 1024;
+/// The largest response head a session sends, as `Session` reads heads.
+const RESPONSE_HEAD_MAX: usize = 16 * 1024;
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const CANCEL: u32 = 0x8;
+const COMPRESSION_ERROR: u32 = 0x9;
+
+/// Response headers that mean something to HTTP/1.1 only.
+const HOP_BY_HOP: [&[u8]; 6] =
+    [b"connection", b"keep-alive", b"proxy-connection", b"transfer-encoding", b"upgrade", b"te"];
+
+/// A connection error: the GOAWAY code and what went wrong.
+struct Error(u32, &'static str);
+
+/// What a stream's session sent back so far.
+enum Response {
+    /// the head is coming, what there is of it
+    Head(Vec<u8>),
+    /// a 2xx went out, the rest is the tunnel
+    Tunnel,
+    /// a refusal went out, its body follows, this much more of it when
+    /// the session said
+    Body(Option<u64>),
+}
+
+/// One CONNECT, with our end of its session's socketpair.
+struct Stream {
+    sock: UnixStream,
+    token: Token,
+    /// what the client still takes on this stream
+    send_window: i64,
+    /// what the client may still send on it
+    recv_window: i64,
+    /// the client's DATA the socketpair did not take yet
+    pending: Vec<u8>,
+    /// taken by the socketpair, not yet credited back to the client
+    unacked: i64,
+    response: Response,
+    /// the session's bytes read behind its response head, not sent yet
+    unsent: Vec<u8>,
+    /// the client sent END_STREAM; a session has no half-close, so the
+    /// stream stays open for the session's answer until it ends
+    ended: bool,
+    /// the session is gone and writes to it fail, DATA is dropped
+    broken: bool,
+}
+
+impl Stream {
+    /// Credits the client for what the socketpair took, the window going
+    /// back to full a quarter at a time, none once it ended its side.
+    fn credit(&mut self, id: u32, out: &mut Vec<u8>) {
+        if self.unacked >= STREAM_WINDOW / 4 && !self.ended {
+            window_update(out, id, self.unacked);
+            self.recv_window += self.unacked;
+            self.unacked = 0;
+        }
+    }
+}
+
+/// An h2 client connection and its streams, held by the worker under the
+/// tokens of its socket and of every stream's socketpair end.
+pub struct Connection {
+    sock: ClientStream,
+    token: Token,
+    pub client: Peer,
+    pub listener: usize,
+    /// who the client's certificate names, the user of each stream's
+    /// session, see `Session::certified`
+    pub user: Option<String>,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    preface: bool,
+    decoder: hpack::Decoder,
+    streams: BTreeMap<u32, Stream>,
+    /// the highest stream the client opened
+    last_stream: u32,
+    send_window: i64,
+    recv_window: i64,
+    /// bytes of the connection window the client is not yet credited for
+    unacked: i64,
+    /// the client's SETTINGS_INITIAL_WINDOW_SIZE
+    initial_window: i64,
+    /// a header block waiting for its CONTINUATION: stream, END_STREAM
+    /// and the block so far
+    continued: Option<(u32, bool, Vec<u8>)>,
+    /// GOAWAY went either way, no new streams
+    going_away: bool,
+    /// sessions the worker still has room for in this `drive`, and the
+    /// session ends of the CONNECTs it opened
+    room: usize,
+    opened: Vec<UnixStream>,
+    /// stream tokens dropped since `take_closed`
+    closed: Vec<Token>,
+    pub last_active: Instant,
+    pub idle_timer: TimerId,
+}
+
+impl Connection {
+    /// Takes over `sock`, a TLS connection that negotiated h2, registering
+    /// it under its own token, our SETTINGS queued.
+    pub fn new(mut sock: ClientStream, client: Peer, listener: usize, registry: &Registry) -> io::Result<Connection> {
+        let token = TokenSpace::http2(sock.as_raw_fd());
+        // frames are small and the client waits for whole TLS records:
+        // Nagle holding a record's tail back until the client's delayed
+        // ACK stalls every window round trip
+        socket2::SockRef::from(&sock).set_nodelay(true)?;
+        registry.register(&mut sock, token, Interest::READABLE | Interest::WRITABLE)?;
+        let user = sock.peer_identity();
+        let mut conn = Connection {
+            sock,
+            token,
+            client,
+            listener,
+            user,
+            input: Vec::new(),
+            output: Vec::new(),
+            preface: false,
+            decoder: hpack::Decoder::new(),
+            streams: BTreeMap::new(),
+            last_stream: 0,
+            send_window: DEFAULT_WINDOW,
+            recv_window: CONNECTION_WINDOW,
+            unacked: 0,
+            initial_window: DEFAULT_WINDOW,
+            continued: None,
+            going_away: false,
+            room: 0,
+            opened: Vec::new(),
+            closed: Vec::new(),
+            last_active: Instant::now(),
+            idle_timer: 0,
+        };
+        let mut settings = Vec::new();
+        for (id, value) in [
+            (SETTINGS_MAX_CONCURRENT_STREAMS, MAX_STREAMS as u32),
+            (SETTINGS_INITIAL_WINDOW_SIZE, STREAM_WINDOW as u32),
+        ] {
+            settings.extend_from_slice(&id.to_be_bytes());
+            settings.extend_from_slice(&value.to_be_bytes());
+        }
+        frame(&mut conn.output, SETTINGS, 0, 0, &settings);
+        window_update(&mut conn.output, 0, CONNECTION_WINDOW - DEFAULT_WINDOW);
+        Ok(conn)
+    }
+
+    pub fn token(&self) -> Token {
+        self.token
+    }
+
+    /// The tokens of the streams' socketpair ends.
+    pub fn stream_tokens(&self) -> Vec<Token> {
+        self.streams.values().map(|s| s.token).collect()
+    }
+
+    /// Stream tokens dropped since the last call, for the worker to forget.
+    pub fn take_closed(&mut self) -> Vec<Token> {
+        std::mem::take(&mut self.closed)
+    }
+
+    pub fn has_streams(&self) -> bool {
+        !self.streams.is_empty()
+    }
+
+    /// Whether there is nothing left to do: GOAWAY went either way, the
+    /// last stream is over and the client has everything we wrote.
+    pub fn finished(&self) -> bool {
+        self.going_away && self.streams.is_empty() && self.output.is_empty()
+    }
+
+    /// Does what there is to do after an event on any of the connection's
+    /// sockets: reads and handles the client's frames, moves the streams'
+    /// data both ways and writes what came of it. Returns the session ends
+    /// of the CONNECTs that came in, at most `room` of them, the rest
+    /// refused. An error ends the connection, a GOAWAY queued if it was
+    /// the client's fault.
+    pub fn drive(&mut self, registry: &Registry, room: usize) -> io::Result<Vec<UnixStream>> {
+        self.room = room;
+        let mut buf = [0u8; 16 * 1024];
+        loop {
+            match self.sock.read(&mut buf) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "eof")),
+                Ok(n) => {
+                    self.input.extend_from_slice(&buf[..n]);
+                    self.last_active = Instant::now();
+                    if let Err(Error(code, why)) = self.frames(registry) {
+                        self.go_away_with(code);
+                        let _ = self.flush();
+                        return Err(io::Error::new(ErrorKind::InvalidData, why));
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        // what a stream could not send for a full output waits for the
+        // output to drain, without an edge of its own
+        loop {
+            let moved = self.move_streams(registry);
+            if self.unacked >= CONNECTION_WINDOW / 4 {
+                window_update(&mut self.output, 0, self.unacked);
+                self.recv_window += self.unacked;
+                self.unacked = 0;
+            }
+            self.flush()?;
+            if !moved || self.output.len() >= OUTPUT_MAX {
+                break;
+            }
+        }
+        Ok(std::mem::take(&mut self.opened))
+    }
+
+    /// Sends GOAWAY without an error: the streams there are go on, new
+    /// ones are refused, for a worker draining.
+    pub fn go_away(&mut self) -> io::Result<()> {
+        if !self.going_away {
+            self.go_away_with(NO_ERROR);
+        }
+        self.flush()
+    }
+
+    /// Deregisters the connection's sockets; dropping it then closes them,
+    /// and so each stream's session sees its client gone.
+    pub fn close(&mut self, registry: &Registry) {
+        let _ = registry.deregister(&mut self.sock);
+        for s in self.streams.values_mut() {
+            let _ = registry.deregister(&mut s.sock);
+        }
+    }
+
+    fn go_away_with(&mut self, code: u32) {
+        self.going_away = true;
+        let mut payload = self.last_stream.to_be_bytes().to_vec();
+        payload.extend_from_slice(&code.to_be_bytes());
+        frame(&mut self.output, GOAWAY, 0, 0, &payload);
+    }
+
+    /// Handles the complete frames in `input`.
+    fn frames(&mut self, registry: &Registry) -> Result<(), Error> {
+        if !self.preface {
+            let n = self.input.len().min(PREFACE.len());
+            if self.input[..n] != PREFACE[..n] {
+                return Err(Error(PROTOCOL_ERROR, "no HTTP/2 connection preface"));
+            }
+            if n < PREFACE.len() {
+                return Ok(());
+            }
+            self.input.drain(..n);
+            self.preface = true;
+        }
+        let mut at = 0;
+        while self.input.len() - at >= FRAME_HEADER {
+            let h = &self.input[at..at + FRAME_HEADER];
+            let len = u32::from_be_bytes([0, h[0], h[1], h[2]]) as usize;
+            let (kind, flags) = (h[3], h[4]);
+            let stream = u32::from_be_bytes([h[5], h[6], h[7], h[8]]) & 0x7fff_ffff;
+            if len > MAX_FRAME {
+                return Err(Error(FRAME_SIZE_ERROR, "frame past SETTINGS_MAX_FRAME_SIZE"));
+            }
+            if self.input.len() - at < FRAME_HEADER + len {
+                break;
+            }
+            let payload = self.input[at + FRAME_HEADER..at + FRAME_HEADER + len].to_vec();
+            at += FRAME_HEADER + len;
+            self.frame_in(kind, flags, stream, &payload, registry)?;
+        }
+        self.input.drain(..at);
+        Ok(())
+    }
+
+    fn frame_in(&mut self, kind: u8, flags: u8, stream: u32, payload: &[u8], registry: &Registry) -> Result<(), Error> {
+        if matches!(self.continued, Some((id, ..)) if kind != CONTINUATION || stream != id) {
+            return Err(Error(PROTOCOL_ERROR, "frame amid a header block"));
+        }
+        match kind {
+            DATA => self.data(flags, stream, payload, registry),
+            HEADERS => {
+                if stream.is_multiple_of(2) {
+                    return Err(Error(PROTOCOL_ERROR, "HEADERS on a stream a client cannot open"));
+                }
+                let mut block = payload;
+                if flags & PADDED != 0 {
+                    block = unpad(block)?;
+                }
+                if flags & PRIORITY_FLAG != 0 {
+                    block = block.get(5..).ok_or(Error(FRAME_SIZE_ERROR, "HEADERS too short"))?;
+                }
+                let end_stream = flags & END_STREAM != 0;
+                match flags & END_HEADERS {
+                    0 => self.continued = Some((stream, end_stream, block.to_vec())),
+                    _ => self.headers(stream, end_stream, block, registry)?,
+                }
+                Ok(())
+            }
+            CONTINUATION => {
+                let Some((id, end_stream, mut block)) = self.continued.take() else {
+                    return Err(Error(PROTOCOL_ERROR, "CONTINUATION without HEADERS"));
+                };
+                block.extend_from_slice(payload);
+                if block.len() > MAX_HEADER_BLOCK {
+                    return Err(Error(PROTOCOL_ERROR, "header block too large"));
+                }
+                match flags & END_HEADERS {
+                    0 => self.continued = Some((id, end_stream, block)),
+                    _ => self.headers(id, end_stream, &block, registry)?,
+                }
+                Ok(())
+            }
+            PRIORITY => Ok(()),
+            RST_STREAM => {
+                if stream == 0 || payload.len() != 4 {
+                    return Err(Error(PROTOCOL_ERROR, "bad RST_STREAM"));
+                }
+                self.drop_stream(stream, registry);
+                Ok(())
+            }
+            SETTINGS => self.settings(flags, stream, payload),
+            PUSH_PROMISE => Err(Error(PROTOCOL_ERROR, "PUSH_PROMISE from a client")),
+            PING => {
+                if stream != 0 || payload.len() != 8 {
+                    return Err(Error(PROTOCOL_ERROR, "bad PING"));
+                }
+                if flags & ACK == 0 {
+                    frame(&mut self.output, PING, ACK, 0, payload);
+                }
+                Ok(())
+            }
+            GOAWAY => {
+                debug!(client:% = self.client; "h2 client sent GOAWAY");
+                self.going_away = true;
+                Ok(())
+            }
+            WINDOW_UPDATE => {
+                let Ok(increment) = <[u8; 4]>::try_from(payload) else {
+                    return Err(Error(FRAME_SIZE_ERROR, "bad WINDOW_UPDATE"));
+                };
+                let increment = (u32::from_be_bytes(increment) & 0x7fff_ffff) as i64;
+                if stream == 0 {
+                    self.send_window += increment;
+                    if increment == 0 || self.send_window > MAX_WINDOW {
+                        return Err(Error(FLOW_CONTROL_ERROR, "bad connection WINDOW_UPDATE"));
+                    }
+                } else if let Some(s) = self.streams.get_mut(&stream) {
+                    s.send_window += increment;
+                    if increment == 0 || s.send_window > MAX_WINDOW {
+                        rst_stream(&mut self.output, stream, FLOW_CONTROL_ERROR);
+                        self.drop_stream(stream, registry);
+                    }
+                }
+                Ok(())
+            }
+            // extensions we do not know are to be ignored
+            _ => Ok(()),
+        }
+    }
+
+    fn settings(&mut self, flags: u8, stream: u32, payload: &[u8]) -> Result<(), Error> {
+        if stream != 0 {
+            return Err(Error(PROTOCOL_ERROR, "SETTINGS on a stream"));
+        }
+        if flags & ACK != 0 {
+            return Ok(());
+        }
+        if !payload.len().is_multiple_of(6) {
+            return Err(Error(FRAME_SIZE_ERROR, "bad SETTINGS"));
+        }
+        for setting in payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]) as i64;
+            if id == SETTINGS_INITIAL_WINDOW_SIZE {
+                if value > MAX_WINDOW {
+                    return Err(Error(FLOW_CONTROL_ERROR, "SETTINGS_INITIAL_WINDOW_SIZE too large"));
+                }
+                // a change applies to the streams open, by the difference
+                for s in self.streams.values_mut() {
+                    s.send_window += value - self.initial_window;
+                }
+                self.initial_window = value;
+            }
+            // what else the client sets concerns how we send, and we send
+            // little enough for the defaults to do
+        }
+        frame(&mut self.output, SETTINGS, ACK, 0, &[]);
+        Ok(())
+    }
+
+    fn data(&mut self, flags: u8, stream: u32, payload: &[u8], registry: &Registry) -> Result<(), Error> {
+        if stream == 0 {
+            return Err(Error(PROTOCOL_ERROR, "DATA on stream 0"));
+        }
+        // flow control counts the padding too
+        let len = payload.len() as i64;
+        self.recv_window -= len;
+        if self.recv_window < 0 {
+            return Err(Error(FLOW_CONTROL_ERROR, "DATA past the connection window"));
+        }
+        let data = match flags & PADDED {
+            0 => payload,
+            _ => unpad(payload)?,
+        };
+        let Some(s) = self.streams.get_mut(&stream) else {
+            if stream > self.last_stream {
+                return Err(Error(PROTOCOL_ERROR, "DATA on a stream never opened"));
+            }
+            // for a stream closed already, or refused
+            self.unacked += len;
+            return Ok(());
+        };
+        s.recv_window -= len;
+        if s.recv_window < 0 || s.ended {
+            let code = if s.ended { STREAM_CLOSED } else { FLOW_CONTROL_ERROR };
+            rst_stream(&mut self.output, stream, code);
+            self.unacked += len;
+            self.drop_stream(stream, registry);
+            return Ok(());
+        }
+        // padding is credited right away, the data once the session took it
+        let padding = len - data.len() as i64;
+        s.unacked += padding;
+        self.unacked += padding;
+        if s.broken {
+            s.unacked += data.len() as i64;
+            self.unacked += data.len() as i64;
+        } else {
+            s.pending.extend_from_slice(data);
+        }
+        if flags & END_STREAM != 0 {
+            s.ended = true;
+        }
+        Ok(())
+    }
+
+    /// A complete header block for `stream`: a new stream's CONNECT, for
+    /// a session to take, or trailers, of which a tunnel only takes the
+    /// END_STREAM.
+    fn headers(&mut self, stream: u32, end_stream: bool, block: &[u8], registry: &Registry) -> Result<(), Error> {
+        // decoded even when refused, the table has to keep up
+        let headers = self.decoder.decode(block).map_err(|why| Error(COMPRESSION_ERROR, why))?;
+        if let Some(s) = self.streams.get_mut(&stream) {
+            s.ended |= end_stream;
+            return Ok(());
+        }
+        if stream <= self.last_stream {
+            // closed, and reset if it came to that
+            return Ok(());
+        }
+        self.last_stream = stream;
+        if self.going_away || self.streams.len() >= MAX_STREAMS || self.room == 0 {
+            rst_stream(&mut self.output, stream, REFUSED_STREAM);
+            return Ok(());
+        }
+        let request = match connect_request(&headers) {
+            Ok(request) => request,
+            Err(status) => {
+                let block = hpack::encode(status, &[]);
+                headers_frame(&mut self.output, stream, &block, true);
+                if !end_stream {
+                    rst_stream(&mut self.output, stream, NO_ERROR);
+                }
+                return Ok(());
+            }
+        };
+        match self.open(stream, &request, end_stream, registry) {
+            Ok(theirs) => {
+                self.room -= 1;
+                self.opened.push(theirs);
+            }
+            Err(e) => {
+                debug!(client:% = self.client, err:% = e; "cannot open h2 stream {}", stream);
+                rst_stream(&mut self.output, stream, REFUSED_STREAM);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets up the socketpair of a new stream with `request` written into
+    /// it, returning the session's end.
+    fn open(&mut self, id: u32, request: &[u8], ended: bool, registry: &Registry) -> io::Result<UnixStream> {
+        let (mut ours, theirs) = UnixStream::pair()?;
+        // a fresh socketpair takes far more than the largest request head
+        if ours.write(request)? != request.len() {
+            return Err(io::Error::new(ErrorKind::WriteZero, "request cut short"));
+        }
+        let token = TokenSpace::http2(ours.as_raw_fd());
+        registry.register(&mut ours, token, Interest::READABLE | Interest::WRITABLE)?;
+        let stream = Stream {
+            sock: ours,
+            token,
+            send_window: self.initial_window,
+            recv_window: STREAM_WINDOW,
+            pending: Vec::new(),
+            unacked: 0,
+            response: Response::Head(Vec::new()),
+            unsent: Vec::new(),
+            ended,
+            broken: false,
+        };
+        self.streams.insert(id, stream);
+        Ok(theirs)
+    }
+
+    fn drop_stream(&mut self, id: u32, registry: &Registry) {
+        if let Some(mut s) = self.streams.remove(&id) {
+            let _ = registry.deregister(&mut s.sock);
+            self.closed.push(s.token);
+            self.last_active = Instant::now();
+        }
+    }
+
+    /// Moves what each stream has to move, true if anything moved.
+    fn move_streams(&mut self, registry: &Registry) -> bool {
+        let mut moved = false;
+        let mut over = Vec::new();
+        for (&id, s) in self.streams.iter_mut() {
+            // the client's data into the socketpair, held back until the
+            // session answered: what it reads behind its head is not
+            // piped
+            let answered = !matches!(s.response, Response::Head(_));
+            while answered && !s.pending.is_empty() && !s.broken {
+                match s.sock.write(&s.pending) {
+                    Ok(n) => {
+                        s.pending.drain(..n);
+                        s.unacked += n as i64;
+                        self.unacked += n as i64;
+                        moved = true;
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    // the session went away, its answer is still to be read
+                    Err(_) => {
+                        s.unacked += s.pending.len() as i64;
+                        self.unacked += s.pending.len() as i64;
+                        s.pending.clear();
+                        s.broken = true;
+                    }
+                }
+            }
+            s.credit(id, &mut self.output);
+            // the session's answer back to the client
+            match answer(id, s, &mut self.output, &mut self.send_window) {
+                Ok(m) => moved |= m,
+                Err(()) => over.push(id),
+            }
+        }
+        for id in over {
+            self.drop_stream(id, registry);
+        }
+        moved
+    }
+
+    /// Writes `output` to the client as far as it takes it.
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.output.is_empty() {
+            match self.sock.write(&self.output) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "write zero")),
+                Ok(n) => {
+                    self.output.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Frames what the stream's session sent as far as the windows and the
+/// output let it, true if anything moved. Err once the stream is over,
+/// its last frame queued.
+fn answer(id: u32, s: &mut Stream, out: &mut Vec<u8>, conn_window: &mut i64) -> Result<bool, ()> {
+    let mut moved = false;
+    let mut buf = [0u8; MAX_FRAME];
+    while out.len() < OUTPUT_MAX {
+        if let Response::Head(head) = &mut s.response {
+            let n = match s.sock.read(&mut buf[..4096]) {
+                Ok(n) if n > 0 => n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(moved),
+                _ if head.is_empty() => {
+                    // gone without a word
+                    rst_stream(out, id, CANCEL);
+                    return Err(());
+                }
+                _ => return respond_garbled(id, out),
+            };
+            moved = true;
+            head.extend_from_slice(&buf[..n]);
+            let Ok(parsed) = parse_response(head) else {
+                return respond_garbled(id, out);
+            };
+            let Some(ResponseHead { len, status, headers, content_length }) = parsed else {
+                if head.len() >= RESPONSE_HEAD_MAX {
+                    return respond_garbled(id, out);
+                }
+                continue;
+            };
+            s.unsent = head[len..].to_vec();
+            let tunnel = (200..300).contains(&status);
+            let headers = headers
+                .iter()
+                .filter(|(name, _)| !HOP_BY_HOP.contains(&name.as_slice()))
+                // a 2xx to a CONNECT has no body to have a length
+                .filter(|(name, _)| !tunnel || name != b"content-length")
+                .map(|(name, value)| (name.as_slice(), value.as_slice()))
+                .collect::<Vec<_>>();
+            let done = !tunnel && content_length == Some(0);
+            headers_frame(out, id, &hpack::encode(status, &headers), done);
+            if done {
+                return finish(id, s, out);
+            }
+            s.response = if tunnel { Response::Tunnel } else { Response::Body(content_length) };
+            continue;
+        }
+        let allowed = s.send_window.min(*conn_window).min(MAX_FRAME as i64);
+        if allowed <= 0 {
+            return Ok(moved);
+        }
+        let n = if !s.unsent.is_empty() {
+            let n = s.unsent.len().min(allowed as usize);
+            buf[..n].copy_from_slice(&s.unsent[..n]);
+            s.unsent.drain(..n);
+            n
+        } else {
+            match s.sock.read(&mut buf[..allowed as usize]) {
+                Ok(0) => {
+                    frame(out, DATA, END_STREAM, id, &[]);
+                    return finish(id, s, out);
+                }
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(moved),
+                Err(_) => {
+                    rst_stream(out, id, CANCEL);
+                    return Err(());
+                }
+            }
+        };
+        moved = true;
+        // past the body the session announced there is nothing to send
+        let (n, last) = match &mut s.response {
+            Response::Body(Some(left)) => {
+                let n = n.min(*left as usize);
+                *left -= n as u64;
+                (n, *left == 0)
+            }
+            _ => (n, false),
+        };
+        frame(out, DATA, if last { END_STREAM } else { 0 }, id, &buf[..n]);
+        s.send_window -= n as i64;
+        *conn_window -= n as i64;
+        if last {
+            return finish(id, s, out);
+        }
+    }
+    Ok(moved)
+}
+
+/// Our side of a stream is ended: the client is told it need not send
+/// the rest of its own, if it has not ended it already.
+fn finish(id: u32, s: &Stream, out: &mut Vec<u8>) -> Result<bool, ()> {
+    if !s.ended {
+        rst_stream(out, id, NO_ERROR);
+    }
+    Err(())
+}
+
+/// A session's answer that is no HTTP/1.1 response head.
+fn respond_garbled(id: u32, out: &mut Vec<u8>) -> Result<bool, ()> {
+    headers_frame(out, id, &hpack::encode(502, &[]), true);
+    rst_stream(out, id, NO_ERROR);
+    Err(())
+}
+
+/// The request a session reads for a CONNECT stream's `headers`, or the
+/// status to refuse it with.
+fn connect_request(headers: &[Header]) -> Result<Vec<u8>, u16> {
+    let (mut method, mut authority, mut protocol) = (None, None, false);
+    let mut rest = Vec::new();
+    for (name, value) in headers {
+        // nothing that would split the request line or a header
+        if value.iter().any(|&b| b == b'\r' || b == b'\n' || b == 0) {
+            return Err(400);
+        }
+        match name.as_slice() {
+            b":method" => method = Some(value),
+            b":authority" => authority = Some(value),
+            b":protocol" => protocol = true,
+            // a CONNECT has no :scheme and :path, they would say nothing
+            n if n.starts_with(b":") => {}
+            b"host" => {}
+            n if HOP_BY_HOP.contains(&n) => {}
+            n if n.iter().all(|&b| b.is_ascii_graphic() && b != b':') => rest.push((name, value)),
+            _ => return Err(400),
+        }
+    }
+    // extended CONNECT (RFC 8441) tunnels a protocol we do not relay
+    if method.map(Vec::as_slice) != Some(b"CONNECT") || protocol {
+        return Err(501);
+    }
+    let authority = authority.filter(|a| !a.is_empty() && !a.iter().any(u8::is_ascii_whitespace)).ok_or(400u16)?;
+    let mut request = b"CONNECT ".to_vec();
+    request.extend_from_slice(authority);
+    request.extend_from_slice(b" HTTP/1.1\r\nhost: ");
+    request.extend_from_slice(authority);
+    request.extend_from_slice(b"\r\n");
+    for (name, value) in rest {
+        request.extend_from_slice(name);
+        request.extend_from_slice(b": ");
+        request.extend_from_slice(value);
+        request.extend_from_slice(b"\r\n");
+    }
+    request.extend_from_slice(b"\r\n");
+    Ok(request)
+}
+
+/// The response head at the start of what a session answered.
+struct ResponseHead {
+    len: usize,
+    status: u16,
+    /// names lowercase, as h2 has them
+    headers: Vec<Header>,
+    content_length: Option<u64>,
+}
+
+/// Ok(None) while the head is incomplete.
+fn parse_response(buf: &[u8]) -> Result<Option<ResponseHead>, ()> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut response = httparse::Response::new(&mut headers);
+    let len = match response.parse(buf) {
+        Ok(httparse::Status::Complete(len)) => len,
+        Ok(httparse::Status::Partial) => return Ok(None),
+        Err(_) => return Err(()),
+    };
+    let status = response.code.ok_or(())?;
+    let mut content_length = None;
+    let headers = response
+        .headers
+        .iter()
+        .map(|h| {
+            let name = h.name.to_ascii_lowercase().into_bytes();
+            if name == b"content-length" {
+                content_length = std::str::from_utf8(h.value).ok().and_then(|v| v.trim().parse().ok());
+            }
+            (name, h.value.to_vec())
+        })
+        .collect();
+    Ok(Some(ResponseHead { len, status, headers, content_length }))
+}
+
+/// The payload of a PADDED frame without its padding.
+fn unpad(payload: &[u8]) -> Result<&[u8], Error> {
+    let (&pad, rest) = payload.split_first().ok_or(Error(FRAME_SIZE_ERROR, "padded frame too short"))?;
+    let len = rest.len().checked_sub(pad as usize).ok_or(Error(PROTOCOL_ERROR, "padding past the frame"))?;
+    Ok(&rest[..len])
+}
+
+/// Queues a frame, `payload` whole.
+fn frame(out: &mut Vec<u8>, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    out.extend_from_slice(&[kind, flags]);
+    out.extend_from_slice(&stream.to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+/// Queues a header block as HEADERS and what CONTINUATION it takes.
+fn headers_frame(out: &mut Vec<u8>, stream: u32, block: &[u8], end_stream: bool) {
+    let mut chunks = block.chunks(MAX_FRAME).peekable();
+    let mut kind = HEADERS;
+    let mut flags = if end_stream { END_STREAM } else { 0 };
+    // an empty block still goes, as one HEADERS
+    let first: &[u8] = chunks.next().unwrap_or_default();
+    let mut chunk = first;
+    loop {
+        let last = chunks.peek().is_none();
+        frame(out, kind, flags | if last { END_HEADERS } else { 0 }, stream, chunk);
+        match chunks.next() {
+            Some(next) => chunk = next,
+            None => break,
+        }
+        (kind, flags) = (CONTINUATION, 0);
+    }
+}
+
+fn window_update(out: &mut Vec<u8>, stream: u32, increment: i64) {
+    frame(out, WINDOW_UPDATE, 0, stream, &(increment as u32).to_be_bytes());
+}
+
+fn rst_stream(out: &mut Vec<u8>, stream: u32, code: u32) {
+    frame(out, RST_STREAM, 0, stream, &code.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        time::Duration,
+    };
+
+    use mio::{Events, Poll};
+
+    use super::*;
+
+    /// A connection over loopback and its client's end, past the preface
+    /// and the SETTINGS both ways.
+    struct Client {
+        poll: Poll,
+        conn: Connection,
+        sock: TcpStream,
+        /// what the client sends with the next `drive`
+        unsent: Vec<u8>,
+    }
+
+    impl Client {
+        fn new() -> Client {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let sock = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            sock.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+            let (ours, addr) = listener.accept().unwrap();
+            ours.set_nonblocking(true).unwrap();
+            let poll = Poll::new().unwrap();
+            let ours = ClientStream::Tcp(mio::net::TcpStream::from_std(ours));
+            let conn = Connection::new(ours, Peer::Ip(addr), 0, poll.registry()).unwrap();
+            let mut client = Client { poll, conn, sock, unsent: PREFACE.to_vec() };
+            client.send(SETTINGS, 0, 0, &[]);
+            client.drive().unwrap();
+            let kinds: Vec<_> = client.frames().iter().map(|f| (f.0, f.1)).collect();
+            assert_eq!(kinds, [(SETTINGS, 0), (WINDOW_UPDATE, 0), (SETTINGS, ACK)]);
+            client
+        }
+
+        fn send(&mut self, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+            frame(&mut self.unsent, kind, flags, stream, payload);
+        }
+
+        /// A CONNECT to example.com:443 on `stream`, in one HEADERS.
+        fn connect(&mut self, stream: u32) {
+            self.send(HEADERS, END_HEADERS, stream, &connect_block());
+        }
+
+        /// Sends what there is to send in one write, and once the
+        /// connection has it, drives it.
+        fn drive(&mut self) -> io::Result<Vec<UnixStream>> {
+            if !self.unsent.is_empty() {
+                self.sock.write_all(&std::mem::take(&mut self.unsent)).unwrap();
+                let mut events = Events::with_capacity(8);
+                let token = self.conn.token();
+                while !events.iter().any(|e| e.token() == token && e.is_readable()) {
+                    self.poll.poll(&mut events, Some(Duration::from_secs(1))).unwrap();
+                    assert!(!events.is_empty(), "the connection never had the frames");
+                }
+            }
+            self.conn.drive(self.poll.registry(), 10)
+        }
+
+        /// The frames written to the client since the last call: kind,
+        /// flags, stream and payload.
+        fn frames(&mut self) -> Vec<(u8, u8, u32, Vec<u8>)> {
+            let mut frames = Vec::new();
+            let mut h = [0u8; FRAME_HEADER];
+            while self.sock.read_exact(&mut h).is_ok() {
+                let mut payload = vec![0u8; u32::from_be_bytes([0, h[0], h[1], h[2]]) as usize];
+                self.sock.read_exact(&mut payload).unwrap();
+                frames.push((h[3], h[4], u32::from_be_bytes([h[5], h[6], h[7], h[8]]), payload));
+            }
+            frames
+        }
+
+        /// Sets SETTINGS_INITIAL_WINDOW_SIZE, the ACK read.
+        fn initial_window(&mut self, size: u32) -> Vec<(u8, u8, u32, Vec<u8>)> {
+            let mut payload = SETTINGS_INITIAL_WINDOW_SIZE.to_be_bytes().to_vec();
+            payload.extend_from_slice(&size.to_be_bytes());
+            self.send(SETTINGS, 0, 0, &payload);
+            self.drive().unwrap();
+            let mut frames = self.frames();
+            assert_eq!(frames.remove(0), (SETTINGS, ACK, 0, vec![]));
+            frames
+        }
+    }
+
+    /// `:method CONNECT` and `:authority example.com:443`, literals
+    /// without indexing of static names.
+    fn connect_block() -> Vec<u8> {
+        [&[0x02, 7][..], b"CONNECT", &[0x01, 15], b"example.com:443"].concat()
+    }
+
+    fn goaway(last_stream: u32, code: u32) -> (u8, u8, u32, Vec<u8>) {
+        (GOAWAY, 0, 0, [last_stream.to_be_bytes(), code.to_be_bytes()].concat())
+    }
+
+    /// What the session reads from its end of a stream's socketpair.
+    fn request(mut theirs: UnixStream) -> String {
+        let mut buf = [0u8; 1024];
+        let n = theirs.read(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..n]).into_owned()
+    }
+
+    #[test]
+    fn a_header_block_goes_on_in_continuation_frames() {
+        let mut c = Client::new();
+        let block = connect_block();
+        c.send(HEADERS, 0, 1, &block[..5]);
+        c.send(CONTINUATION, 0, 1, &block[5..12]);
+        c.send(CONTINUATION, END_HEADERS, 1, &block[12..]);
+        let mut opened = c.drive().unwrap();
+        assert_eq!(opened.len(), 1);
+        assert_eq!(request(opened.remove(0)), "CONNECT example.com:443 HTTP/1.1\r\nhost: example.com:443\r\n\r\n");
+        // nothing else may come between HEADERS and its last CONTINUATION
+        c.send(HEADERS, 0, 3, &block[..5]);
+        c.send(PING, 0, 0, &[0; 8]);
+        assert_eq!(c.drive().unwrap_err().to_string(), "frame amid a header block");
+        assert_eq!(c.frames(), [goaway(1, PROTOCOL_ERROR)]);
+    }
+
+    #[test]
+    fn continuation_without_headers_is_a_protocol_error() {
+        let mut c = Client::new();
+        c.send(CONTINUATION, END_HEADERS, 1, &connect_block());
+        assert_eq!(c.drive().unwrap_err().to_string(), "CONTINUATION without HEADERS");
+        assert_eq!(c.frames(), [goaway(0, PROTOCOL_ERROR)]);
+    }
+
+    #[test]
+    fn a_frame_past_max_frame_size_ends_the_connection() {
+        let mut c = Client::new();
+        // up to the size is fine, an extension frame ignored
+        c.send(0xfa, 0, 0, &vec![0; MAX_FRAME]);
+        c.send(PING, 0, 0, b"8 bytes!");
+        c.drive().unwrap();
+        assert_eq!(c.frames(), [(PING, ACK, 0, b"8 bytes!".to_vec())]);
+        // the header alone says it is too large
+        c.unsent.extend_from_slice(&((MAX_FRAME + 1) as u32).to_be_bytes()[1..]);
+        c.unsent.extend([DATA, 0, 0, 0, 0, 1]);
+        assert_eq!(c.drive().unwrap_err().to_string(), "frame past SETTINGS_MAX_FRAME_SIZE");
+        assert_eq!(c.frames(), [goaway(0, FRAME_SIZE_ERROR)]);
+    }
+
+    #[test]
+    fn a_new_initial_window_applies_to_the_streams_open() {
+        let mut c = Client::new();
+        assert_eq!(c.initial_window(10), []);
+        c.connect(1);
+        let mut theirs = c.drive().unwrap().remove(0);
+        theirs.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").unwrap();
+        theirs.write_all(&[b'x'; 40]).unwrap();
+        c.drive().unwrap();
+        assert_eq!(c.frames(), [(HEADERS, END_HEADERS, 1, vec![0x88]), (DATA, 0, 1, vec![b'x'; 10])]);
+        // 15 more than it was
+        assert_eq!(c.initial_window(25), [(DATA, 0, 1, vec![b'x'; 15])]);
+        // 20 less leaves the stream 20 short, which an update makes up for
+        assert_eq!(c.initial_window(5), []);
+        c.send(WINDOW_UPDATE, 0, 1, &30u32.to_be_bytes());
+        c.drive().unwrap();
+        assert_eq!(c.frames(), [(DATA, 0, 1, vec![b'x'; 10])]);
+        // past 2^31-1 is a connection error
+        let mut payload = SETTINGS_INITIAL_WINDOW_SIZE.to_be_bytes().to_vec();
+        payload.extend_from_slice(&(1u32 << 31).to_be_bytes());
+        c.send(SETTINGS, 0, 0, &payload);
+        assert_eq!(c.drive().unwrap_err().to_string(), "SETTINGS_INITIAL_WINDOW_SIZE too large");
+        assert_eq!(c.frames(), [goaway(1, FLOW_CONTROL_ERROR)]);
+    }
+
+    #[test]
+    fn rst_stream_ends_the_stream_and_its_session_sees_it() {
+        let mut c = Client::new();
+        c.connect(1);
+        let mut theirs = c.drive().unwrap().remove(0);
+        let token = c.conn.stream_tokens()[0];
+        c.send(RST_STREAM, 0, 1, &CANCEL.to_be_bytes());
+        c.drive().unwrap();
+        assert_eq!(c.conn.take_closed(), [token]);
+        assert!(!c.conn.has_streams());
+        let mut buf = [0u8; 1024];
+        // the request, then the end
+        assert!(theirs.read(&mut buf).unwrap() > 0);
+        assert_eq!(theirs.read(&mut buf).unwrap(), 0);
+        // DATA still in flight is counted and dropped
+        c.send(DATA, 0, 1, b"late");
+        c.drive().unwrap();
+        assert_eq!(c.frames(), []);
+        c.send(RST_STREAM, 0, 0, &CANCEL.to_be_bytes());
+        assert_eq!(c.drive().unwrap_err().to_string(), "bad RST_STREAM");
+        assert_eq!(c.frames(), [goaway(1, PROTOCOL_ERROR)]);
+    }
+}
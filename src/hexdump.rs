@@ -0,0 +1,149 @@
+use std::fmt::Write as _;
+
+/// Bytes of an unparsable request head that `head` shows.
+pub const HEAD_DUMP: usize = 256;
+
+/// Headers whose values `head` blanks out, credentials the client sent.
+const REDACTED: [&[u8]; 2] = [b"proxy-authorization", b"authorization"];
+
+/// The first `HEAD_DUMP` bytes of `head` as hexdump lines, 16 bytes each:
+/// offset, hex, and the printable ASCII with `.` for anything else. The
+/// values of `REDACTED` headers are replaced by `*` past their scheme
+/// (`Basic ****`), byte for byte so the offsets still match.
+pub fn head(head: &[u8]) -> String {
+    let mut bytes = head[..head.len().min(HEAD_DUMP)].to_vec();
+    redact(&mut bytes);
+    let mut out = String::with_capacity(bytes.len() * 4 + 64);
+    for (i, line) in bytes.chunks(16).enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        let _ = write!(out, "{:04x} ", i * 16);
+        for b in line {
+            let _ = write!(out, " {:02x}", b);
+        }
+        let _ = write!(out, "{:w$}  |", "", w = (16 - line.len()) * 3);
+        out.extend(line.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }));
+        out.push('|');
+    }
+    out
+}
+
+/// Stars out the value of every `REDACTED` header line in `head`, keeping
+/// the first word, and the lines folded onto it. Matches where httparse
+/// would not, a name in any case and blanks before the colon, since the
+/// head is one that failed to parse.
+fn redact(head: &mut [u8]) {
+    let mut start = 0;
+    let mut folding = false;
+    while start < head.len() {
+        let end = head[start..].iter().position(|&b| b == b'\n').map_or(head.len(), |i| start + i);
+        let line = &head[start..end];
+        // an obs-fold continuation of a redacted value
+        if folding && matches!(line.first(), Some(b' ' | b'\t')) {
+            star(&mut head[start..end]);
+            start = end + 1;
+            continue;
+        }
+        folding = false;
+        let name = REDACTED.iter().find(|n| line.len() >= n.len() && line[..n.len()].eq_ignore_ascii_case(n));
+        if let Some(name) = name {
+            let rest = &line[name.len()..];
+            let blanks = rest.iter().take_while(|&&b| b == b' ' || b == b'\t').count();
+            if rest.get(blanks) == Some(&b':') {
+                let value = start + name.len() + blanks + 1;
+                let value = value + head[value..end].iter().take_while(|&&b| b == b' ' || b == b'\t').count();
+                let scheme = head[value..end].iter().take_while(|&&b| !b.is_ascii_whitespace()).count();
+                // a value without scheme has only the one word, hide that
+                let keep = if head[value + scheme..end].iter().any(|b| !b.is_ascii_whitespace()) {
+                    scheme
+                } else {
+                    0
+                };
+                star(&mut head[value + keep..end]);
+                folding = true;
+            }
+        }
+        start = end + 1;
+    }
+}
+
+fn star(value: &mut [u8]) {
+    for b in value {
+        if !matches!(*b, b' ' | b'\t' | b'\r') {
+            *b = b'*';
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redacted(head: &[u8]) -> String {
+        let mut bytes = head.to_vec();
+        redact(&mut bytes);
+        String::from_utf8(bytes).unwrap()
+    }
+
+    /// The printable column of a dump, its lines joined again.
+    fn ascii(dump: &str) -> String {
+        dump.lines().map(|l| l.split('|').nth(1).unwrap()).collect()
+    }
+
+    #[test]
+    fn masks_credentials_in_any_case() {
+        for name in ["Proxy-Authorization", "proxy-authorization", "PROXY-AUTHORIZATION", "Authorization"] {
+            let head = format!("CONNECT a:443 HTTP/1.1\r\n{}: Basic dXNlcjpwYXNz\r\nHost: a:443\r\n\r\n", name);
+            let want = format!("CONNECT a:443 HTTP/1.1\r\n{}: Basic ************\r\nHost: a:443\r\n\r\n", name);
+            assert_eq!(redacted(head.as_bytes()), want);
+        }
+    }
+
+    #[test]
+    fn masks_odd_spacing_and_schemeless_values() {
+        assert_eq!(redacted(b"Proxy-Authorization :  Basic  abc\r\n"), "Proxy-Authorization :  Basic  ***\r\n");
+        assert_eq!(redacted(b"authorization:\tsecret\r\n"), "authorization:\t******\r\n");
+        // no CR, as a broken client may send
+        assert_eq!(redacted(b"Authorization: Bearer tok\n\n"), "Authorization: Bearer ***\n\n");
+    }
+
+    #[test]
+    fn masks_folded_continuations() {
+        let head = b"Proxy-Authorization: Basic\r\n dXNlcjpwYXNz\r\nX-Other: kept\r\n \tfolded\r\n";
+        assert_eq!(redacted(head), "Proxy-Authorization: *****\r\n ************\r\nX-Other: kept\r\n \tfolded\r\n");
+    }
+
+    #[test]
+    fn leaves_other_headers_alone() {
+        let head = b"GET / HTTP/1.1\r\nX-Authorization: visible\r\nAuthorization-Hint: visible\r\n";
+        assert_eq!(redacted(head), String::from_utf8_lossy(head));
+    }
+
+    #[test]
+    fn value_cut_at_the_dump_boundary_is_not_leaked() {
+        let secret = "c2VjcmV0OmNyZWRlbnRpYWxzCg";
+        let mut head = "X-Pad: ".to_owned();
+        let line = format!("Proxy-Authorization: Basic {}\r\n\r\n", secret);
+        // the 256 bytes end halfway through the secret
+        head.push_str(&"p".repeat(HEAD_DUMP - head.len() - line.len() + secret.len() / 2 + 2));
+        head.push_str("\r\n");
+        head.push_str(&line);
+        let dump = ascii(&super::head(head.as_bytes()));
+        assert_eq!(dump.len(), HEAD_DUMP);
+        assert!(dump.ends_with("Proxy-Authorization: Basic *************"), "{}", dump);
+        for w in secret.as_bytes().windows(4) {
+            assert!(!dump.contains(std::str::from_utf8(w).unwrap()), "{}", dump);
+        }
+    }
+
+    #[test]
+    fn dump_lines() {
+        let dump = head(b"GET /\x00\xff HTTP/1.1\r\n");
+        assert_eq!(
+            dump,
+            "0000  47 45 54 20 2f 00 ff 20 48 54 54 50 2f 31 2e 31  |GET /.. HTTP/1.1|\n\
+             0010  0d 0a                                            |..|"
+        );
+    }
+}
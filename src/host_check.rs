@@ -0,0 +1,134 @@
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use crate::session::split_host_port;
+
+/// Whether a request's target and its Host header must name the same
+/// destination, see `Config::host_check`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostCheck {
+    Off,
+    /// log a mismatch and go where the Host header says
+    Warn,
+    /// answer a mismatch with 400
+    Enforce,
+}
+
+impl FromStr for HostCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(HostCheck::Off),
+            "warn" => Ok(HostCheck::Warn),
+            "enforce" => Ok(HostCheck::Enforce),
+            _ => Err(format!("unknown host check {:?}, expected off, warn or enforce", s)),
+        }
+    }
+}
+
+impl fmt::Display for HostCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HostCheck::Off => "off",
+            HostCheck::Warn => "warn",
+            HostCheck::Enforce => "enforce",
+        })
+    }
+}
+
+/// The authority of a request target with the port it defaults to: a
+/// CONNECT's `host:port`, 443 when the port is left out, or the one of an
+/// absolute `http://` or `https://` URI. None for an origin-form `/path`,
+/// which names no destination.
+pub fn target_authority<'a>(method: &str, target: &'a str) -> Option<(&'a str, u16)> {
+    if method.eq_ignore_ascii_case("CONNECT") {
+        return Some((target, 443));
+    }
+    let scheme = |s: &str| target.as_bytes().get(..s.len()).is_some_and(|b| b.eq_ignore_ascii_case(s.as_bytes()));
+    let (rest, default_port) = if scheme("http://") {
+        (&target[7..], 80)
+    } else if scheme("https://") {
+        (&target[8..], 443)
+    } else {
+        return None;
+    };
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    // userinfo is not part of where the request goes
+    let authority = &rest[..end];
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, a)| a);
+    Some((authority, default_port))
+}
+
+/// Whether authority `a` and Host header `b` name the same host and port,
+/// a missing port being `default_port`. Case and a trailing dot do not
+/// count, nor how an IPv6 literal is written; a port that does not parse
+/// matches nothing.
+pub fn matches(a: &str, b: &str, default_port: u16) -> bool {
+    let endpoint = |s: &str| {
+        let (host, port) = split_host_port(s.trim());
+        let port = match port {
+            None | Some("") => default_port,
+            Some(p) => p.parse().ok()?,
+        };
+        let host = match host.parse::<IpAddr>() {
+            Ok(ip) => ip.to_string(),
+            Err(_) => host.trim_end_matches('.').to_ascii_lowercase(),
+        };
+        Some((host, port))
+    };
+    match (endpoint(a), endpoint(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matching_host() {
+        assert!(matches("example.com:443", "example.com:443", 443));
+        assert!(matches("Example.COM:443", "example.com.:443", 443));
+        assert!(matches("10.0.0.1:8080", "10.0.0.1:8080", 443));
+    }
+
+    #[test]
+    fn mismatch() {
+        assert!(!matches("example.com:443", "evil.com:443", 443));
+        assert!(!matches("example.com:443", "example.com.evil.com:443", 443));
+        assert!(!matches("example.com:443", "", 443));
+    }
+
+    #[test]
+    fn default_port_versus_explicit_one() {
+        assert!(matches("example.com:443", "example.com", 443));
+        assert!(matches("example.com", "example.com:443", 443));
+        assert!(matches("example.com:", "example.com:443", 443));
+        // port-only differences
+        assert!(!matches("example.com:8443", "example.com", 443));
+        assert!(!matches("example.com:443", "example.com:444", 443));
+        assert!(matches("example.com", "example.com:80", 80));
+        assert!(!matches("example.com", "example.com:443", 80));
+        assert!(!matches("example.com:443", "example.com:https", 443));
+    }
+
+    #[test]
+    fn ipv6_literals() {
+        assert!(matches("[::1]:443", "[::1]:443", 443));
+        assert!(matches("[::1]:443", "[::1]", 443));
+        assert!(matches("[2001:DB8::1]:443", "[2001:db8:0:0::1]:443", 443));
+        assert!(!matches("[::1]:443", "[::2]:443", 443));
+        assert!(!matches("[::1]:443", "[::1]:8443", 443));
+    }
+
+    #[test]
+    fn target_authorities() {
+        assert_eq!(target_authority("CONNECT", "example.com:8443"), Some(("example.com:8443", 443)));
+        assert_eq!(target_authority("GET", "http://example.com/a?b"), Some(("example.com", 80)));
+        assert_eq!(target_authority("GET", "HTTPS://user:pw@example.com:8443"), Some(("example.com:8443", 443)));
+        assert_eq!(target_authority("GET", "http://[::1]:8080/"), Some(("[::1]:8080", 80)));
+        assert_eq!(target_authority("GET", "/path"), None);
+        assert_eq!(target_authority("GET", "ftp://example.com/"), None);
+    }
+}
@@ -0,0 +1,216 @@
+use std::{fmt::Display, str::FromStr};
+
+use regex::{Regex, RegexBuilder};
+use serde::{Serialize, Serializer};
+
+use crate::session::split_host_port;
+
+/// A destination pattern matched against the CONNECT authority: a host
+/// glob with an optional port, `*.corp`, `10.0.*:22`, `[fd00::*]:443`.
+/// `*` matches any run of characters dots included, `?` exactly one;
+/// hosts compare case-insensitively and without a trailing dot, so
+/// `*.corp` matches `a.corp.` but not `corp`. Without a port (or with
+/// `:*`) any port matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostPattern {
+    text: String,
+    host: String,
+    port: Option<u16>,
+}
+
+impl HostPattern {
+    pub fn matches(&self, host: &str, port: u16) -> bool {
+        let host = host.strip_suffix('.').unwrap_or(host);
+        self.port.is_none_or(|p| p == port) && glob(self.host.as_bytes(), host.to_ascii_lowercase().as_bytes())
+    }
+}
+
+impl FromStr for HostPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = split_host_port(s);
+        let host = host.strip_suffix('.').unwrap_or(host);
+        if host.is_empty() {
+            return Err(format!("host pattern {:?} has no host", s));
+        }
+        let port = match port {
+            None | Some("*") => None,
+            Some(p) => Some(
+                p.parse()
+                    .map_err(|_| format!("invalid port {:?} in host pattern {:?}", p, s))?,
+            ),
+        };
+        Ok(HostPattern {
+            text: s.to_owned(),
+            host: host.to_ascii_lowercase(),
+            port,
+        })
+    }
+}
+
+impl Display for HostPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl Serialize for HostPattern {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&self.text)
+    }
+}
+
+/// Longest `HostRegex` source taken.
+const MAX_REGEX_LEN: usize = 1024;
+
+/// Cap on the compiled size of one `HostRegex`, well above what host
+/// patterns need; larger ones are refused when the config loads rather
+/// than slowing every request.
+const REGEX_SIZE_LIMIT: usize = 256 * 1024;
+
+/// A regular expression matched against the destination host, for
+/// policies globs cannot say, `^.*\.(ru|cn)$` or `^[0-9.]+$`. Unanchored
+/// as written, case-insensitive, the host without a trailing dot.
+/// Matching runs in time linear in the host, whatever the pattern.
+#[derive(Debug, Clone)]
+pub struct HostRegex(Regex);
+
+impl HostRegex {
+    pub fn matches(&self, host: &str) -> bool {
+        self.0.is_match(host.strip_suffix('.').unwrap_or(host))
+    }
+}
+
+impl FromStr for HostRegex {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() > MAX_REGEX_LEN {
+            return Err(format!("host regex longer than {} bytes", MAX_REGEX_LEN));
+        }
+        RegexBuilder::new(s)
+            .case_insensitive(true)
+            .size_limit(REGEX_SIZE_LIMIT)
+            .nest_limit(32)
+            .build()
+            .map(HostRegex)
+            .map_err(|e| format!("invalid host regex {:?}: {}", s, e))
+    }
+}
+
+impl Display for HostRegex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0.as_str())
+    }
+}
+
+impl Serialize for HostRegex {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(self.0.as_str())
+    }
+}
+
+/// Glob match without allocation, backtracking to the last `*` seen.
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((sp, st)) => {
+                    p = sp + 1;
+                    t = st + 1;
+                    star = Some((sp, st + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(s: &str) -> HostPattern {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn wildcard_needs_a_label_before_the_domain() {
+        let p = pattern("*.example.com");
+        assert!(!p.matches("example.com", 443));
+        assert!(p.matches("a.example.com", 443));
+        assert!(p.matches("a.b.example.com", 443));
+        assert!(!p.matches("badexample.com", 443));
+        assert!(!p.matches("a.example.com.evil", 443));
+    }
+
+    #[test]
+    fn matches_case_insensitively() {
+        assert!(pattern("*.Example.COM").matches("a.example.com", 80));
+        assert!(pattern("api.partner.io").matches("API.Partner.IO", 80));
+    }
+
+    #[test]
+    fn ignores_a_trailing_dot() {
+        assert!(pattern("*.example.com").matches("a.example.com.", 443));
+        assert!(pattern("example.com.").matches("example.com", 443));
+        assert!(!pattern("*.example.com").matches("example.com.", 443));
+    }
+
+    #[test]
+    fn port_constraints() {
+        let p = pattern("*.example.com:443");
+        assert!(p.matches("a.example.com", 443));
+        assert!(!p.matches("a.example.com", 80));
+        assert!(pattern("*.example.com:*").matches("a.example.com", 8443));
+        assert!(pattern("[fd00::*]:22").matches("fd00::1", 22));
+        assert!(pattern("10.0.*:22").matches("10.0.3.4", 22));
+    }
+
+    #[test]
+    fn question_mark_is_one_character() {
+        let p = pattern("node?.corp");
+        assert!(p.matches("node1.corp", 80));
+        assert!(!p.matches("node.corp", 80));
+        assert!(!p.matches("node12.corp", 80));
+    }
+
+    #[test]
+    fn rejects_bad_patterns() {
+        assert!("".parse::<HostPattern>().is_err());
+        assert!(".".parse::<HostPattern>().is_err());
+        assert!("a.corp:http".parse::<HostPattern>().is_err());
+        assert!("a.corp:70000".parse::<HostPattern>().is_err());
+    }
+
+    #[test]
+    fn regex_is_case_insensitive_and_ignores_a_trailing_dot() {
+        let r: HostRegex = r"^.*\.(ru|cn)$".parse().unwrap();
+        assert!(r.matches("mail.yandex.RU"));
+        assert!(r.matches("a.cn."));
+        assert!(!r.matches("ru.example.com"));
+        // unanchored as written
+        let r: HostRegex = "internal".parse().unwrap();
+        assert!(r.matches("db.internal.example.com"));
+    }
+
+    #[test]
+    fn rejects_bad_and_oversized_regexes() {
+        assert!("(".parse::<HostRegex>().is_err());
+        assert!("a".repeat(MAX_REGEX_LEN + 1).parse::<HostRegex>().is_err());
+        // small to write, far over REGEX_SIZE_LIMIT once compiled
+        assert!(r"\w{500}\w{500}".parse::<HostRegex>().unwrap_err().contains("size limit"));
+    }
+}
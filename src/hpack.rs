@@ -0,0 +1,497 @@
+//! HPACK (RFC 7541), the header compression of HTTP/2: a decoder for the
+//! request headers clients send, and the few literals our responses need.
+
+use std::collections::VecDeque;
+
+/// `SETTINGS_HEADER_TABLE_SIZE`, the default we leave as it is: the most
+/// a client's encoder may keep in our decoder's dynamic table.
+pub const TABLE_SIZE: usize = 4096;
+
+/// A header as it goes over the wire, lowercase name and raw value.
+pub type Header = (Vec<u8>, Vec<u8>);
+
+/// Appendix A, index 1 first.
+const STATIC: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// Code lengths of the Huffman code of Appendix B by symbol, 256 being
+/// EOS. The code is canonical, so the lengths are all it takes.
+const HUFFMAN_LENGTHS: [u8; 257] = [
+    13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 30, 28, 28, 28, 28, 28, 28,
+    28, 28, 28, 6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6, 5, 5, 5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15, 6, 12,
+    10, 13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6, 15, 5, 6,
+    5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6, 6, 5, 6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28, 20, 22, 20, 20, 22, 22,
+    22, 23, 22, 23, 23, 23, 23, 23, 24, 23, 24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23, 24, 22, 21, 20,
+    22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23, 21, 21, 22, 21, 23, 22, 23, 23, 20, 22, 22, 22, 23, 22, 22, 23,
+    26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25, 19, 21, 26, 27, 27, 26, 27, 24, 21, 21, 26, 26, 28,
+    27, 27, 27, 20, 24, 20, 21, 22, 21, 21, 23, 22, 22, 25, 25, 24, 24, 26, 23, 26, 27, 26, 26, 27, 27, 27, 27, 27, 28,
+    27, 27, 27, 27, 27, 26, 30,
+];
+
+const HUFFMAN_MAX: usize = 30;
+
+/// The canonical code laid out for decoding: how many codes there are of
+/// each length, and the symbols ordered by code.
+struct Canonical {
+    count: [u16; HUFFMAN_MAX + 1],
+    symbols: [u16; 257],
+}
+
+const CANONICAL: Canonical = canonical();
+
+const fn canonical() -> Canonical {
+    let mut count = [0u16; HUFFMAN_MAX + 1];
+    let mut s = 0;
+    while s < 257 {
+        count[HUFFMAN_LENGTHS[s] as usize] += 1;
+        s += 1;
+    }
+    let mut symbols = [0u16; 257];
+    let mut n = 0;
+    let mut len = 1;
+    while len <= HUFFMAN_MAX {
+        let mut s = 0;
+        while s < 257 {
+            if HUFFMAN_LENGTHS[s] as usize == len {
+                symbols[n] = s as u16;
+                n += 1;
+            }
+            s += 1;
+        }
+        len += 1;
+    }
+    Canonical { count, symbols }
+}
+
+/// The decoding side of a connection's header compression, keeping the
+/// dynamic table the client's encoder fills.
+pub struct Decoder {
+    table: VecDeque<Header>,
+    size: usize,
+    max: usize,
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder { table: VecDeque::new(), size: 0, max: TABLE_SIZE }
+    }
+
+    /// The headers of a complete header block, in order.
+    pub fn decode(&mut self, mut block: &[u8]) -> Result<Vec<Header>, &'static str> {
+        let mut headers = Vec::new();
+        while let Some(&first) = block.first() {
+            if first & 0x80 != 0 {
+                let index = integer(&mut block, 7)?;
+                headers.push(self.entry(index)?);
+            } else if first & 0xc0 == 0x40 {
+                let header = self.literal(&mut block, 6)?;
+                self.insert(header.clone());
+                headers.push(header);
+            } else if first & 0xe0 == 0x20 {
+                let max = integer(&mut block, 5)?;
+                if max > TABLE_SIZE {
+                    return Err("table size update past SETTINGS_HEADER_TABLE_SIZE");
+                }
+                self.max = max;
+                self.evict(0);
+            } else {
+                // without indexing and never indexed alike, to a proxy
+                headers.push(self.literal(&mut block, 4)?);
+            }
+        }
+        Ok(headers)
+    }
+
+    /// The header at `index` of the static table followed by the dynamic.
+    fn entry(&self, index: usize) -> Result<Header, &'static str> {
+        match index {
+            0 => Err("header index 0"),
+            i if i <= STATIC.len() => {
+                let (name, value) = STATIC[i - 1];
+                Ok((name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            }
+            i => self.table.get(i - STATIC.len() - 1).cloned().ok_or("header index past the table"),
+        }
+    }
+
+    /// A literal header field whose name index has a `prefix` bit prefix.
+    fn literal(&self, block: &mut &[u8], prefix: u8) -> Result<Header, &'static str> {
+        let name = match integer(block, prefix)? {
+            0 => string(block)?,
+            index => self.entry(index)?.0,
+        };
+        Ok((name, string(block)?))
+    }
+
+    fn insert(&mut self, header: Header) {
+        let size = entry_size(&header);
+        self.evict(size);
+        // an entry larger than the table empties it and is not kept
+        if size <= self.max {
+            self.size += size;
+            self.table.push_front(header);
+        }
+    }
+
+    /// Drops the oldest entries until `room` more fits.
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.max {
+            match self.table.pop_back() {
+                Some(h) => self.size -= entry_size(&h),
+                None => break,
+            }
+        }
+    }
+}
+
+/// An entry's size as the table counts it.
+fn entry_size((name, value): &Header) -> usize {
+    name.len() + value.len() + 32
+}
+
+/// Decodes an integer with a `prefix` bit prefix off the front of `block`.
+fn integer(block: &mut &[u8], prefix: u8) -> Result<usize, &'static str> {
+    let (&first, mut rest) = block.split_first().ok_or("header block cut short")?;
+    let max = (1usize << prefix) - 1;
+    let mut value = first as usize & max;
+    if value == max {
+        let mut shift = 0;
+        loop {
+            let (&b, tail) = rest.split_first().ok_or("header block cut short")?;
+            rest = tail;
+            if shift > 21 {
+                return Err("header integer too large");
+            }
+            value += ((b & 0x7f) as usize) << shift;
+            shift += 7;
+            if b & 0x80 == 0 {
+                break;
+            }
+        }
+    }
+    *block = rest;
+    Ok(value)
+}
+
+/// Decodes a string literal off the front of `block`, Huffman coded or
+/// not as its first bit says.
+fn string(block: &mut &[u8]) -> Result<Vec<u8>, &'static str> {
+    let huffman = block.first().is_some_and(|b| b & 0x80 != 0);
+    let len = integer(block, 7)?;
+    if len > block.len() {
+        return Err("header block cut short");
+    }
+    let (raw, rest) = block.split_at(len);
+    *block = rest;
+    if huffman {
+        huffman_decode(raw)
+    } else {
+        Ok(raw.to_vec())
+    }
+}
+
+fn huffman_decode(raw: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let mut out = Vec::with_capacity(raw.len() * 8 / 5);
+    // the bits of the code read so far and their number; where codes of
+    // that length start, as a value and in `CANONICAL.symbols`
+    let (mut bits, mut len, mut first, mut index) = (0usize, 0usize, 0usize, 0usize);
+    for bit in raw.iter().flat_map(|b| (0..8).rev().map(move |i| (b >> i) & 1)) {
+        bits = bits << 1 | bit as usize;
+        len += 1;
+        let count = CANONICAL.count[len] as usize;
+        match bits.checked_sub(first) {
+            Some(n) if n < count => {
+                match CANONICAL.symbols[index + n] {
+                    256 => return Err("EOS in a Huffman string"),
+                    s => out.push(s as u8),
+                }
+                (bits, len, first, index) = (0, 0, 0, 0);
+            }
+            _ if len == HUFFMAN_MAX => return Err("bad Huffman code"),
+            _ => {
+                index += count;
+                first = (first + count) << 1;
+            }
+        }
+    }
+    // what is left has to be padding: at most 7 bits of the EOS prefix
+    if len > 7 || bits != (1 << len) - 1 {
+        return Err("bad Huffman padding");
+    }
+    Ok(out)
+}
+
+/// A response header block: `:status` and then `headers`, all literals
+/// the decoder is asked not to index, Huffman left out.
+pub fn encode(status: u16, headers: &[(&[u8], &[u8])]) -> Vec<u8> {
+    let mut out = Vec::new();
+    match status {
+        // the static table has the common ones
+        200 => out.push(0x88),
+        _ => {
+            // literal without indexing, name `:status` at static index 8
+            out.push(0x08);
+            encode_string(&mut out, status.to_string().as_bytes());
+        }
+    }
+    for (name, value) in headers {
+        out.push(0x00);
+        encode_string(&mut out, name);
+        encode_string(&mut out, value);
+    }
+    out
+}
+
+fn encode_string(out: &mut Vec<u8>, s: &[u8]) {
+    encode_integer(out, s.len(), 7, 0);
+    out.extend_from_slice(s);
+}
+
+fn encode_integer(out: &mut Vec<u8>, mut value: usize, prefix: u8, flags: u8) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        out.push(flags | value as u8);
+        return;
+    }
+    out.push(flags | max as u8);
+    value -= max;
+    while value >= 0x80 {
+        out.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The bytes of `hex`, the way the RFC's examples print them.
+    fn bytes(hex: &str) -> Vec<u8> {
+        let digits: Vec<u8> = hex.bytes().filter(u8::is_ascii_hexdigit).collect();
+        digits.chunks(2).map(|d| u8::from_str_radix(std::str::from_utf8(d).unwrap(), 16).unwrap()).collect()
+    }
+
+    fn headers(list: &[(&str, &str)]) -> Vec<Header> {
+        list.iter().map(|(n, v)| (n.as_bytes().to_vec(), v.as_bytes().to_vec())).collect()
+    }
+
+    /// The dynamic table, newest first, and its size.
+    fn table(decoder: &Decoder) -> (Vec<Header>, usize) {
+        (decoder.table.iter().cloned().collect(), decoder.size)
+    }
+
+    #[test]
+    fn integers_of_appendix_c1() {
+        for (value, prefix, wire) in [(10, 5, &[0x0a][..]), (1337, 5, &[0x1f, 0x9a, 0x0a]), (42, 8, &[0x2a])] {
+            let mut block = wire;
+            assert_eq!(integer(&mut block, prefix), Ok(value));
+            assert!(block.is_empty());
+            let mut out = Vec::new();
+            encode_integer(&mut out, value, prefix, 0);
+            assert_eq!(out, wire);
+        }
+        assert_eq!(integer(&mut &[0x1f, 0x9a][..], 5), Err("header block cut short"));
+        assert_eq!(integer(&mut &[0x7f, 0xff, 0xff, 0xff, 0xff, 0x0f][..], 7), Err("header integer too large"));
+    }
+
+    #[test]
+    fn header_fields_of_appendix_c2() {
+        let mut d = Decoder::new();
+        let block = bytes("400a 6375 7374 6f6d 2d6b 6579 0d63 7573 746f 6d2d 6865 6164 6572");
+        assert_eq!(d.decode(&block), Ok(headers(&[("custom-key", "custom-header")])));
+        assert_eq!(table(&d), (headers(&[("custom-key", "custom-header")]), 55));
+        // without indexing and never indexed leave the table as it is
+        let mut d = Decoder::new();
+        assert_eq!(d.decode(&bytes("040c 2f73 616d 706c 652f 7061 7468")), Ok(headers(&[(":path", "/sample/path")])));
+        assert_eq!(d.decode(&bytes("1008 7061 7373 776f 7264 0673 6563 7265 74")), Ok(headers(&[("password", "secret")])));
+        assert_eq!(d.decode(&[0x82]), Ok(headers(&[(":method", "GET")])));
+        assert_eq!(table(&d), (vec![], 0));
+    }
+
+    /// C.3 without Huffman and C.4 with it: the same three requests, the
+    /// later ones indexing what the earlier ones added.
+    #[test]
+    fn requests_of_appendix_c3_and_c4() {
+        let plain = [
+            "8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+            "8286 84be 5808 6e6f 2d63 6163 6865",
+            "8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+        ];
+        let huffman = [
+            "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff",
+            "8286 84be 5886 a8eb 1064 9cbf",
+            "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+        ];
+        let first = [(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")];
+        let second = [
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/"),
+            (":authority", "www.example.com"),
+            ("cache-control", "no-cache"),
+        ];
+        let third = [
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":path", "/index.html"),
+            (":authority", "www.example.com"),
+            ("custom-key", "custom-value"),
+        ];
+        let tables = [
+            (headers(&[(":authority", "www.example.com")]), 57),
+            (headers(&[("cache-control", "no-cache"), (":authority", "www.example.com")]), 110),
+            (
+                headers(&[("custom-key", "custom-value"), ("cache-control", "no-cache"), (":authority", "www.example.com")]),
+                164,
+            ),
+        ];
+        for blocks in [plain, huffman] {
+            let mut d = Decoder::new();
+            for ((block, expected), after) in blocks.iter().zip([&first[..], &second, &third]).zip(&tables) {
+                assert_eq!(d.decode(&bytes(block)), Ok(headers(expected)), "{}", block);
+                assert_eq!(&table(&d), after, "{}", block);
+            }
+        }
+    }
+
+    /// C.5, responses with a table of 256 bytes that the third and fourth
+    /// entries overflow; the size set by an update first, as a client's
+    /// encoder would after SETTINGS.
+    #[test]
+    fn responses_of_appendix_c5_evict_the_oldest() {
+        let mut d = Decoder::new();
+        assert_eq!(d.decode(&[0x3f, 0xe1, 0x01]), Ok(vec![]));
+        let date = |s| format!("Mon, 21 Oct 2013 20:13:{} GMT", s);
+        let (date21, date22) = (date(21), date(22));
+        let block = bytes(
+            "4803 3330 3258 0770 7269 7661 7465 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133 2032 303a 3133 3a32 \
+             3120 474d 546e 1768 7474 7073 3a2f 2f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+        );
+        let location = ("location", "https://www.example.com");
+        let first = [(":status", "302"), ("cache-control", "private"), ("date", &date21), location];
+        assert_eq!(d.decode(&block), Ok(headers(&first)));
+        let mut newest_first = first;
+        newest_first.reverse();
+        assert_eq!(table(&d), (headers(&newest_first), 222));
+        // :status 302 makes room for :status 307
+        let second = [(":status", "307"), ("cache-control", "private"), ("date", &date21), location];
+        assert_eq!(d.decode(&bytes("4803 3330 37c1 c0bf")), Ok(headers(&second)));
+        let after = [(":status", "307"), location, ("date", &date21), ("cache-control", "private")];
+        assert_eq!(table(&d), (headers(&after), 222));
+        let block = bytes(
+            "88c1 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133 2032 303a 3133 3a32 3220 474d 54c0 5a04 677a 6970 \
+             7738 666f 6f3d 4153 444a 4b48 514b 425a 584f 5157 454f 5049 5541 5851 5745 4f49 553b 206d 6178 2d61 \
+             6765 3d33 3630 303b 2076 6572 7369 6f6e 3d31",
+        );
+        let cookie = ("set-cookie", "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1");
+        let third = [
+            (":status", "200"),
+            ("cache-control", "private"),
+            ("date", &date22),
+            location,
+            ("content-encoding", "gzip"),
+            cookie,
+        ];
+        assert_eq!(d.decode(&block), Ok(headers(&third)));
+        assert_eq!(table(&d), (headers(&[cookie, ("content-encoding", "gzip"), ("date", &date22)]), 215));
+    }
+
+    #[test]
+    fn an_entry_larger_than_the_table_empties_it() {
+        let mut d = Decoder::new();
+        d.decode(&bytes("400a 6375 7374 6f6d 2d6b 6579 0d63 7573 746f 6d2d 6865 6164 6572")).unwrap();
+        let mut block = vec![0x3f, 0x21, 0x40, 0x01, b'n', 0x7f, 0x0d];
+        block.extend([b'v'; 140]);
+        // the table is 64 bytes now, the entry 173
+        assert_eq!(d.decode(&block).unwrap()[0].1.len(), 140);
+        assert_eq!(table(&d), (vec![], 0));
+        assert_eq!(d.decode(&[0xbe]), Err("header index past the table"));
+        assert_eq!(d.decode(&[0x3f, 0xe2, 0x1f]), Err("table size update past SETTINGS_HEADER_TABLE_SIZE"));
+        assert_eq!(d.decode(&[0x80]), Err("header index 0"));
+    }
+
+    #[test]
+    fn huffman_padding_is_at_most_seven_bits_of_ones() {
+        // '0' is 00000, then the padding
+        assert_eq!(huffman_decode(&[0x07]), Ok(b"0".to_vec()));
+        assert_eq!(huffman_decode(&[0x00]), Err("bad Huffman padding"));
+        assert_eq!(huffman_decode(&[0x07, 0xff]), Err("bad Huffman padding"));
+        assert_eq!(huffman_decode(&[0xff, 0xff, 0xff, 0xfc]), Err("EOS in a Huffman string"));
+        // a string literal whose Huffman bit is set
+        assert_eq!(string(&mut &[0x81, 0x07][..]), Ok(b"0".to_vec()));
+        assert_eq!(string(&mut &[0x82, 0x07][..]), Err("header block cut short"));
+    }
+
+    #[test]
+    fn what_we_encode_decodes() {
+        for status in [200, 407, 502] {
+            let block = encode(status, &[(b"proxy-authenticate", b"Basic realm=\"proxy\"")]);
+            let expected = headers(&[(":status", &status.to_string()), ("proxy-authenticate", "Basic realm=\"proxy\"")]);
+            assert_eq!(Decoder::new().decode(&block), Ok(expected));
+        }
+        let long = vec![b'x'; 300];
+        assert_eq!(Decoder::new().decode(&encode(200, &[(b"x", &long)])).unwrap()[1].1, long);
+    }
+}
@@ -0,0 +1,316 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+    sync::RwLock,
+};
+
+use log::warn;
+use nix::ifaddrs;
+use serde::{Serialize, Serializer};
+
+/// Addresses of the host's interfaces, see `refresh_own`.
+static OWN: RwLock<Vec<IpAddr>> = RwLock::new(Vec::new());
+
+/// An address block, `10.1.0.0/16` or `fd00::/8`; a bare address is a
+/// block of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = s.split_once('/').map_or((s, None), |(a, p)| (a, Some(p)));
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| format!("invalid address {:?} in {:?}, expected ip or ip/prefix", addr, s))?;
+        let addr = canonical(addr);
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            None => max,
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|&p| p <= max)
+                .ok_or_else(|| format!("invalid prefix length {:?} in {:?}", p, s))?,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+/// `ip` with an IPv4-mapped IPv6 address as the IPv4 one it maps, which
+/// is what a connect to it reaches.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        v4 => v4,
+    }
+}
+
+/// The IPv4 address an IPv6 one carries and reaches it by: the NAT64
+/// well-known prefix `64:ff9b::/96`, 6to4 `2002::/16` and the deprecated
+/// IPv4-compatible `::a.b.c.d`. IPv4-mapped ones are left to `canonical`.
+pub fn embedded_v4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let s = ip.segments();
+    let low = Ipv4Addr::new((s[6] >> 8) as u8, s[6] as u8, (s[7] >> 8) as u8, s[7] as u8);
+    match s {
+        [0x64, 0xff9b, 0, 0, 0, 0, ..] => Some(low),
+        [0x2002, hi, lo, ..] => Some(Ipv4Addr::new((hi >> 8) as u8, hi as u8, (lo >> 8) as u8, lo as u8)),
+        // :: and ::1 are IPv6's own
+        [0, 0, 0, 0, 0, 0, ..] if u32::from(low) > 1 => Some(low),
+        _ => None,
+    }
+}
+
+/// The kind of internal address `ip` is, None for a public one: RFC 1918
+/// and the other private IPv4 blocks, loopback, link-local (cloud
+/// metadata services among them), unique local IPv6, carrier-grade NAT,
+/// benchmarking, multicast, broadcast and reserved, unspecified, or one
+/// of the host's own interfaces. An IPv6 address embedding an IPv4 one,
+/// see `embedded_v4`, is the kind of that.
+pub fn internal(ip: IpAddr) -> Option<&'static str> {
+    let ip = canonical(ip);
+    let kind = match ip {
+        IpAddr::V4(v4) => v4_kind(v4),
+        IpAddr::V6(v6) => v6_kind(v6).or_else(|| embedded_v4(v6).and_then(v4_kind)),
+    };
+    kind.or_else(|| OWN.read().unwrap().contains(&ip).then_some("own-address"))
+}
+
+/// `internal` unless one of the `allow` blocks has `ip`, or the IPv4
+/// address it embeds.
+pub fn blocked(ip: IpAddr, allow: &[Cidr]) -> Option<&'static str> {
+    let embedded = match ip {
+        IpAddr::V6(v6) => embedded_v4(v6).map(IpAddr::V4),
+        IpAddr::V4(_) => None,
+    };
+    let allowed = |c: &Cidr| c.contains(ip) || embedded.is_some_and(|v4| c.contains(v4));
+    internal(ip).filter(|_| !allow.iter().any(allowed))
+}
+
+fn v4_kind(ip: Ipv4Addr) -> Option<&'static str> {
+    let [a, b, ..] = ip.octets();
+    if ip.is_loopback() {
+        Some("loopback")
+    } else if ip.is_private() {
+        Some("private")
+    } else if ip.is_link_local() {
+        Some("link-local")
+    } else if a == 100 && (64..128).contains(&b) {
+        Some("cgn")
+    } else if a == 0 {
+        // 0.0.0.0 connects to the host itself
+        Some("unspecified")
+    } else if a == 198 && (b == 18 || b == 19) {
+        Some("benchmarking")
+    } else if ip.is_multicast() {
+        Some("multicast")
+    } else if ip.is_broadcast() {
+        Some("broadcast")
+    } else if a >= 240 {
+        Some("reserved")
+    } else {
+        None
+    }
+}
+
+fn v6_kind(ip: Ipv6Addr) -> Option<&'static str> {
+    let first = ip.segments()[0];
+    if ip.is_loopback() {
+        Some("loopback")
+    } else if ip.is_unspecified() {
+        Some("unspecified")
+    } else if first & 0xfe00 == 0xfc00 {
+        Some("ula")
+    } else if first & 0xffc0 == 0xfe80 {
+        Some("link-local")
+    } else if ip.is_multicast() {
+        Some("multicast")
+    } else {
+        None
+    }
+}
+
+/// Takes the addresses of the host's interfaces for `internal`. Runs at
+/// startup and on reload; when they cannot be listed the previous ones
+/// stay.
+pub fn refresh_own() {
+    let addrs = match ifaddrs::getifaddrs() {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            warn!("cannot list interface addresses, keep {} known: {}", OWN.read().unwrap().len(), e);
+            return;
+        }
+    };
+    let mut own = addrs
+        .filter_map(|ifa| {
+            let addr = ifa.address?;
+            match (addr.as_sockaddr_in(), addr.as_sockaddr_in6()) {
+                (Some(v4), _) => Some(IpAddr::V4(v4.ip())),
+                (_, Some(v6)) => Some(IpAddr::V6(v6.ip())),
+                _ => None,
+            }
+        })
+        .collect::<Vec<_>>();
+    own.sort_unstable();
+    own.dedup();
+    *OWN.write().unwrap() = own;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kind(ip: &str) -> Option<&'static str> {
+        internal(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn v4_ranges() {
+        let table = [
+            ("127.0.0.1", Some("loopback")),
+            ("127.255.255.254", Some("loopback")),
+            ("10.0.0.5", Some("private")),
+            ("172.16.0.1", Some("private")),
+            ("172.31.255.255", Some("private")),
+            ("192.168.1.1", Some("private")),
+            ("169.254.169.254", Some("link-local")),
+            ("100.64.0.1", Some("cgn")),
+            ("100.127.255.255", Some("cgn")),
+            ("0.0.0.0", Some("unspecified")),
+            ("0.1.2.3", Some("unspecified")),
+            ("198.18.0.1", Some("benchmarking")),
+            ("198.19.255.255", Some("benchmarking")),
+            ("224.0.0.1", Some("multicast")),
+            ("239.255.255.250", Some("multicast")),
+            ("255.255.255.255", Some("broadcast")),
+            ("240.0.0.1", Some("reserved")),
+            // just outside the blocks
+            ("172.32.0.1", None),
+            ("100.128.0.1", None),
+            ("198.20.0.1", None),
+            ("198.17.255.255", None),
+            ("8.8.8.8", None),
+            ("223.255.255.255", None),
+        ];
+        for (ip, want) in table {
+            assert_eq!(kind(ip), want, "{}", ip);
+        }
+    }
+
+    #[test]
+    fn v6_ranges() {
+        let table = [
+            ("::1", Some("loopback")),
+            ("::", Some("unspecified")),
+            ("fd12:3456::1", Some("ula")),
+            ("fc00::1", Some("ula")),
+            ("fe80::1", Some("link-local")),
+            ("febf::1", Some("link-local")),
+            ("ff02::1", Some("multicast")),
+            ("2606:4700::1111", None),
+            ("fec0::1", None),
+        ];
+        for (ip, want) in table {
+            assert_eq!(kind(ip), want, "{}", ip);
+        }
+    }
+
+    #[test]
+    fn v6_embedding_v4_is_the_v4_kind() {
+        let table = [
+            ("::ffff:10.0.0.5", Some("private")),
+            ("::ffff:127.0.0.1", Some("loopback")),
+            ("64:ff9b::10.0.0.5", Some("private")),
+            ("64:ff9b::169.254.169.254", Some("link-local")),
+            ("64:ff9b::8.8.8.8", None),
+            ("2002:a00:5::1", Some("private")),
+            ("2002:7f00:1::", Some("loopback")),
+            ("2002:a9fe:a9fe::1", Some("link-local")),
+            ("2002:808:808::1", None),
+            ("::10.0.0.5", Some("private")),
+            ("::127.0.0.1", Some("loopback")),
+            ("::8.8.8.8", None),
+        ];
+        for (ip, want) in table {
+            assert_eq!(kind(ip), want, "{}", ip);
+        }
+    }
+
+    #[test]
+    fn embedded_v4_only_for_the_embedding_prefixes() {
+        let v4 = |ip: &str| embedded_v4(ip.parse().unwrap());
+        assert_eq!(v4("64:ff9b::a00:5"), Some(Ipv4Addr::new(10, 0, 0, 5)));
+        assert_eq!(v4("2002:c0a8:101:1::1"), Some(Ipv4Addr::new(192, 168, 1, 1)));
+        assert_eq!(v4("::c0a8:101"), Some(Ipv4Addr::new(192, 168, 1, 1)));
+        // a local-use NAT64 prefix, not the well-known one
+        assert_eq!(v4("64:ff9b:1::a00:5"), None);
+        assert_eq!(v4("::1"), None);
+        assert_eq!(v4("::"), None);
+        assert_eq!(v4("2001:db8::a00:5"), None);
+    }
+
+    #[test]
+    fn allow_internal_lets_blocks_through() {
+        let allow: Vec<Cidr> = ["10.20.0.0/16", "fd12:3456::/32"].iter().map(|c| c.parse().unwrap()).collect();
+        let blocked = |ip: &str| blocked(ip.parse().unwrap(), &allow);
+        assert_eq!(blocked("10.20.1.2"), None);
+        assert_eq!(blocked("10.21.1.2"), Some("private"));
+        assert_eq!(blocked("fd12:3456::1"), None);
+        assert_eq!(blocked("fd12:3457::1"), Some("ula"));
+        // an address embedding an allowed one goes where it goes
+        assert_eq!(blocked("::ffff:10.20.1.2"), None);
+        assert_eq!(blocked("64:ff9b::10.20.1.2"), None);
+        assert_eq!(blocked("64:ff9b::10.21.1.2"), Some("private"));
+        assert_eq!(blocked("127.0.0.1"), Some("loopback"));
+        // everything allowed is the check off
+        let all: Vec<Cidr> = ["0.0.0.0/0", "::/0"].iter().map(|c| c.parse().unwrap()).collect();
+        assert_eq!(super::blocked("127.0.0.1".parse().unwrap(), &all), None);
+        assert_eq!(super::blocked("::1".parse().unwrap(), &all), None);
+    }
+
+    #[test]
+    fn cidr_parse_and_contains() {
+        let c: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(c.contains("10.1.200.3".parse().unwrap()));
+        assert!(c.contains("::ffff:10.1.0.1".parse().unwrap()));
+        assert!(!c.contains("10.2.0.1".parse().unwrap()));
+        assert!(!c.contains("::1".parse().unwrap()));
+        let one: Cidr = "192.0.2.7".parse().unwrap();
+        assert_eq!(one.to_string(), "192.0.2.7/32");
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("example.com/8".parse::<Cidr>().is_err());
+    }
+}
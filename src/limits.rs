@@ -0,0 +1,50 @@
+use log::{info, warn};
+use nix::sys::resource::{getrlimit, setrlimit, Resource};
+
+/// fds a tunnel holds: both sockets plus a splice pipe per direction
+pub const FDS_PER_SESSION: u64 = 6;
+
+/// fds kept back for listeners, epoll instances, wakers, stdio and logs
+pub const RESERVED_FDS: u64 = 64;
+
+/// Raises the RLIMIT_NOFILE soft limit to `target`, or to the hard limit
+/// if none is configured, and returns the limit in effect afterwards.
+/// Failing to raise it is not fatal, the current limit is returned.
+pub fn raise_nofile(target: Option<u64>) -> u64 {
+    let (soft, hard) = match getrlimit(Resource::RLIMIT_NOFILE) {
+        Ok(l) => l,
+        Err(e) => {
+            warn!("getrlimit RLIMIT_NOFILE err {:?}", e);
+            return 1024;
+        }
+    };
+    let wanted = target.unwrap_or(hard);
+    if wanted <= soft {
+        info!("RLIMIT_NOFILE soft {} hard {}", soft, hard);
+        return soft;
+    }
+
+    // raising the hard limit itself needs CAP_SYS_RESOURCE
+    if let Err(e) = setrlimit(Resource::RLIMIT_NOFILE, wanted, hard.max(wanted)) {
+        warn!(
+            "cannot raise RLIMIT_NOFILE from {} to {} (hard {}): {}, capacity stays around {} sessions",
+            soft,
+            wanted,
+            hard,
+            e,
+            session_capacity(soft.max(hard))
+        );
+        if wanted <= hard || hard <= soft || setrlimit(Resource::RLIMIT_NOFILE, hard, hard).is_err() {
+            return soft;
+        }
+        info!("RLIMIT_NOFILE raised from {} to the hard limit {}", soft, hard);
+        return hard;
+    }
+    info!("RLIMIT_NOFILE raised from {} to {}", soft, wanted);
+    wanted
+}
+
+/// Sessions that fit into `nofile` descriptors.
+pub fn session_capacity(nofile: u64) -> usize {
+    (nofile.saturating_sub(RESERVED_FDS) / FDS_PER_SESSION).max(1) as usize
+}
@@ -0,0 +1,278 @@
+use std::{
+    env,
+    ffi::c_long,
+    fmt,
+    fs::{self, OpenOptions},
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::fs::OpenOptionsExt,
+    },
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use log::{info, warn};
+use nix::{libc, sys::prctl};
+
+use crate::config::Config;
+
+/// How much of its environment the proxy gives up once started, see
+/// `Config::lockdown`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lockdown {
+    Off,
+    /// drop every capability
+    Caps,
+    /// drop every capability and limit file access to the configured
+    /// paths with Landlock
+    Landlock,
+}
+
+impl FromStr for Lockdown {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Lockdown::Off),
+            "caps" => Ok(Lockdown::Caps),
+            "landlock" => Ok(Lockdown::Landlock),
+            _ => Err(format!("unknown lockdown {:?}, expected off, caps or landlock", s)),
+        }
+    }
+}
+
+impl fmt::Display for Lockdown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Lockdown::Off => "off",
+            Lockdown::Caps => "caps",
+            Lockdown::Landlock => "landlock",
+        })
+    }
+}
+
+/// Read by the resolver (hosts, resolv.conf and what it links to, NSS
+/// modules loaded on first use), the usage sample and the default worker
+/// count; missing ones are skipped.
+const SYSTEM_READ: &[&str] = &[
+    "/etc",
+    "/run/systemd/resolve",
+    "/lib",
+    "/lib64",
+    "/usr/lib",
+    "/usr/lib64",
+    "/proc",
+    "/sys/fs/cgroup",
+];
+
+// LANDLOCK_ACCESS_FS_*; REFER came with ABI 2, TRUNCATE with ABI 3
+const EXECUTE: u64 = 1 << 0;
+const WRITE_FILE: u64 = 1 << 1;
+const READ_FILE: u64 = 1 << 2;
+const READ_DIR: u64 = 1 << 3;
+const REMOVE_FILE: u64 = 1 << 5;
+const MAKE_REG: u64 = 1 << 8;
+const MAKE_SOCK: u64 = 1 << 9;
+const TRUNCATE: u64 = 1 << 14;
+/// Every right of ABI 1.
+const ABI1: u64 = (1 << 13) - 1;
+const REFER: u64 = 1 << 13;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: i32,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+const CAP_VERSION_3: u32 = 0x2008_0522;
+const CAP_SETPCAP: u32 = 8;
+
+/// Applies `Config::lockdown` to the calling thread, and so to every
+/// thread it starts after: run once the listeners are bound and
+/// privileges dropped, before the workers start. Best effort, what the
+/// kernel does not support is logged and skipped.
+pub fn apply(config: &Config, config_file: Option<&Path>) {
+    if config.lockdown == Lockdown::Off {
+        info!("lockdown: off");
+        return;
+    }
+    drop_capabilities();
+    if config.lockdown == Lockdown::Landlock {
+        match landlock(config, config_file) {
+            Ok(abi) => info!("lockdown: landlock ABI {}, files limited to the configured paths", abi),
+            Err(e) if e.raw_os_error() == Some(libc::ENOSYS) || e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                warn!("lockdown: the kernel has no landlock, file access is not restricted")
+            }
+            Err(e) => warn!("lockdown: landlock failed, file access is not restricted: {}", e),
+        }
+    }
+}
+
+/// Clears the ambient, inheritable, permitted and effective sets, and
+/// the bounding set too when CAP_SETPCAP allows it.
+fn drop_capabilities() {
+    let mut header = CapHeader {
+        version: CAP_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    // SAFETY: version 3 takes two data structs
+    if unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) } != 0 {
+        warn!("lockdown: capget failed, capabilities kept: {}", io::Error::last_os_error());
+        return;
+    }
+    let held = |f: fn(&CapData) -> u32| u64::from(f(&data[1])) << 32 | u64::from(f(&data[0]));
+    let (permitted, inheritable) = (held(|d| d.permitted), held(|d| d.inheritable));
+    // SAFETY: plain prctl calls without pointers
+    let ambient = unsafe { libc::prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_CLEAR_ALL, 0, 0, 0) } == 0;
+    let mut bounding = 0;
+    if held(|d| d.effective) & 1 << CAP_SETPCAP != 0 {
+        let last = fs::read_to_string("/proc/sys/kernel/cap_last_cap")
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(40);
+        for cap in 0..=last {
+            // SAFETY: as above
+            if unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap, 0, 0, 0) } == 0 {
+                bounding += 1;
+            }
+        }
+    }
+    if permitted == 0 && inheritable == 0 {
+        info!("lockdown: no capabilities held");
+        return;
+    }
+    let none = [CapData::default(); 2];
+    // SAFETY: as for capget
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header, none.as_ptr()) } != 0 {
+        warn!("lockdown: capset failed, capabilities {:#x} kept: {}", permitted, io::Error::last_os_error());
+        return;
+    }
+    info!(
+        "lockdown: dropped capabilities {:#x}{}{}",
+        permitted,
+        if ambient { ", ambient set cleared" } else { "" },
+        if bounding > 0 { ", bounding set emptied" } else { "" }
+    );
+}
+
+/// Restricts file access to the directories `paths` lists. Returns the ABI used.
+fn landlock(config: &Config, config_file: Option<&Path>) -> io::Result<i64> {
+    let abi = syscall(libc::SYS_landlock_create_ruleset, [0, 0, 1])?;
+    let mut handled = ABI1;
+    if abi >= 2 {
+        handled |= REFER;
+    }
+    if abi >= 3 {
+        handled |= TRUNCATE;
+    }
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+    };
+    let size = std::mem::size_of::<RulesetAttr>();
+    let ruleset = syscall(libc::SYS_landlock_create_ruleset, [&attr as *const _ as c_long, size as c_long, 0])?;
+    // SAFETY: a fresh fd only we hold
+    let ruleset = unsafe { OwnedFd::from_raw_fd(ruleset as i32) };
+    let read = READ_FILE | READ_DIR;
+    let write = read | WRITE_FILE | REMOVE_FILE | MAKE_REG | MAKE_SOCK | (handled & TRUNCATE);
+    let (reads, writes) = paths(config, config_file);
+    // where a SIGUSR2 successor is executed from
+    let exe = env::current_exe().ok().and_then(|p| p.parent().map(Path::to_owned));
+    let rules = reads
+        .iter()
+        .map(|p| (p.as_path(), read))
+        .chain(writes.iter().map(|p| (p.as_path(), write)))
+        .chain(exe.as_deref().map(|p| (p, read | EXECUTE)));
+    for (path, access) in rules {
+        let fd = match OpenOptions::new().read(true).custom_flags(libc::O_PATH).open(path) {
+            Ok(fd) => fd,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(io::Error::new(e.kind(), format!("{}: {}", path.display(), e))),
+        };
+        let beneath = PathBeneathAttr {
+            allowed_access: access & handled,
+            parent_fd: fd.as_raw_fd(),
+        };
+        // LANDLOCK_RULE_PATH_BENEATH
+        syscall(
+            libc::SYS_landlock_add_rule,
+            [ruleset.as_raw_fd() as c_long, 1, &beneath as *const _ as c_long, 0],
+        )
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    }
+    prctl::set_no_new_privs()?;
+    syscall(libc::SYS_landlock_restrict_self, [ruleset.as_raw_fd() as c_long, 0])?;
+    Ok(abi)
+}
+
+/// Directories read in after startup and ones written in. Files are
+/// allowed by their directory, as they get replaced by a rename (an
+/// updated database, an edited config), logs are rotated next to
+/// themselves and state files written through a temporary one.
+fn paths(config: &Config, config_file: Option<&Path>) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let parent = |p: &Path| match p.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_owned(),
+        _ => PathBuf::from("."),
+    };
+    let mut reads: Vec<PathBuf> = SYSTEM_READ.iter().map(PathBuf::from).collect();
+    reads.extend(
+        [config_file, config.auth_file.as_deref(), config.geoip_country_db.as_deref(), config.geoip_asn_db.as_deref()]
+            .into_iter()
+            .flatten()
+            .chain(config.secret_files())
+            .chain(config.listener_tls_cert.values().chain(config.listener_tls_key.values()).map(PathBuf::as_path))
+            .chain(config.routes.iter().filter_map(|r| r.tls_ca.as_deref()))
+            .map(parent),
+    );
+    // THIN_PROXY_<KEY>_FILE values are read again on reload
+    reads.extend(
+        env::vars()
+            .filter(|(name, _)| name.starts_with("THIN_PROXY_") && name.ends_with("_FILE"))
+            .map(|(_, value)| parent(Path::new(&value))),
+    );
+    let mut writes: Vec<PathBuf> = [
+        &config.log_file,
+        &config.access_log,
+        &config.audit_log,
+        &config.pidfile,
+        &config.quota_state,
+        &config.auth_ban_state,
+        &config.listen_unix,
+    ]
+    .into_iter()
+    .flatten()
+    .map(|p| parent(p))
+    .collect();
+    writes.extend(config.capture_dir.clone());
+    (reads, writes)
+}
+
+fn syscall<const N: usize>(nr: c_long, args: [c_long; N]) -> io::Result<i64> {
+    let mut a = [0; 4];
+    a[..N].copy_from_slice(&args);
+    // SAFETY: callers pass the arguments `nr` takes, pointers to live values
+    match unsafe { libc::syscall(nr, a[0], a[1], a[2], a[3]) } {
+        -1 => Err(io::Error::last_os_error()),
+        r => Ok(r),
+    }
+}
@@ -0,0 +1,472 @@
+use std::{
+    fmt::Display,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+use env_logger::Target;
+use log::{
+    kv::{self, Key, Value, VisitSource},
+    info, warn, Level, LevelFilter, Log, Metadata, Record,
+};
+
+use crate::{
+    admin::json_str,
+    config::Config,
+    syslog::{self, Severity, Stream},
+};
+
+/// Bytes of log lines buffered before they go to the file, unless the
+/// stats tick or a warning flushed them earlier.
+const FILE_BUFFER: usize = 64 * 1024;
+
+/// A rotation that failed is tried again after this long, the file
+/// growing past `log_max_size` in between.
+const ROTATE_RETRY: Duration = Duration::from_secs(60);
+
+/// The env_logger doing the work, replaced wholesale on reload. env_logger
+/// fixes its filter when built, so changing the level means a new one.
+static INNER: RwLock<Option<Inner>> = RwLock::new(None);
+
+struct Inner {
+    logger: env_logger::Logger,
+    /// the filter from the config layers, None = RUST_LOG
+    filter: Option<String>,
+    format: LogFormat,
+    /// `Config::log_facility` is set: records passing the filter go to
+    /// syslog, env_logger only filters
+    syslog: bool,
+}
+
+/// `Config::log_file` once opened, None logs to stderr.
+static FILE: Mutex<Option<LogFile>> = Mutex::new(None);
+
+/// Levels set at runtime with `PUT /loglevel`, on top of the filter from
+/// the config layers.
+static OVERRIDE: Mutex<Option<Override>> = Mutex::new(None);
+
+struct Override {
+    /// per target, None for the default level; a later one for the same
+    /// target replaces the earlier
+    levels: Vec<(Option<String>, LevelFilter)>,
+    /// when all of them go again, None = only on reload
+    revert_at: Option<Instant>,
+    /// kept over a reload, dropped by it otherwise
+    persist: bool,
+}
+
+impl Override {
+    fn directives(&self) -> String {
+        self.levels
+            .iter()
+            .map(|(target, level)| match target {
+                Some(t) => format!("{}={}", t, level.as_str().to_ascii_lowercase()),
+                None => level.as_str().to_ascii_lowercase(),
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// The active filter as `GET /loglevel` shows it.
+pub struct Filter {
+    /// the configured directives, then the runtime ones
+    pub directives: String,
+    pub revert_in: Option<Duration>,
+    pub persist: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// env_logger's human readable lines, structured fields appended as
+    /// ` key=value`
+    Text,
+    /// one JSON object per line: ts, level, target, msg, then the record's
+    /// structured fields (session, client, host, bytes_up, bytes_down,
+    /// reason, err_kind, err) under their own keys
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {:?}, expected text or json", s)),
+        }
+    }
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        })
+    }
+}
+
+struct Reloadable;
+
+static LOGGER: Reloadable = Reloadable;
+
+impl Log for Reloadable {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        INNER
+            .read()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|l| l.logger.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        if let Some(l) = INNER.read().unwrap().as_ref() {
+            if !l.syslog {
+                l.logger.log(record);
+            } else if l.logger.matches(record) {
+                to_syslog(record, l.format);
+            }
+        }
+        // buffered lines must not be lost with a crash that follows
+        if record.level() <= Level::Warn {
+            flush();
+        }
+    }
+
+    fn flush(&self) {
+        flush();
+    }
+}
+
+/// A log file rotated by size: `path` -> `path.1` -> ... -> `path.<keep>`.
+struct LogFile {
+    path: PathBuf,
+    out: BufWriter<File>,
+    /// size of the file including what is still buffered
+    written: u64,
+    max_size: Option<u64>,
+    keep: usize,
+    /// set after a failed rotation, none is tried before
+    rotate_at: Option<Instant>,
+}
+
+impl LogFile {
+    fn open(path: &Path, max_size: Option<u64>, keep: usize) -> io::Result<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let written = file.metadata()?.len();
+        Ok(LogFile {
+            path: path.to_owned(),
+            out: BufWriter::with_capacity(FILE_BUFFER, file),
+            written,
+            max_size,
+            keep,
+            rotate_at: None,
+        })
+    }
+
+    /// Starts over on the file at `path`, after logrotate moved it away.
+    fn reopen(&mut self) -> io::Result<()> {
+        self.out.flush()?;
+        *self = LogFile::open(&self.path, self.max_size, self.keep)?;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.out.flush()?;
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                if let Err(e) = fs::rename(numbered(&self.path, n), numbered(&self.path, n + 1)) {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        return Err(e);
+                    }
+                }
+            }
+            fs::rename(&self.path, numbered(&self.path, 1))?;
+        }
+        self.reopen()
+    }
+}
+
+/// `path.<n>`, the `n`th newest rotated log file.
+pub fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut p = path.to_owned().into_os_string();
+    p.push(format!(".{}", n));
+    PathBuf::from(p)
+}
+
+/// What env_logger writes formatted records to when logging to a file.
+struct FileTarget;
+
+impl Write for FileTarget {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut file = FILE.lock().unwrap();
+        let Some(f) = file.as_mut() else {
+            return io::stderr().write(buf);
+        };
+        f.out.write_all(buf)?;
+        f.written += buf.len() as u64;
+        if f.max_size.is_some_and(|max| f.written >= max) && f.rotate_at.is_none_or(|at| Instant::now() >= at) {
+            if let Err(e) = f.rotate() {
+                eprintln!("rotate log {} err {}, trying again in {:?}", f.path.display(), e, ROTATE_RETRY);
+                f.rotate_at = Some(Instant::now() + ROTATE_RETRY);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    /// env_logger flushes after every record; the file is flushed by
+    /// `flush` instead so logging stays a memory copy for the event loops.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Installs the logger writing to stderr. `filter` (`--log-level` and
+/// friends) replaces RUST_LOG when given.
+pub fn init(filter: Option<&str>) {
+    set_filter(filter, LogFormat::Text, false);
+    let _ = log::set_logger(&LOGGER);
+}
+
+/// Switches the logger to what `config` asks for: `log_file` or
+/// `log_facility`, rotation and `log_format`. Runs at startup and again on
+/// reload, after `syslog::configure`; on error the previous destination
+/// stays.
+pub fn configure(filter: Option<&str>, config: &Config) -> io::Result<()> {
+    let file = match &config.log_file {
+        Some(path) => Some(LogFile::open(path, config.log_max_size, config.log_keep)?),
+        None => None,
+    };
+    if let Some(mut old) = std::mem::replace(&mut *FILE.lock().unwrap(), file) {
+        let _ = old.out.flush();
+    }
+    {
+        let mut runtime = OVERRIDE.lock().unwrap();
+        if runtime.as_ref().is_some_and(|o| !o.persist) {
+            // before the switch, so it lands where the debug lines went
+            info!("log level {} set at runtime dropped by the reload", runtime.take().unwrap().directives());
+        }
+    }
+    set_filter(filter, config.log_format, config.log_facility.is_some());
+    Ok(())
+}
+
+/// Sets `target` (a module path, None for every module without a level of
+/// its own) to `level` on top of the configured filter, until a reload
+/// unless `persist`, and until `revert_after` when given, which restarts
+/// for every runtime level already set. `PUT /loglevel`.
+pub fn override_level(target: Option<&str>, level: LevelFilter, revert_after: Option<Duration>, persist: bool) -> Filter {
+    {
+        let mut runtime = OVERRIDE.lock().unwrap();
+        let o = runtime.get_or_insert_with(|| Override {
+            levels: Vec::new(),
+            revert_at: None,
+            persist: false,
+        });
+        o.levels.retain(|(t, _)| t.as_deref() != target);
+        o.levels.push((target.map(str::to_owned), level));
+        o.revert_at = revert_after.map(|d| Instant::now() + d);
+        o.persist |= persist;
+        warn!(
+            "log level {} for {} set at runtime, {}{}",
+            level.as_str().to_ascii_lowercase(),
+            target.unwrap_or("all targets"),
+            match revert_after {
+                Some(d) => format!("reverting in {}", crate::ser::format_duration(d)),
+                None => "until reverted".to_owned(),
+            },
+            if o.persist { ", kept over reloads" } else { ", dropped by a reload" }
+        );
+    }
+    rebuild();
+    filter()
+}
+
+/// Drops the levels set at runtime, back to the configured filter. False
+/// when there were none. `DELETE /loglevel`.
+pub fn clear_override() -> bool {
+    let Some(o) = OVERRIDE.lock().unwrap().take() else {
+        return false;
+    };
+    rebuild();
+    info!("log level {} set at runtime reverted", o.directives());
+    true
+}
+
+/// When the levels set at runtime are due to revert, for the supervisor
+/// to wake up then.
+pub fn revert_at() -> Option<Instant> {
+    OVERRIDE.lock().unwrap().as_ref().and_then(|o| o.revert_at)
+}
+
+/// Reverts the levels set at runtime once their time is up.
+pub fn revert_due(now: Instant) {
+    let due = OVERRIDE
+        .lock()
+        .unwrap()
+        .take_if(|o| o.revert_at.is_some_and(|at| at <= now));
+    if let Some(o) = due {
+        rebuild();
+        info!("log level {} set at runtime expired, back to the configured filter", o.directives());
+    }
+}
+
+pub fn filter() -> Filter {
+    let configured = INNER
+        .read()
+        .unwrap()
+        .as_ref()
+        .and_then(|l| l.filter.clone())
+        .or_else(|| std::env::var(env_logger::DEFAULT_FILTER_ENV).ok())
+        .unwrap_or_else(|| "error".to_owned());
+    let runtime = OVERRIDE.lock().unwrap();
+    Filter {
+        directives: match runtime.as_ref() {
+            Some(o) => format!("{},{}", configured, o.directives()),
+            None => configured,
+        },
+        revert_in: runtime
+            .as_ref()
+            .and_then(|o| o.revert_at)
+            .map(|at| at.saturating_duration_since(Instant::now())),
+        persist: runtime.as_ref().is_some_and(|o| o.persist),
+    }
+}
+
+/// Builds the logger again after the runtime levels changed.
+fn rebuild() {
+    let current = INNER.read().unwrap().as_ref().map(|l| (l.filter.clone(), l.format, l.syslog));
+    if let Some((filter, format, syslog)) = current {
+        set_filter(filter.as_deref(), format, syslog);
+    }
+}
+
+/// Whether `Config::log_file` is in use, which SIGUSR1 then reopens.
+pub fn has_file() -> bool {
+    FILE.lock().unwrap().is_some()
+}
+
+/// Reopens the log file at its configured path (SIGUSR1, for logrotate).
+pub fn reopen() -> io::Result<()> {
+    match FILE.lock().unwrap().as_mut() {
+        Some(f) => f.reopen(),
+        None => Ok(()),
+    }
+}
+
+/// Writes out buffered log lines. Called on the stats tick and after
+/// every warning.
+pub fn flush() {
+    if let Some(f) = FILE.lock().unwrap().as_mut() {
+        let _ = f.out.flush();
+    }
+}
+
+fn set_filter(filter: Option<&str>, format: LogFormat, syslog: bool) {
+    let mut builder = match filter {
+        Some(f) => {
+            let mut b = env_logger::Builder::new();
+            b.parse_filters(f);
+            b
+        }
+        None => env_logger::Builder::from_default_env(),
+    };
+    if let Some(o) = OVERRIDE.lock().unwrap().as_ref() {
+        builder.parse_filters(&o.directives());
+    }
+    if has_file() {
+        builder.target(Target::Pipe(Box::new(FileTarget)));
+    }
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let line = json(record, buf.timestamp_millis())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            writeln!(buf, "{}", line)
+        });
+    }
+    let logger = builder.build();
+    log::set_max_level(logger.filter());
+    *INNER.write().unwrap() = Some(Inner {
+        logger,
+        filter: filter.map(str::to_owned),
+        format,
+        syslog,
+    });
+}
+
+/// `record` as a JSON object: ts, level, target, msg and its fields.
+fn json(record: &Record, ts: impl Display) -> Result<String, kv::Error> {
+    let mut fields = JsonFields(String::new());
+    record.key_values().visit(&mut fields)?;
+    Ok(format!(
+        r#"{{"ts":"{}","level":"{}","target":{},"msg":{}{}}}"#,
+        ts,
+        record.level(),
+        json_str(record.target()),
+        json_str(&record.args().to_string()),
+        fields.0
+    ))
+}
+
+/// Sends `record` to syslog at the level's severity: `target: msg` with the
+/// fields as ` key=value` in text, the JSON object in json.
+fn to_syslog(record: &Record, format: LogFormat) {
+    let severity = match record.level() {
+        Level::Error => Severity::Error,
+        Level::Warn => Severity::Warning,
+        Level::Info => Severity::Info,
+        Level::Debug | Level::Trace => Severity::Debug,
+    };
+    let msg = match format {
+        LogFormat::Text => {
+            let mut fields = TextFields(String::new());
+            let _ = record.key_values().visit(&mut fields);
+            format!("{}: {}{}", record.target(), record.args(), fields.0)
+        }
+        LogFormat::Json => match json(record, humantime::format_rfc3339_millis(SystemTime::now())) {
+            Ok(line) => line,
+            Err(_) => return,
+        },
+    };
+    syslog::send(Stream::Log, severity, &msg);
+}
+
+/// A record's key-value pairs as ` key=value`, like env_logger's lines.
+struct TextFields(String);
+
+impl<'kvs> VisitSource<'kvs> for TextFields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        use std::fmt::Write as _;
+        let _ = write!(self.0, " {}={}", key, value);
+        Ok(())
+    }
+}
+
+/// A record's key-value pairs as `,"key":value` members; numbers and
+/// booleans stay bare, everything else becomes a string.
+struct JsonFields(String);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = match (value.to_u64(), value.to_i64(), value.to_f64(), value.to_bool()) {
+            (Some(n), _, _, _) => n.to_string(),
+            (_, Some(n), _, _) => n.to_string(),
+            (_, _, Some(f), _) if f.is_finite() => f.to_string(),
+            (_, _, _, Some(b)) => b.to_string(),
+            _ => json_str(&value.to_string()),
+        };
+        self.0.push(',');
+        self.0.push_str(&json_str(key.as_str()));
+        self.0.push(':');
+        self.0.push_str(&value);
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+use std::{
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{stall::Activity, stats::WorkerStats};
+
+/// Slots of a `SampleTable`, one per `Activity::code`.
+pub const SLOTS: usize = 16;
+
+/// What the sampled loop iterations of one worker were spent on most:
+/// for each handler the iterations it took the most time in, and that
+/// time summed.
+#[derive(Debug, Default)]
+pub struct SampleTable {
+    iterations: [AtomicU64; SLOTS],
+    total_us: [AtomicU64; SLOTS],
+}
+
+impl SampleTable {
+    fn record(&self, activity: Activity, spent: Duration) {
+        let slot = usize::from(activity.code()) % SLOTS;
+        self.iterations[slot].fetch_add(1, Ordering::Relaxed);
+        self.total_us[slot].fetch_add(spent.as_micros().min(u64::MAX as u128) as u64, Ordering::Relaxed);
+    }
+}
+
+/// Time per handler within one sampled iteration, see `Worker::turn`.
+#[derive(Debug, Default)]
+pub struct Laps {
+    spent: [Duration; SLOTS],
+}
+
+impl Laps {
+    pub fn add(&mut self, activity: Activity, d: Duration) {
+        self.spent[usize::from(activity.code()) % SLOTS] += d;
+    }
+
+    /// Counts the iteration for the handler it spent the most time in,
+    /// none when it did nothing measurable.
+    pub fn finish(self, table: &SampleTable) {
+        let heaviest = (0..SLOTS).max_by_key(|&slot| self.spent[slot]);
+        if let Some(slot) = heaviest.filter(|&slot| !self.spent[slot].is_zero()) {
+            table.record(Activity::from_code(slot as u8), self.spent[slot]);
+        }
+    }
+}
+
+/// The `/loop-samples` body: the workers' tables summed, most time first.
+/// `every` is `Config::loop_sample_every`, null while sampling is off.
+pub fn json(stats: &[Arc<WorkerStats>], every: Option<u64>) -> String {
+    let mut handlers = (0..SLOTS)
+        .map(|slot| {
+            let (iterations, total_us) = stats.iter().fold((0, 0), |(n, us), s| {
+                (
+                    n + s.loop_samples.iterations[slot].load(Ordering::Relaxed),
+                    us + s.loop_samples.total_us[slot].load(Ordering::Relaxed),
+                )
+            });
+            (Activity::from_code(slot as u8), iterations, total_us)
+        })
+        .filter(|&(_, iterations, _)| iterations > 0)
+        .collect::<Vec<_>>();
+    handlers.sort_by(|a, b| b.2.cmp(&a.2).then(b.1.cmp(&a.1)));
+    let mut out = match every {
+        Some(n) => format!(r#"{{"every":{},"handlers":["#, n),
+        None => r#"{"every":null,"handlers":["#.to_owned(),
+    };
+    for (i, (activity, iterations, total_us)) in handlers.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(
+            out,
+            r#"{{"handler":"{}","iterations":{},"total_us":{}}}"#,
+            activity.name(),
+            iterations,
+            total_us
+        );
+    }
+    out.push_str("]}");
+    out
+}
@@ -1,218 +1,924 @@
 use std::{
-    cell::RefCell, error::Error, fs::{self, File}, io::{self, ErrorKind, Write}, os::fd::AsRawFd, rc::Rc, time::Instant
+    collections::VecDeque,
+    error::Error,
+    io,
+    net::SocketAddr,
+    os::fd::{AsRawFd, RawFd},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
-use dns::DNS;
-use log::{debug, error, info};
-use mio::{event::Event, net::TcpListener, Events, Interest, Poll, Registry, Token};
-use session::{Session, SessionRegistry};
-use rand::prelude::*;
+use acceptor::Target;
+use clap::Parser;
+use cli::Cli;
+use client::ClientListener;
+use admin::Admin;
+use command::{Command, CommandSender};
+use config::{AcceptMode, Config, FileConfig};
+use err::Fatal;
+use host_pattern::HostPattern;
+use pidfile::Pidfile;
+use unix_socket::SocketPath;
+use log::{error, info, warn};
+use mio::{
+    net::{TcpListener, UnixListener},
+    Poll,
+};
+use nix::sys::signal::Signal;
+use socket2::{Domain, SockAddr, Socket, Type};
+use stats::{EventKind, Summary, TrafficClass, WorkerStats};
+use statsd::Statsd;
+use worker::{Intake, Worker};
 
+mod accept_rate;
+mod acceptor;
+mod access_log;
+mod acl;
+mod acl_hits;
+mod admin;
+mod affinity;
+mod audit_log;
+mod auth;
+mod bandwidth;
+mod bans;
+mod busy_poll;
+mod capture;
+mod cli;
+mod client;
+mod command;
+mod config;
+mod conn_rate;
+mod daemon;
+mod detect;
 mod dns;
 mod err;
+mod failing_hosts;
+mod geoip;
+#[cfg(feature = "tls")]
+mod h2;
+mod hexdump;
+#[cfg(feature = "tls")]
+mod hpack;
+mod host_check;
+mod host_pattern;
+mod internal_addrs;
+mod limits;
+mod lockdown;
+mod logging;
+mod loop_sampler;
+mod metrics;
+mod mptcp;
+#[cfg(feature = "otlp")]
+mod otlp;
+mod parent;
+mod parent_health;
+mod pidfile;
+mod privileges;
+mod profile;
+mod proxy_protocol;
+mod quota;
+mod schedule;
+mod secret;
+#[cfg(feature = "seccomp")]
+mod seccomp;
+mod ser;
 mod session;
+mod signal;
+mod sni;
+mod socks;
+mod stall;
+mod stats;
+mod statsd;
+mod syslog;
+mod systemd;
+mod timeouts;
+mod timer;
+#[cfg(feature = "tls")]
+mod tls;
+mod token;
+mod top_hosts;
+mod transparent;
+mod udp_relay;
+mod unix_socket;
+mod upgrade;
+mod upstream;
+mod usage;
+mod users;
+mod websocket;
+mod worker;
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let mut f = fs::File::options().truncate(false).create(true).write(true).open("/home/dm/t1")?;
-    f.write_fmt(format_args!("{}","x"))?;
-    env_logger::init();
-    let mut poll = Poll::new()?;
-    let mut events = Events::with_capacity(1024);
-    let listen_token = Token(0);
-    let mut listen_sock = TcpListener::bind("0.0.0.0:7788".parse()?)?;
-    poll.registry()
-        .register(&mut listen_sock, listen_token, Interest::READABLE)?;
-
-    let mut session_registry = SessionRegistry::new();
-    let mut dns_manager = DNS::new();
-    let mut rng = rand::thread_rng();
-    loop {
-        poll.poll(&mut events, None)?;
-        let st = Instant::now();
-        
-        for evt in events.iter().choose_multiple(&mut rng, events.iter().count()) {
-            let st = Instant::now();
-            if let Token(0) = evt.token() {
-                loop {
-                    match accept(&poll.registry(), &mut session_registry, &listen_sock) {
-                        Ok(_) => {},
-                        Err(e) => {
-                            if e.kind() == ErrorKind::WouldBlock {
-                                break;
-                            }
-                        }
-                    }
-                }
-            } else {
-                if evt.is_readable() {
-                    if let Err(e) = handleRead(
-                        poll.registry(),
-                        &mut session_registry,
-                        &mut dns_manager,
-                        evt,
-                    ) {
-                        if e.kind() != ErrorKind::WouldBlock {
-                            error!("handle read error {:?}", e);
-                            closeSession(&poll.registry(), &mut session_registry, evt);
-                        }
-                    }
-                }
+/// How often the supervisor logs the merged worker stats.
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
 
-                if evt.is_writable() {
-                    if let Err(e) = handleWrite(poll.registry(), &mut session_registry, evt) {
-                        if e.kind() != ErrorKind::WouldBlock {
-                            error!("handle write error {:?}", e);
-                            closeSession(&poll.registry(), &mut session_registry, evt);
-                        }
-                    }
-                }
+/// Connections queued on the admin listener; `listen_backlog` is for
+/// clients.
+const ADMIN_BACKLOG: u32 = 1024;
 
-                if evt.is_read_closed() {
-                    closeSession(&poll.registry(), &mut session_registry, evt);
-                }
-                if evt.is_error() {
-                    closeSession(&poll.registry(), &mut session_registry, evt);
-                }
-                if evt.is_write_closed() {
-                    closeSession(&poll.registry(), &mut session_registry, evt);
-                }
-            }
+/// A supervised thread: a worker or the acceptor.
+struct ThreadHandle {
+    name: String,
+    handle: JoinHandle<io::Result<()>>,
+    commands: CommandSender,
+}
 
-            info!("process evt duraion: {:?}", st.elapsed());
+/// What the supervisor waits for between stats ticks.
+enum Notice {
+    /// a supervised thread is gone, also sent when it panicked
+    Exited(String),
+    /// SIGTERM, SIGINT, SIGHUP or SIGUSR2
+    Signal(Signal),
+}
+
+struct ExitNotice(String, Sender<Notice>);
+
+impl Drop for ExitNotice {
+    fn drop(&mut self) {
+        let _ = self.1.send(Notice::Exited(self.0.clone()));
+    }
+}
+
+/// Settings come from the defaults, overridden by `THIN_PROXY_*`
+/// environment variables, overridden by the `--config` file, overridden by
+/// flags.
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    if let Some(user) = &cli.hash_password {
+        return match auth::entry_from_stdin(user) {
+            Ok(entry) => {
+                println!("{}", entry);
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                logging::init(cli.log_level.as_deref());
+                fail(Fatal::Runtime(e))
+            }
+        };
+    }
+    let (config, log_filter) = match load_config(&cli) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            logging::init(cli.log_level.as_deref());
+            return fail(Fatal::Config(e));
         }
+    };
+    logging::init(log_filter.as_deref());
 
-        info!("----  session size {}", session_registry.len());
-        for k in &session_registry {
-            debug!("remaining session key {:?} {}", k.0 .0, k.1.borrow())
+    if let Some(format) = cli.print_config {
+        match config.render(format, log_filter.as_deref()) {
+            Ok(text) => print!("{}", text),
+            Err(e) => return fail(Fatal::Runtime(e)),
         }
-        info!("----  session -----------");
-        info!("---------   per loop duration {:?} \n\n\n", st.elapsed())
     }
-    Ok(())
+    // everything that can be checked is checked before daemonizing, so the
+    // errors still reach the terminal
+    let errors = startup_errors(&config, &cli);
+    if cli.check_config || cli.print_config.is_some() {
+        if errors.is_empty() && cli.print_config.is_none() {
+            println!("config ok");
+        }
+        let mut code = ExitCode::SUCCESS;
+        for e in errors {
+            let e = Fatal::Config(e);
+            eprintln!("{}", e);
+            code = e.exit_code();
+        }
+        return code;
+    }
+    if !errors.is_empty() {
+        return fail(Fatal::Config(errors.join("; ")));
+    }
+    if config.daemon && !upgrade::is_successor() {
+        if let Err(e) = daemon::daemonize(config.log_file.as_deref()) {
+            return fail(e);
+        }
+    }
+    // before the syslog thread, the first one spawned
+    if let Err(e) = signal::block() {
+        return fail(e.into());
+    }
+    if let Err(e) = syslog::configure(&config) {
+        return fail(Fatal::Runtime(format!("cannot start syslog: {}", e)));
+    }
+    if let Err(e) = logging::configure(log_filter.as_deref(), &config) {
+        return fail(Fatal::Config(format!("cannot open log file: {}", e)));
+    }
+    if let Err(e) = access_log::configure(&config) {
+        return fail(Fatal::Config(format!("cannot open access log: {}", e)));
+    }
+    if let Err(e) = audit_log::configure(&config) {
+        return fail(Fatal::Config(format!("cannot open audit log: {}", e)));
+    }
+    internal_addrs::refresh_own();
+    quota::configure(&config);
+    conn_rate::configure(&config);
+    accept_rate::configure(&config);
+    bandwidth::configure(&config);
+    acl_hits::configure(&config);
+    bans::configure(&config);
+    parent_health::configure(&config);
+    if let Some(path) = &config.quota_state {
+        quota::restore(path);
+    }
+    if let Some(path) = &config.auth_ban_state {
+        bans::restore(path);
+    }
+    let code = match run(config, cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => fail(e),
+    };
+    access_log::flush();
+    logging::flush();
+    syslog::flush();
+    code
 }
 
-fn accept(
-    poll: &Registry,
-    session_registry: &mut SessionRegistry,
-    listen_sock: &TcpListener,
-) -> io::Result<()> {
-    match listen_sock.accept() {
-        Ok((sock, addr)) => {
-            let down_sock_id = sock.as_raw_fd();
-            debug!("accpet sock {} fd {}", addr, down_sock_id);
-            let sock_id = (down_sock_id).try_into().unwrap();
-            let session = Rc::new(RefCell::new(Session::new(sock_id, sock)));
-            let r = poll.register(
-                &mut session.borrow_mut().down_sock,
-                Token(sock_id),
-                Interest::READABLE | Interest::WRITABLE,
-            );
+/// Builds the config from every layer, at startup and again on SIGHUP.
+/// Also returns the log filter, which replaces RUST_LOG when set.
+fn load_config(cli: &Cli) -> Result<(Config, Option<String>), String> {
+    let env = FileConfig::from_env()?;
+    let file = cli.config.as_deref().map(FileConfig::load).transpose()?.unwrap_or_default();
+    let log_filter = cli.log_level.clone().or(file.log_level.clone()).or(env.log_level.clone());
+    let mut config = Config::default();
+    env.apply(&mut config);
+    file.apply(&mut config);
+    cli.clone().apply(&mut config);
+    config.load_credentials()?;
+    config.load_secrets()?;
+    config.load_geoip()?;
+    #[cfg(feature = "tls")]
+    config.load_tls()?;
+    Ok((config, log_filter))
+}
 
-            match r {
-                Ok(_) => {
-                    session_registry.insert(Token(sock_id), session);
-                    Ok(())
-                }
-                Err(e) => {
-                    error!("register sock errr {:?}", e);
-                    Err(e)
-                }
+/// `Config::errors` and what else rules out starting with `config`.
+fn startup_errors(config: &Config, cli: &Cli) -> Vec<String> {
+    let mut errors = config.errors();
+    if config.daemon && !upgrade::is_successor() {
+        let relative = daemon::relative_paths([
+            ("--config", cli.config.as_deref()),
+            ("log_file", config.log_file.as_deref()),
+            ("access_log", config.access_log.as_deref()),
+            ("audit_log", config.audit_log.as_deref()),
+            ("auth_file", config.auth_file.as_deref()),
+            ("geoip_country_db", config.geoip_country_db.as_deref()),
+            ("geoip_asn_db", config.geoip_asn_db.as_deref()),
+            ("quota_state", config.quota_state.as_deref()),
+            ("auth_ban_state", config.auth_ban_state.as_deref()),
+            ("capture_dir", config.capture_dir.as_deref()),
+            ("pidfile", config.pidfile.as_deref()),
+            ("listen_unix", config.listen_unix.as_deref()),
+            ("syslog", config.syslog.path()),
+        ]);
+        if !relative.is_empty() {
+            errors.push(format!("{} must be absolute with daemon, which runs in /", relative.join(", ")));
+        }
+    }
+    // rotating renames and creates files next to log_file, which happens
+    // after dropping root
+    if let (Some(user), Some(path), Some(_)) = (&config.user, &config.log_file, config.log_max_size) {
+        if let Ok((uid, gid)) = privileges::resolve(user, config.group.as_deref()) {
+            let dir = path.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            if !privileges::can_write(dir, uid, gid) {
+                errors.push(format!(
+                    "log_max_size rotates log_file in {}, where user {:?} cannot create files",
+                    dir.display(),
+                    user
+                ));
             }
         }
-        Err(e) => Err(e),
     }
+    errors
 }
 
-fn closeSession(poll: &Registry, session_registry: &mut SessionRegistry, evt: &Event) {
-    if let Some(s) = session_registry.remove(&evt.token()) {
-        let sock_id = evt.token().0;
-        debug!("close session {} fd {}", s.borrow(), sock_id);
-        if sock_id == s.borrow().down_sock_id {
-            let s = session_registry.remove(&Token(s.borrow().up_sock_id));
-            s.iter().for_each(|se| {
-                debug!("remove up_sock_fd {}", se.borrow().up_sock_id);
-            });
-        } else {
-            let s = session_registry.remove(&Token(s.borrow().down_sock_id));
-            s.iter().for_each(|se| {
-                debug!("remove down_sock_fd {}", se.borrow().down_sock_id);
-            });
+/// The files the proxy reopens, rotates or empties after dropping root,
+/// which go to `Config::user` before it does.
+fn handed_over(config: &Config) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if let Some(path) = &config.log_file {
+        paths.push(path.clone());
+        if config.log_max_size.is_some() {
+            paths.extend((1..=config.log_keep).map(|n| logging::numbered(path, n)));
         }
+    }
+    paths.extend(config.access_log.iter().cloned());
+    paths.extend(config.audit_log.iter().cloned());
+    paths.extend(config.pidfile.iter().cloned());
+    paths
+}
 
-        let rr = poll.deregister(&mut s.borrow_mut().down_sock);
-        if let Err(e) = rr {
-            error!(
-                "deregister fd {} err {:?}",
-                &mut s.borrow_mut().down_sock.as_raw_fd(),
-                e
-            );
+fn fail(e: Fatal) -> ExitCode {
+    error!("{}", e);
+    e.exit_code()
+}
+
+/// Startup runs in order and reports one line per component: config,
+/// limits, resolver, listeners, admin. Anything that fails there stops the
+/// proxy before a single client is accepted.
+fn run(mut config: Config, cli: Cli) -> Result<(), Fatal> {
+    // before binding, so a second instance is told why it cannot start
+    let _pidfile = config.pidfile.as_deref().map(Pidfile::create).transpose()?;
+    let nofile = limits::raise_nofile(config.nofile);
+    let capacity = limits::session_capacity(nofile);
+    let max_sessions = session_limit(&config, capacity, nofile);
+    info!(
+        "limits: fd limit {}, max sessions {} ({} per worker)",
+        nofile,
+        max_sessions,
+        max_sessions.div_ceil(config.workers)
+    );
+    match dns::probe() {
+        Ok(ip) => info!("resolver: system getaddrinfo with a per-worker cache, localhost is {}", ip),
+        Err(e) => warn!("resolver: system getaddrinfo failed for localhost ({}), upstream names may not resolve", e),
+    }
+    if let (Some(path), Some(credentials)) = (&config.auth_file, &config.credentials) {
+        info!("auth: {} users from {}, realm {:?}", credentials.len(), path.display(), config.auth_realm);
+    }
+    if let Some(geoip) = &config.geoip {
+        info!("geoip: {}", geoip.describe());
+    }
+    unverified_routes(&config);
+    config.max_sessions = Some(max_sessions);
+    let config = Arc::new(config);
+    let (notice_tx, notice_rx) = mpsc::channel();
+
+    // listeners passed down by a parent we are upgrading are used before
+    // binding new ones; their fds are what our own successor inherits
+    let mut inherited: VecDeque<Socket> = upgrade::inherited_listeners().into();
+    let successor = !inherited.is_empty();
+    let mut listen_fds: Vec<RawFd> = Vec::new();
+    // one unix listener shared by every worker, unlike the SO_REUSEPORT
+    // TCP ones; it stays open here for a successor to inherit, and the
+    // guard unlinks its path when we are done
+    let (unix, _unix_path) = match &config.listen_unix {
+        Some(path) => {
+            let backlog = config.backlog_of(&format!("unix:{}", path.display()));
+            let l: std::os::unix::net::UnixListener =
+                match take_inherited(&mut inherited, |a| a.as_pathname() == Some(path)) {
+                    Some(s) => {
+                        s.listen(backlog as i32)
+                            .map_err(|e| Fatal::Config(format!("cannot listen on unix:{}: {}", path.display(), e)))?;
+                        s.into()
+                    }
+                    None => unix_socket::bind(
+                        path,
+                        config.listen_unix_mode,
+                        config.listen_unix_owner.as_deref(),
+                        backlog,
+                    )?,
+                };
+            listen_fds.push(l.as_raw_fd());
+            info!("unix listener: {}", path.display());
+            (Some(l), Some(SocketPath::new(path)?))
+        }
+        None => (None, None),
+    };
+    let unix_listener = || -> Result<Option<ClientListener>, Fatal> {
+        match &unix {
+            Some(l) => Ok(Some(ClientListener::Unix(UnixListener::from_std(l.try_clone()?)))),
+            None => Ok(None),
         }
-        s.borrow_mut().up_sock.iter_mut().for_each(|s| {
-            let rr = poll.deregister(s);
-            if let Err(e) = rr {
-                error!("deregister fd {} err {:?}", s.as_raw_fd(), e);
+    };
+    // an inherited listener takes the backlog anew, it is no part of
+    // what makes it the same one; it keeps IP_TRANSPARENT
+    let mut listener = |addr: SocketAddr, reuse_port: bool, backlog: u32| -> Result<TcpListener, Fatal> {
+        let l = match take_inherited(&mut inherited, |a| a.as_socket() == Some(addr)) {
+            Some(s) => {
+                s.listen(backlog as i32).map_err(|e| Fatal::bind(addr, e))?;
+                TcpListener::from_std(s.into())
+            }
+            None => {
+                let tproxy = config.listener_tproxy.contains(&addr.to_string());
+                bind_listener(addr, reuse_port, config.ipv6_only, backlog, tproxy).map_err(|e| Fatal::bind(addr, e))?
+            }
+        };
+        listen_fds.push(l.as_raw_fd());
+        Ok(l)
+    };
+
+    // all channels exist before the first worker starts, the admin
+    // endpoint needs every worker's sender
+    let mut loops = Vec::with_capacity(config.workers);
+    for _ in 0..config.workers {
+        let poll = Poll::new()?;
+        let (commands, rx) = command::channel(&poll)?;
+        loops.push((poll, commands, rx));
+    }
+    let workers: Vec<CommandSender> = loops.iter().map(|l| l.1.clone()).collect();
+    let stats: Vec<Arc<WorkerStats>> = (0..config.workers)
+        .map(|_| Arc::new(WorkerStats::new(config.listener_names().len())))
+        .collect();
+    let mut admin = match config.admin_listen {
+        Some(addr) => {
+            let admin = Admin::new(
+                listener(addr, false, ADMIN_BACKLOG)?,
+                0,
+                workers.clone(),
+                stats.clone(),
+                nofile,
+                &config,
+            );
+            if !addr.ip().is_loopback() {
+                warn!("admin: {} is not a loopback address and has no authentication", addr);
             }
+            info!("admin: http://{}", addr);
+            Some(admin)
+        }
+        None => {
+            info!("admin: disabled");
+            None
+        }
+    };
+    match (&config.statsd, config.metrics.statsd()) {
+        (Some(target), true) => info!("metrics: {}, statsd to {} every {:?}", config.metrics, target, STATS_INTERVAL),
+        _ => info!("metrics: {}", config.metrics),
+    }
+
+    // in `Config::listener_names` order, the token and counter index
+    let mut client_listeners = |reuse_port: bool| -> Result<Vec<ClientListener>, Fatal> {
+        let mut all = config
+            .listen
+            .iter()
+            .map(|&addr| listener(addr, reuse_port, config.backlog_of(&addr.to_string())).map(ClientListener::Tcp))
+            .collect::<Result<Vec<_>, Fatal>>()?;
+        all.extend(unix_listener()?);
+        Ok(all)
+    };
+
+    // every socket is bound before the first thread starts, so privileges
+    // are dropped with nothing accepting yet and apply to all threads
+    let mut intakes = Vec::with_capacity(config.workers);
+    for _ in 0..config.workers {
+        intakes.push(match config.accept_mode {
+            AcceptMode::ReusePort => Intake::Listener(client_listeners(config.workers > 1)?),
+            AcceptMode::Acceptor => Intake::Handoff,
         });
     }
+    let acceptor_socks = match config.accept_mode {
+        AcceptMode::Acceptor => client_listeners(false)?,
+        AcceptMode::ReusePort => Vec::new(),
+    };
+    for l in inherited {
+        warn!("closing surplus inherited listener fd {}", l.as_raw_fd());
+    }
+    if let Some(user) = &config.user {
+        privileges::hand_over(&handed_over(&config), user, config.group.as_deref())?;
+        privileges::drop_to(user, config.group.as_deref())?;
+    }
+    lockdown::apply(&config, cli.config.as_deref());
+    // the workers are watchdog slots 0.., the acceptor comes last
+    let event_loops = config.workers + usize::from(config.accept_mode == AcceptMode::Acceptor);
+    systemd::init(event_loops, successor);
+    #[cfg(feature = "otlp")]
+    otlp::configure(&config).map_err(|e| Fatal::Runtime(format!("cannot start otlp export: {}", e)))?;
+
+    let mut threads = Vec::with_capacity(config.workers + 1);
+    for (id, ((poll, commands, rx), intake)) in loops.into_iter().zip(intakes).enumerate() {
+        let worker_stats = Arc::clone(&stats[id]);
+        let worker_config = Arc::clone(&config);
+        let worker_admin = admin.take();
+        let name = format!("worker-{}", id);
+        let core = affinity::core_for(&config.worker_affinity, id);
+        threads.push(spawn(name, commands, &notice_tx, core, move || {
+            Worker::new(id, poll, intake, worker_stats, rx, worker_config, worker_admin)?.run()
+        })?);
+    }
+
+    if config.accept_mode == AcceptMode::Acceptor {
+        let targets = workers
+            .iter()
+            .zip(&stats)
+            .map(|(commands, stats)| Target {
+                commands: commands.clone(),
+                stats: Arc::clone(stats),
+            })
+            .collect();
+        let poll = Poll::new()?;
+        let (commands, rx) = command::channel(&poll)?;
+        let core = config.acceptor_core;
+        let watchdog = systemd::watchdog_slot(config.workers);
+        threads.push(spawn("acceptor".to_owned(), commands, &notice_tx, core, move || {
+            acceptor::run(poll, acceptor_socks, targets, rx, watchdog)
+        })?);
+    }
+
+    let signal_tx = notice_tx.clone();
+    signal::spawn(workers, move |sig| {
+        let _ = signal_tx.send(Notice::Signal(sig));
+    })?;
+    parent_health::spawn_prober()?;
+    // last, every thread is running and nothing is left to bind or drop
+    #[cfg(feature = "seccomp")]
+    if config.seccomp {
+        seccomp::install(&config)?;
+    }
+    info!(
+        "listen: {:?} with {} workers ({:?}, poll {:?}, SO_BUSY_POLL {:?})",
+        config.listener_names(), config.workers, config.accept_mode, config.poll_mode, config.so_busy_poll
+    );
+    systemd::ready();
+
+    // a reload only swaps what is safe with sockets and threads in place
+    let reload = move |running: &Config| -> Result<Config, String> {
+        let (mut config, log_filter) = load_config(&cli)?;
+        config.validate()?;
+        let fixed = config.restart_only_changes(running);
+        if !fixed.is_empty() {
+            return Err(format!("{} cannot change without a restart", fixed.join(", ")));
+        }
+        config.max_sessions = Some(session_limit(&config, capacity, nofile));
+        // logged before the switch, so it lands where the previous lines went
+        info!(
+            "config reloaded: idle timeout {:?} ({} overrides), {} profiles, max sessions {:?}, dns cache {}, log file {:?}",
+            config.timeouts.idle, config.timeouts.overrides.len(), config.profiles.len(), config.max_sessions, config.dns_cache, config.log_file
+        );
+        if let (Some(path), Some(credentials)) = (&config.auth_file, &config.credentials) {
+            info!("auth: {} users from {}", credentials.len(), path.display());
+        }
+        if let Some(geoip) = &config.geoip {
+            info!("geoip: {}", geoip.describe());
+        }
+        unverified_routes(&config);
+        access_log::configure(&config).map_err(|e| format!("cannot open access log: {}", e))?;
+        audit_log::configure(&config).map_err(|e| format!("cannot open audit log: {}", e))?;
+        #[cfg(feature = "otlp")]
+        otlp::configure(&config).map_err(|e| format!("cannot start otlp export: {}", e))?;
+        syslog::configure(&config).map_err(|e| format!("cannot start syslog: {}", e))?;
+        logging::configure(log_filter.as_deref(), &config)
+            .map_err(|e| format!("cannot open log file: {}", e))?;
+        // interfaces may have come and gone since
+        internal_addrs::refresh_own();
+        quota::configure(&config);
+        conn_rate::configure(&config);
+        accept_rate::configure(&config);
+        bandwidth::configure(&config);
+        acl_hits::configure(&config);
+        bans::configure(&config);
+        parent_health::configure(&config);
+        Ok(config)
+    };
+    Ok(supervise(threads, &stats, nofile, notice_rx, &listen_fds, config, reload)?)
 }
 
-fn handleWrite(
-    registry: &Registry,
-    session_registry: &mut SessionRegistry,
-    evt: &Event,
-) -> io::Result<()> {
-    if let Some(sess) = session_registry.get(&evt.token()) {
-        return sess.borrow_mut().handle_write(evt);
+/// Warns of every `wrap_tls` route that takes any certificate its
+/// destinations present, at startup and on each reload.
+fn unverified_routes(config: &Config) {
+    for route in config.routes.iter().filter(|r| r.wrap_tls && !r.tls_verify) {
+        let hosts = route.hosts.iter().map(HostPattern::to_string).collect::<Vec<_>>().join(", ");
+        warn!("route for {}: tls_verify = false, its destinations' certificates are not checked", hosts);
     }
+}
 
-    Ok(())
+/// `config.max_sessions`, or what the fd limit allows when unset.
+fn session_limit(config: &Config, capacity: usize, nofile: u64) -> usize {
+    match config.max_sessions {
+        Some(max) if max > capacity => {
+            warn!(
+                "max sessions {} exceeds the {} the fd limit {} allows, expect EMFILE",
+                max, capacity, nofile
+            );
+            max
+        }
+        Some(max) => max,
+        None => capacity,
+    }
 }
 
-fn handleRead(
-    poll: &Registry,
-    sessionRegistry: &mut SessionRegistry,
-    dns: &mut DNS,
-    t: &Event,
-) -> io::Result<()> {
-    let mut sessionOpt = sessionRegistry.get(&t.token());
-    if sessionOpt.is_none() {
-        return Ok(());
+fn spawn(
+    name: String,
+    commands: CommandSender,
+    notice_tx: &Sender<Notice>,
+    core: Option<usize>,
+    f: impl FnOnce() -> io::Result<()> + Send + 'static,
+) -> io::Result<ThreadHandle> {
+    let notice = ExitNotice(name.clone(), notice_tx.clone());
+    let thread_name = name.clone();
+    let handle = thread::Builder::new().name(name.clone()).spawn(move || {
+        let _notice = notice;
+        if let Some(core) = core {
+            affinity::pin_current(&thread_name, core);
+        }
+        f()
+    })?;
+    Ok(ThreadHandle {
+        name,
+        handle,
+        commands,
+    })
+}
+
+/// Removes the first inherited listener whose local address `matches`.
+fn take_inherited(inherited: &mut VecDeque<Socket>, matches: impl Fn(&SockAddr) -> bool) -> Option<Socket> {
+    let i = inherited
+        .iter()
+        .position(|s| s.local_addr().is_ok_and(|a| matches(&a)))?;
+    inherited.remove(i)
+}
+
+/// Binds a client or admin listener; `tproxy` sets IP_TRANSPARENT so it
+/// takes the connections a TPROXY rule sends it, to any address.
+fn bind_listener(
+    addr: SocketAddr,
+    reuse_port: bool,
+    ipv6_only: bool,
+    backlog: u32,
+    tproxy: bool,
+) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        socket.set_reuse_port(true)?;
     }
+    if tproxy {
+        transparent::set_ip_transparent(&socket, addr.is_ipv6())?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(backlog as i32)?;
+    socket.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(socket.into()))
+}
 
-    let session = sessionOpt.unwrap();
-    debug!(
-        "readable event fd {} session {}",
-        t.token().0,
-        session.borrow()
-    );
-    let state = session.borrow().state;
-    let host = session.borrow().host.clone();
-    match state {
-        session::State::Head => {
-            let x = session.borrow_mut().connect(poll, dns);
-            if x.is_ok() {
-                let fd = x.unwrap();
-                sessionRegistry.insert(Token(fd.try_into().unwrap()), Rc::clone(session));
-                return Ok(());
-            } else {
-                let e = x.unwrap_err();
-                if e.kind() == ErrorKind::WouldBlock {
-                    return Ok(());
+/// Logs merged stats, and sends them to statsd when configured, until a
+/// signal asks to stop, then shuts the threads down. SIGTERM lets the
+/// threads drain their sessions first, with the listeners closed and
+/// `/readyz` failing, and a second one (or SIGINT) stops right away.
+/// SIGUSR2 hands the listeners to a successor process and drains too; so
+/// does a thread exiting on its own, which then fails the process once the
+/// others drained. SIGHUP hands the threads the config
+/// `reload` builds, or keeps the running one when it is refused.
+fn supervise(
+    threads: Vec<ThreadHandle>,
+    stats: &[Arc<WorkerStats>],
+    nofile: u64,
+    notice_rx: mpsc::Receiver<Notice>,
+    listen_fds: &[RawFd],
+    mut config: Arc<Config>,
+    reload: impl Fn(&Config) -> Result<Config, String>,
+) -> Result<(), Box<dyn Error>> {
+    let mut last = Summary::default();
+    let mut last_at = Instant::now();
+    let mut statsd = Statsd::new(&config);
+    let mut alerts = failing_hosts::Alerts::default();
+    let mut stalls = stall::StallCheck::default();
+    let mut running = threads.len();
+    // set on SIGTERM, once a successor took over the listeners or a thread
+    // failed
+    let mut drain_deadline: Option<Instant> = None;
+    let mut failed: Option<String> = None;
+    let mut result = loop {
+        // woken every heartbeat to look for stalled loops, sooner for the
+        // drain deadline and levels set at runtime that are due to revert
+        let wait = [drain_deadline, logging::revert_at()]
+            .into_iter()
+            .flatten()
+            .fold(worker::HEARTBEAT, |wait, d| d.saturating_duration_since(Instant::now()).min(wait));
+        match notice_rx.recv_timeout(wait) {
+            Ok(Notice::Exited(name)) => {
+                running -= 1;
+                if drain_deadline.is_some() {
+                    info!("{} drained", name);
+                } else {
+                    error!("{} exited, draining the others", name);
+                    failed = Some(format!("{} exited", name));
+                    systemd::stopping();
+                    drain(&threads, false);
+                    drain_deadline = Some(Instant::now() + config.drain_timeout);
+                }
+                if running == 0 {
+                    break Ok(());
                 }
-                error!("connect error {:?}", e);
-                return Err(e);
             }
-        }
-        session::State::Piping => {
-            debug!("piping..");
-            if let Err(e) = session.borrow_mut().pipe(t.token().0) {
-                if e.kind() == ErrorKind::WouldBlock {
-                    return Ok(());
+            Ok(Notice::Signal(Signal::SIGUSR2)) => {
+                if drain_deadline.is_some() {
+                    warn!("already draining, ignoring SIGUSR2");
+                    continue;
+                }
+                if config.seccomp {
+                    warn!("seccomp leaves no way to start a successor, ignoring SIGUSR2; restart instead");
+                    continue;
+                }
+                match upgrade::spawn_successor(listen_fds) {
+                    Ok(child) => {
+                        info!(
+                            "successor pid {} took over, draining for up to {:?}",
+                            child.id(),
+                            config.drain_timeout
+                        );
+                        drain(&threads, true);
+                        drain_deadline = Some(Instant::now() + config.drain_timeout);
+                    }
+                    Err(e) => error!("upgrade failed, keep serving: {:?}", e),
                 }
-                error!("piping {} error {:?}", host, e);
-                return Err(e);
             }
-            Ok(())
+            Ok(Notice::Signal(Signal::SIGHUP)) => {
+                systemd::reloading();
+                match reload(&config) {
+                    Ok(new) => {
+                        config = Arc::new(new);
+                        statsd = Statsd::new(&config);
+                        for t in &threads {
+                            if t.handle.is_finished() {
+                                continue;
+                            }
+                            if let Err(e) = t.commands.send(Command::Reload(Arc::clone(&config))) {
+                                error!("reload {} err {:?}", t.name, e);
+                            }
+                        }
+                    }
+                    Err(e) => error!("reload refused, keep running settings: {}", e),
+                }
+                systemd::ready();
+            }
+            Ok(Notice::Signal(Signal::SIGTERM)) if drain_deadline.is_none() => {
+                info!("SIGTERM, draining for up to {:?}, again to stop now", config.drain_timeout);
+                systemd::stopping();
+                drain(&threads, false);
+                drain_deadline = Some(Instant::now() + config.drain_timeout);
+            }
+            Ok(Notice::Signal(sig)) => {
+                info!("{:?}, stopping", sig);
+                systemd::stopping();
+                break Ok(());
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if drain_deadline.is_some_and(|d| Instant::now() >= d) {
+                    warn!("drain timeout, closing the remaining sessions");
+                    break Ok(());
+                }
+                logging::revert_due(Instant::now());
+                stalls.check(stats, config.loop_stall, config.loop_stall_action);
+                if last_at.elapsed() < STATS_INTERVAL {
+                    continue;
+                }
+                let summary = Summary::merge(stats.iter().map(|s| s.as_ref()));
+                let secs = last_at.elapsed().as_secs_f64();
+                let polls = summary.polls - last.polls;
+                let events = summary.events - last.events;
+                info!(
+                    "workers {} active sessions {} opened {} closed {} polls/s {:.1} events/wake {:.1} full polls {} accepted {:?} accept cap hits {}",
+                    stats.len(),
+                    summary.active_sessions,
+                    summary.sessions_opened,
+                    summary.sessions_closed,
+                    polls as f64 / secs,
+                    if polls == 0 { 0.0 } else { events as f64 / polls as f64 },
+                    summary.full_polls - last.full_polls,
+                    summary.listeners.iter().map(|l| l.accepted).collect::<Vec<_>>(),
+                    summary.accept_cap_hits - last.accept_cap_hits
+                );
+                let shed = summary.accepts_shed - last.accepts_shed;
+                let pauses = summary.accept_pauses - last.accept_pauses;
+                if shed > 0 || pauses > 0 {
+                    info!("accept_rate: {} connections shed, listeners paused {} times", shed, pauses);
+                }
+                let listeners = config.listener_labels();
+                if listeners.len() > 1 {
+                    let per_listener = listeners
+                        .iter()
+                        .zip(&summary.listeners)
+                        .map(|((name, profile), l)| {
+                            format!(
+                                "{} ({}) accepted {} active {} bytes up/down {}/{} denied {}",
+                                name, profile, l.accepted, l.active_sessions, l.bytes_up, l.bytes_down, l.denied
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    info!("listeners {}", per_listener);
+                }
+                let traffic = TrafficClass::ALL
+                    .iter()
+                    .map(|&c| {
+                        let i = c as usize;
+                        format!(
+                            "{} {}/{}/{}",
+                            c.name(),
+                            summary.class_sessions[i] - last.class_sessions[i],
+                            summary.class_bytes_up[i] - last.class_bytes_up[i],
+                            summary.class_bytes_down[i] - last.class_bytes_down[i]
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                info!("traffic (sessions/bytes up/bytes down) {}", traffic);
+                let loops = summary.loop_latency.since(&last.loop_latency);
+                let per_kind = EventKind::ALL
+                    .iter()
+                    .zip(summary.event_latency.iter().zip(&last.event_latency))
+                    .map(|(kind, (now, then))| {
+                        let d = now.since(then);
+                        format!("{} {}/{:?}", kind.name(), d.count(), d.quantile(0.99))
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let longest = stats.iter().map(|s| s.take_longest_event()).max().unwrap_or_default();
+                info!(
+                    "loop p50 {:?} p99 {:?} events (count/p99) {} longest event {:?} max tick gap {:?}",
+                    loops.quantile(0.5),
+                    loops.quantile(0.99),
+                    per_kind,
+                    longest,
+                    summary.max_tick_gap
+                );
+                let usage = usage::sample(&summary);
+                let (now, peak) = (usage.now, usage.peak);
+                info!(
+                    "rss {} fds {} pipes {} pending {}B, peaks rss {} fds {} pipes {} pending {}B",
+                    usage::mib(now.rss_bytes),
+                    now.fds,
+                    now.pipes,
+                    now.pending_bytes,
+                    usage::mib(peak.rss_bytes),
+                    peak.fds,
+                    peak.pipes,
+                    peak.pending_bytes
+                );
+                if let Some(statsd) = &mut statsd {
+                    statsd.emit(&summary, &last, &usage, nofile, &listeners);
+                }
+                alerts.check(
+                    stats,
+                    failing_hosts::minute(),
+                    config.failing_host_min_failures,
+                    config.failing_host_ratio,
+                );
+                let (top, _) = top_hosts::top(stats, 5);
+                if !top.is_empty() {
+                    info!("top hosts (bytes up/down sessions/failures) {}", top_hosts::summary(&top));
+                }
+                last = summary;
+                last_at = Instant::now();
+                save_state(&config);
+                access_log::flush();
+                logging::flush();
+            }
+            Err(RecvTimeoutError::Disconnected) => unreachable!("supervisor holds a sender"),
+        }
+    };
+
+    for t in &threads {
+        if t.handle.is_finished() {
+            continue;
+        }
+        match t.commands.send(Command::Shutdown) {
+            // it exited since the check above
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+            Err(e) => error!("shutdown {} err {:?}", t.name, e),
+            Ok(()) => {}
+        }
+    }
+    for t in threads {
+        match t.handle.join() {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                error!("{} failed {:?}", t.name, e);
+                result = Err(e.into());
+            }
+            Err(_) => {
+                error!("{} panicked", t.name);
+                result = Err(format!("{} panicked", t.name).into());
+            }
+        }
+    }
+    info!("all threads stopped");
+    // the sessions closed last charged their users on the way out
+    save_state(&config);
+    if let Some(reason) = failed {
+        result = result.and(Err(reason.into()));
+    }
+    result
+}
+
+/// Writes the quota usage and the running bans, where the config keeps
+/// them over restarts.
+fn save_state(config: &Config) {
+    if let Some(path) = &config.quota_state {
+        if let Err(e) = quota::save(path) {
+            error!("cannot save quota state {}: {}", path.display(), e);
+        }
+    }
+    if let Some(path) = &config.auth_ban_state {
+        if let Err(e) = bans::save(path) {
+            error!("cannot save ban state {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn drain(threads: &[ThreadHandle], handoff: bool) {
+    for t in threads {
+        if t.handle.is_finished() {
+            continue;
+        }
+        if let Err(e) = t.commands.send(Command::Drain { handoff }) {
+            error!("drain {} err {:?}", t.name, e);
         }
     }
 }
@@ -0,0 +1,369 @@
+use std::{fmt::Display, fmt::Write as _, str::FromStr};
+
+use crate::{
+    err::ErrorCategory,
+    limits::{FDS_PER_SESSION, RESERVED_FDS},
+    session::CloseReason,
+    stats::{Buckets, ConnectFailure, ListenerTotals, Phase, Summary, TrafficClass, BUCKETS},
+    usage::Usage,
+};
+
+/// `Config::metrics`: where the counters are exported to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Export {
+    None,
+    /// `/metrics` on the admin listener
+    Prometheus,
+    /// pushed to `Config::statsd` on every stats tick
+    Statsd,
+    Both,
+}
+
+impl Export {
+    pub fn prometheus(self) -> bool {
+        matches!(self, Export::Prometheus | Export::Both)
+    }
+
+    pub fn statsd(self) -> bool {
+        matches!(self, Export::Statsd | Export::Both)
+    }
+}
+
+impl FromStr for Export {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Export::None),
+            "prometheus" => Ok(Export::Prometheus),
+            "statsd" => Ok(Export::Statsd),
+            "both" => Ok(Export::Both),
+            _ => Err(format!("unknown metrics export {:?}, expected prometheus, statsd, both or none", s)),
+        }
+    }
+}
+
+impl Display for Export {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Export::None => "none",
+            Export::Prometheus => "prometheus",
+            Export::Statsd => "statsd",
+            Export::Both => "both",
+        })
+    }
+}
+
+/// Listeners that get series of their own, by `listener` and `profile`
+/// label; the counts of any past them go to one `other` series, so a long
+/// `listen` list cannot blow up the number of series.
+pub const MAX_LISTENER_SERIES: usize = 32;
+
+/// Label names and values of one sample.
+pub type Labels = Vec<(&'static str, String)>;
+
+/// One metric and its values, by label where it has one.
+pub struct Family<T> {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub samples: Vec<(Labels, T)>,
+}
+
+fn single<T>(name: &'static str, help: &'static str, value: T) -> Family<T> {
+    Family {
+        name,
+        help,
+        samples: vec![(Vec::new(), value)],
+    }
+}
+
+fn label(key: &'static str, value: &str) -> Labels {
+    vec![(key, value.to_owned())]
+}
+
+/// A family with one sample per listener, `value` taken from its totals;
+/// `listeners` are the name and profile of each, see
+/// `Config::listener_labels`.
+fn per_listener<T: Default + std::ops::AddAssign>(
+    name: &'static str,
+    help: &'static str,
+    s: &Summary,
+    listeners: &[(String, String)],
+    value: impl Fn(&ListenerTotals) -> T,
+) -> Family<T> {
+    let mut samples = Vec::new();
+    let mut other = None;
+    for (i, totals) in s.listeners.iter().enumerate() {
+        match listeners.get(i) {
+            Some((listener, profile)) if i < MAX_LISTENER_SERIES => {
+                samples.push((vec![("listener", listener.clone()), ("profile", profile.clone())], value(totals)))
+            }
+            _ => *other.get_or_insert_with(T::default) += value(totals),
+        }
+    }
+    if let Some(v) = other {
+        samples.push((vec![("listener", "other".to_owned()), ("profile", "other".to_owned())], v));
+    }
+    Family { name, help, samples }
+}
+
+/// The monotonic counters, the same for every export.
+pub fn counters(s: &Summary, listeners: &[(String, String)]) -> Vec<Family<u64>> {
+    vec![
+        single("sessions_opened_total", "Sessions accepted and started.", s.sessions_opened),
+        Family {
+            name: "sessions_closed_total",
+            help: "Sessions closed, by reason; max-sessions counts clients turned away at accept.",
+            samples: CloseReason::ALL
+                .iter()
+                .map(|&r| (label("reason", r.name()), s.closed_by[r as usize]))
+                .collect(),
+        },
+        single(
+            "clients_refused_total",
+            "Clients turned away at accept by allow_clients or deny_clients.",
+            s.clients_refused,
+        ),
+        single(
+            "conn_rate_limited_total",
+            "Connections closed at accept, their source went over conn_rate.",
+            s.conn_rate_limited,
+        ),
+        single(
+            "accepts_shed_total",
+            "Connections closed at accept, accept_rate was used up and accept_rate_shed set.",
+            s.accepts_shed,
+        ),
+        single(
+            "accept_pauses_total",
+            "Times a listener was left alone for accept_rate to refill.",
+            s.accept_pauses,
+        ),
+        single(
+            "banned_dropped_total",
+            "Connections dropped at accept, their source is banned for failed logins.",
+            s.banned_dropped,
+        ),
+        single(
+            "sni_mismatches_total",
+            "Tunnels whose ClientHello named another host than their CONNECT.",
+            s.sni_mismatches,
+        ),
+        single("bytes_up_total", "Payload bytes copied client to upstream.", s.bytes_up),
+        single("bytes_down_total", "Payload bytes copied upstream to client.", s.bytes_down),
+        Family {
+            name: "dns_lookups_total",
+            help: "Upstream name lookups, by result.",
+            samples: vec![
+                (label("result", "hit"), s.dns_hits),
+                (label("result", "resolved"), s.dns_misses.saturating_sub(s.dns_failures)),
+                (label("result", "failed"), s.dns_failures),
+            ],
+        },
+        Family {
+            name: "connect_failures_total",
+            help: "Upstreams that could not be reached, by kind.",
+            samples: ConnectFailure::ALL
+                .iter()
+                .map(|&k| (label("kind", k.name()), s.connect_failures[k as usize]))
+                .collect(),
+        },
+        Family {
+            name: "upstream_mptcp_total",
+            help: "Upstream connects tried over MPTCP, by whether it was negotiated or fell back to TCP.",
+            samples: vec![
+                (label("result", "negotiated"), s.mptcp_negotiated),
+                (label("result", "fallback"), s.mptcp_fallbacks),
+            ],
+        },
+        Family {
+            name: "session_errors_total",
+            help: "Sessions closed on an error, by category.",
+            samples: ErrorCategory::ALL
+                .iter()
+                .map(|&c| (label("category", c.name()), s.errors[c as usize]))
+                .collect(),
+        },
+        Family {
+            name: "traffic_sessions_total",
+            help: "Established sessions, by TLS or other tunnel or forwarded HTTP.",
+            samples: TrafficClass::ALL
+                .iter()
+                .map(|&c| (label("class", c.name()), s.class_sessions[c as usize]))
+                .collect(),
+        },
+        Family {
+            name: "traffic_bytes_up_total",
+            help: "Payload bytes copied client to upstream, by traffic class.",
+            samples: TrafficClass::ALL
+                .iter()
+                .map(|&c| (label("class", c.name()), s.class_bytes_up[c as usize]))
+                .collect(),
+        },
+        Family {
+            name: "traffic_bytes_down_total",
+            help: "Payload bytes copied upstream to client, by traffic class.",
+            samples: TrafficClass::ALL
+                .iter()
+                .map(|&c| (label("class", c.name()), s.class_bytes_down[c as usize]))
+                .collect(),
+        },
+        per_listener("listener_accepted_total", "Clients accepted, by listener.", s, listeners, |l| l.accepted),
+        per_listener(
+            "listener_bytes_up_total",
+            "Payload bytes copied client to upstream, by listener.",
+            s,
+            listeners,
+            |l| l.bytes_up,
+        ),
+        per_listener(
+            "listener_bytes_down_total",
+            "Payload bytes copied upstream to client, by listener.",
+            s,
+            listeners,
+            |l| l.bytes_down,
+        ),
+        per_listener(
+            "listener_denied_total",
+            "Clients refused at max sessions, for a bad request or by the acl, by listener.",
+            s,
+            listeners,
+            |l| l.denied,
+        ),
+    ]
+}
+
+/// The gauges, `nofile` being the fd limit in effect.
+pub fn gauges(s: &Summary, usage: &Usage, nofile: u64, listeners: &[(String, String)]) -> Vec<Family<f64>> {
+    let (now, peak) = (usage.now, usage.peak);
+    let used = s.active_sessions as u64 * FDS_PER_SESSION + RESERVED_FDS;
+    vec![
+        single("active_sessions", "Sessions open now.", s.active_sessions as f64),
+        single(
+            "active_head_sessions",
+            "Open sessions still reading their request head.",
+            s.head_sessions as f64,
+        ),
+        single(
+            "fd_budget_used",
+            "Share of the fd limit taken, estimated from open sessions and the reserve.",
+            used as f64 / nofile.max(1) as f64,
+        ),
+        single("resident_memory_bytes", "Resident set size of the process.", now.rss_bytes as f64),
+        single(
+            "resident_memory_peak_bytes",
+            "Highest resident set size since startup.",
+            peak.rss_bytes as f64,
+        ),
+        single("open_fds", "File descriptors open in the process.", now.fds as f64),
+        single("open_fds_peak", "Most file descriptors open at a sample since startup.", peak.fds as f64),
+        single("splice_pipes", "Splice pipes held by sessions, two fds each.", now.pipes as f64),
+        single("splice_pipes_peak", "Most splice pipes at a sample since startup.", peak.pipes as f64),
+        single(
+            "pending_bytes",
+            "Bytes read but not yet written on, in pipes and capture buffers.",
+            now.pending_bytes as f64,
+        ),
+        single(
+            "pending_peak_bytes",
+            "Most pending bytes at a sample since startup.",
+            peak.pending_bytes as f64,
+        ),
+        single(
+            "loop_tick_gap_max_seconds",
+            "Longest time between two heartbeats of a worker loop since startup, 1s when it turns freely.",
+            s.max_tick_gap.as_secs_f64(),
+        ),
+        per_listener("listener_active_sessions", "Sessions open now, by listener.", s, listeners, |l| {
+            l.active_sessions as f64
+        }),
+    ]
+}
+
+/// `/metrics` in the Prometheus text exposition format, version 0.0.4.
+/// Every name is prefixed `thin_proxy_`; the values are the workers'
+/// counters summed, read without stopping any loop, so two of them may be
+/// a few events apart.
+pub fn render(s: &Summary, usage: &Usage, nofile: u64, listeners: &[(String, String)]) -> String {
+    let mut out = String::with_capacity(8 * 1024);
+    for family in counters(s, listeners) {
+        write_family(&mut out, &family, "counter");
+    }
+    for family in gauges(s, usage, nofile, listeners) {
+        write_family(&mut out, &family, "gauge");
+    }
+    histogram(&mut out, "dns_duration_seconds", "Time spent in the system resolver, cache hits excluded.", &s.dns_latency);
+    histogram(
+        &mut out,
+        "upstream_connect_duration_seconds",
+        "From issuing the upstream connect to it being established.",
+        &s.connect_latency,
+    );
+    let name = "establish_phase_seconds";
+    header(
+        &mut out,
+        name,
+        "histogram",
+        "Steps from accept to an established tunnel: head, dns, connect and reply (200 written).",
+    );
+    for phase in Phase::ALL {
+        let labels = format!("phase=\"{}\"", phase.name());
+        series(&mut out, name, &labels, &s.establish_latency[phase as usize]);
+    }
+    histogram(&mut out, "loop_iteration_seconds", "Worker event loop iterations, poll wait excluded.", &s.loop_latency);
+    out
+}
+
+fn write_family<T: Display>(out: &mut String, family: &Family<T>, kind: &str) {
+    header(out, family.name, kind, family.help);
+    for (labels, value) in &family.samples {
+        let labels = labels
+            .iter()
+            .map(|(key, v)| format!("{}=\"{}\"", key, escape(v)))
+            .collect::<Vec<_>>()
+            .join(",");
+        sample(out, family.name, &labels, value);
+    }
+}
+
+/// A label value as the exposition format wants it quoted.
+fn escape(v: &str) -> String {
+    v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP thin_proxy_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE thin_proxy_{} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &str, value: impl Display) {
+    if labels.is_empty() {
+        let _ = writeln!(out, "thin_proxy_{} {}", name, value);
+    } else {
+        let _ = writeln!(out, "thin_proxy_{}{{{}}} {}", name, labels, value);
+    }
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, b: &Buckets) {
+    header(out, name, "histogram", help);
+    series(out, name, "", b);
+}
+
+/// Cumulative `le` buckets at the histogram's power-of-two bounds, after
+/// `labels` if there are any; the last, open-ended bucket only shows in
+/// `+Inf`.
+fn series(out: &mut String, name: &str, labels: &str, b: &Buckets) {
+    let with = |label: String| match labels {
+        "" => label,
+        _ => format!("{},{}", labels, label),
+    };
+    let mut total = 0;
+    for (i, n) in b.counts[..BUCKETS - 1].iter().enumerate() {
+        total += n;
+        let le = with(format!("le=\"{}\"", (1u64 << i) as f64 / 1e6));
+        sample(out, &format!("{}_bucket", name), &le, total);
+    }
+    sample(out, &format!("{}_bucket", name), &with("le=\"+Inf\"".to_owned()), b.count());
+    sample(out, &format!("{}_sum", name), labels, b.sum_us as f64 / 1e6);
+    sample(out, &format!("{}_count", name), labels, b.count());
+}
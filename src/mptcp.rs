@@ -0,0 +1,68 @@
+use std::{
+    io,
+    net::SocketAddr,
+    os::fd::AsRawFd,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use log::info;
+use mio::net::TcpStream;
+use nix::libc;
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// SOL_MPTCP and MPTCP_INFO from linux/mptcp.h, which libc leaves out.
+const SOL_MPTCP: libc::c_int = 284;
+const MPTCP_INFO: libc::c_int = 1;
+/// `mptcpi_flags`, a u32 at this offset of struct mptcp_info
+const FLAGS_AT: usize = 8;
+const FLAG_FALLBACK: u32 = 1 << 0;
+const FLAG_REMOTE_KEY_RECEIVED: u32 = 1 << 1;
+
+/// Set once socket() said the kernel has no MPTCP, so later dials go
+/// straight to TCP.
+static UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Starts a non-blocking connect to `to` over multipath TCP. None when
+/// the kernel has no MPTCP, or has it switched off, for the caller to
+/// dial plain TCP instead.
+pub fn connect(to: SocketAddr) -> io::Result<Option<TcpStream>> {
+    if UNSUPPORTED.load(Ordering::Relaxed) {
+        return Ok(None);
+    }
+    let socket = match Socket::new(Domain::for_address(to), Type::STREAM, Some(Protocol::MPTCP)) {
+        Ok(s) => s,
+        // EPROTONOSUPPORT without CONFIG_MPTCP, ENOPROTOOPT with
+        // net.mptcp.enabled = 0, EINVAL on kernels before 5.6
+        Err(e) if matches!(e.raw_os_error(), Some(libc::EPROTONOSUPPORT | libc::ENOPROTOOPT | libc::EINVAL)) => {
+            if !UNSUPPORTED.swap(true, Ordering::Relaxed) {
+                info!("MPTCP unavailable ({}), upstream connections go over TCP", e);
+            }
+            return Ok(None);
+        }
+        Err(e) => return Err(e),
+    };
+    socket.set_nonblocking(true)?;
+    match socket.connect(&to.into()) {
+        Err(e) if e.raw_os_error() != Some(libc::EINPROGRESS) => return Err(e),
+        _ => {}
+    }
+    Ok(Some(TcpStream::from_std(socket.into())))
+}
+
+/// Whether the connected `sock`, made by `connect`, speaks MPTCP with
+/// its peer: false when the peer or a middlebox had the kernel fall
+/// back to plain TCP.
+pub fn negotiated(sock: &impl AsRawFd) -> bool {
+    let mut info = [0u8; 64];
+    let mut len = info.len() as libc::socklen_t;
+    // SAFETY: valid fd for the lifetime of `sock`, the kernel writes at
+    // most `len` bytes into `info` and says how many in `len`
+    let r = unsafe {
+        libc::getsockopt(sock.as_raw_fd(), SOL_MPTCP, MPTCP_INFO, info.as_mut_ptr() as *mut libc::c_void, &mut len)
+    };
+    if r != 0 || (len as usize) < FLAGS_AT + 4 {
+        return false;
+    }
+    let flags = u32::from_ne_bytes(info[FLAGS_AT..FLAGS_AT + 4].try_into().unwrap_or_default());
+    flags & FLAG_FALLBACK == 0 && flags & FLAG_REMOTE_KEY_RECEIVED != 0
+}
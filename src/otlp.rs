@@ -0,0 +1,422 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::BuildHasher,
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+
+use crate::{
+    config::{self, Config},
+    session::{CloseReason, Outcome, Session},
+};
+
+/// Sessions whose spans wait for the export thread; past this they are
+/// dropped rather than holding up the worker.
+const QUEUE: usize = 4096;
+
+/// Sessions per export request, fewer when `FLUSH_EVERY` is up first.
+const BATCH: usize = 512;
+
+/// Longest a finished session waits to be exported.
+const FLUSH_EVERY: Duration = Duration::from_secs(5);
+
+/// Connect, write and read timeout of one export request.
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// At most one warning per this long while exports keep failing.
+const WARN_EVERY: Duration = Duration::from_secs(60);
+
+/// Path of the OTLP/HTTP traces endpoint when the URL has none.
+const DEFAULT_PATH: &str = "/v1/traces";
+
+/// `Config::otlp_endpoint` once its thread runs, None exports nothing.
+static EXPORT: Mutex<Option<Export>> = Mutex::new(None);
+
+/// Sessions traced but dropped on a full queue, logged by the export
+/// thread with its next warning.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+struct Export {
+    /// the encoded spans of one session, see `spans`
+    tx: SyncSender<Vec<u8>>,
+    sample_ratio: f64,
+}
+
+/// Starts exporting to `config.otlp_endpoint`, or stops when unset. Runs at
+/// startup and again on reload; the previous thread sends what it has and
+/// exits once its queue is dropped.
+pub fn configure(config: &Config) -> io::Result<()> {
+    let export = match &config.otlp_endpoint {
+        Some(url) => {
+            let (authority, path) = config::split_http_url(url)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "bad otlp endpoint"))?;
+            let collector = Collector {
+                authority: authority.to_owned(),
+                path: path.unwrap_or(DEFAULT_PATH).to_owned(),
+                failures: 0,
+                warned: None,
+            };
+            let (tx, rx) = mpsc::sync_channel(QUEUE);
+            thread::Builder::new()
+                .name("otlp".to_owned())
+                .spawn(move || collector.run(rx))?;
+            info!("otlp: traces to {}, sampling {}", url, config.otlp_sample_ratio);
+            Some(Export {
+                tx,
+                sample_ratio: config.otlp_sample_ratio,
+            })
+        }
+        None => None,
+    };
+    *EXPORT.lock().unwrap() = export;
+    Ok(())
+}
+
+/// Queues the trace of `session`, closed for `reason`, when it is
+/// sampled. Never blocks: a full queue drops it.
+pub fn session(session: &Session, reason: CloseReason) {
+    let export = EXPORT.lock().unwrap();
+    let Some(export) = export.as_ref() else {
+        return;
+    };
+    let parent = session
+        .method()
+        .filter(|m| m != "CONNECT")
+        .and_then(|_| traceparent(&session.connect_header_buf));
+    let sampled = match &parent {
+        Some(p) => p.sampled,
+        None => (random() as f64) < export.sample_ratio * u64::MAX as f64,
+    };
+    if !sampled {
+        return;
+    }
+    match export.tx.try_send(spans(session, reason, parent)) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(TrySendError::Disconnected(_)) => {}
+    }
+}
+
+/// The W3C trace context of a forwarded request.
+struct Parent {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    sampled: bool,
+}
+
+/// The `traceparent: 00-<trace id>-<span id>-<flags>` header of `head`, if
+/// there is a valid one.
+fn traceparent(head: &[u8]) -> Option<Parent> {
+    let value = head
+        .split(|&b| b == b'\n')
+        .skip(1)
+        .take_while(|l| !l.is_empty() && *l != b"\r")
+        .find_map(|l| {
+            let (name, value) = l.split_at(l.iter().position(|&b| b == b':')?);
+            name.eq_ignore_ascii_case(b"traceparent").then(|| value[1..].trim_ascii())
+        })?;
+    let value = std::str::from_utf8(value).ok()?;
+    let mut parts = value.split('-');
+    let (version, trace, span, flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    if version != "00" || parts.next().is_some() || trace.len() != 32 || span.len() != 16 || flags.len() != 2 {
+        return None;
+    }
+    let trace_id = u128::from_str_radix(trace, 16).ok().filter(|&t| t != 0)?.to_be_bytes();
+    let span_id = u64::from_str_radix(span, 16).ok().filter(|&s| s != 0)?.to_be_bytes();
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    Some(Parent {
+        trace_id,
+        span_id,
+        sampled: flags & 1 == 1,
+    })
+}
+
+/// Randomness for ids and sampling: std's per-process random hash keys over
+/// a counter.
+fn random() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    RandomState::new().hash_one(COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+/// The session span and its children, encoded as `Span` messages for
+/// `ScopeSpans.spans`. Phases the session never reached have no span.
+fn spans(session: &Session, reason: CloseReason, parent: Option<Parent>) -> Vec<u8> {
+    let closed = Instant::now();
+    let wall = SystemTime::now();
+    let nanos = |at: Instant| {
+        let at = wall - closed.duration_since(at);
+        at.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
+    };
+    let (trace_id, parent_id) = match parent {
+        Some(p) => (p.trace_id, Some(p.span_id)),
+        None => ((u128::from(random()) << 64 | u128::from(random())).to_be_bytes(), None),
+    };
+    let root_id = random().to_be_bytes();
+    let failed = matches!(session.outcome(), Outcome::Failed(_));
+
+    let host = session.host.as_str();
+    let mut attributes = Vec::new();
+    attribute(&mut attributes, "client.address", Value::Str(&session.client.to_string()));
+    if !host.is_empty() {
+        attribute(&mut attributes, "server.address", Value::Str(host));
+        attribute(&mut attributes, "server.port", Value::Int(session.port.into()));
+    }
+    attribute(&mut attributes, "thin_proxy.session", Value::Int(session.id as i64));
+    attribute(&mut attributes, "thin_proxy.bytes_up", Value::Int(session.bytes_up as i64));
+    attribute(&mut attributes, "thin_proxy.bytes_down", Value::Int(session.bytes_down as i64));
+    attribute(&mut attributes, "thin_proxy.close_reason", Value::Str(&reason.to_string()));
+    attribute(&mut attributes, "thin_proxy.outcome", Value::Str(&session.outcome().to_string()));
+
+    let mut out = Vec::with_capacity(512);
+    let root = Span {
+        trace_id,
+        span_id: root_id,
+        parent_id,
+        name: "session",
+        kind: SPAN_KIND_SERVER,
+        start: nanos(session.created),
+        end: nanos(closed),
+        attributes: &attributes,
+        error: failed,
+    };
+    message(&mut out, 2, &root.encode());
+    let m = &session.milestones;
+    // a phase that started but never finished ends, failed, with the session
+    let phases = [
+        ("dns", SPAN_KIND_INTERNAL, m.head, m.resolved),
+        ("connect", SPAN_KIND_CLIENT, m.resolved, m.connected),
+        ("established", SPAN_KIND_INTERNAL, m.established, Some(closed)),
+    ];
+    for (name, kind, start, end) in phases {
+        let Some(start) = start else {
+            continue;
+        };
+        let span = Span {
+            trace_id,
+            span_id: random().to_be_bytes(),
+            parent_id: Some(root_id),
+            name,
+            kind,
+            start: nanos(start),
+            end: nanos(end.unwrap_or(closed)),
+            attributes: &[],
+            error: end.is_none(),
+        };
+        message(&mut out, 2, &span.encode());
+    }
+    out
+}
+
+/// Sends the batches, one at a time, to the collector.
+struct Collector {
+    authority: String,
+    path: String,
+    /// failures not warned about since the last warning
+    failures: u64,
+    warned: Option<Instant>,
+}
+
+impl Collector {
+    fn run(mut self, rx: Receiver<Vec<u8>>) {
+        let mut batch = Vec::new();
+        let mut sessions = 0;
+        let mut deadline = Instant::now() + FLUSH_EVERY;
+        loop {
+            let done = match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(spans) => {
+                    batch.extend_from_slice(&spans);
+                    sessions += 1;
+                    false
+                }
+                Err(RecvTimeoutError::Timeout) => false,
+                Err(RecvTimeoutError::Disconnected) => true,
+            };
+            if sessions >= BATCH || Instant::now() >= deadline || done {
+                if sessions > 0 {
+                    if let Err(e) = self.send(&request(&batch)) {
+                        self.failed(e, sessions);
+                    }
+                }
+                batch.clear();
+                sessions = 0;
+                deadline = Instant::now() + FLUSH_EVERY;
+            }
+            if done {
+                return;
+            }
+        }
+    }
+
+    /// POSTs one `ExportTraceServiceRequest`, any 2xx answer is success.
+    fn send(&self, body: &[u8]) -> io::Result<()> {
+        let addr = self
+            .authority
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+        let mut sock = TcpStream::connect_timeout(&addr, IO_TIMEOUT)?;
+        sock.set_read_timeout(Some(IO_TIMEOUT))?;
+        sock.set_write_timeout(Some(IO_TIMEOUT))?;
+        let head = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/x-protobuf\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.path,
+            self.authority,
+            body.len()
+        );
+        sock.write_all(head.as_bytes())?;
+        sock.write_all(body)?;
+        let mut answer = [0u8; 64];
+        let mut n = 0;
+        while n < 12 {
+            match sock.read(&mut answer[n..])? {
+                0 => break,
+                read => n += read,
+            }
+        }
+        let status = String::from_utf8_lossy(&answer[..n]);
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            Some(code) => Err(io::Error::other(format!("collector answered {}", code))),
+            None => Err(io::Error::other("no answer from the collector")),
+        }
+    }
+
+    fn failed(&mut self, e: io::Error, sessions: usize) {
+        if self.warned.is_some_and(|t| t.elapsed() < WARN_EVERY) {
+            self.failures += 1;
+            return;
+        }
+        warn!(
+            "otlp {}: {}, dropped {} sessions, {} more failures and {} sessions over the queue since the last warning",
+            self.authority,
+            e,
+            sessions,
+            self.failures,
+            DROPPED.swap(0, Ordering::Relaxed)
+        );
+        self.failures = 0;
+        self.warned = Some(Instant::now());
+    }
+}
+
+const SPAN_KIND_INTERNAL: u64 = 1;
+const SPAN_KIND_SERVER: u64 = 2;
+const SPAN_KIND_CLIENT: u64 = 3;
+const STATUS_CODE_ERROR: u64 = 2;
+
+/// One `opentelemetry.proto.trace.v1.Span`.
+struct Span<'a> {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_id: Option<[u8; 8]>,
+    name: &'a str,
+    kind: u64,
+    start: u64,
+    end: u64,
+    /// encoded `KeyValue`s, see `attribute`
+    attributes: &'a [u8],
+    error: bool,
+}
+
+impl Span<'_> {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(128 + self.attributes.len());
+        message(&mut out, 1, &self.trace_id);
+        message(&mut out, 2, &self.span_id);
+        if let Some(parent) = &self.parent_id {
+            message(&mut out, 4, parent);
+        }
+        message(&mut out, 5, self.name.as_bytes());
+        varint_field(&mut out, 6, self.kind);
+        fixed64_field(&mut out, 7, self.start);
+        fixed64_field(&mut out, 8, self.end);
+        out.extend_from_slice(self.attributes);
+        if self.error {
+            let mut status = Vec::new();
+            varint_field(&mut status, 3, STATUS_CODE_ERROR);
+            message(&mut out, 15, &status);
+        }
+        out
+    }
+}
+
+enum Value<'a> {
+    Str(&'a str),
+    Int(i64),
+}
+
+/// Appends `key = value` as a `KeyValue` in field 9, where `Span`
+/// attributes go.
+fn attribute(out: &mut Vec<u8>, key: &str, value: Value) {
+    let mut any = Vec::new();
+    match value {
+        Value::Str(s) => message(&mut any, 1, s.as_bytes()),
+        Value::Int(i) => varint_field(&mut any, 3, i as u64),
+    }
+    let mut kv = Vec::new();
+    message(&mut kv, 1, key.as_bytes());
+    message(&mut kv, 2, &any);
+    message(out, 9, &kv);
+}
+
+/// An `ExportTraceServiceRequest` of one resource and scope holding the
+/// encoded `spans`.
+fn request(spans: &[u8]) -> Vec<u8> {
+    let mut service = Vec::new();
+    message(&mut service, 1, b"service.name");
+    let mut name = Vec::new();
+    message(&mut name, 1, b"thin_proxy");
+    message(&mut service, 2, &name);
+    let mut resource = Vec::new();
+    message(&mut resource, 1, &service);
+
+    let mut scope = Vec::new();
+    message(&mut scope, 1, b"thin_proxy");
+    message(&mut scope, 2, env!("CARGO_PKG_VERSION").as_bytes());
+    let mut scope_spans = Vec::with_capacity(spans.len() + 32);
+    message(&mut scope_spans, 1, &scope);
+    scope_spans.extend_from_slice(spans);
+
+    let mut resource_spans = Vec::with_capacity(scope_spans.len() + 64);
+    message(&mut resource_spans, 1, &resource);
+    message(&mut resource_spans, 2, &scope_spans);
+    let mut out = Vec::with_capacity(resource_spans.len() + 8);
+    message(&mut out, 1, &resource_spans);
+    out
+}
+
+fn varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn varint_field(out: &mut Vec<u8>, field: u64, v: u64) {
+    varint(out, field << 3);
+    varint(out, v);
+}
+
+fn fixed64_field(out: &mut Vec<u8>, field: u64, v: u64) {
+    varint(out, field << 3 | 1);
+    out.extend_from_slice(&v.to_le_bytes());
+}
+
+/// A length-delimited field: bytes, a string or an embedded message.
+fn message(out: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    varint(out, field << 3 | 2);
+    varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
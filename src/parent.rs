@@ -0,0 +1,228 @@
+use std::{fmt::Display, path::PathBuf, str::FromStr};
+
+use serde::Serialize;
+
+use crate::{host_pattern::HostPattern, secret::Secret, ser, session::split_host_port};
+
+/// `Config::parent_proxy` or `Config::parent_socks`: a proxy that routed
+/// destinations are reached through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParentProxy {
+    pub host: String,
+    pub port: u16,
+    pub protocol: Protocol,
+}
+
+/// What a parent proxy speaks, with the credentials we give it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Protocol {
+    /// HTTP, `Basic <base64 user:password>` sent as Proxy-Authorization
+    Http(Option<Secret>),
+    /// SOCKS5, a username and password for its RFC 1929 method
+    Socks5(Option<(String, Secret)>),
+}
+
+impl ParentProxy {
+    /// `Config::parent_proxy` with its credentials: `address` is
+    /// `host:port`, `[v6]:port` for IPv6 literals.
+    pub fn new(address: &str, user: Option<&str>, password: Option<&Secret>) -> Result<ParentProxy, String> {
+        let (host, port) = parse_address(address)?;
+        let authorization = match (user, password) {
+            (Some(user), password) => Some(Secret::new(format!(
+                "Basic {}",
+                base64(format!("{}:{}", user, password.map_or("", Secret::expose)).as_bytes())
+            ))),
+            (None, Some(_)) => return Err("parent_proxy_password needs parent_proxy_user".to_owned()),
+            (None, None) => None,
+        };
+        Ok(ParentProxy {
+            host,
+            port,
+            protocol: Protocol::Http(authorization),
+        })
+    }
+
+    /// `Config::parent_socks` with its credentials, `address` as for
+    /// `new`. RFC 1929 caps the username and password at 255 bytes.
+    pub fn socks(address: &str, user: Option<&str>, password: Option<&Secret>) -> Result<ParentProxy, String> {
+        let (host, port) = parse_address(address)?;
+        let credentials = match (user, password) {
+            (Some(user), password) => {
+                let password = password.cloned().unwrap_or_else(|| Secret::new(String::new()));
+                if user.is_empty() || user.len() > 255 || password.expose().len() > 255 {
+                    return Err("parent_socks_user and parent_socks_password need 1 to 255 bytes".to_owned());
+                }
+                Some((user.to_owned(), password))
+            }
+            (None, Some(_)) => return Err("parent_socks_password needs parent_socks_user".to_owned()),
+            (None, None) => None,
+        };
+        Ok(ParentProxy {
+            host,
+            port,
+            protocol: Protocol::Socks5(credentials),
+        })
+    }
+
+    pub fn is_socks(&self) -> bool {
+        matches!(self.protocol, Protocol::Socks5(_))
+    }
+
+    /// The username and password a SOCKS5 parent gets, when it has them.
+    pub fn socks_credentials(&self) -> Option<(&str, &Secret)> {
+        match &self.protocol {
+            Protocol::Socks5(Some((user, password))) => Some((user, password)),
+            _ => None,
+        }
+    }
+
+    fn authorization(&self) -> Option<&Secret> {
+        match &self.protocol {
+            Protocol::Http(authorization) => authorization.as_ref(),
+            Protocol::Socks5(_) => None,
+        }
+    }
+
+    /// The request opening a tunnel to `authority` through the parent.
+    pub fn connect_request(&self, authority: &str) -> Vec<u8> {
+        let mut req = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+        if let Some(auth) = self.authorization() {
+            req.push_str(&format!("Proxy-Authorization: {}\r\n", auth.expose()));
+        }
+        req.push_str("\r\n");
+        req.into_bytes()
+    }
+
+    /// `head`, a client's forwarded request in absolute form, with our
+    /// Proxy-Authorization added after the request line.
+    pub fn forward_request(&self, head: &[u8]) -> Vec<u8> {
+        let Some(auth) = self.authorization() else {
+            return head.to_vec();
+        };
+        let split = head.iter().position(|&b| b == b'\n').map_or(head.len(), |i| i + 1);
+        let mut req = Vec::with_capacity(head.len() + auth.expose().len() + 32);
+        req.extend_from_slice(&head[..split]);
+        req.extend_from_slice(format!("Proxy-Authorization: {}\r\n", auth.expose()).as_bytes());
+        req.extend_from_slice(&head[split..]);
+        req
+    }
+}
+
+impl Display for ParentProxy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// `host:port` of a parent proxy, `[v6]:port` for IPv6 literals.
+fn parse_address(address: &str) -> Result<(String, u16), String> {
+    let (host, port) = split_host_port(address);
+    let port = port
+        .and_then(|p| p.parse().ok())
+        .ok_or_else(|| format!("parent proxy {:?} needs host:port", address))?;
+    if host.is_empty() {
+        return Err(format!("parent proxy {:?} has no host", address));
+    }
+    Ok((host.to_owned(), port))
+}
+
+/// How a destination is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Via {
+    Direct,
+    /// through `Config::parent_proxy`
+    Parent,
+    /// through `Config::parent_socks`
+    SocksParent,
+}
+
+impl FromStr for Via {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "direct" => Ok(Via::Direct),
+            "via-parent" => Ok(Via::Parent),
+            "via-socks-parent" => Ok(Via::SocksParent),
+            _ => Err(format!("unknown route {:?}, expected direct, via-parent or via-socks-parent", s)),
+        }
+    }
+}
+
+impl Display for Via {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Via::Direct => "direct",
+            Via::Parent => "via-parent",
+            Via::SocksParent => "via-socks-parent",
+        })
+    }
+}
+
+/// One `[[route]]` entry: destinations matching any of `hosts` go `via`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Route {
+    pub hosts: Vec<HostPattern>,
+    #[serde(rename = "action", serialize_with = "ser::display")]
+    pub via: Via,
+    /// the upstream connection starts with a PROXY protocol v2 header
+    /// naming the client; direct routes only
+    pub proxy_protocol: bool,
+    /// the unix socket the destinations are reached on instead of their
+    /// host:port; direct routes only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unix_socket: Option<PathBuf>,
+    /// the destinations are spoken TLS to over the upstream connection,
+    /// the client's own bytes being plaintext; direct TCP routes only
+    pub wrap_tls: bool,
+    /// the server name `wrap_tls` sends and checks the certificate for,
+    /// None for the destination's host
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_sni: Option<String>,
+    /// whether `wrap_tls` checks the destination's certificate at all
+    #[serde(skip_serializing_if = "ser::is_true")]
+    pub tls_verify: bool,
+    /// PEM certificates `wrap_tls` checks against, None for the system's
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_ca: Option<PathBuf>,
+    /// whether the upstream connection is dialed over multipath TCP, None
+    /// for `Config::upstream_mptcp`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mptcp: Option<bool>,
+    /// `host:port` of the parents the destinations go through, tried in
+    /// turn as they fail, see `parent_health`; empty for
+    /// `Config::parent_proxy` or `Config::parent_socks` alone
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub parents: Vec<String>,
+}
+
+/// The first route matching `host:port`, None when none does.
+pub fn route<'a>(routes: &'a [Route], host: &str, port: u16) -> Option<&'a Route> {
+    routes.iter().find(|r| r.hosts.iter().any(|p| p.matches(host, port)))
+}
+
+/// How the first route matching `host:port` goes, destinations matching
+/// none go direct.
+pub fn route_for(routes: &[Route], host: &str, port: u16) -> Via {
+    route(routes, host, port).map_or(Via::Direct, |r| r.via)
+}
+
+pub fn base64(bytes: &[u8]) -> String {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | ((b as u32) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(TABLE[((n >> (18 - 6 * i)) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
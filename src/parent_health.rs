@@ -0,0 +1,257 @@
+use std::{
+    fmt::Write as _,
+    io::{self, ErrorKind, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use log::{debug, info, warn};
+
+use crate::{
+    admin::json_str,
+    config::Config,
+    parent::ParentProxy,
+    session::{split_host_port, MAX_PARENT_HEAD},
+    socks::{self, Reply},
+};
+
+/// How every parent proxy of the config fares, shared by every worker.
+/// Started afresh on each reload, every parent healthy.
+static HEALTH: Mutex<Health> = Mutex::new(Health {
+    parents: Vec::new(),
+    backoff: Duration::ZERO,
+    timeout: Duration::ZERO,
+    probe: None,
+    probe_every: Duration::from_secs(10),
+});
+
+struct Health {
+    /// in config order, see `Config::all_parents`
+    parents: Vec<Parent>,
+    backoff: Duration,
+    timeout: Duration,
+    probe: Option<String>,
+    probe_every: Duration,
+}
+
+struct Parent {
+    proxy: ParentProxy,
+    /// when it was marked down, None while it works
+    down_since: Option<SystemTime>,
+    /// passed over for the next of a route's parents until then
+    retry_at: Option<Instant>,
+    /// since the reload
+    failures: u64,
+    last_error: Option<String>,
+}
+
+impl Health {
+    fn get_mut(&mut self, proxy: &ParentProxy) -> Option<&mut Parent> {
+        self.parents.iter_mut().find(|p| p.proxy == *proxy)
+    }
+}
+
+/// Takes the parents, backoff and probe of `config`; what was known of
+/// the parents before is forgotten.
+pub fn configure(config: &Config) {
+    let mut health = HEALTH.lock().unwrap();
+    health.parents = config
+        .all_parents()
+        .into_iter()
+        .map(|proxy| Parent { proxy, down_since: None, retry_at: None, failures: 0, last_error: None })
+        .collect();
+    health.backoff = config.parent_backoff;
+    health.timeout = config.parent_timeout;
+    health.probe = config.parent_probe.clone();
+    health.probe_every = config.parent_probe_every;
+}
+
+/// Sorts the parents of a route so the ones within their backoff come
+/// last: tried only once every other failed too.
+pub fn order(parents: &mut [ParentProxy]) {
+    let health = HEALTH.lock().unwrap();
+    let now = Instant::now();
+    let retry_at = |p: &ParentProxy| health.parents.iter().find(|h| h.proxy == *p).and_then(|h| h.retry_at);
+    // stable, the others keep the route's order
+    parents.sort_by_cached_key(|p| retry_at(p).is_some_and(|at| at > now));
+}
+
+/// Marks `proxy` down for `Config::parent_backoff`, having failed with
+/// `why`: refused or timed out the connection, or broke off our
+/// handshake. Said once per outage; a parent not in the config since a
+/// reload is let be.
+pub fn failed(proxy: &ParentProxy, why: &str) {
+    let mut health = HEALTH.lock().unwrap();
+    let backoff = health.backoff;
+    let Some(p) = health.get_mut(proxy) else {
+        return;
+    };
+    p.failures += 1;
+    p.retry_at = Some(Instant::now() + backoff);
+    p.last_error = Some(why.to_owned());
+    if p.down_since.is_none() {
+        p.down_since = Some(SystemTime::now());
+        warn!("parent proxy {} down for {}s: {}", proxy, backoff.as_secs(), why);
+    } else {
+        debug!("parent proxy {} still down: {}", proxy, why);
+    }
+}
+
+/// Marks `proxy` healthy again once a tunnel or a probe went through it.
+pub fn worked(proxy: &ParentProxy) {
+    let mut health = HEALTH.lock().unwrap();
+    let Some(p) = health.get_mut(proxy) else {
+        return;
+    };
+    if let Some(since) = p.down_since.take() {
+        let down = since.elapsed().unwrap_or_default();
+        info!("parent proxy {} is back after {}s", proxy, down.as_secs());
+        p.retry_at = None;
+    }
+}
+
+/// The `/parents` body: every parent in config order, whether it is up
+/// and since when it is not.
+pub fn json() -> String {
+    let health = HEALTH.lock().unwrap();
+    let now = Instant::now();
+    let mut out = String::from(r#"{"parents":["#);
+    for (i, p) in health.parents.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let retry_in = p.down_since.and(p.retry_at).map(|at| at.saturating_duration_since(now).as_secs());
+        let _ = write!(
+            out,
+            concat!(
+                r#"{{"parent":{},"protocol":"{}","healthy":{},"down_since":{},"#,
+                r#""retry_in_secs":{},"failures":{},"last_error":{}}}"#
+            ),
+            json_str(&p.proxy.to_string()),
+            if p.proxy.is_socks() { "socks5" } else { "http" },
+            p.down_since.is_none(),
+            p.down_since.map_or("null".to_owned(), |t| format!(r#""{}""#, humantime::format_rfc3339_seconds(t))),
+            retry_in.map_or("null".to_owned(), |s| s.to_string()),
+            p.failures,
+            p.last_error.as_deref().map_or("null".to_owned(), json_str)
+        );
+    }
+    let _ = write!(out, r#"],"probe":{}}}"#, health.probe.as_deref().map_or("null".to_owned(), json_str));
+    out
+}
+
+/// Starts the thread that asks the parents marked down for a tunnel to
+/// `Config::parent_probe` every `Config::parent_probe_every`, marking the
+/// ones that open it healthy. Started whatever the config, as a reload
+/// may set a probe once no thread can start any more, see `seccomp`.
+pub fn spawn_prober() -> io::Result<()> {
+    thread::Builder::new().name("parent-probe".to_owned()).spawn(|| loop {
+        let every = HEALTH.lock().unwrap().probe_every;
+        thread::sleep(every);
+        let (target, timeout, down) = {
+            let health = HEALTH.lock().unwrap();
+            let down = health.parents.iter().filter(|p| p.down_since.is_some()).map(|p| p.proxy.clone());
+            (health.probe.clone(), health.timeout, down.collect::<Vec<_>>())
+        };
+        let Some(target) = target else {
+            continue;
+        };
+        for proxy in down {
+            match probe(&proxy, &target, timeout) {
+                Ok(()) => worked(&proxy),
+                Err(e) => failed(&proxy, &format!("probe: {}", e)),
+            }
+        }
+    })?;
+    Ok(())
+}
+
+/// Opens a tunnel to `target` through `proxy`, blocking for at most
+/// `timeout` on each step.
+fn probe(proxy: &ParentProxy, target: &str, timeout: Duration) -> io::Result<()> {
+    let mut last = io::Error::new(ErrorKind::NotFound, "dns query failed");
+    let mut sock = None;
+    for addr in (proxy.host.as_str(), proxy.port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(s) => {
+                sock = Some(s);
+                break;
+            }
+            Err(e) => last = e,
+        }
+    }
+    let mut sock = sock.ok_or(last)?;
+    sock.set_read_timeout(Some(timeout))?;
+    sock.set_write_timeout(Some(timeout))?;
+    if proxy.is_socks() {
+        return probe_socks(&mut sock, proxy, target);
+    }
+    sock.write_all(&proxy.connect_request(target))?;
+    let mut head = Vec::new();
+    loop {
+        read_more(&mut sock, &mut head)?;
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut response = httparse::Response::new(&mut headers);
+        match response.parse(&head) {
+            Ok(httparse::Status::Complete(_)) if response.code == Some(200) => return Ok(()),
+            Ok(httparse::Status::Complete(_)) => {
+                let (code, reason) = (response.code.unwrap_or_default(), response.reason.unwrap_or_default());
+                return Err(io::Error::other(format!("refused CONNECT {}: {} {}", target, code, reason)));
+            }
+            Ok(httparse::Status::Partial) if head.len() < MAX_PARENT_HEAD => {}
+            Ok(httparse::Status::Partial) => return Err(io::Error::other("answer head too long")),
+            Err(e) => return Err(io::Error::other(format!("bad answer: {}", e))),
+        }
+    }
+}
+
+/// `probe` of a SOCKS5 parent, the steps of `Session::parent_socks_response`.
+fn probe_socks(sock: &mut TcpStream, proxy: &ParentProxy, target: &str) -> io::Result<()> {
+    let (host, port) = split_host_port(target);
+    let port = port.and_then(|p| p.parse().ok()).ok_or_else(|| io::Error::other("probe needs host:port"))?;
+    let connect = socks::client_connect(host, port).map_err(io::Error::other)?;
+    let mut buf = Vec::new();
+    sock.write_all(&socks::client_greeting(proxy.socks_credentials().is_some()))?;
+    match (answer(sock, &mut buf, socks::method_choice)?, proxy.socks_credentials()) {
+        (socks::USER_PASS, Some((user, password))) => {
+            sock.write_all(&socks::client_auth(user, password.expose()))?;
+            if !answer(sock, &mut buf, socks::auth_status)? {
+                return Err(io::Error::other("refused our credentials"));
+            }
+        }
+        (socks::NO_AUTH, _) => {}
+        _ => return Err(io::Error::other("takes none of the methods we offer")),
+    }
+    sock.write_all(&connect)?;
+    match answer(sock, &mut buf, socks::connect_reply)? {
+        reply if reply == Reply::Succeeded as u8 => Ok(()),
+        reply => Err(io::Error::other(format!("refused CONNECT {}: reply {}", target, reply))),
+    }
+}
+
+/// The next answer of a SOCKS5 parent as `parse` reads it, read into
+/// `buf` until it is complete.
+fn answer<T>(sock: &mut TcpStream, buf: &mut Vec<u8>, parse: fn(&[u8]) -> socks::Parsed<T>) -> io::Result<T> {
+    loop {
+        if let Some((n, answer)) = parse(buf).map_err(io::Error::other)? {
+            buf.drain(..n);
+            return Ok(answer);
+        }
+        read_more(sock, buf)?;
+    }
+}
+
+/// Appends what `sock` has to `buf`; its end of file is an error, it
+/// closed before answering.
+fn read_more(sock: &mut TcpStream, buf: &mut Vec<u8>) -> io::Result<()> {
+    let mut chunk = [0u8; 1024];
+    match sock.read(&mut chunk)? {
+        0 => Err(io::Error::new(ErrorKind::UnexpectedEof, "closed before answering")),
+        n => {
+            buf.extend_from_slice(&chunk[..n]);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+use nix::{
+    errno::Errno,
+    sys::signal::kill,
+    unistd::{getpid, getppid, Pid},
+};
+
+use crate::err::Fatal;
+
+/// A pidfile owned by this process, removed again on drop as long as it
+/// still names us (an upgrade successor takes it over).
+pub struct Pidfile {
+    path: PathBuf,
+    pid: i32,
+}
+
+impl Pidfile {
+    /// Writes our pid to `path`. Refuses while the pid already in there is
+    /// alive, unless it is our parent handing over through an upgrade; a
+    /// stale one from a crashed run is overwritten.
+    pub fn create(path: &Path) -> Result<Pidfile, Fatal> {
+        let pid = getpid().as_raw();
+        if let Some(holder) = read_pid(path) {
+            if holder != pid && holder != getppid().as_raw() && alive(holder) {
+                return Err(Fatal::Runtime(format!(
+                    "pidfile {} is held by running pid {}, is another instance up?",
+                    path.display(),
+                    holder
+                )));
+            }
+            if holder != getppid().as_raw() {
+                warn!("pidfile {} names pid {} which is gone, overwriting", path.display(), holder);
+            }
+        }
+        write_atomic(path, pid).map_err(|e| Fatal::Config(format!("cannot write pidfile {}: {}", path.display(), e)))?;
+        info!("pidfile: {} with pid {}", path.display(), pid);
+        Ok(Pidfile {
+            path: path.to_owned(),
+            pid,
+        })
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        if read_pid(&self.path) != Some(self.pid) {
+            return;
+        }
+        match fs::remove_file(&self.path) {
+            Ok(()) => {}
+            // in a directory only root may change, after dropping it; the
+            // file itself was handed over and is emptied to name no one
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                if let Err(e) = fs::File::create(&self.path) {
+                    warn!("empty pidfile {} err {}", self.path.display(), e);
+                }
+            }
+            Err(e) => warn!("remove pidfile {} err {}", self.path.display(), e),
+        }
+    }
+}
+
+fn read_pid(path: &Path) -> Option<i32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+fn alive(pid: i32) -> bool {
+    // EPERM means it exists but belongs to someone else
+    matches!(kill(Pid::from_raw(pid), None), Ok(()) | Err(Errno::EPERM))
+}
+
+/// Write to a temporary file next to `path` and rename it into place, so
+/// readers never see a partial pid.
+fn write_atomic(path: &Path, pid: i32) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{}.tmp", pid));
+    let tmp = PathBuf::from(tmp);
+    let mut f = fs::File::create(&tmp)?;
+    writeln!(f, "{}", pid)?;
+    f.sync_all()?;
+    fs::rename(&tmp, path).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })
+}
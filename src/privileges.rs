@@ -0,0 +1,114 @@
+use std::{
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+use nix::{
+    errno::Errno,
+    unistd::{chown, getegid, geteuid, getgid, getuid, setgid, setgroups, setuid, Gid, Group, Uid, User},
+};
+
+use crate::err::Fatal;
+
+/// Looks up a user by name or numeric id.
+pub fn lookup_user(name: &str) -> Result<User, String> {
+    let found = match name.parse() {
+        Ok(id) => User::from_uid(Uid::from_raw(id)),
+        Err(_) => User::from_name(name),
+    };
+    found
+        .map_err(|e| format!("look up user {:?}: {}", name, e))?
+        .ok_or_else(|| format!("unknown user {:?}", name))
+}
+
+/// Looks up a group by name or numeric id.
+pub fn lookup_group(name: &str) -> Result<Gid, String> {
+    if let Ok(id) = name.parse() {
+        return Ok(Gid::from_raw(id));
+    }
+    Group::from_name(name)
+        .map_err(|e| format!("look up group {:?}: {}", name, e))?
+        .map(|g| g.gid)
+        .ok_or_else(|| format!("unknown group {:?}", name))
+}
+
+/// Resolves `Config::user`/`Config::group`; the group defaults to the
+/// user's primary one.
+pub fn resolve(user: &str, group: Option<&str>) -> Result<(Uid, Gid), String> {
+    let user = lookup_user(user)?;
+    let gid = match group {
+        Some(g) => lookup_group(g)?,
+        None => user.gid,
+    };
+    Ok((user.uid, gid))
+}
+
+/// Whether `uid` in `gid` may create, rename and remove files in `dir`,
+/// by its mode bits.
+pub fn can_write(dir: &Path, uid: Uid, gid: Gid) -> bool {
+    fs::metadata(dir).is_ok_and(|meta| {
+        let bits = if uid.is_root() {
+            return true;
+        } else if meta.uid() == uid.as_raw() {
+            meta.mode() >> 6
+        } else if meta.gid() == gid.as_raw() {
+            meta.mode() >> 3
+        } else {
+            meta.mode()
+        };
+        // write, and search to get at the entries
+        bits & 0o3 == 0o3
+    })
+}
+
+/// Gives the files among `paths` that exist to `user` and `group`, for
+/// what the proxy still does with them once it runs as that user:
+/// reopening and rotating logs, emptying its pidfile. Only root can; an
+/// upgrade successor already running as the user finds them handed over.
+pub fn hand_over(paths: &[PathBuf], user: &str, group: Option<&str>) -> Result<(), Fatal> {
+    if !geteuid().is_root() {
+        return Ok(());
+    }
+    let (uid, gid) = resolve(user, group).map_err(Fatal::Config)?;
+    for path in paths {
+        match chown(path.as_path(), Some(uid), Some(gid)) {
+            Ok(()) | Err(Errno::ENOENT) => {}
+            Err(e) => warn!("privileges: cannot give {} to {}: {}", path.display(), user, e),
+        }
+    }
+    Ok(())
+}
+
+/// Switches the whole process to `user` and `group`: supplementary groups
+/// first, then the gid, then the uid, and checks root cannot be regained.
+/// Must run before any thread that serves clients is started. A process
+/// already running as exactly that user (an upgrade successor re-executed
+/// by a dropped parent) carries on; any other non-root start is refused.
+pub fn drop_to(user: &str, group: Option<&str>) -> Result<(), Fatal> {
+    let (uid, gid) = resolve(user, group).map_err(Fatal::Config)?;
+    if !geteuid().is_root() {
+        if (getuid(), geteuid(), getgid(), getegid()) == (uid, uid, gid, gid) {
+            info!("privileges: already running as {} ({}:{})", user, uid, gid);
+            return Ok(());
+        }
+        return Err(Fatal::Config(format!(
+            "user = {:?} needs the proxy started as root, running as uid {}",
+            user,
+            geteuid()
+        )));
+    }
+    let fail = |what: &str, e: nix::Error| Fatal::Runtime(format!("drop privileges: {} failed: {}", what, e));
+    setgroups(&[gid]).map_err(|e| fail("setgroups", e))?;
+    setgid(gid).map_err(|e| fail("setgid", e))?;
+    setuid(uid).map_err(|e| fail("setuid", e))?;
+    if setuid(Uid::from_raw(0)).is_ok() || (geteuid(), getegid()) != (uid, gid) {
+        return Err(Fatal::Runtime(format!(
+            "drop privileges: still able to act as root after switching to {}",
+            user
+        )));
+    }
+    info!("privileges: dropped to {} ({}:{})", user, uid, gid);
+    Ok(())
+}
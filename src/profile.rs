@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+use serde::{Serialize, Serializer};
+
+use crate::{
+    ser,
+    timeouts::{self, SessionTimeouts, TimeoutOverride, Timeouts},
+};
+
+/// Name of the profile listeners without one get. Defining it changes
+/// what they get.
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// A `[profile.<name>]` policy bundle that `[[listener]]` entries refer
+/// to. A session takes its listener's profile when accepted and keeps it
+/// for its life, a reload only affects sessions accepted after it. Parts
+/// a profile leaves out fall back to the top-level settings.
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    pub name: String,
+    /// whether clients need `auth_file` credentials, the default being
+    /// that they do when it is set; false lets them in without
+    pub auth: Option<bool>,
+    /// replaces `timeouts.idle`
+    pub idle_timeout: Option<Duration>,
+    /// replaces `timeouts.lifetime`, `Some(None)` lifting the cap
+    pub lifetime: Option<Option<Duration>>,
+    /// replaces `timeouts.override` as a whole
+    pub timeout_overrides: Option<Vec<TimeoutOverride>>,
+}
+
+impl Profile {
+    /// Whether its clients must authenticate, with `auth_file` set or not.
+    pub fn auth_required(&self, auth_file: bool) -> bool {
+        self.auth.unwrap_or(auth_file)
+    }
+
+    /// Timeouts under this profile for `destination`, None until the
+    /// CONNECT named one.
+    pub fn timeouts(&self, global: &Timeouts, destination: Option<(&str, u16)>) -> SessionTimeouts {
+        timeouts::resolve(
+            self.idle_timeout.unwrap_or(global.idle),
+            self.lifetime.unwrap_or(global.lifetime),
+            self.timeout_overrides.as_deref().unwrap_or(&global.overrides),
+            destination,
+        )
+    }
+
+    /// Adds what is wrong to `errors`, each prefixed with the profile.
+    pub fn validate(&self, errors: &mut Vec<String>) {
+        let mut own = Vec::new();
+        if self.idle_timeout.is_some_and(|d| d.is_zero()) {
+            own.push("idle timeout must be above zero".to_owned());
+        }
+        if self.lifetime.is_some_and(|l| l.is_some_and(|d| d.is_zero())) {
+            own.push("session lifetime must be above zero, \"none\" for no cap".to_owned());
+        }
+        if let Some(overrides) = &self.timeout_overrides {
+            timeouts::validate_overrides(overrides, &mut own);
+        }
+        errors.extend(own.into_iter().map(|e| format!("profile {}: {}", self.name, e)));
+    }
+}
+
+/// As the `[profile.<name>]` table, the name being its key.
+impl Serialize for Profile {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Table<'a> {
+            #[serde(skip_serializing_if = "Option::is_none")]
+            auth: Option<bool>,
+            timeouts: TimeoutsTable<'a>,
+        }
+        #[derive(Serialize)]
+        struct TimeoutsTable<'a> {
+            #[serde(serialize_with = "ser::duration_opt")]
+            idle: Option<Duration>,
+            #[serde(skip_serializing_if = "Option::is_none", serialize_with = "lifetime")]
+            lifetime: Option<Option<Duration>>,
+            #[serde(rename = "override")]
+            overrides: Option<&'a [TimeoutOverride]>,
+        }
+        Table {
+            auth: self.auth,
+            timeouts: TimeoutsTable {
+                idle: self.idle_timeout,
+                lifetime: self.lifetime,
+                overrides: self.timeout_overrides.as_deref(),
+            },
+        }
+        .serialize(s)
+    }
+}
+
+/// A profile's `lifetime`, `"none"` when it lifts the cap.
+fn lifetime<S: Serializer>(lifetime: &Option<Option<Duration>>, s: S) -> Result<S::Ok, S::Error> {
+    match lifetime {
+        Some(None) => s.serialize_str("none"),
+        Some(d) => ser::duration_opt(d, s),
+        None => s.serialize_none(),
+    }
+}
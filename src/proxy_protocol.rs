@@ -0,0 +1,241 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+/// The most of a header we take: a v1 line is at most 107 bytes, and v2
+/// with the TLVs load balancers add stays well below this.
+pub const MAX_HEADER: usize = 4096;
+
+/// How long a connection on a `proxy_protocol` listener has to bring its
+/// header. The load balancer sends it as it connects, whatever the client
+/// does, so a silent one is not a balancer speaking the protocol.
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub const V1_PREFIX: &[u8] = b"PROXY ";
+/// Longest v1 line, `\r\n` included.
+const V1_MAX: usize = 107;
+pub const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_FIXED: usize = 16;
+
+const V2_LOCAL: u8 = 0x0;
+const V2_PROXY: u8 = 0x1;
+const V2_UNSPEC: u8 = 0x0;
+const V2_INET: u8 = 0x1;
+const V2_INET6: u8 = 0x2;
+const V2_STREAM: u8 = 0x1;
+
+/// Parses the PROXY protocol header at the start of `buf`, v1 or v2 as
+/// its first bytes say. Ok(None) while it is incomplete, else its length
+/// with the client address it conveys: None for a LOCAL connection, the
+/// balancer's own health check, or a source of another family (UNKNOWN,
+/// unix), which keep the balancer's address.
+pub fn header(buf: &[u8]) -> Result<Option<(usize, Option<SocketAddr>)>, &'static str> {
+    if buf.is_empty() {
+        Ok(None)
+    } else if begins(buf, V2_SIGNATURE) {
+        v2(buf)
+    } else if begins(buf, V1_PREFIX) {
+        v1(buf)
+    } else {
+        Err("no PROXY protocol header")
+    }
+}
+
+/// The v2 header a `proxy_protocol` route sends ahead of everything else
+/// on the upstream connection: the client's address as the source, the
+/// one it reached us on as the destination. Without both, a unix client,
+/// the family is UNSPEC and the upstream goes by the connection itself.
+pub fn v2_header(source: Option<SocketAddr>, destination: Option<SocketAddr>) -> Vec<u8> {
+    let mut out = V2_SIGNATURE.to_vec();
+    out.push(0x20 | V2_PROXY);
+    let Some((source, destination)) = source.zip(destination) else {
+        out.extend_from_slice(&[V2_UNSPEC << 4, 0, 0]);
+        return out;
+    };
+    let ip = |addr: SocketAddr| addr.ip().to_canonical();
+    match (ip(source), ip(destination)) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            out.extend_from_slice(&[V2_INET << 4 | V2_STREAM, 0, 12]);
+            out.extend_from_slice(&s.octets());
+            out.extend_from_slice(&d.octets());
+        }
+        // of different families both go as IPv6, IPv4 mapped
+        (s, d) => {
+            let v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            out.extend_from_slice(&[V2_INET6 << 4 | V2_STREAM, 0, 36]);
+            out.extend_from_slice(&v6(s).octets());
+            out.extend_from_slice(&v6(d).octets());
+        }
+    }
+    out.extend_from_slice(&source.port().to_be_bytes());
+    out.extend_from_slice(&destination.port().to_be_bytes());
+    out
+}
+
+/// Whether `buf` is `prefix`, or as much of it as came so far.
+fn begins(buf: &[u8], prefix: &[u8]) -> bool {
+    let n = buf.len().min(prefix.len());
+    buf[..n] == prefix[..n]
+}
+
+fn v1(buf: &[u8]) -> Result<Option<(usize, Option<SocketAddr>)>, &'static str> {
+    let window = &buf[..buf.len().min(V1_MAX)];
+    let Some(end) = window.windows(2).position(|w| w == b"\r\n") else {
+        return if buf.len() >= V1_MAX { Err("v1 line too long") } else { Ok(None) };
+    };
+    let line = std::str::from_utf8(&buf[..end]).map_err(|_| "v1 line not text")?;
+    let mut words = line.split(' ').skip(1);
+    let source = match words.next() {
+        // the rest of the line is to be ignored
+        Some("UNKNOWN") => None,
+        Some(family @ ("TCP4" | "TCP6")) => {
+            let mut next = || words.next().ok_or("v1 line too short");
+            let (source, _destination) = (next()?, next()?);
+            let (port, _) = (next()?, next()?);
+            if words.next().is_some() {
+                return Err("v1 line too long");
+            }
+            let ip = match family {
+                "TCP4" => source.parse::<Ipv4Addr>().map(IpAddr::from),
+                _ => source.parse::<Ipv6Addr>().map(IpAddr::from),
+            };
+            let ip = ip.map_err(|_| "v1 bad source address")?;
+            let port = port.parse::<u16>().map_err(|_| "v1 bad source port")?;
+            Some(SocketAddr::new(ip, port))
+        }
+        _ => return Err("v1 bad protocol"),
+    };
+    Ok(Some((end + 2, source)))
+}
+
+fn v2(buf: &[u8]) -> Result<Option<(usize, Option<SocketAddr>)>, &'static str> {
+    let Some(fixed) = buf.get(..V2_FIXED) else {
+        return Ok(None);
+    };
+    let (version, command) = (fixed[12] >> 4, fixed[12] & 0x0f);
+    if version != 2 {
+        return Err("v2 bad version");
+    }
+    let len = V2_FIXED + usize::from(u16::from_be_bytes([fixed[14], fixed[15]]));
+    if len > MAX_HEADER {
+        return Err("v2 header too long");
+    }
+    let Some(addresses) = buf.get(V2_FIXED..len) else {
+        return Ok(None);
+    };
+    let source = match command {
+        V2_LOCAL => None,
+        V2_PROXY => match fixed[13] >> 4 {
+            V2_INET => {
+                let a = addresses.get(..12).ok_or("v2 address block too short")?;
+                let ip = Ipv4Addr::new(a[0], a[1], a[2], a[3]);
+                Some(SocketAddr::new(ip.into(), u16::from_be_bytes([a[8], a[9]])))
+            }
+            V2_INET6 => {
+                let a = addresses.get(..36).ok_or("v2 address block too short")?;
+                let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&a[..16]).unwrap());
+                Some(SocketAddr::new(ip.to_canonical(), u16::from_be_bytes([a[32], a[33]])))
+            }
+            // AF_UNSPEC or AF_UNIX: nothing we could use as the client
+            _ => None,
+        },
+        _ => return Err("v2 bad command"),
+    };
+    Ok(Some((len, source)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    /// Asserts the header is incomplete for each proper prefix of `msg`.
+    fn incomplete_until_whole(msg: &[u8]) {
+        for n in 0..msg.len() {
+            assert_eq!(header(&msg[..n]), Ok(None), "{} of {:?}", n, msg);
+        }
+    }
+
+    #[test]
+    fn v1_lines() {
+        let line = b"PROXY TCP4 192.0.2.1 198.51.100.1 5555 443\r\nGET";
+        assert_eq!(header(line), Ok(Some((line.len() - 3, Some(addr("192.0.2.1:5555"))))));
+        incomplete_until_whole(&line[..line.len() - 3]);
+        let line = b"PROXY TCP6 2001:db8::1 2001:db8::2 5555 443\r\n";
+        assert_eq!(header(line), Ok(Some((line.len(), Some(addr("[2001:db8::1]:5555"))))));
+        let line = b"PROXY UNKNOWN whatever follows\r\n";
+        assert_eq!(header(line), Ok(Some((line.len(), None))));
+    }
+
+    #[test]
+    fn bad_v1_lines() {
+        for line in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.1 5555\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 5555 443 more\r\n",
+            b"PROXY TCP4 2001:db8::1 198.51.100.1 5555 443\r\n",
+            b"PROXY TCP6 192.0.2.1 198.51.100.1 5555 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 65536 443\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 5555 443\r\n",
+            b"GET / HTTP/1.1\r\n",
+        ] {
+            assert!(header(line).is_err(), "{:?}", String::from_utf8_lossy(line));
+        }
+        // no end in sight by the longest a line can be
+        assert_eq!(header(&[b"PROXY ".as_slice(), &[b'x'; V1_MAX - 7]].concat()), Ok(None));
+        assert!(header(&[b"PROXY ".as_slice(), &[b'x'; V1_MAX]].concat()).is_err());
+    }
+
+    #[test]
+    fn v2_headers_read_back_as_sent() {
+        for (source, destination) in [
+            ("192.0.2.1:5555", "198.51.100.1:443"),
+            ("[2001:db8::1]:5555", "[2001:db8::2]:443"),
+            // mixed families go as IPv6, and come back canonical
+            ("192.0.2.1:5555", "[2001:db8::2]:443"),
+        ] {
+            let msg = v2_header(Some(addr(source)), Some(addr(destination)));
+            assert_eq!(header(&msg), Ok(Some((msg.len(), Some(addr(source))))), "{}", source);
+            incomplete_until_whole(&msg);
+        }
+        let v4 = v2_header(Some(addr("192.0.2.1:5555")), Some(addr("198.51.100.1:443")));
+        assert_eq!(v4.len(), V2_FIXED + 12);
+        assert_eq!(&v4[V2_FIXED + 4..V2_FIXED + 8], [198, 51, 100, 1]);
+        assert_eq!(&v4[V2_FIXED + 8..], [0x15, 0xb3, 0x01, 0xbb]);
+        // a unix client has no address to give
+        let unspec = v2_header(None, Some(addr("198.51.100.1:443")));
+        assert_eq!((unspec.len(), unspec[13]), (V2_FIXED, V2_UNSPEC << 4));
+        assert_eq!(header(&unspec), Ok(Some((V2_FIXED, None))));
+    }
+
+    #[test]
+    fn v2_local_and_bad_headers() {
+        let mut local = v2_header(Some(addr("192.0.2.1:5555")), Some(addr("198.51.100.1:443")));
+        local[12] = 0x20 | V2_LOCAL;
+        assert_eq!(header(&local), Ok(Some((local.len(), None))));
+        let good = v2_header(Some(addr("192.0.2.1:5555")), Some(addr("198.51.100.1:443")));
+        let with = |at: usize, b: u8| {
+            let mut msg = good.clone();
+            msg[at] = b;
+            msg
+        };
+        assert!(header(&with(12, 0x10 | V2_PROXY)).is_err(), "version 1");
+        assert!(header(&with(12, 0x2f)).is_err(), "command");
+        // an INET block shorter than its addresses
+        assert!(header(&[&with(15, 4)[..V2_FIXED], &[0; 4]].concat()).is_err());
+        let mut huge = good[..V2_FIXED].to_vec();
+        huge[14..16].copy_from_slice(&(MAX_HEADER as u16).to_be_bytes());
+        assert!(header(&huge).is_err());
+        // TLVs after the addresses are skipped with the length
+        let mut tlv = good.clone();
+        tlv[15] += 3;
+        tlv.extend_from_slice(&[0x04, 0, 0]);
+        assert_eq!(header(&tlv), Ok(Some((tlv.len(), Some(addr("192.0.2.1:5555"))))));
+    }
+}
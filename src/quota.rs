@@ -0,0 +1,159 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs,
+    io::{self, Write},
+    path::Path,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+
+use crate::{admin::json_str, config::Config};
+
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+/// Bytes each user moved since the last reset, shared by every worker.
+static LEDGER: Mutex<Ledger> = Mutex::new(Ledger {
+    reset_hour: 0,
+    day: 0,
+    used: BTreeMap::new(),
+});
+
+struct Ledger {
+    /// `Config::quota_reset_hour`
+    reset_hour: u8,
+    /// the quota day `used` counts, see `day`
+    day: u64,
+    used: BTreeMap<String, u64>,
+}
+
+impl Ledger {
+    /// Starts over when the reset hour passed since the last call.
+    fn roll(&mut self, now: SystemTime) {
+        let today = day(now, self.reset_hour);
+        if today != self.day {
+            if !self.used.is_empty() {
+                info!("quota day over, {} users start from zero", self.used.len());
+            }
+            self.used.clear();
+            self.day = today;
+        }
+    }
+}
+
+/// Days since the epoch, each starting at `reset_hour` UTC.
+fn day(now: SystemTime, reset_hour: u8) -> u64 {
+    let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    secs.saturating_sub(u64::from(reset_hour) * 3600) / DAY_SECS
+}
+
+/// Takes the reset hour; the day changes with it, so changing the hour
+/// starts every user from zero.
+pub fn configure(config: &Config) {
+    let mut ledger = LEDGER.lock().unwrap();
+    ledger.reset_hour = config.quota_reset_hour;
+    ledger.roll(SystemTime::now());
+}
+
+/// Counts `bytes` for `user` in the day they moved in.
+pub fn charge(user: &str, bytes: u64) {
+    let mut ledger = LEDGER.lock().unwrap();
+    ledger.roll(SystemTime::now());
+    match ledger.used.get_mut(user) {
+        Some(used) => *used += bytes,
+        None => {
+            ledger.used.insert(user.to_owned(), bytes);
+        }
+    }
+}
+
+/// What `user` moved today.
+pub fn used(user: &str) -> u64 {
+    let mut ledger = LEDGER.lock().unwrap();
+    ledger.roll(SystemTime::now());
+    ledger.used.get(user).copied().unwrap_or(0)
+}
+
+/// The `/quota` body: each user with a quota in `quotas` or bytes moved
+/// today, by name.
+pub fn json(quotas: &BTreeMap<String, u64>) -> String {
+    let mut ledger = LEDGER.lock().unwrap();
+    let now = SystemTime::now();
+    ledger.roll(now);
+    let start = UNIX_EPOCH + Duration::from_secs(ledger.day * DAY_SECS + u64::from(ledger.reset_hour) * 3600);
+    let mut users = ledger.used.iter().map(|(u, &n)| (u.as_str(), n)).collect::<BTreeMap<_, _>>();
+    for user in quotas.keys() {
+        users.entry(user.as_str()).or_insert(0);
+    }
+    let mut out = format!(
+        r#"{{"day_start":"{}","resets_in_secs":{},"users":["#,
+        humantime::format_rfc3339_seconds(start),
+        (start + Duration::from_secs(DAY_SECS)).duration_since(now).unwrap_or_default().as_secs()
+    );
+    for (i, (user, used)) in users.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, r#"{{"user":{},"used":{},"#, json_str(user), used);
+        let _ = match quotas.get(*user) {
+            Some(quota) => write!(out, r#""daily_quota":{},"remaining":{}}}"#, quota, quota.saturating_sub(*used)),
+            None => write!(out, r#""daily_quota":null,"remaining":null}}"#),
+        };
+    }
+    out.push_str("]}");
+    out
+}
+
+/// Writes today's usage to `path`, through a temporary file so a crash
+/// leaves the previous one: a `day <day> <reset hour>` line, then one
+/// `<bytes> <user>` line per user.
+pub fn save(path: &Path) -> io::Result<()> {
+    let text = {
+        let mut ledger = LEDGER.lock().unwrap();
+        ledger.roll(SystemTime::now());
+        let mut text = format!("day {} {}\n", ledger.day, ledger.reset_hour);
+        for (user, used) in &ledger.used {
+            let _ = writeln!(text, "{} {}", used, user);
+        }
+        text
+    };
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(text.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+/// Takes back the usage `save` wrote, when it is for the current day and
+/// reset hour; a missing file is a first start.
+pub fn restore(path: &Path) {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+        Err(e) => {
+            warn!("cannot read quota state {}, start from zero: {}", path.display(), e);
+            return;
+        }
+    };
+    let mut lines = text.lines();
+    let mut ledger = LEDGER.lock().unwrap();
+    ledger.roll(SystemTime::now());
+    let header = lines.next().and_then(|l| l.strip_prefix("day ")).and_then(|l| l.split_once(' '));
+    let saved = header.and_then(|(d, h)| Some((d.parse::<u64>().ok()?, h.parse::<u8>().ok()?)));
+    if saved != Some((ledger.day, ledger.reset_hour)) {
+        info!("quota state {} is from another day, start from zero", path.display());
+        return;
+    }
+    let mut restored = 0;
+    for line in lines {
+        let Some((used, user)) = line.split_once(' ').and_then(|(n, u)| Some((n.parse::<u64>().ok()?, u))) else {
+            warn!("quota state {}: skipping bad line {:?}", path.display(), line);
+            continue;
+        };
+        *ledger.used.entry(user.to_owned()).or_insert(0) += used;
+        restored += 1;
+    }
+    info!("quota state: today's usage of {} users from {}", restored, path.display());
+}
@@ -0,0 +1,255 @@
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use nix::libc;
+use serde::{Serialize, Serializer};
+
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// A moment as the acl time windows look at it: the day of the week,
+/// Monday being 0, and the minute of that day. `acl::check` takes it as
+/// an argument, so it can be called for any moment, not only now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WallTime {
+    pub day: u8,
+    pub minute: u16,
+}
+
+/// The zone `Config::acl_timezone` reads the clock in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeZone {
+    Utc,
+    /// the system's, from TZ or /etc/localtime, daylight saving included
+    Local,
+    /// minutes east of UTC
+    Offset(i32),
+}
+
+impl TimeZone {
+    /// `now` on the wall clock of this zone.
+    pub fn wall(self, now: SystemTime) -> WallTime {
+        let secs = match now.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs() as i64,
+            Err(e) => -(e.duration().as_secs() as i64),
+        };
+        let offset = match self {
+            TimeZone::Utc => 0,
+            TimeZone::Offset(minutes) => i64::from(minutes) * 60,
+            TimeZone::Local => local_offset(secs),
+        };
+        let local = secs + offset;
+        // 1970-01-01 was a Thursday
+        WallTime {
+            day: (local.div_euclid(86_400) + 3).rem_euclid(7) as u8,
+            minute: (local.rem_euclid(86_400) / 60) as u16,
+        }
+    }
+}
+
+/// Seconds east of UTC the system zone is at `secs` since the epoch.
+fn local_offset(secs: i64) -> i64 {
+    let t = secs as libc::time_t;
+    // SAFETY: localtime_r only writes the tm it is given
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&t, &mut tm) }.is_null() {
+        return 0;
+    }
+    tm.tm_gmtoff
+}
+
+impl FromStr for TimeZone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || format!("invalid time zone {:?}, expected utc, local or an offset like +02:00", s);
+        match s {
+            "utc" | "UTC" => return Ok(TimeZone::Utc),
+            "local" => return Ok(TimeZone::Local),
+            _ => {}
+        }
+        let (sign, rest) = match s.as_bytes().first() {
+            Some(b'+') => (1, &s[1..]),
+            Some(b'-') => (-1, &s[1..]),
+            _ => return Err(bad()),
+        };
+        let minutes = clock_minutes(rest).ok_or_else(bad)?;
+        if minutes > 14 * 60 {
+            return Err(format!("time zone offset {} is more than 14 hours", s));
+        }
+        Ok(TimeZone::Offset(sign * i32::from(minutes)))
+    }
+}
+
+impl Display for TimeZone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            TimeZone::Utc => f.write_str("utc"),
+            TimeZone::Local => f.write_str("local"),
+            TimeZone::Offset(m) => {
+                let sign = if m < 0 { '-' } else { '+' };
+                write!(f, "{}{:02}:{:02}", sign, m.abs() / 60, m.abs() % 60)
+            }
+        }
+    }
+}
+
+/// `HH:MM` as minutes of the day, 24:00 included.
+fn clock_minutes(s: &str) -> Option<u16> {
+    let (h, m) = s.split_once(':')?;
+    if h.is_empty() || h.len() > 2 || m.len() != 2 {
+        return None;
+    }
+    let (h, m) = (h.parse::<u16>().ok()?, m.parse::<u16>().ok()?);
+    match (h, m) {
+        (24, 0) => Some(24 * 60),
+        (0..=23, 0..=59) => Some(h * 60 + m),
+        _ => None,
+    }
+}
+
+/// The `hours` of an acl entry, `08:00-18:00`: from the first minute up
+/// to the last, which is not part of it. A window starting later than it
+/// ends runs past midnight, `22:00-06:00`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hours {
+    from: u16,
+    to: u16,
+}
+
+impl Hours {
+    pub fn contains(self, minute: u16) -> bool {
+        if self.from < self.to {
+            (self.from..self.to).contains(&minute)
+        } else {
+            minute >= self.from || minute < self.to
+        }
+    }
+}
+
+impl FromStr for Hours {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || format!("invalid hours {:?}, expected a range like 08:00-18:00", s);
+        let (from, to) = s.split_once('-').ok_or_else(bad)?;
+        let (from, to) = (clock_minutes(from.trim()).ok_or_else(bad)?, clock_minutes(to.trim()).ok_or_else(bad)?);
+        if from == 24 * 60 {
+            return Err(format!("hours {:?} start at 24:00, write 00:00", s));
+        }
+        if from == to % (24 * 60) {
+            return Err(format!("hours {:?} cover no time, or all of it; leave hours out for the whole day", s));
+        }
+        Ok(Hours { from, to })
+    }
+}
+
+impl Display for Hours {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}-{:02}:{:02}", self.from / 60, self.from % 60, self.to / 60, self.to % 60)
+    }
+}
+
+/// The `days` of an acl entry, bit 0 for Monday.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Days(u8);
+
+impl Days {
+    /// From items like `"mon-fri"` and `"sun"`; a range may run over the
+    /// weekend, `"fri-mon"`.
+    pub fn parse<S: AsRef<str>>(items: &[S]) -> Result<Days, String> {
+        if items.is_empty() {
+            return Err("days is empty, the entry would never apply; leave days out for every day".to_owned());
+        }
+        let day = |s: &str| {
+            DAY_NAMES
+                .iter()
+                .position(|d| d.eq_ignore_ascii_case(s.trim()))
+                .ok_or_else(|| format!("unknown day {:?}, expected mon, tue, wed, thu, fri, sat or sun", s.trim()))
+        };
+        let mut bits = 0u8;
+        for item in items {
+            let (from, to) = match item.as_ref().split_once('-') {
+                Some((from, to)) => (day(from)?, day(to)?),
+                None => (day(item.as_ref())?, day(item.as_ref())?),
+            };
+            let mut d = from;
+            loop {
+                bits |= 1 << d;
+                if d == to {
+                    break;
+                }
+                d = (d + 1) % 7;
+            }
+        }
+        Ok(Days(bits))
+    }
+
+    pub fn contains(self, day: u8) -> bool {
+        self.0 & 1 << day != 0
+    }
+}
+
+/// As the list the config takes, one name per day.
+impl Serialize for Days {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_seq((0..7).filter(|&d| self.contains(d)).map(|d| DAY_NAMES[usize::from(d)]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// 2024-01-01T00:00:00Z, a Monday.
+    const MONDAY: u64 = 1_704_067_200;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn wall_time_in_zones() {
+        assert_eq!(TimeZone::Utc.wall(at(MONDAY)), WallTime { day: 0, minute: 0 });
+        assert_eq!(TimeZone::Utc.wall(at(MONDAY + 6 * 86_400 + 86_399)), WallTime { day: 6, minute: 1439 });
+        // east of UTC it is already 02:00
+        assert_eq!(TimeZone::Offset(120).wall(at(MONDAY)), WallTime { day: 0, minute: 120 });
+        // west of UTC it is still Sunday
+        assert_eq!(TimeZone::Offset(-300).wall(at(MONDAY)), WallTime { day: 6, minute: 19 * 60 });
+        assert_eq!(TimeZone::Utc.wall(UNIX_EPOCH), WallTime { day: 3, minute: 0 });
+    }
+
+    #[test]
+    fn time_zones_parse() {
+        assert_eq!("utc".parse(), Ok(TimeZone::Utc));
+        assert_eq!("+05:30".parse(), Ok(TimeZone::Offset(330)));
+        assert_eq!("-08:00".parse(), Ok(TimeZone::Offset(-480)));
+        assert_eq!(TimeZone::Offset(-480).to_string(), "-08:00");
+        assert!("+15:00".parse::<TimeZone>().is_err());
+        assert!("05:00".parse::<TimeZone>().is_err());
+        assert!("Europe/Berlin".parse::<TimeZone>().is_err());
+    }
+
+    #[test]
+    fn nonsensical_hours_are_refused() {
+        for bad in ["08:00", "8-18", "08:00-25:00", "07:60-08:00", "24:00-06:00", "08:00-08:00", "00:00-24:00"] {
+            assert!(bad.parse::<Hours>().is_err(), "{}", bad);
+        }
+        let h: Hours = " 9:05 - 17:30 ".parse().unwrap();
+        assert_eq!(h.to_string(), "09:05-17:30");
+    }
+
+    #[test]
+    fn days_parse() {
+        let d = Days::parse(&["mon-wed", "sat"]).unwrap();
+        let days: Vec<_> = (0..7).map(|day| d.contains(day)).collect();
+        assert_eq!(days, [true, true, true, false, false, true, false]);
+        assert!(Days::parse::<&str>(&[]).is_err());
+        assert!(Days::parse(&["monday"]).is_err());
+        assert!(Days::parse(&["Fri-MON"]).unwrap().contains(6));
+    }
+}
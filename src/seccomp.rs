@@ -0,0 +1,343 @@
+use std::{
+    ffi::{c_int, c_long, c_void},
+    io,
+    mem::{self, offset_of},
+};
+
+use log::info;
+use nix::{
+    libc::{self, seccomp_data, sock_filter, sock_fprog},
+    sys::{
+        prctl,
+        signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
+    },
+};
+
+use crate::{busy_poll::PollMode, config::Config, err::Fatal};
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+compile_error!("the seccomp feature knows the syscalls of x86_64 and aarch64 only");
+
+/// `AUDIT_ARCH_*` of the build: the machine, 64 bit, little endian.
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// Syscalls the proxy makes once serving, whatever the config. Everything
+/// before, binding, daemonizing, dropping privileges and starting threads,
+/// has happened by the time the filter goes in.
+const SERVING: &[c_long] = &[
+    // the event loops, their wakeups and the signal thread
+    libc::SYS_epoll_pwait,
+    libc::SYS_epoll_ctl,
+    libc::SYS_futex,
+    libc::SYS_rt_sigtimedwait,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_restart_syscall,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    // clients, upstreams, the admin endpoint and the data path; std
+    // writes sockets with send, so statsd, syslog and sd_notify datagrams
+    // go out the same way
+    libc::SYS_accept4,
+    libc::SYS_socket,
+    libc::SYS_connect,
+    libc::SYS_shutdown,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockopt,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_recvfrom,
+    libc::SYS_sendto,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmsg,
+    libc::SYS_splice,
+    libc::SYS_pipe2,
+    libc::SYS_close,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    // getaddrinfo: hosts and resolv.conf, the resolver's queries and the
+    // netlink dump behind AI_ADDRCONFIG, which refresh_own walks too
+    libc::SYS_sendmmsg,
+    libc::SYS_ppoll,
+    libc::SYS_bind,
+    libc::SYS_uname,
+    // the allocator, and the thread stacks going away at shutdown
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_getrandom,
+    libc::SYS_sigaltstack,
+    // reloads, log and capture files and their rotation, the state files,
+    // the /proc reads behind the usage sample
+    libc::SYS_openat,
+    libc::SYS_lseek,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_getdents64,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_unlinkat,
+    libc::SYS_fsync,
+    libc::SYS_sched_getaffinity,
+    // `violation`, and the way out
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_tgkill,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+];
+
+/// What glibc still calls the old way on x86_64.
+#[cfg(target_arch = "x86_64")]
+const LEGACY: &[c_long] = &[
+    libc::SYS_epoll_wait,
+    libc::SYS_poll,
+    libc::SYS_open,
+    libc::SYS_stat,
+    libc::SYS_lstat,
+    libc::SYS_rename,
+    libc::SYS_unlink,
+];
+#[cfg(target_arch = "aarch64")]
+const LEGACY: &[c_long] = &[];
+
+/// The end of a thread's start, registering with the kernel and naming
+/// it, which the signal thread may still be in as the filter goes in;
+/// prctl is let through for PR_SET_NAME only.
+const THREAD_START: &[c_long] = &[libc::SYS_set_robust_list, libc::SYS_rseq];
+
+/// Installs the `Config::seccomp` filter on every thread. A syscall off
+/// the list raises SIGSYS, which `violation` turns into the process dying
+/// of it. Must come last in startup, once every thread the config needs is
+/// running.
+pub fn install(config: &Config) -> Result<(), Fatal> {
+    let filter = program(config);
+    let fail = |what: &str, e: io::Error| Fatal::Runtime(format!("seccomp: {} failed: {}", what, e));
+    let action = SigAction::new(
+        SigHandler::SigAction(violation),
+        SaFlags::SA_SIGINFO | SaFlags::SA_RESETHAND,
+        SigSet::empty(),
+    );
+    // SAFETY: the handler only makes async-signal-safe calls
+    unsafe { sigaction(Signal::SIGSYS, &action) }.map_err(|e| fail("sigaction", e.into()))?;
+    prctl::set_no_new_privs().map_err(|e| fail("PR_SET_NO_NEW_PRIVS", e.into()))?;
+    let prog = sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_ptr().cast_mut(),
+    };
+    // SAFETY: prog points at a sound program that outlives the call
+    let synced = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &prog,
+        )
+    };
+    match synced {
+        0 => {}
+        -1 => return Err(fail("installing the filter", io::Error::last_os_error())),
+        tid => return Err(Fatal::Runtime(format!("seccomp: thread {} has a filter of its own", tid))),
+    }
+    info!("seccomp: filter of {} instructions on every thread, other syscalls kill the proxy", filter.len());
+    Ok(())
+}
+
+/// The BPF program: a foreign architecture or x32 syscall is killed
+/// outright, the lists are allowed, the rest traps.
+fn program(config: &Config) -> Vec<sock_filter> {
+    let mut p = Program::default();
+    p.load(offset_of!(seccomp_data, arch));
+    p.jump(libc::BPF_JEQ, AUDIT_ARCH, 1, 0);
+    p.ret(libc::SECCOMP_RET_KILL_PROCESS);
+    p.load(offset_of!(seccomp_data, nr));
+    #[cfg(target_arch = "x86_64")]
+    {
+        // __X32_SYSCALL_BIT, the same calls under other numbers
+        p.jump(libc::BPF_JGE, 0x4000_0000, 0, 1);
+        p.ret(libc::SECCOMP_RET_KILL_PROCESS);
+    }
+    for &nr in SERVING.iter().chain(LEGACY).chain(THREAD_START) {
+        p.allow(nr);
+    }
+    p.allow_arg(libc::SYS_prctl, libc::BPF_JEQ, libc::PR_SET_NAME as u32);
+    if config.poll_mode == (PollMode::Spin { yield_cpu: true }) {
+        p.allow(libc::SYS_sched_yield);
+    }
+    // the otlp export thread, started again on each reload
+    if config.otlp_endpoint.is_some() {
+        // glibc falls back to clone, whose flags the filter can see
+        p.jump(libc::BPF_JEQ, libc::SYS_clone3 as u32, 0, 1);
+        p.ret(libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32);
+        p.allow_arg(libc::SYS_clone, libc::BPF_JSET, libc::CLONE_THREAD as u32);
+    }
+    p.ret(libc::SECCOMP_RET_TRAP);
+    p.0
+}
+
+#[derive(Default)]
+struct Program(Vec<sock_filter>);
+
+impl Program {
+    fn push(&mut self, code: u32, k: u32, jt: u8, jf: u8) {
+        self.0.push(sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        });
+    }
+
+    /// The 32 bits of `seccomp_data` at `offset` into the accumulator.
+    fn load(&mut self, offset: usize) {
+        self.push(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, offset as u32, 0, 0);
+    }
+
+    /// Compares the accumulator with `k`, skipping `jt` instructions when
+    /// the test holds and `jf` when not.
+    fn jump(&mut self, op: u32, k: u32, jt: u8, jf: u8) {
+        self.push(libc::BPF_JMP | op | libc::BPF_K, k, jt, jf);
+    }
+
+    fn ret(&mut self, action: u32) {
+        self.push(libc::BPF_RET | libc::BPF_K, action, 0, 0);
+    }
+
+    /// Lets syscall `nr` through, with the number in the accumulator.
+    fn allow(&mut self, nr: c_long) {
+        self.jump(libc::BPF_JEQ, nr as u32, 0, 1);
+        self.ret(libc::SECCOMP_RET_ALLOW);
+    }
+
+    /// Lets syscall `nr` through when `op` with `k` holds for the low half
+    /// of its first argument, both architectures being little endian, and
+    /// traps it when not.
+    fn allow_arg(&mut self, nr: c_long, op: u32, k: u32) {
+        self.jump(libc::BPF_JEQ, nr as u32, 0, 5);
+        self.load(offset_of!(seccomp_data, args));
+        self.jump(op, k, 0, 1);
+        self.ret(libc::SECCOMP_RET_ALLOW);
+        self.ret(libc::SECCOMP_RET_TRAP);
+        self.load(offset_of!(seccomp_data, nr));
+    }
+}
+
+/// SIGSYS from the filter. Names the syscall on stderr, the log file is
+/// not safe to touch from a handler and may be what the thread was in the
+/// middle of, then sends SIGSYS again, which SA_RESETHAND left to kill the
+/// process once the handler returns.
+extern "C" fn violation(_: c_int, info: *mut libc::siginfo_t, _: *mut c_void) {
+    // si_syscall, which libc does not name: after si_signo, si_errno,
+    // si_code and the padding to the union comes si_call_addr
+    // SAFETY: the kernel filled in a SIGSYS siginfo_t
+    let nr = unsafe { *info.cast::<u8>().add(16 + mem::size_of::<usize>()).cast::<c_int>() };
+    let mut digits = [0u8; 10];
+    let mut i = digits.len();
+    let mut n = nr.unsigned_abs();
+    loop {
+        i -= 1;
+        digits[i] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    for part in [&b"seccomp: syscall "[..], &digits[i..], b" is not allowed, killing the proxy\n"] {
+        // SAFETY: write and tgkill are async-signal-safe and on the list
+        unsafe { libc::write(libc::STDERR_FILENO, part.as_ptr().cast(), part.len()) };
+    }
+    unsafe { libc::syscall(libc::SYS_tgkill, libc::getpid(), libc::gettid(), libc::SIGSYS) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `filter` on a syscall the way the kernel would, for the few
+    /// classic BPF instructions `Program` emits.
+    fn run(filter: &[sock_filter], arch: u32, nr: c_long, arg0: u64) -> u32 {
+        let mut data = [0u8; mem::size_of::<seccomp_data>()];
+        data[offset_of!(seccomp_data, nr)..][..4].copy_from_slice(&(nr as i32).to_ne_bytes());
+        data[offset_of!(seccomp_data, arch)..][..4].copy_from_slice(&arch.to_ne_bytes());
+        data[offset_of!(seccomp_data, args)..][..8].copy_from_slice(&arg0.to_ne_bytes());
+        let (mut acc, mut pc) = (0u32, 0usize);
+        loop {
+            let i = filter[pc];
+            let (code, k) = (u32::from(i.code), i.k);
+            pc += 1;
+            match code & 0x07 {
+                libc::BPF_LD => {
+                    let at = k as usize;
+                    acc = u32::from_ne_bytes(data[at..at + 4].try_into().unwrap());
+                }
+                libc::BPF_JMP => {
+                    let holds = match code & 0xf0 {
+                        libc::BPF_JEQ => acc == k,
+                        libc::BPF_JGE => acc >= k,
+                        libc::BPF_JSET => acc & k != 0,
+                        op => panic!("jump op {:#x}", op),
+                    };
+                    pc += usize::from(if holds { i.jt } else { i.jf });
+                }
+                libc::BPF_RET => return k,
+                class => panic!("instruction class {:#x}", class),
+            }
+        }
+    }
+
+    fn verdict(config: &Config, nr: c_long, arg0: u64) -> u32 {
+        run(&program(config), AUDIT_ARCH, nr, arg0)
+    }
+
+    #[test]
+    fn the_lists_pass_and_the_rest_traps() {
+        let config = Config::default();
+        for &nr in SERVING.iter().chain(LEGACY).chain(THREAD_START) {
+            assert_eq!(verdict(&config, nr, 0), libc::SECCOMP_RET_ALLOW, "syscall {}", nr);
+        }
+        for nr in [libc::SYS_execve, libc::SYS_ptrace, libc::SYS_clone, libc::SYS_clone3, libc::SYS_sched_yield] {
+            assert_eq!(verdict(&config, nr, 0), libc::SECCOMP_RET_TRAP, "syscall {}", nr);
+        }
+    }
+
+    #[test]
+    fn foreign_calls_are_killed() {
+        let filter = program(&Config::default());
+        // i386 on x86_64, arm on aarch64: either way not the build's
+        assert_eq!(run(&filter, 0x4000_0003, libc::SYS_read, 0), libc::SECCOMP_RET_KILL_PROCESS);
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(run(&filter, AUDIT_ARCH, 0x4000_0000 | libc::SYS_read, 0), libc::SECCOMP_RET_KILL_PROCESS);
+    }
+
+    #[test]
+    fn prctl_only_names_threads() {
+        let config = Config::default();
+        assert_eq!(verdict(&config, libc::SYS_prctl, libc::PR_SET_NAME as u64), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(verdict(&config, libc::SYS_prctl, libc::PR_SET_DUMPABLE as u64), libc::SECCOMP_RET_TRAP);
+        // the filter checks what it is allowed to, not the next syscall
+        assert_eq!(verdict(&config, libc::SYS_read, libc::PR_SET_DUMPABLE as u64), libc::SECCOMP_RET_ALLOW);
+    }
+
+    #[test]
+    fn optional_paths_open_their_syscalls() {
+        let spin = Config { poll_mode: PollMode::Spin { yield_cpu: true }, ..Config::default() };
+        assert_eq!(verdict(&spin, libc::SYS_sched_yield, 0), libc::SECCOMP_RET_ALLOW);
+        let otlp = Config { otlp_endpoint: Some("http://127.0.0.1:4318".to_owned()), ..Config::default() };
+        // clone3 hides its flags, glibc retries with clone on ENOSYS
+        assert_eq!(verdict(&otlp, libc::SYS_clone3, 0), libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32);
+        assert_eq!(verdict(&otlp, libc::SYS_clone, libc::CLONE_THREAD as u64), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(verdict(&otlp, libc::SYS_clone, 0), libc::SECCOMP_RET_TRAP);
+    }
+}
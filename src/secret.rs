@@ -0,0 +1,114 @@
+use std::{
+    env, fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Serialize, Serializer};
+
+/// A password or key, shown as `<redacted>` by Debug, Display and
+/// `--print-config`; `expose` has the value.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: String) -> Secret {
+        Secret(value)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str("<redacted>")
+    }
+}
+
+/// Where a secret setting comes from: the `<key>` itself, `<key>_file`
+/// naming a file that holds it, or `<key>_env` naming an environment
+/// variable that does. Files and variables are read by `read`, at startup
+/// and on every reload, so a rotated secret takes effect on SIGHUP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SecretSource {
+    Inline(Secret),
+    File(PathBuf),
+    Env(String),
+}
+
+impl SecretSource {
+    /// The one of `inline`, `file` and `env` a config layer sets, Err
+    /// when it sets more than one. `key` names the setting.
+    pub fn pick(
+        key: &str,
+        inline: Option<String>,
+        file: Option<PathBuf>,
+        env: Option<String>,
+    ) -> Result<Option<SecretSource>, String> {
+        let set = [inline.is_some(), file.is_some(), env.is_some()];
+        if set.iter().filter(|&&s| s).count() > 1 {
+            return Err(format!("{0}, {0}_file and {0}_env are alternatives, set one", key));
+        }
+        Ok(inline
+            .map(|v| SecretSource::Inline(Secret::new(v)))
+            .or(file.map(SecretSource::File))
+            .or(env.map(SecretSource::Env)))
+    }
+
+    /// The secret, read from its file or variable now. A file's trailing
+    /// line break is not part of it; an empty one is an error, as is a
+    /// variable that is unset or empty.
+    pub fn read(&self, key: &str) -> Result<Secret, String> {
+        let value = match self {
+            SecretSource::Inline(s) => return Ok(s.clone()),
+            SecretSource::File(path) => {
+                let text = fs::read_to_string(path)
+                    .map_err(|e| format!("{}_file: cannot read {}: {}", key, path.display(), e))?;
+                let value = text.trim_end_matches(['\n', '\r']).to_owned();
+                if value.is_empty() {
+                    return Err(format!("{}_file: {} is empty", key, path.display()));
+                }
+                value
+            }
+            SecretSource::Env(name) => match env::var(name) {
+                Ok(value) if !value.is_empty() => value,
+                Ok(_) => return Err(format!("{}_env: {} is empty", key, name)),
+                Err(env::VarError::NotPresent) => return Err(format!("{}_env: {} is not set", key, name)),
+                Err(env::VarError::NotUnicode(_)) => return Err(format!("{}_env: {} is not UTF-8", key, name)),
+            },
+        };
+        Ok(Secret::new(value))
+    }
+
+    /// The file it is read from, for `lockdown` to leave readable.
+    pub fn file(&self) -> Option<&Path> {
+        match self {
+            SecretSource::File(path) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// Inserts it into a `--print-config` table as `key`, `<key>_file` or
+    /// `<key>_env`, an inline value redacted.
+    pub fn render(&self, key: &str, table: &mut toml::Table) {
+        let (key, value) = match self {
+            SecretSource::Inline(s) => (key.to_owned(), s.to_string()),
+            SecretSource::File(path) => (format!("{}_file", key), path.display().to_string()),
+            SecretSource::Env(name) => (format!("{}_env", key), name.clone()),
+        };
+        table.insert(key, value.into());
+    }
+}
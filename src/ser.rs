@@ -0,0 +1,112 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{Display, Write},
+    time::Duration,
+};
+
+use serde::{Serialize, Serializer};
+
+use crate::admin::json_str;
+
+// Serializers for `--print-config`, writing values the way the config
+// file takes them so the output reads like one.
+
+/// Through `Display`, which prints what `FromStr` parses.
+pub fn display<S: Serializer, T: Display>(v: &T, s: S) -> Result<S::Ok, S::Error> {
+    s.collect_str(v)
+}
+
+pub fn display_opt<S: Serializer, T: Display>(v: &Option<T>, s: S) -> Result<S::Ok, S::Error> {
+    match v {
+        Some(v) => s.collect_str(v),
+        None => s.serialize_none(),
+    }
+}
+
+pub fn duration<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&format_duration(*d))
+}
+
+pub fn duration_opt<S: Serializer>(d: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
+    match d {
+        Some(d) => duration(d, s),
+        None => s.serialize_none(),
+    }
+}
+
+/// Octal like `0660`, as `parse_mode` takes it.
+pub fn mode_opt<S: Serializer>(m: &Option<u32>, s: S) -> Result<S::Ok, S::Error> {
+    match m {
+        Some(m) => s.serialize_str(&format!("0{:o}", m)),
+        None => s.serialize_none(),
+    }
+}
+
+/// For `skip_serializing_if`, a flag left at its default of true.
+pub fn is_true(b: &bool) -> bool {
+    *b
+}
+
+/// In key order rather than the hash order, which changes between runs.
+pub fn sorted<S: Serializer, V: Serialize>(m: &HashMap<String, V>, s: S) -> Result<S::Ok, S::Error> {
+    m.iter().collect::<BTreeMap<_, _>>().serialize(s)
+}
+
+/// The largest of h, m, s and ms that holds `d` exactly.
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if d.subsec_nanos() != 0 {
+        format!("{}ms", d.as_millis())
+    } else if secs != 0 && secs.is_multiple_of(3600) {
+        format!("{}h", secs / 3600)
+    } else if secs != 0 && secs.is_multiple_of(60) {
+        format!("{}m", secs / 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// `value` as JSON, tables keeping their key order.
+pub fn to_json(value: &toml::Value) -> String {
+    let mut out = String::new();
+    write_json(&mut out, value);
+    out
+}
+
+fn write_json(out: &mut String, value: &toml::Value) {
+    match value {
+        toml::Value::String(s) => out.push_str(&json_str(s)),
+        toml::Value::Integer(n) => {
+            let _ = write!(out, "{}", n);
+        }
+        toml::Value::Float(f) => {
+            let _ = write!(out, "{}", f);
+        }
+        toml::Value::Boolean(b) => {
+            let _ = write!(out, "{}", b);
+        }
+        toml::Value::Datetime(d) => out.push_str(&json_str(&d.to_string())),
+        toml::Value::Array(items) => {
+            out.push('[');
+            for (i, v) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_json(out, v);
+            }
+            out.push(']');
+        }
+        toml::Value::Table(table) => {
+            out.push('{');
+            for (i, (k, v)) in table.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&json_str(k));
+                out.push(':');
+                write_json(out, v);
+            }
+            out.push('}');
+        }
+    }
+}
@@ -1,181 +1,862 @@
 use std::{
     borrow::Cow,
-    cell::{Ref, RefCell},
-    collections::HashMap,
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
     fmt::Display,
-    fs::{self, OpenOptions},
-    io::{self, BufRead, BufReader, Bytes, Cursor, ErrorKind, Read, Write},
+    io::{self, ErrorKind, Read, Write},
     net::{IpAddr, SocketAddr},
-    os::{
-        self,
-        fd::{AsFd, AsRawFd, OwnedFd, RawFd},
-    },
-    process::Output,
+    os::fd::{AsFd, AsRawFd, OwnedFd, RawFd},
+    path::{Path, PathBuf},
     rc::Rc,
-    time::Instant,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
-use log::{debug, error, info};
-use mio::{event::Event, net::TcpStream, Interest, Registry, Token};
+use log::{debug, info, warn};
+use mio::{
+    net::{TcpStream, UnixStream},
+    Interest, Registry, Token,
+};
 use nix::{
     errno::Errno,
     fcntl::{splice, OFlag, SpliceFFlags},
     unistd::pipe2,
 };
 
-use crate::{dns::DNS, err};
+use crate::{
+    acl::{self, AclAction, AclRule, Matched},
+    acl_hits,
+    audit_log::{self, Denial},
+    auth::Credentials,
+    bandwidth::{self, Throttle},
+    bans,
+    capture::{Capture, CaptureError},
+    client::{self, ClientStream, Peer},
+    config::Config,
+    detect::{self, FirstBytes, Protocol, Speaks},
+    dns::DNS,
+    err::{ErrorCategory, Side, SpliceError},
+    geoip::Place,
+    host_check::{self, HostCheck},
+    internal_addrs,
+    mptcp,
+    parent::{self, ParentProxy, Via},
+    parent_health,
+    profile::Profile,
+    proxy_protocol,
+    quota,
+    schedule::WallTime,
+    sni::{self, Hello, SniCheck, TransparentSni},
+    socks::{self, Handshake, Reply, Version},
+    stats::{ConnectFailure, Phase, TrafficClass, WorkerStats},
+    timeouts::SessionTimeouts,
+    timer::TimerId,
+    token::TokenSpace,
+    transparent,
+    udp_relay::{self, Association},
+    upstream::UpStream,
+    websocket,
+};
+#[cfg(feature = "tls")]
+use crate::tls::{self, ClientContext, TlsStream};
 
 pub type SessionRegistry = HashMap<Token, Rc<RefCell<Session>>>;
 
+/// Cap on an HTTP parent proxy's answer to our CONNECT.
+pub const MAX_PARENT_HEAD: usize = 16 * 1024;
+
+/// `Session::id` of the next session accepted, by any worker.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
 #[derive(Debug, Clone, Copy)]
 pub enum State {
     Piping,
-    Head,
+    /// upstream connect issued, waiting for its writable edge
+    Connecting,
+    /// our CONNECT went to the parent proxy, waiting for its answer; to a
+    /// SOCKS5 one, the step of `Session::parent_socks`
+    ParentHandshake,
+    /// on a `proxy_protocol` listener, the load balancer's header is not
+    /// read yet; `client` is the balancer until it is
+    ProxyHeader,
+    /// on a TLS listener, the client's handshake is not complete; its
+    /// request is read through the TLS session once it is
+    TlsHandshake,
+    /// the client's request is being read, in what it speaks
+    Head(Speaks),
+    /// a SOCKS5 UDP association is relaying, see `Session::udp`; the
+    /// client's connection only holds it open
+    Associated,
+}
+
+/// What the client got, for the access log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// no destination yet, the head never completed
+    Pending,
+    /// the tunnel is up, or the request was forwarded
+    Established,
+    /// turned away by policy or a limit
+    Denied,
+    /// the destination could not be reached; the status the client was
+    /// answered with, None when the connection was just closed
+    Failed(Option<u16>),
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Pending => f.write_str("-"),
+            Outcome::Established => f.write_str("established"),
+            Outcome::Denied => f.write_str("denied"),
+            Outcome::Failed(None) => f.write_str("failed"),
+            Outcome::Failed(Some(code)) => write!(f, "failed-{}", code),
+        }
+    }
+}
+
+/// Why a session ended. The first cause seen wins: once one side hung up
+/// the errors that follow on the other are a consequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    ClientClosed,
+    UpstreamClosed,
+    Idle,
+    /// a socket, connect or protocol error
+    Error,
+    /// the worker stopped with the session still open
+    Shutdown,
+    /// refused at accept, `max_sessions` was reached
+    MaxSessions,
+    /// the destination is not allowed, see `acl::check`
+    Denied,
+    /// an acl time window refused the destination after the session
+    /// started, see `Config::acl_window_close`
+    AclWindow,
+    /// established longer ago than `timeouts.lifetime`
+    LifetimeExceeded,
+    /// the admin endpoint banned the client and asked to close its
+    /// sessions, `PUT /bans/<ip>?close=true`
+    PolicyDenied,
+    /// the TLS handshake settled on h2: the connection goes on as HTTP/2,
+    /// each CONNECT on it a session of its own
+    Http2,
+}
+
+impl CloseReason {
+    pub const ALL: [CloseReason; 11] = [
+        CloseReason::ClientClosed,
+        CloseReason::UpstreamClosed,
+        CloseReason::Idle,
+        CloseReason::Error,
+        CloseReason::Shutdown,
+        CloseReason::MaxSessions,
+        CloseReason::Denied,
+        CloseReason::AclWindow,
+        CloseReason::LifetimeExceeded,
+        CloseReason::PolicyDenied,
+        CloseReason::Http2,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CloseReason::ClientClosed => "client-closed",
+            CloseReason::UpstreamClosed => "upstream-closed",
+            CloseReason::Idle => "idle-timeout",
+            CloseReason::Error => "error",
+            CloseReason::Shutdown => "shutdown",
+            CloseReason::MaxSessions => "max-sessions",
+            CloseReason::Denied => "denied",
+            CloseReason::AclWindow => "acl-window",
+            CloseReason::LifetimeExceeded => "lifetime-exceeded",
+            CloseReason::PolicyDenied => "policy-denied",
+            CloseReason::Http2 => "http2",
+        }
+    }
+}
+
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drain {
+    /// source would block or destination is full; the next edge resumes
+    Done,
+    /// budget exhausted with data possibly left in the source
+    Again,
+    /// the limit of the session's acl entry is used up, pump again then
+    Wait(Instant),
 }
 pub struct Session {
-    pub down_sock: TcpStream,
-    pub up_sock: Option<TcpStream>,
+    /// unique for the life of the process, unlike the fds which are reused
+    /// right away; what logs, the access log and `/sessions` name it by
+    pub id: u64,
+    pub down_sock: ClientStream,
+    pub up_sock: Option<UpStream>,
     pub state: State,
     pub down_sock_id: usize,
     pub up_sock_id: usize,
+    /// the addresses of the destination, or of the parent, that passed
+    /// `Config::block_internal` and are not dialed yet, see `redial`
+    up_addrs: VecDeque<SocketAddr>,
+    /// the unix socket dialed instead when the destination's route has
+    /// one
+    up_path: Option<PathBuf>,
 
     pub connect_header_buf: Vec<u8>,
+    /// the destination is tunnelled: a CONNECT to any port, or any SOCKS
+    /// request
     pub is_https: bool,
+    /// how far the handshake got when the client speaks SOCKS, see
+    /// `Config::socks`; None for HTTP
+    socks: Option<Handshake>,
+    /// the user id of a SOCKS4 request, what the client says it is: only
+    /// logged, `user` is who authenticated
+    pub ident: Option<String>,
+    /// the relay of a SOCKS5 UDP ASSOCIATE, for as long as the client's
+    /// connection is open; such a session has no upstream and no host
+    pub udp: Option<Association>,
+    /// on a transparent listener, where the client's connection was headed
+    /// before it was redirected to us: the destination, dialed without a
+    /// request to read, and the client is never answered by us
+    pub original_dst: Option<SocketAddr>,
+    /// on a tunnel listener, its `upstream`: dialed without a request to
+    /// read, like `original_dst`, and the client is never answered by us
+    pub tunnel: Option<(String, u16)>,
+    /// what its listener takes, see `Config::listener_protocol`; None for
+    /// `Config::socks` to say
+    pub protocol: Option<Protocol>,
+    /// on a websocket listener, the Sec-WebSocket-Accept of the 101 its
+    /// client gets once the tunnel is up; None before the upgrade is read
+    /// and after it is answered
+    ws_accept: Option<String>,
+    /// on a `spoof_source` tproxy listener: the upstream is dialed from the
+    /// client's address rather than ours
+    pub spoof_source: bool,
     pub host: String,
+    pub port: u16,
+    pub client: Peer,
+    /// who the client authenticated as, see `Config::auth_file`, or who
+    /// its TLS client certificate names, see `Config::listener_client_ca`
+    pub user: Option<String>,
+    /// whether `user` comes of a client certificate, which stands in for
+    /// Proxy-Authorization and the SOCKS5 password
+    by_certificate: bool,
+    /// the request's Proxy-Authorization, until it is checked
+    authorization: Option<String>,
+    /// of `bytes_up` and `bytes_down`, what `charge_quota` counted for
+    /// `user` so far
+    charged: u64,
+    /// index into `Config::listen` of the listener the client came in on
+    pub listener: usize,
+    pub created: Instant,
+    /// payload bytes spliced client to upstream / upstream to client
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    /// what the session carries, known once it is established and, for a
+    /// tunnel, the client sent its first byte
+    pub class: Option<TrafficClass>,
+    /// the client's ClientHello was looked at, see `check_sni`
+    sni_checked: bool,
+    /// the client half-closed, as a read-closed edge told the worker; a
+    /// peek cannot see the end behind the data before it
+    pub client_eof: bool,
+    /// a transparent session waits no longer for its client's ClientHello,
+    /// see `transparent_hello`
+    pub hello_waited: bool,
+    /// where the destination address is, when an acl entry needed to
+    /// know; kept for `acl_recheck`
+    place: Option<Place>,
+    /// the limit of the acl entry that allowed the destination, when it
+    /// has one
+    throttle: Option<Throttle>,
+    /// armed by `Worker::throttle` while a pump waits on `throttle`, 0
+    /// when none is
+    pub throttle_timer: TimerId,
+    /// set at the first end of file or by `Worker::close_session`
+    pub close_reason: Option<CloseReason>,
+    /// the error the session is closed for, once counted
+    pub error: Option<ErrorCategory>,
+    outcome: Outcome,
+    /// how far establishing the tunnel got, and when
+    pub milestones: Milestones,
+    /// why the upstream could not be reached, when the socket error that
+    /// told us is already consumed
+    connect_failure: Option<ConnectFailure>,
+
+    /// last time bytes moved in either direction (or the head was read)
+    pub last_active: Instant,
+    pub idle_timer: TimerId,
+    /// armed once the upstream connect is issued when the session has a
+    /// lifetime, see `lifetime_ends`
+    pub lifetime_timer: TimerId,
+    /// the policies of `listener` as of the accept; a reload does not
+    /// change them
+    pub profile: Arc<Profile>,
+    /// the profile's timeouts until the CONNECT names a destination, then
+    /// the ones for it
+    timeouts: SessionTimeouts,
+    /// set when the destination is routed through the parent proxy
+    parent: Option<ParentProxy>,
+    /// the parent's answer to our CONNECT, read until it is complete
+    parent_buf: Vec<u8>,
+    /// with a SOCKS5 parent, how far our handshake with it got: what it
+    /// answers next
+    parent_socks: Option<Handshake>,
+    /// the other parents of the route, tried in turn when `parent` fails
+    /// rather than refuses, see `Session::fail_over`
+    parents_left: VecDeque<ParentProxy>,
+    /// `parent` answered our handshake, with a tunnel or a refusal another
+    /// parent would not answer differently; what fails after is not the
+    /// parent's to fail over from
+    parent_answered: bool,
+    /// the destination's route asks for a PROXY protocol header, see
+    /// `Route::proxy_protocol`; never set with a parent
+    send_proxy_header: bool,
+    /// what of that header the upstream dialed last has not taken yet
+    proxy_header: Vec<u8>,
+    /// what we wrote to the client ourselves, our replies and what a
+    /// parent sent behind its answer, that its socket did not take yet;
+    /// it goes out on the next writable edge, before anything piped
+    queued_down: Vec<u8>,
+    /// the same toward the upstream: our handshake with a parent, and the
+    /// request or what the client sent behind it
+    queued_up: Vec<u8>,
+    /// set when the destination's route has `Route::wrap_tls`, the
+    /// connections dialed for it get TLS on top
+    #[cfg(feature = "tls")]
+    up_tls: Option<UpTls>,
+    /// dial the upstream over multipath TCP, see `Config::upstream_mptcp`
+    /// and `Route::mptcp`
+    mptcp: bool,
+    /// where the MPTCP socket in `up_sock` is connecting to, for `redial`
+    /// to try over TCP should that fail
+    mptcp_dialed: Option<SocketAddr>,
+    /// whether the upstream speaks MPTCP with us, None until the connect
+    /// went through or when none was tried
+    pub mptcp_negotiated: Option<bool>,
+
+    down_pipe: Option<Pipe>,
+    up_pipe: Option<Pipe>,
+    /// set once a capture starts: the bytes go through `copy_buffered`
+    /// instead of the pipes from then on, to the end of the session; from
+    /// the start when either side is TLS
+    buffered: Option<Buffers>,
+    /// `POST /sessions/<id>/capture` running, until its limit
+    capture: Option<Capture>,
+    stats: Arc<WorkerStats>,
+    config: Arc<Config>,
 }
 
 impl Display for Session {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_fmt(format_args!(
-            "down_sock_id [{}] up_sock_id [{}] host [{}] state [{:?}] down port {:?} up port {:?}",
-            self.down_sock_id,
-            self.up_sock_id,
-            self.host,
-            self.state,
-            self.down_sock.peer_addr(),
-            self.up_sock.as_ref().map(|x| x.peer_addr())
+            "session {} host [{}] state [{:?}] client {}",
+            self.id, self.host, self.state, self.client
         ))
     }
 }
 
 impl Session {
-    pub fn new(down_sock_id: usize, down_sock: TcpStream) -> Self {
+    pub fn new(
+        down_sock_id: usize,
+        down_sock: ClientStream,
+        client: Peer,
+        listener: usize,
+        profile: Arc<Profile>,
+        stats: Arc<WorkerStats>,
+        config: Arc<Config>,
+    ) -> Self {
+        // a TLS client's bytes are only to be had through its TLS session:
+        // the handshake comes first, and copying rather than splicing, as
+        // through a websocket client's framing
+        let tls = down_sock.is_tls();
+        let copied = tls || down_sock.is_websocket();
         Session {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
             host: Default::default(),
+            port: 0,
             down_sock,
             up_sock: None,
-            state: State::Head,
+            state: if tls { State::TlsHandshake } else { State::Head(Speaks::Detect) },
             connect_header_buf: Vec::with_capacity(512),
             down_sock_id,
             up_sock_id: 0,
+            up_addrs: VecDeque::new(),
+            up_path: None,
             is_https: false,
+            socks: None,
+            ident: None,
+            udp: None,
+            original_dst: None,
+            tunnel: None,
+            protocol: None,
+            ws_accept: None,
+            spoof_source: false,
+            client,
+            user: None,
+            by_certificate: false,
+            authorization: None,
+            charged: 0,
+            listener,
+            created: Instant::now(),
+            bytes_up: 0,
+            bytes_down: 0,
+            class: None,
+            sni_checked: false,
+            client_eof: false,
+            hello_waited: false,
+            place: None,
+            throttle: None,
+            throttle_timer: 0,
+            close_reason: None,
+            error: None,
+            outcome: Outcome::Pending,
+            milestones: Milestones::default(),
+            connect_failure: None,
+            last_active: Instant::now(),
+            idle_timer: 0,
+            lifetime_timer: 0,
+            timeouts: profile.timeouts(&config.timeouts, None),
+            profile,
+            parent: None,
+            parent_buf: Vec::new(),
+            parent_socks: None,
+            parents_left: VecDeque::new(),
+            parent_answered: false,
+            send_proxy_header: false,
+            proxy_header: Vec::new(),
+            queued_down: Vec::new(),
+            queued_up: Vec::new(),
+            #[cfg(feature = "tls")]
+            up_tls: None,
+            mptcp: false,
+            mptcp_dialed: None,
+            mptcp_negotiated: None,
+            down_pipe: None,
+            up_pipe: None,
+            buffered: copied.then(Buffers::default),
+            capture: None,
+            stats,
+            config,
         }
     }
 
-    pub fn down2up(&mut self) -> io::Result<u64> {
-        self.up_sock
-            .as_mut()
-            .map(|up| {
-                debug!(
-                    "pipe down fd {} to up fd {}",
-                    self.down_sock_id, self.up_sock_id
-                );
-                
-                match splice_copy(&mut self.down_sock, up) {
-                    Ok(size) => {
-                        debug!("piping down to up size {}", size);
-                        return Ok(size);
-                    }
-                    Err(e) => {
-                        if e.kind() == ErrorKind::WouldBlock {
-                            return Err(io::Error::new(ErrorKind::WouldBlock, ""));
-                        }
-                        error!("splice error {:?}", e);
-                        return Err(io::Error::new(ErrorKind::Other, e.to_string()));
-                    }
-                }
-            })
-            .map(|result| match result {
-                Ok(u) => {
-                    debug!("piping down to up size {}", u);
-                    if u == 0 {
-                        return Err(io::Error::new(ErrorKind::UnexpectedEof, "eof"));
-                    }
+    pub fn idle_timeout(&self) -> Duration {
+        match self.state {
+            State::ProxyHeader => self.timeouts.idle.min(proxy_protocol::HEADER_TIMEOUT),
+            State::TlsHandshake => self.timeouts.idle.min(client::HANDSHAKE_TIMEOUT),
+            State::Head(_) if self.waits_for_hello() => self.timeouts.idle.min(sni::HELLO_WAIT),
+            State::Connecting | State::ParentHandshake if !self.parents_left.is_empty() => {
+                self.timeouts.idle.min(self.config.parent_timeout)
+            }
+            _ => self.timeouts.idle,
+        }
+    }
+
+    /// Whether the session waits on its parent proxy to take the
+    /// connection or answer our handshake.
+    pub fn awaits_parent(&self) -> bool {
+        self.parent.is_some() && matches!(self.state, State::Connecting | State::ParentHandshake)
+    }
+
+    /// What the upstream socket failed with, for an error event, which
+    /// does not say.
+    pub fn up_error(&self) -> String {
+        match self.up_sock.as_ref().map(UpStream::take_error) {
+            Some(Ok(Some(e))) => e.to_string(),
+            _ => "connection closed".to_owned(),
+        }
+    }
+
+    /// Whether the client sends no request and hears nothing from us: a
+    /// transparent or a tunnel listener's.
+    fn unrequested(&self) -> bool {
+        self.original_dst.is_some() || self.tunnel.is_some()
+    }
+
+    /// Whether a transparent session holds off dialing until its client's
+    /// ClientHello came, or `sni::HELLO_WAIT` passed.
+    pub fn waits_for_hello(&self) -> bool {
+        matches!(self.state, State::Head(_))
+            && self.original_dst.is_some()
+            && self.config.transparent_sni != TransparentSni::Off
+            && !self.hello_waited
+    }
+
+    /// `timeouts.lifetime` of the profile, None without a cap.
+    pub fn lifetime(&self) -> Option<Duration> {
+        self.timeouts.lifetime
+    }
+
+    /// When the session has lived its lifetime, counted from when it was
+    /// established: time spent reading the head, resolving and connecting
+    /// does not count. None without a cap or before it is established.
+    pub fn lifetime_ends(&self) -> Option<Instant> {
+        Some(self.milestones.established? + self.timeouts.lifetime?)
+    }
+
+    /// The request method, once the client sent the request line's first
+    /// word; `SOCKS4` or `SOCKS5` for a SOCKS client, `SOCKS5-UDP` once it
+    /// has an association, `TRANSPARENT` on a transparent listener,
+    /// `TUNNEL` on a tunnel one and `WEBSOCKET` on a websocket one.
+    pub fn method(&self) -> Option<String> {
+        if self.udp.is_some() {
+            return Some("SOCKS5-UDP".to_owned());
+        }
+        if self.original_dst.is_some() {
+            return Some("TRANSPARENT".to_owned());
+        }
+        if self.tunnel.is_some() {
+            return Some("TUNNEL".to_owned());
+        }
+        if self.down_sock.is_websocket() {
+            return Some("WEBSOCKET".to_owned());
+        }
+        if let Some(handshake) = self.socks {
+            return Some(handshake.version().to_string());
+        }
+        let end = self.connect_header_buf.iter().position(|&b| b == b' ')?;
+        Some(String::from_utf8_lossy(&self.connect_header_buf[..end]).into_owned())
+    }
+
+    /// `host:port` of the destination, None before the head is parsed.
+    pub fn authority(&self) -> Option<String> {
+        (!self.host.is_empty()).then(|| authority(&self.host, self.port))
+    }
+
+    /// Bytes read but not yet written on, client to upstream and upstream
+    /// to client: what sits in the splice pipes.
+    pub fn pending(&self) -> (usize, usize) {
+        let pending = |p: &Option<Pipe>| p.as_ref().map_or(0, |p| p.pending);
+        let (up, down) = self.buffered.as_ref().map_or((0, 0), |b| (b.up.len(), b.down.len()));
+        (pending(&self.down_pipe) + up, pending(&self.up_pipe) + down)
+    }
 
-                    return Ok(u as u64);
+    /// Splice pipes the session holds open, one per direction once it moved
+    /// bytes that way.
+    pub fn pipes(&self) -> usize {
+        self.down_pipe.is_some() as usize + self.up_pipe.is_some() as usize
+    }
+
+    /// Starts teeing the session's bytes into a new file in `dir`, at most
+    /// `limit` of them, and returns its path.
+    pub fn start_capture(&mut self, dir: &Path, limit: u64) -> Result<PathBuf, CaptureError> {
+        if let Some(c) = &self.capture {
+            return Err(CaptureError::Running(c.path().to_owned()));
+        }
+        let host = self.authority().unwrap_or_default();
+        let capture = Capture::start(dir, self.id, &self.client, &host, limit)
+            .map_err(|e| CaptureError::Io(format!("cannot create capture in {}: {}", dir.display(), e)))?;
+        let path = capture.path().to_owned();
+        self.capture = Some(capture);
+        self.buffered.get_or_insert_with(Buffers::default);
+        Ok(path)
+    }
+
+    /// Ends a running capture, closing its file. Called as the session
+    /// closes.
+    pub fn stop_capture(&mut self) {
+        self.capture = None;
+    }
+
+    /// A destination that was named but never reached counts as failed.
+    pub fn outcome(&self) -> Outcome {
+        match self.outcome {
+            Outcome::Pending if !self.host.is_empty() => Outcome::Failed(None),
+            o => o,
+        }
+    }
+
+    /// Why the upstream was never reached if that is what ended the session
+    /// closing for `reason`, None when it was reached or the client gave up
+    /// first. Reads the socket's pending error, so ask only once.
+    pub fn connect_failure(&mut self, reason: CloseReason) -> Option<ConnectFailure> {
+        if self.connect_failure.is_some() {
+            return self.connect_failure;
+        }
+        match (self.state, reason) {
+            (State::Connecting, CloseReason::Idle) => Some(ConnectFailure::Timeout),
+            (State::Connecting, CloseReason::Error | CloseReason::UpstreamClosed) => {
+                let error = self.up_sock.as_ref().and_then(|s| s.take_error().ok().flatten());
+                Some(match error {
+                    _ if self.parent.is_some() => ConnectFailure::Parent,
+                    Some(e) => ConnectFailure::from_error(&e),
+                    None => ConnectFailure::Other,
+                })
+            }
+            (State::ParentHandshake, CloseReason::Idle | CloseReason::Error | CloseReason::UpstreamClosed) => {
+                Some(ConnectFailure::Parent)
+            }
+            _ => None,
+        }
+    }
+
+    /// What a connect error counts as, everything on the way to the
+    /// parent proxy is the parent's.
+    fn failure_kind(&self, e: &io::Error) -> ConnectFailure {
+        if self.parent.is_some() {
+            ConnectFailure::Parent
+        } else {
+            ConnectFailure::from_error(e)
+        }
+    }
+
+    /// Counts the session as carrying `class`, with the bytes it moved so
+    /// far.
+    fn classify(&mut self, class: TrafficClass) {
+        self.class = Some(class);
+        self.stats.classified(class, self.bytes_up, self.bytes_down);
+    }
+
+    /// Tells a TLS tunnel from others by the client's first byte, which
+    /// opens a handshake record with 0x16; the byte stays in the socket.
+    /// Nothing yet while the client has not sent any.
+    fn sniff(&mut self) {
+        match self.down_sock.peek_byte() {
+            Ok(Some(0x16)) => self.classify(TrafficClass::TunnelTls),
+            Ok(Some(_)) => self.classify(TrafficClass::TunnelOther),
+            Ok(None) | Err(_) => {}
+        }
+    }
+
+    /// Holds the client's bytes back until its ClientHello shows whether
+    /// the tunnel goes to the host the CONNECT named; false while more of
+    /// the hello is to come, the next edge looks again. Tunnels that do not
+    /// start with a handshake record, and hellos without a server name,
+    /// pass. A hello cut short by the client's FIN counts as malformed. A
+    /// mismatch is logged, or with `SniCheck::Enforce` audited and the
+    /// tunnel refused before the hello goes upstream.
+    fn check_sni(&mut self) -> io::Result<bool> {
+        let mut buf = [0u8; sni::MAX_HELLO];
+        let n = match self.down_sock.peek(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let rule = match sni::parse(&buf[..n]) {
+            // nothing at end of file, the splice will see it
+            Hello::Incomplete if n > 0 && !self.client_eof => return Ok(false),
+            Hello::Incomplete if n > 0 => Some("sni_check:truncated".to_owned()),
+            Hello::Incomplete | Hello::NotTls | Hello::Sni(None) => None,
+            Hello::Sni(Some(name)) if sni::matches(&self.host, &name, self.config.sni_allow_subdomains) => None,
+            Hello::Sni(Some(name)) => Some(format!("sni_check:{}", name)),
+            Hello::Malformed => Some("sni_check:malformed".to_owned()),
+        };
+        self.sni_checked = true;
+        let Some(rule) = rule else {
+            return Ok(true);
+        };
+        self.stats.sni_mismatch();
+        if self.config.sni_check == SniCheck::Log {
+            warn!(
+                session = self.id, client:% = self.client, host = self.host.as_str(), rule = rule.as_str();
+                "tunnel hello does not match its connect host"
+            );
+            return Ok(true);
+        }
+        info!(session = self.id, client:% = self.client, host = self.host.as_str(); "closing tunnel, {}", rule);
+        self.stats.denied_on(self.listener);
+        audit_log::denied(&self.client, self.authority().as_deref(), Denial::Sni, Some(&rule), Some(self.id));
+        self.close_reason = Some(CloseReason::Denied);
+        Err(io::Error::new(ErrorKind::PermissionDenied, format!("tunnel denied by {}", rule)))
+    }
+
+    /// The server name of a transparent session's ClientHello, peeked so
+    /// it still goes upstream as it came. WouldBlock while more of a hello
+    /// may come and the wait is not over; None for a client that sends no
+    /// hello, or no sound name in it.
+    fn transparent_hello(&mut self) -> io::Result<Option<String>> {
+        let mut buf = [0u8; sni::MAX_HELLO];
+        let (n, eof) = match self.down_sock.peek(&mut buf) {
+            Ok(n) => (n, n == 0),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => (0, false),
+            Err(e) => return Err(e),
+        };
+        match sni::parse(&buf[..n]) {
+            Hello::Incomplete if !eof && !self.hello_waited => Err(ErrorKind::WouldBlock.into()),
+            Hello::Sni(Some(name)) if sni::is_hostname(&name) => Ok(Some(name.trim_end_matches('.').to_owned())),
+            _ => Ok(None),
+        }
+    }
+
+    /// A tunnel closing before the client sent anything counts as
+    /// `TrafficClass::TunnelOther`. Called as the session closes.
+    pub fn classify_unsniffed(&mut self) {
+        if self.class.is_none() && self.outcome == Outcome::Established {
+            self.classify(TrafficClass::TunnelOther);
+        }
+    }
+
+    pub fn down2up(&mut self) -> io::Result<Drain> {
+        debug!("session {} pipe down to up", self.id);
+        if self.class.is_none() {
+            self.sniff();
+        }
+        if !self.sni_checked && self.is_https && self.config.sni_check != SniCheck::Off && !self.check_sni()? {
+            return Ok(Drain::Done);
+        }
+        let budget = match self.budget() {
+            Ok(budget) => budget,
+            Err(wait) => return Ok(wait),
+        };
+        let up = self
+            .up_sock
+            .as_mut()
+            .ok_or_else(|| io::Error::other("up not ready"))?;
+        if !flush_queued(up, &mut self.queued_up)? {
+            return Ok(Drain::Done);
+        }
+        let sides = (Side::Client, Side::Upstream);
+        let copied = match &mut self.buffered {
+            Some(b) => copy_buffered(
+                &mut self.down_sock,
+                up,
+                sides,
+                self.down_pipe.as_mut(),
+                &mut b.up,
+                &mut self.capture,
+                budget,
+            ),
+            None => {
+                let pipe = match self.down_pipe.as_mut() {
+                    Some(p) => p,
+                    None => self.down_pipe.insert(Pipe::new()?),
+                };
+                splice_copy(&self.down_sock, up, sides, pipe, budget)
+            }
+        };
+        let (size, drain) = copied
+            .inspect_err(|e| {
+                if e.kind() == ErrorKind::UnexpectedEof {
+                    self.close_reason.get_or_insert(CloseReason::ClientClosed);
                 }
-                Err(e) => Err(e),
-            })
-            .unwrap_or(Err(io::Error::new(io::ErrorKind::Other, "up not ready")))
+            })?;
+        debug!("piping down to up size {} {:?}", size, drain);
+        if size > 0 {
+            self.last_active = Instant::now();
+            self.bytes_up += size as u64;
+            self.stats.bytes_moved(self.listener, true, size);
+            if let Some(t) = &self.throttle {
+                t.limit().spend(size);
+            }
+            match self.class {
+                Some(class) => self.stats.class_bytes_moved(class, true, size),
+                None => self.classify(TrafficClass::TunnelOther),
+            }
+        }
+        Ok(drain)
     }
 
-    pub fn up2down(&mut self) -> io::Result<u64> {
-        debug!(
-            "pipe up fd {} to down fd {}",
-            self.up_sock_id, self.down_sock_id
-        );
-        match splice_copy(self.up_sock.as_mut().unwrap(), &mut self.down_sock) {
-            Ok(size) => {
-                debug!("piping up to down size {}", size);
-                return Ok(size as u64);
+    pub fn up2down(&mut self) -> io::Result<Drain> {
+        debug!("session {} pipe up to down", self.id);
+        let budget = match self.budget() {
+            Ok(budget) => budget,
+            Err(wait) => return Ok(wait),
+        };
+        if !flush_queued(&mut self.down_sock, &mut self.queued_down)? {
+            return Ok(Drain::Done);
+        }
+        let up = self
+            .up_sock
+            .as_mut()
+            .ok_or_else(|| io::Error::other("up not ready"))?;
+        let sides = (Side::Upstream, Side::Client);
+        let copied = match &mut self.buffered {
+            Some(b) => copy_buffered(
+                up,
+                &mut self.down_sock,
+                sides,
+                self.up_pipe.as_mut(),
+                &mut b.down,
+                &mut self.capture,
+                budget,
+            ),
+            None => {
+                let pipe = match self.up_pipe.as_mut() {
+                    Some(p) => p,
+                    None => self.up_pipe.insert(Pipe::new()?),
+                };
+                splice_copy(up, &self.down_sock, sides, pipe, budget)
             }
-            Err(e) => {
-                if e.kind() == ErrorKind::WouldBlock {
-                    return Err(io::Error::new(ErrorKind::WouldBlock, ""));
+        };
+        let (size, drain) = copied
+            .inspect_err(|e| {
+                if e.kind() == ErrorKind::UnexpectedEof {
+                    self.close_reason.get_or_insert(CloseReason::UpstreamClosed);
                 }
-                error!("splice error {:?}", e);
-                return Err(io::Error::new(ErrorKind::Other, e.to_string()));
+            })?;
+        debug!("piping up to down size {} {:?}", size, drain);
+        if size > 0 {
+            self.last_active = Instant::now();
+            self.bytes_down += size as u64;
+            self.stats.bytes_moved(self.listener, false, size);
+            if let Some(t) = &self.throttle {
+                t.limit().spend(size);
+            }
+            if let Some(class) = self.class {
+                self.stats.class_bytes_moved(class, false, size);
             }
         }
+        Ok(drain)
     }
 
-    pub fn parse_header_line(&mut self) -> io::Result<String> {
-        let mut reader = &mut self.down_sock;
+    /// Reads the request head, returning its request line and its Host
+    /// headers, in order, once the head is complete.
+    pub fn parse_header_line(&mut self) -> io::Result<(String, Vec<String>)> {
+        let reader = &mut self.down_sock;
         let d = b'\n';
         let mut buf = [0u8; 1024];
-        let mut headers = [httparse::EMPTY_HEADER; 10];
+        let mut headers = [httparse::EMPTY_HEADER; 32];
         loop {
             match reader.read(&mut buf) {
                 Ok(s) => {
                     debug!("read header size {}", s);
                     if s == 0 {
-                        return Err(io::Error::new(ErrorKind::Other, "eof"));
+                        self.close_reason.get_or_insert(CloseReason::ClientClosed);
+                        return Err(io::Error::new(ErrorKind::UnexpectedEof, "eof"));
                     }
 
-                    self.connect_header_buf.write(&buf[0..s])?;
-
+                    self.connect_header_buf.extend_from_slice(&buf[0..s]);
+                    self.last_active = Instant::now();
                 }
                 Err(e) => {
                     if e.kind() == ErrorKind::WouldBlock {
-                        let mut idx = 0;
-                        for i in 0..self.connect_header_buf.len() {
-                            if self.connect_header_buf[i] == d {
-                                idx = i;
-                                break;
-                            }
-                        }
+                        // a slow client's request line may not be complete
+                        let Some(idx) = self.connect_header_buf.iter().position(|&b| b == d) else {
+                            return Err(e);
+                        };
                         let r = httparse::parse_headers(
                             &self.connect_header_buf[idx + 1..],
                             &mut headers,
                         );
                         if let Ok(s) = r {
                             if let httparse::Status::Complete(_) = s {
-                                if let Some(host) = headers
+                                self.authorization = headers
+                                    .iter()
+                                    .find(|h| h.name.eq_ignore_ascii_case("proxy-authorization"))
+                                    .map(|h| String::from_utf8_lossy(h.value).into_owned());
+                                let hosts = headers
                                     .iter()
-                                    .filter(|h| h.name == "Host")
-                                    .map(|h| String::from_utf8_lossy(h.value))
-                                    .last()
-                                {
-                                    return Ok(host.into_owned());
-                                }
+                                    .filter(|h| h.name.eq_ignore_ascii_case("host"))
+                                    .map(|h| String::from_utf8_lossy(h.value).into_owned())
+                                    .collect();
+                                let line = String::from_utf8_lossy(&self.connect_header_buf[..idx]);
+                                return Ok((line.trim_end().to_owned(), hosts));
                             } else {
                                 debug!(
                                     "head not complete , buf {}",
                                     String::from_utf8_lossy(&self.connect_header_buf[idx + 1..])
                                 );
                             }
-                        } else {
-                            error!("parse header error {:?}", r.err().unwrap())
+                        } else if let Err(parse) = r {
+                            return Err(io::Error::new(ErrorKind::InvalidData, format!("bad request head: {}", parse)));
                         }
                     }
                     return Err(e);
@@ -184,190 +865,1880 @@ impl Session {
         }
     }
 
-    fn formatHost(s: Cow<str>) -> Cow<str> {
+    fn format_host(s: Cow<str>) -> Cow<str> {
         if s.starts_with("http") {
             return Cow::Owned(s.replace("https?://", "").replace("/", ""));
         }
         s
     }
 
-    pub fn connect(&mut self, poll: &Registry, dns: &mut DNS) -> io::Result<RawFd> {
-        let header_line = self.parse_header_line()?;
-        debug!("parsed connect header {}", &header_line);
-        let url = header_line.split(" ").take(2).last().unwrap();
-        self.is_https = url.ends_with(":443");
-
-        let format_url = Self::formatHost(Cow::Borrowed(url));
-        let mut host_port = format_url.split(":");
-        let host = host_port.next().unwrap();
-        self.host = host.to_owned();
-        let st = Instant::now();
-        let ips = dns.query(host);
-        if ips.is_none() {
-            return Err(io::Error::new(
-                ErrorKind::NetworkUnreachable,
-                "dns qwuery failed",
-            ));
+    /// Reads the client's request, HTTP, SOCKS4 or SOCKS5 as its first
+    /// byte says, and dials its destination once policy lets it through,
+    /// or opens the relay of a UDP ASSOCIATE. A redirected connection has
+    /// no request, its original destination is dialed, and neither has a
+    /// tunnel listener's, dialed to its upstream. Returns the token of the
+    /// new socket.
+    pub fn connect(&mut self, poll: &Registry, dns: &mut DNS) -> io::Result<Token> {
+        if let Some((host, port)) = self.tunnel.clone() {
+            // the client named no host, its hello has nothing to match
+            self.sni_checked = true;
+            self.target(&host, port);
+            self.milestones.head = Some(Instant::now());
+            return self.open(poll, dns).map(TokenSpace::session);
         }
-        let ip = ips.unwrap();
-
-        info!("connect  {} duration: {:?}", host, st.elapsed());
-        let up_addr = SocketAddr::new(ip, host_port.next().unwrap_or("80").parse().unwrap());
-        debug!("up addr  {:?}", &up_addr);
-        let mut up_sock = TcpStream::connect(up_addr)?;
-        let up_sock_fd = &up_sock.as_raw_fd();
-        debug!("up sock fd {}", up_sock_fd);
-        match poll.register(
-            &mut up_sock,
-            Token((*up_sock_fd).try_into().unwrap()),
-            Interest::READABLE | Interest::WRITABLE,
-        ) {
-            Ok(_) => {
-                //
-                self.up_sock = Some(up_sock);
-                self.up_sock_id = (*up_sock_fd).try_into().unwrap();
+        if let Some(to) = self.original_dst {
+            // known by the name its hello gives, else by its address only,
+            // which the acl and logs go by
+            let name = match self.config.transparent_sni {
+                TransparentSni::Off => None,
+                TransparentSni::Host | TransparentSni::Dial => self.transparent_hello()?,
+            };
+            self.hello_waited = true;
+            // the hello named the host, there is nothing to compare it to
+            self.sni_checked = name.is_some();
+            self.target(name.as_deref().unwrap_or(&to.ip().to_string()), to.port());
+            self.milestones.head = Some(Instant::now());
+            return self.open(poll, dns).map(TokenSpace::session);
+        }
+        if matches!(self.state, State::Head(Speaks::Detect)) {
+            self.state = State::Head(self.detect()?);
+        }
+        let associate = match self.state {
+            State::Head(Speaks::Socks) => self.socks_request()?,
+            _ => {
+                self.http_request()?;
+                None
+            }
+        };
+        self.milestones.head = Some(Instant::now());
+        match associate {
+            Some(port) => self.associate(poll, port),
+            None => self.open(poll, dns).map(TokenSpace::session),
+        }
+    }
 
-                Ok(*up_sock_fd)
+    /// What the client speaks, from its first bytes, which stay in the
+    /// socket for the parser that takes them; WouldBlock while too few
+    /// came to tell. Without a listener `protocol` a client is HTTP unless
+    /// `Config::socks` is set and its first byte is a SOCKS version.
+    fn detect(&mut self) -> io::Result<Speaks> {
+        let mut first = [0u8; detect::FIRST_BYTES];
+        let n = match self.protocol {
+            None if !self.config.socks => return Ok(Speaks::Http),
+            None => self.down_sock.peek(&mut first[..1])?,
+            Some(_) => self.down_sock.peek(&mut first)?,
+        };
+        if n == 0 {
+            self.close_reason.get_or_insert(CloseReason::ClientClosed);
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "eof"));
+        }
+        let kind = match (self.protocol, detect::classify(&first[..n])) {
+            (_, Some(kind @ (FirstBytes::Socks5 | FirstBytes::Socks4))) => kind,
+            (None, _) => FirstBytes::Http,
+            (Some(_), Some(kind)) => kind,
+            (Some(_), None) => return Err(io::Error::new(ErrorKind::WouldBlock, "first bytes not in yet")),
+        };
+        let speaks = match self.protocol.map(|p| detect::dispatch(p, kind, self.down_sock.is_tls())) {
+            Some(Ok(speaks)) => speaks,
+            Some(Err(why)) => {
+                info!(session = self.id, client:% = self.client; "closing client: {}", why);
+                // for `Config::dump_bad_heads` to show
+                let _ = self.read_head();
+                return Err(io::Error::new(ErrorKind::InvalidData, why));
             }
-            Err(e) => Err(e),
+            None if kind == FirstBytes::Http => Speaks::Http,
+            None => Speaks::Socks,
+        };
+        match kind {
+            FirstBytes::Socks5 => self.socks = Some(Handshake::Greeting),
+            FirstBytes::Socks4 => self.socks = Some(Handshake::Request4),
+            _ => {}
         }
+        debug!(session = self.id, client:% = self.client; "client speaks {:?}", speaks);
+        Ok(speaks)
     }
 
-    pub(crate) fn pipe(&mut self, sock_id: usize) -> io::Result<u64> {
-        let mut send = 0;
-        if sock_id == self.down_sock_id {
-            send += self.down2up()?;
-        } else if sock_id == self.up_sock_id {
-            send += self.up2down()?;
+    /// Takes the destination of an HTTP request once its head is complete,
+    /// checking its Host header and Proxy-Authorization.
+    fn http_request(&mut self) -> io::Result<()> {
+        let (request_line, hosts) = self.parse_header_line()?;
+        if self.protocol == Some(Protocol::WebSocket) {
+            return self.websocket_request();
         }
+        debug!("parsed request line {:?} host {:?}", &request_line, &hosts);
+        let host_header = hosts.last().cloned();
+        let mut words = request_line.split(' ');
+        let requested = host_check::target_authority(words.next().unwrap_or(""), words.next().unwrap_or(""));
+        // without a Host header the destination is the one the target names
+        let url = match (&host_header, requested) {
+            (Some(host), _) => host.as_str(),
+            (None, Some((authority, _))) => authority,
+            (None, None) => return Err(io::Error::new(ErrorKind::InvalidData, "request names no host")),
+        };
 
-        debug!("piping {} size {}", self.host, send);
-        Ok(send)
+        let format_url = Self::format_host(Cow::Borrowed(url));
+        let (host, port) = split_host_port(&format_url);
+        let port = match port.map(str::parse) {
+            // the port the target defaults to, which the check below takes too
+            None => requested.map_or(80, |(_, port)| port),
+            Some(Ok(port)) => port,
+            Some(Err(_)) => return Err(io::Error::new(ErrorKind::InvalidInput, "bad connect port")),
+        };
+        self.target(host, port);
+        let target = requested.map_or("", |(target, _)| target);
+        let rule = match (&host_header, requested) {
+            // which of them goes is anyone's guess, RFC 9112 section 3.2
+            _ if hosts.len() > 1 => Some("host_check:duplicate".to_owned()),
+            (Some(header), Some((target, default_port))) if !host_check::matches(target, header, default_port) => {
+                Some(format!("host_check:{}", target))
+            }
+            _ => None,
+        };
+        match (rule, self.config.host_check) {
+            (Some(_), HostCheck::Warn) => warn!(
+                session = self.id, client:% = self.client, target = target, host:% = hosts.join(", ");
+                "request target and host header disagree, going by the last host header"
+            ),
+            (Some(rule), HostCheck::Enforce) => {
+                info!(
+                    session = self.id, client:% = self.client, target = target, host:% = hosts.join(", ");
+                    "refusing request, its target and host header disagree"
+                );
+                self.refuse(
+                    Denial::Host,
+                    &rule,
+                    b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                );
+                return Err(io::Error::new(ErrorKind::PermissionDenied, format!("denied by {}", rule)));
+            }
+            _ => {}
+        }
+        self.check_credentials()
     }
 
-    fn handle_up_sock_connected(&mut self, evt: &Event) -> io::Result<()> {
-        match self.state {
-            State::Head => {
-                let up_sock_id = self.up_sock_id;
-                if evt.token().0 == up_sock_id {
-                    debug!("session connect {} done {}", self.host, up_sock_id);
-                    if self.is_https {
-                        debug!("respond https");
-                        self.down_sock
-                            .write_all("HTTP/1.1 200 Connection established\r\n\r\n".as_bytes())?;
-                    } else {
-                        debug!("respond http");
-                        self.up_sock
-                            .as_mut()
-                            .map(|s| s.write_all(&self.connect_header_buf));
+    /// Takes the destination of a websocket listener's upgrade request,
+    /// checking its Proxy-Authorization; a request that is no upgrade is
+    /// answered with a 400, or a 426 for a version other than 13. The
+    /// 101 waits for the tunnel, see `tunnel_established`.
+    fn websocket_request(&mut self) -> io::Result<()> {
+        let upgrade = websocket::upgrade(&self.connect_header_buf).map_err(|refused| {
+            let _ = self.down_sock.write_all(refused.response);
+            io::Error::new(ErrorKind::InvalidData, refused.why)
+        })?;
+        self.ws_accept = Some(upgrade.accept);
+        self.target(&upgrade.host, upgrade.port);
+        self.check_credentials()
+    }
+
+    /// Checks the Proxy-Authorization of an HTTP request, when its
+    /// listener's profile asks for credentials, answering a 407 without
+    /// good ones.
+    fn check_credentials(&mut self) -> io::Result<()> {
+        if let Some(credentials) = self.credentials() {
+            let authorization = self.authorization.take();
+            match authorization.as_deref().and_then(|a| credentials.verify(a)) {
+                Some(user) => {
+                    self.user = Some(user.to_owned());
+                    // ours, not for the upstream of a forwarded request
+                    strip_header(&mut self.connect_header_buf, b"proxy-authorization");
+                }
+                None => {
+                    let rule = if authorization.is_some() { "auth_file:bad-credentials" } else { "auth_file:missing" };
+                    // a missing header is how every client starts, only a wrong one counts
+                    if let (Some(_), Peer::Ip(ip)) = (&authorization, self.client) {
+                        bans::failed(ip.ip(), Instant::now());
                     }
-                    self.state = State::Piping;
+                    let challenge = format!(
+                        "HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"{}\"\r\n\
+                         Content-Length: 0\r\nConnection: close\r\n\r\n",
+                        self.config.auth_realm
+                    );
+                    self.refuse(Denial::Auth, rule, challenge.as_bytes());
+                    return Err(io::Error::new(ErrorKind::PermissionDenied, format!("not authenticated, {}", rule)));
                 }
             }
-            State::Piping => {
-                // debug!("piping..");
-                // if let Err(e) = session.borrow_mut().pipe(evt.token().0) {
-                //     error!("piping error {}", e);
-                //     if e.kind() == ErrorKind::WouldBlock {
-                //         return Ok(());
-                //     }
-                //     return Err(e);
-                // }
-            }
         }
         Ok(())
     }
 
-    pub(crate) fn handle_write(&mut self, evt: &Event) -> io::Result<()> {
-        debug!("writeable fd {} session {}", evt.token().0, self);
-        let err = self.up_sock.as_mut().map(|sock| {
-            if let Err(e) = sock.take_error() {
-                if e.kind() == ErrorKind::NotConnected {
-                    return Err(io::Error::new(ErrorKind::WouldBlock, "not connected"));
-                }
-                return Err(e);
-            }
+    /// The users of `Config::auth_file` the client must be one of, None
+    /// when its listener's profile does not ask for credentials or its
+    /// client certificate named it already.
+    fn credentials(&self) -> Option<Arc<Credentials>> {
+        let required = self.profile.auth_required(self.config.credentials.is_some()) && !self.by_certificate;
+        self.config.credentials.clone().filter(|_| required)
+    }
 
-            if let Ok(Some(e)) = sock.take_error() {
-                if e.kind() == ErrorKind::NotConnected {
-                    return Err(io::Error::new(ErrorKind::WouldBlock, "not connected"));
+    /// Runs the SOCKS5 handshake as far as the client's bytes go: picks a
+    /// method, `socks::USER_PASS` when the client must authenticate, checks
+    /// the credentials and takes the destination of a CONNECT request.
+    /// Bytes the client sent after the request go upstream once it is
+    /// reached. Some for a UDP ASSOCIATE, with the port the client sends
+    /// its datagrams from, 0 when it did not say.
+    fn socks_request(&mut self) -> io::Result<Option<u16>> {
+        self.read_head()?;
+        let incomplete = || io::Error::new(ErrorKind::WouldBlock, "socks5 handshake not complete");
+        let malformed = |why| io::Error::new(ErrorKind::InvalidData, why);
+        loop {
+            match self.socks {
+                Some(Handshake::Greeting) => {
+                    let (n, methods) = socks::greeting(&self.connect_header_buf)
+                        .map_err(malformed)?
+                        .ok_or_else(incomplete)?;
+                    let auth = self.credentials().is_some();
+                    let wanted = if auth { socks::USER_PASS } else { socks::NO_AUTH };
+                    let method = if methods.contains(&wanted) { wanted } else { socks::NO_METHOD };
+                    self.connect_header_buf.drain(..n);
+                    if method == socks::NO_METHOD && auth {
+                        let rule = "auth_file:missing";
+                        self.refuse(Denial::Auth, rule, &[socks::VERSION_5, method]);
+                        return Err(io::Error::new(ErrorKind::PermissionDenied, format!("not authenticated, {}", rule)));
+                    }
+                    self.send_down(&[socks::VERSION_5, method])?;
+                    if method == socks::NO_METHOD {
+                        return Err(io::Error::new(ErrorKind::InvalidData, "socks5 client offers no method we take"));
+                    }
+                    self.socks = Some(if method == socks::USER_PASS { Handshake::Auth } else { Handshake::Request });
                 }
-                return Err(e);
-            }
-
-            //TcpStream::peer_addr. If it returns libc::EINPROGRESS or ErrorKind::NotConnected
-            if let Err(e) = sock.peer_addr() {
-                if e.kind() == ErrorKind::NotConnected {
-                    return Err(io::Error::new(ErrorKind::WouldBlock, "not connected"));
+                Some(Handshake::Auth) => {
+                    let (n, (user, password)) = socks::auth(&self.connect_header_buf)
+                        .map_err(malformed)?
+                        .ok_or_else(incomplete)?;
+                    let credentials = self.credentials();
+                    let user = std::str::from_utf8(user)
+                        .ok()
+                        .and_then(|u| credentials.as_deref()?.check(u, password))
+                        .map(str::to_owned);
+                    self.connect_header_buf.drain(..n);
+                    let Some(user) = user else {
+                        let rule = "auth_file:bad-credentials";
+                        if let Peer::Ip(ip) = self.client {
+                            bans::failed(ip.ip(), Instant::now());
+                        }
+                        self.refuse(Denial::Auth, rule, &socks::auth_reply(false));
+                        return Err(io::Error::new(ErrorKind::PermissionDenied, format!("not authenticated, {}", rule)));
+                    };
+                    self.user = Some(user);
+                    self.send_down(&socks::auth_reply(true))?;
+                    self.socks = Some(Handshake::Request);
                 }
-                return Err(e);
+                Some(Handshake::Request) => {
+                    let (n, request) = socks::request(&self.connect_header_buf)
+                        .map_err(|m| self.socks_malformed(m))?
+                        .ok_or_else(incomplete)?;
+                    // left in for `Config::dump_bad_heads` until it is taken
+                    if !request.is_connect() && !request.is_udp_associate() {
+                        return Err(self.socks_unsupported(request.command));
+                    }
+                    self.connect_header_buf.drain(..n);
+                    self.socks = Some(Handshake::Done(Version::Five));
+                    if request.is_udp_associate() {
+                        // the client's own address, as it sees it; its port
+                        // is only worth taking when we see the same one
+                        let named = request.host.parse::<IpAddr>().map(|ip| ip.to_canonical());
+                        let port = match (named, self.client) {
+                            (Ok(ip), Peer::Ip(client)) if ip.is_unspecified() || ip == client.ip() => request.port,
+                            _ => 0,
+                        };
+                        debug!("session {} socks5 UDP ASSOCIATE from {}:{}", self.id, request.host, request.port);
+                        // nothing but the association rides the connection
+                        self.connect_header_buf.clear();
+                        return Ok(Some(port));
+                    }
+                    debug!("session {} socks5 CONNECT {}:{}", self.id, request.host, request.port);
+                    self.target(&request.host, request.port);
+                    return Ok(None);
+                }
+                Some(Handshake::Request4) => {
+                    let (n, (request, user_id)) = socks::request4(&self.connect_header_buf)
+                        .map_err(|m| self.socks_malformed(m))?
+                        .ok_or_else(incomplete)?;
+                    // the user id proves nothing, a SOCKS4 client cannot
+                    // authenticate
+                    if self.credentials().is_some() {
+                        let rule = "auth_file:missing";
+                        self.refuse(Denial::Auth, rule, &socks::reply(Version::Four, Reply::NotAllowed, None));
+                        return Err(io::Error::new(ErrorKind::PermissionDenied, format!("not authenticated, {}", rule)));
+                    }
+                    if !request.is_connect() {
+                        return Err(self.socks_unsupported(request.command));
+                    }
+                    self.connect_header_buf.drain(..n);
+                    debug!(
+                        "session {} socks4 CONNECT {}:{} user id {:?}",
+                        self.id, request.host, request.port, user_id
+                    );
+                    self.ident = Some(user_id).filter(|u| !u.is_empty());
+                    self.socks = Some(Handshake::Done(Version::Four));
+                    self.target(&request.host, request.port);
+                    return Ok(None);
+                }
+                Some(Handshake::Done(_)) | None => return Ok(None),
             }
+        }
+    }
 
-            Ok(())
-        });
+    fn socks_version(&self) -> Version {
+        self.socks.map_or(Version::Five, Handshake::version)
+    }
 
-        if let Some(Err(e)) = err {
-            return Err(e);
+    /// Our answer to a SOCKS request, in the client's version.
+    fn socks_reply(&self, reply: Reply, bound: Option<SocketAddr>) -> Vec<u8> {
+        socks::reply(self.socks_version(), reply, bound)
+    }
+
+    /// Answers a SOCKS request that breaks the protocol, when there is an
+    /// answer for it.
+    fn socks_malformed(&mut self, socks::Malformed(why, reply): socks::Malformed) -> io::Error {
+        if let Some(reply) = reply {
+            let _ = self.down_sock.write_all(&self.socks_reply(reply, None));
         }
-        return self.handle_up_sock_connected(evt);
+        io::Error::new(ErrorKind::InvalidData, why)
     }
-}
 
-#[cfg(target_os="linux")]
-fn splice_copy(src: &mut TcpStream, dst: &mut TcpStream) -> io::Result<usize> {
-    let (read_pipe, write_pipe) = pipe2(OFlag::O_NONBLOCK)?;
-    let mut send = 0;
-    loop {
-        let mut r = splice(
-            src.as_fd(),
-            None,
-            write_pipe.as_fd(),
-            None,
-            8192,
-            SpliceFFlags::SPLICE_F_NONBLOCK | SpliceFFlags::SPLICE_F_MOVE,
-        );
-        match r {
-            Ok(u) => {
-                if u == 0 {
-                    return Err(io::Error::new(ErrorKind::UnexpectedEof, "eof"));
-                }
-    
-                send = send + u;
-            }
+    /// Answers a SOCKS request for anything but CONNECT.
+    fn socks_unsupported(&mut self, command: u8) -> io::Error {
+        let _ = self.down_sock.write_all(&self.socks_reply(Reply::CommandNotSupported, None));
+        let version = self.socks_version();
+        io::Error::new(ErrorKind::InvalidInput, format!("{} command {:#04x} not supported", version, command))
+    }
+
+    /// Reads the PROXY protocol header ahead of what the client sent and
+    /// takes the client address it names, see
+    /// `Config::listener_proxy_protocol`; what follows stays in the socket
+    /// for the head. WouldBlock until the header is complete.
+    pub fn proxy_header(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; proxy_protocol::MAX_HEADER];
+        let n = self.down_sock.peek(&mut buf)?;
+        if n == 0 {
+            self.close_reason.get_or_insert(CloseReason::ClientClosed);
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "eof"));
+        }
+        let (len, source) = match proxy_protocol::header(&buf[..n]) {
+            Ok(Some(header)) => header,
+            Ok(None) => return Err(ErrorKind::WouldBlock.into()),
             Err(e) => {
-                if e == Errno::EAGAIN {
-                    return Err(io::Error::new(ErrorKind::WouldBlock, ""));
-                }
-                error!("splice error {:?}", e);
-                return Err(io::Error::new(ErrorKind::Other, e.desc()));
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("bad PROXY protocol header from {}: {}", self.client, e),
+                ))
             }
         };
+        self.down_sock.read_exact(&mut buf[..len])?;
+        self.last_active = Instant::now();
+        if let Some(source) = source {
+            debug!(session = self.id, balancer:% = self.client, client:% = source; "PROXY protocol header");
+            self.client = Peer::Ip(source);
+        }
+        self.state = if self.down_sock.is_tls() { State::TlsHandshake } else { State::Head(Speaks::Detect) };
+        Ok(())
+    }
 
-        r = splice(
-            read_pipe.as_fd(),
-            None,
-            dst.as_fd(),
-            None,
-            8192,
-            SpliceFFlags::SPLICE_F_NONBLOCK,
-        );
-        match r {
-            Ok(u) => {
-                if u == 0 {
+    /// Takes a TLS client's handshake as far as the socket lets it, the
+    /// request is read once it completed; WouldBlock until then.
+    pub fn tls_handshake(&mut self) -> io::Result<()> {
+        match self.down_sock.handshake() {
+            Err(e) if e.kind() != ErrorKind::WouldBlock => return Err(self.handshake_failed(e)),
+            r => r?,
+        }
+        self.last_active = Instant::now();
+        #[cfg(feature = "tls")]
+        self.certified(self.down_sock.peer_identity());
+        debug!(session = self.id, client:% = self.client, user = self.user.as_deref(); "tls handshake complete");
+        self.state = State::Head(Speaks::Detect);
+        Ok(())
+    }
+
+    /// Takes the user a client certificate names, for the session's
+    /// ACLs and logs and in place of its credentials; None without one.
+    #[cfg(feature = "tls")]
+    pub fn certified(&mut self, user: Option<String>) {
+        self.by_certificate = user.is_some();
+        if user.is_some() {
+            self.user = user;
+        }
+    }
+
+    /// Logs a TLS client's failed handshake, auditing one that failed over
+    /// its certificate, see `Config::listener_client_ca`.
+    fn handshake_failed(&mut self, e: io::Error) -> io::Error {
+        warn!(session = self.id, client:% = self.client, err:% = e; "tls handshake failed");
+        #[cfg(feature = "tls")]
+        if let Some(rule) = tls::client_cert_refused(&e) {
+            self.stats.denied_on(self.listener);
+            audit_log::denied(&self.client, None, Denial::Auth, Some(rule), Some(self.id));
+            self.close_reason = Some(CloseReason::Denied);
+        }
+        e
+    }
+
+    /// The client connection, for a TLS client that settled on h2 and
+    /// goes on as an `h2::Connection` rather than this session.
+    #[cfg(feature = "tls")]
+    pub fn into_client(self) -> ClientStream {
+        self.down_sock
+    }
+
+    /// Reads what the client sent so far into `connect_header_buf`.
+    fn read_head(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; 1024];
+        loop {
+            match self.down_sock.read(&mut buf) {
+                Ok(0) => {
+                    self.close_reason.get_or_insert(CloseReason::ClientClosed);
                     return Err(io::Error::new(ErrorKind::UnexpectedEof, "eof"));
                 }
-    
-                send = send + u;
-            }
-            Err(e) => {
-                if e == Errno::EAGAIN {
-                    break;
+                Ok(n) => {
+                    self.connect_header_buf.extend_from_slice(&buf[..n]);
+                    self.last_active = Instant::now();
                 }
-                error!("splice error {:?}", e);
-                return Err(io::Error::new(ErrorKind::Other, e.desc()));
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
             }
+        }
+    }
+
+    /// Takes `host:port` as the destination, with the timeouts for it.
+    fn target(&mut self, host: &str, port: u16) {
+        // a CONNECT, a SOCKS request, a websocket upgrade or a connection
+        // without a request is a tunnel whatever the port; any other
+        // request is forwarded as it came, to port 443 too
+        self.is_https = self.method().is_some_and(|m| m.eq_ignore_ascii_case("CONNECT"))
+            || self.socks.is_some()
+            || self.ws_accept.is_some()
+            || self.unrequested();
+        self.host = host.to_owned();
+        self.port = port;
+        self.timeouts = self.profile.timeouts(&self.config.timeouts, Some((host, port)));
+        debug!("timeouts for {}:{} in profile {} {:?}", host, port, self.profile.name, self.timeouts);
+    }
+
+    /// Dials the destination, or the parent proxy it is routed through,
+    /// once quota, acl and `Config::block_internal` let it through.
+    fn open(&mut self, poll: &Registry, dns: &mut DNS) -> io::Result<RawFd> {
+        let (host, port) = (self.host.clone(), self.port);
+        let host = host.as_str();
+        if let Some(rule) = self.over_quota() {
+            self.deny(Denial::Quota, &rule);
+            return Err(io::Error::new(ErrorKind::PermissionDenied, format!("denied by {}", rule)));
+        }
+        // rules by country or asn wait for the address
+        let now = self.config.acl_timezone.wall(SystemTime::now());
+        let by_place = acl::needs_place(self.config.acl_for(self.user.as_deref()).0, host, port, now);
+        if !by_place {
+            self.check_acl(host, port, None, now)?;
+        }
+        let route = parent::route(&self.config.routes, host, port);
+        let mut parents = self.config.parents_for(route);
+        parent_health::order(&mut parents);
+        self.parents_left = parents.into();
+        self.parent = self.parents_left.pop_front();
+        self.send_proxy_header = self.parent.is_none() && route.is_some_and(|r| r.proxy_protocol);
+        self.mptcp = route.and_then(|r| r.mptcp).unwrap_or(self.config.upstream_mptcp);
+        // no address to resolve or to place, nor one block_internal could
+        // refuse: the route's socket is ours to pick
+        if let Some(path) = route.and_then(|r| r.unix_socket.clone()) {
+            if by_place {
+                let place = Place::default();
+                self.place = Some(place);
+                self.check_acl(host, port, Some(&place), now)?;
+            }
+            self.up_path = Some(path);
+            return self.dial(poll);
+        }
+        #[cfg(feature = "tls")]
+        if let Some(r) = route.filter(|r| r.wrap_tls) {
+            let ctx = self.config.tls_clients.get(&r.tls_ca).cloned();
+            let ctx = ctx.ok_or_else(|| io::Error::other("wrap_tls certificates not loaded"))?;
+            let name = r.tls_sni.clone().unwrap_or_else(|| host.to_owned());
+            self.up_tls = Some(UpTls { ctx, name, verify: r.tls_verify });
+            // the client's plaintext goes through the TLS session
+            self.buffered.get_or_insert_with(Buffers::default);
+        }
+        // a transparent session's name from its hello is dialed only when
+        // `transparent_sni` says so, else the address it was headed to
+        let original = self.original_dst.map(|to| to.ip().to_string());
+        let ips = match &original {
+            _ if self.parent.is_some() => self.resolve_parent(dns),
+            Some(ip) if self.config.transparent_sni != TransparentSni::Dial => dns.query(ip),
+            _ => dns.query(host),
         };
+        let dial_port = self.parent.as_ref().map_or(port, |p| p.port);
+        if ips.is_none() {
+            self.connect_failure = Some(ConnectFailure::Dns);
+            // NotFound is what `err::classify` takes for a failed lookup
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                match &self.parent {
+                    Some(p) => format!("dns query for parent proxy {} failed", p),
+                    None => "dns query failed".to_owned(),
+                },
+            ));
+        }
+        // the addresses checked here are the only ones ever dialed, a
+        // retry takes the next of them rather than resolving again
+        let mut ips = ips.unwrap();
+        self.milestones.resolved = Some(Instant::now());
+        // a tunnel's upstream is the operator's to pick, not the client's
+        if self.parent.is_none() && self.config.block_internal && self.tunnel.is_none() {
+            let first = ips.iter().find_map(|&ip| self.internal(ip).map(|kind| (kind, ip)));
+            ips.retain(|&ip| self.internal(ip).is_none());
+            match first {
+                Some((kind, ip)) if ips.is_empty() => {
+                    let rule = format!("block_internal:{}:{}", kind, ip);
+                    self.deny(Denial::Internal, &rule);
+                    return Err(io::Error::new(ErrorKind::PermissionDenied, format!("destination denied by {}", rule)));
+                }
+                Some((kind, ip)) => debug!("session {} passing over {} address {} of {}", self.id, kind, ip, host),
+                None => {}
+            }
+        }
+        if by_place {
+            // the destination's own address, not the parent proxy's; the
+            // acl decides on that one, the only one dialed then
+            let dest = match &self.parent {
+                Some(_) => dns.query(host).and_then(|ips| ips.first().copied()),
+                None => {
+                    ips.truncate(1);
+                    Some(ips[0])
+                }
+            };
+            let place = match (&self.config.geoip, dest) {
+                (Some(geoip), Some(dest)) => geoip.lookup(dest),
+                _ => Place::default(),
+            };
+            debug!("session {} destination {:?} place {:?}", self.id, dest, place);
+            self.place = Some(place);
+            self.check_acl(host, port, Some(&place), now)?;
+        }
+        self.up_addrs = ips.into_iter().map(|ip| SocketAddr::new(ip, dial_port)).collect();
+        match self.dial(poll) {
+            Err(e) if self.next_parent(&e.to_string()) => self.dial_parent(poll, dns),
+            r => r,
+        }
+    }
+
+    /// The addresses of `parent`. One whose name does not resolve is down
+    /// like one that does not answer, the next is taken; None once none
+    /// of the route's is left.
+    fn resolve_parent(&mut self, dns: &mut DNS) -> Option<Vec<IpAddr>> {
+        loop {
+            let parent = self.parent.as_ref()?;
+            if let Some(ips) = dns.query(&parent.host) {
+                return Some(ips);
+            }
+            if !self.next_parent("dns query failed") {
+                return None;
+            }
+        }
+    }
+
+    /// Marks `parent` down for having failed with `why`, see
+    /// `parent_health::failed`, and moves on to the next one the route
+    /// has. False when there is none left, `parent` staying the one that
+    /// failed.
+    fn next_parent(&mut self, why: &str) -> bool {
+        let Some(failed) = self.parent.take() else {
+            return false;
+        };
+        parent_health::failed(&failed, why);
+        let Some(next) = self.parents_left.pop_front() else {
+            self.parent = Some(failed);
+            return false;
+        };
+        info!(session = self.id, host = self.host.as_str(); "parent proxy {} failed, trying {}", failed, next);
+        self.parent = Some(next);
+        true
+    }
+
+    /// Dials `parent` afresh, after the one before it failed: resolves
+    /// it, then connects to its first address, the next parent on a
+    /// connect that fails right away.
+    fn dial_parent(&mut self, poll: &Registry, dns: &mut DNS) -> io::Result<RawFd> {
+        loop {
+            let Some(ips) = self.resolve_parent(dns) else {
+                self.connect_failure = Some(ConnectFailure::Dns);
+                let parent = self.parent.as_ref().map(ParentProxy::to_string).unwrap_or_default();
+                let why = format!("dns query for parent proxy {} failed", parent);
+                return Err(io::Error::new(ErrorKind::NotFound, why));
+            };
+            let port = self.parent.as_ref().map_or(0, |p| p.port);
+            self.up_addrs = ips.into_iter().map(|ip| SocketAddr::new(ip, port)).collect();
+            self.connect_failure = None;
+            match self.dial(poll) {
+                Err(e) if self.next_parent(&e.to_string()) => {}
+                r => return r,
+            }
+        }
+    }
+
+    /// What kind of internal address `ip` is, when `Config::allow_internal`
+    /// does not let it through.
+    fn internal(&self, ip: IpAddr) -> Option<&'static str> {
+        internal_addrs::blocked(ip, &self.config.allow_internal)
+    }
+
+    /// Opens the relay of a UDP ASSOCIATE and tells the client where it
+    /// is: the address of the client's connection with the relay socket's
+    /// port. `port` is the one the client sends from, 0 when not known.
+    fn associate(&mut self, poll: &Registry, port: u16) -> io::Result<Token> {
+        let (Peer::Ip(client), Some(tcp)) = (self.client, self.down_sock.as_tcp()) else {
+            let _ = self.down_sock.write_all(&self.socks_reply(Reply::CommandNotSupported, None));
+            return Err(io::Error::new(ErrorKind::InvalidInput, "socks5 UDP ASSOCIATE on a unix socket"));
+        };
+        let local = tcp.local_addr()?;
+        if let Some(rule) = self.over_quota() {
+            self.deny(Denial::Quota, &rule);
+            return Err(io::Error::new(ErrorKind::PermissionDenied, format!("denied by {}", rule)));
+        }
+        let mut udp = Association::bind(client, port)?;
+        let token = udp.token();
+        poll.register(&mut udp.socket, token, Interest::READABLE)?;
+        let bound = SocketAddr::new(local.ip().to_canonical(), udp.socket.local_addr()?.port());
+        self.send_down(&self.socks_reply(Reply::Succeeded, Some(bound)))?;
+        debug!("session {} relaying datagrams of {} on {}", self.id, client, bound);
+        self.udp = Some(udp);
+        self.state = State::Associated;
+        self.outcome = Outcome::Established;
+        self.milestones.established = Some(Instant::now());
+        self.classify(TrafficClass::Udp);
+        Ok(token)
+    }
+
+    /// Relays the datagrams waiting on the association's socket: the
+    /// client's to the destinations their headers name, as far as policy
+    /// lets them, and the answers of those destinations back to it. Up to
+    /// `Config::pipe_budget` bytes, `Drain::Again` when there may be more.
+    pub(crate) fn relay(&mut self, dns: &mut DNS) -> io::Result<Drain> {
+        let mut buf = [0u8; udp_relay::MAX_DATAGRAM];
+        let idle = self.idle_timeout();
+        let mut moved = 0;
+        while moved < self.config.pipe_budget {
+            let Some(udp) = self.udp.as_mut() else {
+                return Ok(Drain::Done);
+            };
+            let (n, from) = match udp.socket.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(Drain::Done),
+                Err(e) => return Err(e),
+            };
+            let from = SocketAddr::new(from.ip().to_canonical(), from.port());
+            let now = Instant::now();
+            udp.expire(idle, now);
+            let relayed = if udp.is_client(from) {
+                self.datagram_up(&buf[..n], dns, now)
+            } else if udp.is_peer(from, idle, now) {
+                self.datagram_down(from, &buf[..n])
+            } else {
+                debug!("session {} dropping datagram from {}", self.id, from);
+                None
+            };
+            match relayed {
+                Some(size) => {
+                    moved += size;
+                    self.last_active = now;
+                }
+                None => self.udp.iter_mut().for_each(|u| u.dropped += 1),
+            }
+        }
+        Ok(Drain::Again)
+    }
+
+    /// Sends the payload of a datagram from the client on to where its
+    /// header says, once that destination was decided. Its size, None
+    /// when it was dropped.
+    fn datagram_up(&mut self, datagram: &[u8], dns: &mut DNS, now: Instant) -> Option<usize> {
+        let Some(header) = socks::datagram(datagram) else {
+            debug!("session {} dropping malformed datagram", self.id);
+            return None;
+        };
+        // reassembly is optional, RFC 1928 section 7
+        if header.frag != 0 {
+            debug!("session {} dropping datagram fragment {}", self.id, header.frag);
+            return None;
+        }
+        let (host, port) = (header.host.as_str(), header.port);
+        let to = match self.udp.as_ref()?.target(host, port, now) {
+            Some(to) => to,
+            None => {
+                let to = self.udp_target(host, port, dns);
+                if !self.udp.as_mut()?.decided(host, port, to, now) {
+                    debug!("session {} dropping datagram for {}:{}, too many destinations", self.id, host, port);
+                    return None;
+                }
+                to
+            }
+        }?;
+        let udp = self.udp.as_mut()?;
+        let payload = &datagram[header.payload..];
+        if let Err(e) = udp.socket.send_to(payload, udp.destination(to)) {
+            debug!("session {} datagram to {} failed: {}", self.id, to, e);
+            return None;
+        }
+        udp.sent(to, now);
+        self.datagram_moved(true, payload.len());
+        Some(payload.len())
+    }
+
+    /// Sends a datagram from the peer `from` to the client, behind the
+    /// header that names it.
+    fn datagram_down(&mut self, from: SocketAddr, payload: &[u8]) -> Option<usize> {
+        let udp = self.udp.as_mut()?;
+        let client = udp.client()?;
+        let mut datagram = socks::datagram_header(from);
+        datagram.extend_from_slice(payload);
+        if let Err(e) = udp.socket.send_to(&datagram, udp.destination(client)) {
+            debug!("session {} datagram from {} to the client failed: {}", self.id, from, e);
+            return None;
+        }
+        self.datagram_moved(false, payload.len());
+        Some(payload.len())
+    }
+
+    fn datagram_moved(&mut self, up: bool, size: usize) {
+        if up {
+            self.bytes_up += size as u64;
+        } else {
+            self.bytes_down += size as u64;
+        }
+        self.stats.bytes_moved(self.listener, up, size);
+        self.stats.class_bytes_moved(TrafficClass::Udp, up, size);
+    }
+
+    /// Where the client's datagrams for `host:port` go, None when policy
+    /// drops them: what `open` checks of a destination, audited alike, but
+    /// the client is not told. A destination routed through a parent
+    /// proxy is dropped too, the parent only takes a CONNECT.
+    fn udp_target(&mut self, host: &str, port: u16, dns: &mut DNS) -> Option<SocketAddr> {
+        let refused = |s: &Self, denial: Denial, rule: &str| {
+            debug!("session {} dropping datagrams for {}:{}, denied by {}", s.id, host, port, rule);
+            s.stats.denied_on(s.listener);
+            audit_log::denied(&s.client, Some(&authority(host, port)), denial, Some(rule), Some(s.id));
+            None
+        };
+        if let Some(rule) = self.over_quota() {
+            return refused(self, Denial::Quota, &rule);
+        }
+        // rules by country or asn wait for the address, as in `open`
+        let now = self.config.acl_timezone.wall(SystemTime::now());
+        let by_place = acl::needs_place(self.config.acl_for(self.user.as_deref()).0, host, port, now);
+        if !by_place {
+            if let Err((denial, rule)) = self.acl_decision(host, port, None, now) {
+                return refused(self, denial, &rule);
+            }
+        }
+        if parent::route_for(&self.config.routes, host, port) != Via::Direct {
+            debug!("session {} dropping datagrams for {}:{}, routed through a parent", self.id, host, port);
+            return None;
+        }
+        let Some(ips) = dns.query(host).filter(|ips| !ips.is_empty()) else {
+            debug!("session {} dropping datagrams for {}:{}, dns query failed", self.id, host, port);
+            return None;
+        };
+        let ip = match ips.iter().copied().find(|&ip| !self.config.block_internal || self.internal(ip).is_none()) {
+            Some(ip) => ip,
+            None => {
+                let kind = self.internal(ips[0]).unwrap_or_default();
+                return refused(self, Denial::Internal, &format!("block_internal:{}:{}", kind, ips[0]));
+            }
+        };
+        if by_place {
+            let place = self.config.geoip.as_ref().map_or_else(Place::default, |g| g.lookup(ip));
+            if let Err((denial, rule)) = self.acl_decision(host, port, Some(&place), now) {
+                return refused(self, denial, &rule);
+            }
+        }
+        Some(SocketAddr::new(ip, port))
+    }
+
+    /// Reads and drops what the client sends on the connection of a UDP
+    /// association; it carries nothing, its end ends the association.
+    pub(crate) fn drain_control(&mut self) -> io::Result<Drain> {
+        let mut buf = [0u8; 1024];
+        loop {
+            match self.down_sock.read(&mut buf) {
+                Ok(0) => {
+                    self.close_reason.get_or_insert(CloseReason::ClientClosed);
+                    return Ok(Drain::Done);
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(Drain::Done),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Connects to the next of `up_addrs`, or to `up_path`, and registers
+    /// the socket, in place of the one before when there was one.
+    fn dial(&mut self, poll: &Registry) -> io::Result<RawFd> {
+        let mut up_sock = match self.up_path.clone() {
+            Some(path) => self.dial_unix(&path)?,
+            None => self.dial_tcp()?,
+        };
+        let up_sock_fd = &up_sock.as_raw_fd();
+        debug!("session {} client fd {} upstream fd {}", self.id, self.down_sock_id, up_sock_fd);
+        match poll.register(
+            &mut up_sock,
+            TokenSpace::session(*up_sock_fd),
+            Interest::READABLE | Interest::WRITABLE,
+        ) {
+            Ok(_) => {
+                // closed only now, so the new socket cannot have its fd
+                if let Some(mut old) = self.up_sock.replace(up_sock) {
+                    let _ = poll.deregister(&mut old);
+                }
+                self.up_sock_id = (*up_sock_fd).try_into().unwrap();
+                self.state = State::Connecting;
+                // a handshake with the parent before starts over with this one
+                self.queued_up.clear();
+                if self.send_proxy_header {
+                    let source = match self.client {
+                        Peer::Ip(addr) => Some(addr),
+                        Peer::Local => None,
+                    };
+                    let destination = self.down_sock.as_tcp().and_then(|s| s.local_addr().ok());
+                    self.proxy_header = proxy_protocol::v2_header(source, destination);
+                }
+
+                Ok(*up_sock_fd)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Connects to the next of `up_addrs`. An address whose connect fails
+    /// right away is passed over for the next.
+    fn dial_tcp(&mut self) -> io::Result<UpStream> {
+        loop {
+            let up_addr = self
+                .up_addrs
+                .pop_front()
+                .ok_or_else(|| io::Error::other("no address left to dial"))?;
+            debug!("up addr  {:?} via parent {:?}", &up_addr, self.parent.as_ref().map(|p| p.to_string()));
+            let connect = match (self.spoof_source, &self.parent, self.client) {
+                (true, None, Peer::Ip(client)) if client.ip().to_canonical().is_ipv4() == up_addr.is_ipv4() => {
+                    transparent::connect_from(client.ip().to_canonical(), up_addr)
+                }
+                // once it fell back for one address the others go over TCP too
+                _ if self.mptcp && self.mptcp_negotiated.is_none() => match mptcp::connect(up_addr) {
+                    Ok(Some(sock)) => {
+                        self.mptcp_dialed = Some(up_addr);
+                        Ok(sock)
+                    }
+                    Ok(None) | Err(_) => {
+                        self.mptcp_negotiated = Some(false);
+                        TcpStream::connect(up_addr)
+                    }
+                },
+                _ => TcpStream::connect(up_addr),
+            };
+            match connect {
+                Ok(sock) => return self.upstream(sock),
+                Err(e) if !self.up_addrs.is_empty() => {
+                    debug!("session {} connect {} failed, trying the next address: {}", self.id, up_addr, e)
+                }
+                Err(e) => {
+                    self.connect_failure = Some(self.failure_kind(&e));
+                    return Err(match &self.parent {
+                        Some(p) => io::Error::new(e.kind(), format!("connect parent proxy {}: {}", p, e)),
+                        None => e,
+                    });
+                }
+            }
+        }
+    }
+
+    /// `sock` as the upstream, with TLS on top when the route wraps it.
+    fn upstream(&self, sock: TcpStream) -> io::Result<UpStream> {
+        #[cfg(feature = "tls")]
+        if let Some(t) = &self.up_tls {
+            return Ok(UpStream::Tls(Box::new(TlsStream::connect(&t.ctx, sock, &t.name, t.verify)?)));
+        }
+        Ok(UpStream::Tcp(sock))
+    }
+
+    /// Connects to the unix socket at `path`. A missing socket counts as
+    /// refused, nothing listens there.
+    fn dial_unix(&mut self, path: &Path) -> io::Result<UpStream> {
+        debug!("session {} up path {}", self.id, path.display());
+        UnixStream::connect(path).map(UpStream::Unix).map_err(|e| {
+            let kind = match e.kind() {
+                ErrorKind::NotFound => ErrorKind::ConnectionRefused,
+                kind => kind,
+            };
+            let e = io::Error::new(kind, format!("connect unix socket {}: {}", path.display(), e));
+            self.connect_failure = Some(ConnectFailure::from_error(&e));
+            e
+        })
+    }
+
+    /// After the upstream connect failed, dials the next address `connect`
+    /// let through, None when there is none left. The caller moves the
+    /// session from the old upstream token to the new one.
+    /// `why` is what the upstream failed with, None when it was the
+    /// client's socket that did; a parent proxy that failed, at the
+    /// connect or in our handshake, has the session fail over.
+    pub fn redial(&mut self, poll: &Registry, dns: &mut DNS, why: Option<&str>) -> Option<io::Result<RawFd>> {
+        match self.state {
+            State::Connecting => {}
+            State::ParentHandshake if !self.parent_answered => return self.fail_over(poll, dns, why?),
+            _ => return None,
+        }
+        // something on the way may drop what it does not know, the same
+        // address gets another go over plain TCP
+        if let Some(addr) = self.mptcp_dialed.take() {
+            debug!("session {} MPTCP connect to {} failed, trying TCP", self.id, addr);
+            self.mptcp_negotiated = Some(false);
+            self.up_addrs.push_front(addr);
+        }
+        if self.up_addrs.is_empty() {
+            return self.fail_over(poll, dns, why?);
+        }
+        debug!("session {} connect to {} failed, trying the next address", self.id, self.host);
+        self.connect_failure = None;
+        Some(self.dial(poll))
+    }
+
+    /// Has the session go through the next of the route's parents once
+    /// `parent` failed with `why`, None when it has none or none is left.
+    fn fail_over(&mut self, poll: &Registry, dns: &mut DNS, why: &str) -> Option<io::Result<RawFd>> {
+        if !self.next_parent(why) {
+            return None;
+        }
+        self.parent_socks = None;
+        self.parent_buf.clear();
+        self.mptcp_dialed = None;
+        // the next parent gets `Config::parent_timeout` of its own
+        self.last_active = Instant::now();
+        Some(self.dial_parent(poll, dns))
+    }
+
+    /// Pumps the direction whose source is `sock_id`.
+    pub(crate) fn pipe(&mut self, sock_id: usize) -> io::Result<Drain> {
+        let drain = if sock_id == self.down_sock_id {
+            self.down2up()?
+        } else if sock_id == self.up_sock_id {
+            self.up2down()?
+        } else {
+            Drain::Done
+        };
+
+        debug!("piping {} {:?}", self.host, drain);
+        Ok(drain)
+    }
+
+    /// Pumps both directions. Used after the tunnel is established, when
+    /// requeued after a budget cut, and whenever bytes may be waiting on either
+    /// side without a fresh edge to announce them.
+    pub(crate) fn pump(&mut self) -> io::Result<Drain> {
+        let down = self.down2up()?;
+        let up = self.up2down()?;
+        Ok(match (down, up) {
+            (Drain::Again, _) | (_, Drain::Again) => Drain::Again,
+            (Drain::Wait(a), Drain::Wait(b)) => Drain::Wait(a.min(b)),
+            (Drain::Wait(at), _) | (_, Drain::Wait(at)) => Drain::Wait(at),
+            _ => Drain::Done,
+        })
+    }
+
+    /// What one pump of a direction may move: `Config::pipe_budget`, or
+    /// less when that is all the session's limit has left. With nothing
+    /// left, the `Drain::Wait` to return.
+    fn budget(&self) -> Result<usize, Drain> {
+        let budget = self.config.pipe_budget;
+        match &self.throttle {
+            None => Ok(budget),
+            Some(t) => t
+                .limit()
+                .allowance()
+                .map(|n| n.min(budget))
+                .map_err(|wait| Drain::Wait(Instant::now() + wait)),
+        }
+    }
+
+    fn handle_up_sock_connected(&mut self, token: Token) -> io::Result<Drain> {
+        match self.state {
+            State::ProxyHeader | State::TlsHandshake | State::Head(_) | State::ParentHandshake | State::Associated => {
+                // a reply or a handshake of ours the socket did not take whole
+                self.flush(token)?;
+                Ok(Drain::Done)
+            }
+            State::Connecting => {
+                let up_sock_id = self.up_sock_id;
+                if token.0 != up_sock_id {
+                    return Ok(Drain::Done);
+                }
+                // the connect went through, what fails from here on is not
+                // for `redial` to try over TCP
+                if self.mptcp_dialed.take().is_some() {
+                    self.mptcp_negotiated = Some(self.up_sock.as_ref().is_some_and(mptcp::negotiated));
+                }
+                // the header goes whole and first, before the client hears
+                // back or its request goes on
+                if !self.write_proxy_header()? || !self.upstream_handshake()? {
+                    return Ok(Drain::Done);
+                }
+                if let Some(negotiated) = self.mptcp_negotiated {
+                    self.stats.mptcp(negotiated);
+                }
+                debug!("session {} connected to {} mptcp {:?}", self.id, self.host, self.mptcp_negotiated);
+                let connected = Instant::now();
+                if let Some(resolved) = self.milestones.resolved {
+                    self.stats.connect_latency.record(connected - resolved);
+                }
+                self.milestones.connected = Some(connected);
+                if let Some(parent) = self.parent.clone() {
+                    // a plain request goes through a SOCKS parent's tunnel too
+                    if parent.is_socks() {
+                        debug!("send SOCKS5 greeting to parent {}", parent);
+                        self.send_up(&socks::client_greeting(parent.socks_credentials().is_some()))?;
+                        self.parent_socks = Some(Handshake::Greeting);
+                        self.state = State::ParentHandshake;
+                        return Ok(Drain::Done);
+                    }
+                    if self.is_https {
+                        // the client hears back once the parent said yes
+                        debug!("send CONNECT to parent {}", parent);
+                        self.send_up(&parent.connect_request(&authority(&self.host, self.port)))?;
+                        self.state = State::ParentHandshake;
+                        return Ok(Drain::Done);
+                    }
+                    debug!("forward request to parent {}", parent);
+                    self.send_up(&parent.forward_request(&self.connect_header_buf))?;
+                    // what it answers is the origin's, the connect is all we know of it
+                    parent_health::worked(&parent);
+                } else if self.is_https {
+                    debug!("respond https");
+                    self.tunnel_established()?;
+                } else {
+                    debug!("respond http");
+                    let head = self.connect_header_buf.clone();
+                    self.send_up(&head)?;
+                }
+                self.state = State::Piping;
+                self.outcome = Outcome::Established;
+                self.established();
+                // readable edges seen while connecting were skipped, drain now
+                self.pump()
+            }
+            State::Piping => {
+                // the writable side had filled up, flush what is pending toward it
+                if token.0 == self.up_sock_id {
+                    self.down2up()
+                } else {
+                    self.up2down()
+                }
+            }
+        }
+    }
+
+    /// Takes the TLS handshake of a wrapped upstream as far as the socket
+    /// lets it, after the PROXY header and before anything else goes up.
+    /// False while it waits for the socket, true once it is complete or
+    /// for a plain upstream.
+    fn upstream_handshake(&mut self) -> io::Result<bool> {
+        let Some(up) = self.up_sock.as_mut() else {
+            return Ok(true);
+        };
+        match up.handshake() {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => {
+                let upstream = authority(&self.host, self.port);
+                warn!(session = self.id, client:% = self.client, err:% = e; "tls handshake with {} failed", upstream);
+                // the next address would present the same certificate
+                self.up_addrs.clear();
+                self.connect_failure = Some(ConnectFailure::Other);
+                Err(io::Error::other(format!("tls handshake with {}: {}", upstream, e)))
+            }
+        }
+    }
+
+    /// Whether the upstream connection gets TLS from us, see
+    /// `Route::wrap_tls`.
+    pub fn wraps_tls(&self) -> bool {
+        #[cfg(feature = "tls")]
+        return self.up_tls.is_some();
+        #[cfg(not(feature = "tls"))]
+        false
+    }
+
+    /// Writes what is left of `proxy_header` to the upstream. False while
+    /// part of it waits for the socket to take more, on its next writable
+    /// edge.
+    fn write_proxy_header(&mut self) -> io::Result<bool> {
+        match self.up_sock.as_mut() {
+            Some(up) => flush_queued(up, &mut self.proxy_header),
+            None => Ok(true),
+        }
+    }
+
+    /// Writes `bytes` to the client behind what is queued for it already,
+    /// queueing what its socket does not take.
+    fn send_down(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.queued_down.extend_from_slice(bytes);
+        flush_queued(&mut self.down_sock, &mut self.queued_down).map(drop)
+    }
+
+    /// `send_down` toward the upstream.
+    fn send_up(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let up = self
+            .up_sock
+            .as_mut()
+            .ok_or_else(|| io::Error::other("up not ready"))?;
+        self.queued_up.extend_from_slice(bytes);
+        flush_queued(up, &mut self.queued_up).map(drop)
+    }
+
+    /// Writes on what is queued toward the socket of `token`, true once
+    /// nothing is.
+    fn flush(&mut self, token: Token) -> io::Result<bool> {
+        if token.0 == self.down_sock_id {
+            flush_queued(&mut self.down_sock, &mut self.queued_down)
+        } else if let (true, Some(up)) = (token.0 == self.up_sock_id, self.up_sock.as_mut()) {
+            flush_queued(up, &mut self.queued_up)
+        } else {
+            Ok(true)
+        }
+    }
+
+    /// Reads the parent proxy's answer to our CONNECT. With a 200 the
+    /// client gets its own and the tunnel starts piping; anything else
+    /// is an error, answered with a 502 when the session closes.
+    pub(crate) fn parent_response(&mut self) -> io::Result<Drain> {
+        let Some(parent) = self.parent.clone() else {
+            return Ok(Drain::Done);
+        };
+        let eof = self.read_parent()?;
+        if self.parent_socks.is_some() {
+            return self.parent_socks_response(&parent, eof);
+        }
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut response = httparse::Response::new(&mut headers);
+        let head = match response.parse(&self.parent_buf) {
+            Ok(httparse::Status::Complete(n)) => n,
+            Ok(httparse::Status::Partial) if eof => {
+                return Err(io::Error::other(format!("parent proxy {} closed before answering", parent)))
+            }
+            Ok(httparse::Status::Partial) if self.parent_buf.len() < MAX_PARENT_HEAD => return Ok(Drain::Done),
+            Ok(httparse::Status::Partial) => {
+                return Err(io::Error::other(format!("parent proxy {} answer head too long", parent)))
+            }
+            Err(e) => return Err(io::Error::other(format!("parent proxy {} bad answer: {}", parent, e))),
+        };
+        if response.code != Some(200) {
+            self.parent_answered = true;
+            return Err(io::Error::other(format!(
+                "parent proxy {} refused CONNECT {}: {} {}",
+                parent,
+                authority(&self.host, self.port),
+                response.code.unwrap_or_default(),
+                response.reason.unwrap_or_default()
+            )));
+        }
+        debug!("parent {} established tunnel to {}", parent, self.host);
+        // bytes the origin sent right behind the parent's answer
+        let early = self.parent_buf.split_off(head);
+        self.parent_established(&early)
+    }
+
+    /// Takes the SOCKS5 parent's answers as far as they came in, each
+    /// followed by our next step: our credentials when it picked
+    /// username/password, then the CONNECT, by name for it to resolve.
+    /// A refusal is an error like an HTTP parent's.
+    fn parent_socks_response(&mut self, parent: &ParentProxy, eof: bool) -> io::Result<Drain> {
+        let bad = |why| io::Error::other(format!("parent socks proxy {} {}", parent, why));
+        let connect = match socks::client_connect(&self.host, self.port) {
+            Ok(connect) => connect,
+            // a name no parent takes, nor would the next
+            Err(why) => {
+                self.parent_answered = true;
+                return Err(bad(why));
+            }
+        };
+        loop {
+            match self.parent_socks {
+                Some(Handshake::Greeting) => {
+                    let Some((n, method)) = socks::method_choice(&self.parent_buf).map_err(bad)? else {
+                        break;
+                    };
+                    self.parent_buf.drain(..n);
+                    let next = match (method, parent.socks_credentials()) {
+                        (socks::USER_PASS, Some((user, password))) => {
+                            self.send_up(&socks::client_auth(user, password.expose()))?;
+                            Handshake::Auth
+                        }
+                        (socks::NO_AUTH, _) => {
+                            self.send_up(&connect)?;
+                            Handshake::Request
+                        }
+                        _ => {
+                            self.parent_answered = true;
+                            return Err(bad("takes none of the methods we offer"));
+                        }
+                    };
+                    self.parent_socks = Some(next);
+                }
+                Some(Handshake::Auth) => {
+                    let Some((n, ok)) = socks::auth_status(&self.parent_buf).map_err(bad)? else {
+                        break;
+                    };
+                    if !ok {
+                        self.parent_answered = true;
+                        return Err(bad("refused our credentials"));
+                    }
+                    self.parent_buf.drain(..n);
+                    self.send_up(&connect)?;
+                    self.parent_socks = Some(Handshake::Request);
+                }
+                Some(Handshake::Request) => {
+                    let Some((n, reply)) = socks::connect_reply(&self.parent_buf).map_err(bad)? else {
+                        break;
+                    };
+                    if reply != Reply::Succeeded as u8 {
+                        self.parent_answered = true;
+                        let authority = authority(&self.host, self.port);
+                        return Err(bad(&format!("refused CONNECT {}: reply {}", authority, reply)));
+                    }
+                    debug!("parent socks proxy {} established tunnel to {}", parent, self.host);
+                    self.parent_socks = Some(Handshake::Done(Version::Five));
+                    let early = self.parent_buf.split_off(n);
+                    return self.parent_established(&early);
+                }
+                _ => return Ok(Drain::Done),
+            }
+        }
+        if eof {
+            return Err(bad("closed before answering"));
+        }
+        Ok(Drain::Done)
+    }
+
+    /// Reads what the parent sent so far into `parent_buf`, true at its
+    /// end of file.
+    fn read_parent(&mut self) -> io::Result<bool> {
+        let up = self
+            .up_sock
+            .as_mut()
+            .ok_or_else(|| io::Error::other("up not ready"))?;
+        let mut buf = [0u8; 1024];
+        while self.parent_buf.len() < MAX_PARENT_HEAD {
+            match up.read(&mut buf) {
+                // a refusal often comes with the parent closing right away
+                Ok(0) => return Ok(true),
+                Ok(n) => self.parent_buf.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(false)
+    }
+
+    /// The parent opened the tunnel: the client hears it is up, or its
+    /// plain request goes through, and piping starts with the `early`
+    /// bytes the origin sent behind the parent's answer.
+    fn parent_established(&mut self, early: &[u8]) -> io::Result<Drain> {
+        self.parent_answered = true;
+        if let Some(parent) = &self.parent {
+            parent_health::worked(parent);
+        }
+        if self.is_https {
+            self.tunnel_established()?;
+        } else {
+            let head = self.connect_header_buf.clone();
+            self.send_up(&head)?;
+        }
+        self.send_down(early)?;
+        self.parent_buf = Vec::new();
+        self.state = State::Piping;
+        self.outcome = Outcome::Established;
+        self.established();
+        self.pump()
+    }
+
+    /// Tells the client its tunnel is up: a 200 to a CONNECT, the SOCKS
+    /// success reply with the address we connect from, after which the
+    /// bytes the client sent behind its request go upstream. A redirected
+    /// or tunnel listener's client is told nothing.
+    fn tunnel_established(&mut self) -> io::Result<()> {
+        // the client of a redirected connection thinks it is talking to
+        // the destination already
+        if self.unrequested() {
+            return Ok(());
+        }
+        if let Some(accept) = self.ws_accept.take() {
+            // framed from the first byte behind it, the 101 goes out first
+            self.down_sock.start_websocket(websocket::switching(&accept).as_bytes());
+            return Ok(());
+        }
+        if self.socks.is_none() {
+            return self.send_down(b"HTTP/1.1 200 Connection established\r\n\r\n");
+        }
+        let bound = self.up_sock.as_ref().and_then(UpStream::local_addr);
+        self.send_down(&self.socks_reply(Reply::Succeeded, bound))?;
+        let early = self.connect_header_buf.clone();
+        self.send_up(&early)
+    }
+
+    /// Records how long each step to the tunnel took, warning when all of
+    /// them together reached `Config::slow_establishment`.
+    fn established(&mut self) {
+        if !self.is_https {
+            self.classify(TrafficClass::ForwardHttp);
+        }
+        let now = Instant::now();
+        self.milestones.established = Some(now);
+        let m = &self.milestones;
+        let (Some(head), Some(resolved), Some(connected)) = (m.head, m.resolved, m.connected) else {
+            return;
+        };
+        let phases = [
+            (Phase::Head, head - self.created),
+            (Phase::Dns, resolved - head),
+            (Phase::Connect, connected - resolved),
+            (Phase::Reply, now - connected),
+        ];
+        for (phase, took) in phases {
+            self.stats.establish_latency[phase as usize].record(took);
+        }
+        let total = now - self.created;
+        if total >= self.config.slow_establishment {
+            warn!(
+                session = self.id, client:% = self.client, host = self.host.as_str(), total:? = total,
+                head:? = phases[0].1, dns:? = phases[1].1, connect:? = phases[2].1, reply:? = phases[3].1;
+                "slow establishment"
+            );
+        }
+    }
+
+    /// The audit rule when `user` moved their `UserPolicy::daily_quota`
+    /// today.
+    pub fn over_quota(&self) -> Option<String> {
+        let user = self.user.as_deref()?;
+        let quota = self.config.users.get(user)?.daily_quota?;
+        (quota::used(user) >= quota).then(|| format!("daily_quota:{}", quota))
+    }
+
+    /// Counts the bytes moved since the last call toward `user`'s quota
+    /// day, from the heartbeat and as the session closes; a session going
+    /// past the reset counts what it moved after toward the new day.
+    pub fn charge_quota(&mut self) {
+        let Some(user) = &self.user else {
+            return;
+        };
+        let moved = self.bytes_up + self.bytes_down;
+        if moved > self.charged {
+            quota::charge(user, moved - self.charged);
+            self.charged = moved;
+        }
+    }
+
+    /// Answers 403 when `acl::check` refuses `host:port`, else takes the
+    /// limit of the entry that allowed it.
+    fn check_acl(&mut self, host: &str, port: u16, place: Option<&Place>, now: WallTime) -> io::Result<()> {
+        match self.acl_decision(host, port, place, now) {
+            Ok(throttle) => {
+                self.throttle = throttle;
+                Ok(())
+            }
+            Err((denial, rule)) => {
+                self.deny(denial, &rule);
+                Err(io::Error::new(ErrorKind::PermissionDenied, format!("destination denied by {}", rule)))
+            }
+        }
+    }
+
+    /// `acl::check` of `host:port` for the session's user: the limit of the
+    /// entry that allowed it, or the denial with its rule. Counts the
+    /// entries that matched in `acl_hits`, logging what the `log` ones would
+    /// have denied.
+    fn acl_decision(
+        &self,
+        host: &str,
+        port: u16,
+        place: Option<&Place>,
+        now: WallTime,
+    ) -> Result<Option<Throttle>, (Denial, String)> {
+        let config = &self.config;
+        let acl = config.acl_for(self.user.as_deref());
+        let hit = |rule: &AclRule, m: Matched| {
+            acl_hits::hit(rule, host, port);
+            if rule.action == AclAction::Log {
+                info!(
+                    session = self.id, client:% = self.client, host = host, port = port, rule = rule.label.as_str();
+                    "acl would deny, by acl:{}", m
+                );
+            }
+        };
+        acl::check(acl, config.allowed_ports.as_ref(), host, port, place, now, hit)
+            .map(|rule| rule.and_then(bandwidth::throttle))
+            .map_err(|refusal| (refusal.denial(), refusal.to_string()))
+    }
+
+    /// With `Config::acl_window_close`, what refuses the session's
+    /// destination at `now` when its acl has time windows and one does.
+    /// The session's own config decides, the one it was accepted with.
+    pub fn acl_recheck(&self, now: SystemTime) -> Option<String> {
+        let acl = self.config.acl_for(self.user.as_deref());
+        if !self.config.acl_window_close || self.host.is_empty() || !acl.0.iter().any(AclRule::has_window) {
+            return None;
+        }
+        let allowed_ports = self.config.allowed_ports.as_ref();
+        let now = self.config.acl_timezone.wall(now);
+        acl::check(acl, allowed_ports, &self.host, self.port, self.place.as_ref(), now, |_, _| {})
+            .err()
+            .map(|refusal| refusal.to_string())
+    }
+
+    /// Answers 403 to a client whose destination `rule` does not allow,
+    /// before anything is dialed; a SOCKS client gets its not-allowed
+    /// reply.
+    fn deny(&mut self, denial: Denial, rule: &str) {
+        if self.unrequested() {
+            return self.refuse(denial, rule, &[]);
+        }
+        if self.socks.is_some() {
+            return self.refuse(denial, rule, &self.socks_reply(Reply::NotAllowed, None));
+        }
+        self.refuse(denial, rule, b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+    }
+
+    /// Answers `response` to a request `rule` refused.
+    fn refuse(&mut self, denial: Denial, rule: &str, response: &[u8]) {
+        debug!("session {} destination {}:{} denied by {}", self.id, self.host, self.port, rule);
+        self.stats.denied_on(self.listener);
+        audit_log::denied(&self.client, self.authority().as_deref(), denial, Some(rule), Some(self.id));
+        let _ = self.down_sock.write_all(response);
+        self.outcome = Outcome::Denied;
+    }
+
+    /// Answers 502 to a client whose parent proxy route, unix socket one
+    /// or `wrap_tls` one failed before the tunnel was up. Called as the
+    /// session closes, for any reason.
+    pub(crate) fn route_failed(&mut self) {
+        let upstream = match (&self.parent, &self.up_path) {
+            (Some(parent), _) => format!("parent proxy {}", parent),
+            (None, Some(path)) => format!("unix socket {}", path.display()),
+            (None, None) if self.wraps_tls() => format!("tls upstream {}", authority(&self.host, self.port)),
+            (None, None) => return,
+        };
+        // not HTTP, `socks_failed` answers those and a redirected client
+        // is never answered
+        if matches!(self.state, State::Piping) || self.socks.is_some() || self.unrequested() {
+            return;
+        }
+        warn!("{} failed for {} from {}, answering 502", upstream, self.host, self.client);
+        let _ = self
+            .down_sock
+            .write_all(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        self.outcome = Outcome::Failed(Some(502));
+    }
+
+    /// Answers a SOCKS client whose destination could not be reached with
+    /// the reply for `failure`. Called as the session closes, for any
+    /// reason; a client that was answered already, or whose request never
+    /// came, hears nothing more.
+    pub(crate) fn socks_failed(&mut self, failure: Option<ConnectFailure>) {
+        if !matches!(self.socks, Some(Handshake::Done(_))) || self.outcome != Outcome::Pending {
+            return;
+        }
+        let reply = failure.map_or(Reply::GeneralFailure, Reply::for_failure);
+        let _ = self.down_sock.write_all(&self.socks_reply(reply, None));
+    }
+
+    /// Answers a websocket client whose destination could not be reached
+    /// with a 502, or a 504 when the connect timed out, instead of the
+    /// 101; an upgraded one gets the close frame for `reason`. Called as
+    /// the session closes, for any reason.
+    pub(crate) fn websocket_closed(&mut self, reason: CloseReason, failure: Option<ConnectFailure>) {
+        if self.ws_accept.is_none() {
+            return self.down_sock.close_websocket(websocket::close_code(reason));
+        }
+        if self.outcome != Outcome::Pending || reason == CloseReason::ClientClosed {
+            return;
+        }
+        let (status, response) = match failure {
+            Some(ConnectFailure::Timeout) => (504, "504 Gateway Timeout"),
+            _ => (502, "502 Bad Gateway"),
+        };
+        let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", response);
+        let _ = self.down_sock.write_all(response.as_bytes());
+        self.outcome = Outcome::Failed(Some(status));
+    }
+
+    pub(crate) fn handle_write(&mut self, token: Token) -> io::Result<Drain> {
+        debug!("writable event {}", self);
+        let err = self.up_sock.as_mut().map(|sock| {
+            // one read: SO_ERROR is cleared by it, a failed connect's
+            // error would be gone for the next
+            if let Some(e) = sock.take_error().unwrap_or_else(Some) {
+                if e.kind() == ErrorKind::NotConnected {
+                    return Err(io::Error::new(ErrorKind::WouldBlock, "not connected"));
+                }
+                return Err(e);
+            }
+
+            //TcpStream::peer_addr. If it returns libc::EINPROGRESS or ErrorKind::NotConnected
+            if let Err(e) = sock.connected() {
+                if e.kind() == ErrorKind::NotConnected {
+                    return Err(io::Error::new(ErrorKind::WouldBlock, "not connected"));
+                }
+                return Err(e);
+            }
+
+            Ok(())
+        });
+
+        if let Some(Err(e)) = err {
+            if matches!(self.state, State::Connecting) && e.kind() != ErrorKind::WouldBlock {
+                self.connect_failure = Some(self.failure_kind(&e));
+            }
+            return Err(e);
+        }
+        self.handle_up_sock_connected(token)
+    }
+}
+
+/// When a session reached each step to its tunnel, the accept being
+/// `Session::created`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Milestones {
+    /// request head complete
+    pub head: Option<Instant>,
+    /// address of the destination, or of the parent, known; the upstream
+    /// connect is issued right after
+    pub resolved: Option<Instant>,
+    pub connected: Option<Instant>,
+    /// the client was answered and piping starts
+    pub established: Option<Instant>,
+}
+
+/// `host:port`, bracketing IPv6 literals.
+/// Drops the `name` header lines from the request head at the start of
+/// `buf`; what follows the head stays as it is.
+fn strip_header(buf: &mut Vec<u8>, name: &[u8]) {
+    let end = buf.windows(4).position(|w| w == b"\r\n\r\n").map_or(buf.len(), |i| i + 2);
+    let mut out = Vec::with_capacity(buf.len());
+    let mut start = 0;
+    while start < end {
+        let stop = buf[start..end].iter().position(|&b| b == b'\n').map_or(end, |i| start + i + 1);
+        let line = &buf[start..stop];
+        let named = line.len() > name.len()
+            && line[..name.len()].eq_ignore_ascii_case(name)
+            && line[name.len()] == b':';
+        if !named {
+            out.extend_from_slice(line);
+        }
+        start = stop;
+    }
+    out.extend_from_slice(&buf[end..]);
+    *buf = out;
+}
+
+fn authority(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Splits a CONNECT target into host and port. IPv6 literals come in
+/// brackets, `[::1]:443`; a bare one without a port is all host.
+pub fn split_host_port(target: &str) -> (&str, Option<&str>) {
+    if let Some(rest) = target.strip_prefix('[') {
+        return match rest.split_once(']') {
+            Some((host, port)) => (host, port.strip_prefix(':')),
+            None => (rest, None),
+        };
+    }
+    match target.split_once(':') {
+        Some((host, port)) if !port.contains(':') => (host, Some(port)),
+        Some(_) => (target, None),
+        None => (target, None),
+    }
+}
+
+/// Kernel pipe carrying one direction of a tunnel. It outlives a single
+/// readiness event so bytes the destination could not take yet are kept here
+/// and flushed on its next writable edge instead of being dropped.
+pub struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+    pending: usize,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Pipe> {
+        let (read, write) = pipe2(OFlag::O_NONBLOCK | OFlag::O_CLOEXEC)?;
+        Ok(Pipe {
+            read,
+            write,
+            pending: 0,
+        })
+    }
+}
+
+/// Moves bytes `src` -> `pipe` -> `dst` until `src` would block, `dst` is
+/// full, or `budget` bytes were delivered. Returns the delivered size.
+/// `sides` are those of `src` and `dst`, for the `SpliceError`s.
+///
+/// The budget bounds the bytes moved per direction for one readiness event,
+/// so one busy tunnel cannot starve the other sessions in the batch.
+/// Registrations are edge-triggered, so a pump cut short by the budget
+/// reports `Drain::Again` and the loop requeues it rather than waiting for
+/// an edge.
+#[cfg(target_os="linux")]
+fn splice_copy(
+    src: &impl AsFd,
+    dst: &impl AsFd,
+    (src_side, dst_side): (Side, Side),
+    pipe: &mut Pipe,
+    budget: usize,
+) -> io::Result<(usize, Drain)> {
+    let mut send = 0;
+    loop {
+        let (sent, empty) = drain_pipe(pipe, dst, dst_side)?;
+        send += sent;
+        if !empty {
+            return Ok((send, Drain::Done));
+        }
+
+        if send >= budget {
+            return Ok((send, Drain::Again));
+        }
+
+        match splice(
+            src.as_fd(),
+            None,
+            pipe.write.as_fd(),
+            None,
+            8192,
+            SpliceFFlags::SPLICE_F_NONBLOCK | SpliceFFlags::SPLICE_F_MOVE,
+        ) {
+            // report what moved first, the requeued pump sees the eof again
+            Ok(0) if send > 0 => return Ok((send, Drain::Again)),
+            Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "eof")),
+            Ok(u) => pipe.pending += u,
+            Err(Errno::EAGAIN) => return Ok((send, Drain::Done)),
+            Err(e) => return Err(SpliceError::io(src_side, e)),
+        }
+    }
+}
+
+/// Splices what `pipe` holds on to `dst`, on `dst_side`. Returns the bytes
+/// written and whether the pipe is empty now; when not, `dst` is full.
+fn drain_pipe(pipe: &mut Pipe, dst: &impl AsFd, dst_side: Side) -> io::Result<(usize, bool)> {
+    let mut send = 0;
+    while pipe.pending > 0 {
+        match splice(
+            pipe.read.as_fd(),
+            None,
+            dst.as_fd(),
+            None,
+            pipe.pending,
+            SpliceFFlags::SPLICE_F_NONBLOCK | SpliceFFlags::SPLICE_F_MOVE,
+        ) {
+            Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "write zero")),
+            Ok(u) => {
+                pipe.pending -= u;
+                send += u;
+            }
+            Err(Errno::EAGAIN) => return Ok((send, false)),
+            Err(e) => return Err(SpliceError::io(dst_side, e)),
+        }
+    }
+    Ok((send, true))
+}
+
+/// The server name and certificates a `Route::wrap_tls` upstream is
+/// dialed with.
+#[cfg(feature = "tls")]
+struct UpTls {
+    ctx: Arc<ClientContext>,
+    name: String,
+    verify: bool,
+}
+
+/// Unwritten bytes of a session switched to `copy_buffered`.
+#[derive(Debug, Default)]
+struct Buffers {
+    up: Vec<u8>,
+    down: Vec<u8>,
+}
+
+/// Writes `queued` to `dst` as far as it takes it, and what `dst` holds
+/// itself, a websocket client's frames; true once it is all out, the
+/// rest waits for the next writable edge.
+fn flush_queued(dst: &mut impl Write, queued: &mut Vec<u8>) -> io::Result<bool> {
+    while !queued.is_empty() {
+        match dst.write(queued) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                queued.drain(..n);
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+    match dst.flush() {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// `splice_copy` through userspace, for a session being captured: what
+/// `src` gives is handed to `capture` before it goes to `dst`, and what
+/// `dst` cannot take yet waits in `pending`. Bytes the splice `pipe` still
+/// held at the switch go out first, uncaptured. `src_side` is the side
+/// read from, which for `capture` is client to upstream if it is the
+/// client.
+fn copy_buffered(
+    src: &mut impl Read,
+    dst: &mut (impl Write + AsFd),
+    (src_side, dst_side): (Side, Side),
+    pipe: Option<&mut Pipe>,
+    pending: &mut Vec<u8>,
+    capture: &mut Option<Capture>,
+    budget: usize,
+) -> io::Result<(usize, Drain)> {
+    let mut send = 0;
+    if let Some(pipe) = pipe {
+        let (sent, empty) = drain_pipe(pipe, dst, dst_side)?;
+        send += sent;
+        if !empty {
+            return Ok((send, Drain::Done));
+        }
+    }
+    let mut buf = [0u8; 16 * 1024];
+    loop {
+        while !pending.is_empty() {
+            match dst.write(pending) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "write zero")),
+                Ok(n) => {
+                    pending.drain(..n);
+                    send += n;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok((send, Drain::Done)),
+                Err(e) => return Err(e),
+            }
+        }
+
+        if send >= budget {
+            return Ok((send, Drain::Again));
+        }
+
+        match src.read(&mut buf) {
+            Ok(0) if send > 0 => return Ok((send, Drain::Again)),
+            Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "eof")),
+            Ok(n) => {
+                if let Some(c) = capture {
+                    if !c.record(src_side == Side::Client, &buf[..n]) {
+                        *capture = None;
+                    }
+                }
+                pending.extend_from_slice(&buf[..n]);
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok((send, Drain::Done)),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, sync::atomic::AtomicUsize};
+
+    use mio::{Events, Poll};
+
+    use super::*;
+
+    static LOOKUPS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Answers 127.0.0.2 and then 127.0.0.1 the first time, an internal
+    /// address no session may dial every time after.
+    fn changing_answer(_: &str) -> io::Result<Vec<IpAddr>> {
+        let ips = match LOOKUPS.fetch_add(1, Ordering::Relaxed) {
+            0 => ["127.0.0.2", "127.0.0.1"],
+            _ => ["10.0.0.1", "10.0.0.1"],
+        };
+        Ok(ips.iter().map(|ip| ip.parse().unwrap()).collect())
+    }
+
+    /// A session whose client, on the other end of the pair, tunnels to
+    /// `port` of a name.
+    fn tunnel_to(port: u16, config: Config) -> (Session, std::net::TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (sock, addr) = listener.accept().unwrap();
+        sock.set_nonblocking(true).unwrap();
+        let down = ClientStream::Tcp(TcpStream::from_std(sock));
+        let profile = Arc::new(Profile::default());
+        let stats = Arc::new(WorkerStats::new(1));
+        let mut session = Session::new(0, down, Peer::Ip(addr), 0, profile, stats, Arc::new(config));
+        session.tunnel = Some(("changing.test".to_owned(), port));
+        (session, client)
+    }
+
+    #[test]
+    fn refused_address_falls_through_to_the_next_without_a_lookup() {
+        // nothing listens on 127.0.0.2 at this port, so it refuses
+        let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+        origin.set_nonblocking(true).unwrap();
+        let config = Config {
+            block_internal: true,
+            allow_internal: vec!["127.0.0.0/8".parse().unwrap()],
+            ..Config::default()
+        };
+        let mut dns = DNS::with_lookup(&config, Arc::new(WorkerStats::new(1)), changing_answer);
+        let (mut session, _client) = tunnel_to(origin.local_addr().unwrap().port(), config);
+        let mut poll = Poll::new().unwrap();
+        let mut events = Events::with_capacity(8);
+        let mut token = session.connect(poll.registry(), &mut dns).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        // a connect refused at once is passed over inside connect itself,
+        // one refused later shows as an error event on its socket
+        let accepted = loop {
+            if let Ok((sock, _)) = origin.accept() {
+                break sock;
+            }
+            assert!(Instant::now() < deadline, "127.0.0.1 never dialed");
+            poll.poll(&mut events, Some(Duration::from_millis(20))).unwrap();
+            if events.iter().any(|e| e.token() == token && (e.is_error() || e.is_write_closed())) {
+                let fd = session.redial(poll.registry(), &mut dns, Some("refused")).expect("an address left");
+                token = TokenSpace::session(fd.unwrap());
+            }
+        };
+        assert_eq!(accepted.peer_addr().unwrap().ip().to_string(), "127.0.0.1");
+        assert_eq!(LOOKUPS.load(Ordering::Relaxed), 1);
+        // with the list used up, there is nothing left to redial
+        assert!(session.up_addrs.is_empty());
+        assert!(session.redial(poll.registry(), &mut dns, Some("refused")).is_none());
+    }
+
+    #[test]
+    fn a_hello_cut_short_by_the_clients_fin_is_a_mismatch() {
+        for (check, refused) in [(SniCheck::Enforce, true), (SniCheck::Log, false)] {
+            let config = Config { sni_check: check, ..Config::default() };
+            let (mut session, mut client) = tunnel_to(443, config);
+            session.host = "example.com".to_owned();
+            // record and handshake headers promising 200 bytes, then FIN
+            std::io::Write::write_all(&mut client, &[0x16, 0x03, 0x01, 0x00, 0xc8, 0x01, 0x00, 0x00, 0xc4, 0x03, 0x03])
+                .unwrap();
+            client.shutdown(std::net::Shutdown::Write).unwrap();
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while session.down_sock.peek(&mut [0u8; 16]).ok() != Some(11) {
+                assert!(Instant::now() < deadline, "the hello never came");
+            }
+            // for all a peek tells, the rest may still come
+            assert!(!session.check_sni().unwrap());
+            session.client_eof = true;
+            match session.check_sni() {
+                Err(e) if refused => assert_eq!(e.kind(), ErrorKind::PermissionDenied),
+                checked => assert!(checked.unwrap(), "{:?}", check),
+            }
+            assert_eq!(session.close_reason.is_some(), refused);
+        }
     }
-    
-    Ok(send)
 }
@@ -0,0 +1,83 @@
+use std::{io, thread};
+
+use log::{error, info};
+use nix::sys::signal::{SigSet, Signal};
+
+use crate::{
+    access_log, audit_log,
+    command::{Command, CommandSender},
+    logging,
+};
+
+/// Signals the proxy handles itself. They are blocked in every thread and
+/// only delivered to the signal thread through `sigwait`.
+fn handled() -> SigSet {
+    let mut set = SigSet::empty();
+    set.add(Signal::SIGUSR1);
+    set.add(Signal::SIGUSR2);
+    set.add(Signal::SIGHUP);
+    set.add(Signal::SIGTERM);
+    set.add(Signal::SIGINT);
+    set
+}
+
+/// Blocks the handled signals for the calling thread. Must run before any
+/// other thread is spawned so they all inherit the mask.
+pub fn block() -> io::Result<()> {
+    handled().thread_block()?;
+    Ok(())
+}
+
+/// Starts the thread turning signals into commands: SIGUSR1 reopens the
+/// log file, the access log and the audit log, then has every worker dump its sessions
+/// into the (new) log; SIGHUP (reload), SIGUSR2 (upgrade) and
+/// SIGTERM/SIGINT are handed to `notify`.
+pub fn spawn(
+    workers: Vec<CommandSender>,
+    notify: impl Fn(Signal) + Send + 'static,
+) -> io::Result<()> {
+    thread::Builder::new()
+        .name("signals".to_owned())
+        .spawn(move || {
+            let set = handled();
+            loop {
+                let sig = match set.wait() {
+                    Ok(s) => s,
+                    Err(e) => {
+                        error!("sigwait err {:?}", e);
+                        return;
+                    }
+                };
+                info!("received {:?}", sig);
+                match sig {
+                    Signal::SIGUSR1 => {
+                        if logging::has_file() {
+                            match logging::reopen() {
+                                Ok(()) => info!("log file reopened"),
+                                Err(e) => error!("reopen log file err {}", e),
+                            }
+                        }
+                        if access_log::enabled() {
+                            match access_log::reopen() {
+                                Ok(()) => info!("access log reopened"),
+                                Err(e) => error!("reopen access log err {}", e),
+                            }
+                        }
+                        if audit_log::enabled() {
+                            match audit_log::reopen() {
+                                Ok(()) => info!("audit log reopened"),
+                                Err(e) => error!("reopen audit log err {}", e),
+                            }
+                        }
+                        for w in &workers {
+                            if let Err(e) = w.send(Command::DumpSessions) {
+                                error!("send dump sessions err {:?}", e);
+                            }
+                        }
+                    }
+                    _ => notify(sig),
+                }
+            }
+        })?;
+    Ok(())
+}
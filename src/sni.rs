@@ -0,0 +1,406 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+/// Client bytes looked at for a ClientHello at most. Hellos are well under
+/// 2 KB, post-quantum key shares included.
+pub const MAX_HELLO: usize = 8 * 1024;
+
+/// Whether tunnels must go to the host their CONNECT named, see
+/// `Config::sni_check`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SniCheck {
+    Off,
+    /// log a mismatch and let the tunnel be
+    Log,
+    /// close the tunnel on a mismatch, before the hello goes upstream
+    Enforce,
+}
+
+impl FromStr for SniCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(SniCheck::Off),
+            "log" => Ok(SniCheck::Log),
+            "enforce" => Ok(SniCheck::Enforce),
+            _ => Err(format!("unknown sni check {:?}, expected off, log or enforce", s)),
+        }
+    }
+}
+
+impl fmt::Display for SniCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SniCheck::Off => "off",
+            SniCheck::Log => "log",
+            SniCheck::Enforce => "enforce",
+        })
+    }
+}
+
+/// What a transparent listener makes of the server name in a client's
+/// ClientHello, see `Config::transparent_sni`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransparentSni {
+    Off,
+    /// the name is the session's host for the acl and the logs, the
+    /// original address is still the one dialed
+    Host,
+    /// the name is resolved and dialed in place of the original address
+    Dial,
+}
+
+impl FromStr for TransparentSni {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(TransparentSni::Off),
+            "host" => Ok(TransparentSni::Host),
+            "dial" => Ok(TransparentSni::Dial),
+            _ => Err(format!("unknown transparent sni {:?}, expected off, host or dial", s)),
+        }
+    }
+}
+
+impl fmt::Display for TransparentSni {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TransparentSni::Off => "off",
+            TransparentSni::Host => "host",
+            TransparentSni::Dial => "dial",
+        })
+    }
+}
+
+/// How long a transparent session waits for its client's ClientHello
+/// before dialing by the original address alone; a protocol where the
+/// server speaks first waits this long for nothing.
+pub const HELLO_WAIT: Duration = Duration::from_millis(500);
+
+/// What the first client bytes of a tunnel hold.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Hello {
+    /// a ClientHello may still be on its way, look again with more bytes
+    Incomplete,
+    /// not a TLS handshake; nothing to check
+    NotTls,
+    /// a ClientHello, with the host_name of its server_name extension
+    /// when it has one
+    Sni(Option<String>),
+    /// a TLS record that does not hold a sound ClientHello, or one longer
+    /// than `MAX_HELLO`
+    Malformed,
+}
+
+/// Reads the ClientHello starting `buf`, which may run over several
+/// handshake records.
+pub fn parse(buf: &[u8]) -> Hello {
+    match buf.first() {
+        None => return Hello::Incomplete,
+        Some(0x16) => {}
+        Some(_) => return Hello::NotTls,
+    }
+    let incomplete = || if buf.len() >= MAX_HELLO { Hello::Malformed } else { Hello::Incomplete };
+    // the handshake message, unwrapped from as many records as it spans
+    let mut message = Vec::new();
+    let mut rest = buf;
+    loop {
+        if message.len() >= 4 {
+            let len = usize::from(message[1]) << 16 | usize::from(message[2]) << 8 | usize::from(message[3]);
+            if message[0] != 1 || len + 4 > MAX_HELLO {
+                return Hello::Malformed;
+            }
+            if message.len() >= len + 4 {
+                return server_name(&message[4..len + 4]).map_or(Hello::Malformed, Hello::Sni);
+            }
+        }
+        if rest.len() < 5 {
+            return incomplete();
+        }
+        let (kind, major, len) = (rest[0], rest[1], usize::from(u16::from_be_bytes([rest[3], rest[4]])));
+        if kind != 0x16 || major != 3 || len == 0 {
+            return Hello::Malformed;
+        }
+        let Some(fragment) = rest.get(5..5 + len) else {
+            return incomplete();
+        };
+        message.extend_from_slice(fragment);
+        rest = &rest[5 + len..];
+    }
+}
+
+/// The host_name of a ClientHello body, None when the body does not parse.
+fn server_name(body: &[u8]) -> Option<Option<String>> {
+    let mut r = Reader(body);
+    r.take(2 + 32)?; // legacy_version, random
+    r.vec8()?; // legacy_session_id
+    r.vec16()?; // cipher_suites
+    r.vec8()?; // legacy_compression_methods
+    if r.0.is_empty() {
+        return Some(None);
+    }
+    let mut extensions = Reader(r.vec16()?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let data = extensions.vec16()?;
+        if kind != 0 {
+            continue;
+        }
+        let mut names = Reader(Reader(data).vec16()?);
+        while !names.0.is_empty() {
+            let name_type = names.take(1)?[0];
+            let name = names.vec16()?;
+            if name_type == 0 {
+                let name = std::str::from_utf8(name).ok()?;
+                return Some(Some(name.to_ascii_lowercase()));
+            }
+        }
+        return Some(None);
+    }
+    Some(None)
+}
+
+/// Whether a hello's server name will do as a destination host: letters,
+/// digits, hyphens and underscores in dot-separated labels. Anything else
+/// leaves a transparent session with its address.
+pub fn is_hostname(name: &str) -> bool {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            (1..=63).contains(&label.len())
+                && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        })
+}
+
+/// Whether a tunnel whose CONNECT named `host` may carry a hello for
+/// `sni`: the same name, or with `subdomains` one under it. Case and a
+/// trailing dot do not matter.
+pub fn matches(host: &str, sni: &str, subdomains: bool) -> bool {
+    let host = host.trim_end_matches('.').as_bytes();
+    let sni = sni.trim_end_matches('.').as_bytes();
+    if sni.eq_ignore_ascii_case(host) {
+        return true;
+    }
+    subdomains
+        && sni.len() > host.len() + 1
+        && sni[sni.len() - host.len() - 1] == b'.'
+        && sni[sni.len() - host.len()..].eq_ignore_ascii_case(host)
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let n = self.take(1)?[0];
+        self.take(usize::from(n))
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let n = self.u16()?;
+        self.take(usize::from(n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The data of a server_name extension listing `names`, each with its
+    /// name type.
+    fn server_names(names: &[(u8, &str)]) -> Vec<u8> {
+        let mut list = Vec::new();
+        for (kind, name) in names {
+            list.push(*kind);
+            list.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            list.extend_from_slice(name.as_bytes());
+        }
+        let mut data = (list.len() as u16).to_be_bytes().to_vec();
+        data.extend(list);
+        data
+    }
+
+    /// A ClientHello body with `extensions`, none at all for None.
+    fn body(extensions: Option<&[(u16, Vec<u8>)]>) -> Vec<u8> {
+        let mut b = vec![3, 3];
+        b.extend([0x5a; 32]);
+        b.push(32);
+        b.extend([0xa5; 32]);
+        b.extend([0, 4, 0x13, 0x01, 0x13, 0x02]);
+        b.extend([1, 0]);
+        if let Some(extensions) = extensions {
+            let mut all = Vec::new();
+            for (kind, data) in extensions {
+                all.extend_from_slice(&kind.to_be_bytes());
+                all.extend_from_slice(&(data.len() as u16).to_be_bytes());
+                all.extend_from_slice(data);
+            }
+            b.extend_from_slice(&(all.len() as u16).to_be_bytes());
+            b.extend(all);
+        }
+        b
+    }
+
+    /// `body` as a ClientHello handshake message.
+    fn message(body: &[u8]) -> Vec<u8> {
+        let mut m = vec![1];
+        m.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        m.extend_from_slice(body);
+        m
+    }
+
+    /// `message` in handshake records, the first ones of `sizes` bytes and
+    /// the last one with the rest.
+    fn records(message: &[u8], sizes: &[usize]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut rest = message;
+        for &size in sizes.iter().chain([&usize::MAX]) {
+            let (fragment, tail) = rest.split_at(size.min(rest.len()));
+            out.extend([0x16, 3, 1]);
+            out.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+            out.extend_from_slice(fragment);
+            rest = tail;
+            if rest.is_empty() {
+                break;
+            }
+        }
+        out
+    }
+
+    fn hello_for(name: &str) -> Vec<u8> {
+        let extensions = [(10, vec![0, 2, 0, 29]), (0, server_names(&[(0, name)]))];
+        message(&body(Some(&extensions)))
+    }
+
+    #[test]
+    fn the_host_name_of_a_hello() {
+        assert_eq!(parse(&records(&hello_for("Example.COM"), &[])), Hello::Sni(Some("example.com".to_owned())));
+    }
+
+    #[test]
+    fn a_hello_split_across_reads() {
+        let hello = records(&hello_for("example.com"), &[]);
+        for n in 0..hello.len() {
+            assert_eq!(parse(&hello[..n]), Hello::Incomplete, "{} bytes", n);
+        }
+        assert_eq!(parse(&hello), Hello::Sni(Some("example.com".to_owned())));
+    }
+
+    #[test]
+    fn a_hello_split_across_records() {
+        let message = hello_for("example.com");
+        // the split inside the handshake header, inside the body, and a
+        // record of one byte
+        for sizes in [&[2][..], &[40, 40], &[1, 1, 1, 1, 60]] {
+            let hello = records(&message, sizes);
+            for n in 0..hello.len() {
+                assert_eq!(parse(&hello[..n]), Hello::Incomplete, "{:?}, {} bytes", sizes, n);
+            }
+            assert_eq!(parse(&hello), Hello::Sni(Some("example.com".to_owned())), "{:?}", sizes);
+        }
+    }
+
+    #[test]
+    fn what_follows_the_hello_is_left_alone() {
+        let mut hello = records(&hello_for("example.com"), &[]);
+        hello.extend([0x17, 3, 3, 0, 1, 0]);
+        assert_eq!(parse(&hello), Hello::Sni(Some("example.com".to_owned())));
+    }
+
+    #[test]
+    fn a_hello_without_a_server_name() {
+        assert_eq!(parse(&records(&message(&body(None)), &[])), Hello::Sni(None));
+        let others = [(10, vec![0, 2, 0, 29]), (43, vec![2, 3, 4])];
+        assert_eq!(parse(&records(&message(&body(Some(&others))), &[])), Hello::Sni(None));
+        let empty = [(0, server_names(&[]))];
+        assert_eq!(parse(&records(&message(&body(Some(&empty))), &[])), Hello::Sni(None));
+    }
+
+    #[test]
+    fn names_of_another_type_are_passed_over() {
+        let only = [(0, server_names(&[(1, "not-a-host")]))];
+        assert_eq!(parse(&records(&message(&body(Some(&only))), &[])), Hello::Sni(None));
+        let then_host = [(0, server_names(&[(7, "opaque"), (0, "example.com")]))];
+        let hello = records(&message(&body(Some(&then_host))), &[]);
+        assert_eq!(parse(&hello), Hello::Sni(Some("example.com".to_owned())));
+    }
+
+    #[test]
+    fn a_truncated_extension_length() {
+        let mut b = body(Some(&[(0, server_names(&[(0, "example.com")]))]));
+        // the extensions block says 2 bytes more than there are
+        let at = b.len() - (4 + 2 + 3 + 11) - 2;
+        let claimed = u16::from_be_bytes([b[at], b[at + 1]]) + 2;
+        b[at..at + 2].copy_from_slice(&claimed.to_be_bytes());
+        assert_eq!(parse(&records(&message(&b), &[])), Hello::Malformed);
+        // an extension header cut short
+        let mut b = body(Some(&[]));
+        b.truncate(b.len() - 2);
+        b.extend([0, 3, 0, 0, 0]);
+        assert_eq!(parse(&records(&message(&b), &[])), Hello::Malformed);
+    }
+
+    #[test]
+    fn oversized_lengths() {
+        // a handshake message past MAX_HELLO
+        assert_eq!(parse(&[0x16, 3, 1, 0, 4, 1, 0xff, 0xff, 0xff]), Hello::Malformed);
+        // a record that would only end past MAX_HELLO waits, then is given up on
+        let mut record = vec![0x16, 3, 1, 0xff, 0xff, 1, 0, 0xff, 0xf0];
+        assert_eq!(parse(&record), Hello::Incomplete);
+        record.resize(MAX_HELLO, 0);
+        assert_eq!(parse(&record), Hello::Malformed);
+        // lengths inside the body past its end
+        let long_name = [(0, vec![0, 9, 0, 0xff, 0xff, b'a'])];
+        assert_eq!(parse(&records(&message(&body(Some(&long_name))), &[])), Hello::Malformed);
+        let long_list = [(0, vec![0xff, 0xff, 0, 0, 1, b'a'])];
+        assert_eq!(parse(&records(&message(&body(Some(&long_list))), &[])), Hello::Malformed);
+        let mut b = body(None);
+        b[2 + 32] = 0xff;
+        assert_eq!(parse(&records(&message(&b), &[])), Hello::Malformed);
+    }
+
+    #[test]
+    fn other_first_bytes() {
+        assert_eq!(parse(b""), Hello::Incomplete);
+        assert_eq!(parse(b"GET / HTTP/1.1\r\n"), Hello::NotTls);
+        // a handshake record of another version, or empty
+        assert_eq!(parse(&[0x16, 2, 0, 0, 1, 1]), Hello::Malformed);
+        assert_eq!(parse(&[0x16, 3, 1, 0, 0]), Hello::Malformed);
+        // a handshake message that is no ClientHello
+        assert_eq!(parse(&[0x16, 3, 1, 0, 4, 2, 0, 0, 0]), Hello::Malformed);
+        // the message goes on in a record of another type
+        let hello = records(&hello_for("example.com"), &[20]);
+        let mut alert = hello[..25].to_vec();
+        alert.extend([0x15, 3, 1, 0, 2, 2, 40]);
+        assert_eq!(parse(&alert), Hello::Malformed);
+    }
+
+    #[test]
+    fn no_cut_nor_flipped_byte_panics() {
+        let hello = records(&hello_for("example.com"), &[30]);
+        for n in 0..=hello.len() {
+            parse(&hello[..n]);
+        }
+        for i in 0..hello.len() {
+            for flip in [0x01, 0x80, 0xff] {
+                let mut bad = hello.clone();
+                bad[i] ^= flip;
+                parse(&bad);
+            }
+        }
+    }
+}
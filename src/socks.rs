@@ -0,0 +1,554 @@
+use std::{
+    fmt::{self, Display},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use crate::stats::ConnectFailure;
+
+/// The first byte of every SOCKS5 message from the client, which tells a
+/// SOCKS client from an HTTP one, see `Config::socks`.
+pub const VERSION_5: u8 = 0x05;
+/// The first byte of a SOCKS4 or 4a request.
+pub const VERSION_4: u8 = 0x04;
+
+/// The methods of the client's greeting we pick from.
+pub const NO_AUTH: u8 = 0x00;
+pub const USER_PASS: u8 = 0x02;
+pub const NO_METHOD: u8 = 0xff;
+
+/// Version byte of the username/password subnegotiation, RFC 1929.
+const AUTH_VERSION: u8 = 0x01;
+
+const CONNECT: u8 = 0x01;
+const UDP_ASSOCIATE: u8 = 0x03;
+
+/// Longest user id or host name a SOCKS4 request may have, the bound
+/// SOCKS5 puts on both.
+const MAX_SOCKS4_FIELD: usize = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    /// SOCKS4 and its 4a extension, which can name a host
+    Four,
+    Five,
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Version::Four => "SOCKS4",
+            Version::Five => "SOCKS5",
+        })
+    }
+}
+
+/// How far the handshake of a SOCKS session got: what the client sends
+/// next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handshake {
+    /// the methods it can authenticate with
+    Greeting,
+    /// its username and password, we picked `USER_PASS`
+    Auth,
+    /// the command and where to
+    Request,
+    /// a SOCKS4 request, which is all a SOCKS4 client sends
+    Request4,
+    /// the request is in; the session goes on like a CONNECT
+    Done(Version),
+}
+
+impl Handshake {
+    pub fn version(self) -> Version {
+        match self {
+            Handshake::Request4 | Handshake::Done(Version::Four) => Version::Four,
+            _ => Version::Five,
+        }
+    }
+}
+
+/// The reply field of our answer to a request. SOCKS4 only tells granted
+/// from not, see `reply`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reply {
+    Succeeded = 0x00,
+    GeneralFailure = 0x01,
+    NotAllowed = 0x02,
+    NetworkUnreachable = 0x03,
+    HostUnreachable = 0x04,
+    Refused = 0x05,
+    TtlExpired = 0x06,
+    CommandNotSupported = 0x07,
+    AddressTypeNotSupported = 0x08,
+}
+
+impl Reply {
+    /// What a client hears for an upstream that could not be reached.
+    pub fn for_failure(failure: ConnectFailure) -> Reply {
+        match failure {
+            ConnectFailure::Dns => Reply::HostUnreachable,
+            ConnectFailure::Refused => Reply::Refused,
+            ConnectFailure::Timeout => Reply::TtlExpired,
+            ConnectFailure::Unreachable => Reply::NetworkUnreachable,
+            ConnectFailure::Parent | ConnectFailure::Other => Reply::GeneralFailure,
+        }
+    }
+}
+
+/// A request's command and destination. The host is a name or an
+/// address, IPv6 without brackets, as the target of a CONNECT has it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request {
+    pub command: u8,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Request {
+    pub fn is_connect(&self) -> bool {
+        self.command == CONNECT
+    }
+
+    pub fn is_udp_associate(&self) -> bool {
+        self.command == UDP_ASSOCIATE
+    }
+}
+
+/// The header of a datagram the client relays through us, RFC 1928
+/// section 7: its fragment number, destination, and where the payload
+/// starts.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Datagram {
+    pub frag: u8,
+    pub host: String,
+    pub port: u16,
+    pub payload: usize,
+}
+
+/// A request that does not follow the protocol, and the reply the client
+/// gets for it when there is one.
+#[derive(Debug)]
+pub struct Malformed(pub &'static str, pub Option<Reply>);
+
+/// Every parser below returns Ok(None) while the message is incomplete,
+/// else how many bytes of `buf` it took with what they said.
+pub type Parsed<T, E = &'static str> = Result<Option<(usize, T)>, E>;
+
+/// The greeting: the methods the client offers.
+pub fn greeting(buf: &[u8]) -> Parsed<&[u8]> {
+    let Some(&[version, n]) = buf.get(..2) else {
+        return Ok(None);
+    };
+    if version != VERSION_5 {
+        return Err("not a socks5 greeting");
+    }
+    let end = 2 + usize::from(n);
+    Ok(buf.get(2..end).map(|methods| (end, methods)))
+}
+
+/// The username/password subnegotiation, RFC 1929.
+pub fn auth(buf: &[u8]) -> Parsed<(&[u8], &[u8])> {
+    let Some(&[version, user_len]) = buf.get(..2) else {
+        return Ok(None);
+    };
+    if version != AUTH_VERSION {
+        return Err("bad socks5 username/password version");
+    }
+    let user_end = 2 + usize::from(user_len);
+    let Some(&password_len) = buf.get(user_end) else {
+        return Ok(None);
+    };
+    let end = user_end + 1 + usize::from(password_len);
+    Ok(buf.get(..end).map(|b| (end, (&b[2..user_end], &b[user_end + 1..]))))
+}
+
+/// The request: the command with its address, of any of the three types,
+/// and port.
+pub fn request(buf: &[u8]) -> Parsed<Request, Malformed> {
+    let Some(&[version, command, _, kind]) = buf.get(..4) else {
+        return Ok(None);
+    };
+    if version != VERSION_5 {
+        return Err(Malformed("bad socks5 request version", Some(Reply::GeneralFailure)));
+    }
+    let Some((host, port, end)) = address(buf, kind, 4)? else {
+        return Ok(None);
+    };
+    Ok(Some((end, Request { command, host, port })))
+}
+
+/// A datagram's header, None when it is cut short or malformed.
+pub fn datagram(buf: &[u8]) -> Option<Datagram> {
+    let &[0, 0, frag, kind] = buf.get(..4)? else {
+        return None;
+    };
+    let (host, port, payload) = address(buf, kind, 4).ok()??;
+    Some(Datagram { frag, host, port, payload })
+}
+
+/// The address of the type `kind` at `at`, of a request or a datagram
+/// header, with its port and where they end.
+fn address(buf: &[u8], kind: u8, at: usize) -> Result<Option<(String, u16, usize)>, Malformed> {
+    let (host, at) = match kind {
+        0x01 => match buf.get(at..at + 4) {
+            Some(b) => (Ipv4Addr::from(<[u8; 4]>::try_from(b).unwrap()).to_string(), at + 4),
+            None => return Ok(None),
+        },
+        0x04 => match buf.get(at..at + 16) {
+            Some(b) => (Ipv6Addr::from(<[u8; 16]>::try_from(b).unwrap()).to_string(), at + 16),
+            None => return Ok(None),
+        },
+        0x03 => {
+            let Some(&len) = buf.get(at) else {
+                return Ok(None);
+            };
+            let end = at + 1 + usize::from(len);
+            let Some(name) = buf.get(at + 1..end) else {
+                return Ok(None);
+            };
+            let name = std::str::from_utf8(name)
+                .ok()
+                .filter(|n| !n.is_empty() && !n.contains(['/', ' ', '\0']))
+                .ok_or(Malformed("bad socks5 domain name", Some(Reply::HostUnreachable)))?;
+            (name.to_owned(), end)
+        }
+        _ => return Err(Malformed("unknown socks5 address type", Some(Reply::AddressTypeNotSupported))),
+    };
+    let Some(&[hi, lo]) = buf.get(at..at + 2) else {
+        return Ok(None);
+    };
+    Ok(Some((host, u16::from_be_bytes([hi, lo]), at + 2)))
+}
+
+/// A SOCKS4 request, with the user id the client sent. An address of
+/// 0.0.0.x, x not 0, is the 4a form: the host name follows the user id.
+pub fn request4(buf: &[u8]) -> Parsed<(Request, String), Malformed> {
+    let Some(&[version, command, hi, lo, a, b, c, d]) = buf.get(..8) else {
+        return Ok(None);
+    };
+    if version != VERSION_4 {
+        return Err(Malformed("not a socks4 request", None));
+    }
+    // a field ends at its nul; without one in reach it is still to come,
+    // or too long
+    let field = |from: usize| match buf[from..].iter().take(MAX_SOCKS4_FIELD + 1).position(|&b| b == 0) {
+        Some(len) => Ok(Some((&buf[from..from + len], from + len + 1))),
+        None if buf.len() - from > MAX_SOCKS4_FIELD => {
+            Err(Malformed("socks4 field too long", Some(Reply::GeneralFailure)))
+        }
+        None => Ok(None),
+    };
+    let Some((user_id, at)) = field(8)? else {
+        return Ok(None);
+    };
+    let user_id = String::from_utf8_lossy(user_id).into_owned();
+    let port = u16::from_be_bytes([hi, lo]);
+    if [a, b, c] != [0, 0, 0] || d == 0 {
+        let host = Ipv4Addr::new(a, b, c, d).to_string();
+        return Ok(Some((at, (Request { command, host, port }, user_id))));
+    }
+    let Some((name, end)) = field(at)? else {
+        return Ok(None);
+    };
+    let host = std::str::from_utf8(name)
+        .ok()
+        .filter(|n| !n.is_empty() && !n.contains(['/', ' ']))
+        .ok_or(Malformed("bad socks4a host name", Some(Reply::GeneralFailure)))?;
+    Ok(Some((end, (Request { command, host: host.to_owned(), port }, user_id))))
+}
+
+/// Our answer to a request, with the address we connect from, or none
+/// when we did not. A SOCKS4 one says granted (90) for `Succeeded` and
+/// rejected or failed (91) for anything else.
+pub fn reply(version: Version, reply: Reply, bound: Option<SocketAddr>) -> Vec<u8> {
+    if version == Version::Four {
+        let code = if reply == Reply::Succeeded { 90 } else { 91 };
+        let mut out = vec![0, code];
+        match bound {
+            Some(SocketAddr::V4(a)) => {
+                out.extend_from_slice(&a.port().to_be_bytes());
+                out.extend_from_slice(&a.ip().octets());
+            }
+            _ => out.extend_from_slice(&[0; 6]),
+        }
+        return out;
+    }
+    let mut out = vec![VERSION_5, reply as u8, 0];
+    push_address(&mut out, bound);
+    out
+}
+
+/// The header we put before a datagram from `from` on its way to the
+/// client.
+pub fn datagram_header(from: SocketAddr) -> Vec<u8> {
+    let mut out = vec![0, 0, 0];
+    push_address(&mut out, Some(from));
+    out
+}
+
+/// Appends `addr` in the address type, address and port fields, all
+/// zeros for none.
+fn push_address(out: &mut Vec<u8>, addr: Option<SocketAddr>) {
+    match addr.map(|a| (a.ip(), a.port())) {
+        Some((IpAddr::V4(ip), port)) => {
+            out.push(0x01);
+            out.extend_from_slice(&ip.octets());
+            out.extend_from_slice(&port.to_be_bytes());
+        }
+        Some((IpAddr::V6(ip), port)) => {
+            out.push(0x04);
+            out.extend_from_slice(&ip.octets());
+            out.extend_from_slice(&port.to_be_bytes());
+        }
+        None => out.extend_from_slice(&[0x01, 0, 0, 0, 0, 0, 0]),
+    }
+}
+
+/// Our answer to the username/password subnegotiation.
+pub fn auth_reply(ok: bool) -> [u8; 2] {
+    [AUTH_VERSION, if ok { 0x00 } else { 0x01 }]
+}
+
+/// Our greeting to a parent SOCKS5 proxy: no authentication, and
+/// username/password too when we have credentials for it.
+pub fn client_greeting(credentials: bool) -> Vec<u8> {
+    if credentials {
+        vec![VERSION_5, 2, NO_AUTH, USER_PASS]
+    } else {
+        vec![VERSION_5, 1, NO_AUTH]
+    }
+}
+
+/// Our username/password subnegotiation, both at most 255 bytes.
+pub fn client_auth(user: &str, password: &str) -> Vec<u8> {
+    let mut out = vec![AUTH_VERSION, user.len() as u8];
+    out.extend_from_slice(user.as_bytes());
+    out.push(password.len() as u8);
+    out.extend_from_slice(password.as_bytes());
+    out
+}
+
+/// Our CONNECT to `host:port`: an address as one, a name in the domain
+/// form for the parent to resolve.
+pub fn client_connect(host: &str, port: u16) -> Result<Vec<u8>, &'static str> {
+    let mut out = vec![VERSION_5, CONNECT, 0];
+    match host.parse::<IpAddr>() {
+        Ok(ip) => push_address(&mut out, Some(SocketAddr::new(ip, port))),
+        Err(_) => {
+            let len = u8::try_from(host.len()).map_err(|_| "host name too long for socks5")?;
+            out.extend_from_slice(&[0x03, len]);
+            out.extend_from_slice(host.as_bytes());
+            out.extend_from_slice(&port.to_be_bytes());
+        }
+    }
+    Ok(out)
+}
+
+/// The parent's pick of the methods we offered.
+pub fn method_choice(buf: &[u8]) -> Parsed<u8> {
+    match buf.get(..2) {
+        Some(&[VERSION_5, method]) => Ok(Some((2, method))),
+        Some(_) => Err("not a socks5 method choice"),
+        None => Ok(None),
+    }
+}
+
+/// The parent's answer to our username and password: whether it took them.
+pub fn auth_status(buf: &[u8]) -> Parsed<bool> {
+    match buf.get(..2) {
+        Some(&[AUTH_VERSION, status]) => Ok(Some((2, status == 0))),
+        Some(_) => Err("bad socks5 username/password status"),
+        None => Ok(None),
+    }
+}
+
+/// The reply field of the parent's answer to our CONNECT.
+pub fn connect_reply(buf: &[u8]) -> Parsed<u8> {
+    let Some(&[version, reply, _, kind]) = buf.get(..4) else {
+        return Ok(None);
+    };
+    if version != VERSION_5 {
+        return Err("bad socks5 reply version");
+    }
+    match address(buf, kind, 4) {
+        Ok(Some((_, _, end))) => Ok(Some((end, reply))),
+        Ok(None) => Ok(None),
+        Err(Malformed(why, _)) => Err(why),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Asserts `more`, the parser wanting more, for each proper prefix of `msg`.
+    fn incomplete_until_whole(msg: &[u8], more: impl Fn(&[u8]) -> bool) {
+        for n in 0..msg.len() {
+            assert!(more(&msg[..n]), "{} of {:?}", n, msg);
+        }
+    }
+
+    fn request_ok(buf: &[u8]) -> (usize, Request) {
+        request(buf).unwrap().unwrap()
+    }
+
+    #[test]
+    fn greeting_lists_the_offered_methods() {
+        let msg = [VERSION_5, 2, NO_AUTH, USER_PASS];
+        assert_eq!(greeting(&msg), Ok(Some((4, &[NO_AUTH, USER_PASS][..]))));
+        incomplete_until_whole(&msg, |b| greeting(b) == Ok(None));
+        // the request right behind it is left where it is
+        assert_eq!(greeting(&[VERSION_5, 1, NO_AUTH, VERSION_5, 1]), Ok(Some((3, &[NO_AUTH][..]))));
+        assert_eq!(greeting(&[VERSION_5, 0]), Ok(Some((2, &[][..]))));
+        assert!(greeting(&[VERSION_4, 1, 0]).is_err());
+    }
+
+    #[test]
+    fn auth_takes_the_username_and_password() {
+        let msg = [&[AUTH_VERSION, 5][..], b"alice", &[6], b"secret"].concat();
+        assert_eq!(auth(&msg), Ok(Some((msg.len(), (&b"alice"[..], &b"secret"[..])))));
+        incomplete_until_whole(&msg, |b| auth(b) == Ok(None));
+        // RFC 1929 lets either be empty
+        assert_eq!(auth(&[AUTH_VERSION, 0, 0]), Ok(Some((3, (&b""[..], &b""[..])))));
+        assert!(auth(&[VERSION_5, 1, b'a', 1, b'b']).is_err());
+        assert_eq!(auth_reply(true), [AUTH_VERSION, 0]);
+        assert_ne!(auth_reply(false)[1], 0);
+    }
+
+    #[test]
+    fn requests_of_each_address_type() {
+        let v4 = [VERSION_5, CONNECT, 0, 0x01, 10, 0, 0, 1, 0x01, 0xbb];
+        assert_eq!(request_ok(&v4), (10, Request { command: CONNECT, host: "10.0.0.1".to_owned(), port: 443 }));
+        incomplete_until_whole(&v4, |b| matches!(request(b), Ok(None)));
+
+        let mut v6 = vec![VERSION_5, CONNECT, 0, 0x04];
+        v6.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        v6.extend_from_slice(&[0, 80]);
+        assert_eq!(request_ok(&v6), (22, Request { command: CONNECT, host: "2001:db8::1".to_owned(), port: 80 }));
+        incomplete_until_whole(&v6, |b| matches!(request(b), Ok(None)));
+
+        let mut name = vec![VERSION_5, UDP_ASSOCIATE, 0, 0x03, 11];
+        name.extend_from_slice(b"example.com");
+        name.extend_from_slice(&[0x1f, 0x90]);
+        let (end, req) = request_ok(&name);
+        assert_eq!((end, req.host.as_str(), req.port), (name.len(), "example.com", 8080));
+        assert!(req.is_udp_associate() && !req.is_connect());
+        incomplete_until_whole(&name, |b| matches!(request(b), Ok(None)));
+    }
+
+    #[test]
+    fn bad_requests_say_what_to_answer() {
+        let reply_of = |buf: &[u8]| request(buf).unwrap_err().1;
+        assert_eq!(reply_of(&[VERSION_4, CONNECT, 0, 0x01]), Some(Reply::GeneralFailure));
+        assert_eq!(reply_of(&[VERSION_5, CONNECT, 0, 0x02, 0]), Some(Reply::AddressTypeNotSupported));
+        for name in [&b""[..], b"a b", b"a/b", b"a\0b", b"\xff\xfe"] {
+            let mut buf = vec![VERSION_5, CONNECT, 0, 0x03, name.len() as u8];
+            buf.extend_from_slice(name);
+            buf.extend_from_slice(&[0, 80]);
+            assert_eq!(reply_of(&buf), Some(Reply::HostUnreachable), "{:?}", name);
+        }
+        // BIND parses, it is the session that refuses it
+        let bind = [VERSION_5, 0x02, 0, 0x01, 127, 0, 0, 1, 0, 80];
+        assert!(!request_ok(&bind).1.is_connect());
+    }
+
+    #[test]
+    fn replies_carry_the_bound_address() {
+        let v4 = reply(Version::Five, Reply::Succeeded, Some("192.0.2.1:1080".parse().unwrap()));
+        assert_eq!(v4, [VERSION_5, 0, 0, 0x01, 192, 0, 2, 1, 0x04, 0x38]);
+        let v6 = reply(Version::Five, Reply::Succeeded, Some("[::1]:80".parse().unwrap()));
+        assert_eq!(v6.len(), 22);
+        assert_eq!((v6[3], v6[19], &v6[20..]), (0x04, 1, &[0, 80][..]));
+        let refused = reply(Version::Five, Reply::Refused, None);
+        assert_eq!(refused, [VERSION_5, 0x05, 0, 0x01, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn socks4_requests_by_address_and_4a_name() {
+        let v4 = [&[VERSION_4, CONNECT, 0, 80, 192, 0, 2, 1][..], b"ident\0"].concat();
+        let (end, (req, user_id)) = request4(&v4).unwrap().unwrap();
+        assert_eq!((end, req.host.as_str(), req.port, user_id.as_str()), (v4.len(), "192.0.2.1", 80, "ident"));
+        incomplete_until_whole(&v4, |b| matches!(request4(b), Ok(None)));
+
+        let v4a = [&[VERSION_4, CONNECT, 0x01, 0xbb, 0, 0, 0, 1][..], b"\0example.com\0"].concat();
+        let (end, (req, user_id)) = request4(&v4a).unwrap().unwrap();
+        assert_eq!((end, req.host.as_str(), req.port, user_id.as_str()), (v4a.len(), "example.com", 443, ""));
+        assert!(req.is_connect());
+        incomplete_until_whole(&v4a, |b| matches!(request4(b), Ok(None)));
+        // 0.0.0.0 is an address, not the 4a form
+        let zero = [VERSION_4, CONNECT, 0, 80, 0, 0, 0, 0, 0];
+        assert_eq!(request4(&zero).unwrap().unwrap().1 .0.host, "0.0.0.0");
+    }
+
+    #[test]
+    fn bad_socks4_requests() {
+        assert!(request4(&[VERSION_5, CONNECT, 0, 80, 1, 2, 3, 4, 0]).is_err());
+        let long = [&[VERSION_4, CONNECT, 0, 80, 1, 2, 3, 4][..], &[b'u'; MAX_SOCKS4_FIELD + 1]].concat();
+        assert!(request4(&long).is_err());
+        // a field just short of the bound is still to come
+        let nearly = [&[VERSION_4, CONNECT, 0, 80, 1, 2, 3, 4][..], &[b'u'; MAX_SOCKS4_FIELD]].concat();
+        assert!(matches!(request4(&nearly), Ok(None)));
+        for name in [&b""[..], b"a b", b"a/b", b"\xff"] {
+            let msg = [&[VERSION_4, CONNECT, 0, 80, 0, 0, 0, 9, 0][..], name, b"\0"].concat();
+            assert!(request4(&msg).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn socks4_replies_are_granted_or_not() {
+        let bound = Some("192.0.2.1:1080".parse().unwrap());
+        assert_eq!(reply(Version::Four, Reply::Succeeded, bound), [0, 90, 0x04, 0x38, 192, 0, 2, 1]);
+        for failure in [Reply::Refused, Reply::NotAllowed, Reply::HostUnreachable] {
+            assert_eq!(reply(Version::Four, failure, None), [0, 91, 0, 0, 0, 0, 0, 0]);
+        }
+        // an IPv6 address does not fit, zeros say nothing
+        assert_eq!(reply(Version::Four, Reply::Succeeded, Some("[::1]:80".parse().unwrap()))[2..], [0; 6]);
+    }
+
+    #[test]
+    fn our_side_of_a_handshake_with_a_parent() {
+        assert_eq!(client_greeting(false), [VERSION_5, 1, NO_AUTH]);
+        let offered = client_greeting(true);
+        assert_eq!(greeting(&offered).unwrap().unwrap().1, [NO_AUTH, USER_PASS]);
+        let msg = client_auth("svc", "pw");
+        assert_eq!(auth(&msg), Ok(Some((msg.len(), (&b"svc"[..], &b"pw"[..])))));
+        // a name goes in the domain form, for the parent to resolve
+        for (host, port) in [("example.com", 443), ("10.0.0.1", 80), ("2001:db8::1", 8080)] {
+            let msg = client_connect(host, port).unwrap();
+            let (end, req) = request_ok(&msg);
+            assert_eq!((end, req.host.as_str(), req.port), (msg.len(), host, port));
+            assert!(req.is_connect());
+        }
+        assert_eq!(client_connect("example.com", 443).unwrap()[3], 0x03);
+        assert!(client_connect(&"a".repeat(256), 443).is_err());
+    }
+
+    #[test]
+    fn the_parent_answers() {
+        assert_eq!(method_choice(&[VERSION_5, USER_PASS]), Ok(Some((2, USER_PASS))));
+        assert_eq!(method_choice(&[VERSION_5]), Ok(None));
+        assert!(method_choice(&[VERSION_4, 0]).is_err());
+        assert_eq!(auth_status(&[AUTH_VERSION, 0]), Ok(Some((2, true))));
+        assert_eq!(auth_status(&[AUTH_VERSION, 1]), Ok(Some((2, false))));
+        assert!(auth_status(&[VERSION_5, 0]).is_err());
+        // the reply is as long as its bound address, and no longer
+        for bound in [None, Some("192.0.2.1:1080".parse().unwrap()), Some("[::1]:1080".parse().unwrap())] {
+            let msg = reply(Version::Five, Reply::Refused, bound);
+            assert_eq!(connect_reply(&[&msg[..], b"data"].concat()), Ok(Some((msg.len(), Reply::Refused as u8))));
+            incomplete_until_whole(&msg, |b| connect_reply(b) == Ok(None));
+        }
+        let mut named = vec![VERSION_5, 0, 0, 0x03, 4];
+        named.extend_from_slice(b"host\0\x50");
+        assert_eq!(connect_reply(&named), Ok(Some((named.len(), 0))));
+        assert!(connect_reply(&[VERSION_4, 0, 0, 0x01]).is_err());
+    }
+
+    #[test]
+    fn connect_failures_map_to_replies() {
+        assert_eq!(Reply::for_failure(ConnectFailure::Dns), Reply::HostUnreachable);
+        assert_eq!(Reply::for_failure(ConnectFailure::Refused), Reply::Refused);
+        assert_eq!(Reply::for_failure(ConnectFailure::Timeout), Reply::TtlExpired);
+        assert_eq!(Reply::for_failure(ConnectFailure::Unreachable), Reply::NetworkUnreachable);
+        assert_eq!(Reply::for_failure(ConnectFailure::Other), Reply::GeneralFailure);
+    }
+}
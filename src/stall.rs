@@ -0,0 +1,175 @@
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use log::{error, warn};
+
+use crate::{
+    access_log, logging,
+    stats::{EventKind, WorkerStats},
+};
+
+/// What to do about a worker loop that has not turned for
+/// `Config::loop_stall`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StallAction {
+    /// log it at error level, and once more when the loop turns again
+    Log,
+    /// log it and abort, for systemd or whatever started the proxy to
+    /// restart it; the sessions of every worker go with it
+    Abort,
+}
+
+impl FromStr for StallAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "log" => Ok(StallAction::Log),
+            "abort" => Ok(StallAction::Abort),
+            _ => Err(format!("unknown loop stall action {:?}, expected log or abort", s)),
+        }
+    }
+}
+
+impl Display for StallAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StallAction::Log => "log",
+            StallAction::Abort => "abort",
+        })
+    }
+}
+
+/// What a worker loop was last busy with, kept in `WorkerStats` for the
+/// stall report; also what `loop_sampler` sorts loop time by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Activity {
+    /// waiting for events, or between the steps below
+    Polling,
+    Timers,
+    /// the heartbeat timer, which goes over all sessions
+    Heartbeat,
+    Event(EventKind),
+    /// messages from the supervisor and the other workers
+    Commands,
+    Admin,
+    /// a session pumped again right away as it had more to move
+    Requeued,
+    /// writing out the hexdump of a bad request head
+    Dump,
+    /// the rate-limited error lines and the loop summary
+    Logging,
+}
+
+impl Activity {
+    /// The form `WorkerStats` stores.
+    pub fn code(self) -> u8 {
+        match self {
+            Activity::Polling => 0,
+            Activity::Timers => 1,
+            Activity::Commands => 2,
+            Activity::Admin => 3,
+            Activity::Requeued => 4,
+            Activity::Dump => 5,
+            Activity::Heartbeat => 6,
+            Activity::Logging => 7,
+            Activity::Event(kind) => 8 + kind as u8,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Activity {
+        match code {
+            1 => Activity::Timers,
+            2 => Activity::Commands,
+            3 => Activity::Admin,
+            4 => Activity::Requeued,
+            5 => Activity::Dump,
+            6 => Activity::Heartbeat,
+            7 => Activity::Logging,
+            c => match EventKind::ALL.get(usize::from(c.wrapping_sub(8))) {
+                Some(&kind) => Activity::Event(kind),
+                None => Activity::Polling,
+            },
+        }
+    }
+
+    /// Short name, the event kind's for events.
+    pub fn name(self) -> &'static str {
+        match self {
+            Activity::Polling => "polling",
+            Activity::Timers => "timers",
+            Activity::Heartbeat => "heartbeat",
+            Activity::Event(kind) => kind.name(),
+            Activity::Commands => "commands",
+            Activity::Admin => "admin",
+            Activity::Requeued => "requeued",
+            Activity::Dump => "dump",
+            Activity::Logging => "logging",
+        }
+    }
+}
+
+impl Display for Activity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Activity::Polling => f.write_str("polling"),
+            Activity::Timers => f.write_str("timers"),
+            Activity::Heartbeat => f.write_str("the heartbeat"),
+            Activity::Event(kind) => write!(f, "a {} event", kind.name()),
+            Activity::Commands => f.write_str("commands"),
+            Activity::Admin => f.write_str("an admin request"),
+            Activity::Requeued => f.write_str("a requeued pump"),
+            Activity::Dump => f.write_str("a head dump"),
+            Activity::Logging => f.write_str("logging"),
+        }
+    }
+}
+
+/// Worker loops found stalled, with the longest they were seen so, for
+/// each stall to be reported once.
+#[derive(Debug, Default)]
+pub struct StallCheck {
+    stalled: Vec<Option<Duration>>,
+}
+
+impl StallCheck {
+    /// Logs each worker loop that has not ticked for `threshold` along with
+    /// what it was busy with, and aborts on `StallAction::Abort`. Runs in
+    /// the supervisor every `worker::HEARTBEAT`, so it still works when
+    /// every worker is stuck.
+    pub fn check(&mut self, stats: &[Arc<WorkerStats>], threshold: Duration, action: StallAction) {
+        self.stalled.resize(stats.len(), None);
+        for (n, (s, stalled)) in stats.iter().zip(&mut self.stalled).enumerate() {
+            match (s.tick_age(), *stalled) {
+                (Some(age), None) if age >= threshold => {
+                    let (activity, session) = s.activity();
+                    if session == 0 {
+                        error!(worker = n; "worker {} loop stalled for {:.1}s in {}", n, age.as_secs_f64(), activity);
+                    } else {
+                        error!(
+                            worker = n, session = session;
+                            "worker {} loop stalled for {:.1}s in {} on session {}", n, age.as_secs_f64(), activity, session
+                        );
+                    }
+                    if action == StallAction::Abort {
+                        error!("aborting, loop_stall_action = abort");
+                        access_log::flush();
+                        logging::flush();
+                        std::process::abort();
+                    }
+                    *stalled = Some(age);
+                }
+                (Some(age), Some(longest)) if age >= threshold => *stalled = Some(longest.max(age)),
+                (_, Some(longest)) => {
+                    warn!(worker = n; "worker {} loop turning again after at least {:.1}s", n, longest.as_secs_f64());
+                    *stalled = None;
+                }
+                _ => {}
+            }
+        }
+    }
+}
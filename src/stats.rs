@@ -0,0 +1,642 @@
+use std::{
+    io::{self, ErrorKind},
+    sync::{
+        atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    err::ErrorCategory, failing_hosts::FailureTable, loop_sampler::SampleTable, session::CloseReason,
+    stall::Activity, top_hosts::HostTable,
+};
+
+/// Latency buckets of a `Histogram`: bucket i counts durations below 2^i
+/// µs, the last one also everything longer (about half a second and up).
+pub const BUCKETS: usize = 20;
+
+/// What a timed event was spent on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Accept,
+    HeadRead,
+    Pipe,
+    Write,
+    Close,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 5] = [
+        EventKind::Accept,
+        EventKind::HeadRead,
+        EventKind::Pipe,
+        EventKind::Write,
+        EventKind::Close,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EventKind::Accept => "accept",
+            EventKind::HeadRead => "head-read",
+            EventKind::Pipe => "pipe",
+            EventKind::Write => "write",
+            EventKind::Close => "close",
+        }
+    }
+}
+
+/// A step from accepting a client to its tunnel being up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// accept to the request head complete
+    Head,
+    /// head to the destination's address
+    Dns,
+    /// address to the TCP connect done
+    Connect,
+    /// connected to the client's 200 written, or its request forwarded;
+    /// includes a parent proxy's answer
+    Reply,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 4] = [Phase::Head, Phase::Dns, Phase::Connect, Phase::Reply];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Head => "head",
+            Phase::Dns => "dns",
+            Phase::Connect => "connect",
+            Phase::Reply => "reply",
+        }
+    }
+}
+
+/// What an established session carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrafficClass {
+    /// a CONNECT tunnel whose client opened with a TLS handshake record
+    TunnelTls,
+    /// a CONNECT tunnel with any other first byte, or none at all
+    TunnelOther,
+    /// a plain HTTP request passed on
+    ForwardHttp,
+    /// the datagrams of a SOCKS5 UDP association
+    Udp,
+}
+
+impl TrafficClass {
+    pub const ALL: [TrafficClass; 4] =
+        [TrafficClass::TunnelTls, TrafficClass::TunnelOther, TrafficClass::ForwardHttp, TrafficClass::Udp];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TrafficClass::TunnelTls => "tunnel-tls",
+            TrafficClass::TunnelOther => "tunnel-other",
+            TrafficClass::ForwardHttp => "forward-http",
+            TrafficClass::Udp => "udp",
+        }
+    }
+}
+
+/// Why an upstream could not be reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectFailure {
+    Dns,
+    Refused,
+    Timeout,
+    Unreachable,
+    /// the parent proxy failed or refused our CONNECT
+    Parent,
+    Other,
+}
+
+impl ConnectFailure {
+    pub const ALL: [ConnectFailure; 6] = [
+        ConnectFailure::Dns,
+        ConnectFailure::Refused,
+        ConnectFailure::Timeout,
+        ConnectFailure::Unreachable,
+        ConnectFailure::Parent,
+        ConnectFailure::Other,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ConnectFailure::Dns => "dns",
+            ConnectFailure::Refused => "refused",
+            ConnectFailure::Timeout => "timeout",
+            ConnectFailure::Unreachable => "unreachable",
+            ConnectFailure::Parent => "parent",
+            ConnectFailure::Other => "other",
+        }
+    }
+
+    /// The kind of a failed connect's socket error.
+    pub fn from_error(e: &io::Error) -> ConnectFailure {
+        match e.kind() {
+            ErrorKind::ConnectionRefused => ConnectFailure::Refused,
+            ErrorKind::TimedOut => ConnectFailure::Timeout,
+            ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable => ConnectFailure::Unreachable,
+            _ => ConnectFailure::Other,
+        }
+    }
+}
+
+/// Power-of-two latency histogram. Recording is one relaxed increment, so
+/// the loop can afford to time every event.
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    /// total of everything recorded, for the Prometheus `_sum`
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    pub fn record(&self, d: Duration) {
+        let us = d.as_micros().min(u64::MAX as u128) as u64;
+        let i = ((u64::BITS - us.leading_zeros()) as usize).min(BUCKETS - 1);
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(us, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> Buckets {
+        Buckets {
+            counts: std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed)),
+            sum_us: self.sum_us.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Plain copy of histogram counts, summed over workers and diffed per tick.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Buckets {
+    /// `counts[i]` is the number of durations below 2^i µs and at least
+    /// 2^(i-1), the last bucket is open-ended
+    pub counts: [u64; BUCKETS],
+    pub sum_us: u64,
+}
+
+impl Buckets {
+    fn add(&mut self, other: &Buckets) {
+        self.counts.iter_mut().zip(other.counts).for_each(|(a, b)| *a += b);
+        self.sum_us += other.sum_us;
+    }
+
+    /// Counts recorded after `earlier` was taken.
+    pub fn since(&self, earlier: &Buckets) -> Buckets {
+        Buckets {
+            counts: std::array::from_fn(|i| self.counts[i] - earlier.counts[i]),
+            sum_us: self.sum_us - earlier.sum_us,
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Upper bound of the bucket holding the `q` quantile, zero when empty.
+    pub fn quantile(&self, q: f64) -> Duration {
+        let total = self.count();
+        if total == 0 {
+            return Duration::ZERO;
+        }
+        let rank = ((total as f64 * q).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Duration::from_micros(1 << i);
+            }
+        }
+        Duration::from_micros(1 << (BUCKETS - 1))
+    }
+}
+
+/// Counters a worker publishes for the supervisor. Written only by the
+/// owning worker, read (and summed) by the main thread.
+#[derive(Default)]
+pub struct WorkerStats {
+    pub active_sessions: AtomicUsize,
+    /// sessions still reading their request head
+    pub head_sessions: AtomicUsize,
+    pub sessions_opened: AtomicU64,
+    pub sessions_closed: AtomicU64,
+    /// closes indexed like `CloseReason::ALL`; `MaxSessions` counts the
+    /// clients turned away at accept, which never opened a session
+    pub closed_by: [AtomicU64; CloseReason::ALL.len()],
+    /// sockets the acceptor sent that the worker has not adopted yet
+    pub handoff_pending: AtomicUsize,
+    /// wakeups of the worker's poll
+    pub polls: AtomicU64,
+    /// readiness events delivered over all polls
+    pub events: AtomicU64,
+    /// polls that filled the events buffer, i.e. there may have been more
+    pub full_polls: AtomicU64,
+    /// payload bytes spliced client to upstream / upstream to client
+    pub bytes_up: AtomicU64,
+    pub bytes_down: AtomicU64,
+    /// lookups answered from the DNS cache / resolved / failed
+    pub dns_hits: AtomicU64,
+    pub dns_misses: AtomicU64,
+    pub dns_failures: AtomicU64,
+    /// time spent in getaddrinfo, cache hits excluded
+    pub dns_latency: Histogram,
+    /// from issuing the upstream connect to it being established
+    pub connect_latency: Histogram,
+    /// establishment of the sessions that got that far, indexed like
+    /// `Phase::ALL`
+    pub establish_latency: [Histogram; Phase::ALL.len()],
+    /// upstream connects that failed, indexed like `ConnectFailure::ALL`
+    pub connect_failures: [AtomicU64; ConnectFailure::ALL.len()],
+    /// upstream connects made with `Config::upstream_mptcp` or
+    /// `Route::mptcp` that speak MPTCP / that ended up on plain TCP
+    pub mptcp_negotiated: AtomicU64,
+    pub mptcp_fallbacks: AtomicU64,
+    /// sessions closed on an error, indexed like `ErrorCategory::ALL`
+    pub errors: [AtomicU64; ErrorCategory::ALL.len()],
+    /// sessions and their bytes by what they carry, indexed like
+    /// `TrafficClass::ALL`; bytes moved before a tunnel is classified are
+    /// added when it is
+    pub class_sessions: [AtomicU64; TrafficClass::ALL.len()],
+    pub class_bytes_up: [AtomicU64; TrafficClass::ALL.len()],
+    pub class_bytes_down: [AtomicU64; TrafficClass::ALL.len()],
+    /// per listener, indexed like `Config::listener_names`
+    pub listeners: Vec<ListenerStats>,
+    /// accept batches that stopped on the cap with connections still queued
+    pub accept_cap_hits: AtomicU64,
+    /// connections closed at accept, `Config::accept_rate` was used up
+    /// and `accept_rate_shed` set
+    pub accepts_shed: AtomicU64,
+    /// times a listener was left alone for `Config::accept_rate` to refill
+    pub accept_pauses: AtomicU64,
+    /// clients turned away at accept by their address, see
+    /// `acl::check_client`
+    pub clients_refused: AtomicU64,
+    /// connections closed at accept for their source's `Config::conn_rate`
+    pub conn_rate_limited: AtomicU64,
+    /// connections dropped at accept, their source banned by `bans::failed`
+    pub banned_dropped: AtomicU64,
+    /// tunnels whose ClientHello did not match their CONNECT host, closed
+    /// or only logged as `Config::sni_check` has it
+    pub sni_mismatches: AtomicU64,
+    /// time from poll return to the end of the loop iteration
+    pub loop_latency: Histogram,
+    /// handling time per readiness event, indexed like `EventKind::ALL`
+    pub event_latency: [Histogram; EventKind::ALL.len()],
+    /// longest single event in µs since the supervisor last took it
+    pub longest_event_us: AtomicU64,
+    /// traffic by destination host, added to as sessions close
+    pub hosts: Mutex<HostTable>,
+    /// recent connects by destination host, for `/failing-hosts`
+    pub failing_hosts: Mutex<FailureTable>,
+    /// splice pipes and pending bytes of the sessions, counted on every
+    /// heartbeat, see `Session::pending`
+    pub pipes: AtomicUsize,
+    pub pending_bytes: AtomicU64,
+    /// when the loop last went through its heartbeat timer, ms after
+    /// `EPOCH`; `u64::MAX` once it exited on purpose
+    pub last_tick_ms: AtomicU64,
+    /// longest time between two of those ticks, in ms
+    pub max_tick_gap_ms: AtomicU64,
+    /// `Activity::code` of what the loop is on and the session it is for,
+    /// 0 for none, see `busy`
+    pub activity: AtomicU8,
+    pub busy_session: AtomicU64,
+    /// what sampled loop iterations spent the most time on, see
+    /// `Config::loop_sample_every`
+    pub loop_samples: SampleTable,
+}
+
+/// Reference point of `WorkerStats::last_tick_ms`, the first stats made.
+static EPOCH: OnceLock<Instant> = OnceLock::new();
+
+pub fn since_epoch() -> Duration {
+    EPOCH.get_or_init(Instant::now).elapsed()
+}
+
+impl WorkerStats {
+    pub fn new(listeners: usize) -> WorkerStats {
+        let stats = WorkerStats {
+            listeners: (0..listeners).map(|_| ListenerStats::default()).collect(),
+            last_tick_ms: AtomicU64::new(u64::MAX),
+            ..Default::default()
+        };
+        stats.ticked();
+        stats
+    }
+
+    /// The loop is turning, see `tick_age`.
+    pub fn ticked(&self) {
+        let now = since_epoch().as_millis() as u64;
+        let last = self.last_tick_ms.swap(now, Ordering::Relaxed);
+        if last != u64::MAX {
+            self.max_tick_gap_ms.fetch_max(now.saturating_sub(last), Ordering::Relaxed);
+        }
+    }
+
+    /// The loop is done, shutting down or drained, and no longer ticks.
+    pub fn stopped_ticking(&self) {
+        self.last_tick_ms.store(u64::MAX, Ordering::Relaxed);
+    }
+
+    /// Time since the loop last ticked, None after `stopped_ticking`.
+    pub fn tick_age(&self) -> Option<Duration> {
+        match self.last_tick_ms.load(Ordering::Relaxed) {
+            u64::MAX => None,
+            ms => Some(since_epoch().saturating_sub(Duration::from_millis(ms))),
+        }
+    }
+
+    /// The loop turns to `activity`, for `session` unless 0.
+    pub fn busy(&self, activity: Activity, session: u64) {
+        self.activity.store(activity.code(), Ordering::Relaxed);
+        self.busy_session.store(session, Ordering::Relaxed);
+    }
+
+    /// What the loop was last busy with, and for which session.
+    pub fn activity(&self) -> (Activity, u64) {
+        (
+            Activity::from_code(self.activity.load(Ordering::Relaxed)),
+            self.busy_session.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Load figure the acceptor balances on.
+    pub fn load(&self) -> usize {
+        self.active_sessions.load(Ordering::Relaxed) + self.handoff_pending.load(Ordering::Relaxed)
+    }
+
+    pub fn handoff_sent(&self) {
+        self.handoff_pending.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn handoff_done(&self) {
+        self.handoff_pending.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn polled(&self, events: usize, capacity: usize) {
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        self.events.fetch_add(events as u64, Ordering::Relaxed);
+        if events >= capacity {
+            self.full_polls.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn bytes_moved(&self, listener: usize, up: bool, n: usize) {
+        let counter = if up { &self.bytes_up } else { &self.bytes_down };
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+        if let Some(l) = self.listeners.get(listener) {
+            let counter = if up { &l.bytes_up } else { &l.bytes_down };
+            counter.fetch_add(n as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn accepted_on(&self, listener: usize) {
+        if let Some(l) = self.listeners.get(listener) {
+            l.accepted.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// A session turned out to carry `class`, having moved `up` and `down`
+    /// bytes so far.
+    pub fn classified(&self, class: TrafficClass, up: u64, down: u64) {
+        self.class_sessions[class as usize].fetch_add(1, Ordering::Relaxed);
+        self.class_bytes_up[class as usize].fetch_add(up, Ordering::Relaxed);
+        self.class_bytes_down[class as usize].fetch_add(down, Ordering::Relaxed);
+    }
+
+    pub fn class_bytes_moved(&self, class: TrafficClass, up: bool, n: usize) {
+        let counter = if up { &self.class_bytes_up } else { &self.class_bytes_down };
+        counter[class as usize].fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// A client of `listener` was refused, see `audit_log::Denial`.
+    pub fn denied_on(&self, listener: usize) {
+        if let Some(l) = self.listeners.get(listener) {
+            l.denied.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn accept_cap_hit(&self) {
+        self.accept_cap_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn accept_shed(&self) {
+        self.accepts_shed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn accept_paused(&self) {
+        self.accept_pauses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn client_refused(&self) {
+        self.clients_refused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn conn_rate_limit(&self) {
+        self.conn_rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn banned_drop(&self) {
+        self.banned_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sni_mismatch(&self) {
+        self.sni_mismatches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn event_handled(&self, kind: EventKind, d: Duration) {
+        self.event_latency[kind as usize].record(d);
+        self.longest_event_us
+            .fetch_max(d.as_micros().min(u64::MAX as u128) as u64, Ordering::Relaxed);
+    }
+
+    /// Longest event since the previous call, resets the gauge.
+    pub fn take_longest_event(&self) -> Duration {
+        Duration::from_micros(self.longest_event_us.swap(0, Ordering::Relaxed))
+    }
+
+    pub fn session_opened(&self, listener: usize) {
+        if let Some(l) = self.listeners.get(listener) {
+            l.active_sessions.fetch_add(1, Ordering::Relaxed);
+        }
+        self.active_sessions.fetch_add(1, Ordering::Relaxed);
+        self.head_sessions.fetch_add(1, Ordering::Relaxed);
+        self.sessions_opened.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A session's request head is dealt with, by connecting or closing.
+    pub fn head_done(&self) {
+        self.head_sessions.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn session_closed(&self, listener: usize, reason: CloseReason) {
+        if let Some(l) = self.listeners.get(listener) {
+            l.active_sessions.fetch_sub(1, Ordering::Relaxed);
+        }
+        self.active_sessions.fetch_sub(1, Ordering::Relaxed);
+        self.sessions_closed.fetch_add(1, Ordering::Relaxed);
+        self.closed_by[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn session_rejected(&self) {
+        self.closed_by[CloseReason::MaxSessions as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connect_failed(&self, kind: ConnectFailure) {
+        self.connect_failures[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn mptcp(&self, negotiated: bool) {
+        match negotiated {
+            true => self.mptcp_negotiated.fetch_add(1, Ordering::Relaxed),
+            false => self.mptcp_fallbacks.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    pub fn session_error(&self, category: ErrorCategory) {
+        self.errors[category as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn buffers_counted(&self, pipes: usize, pending_bytes: u64) {
+        self.pipes.store(pipes, Ordering::Relaxed);
+        self.pending_bytes.store(pending_bytes, Ordering::Relaxed);
+    }
+}
+
+/// One listener's share of a worker's counters.
+#[derive(Debug, Default)]
+pub struct ListenerStats {
+    pub accepted: AtomicU64,
+    pub active_sessions: AtomicUsize,
+    pub bytes_up: AtomicU64,
+    pub bytes_down: AtomicU64,
+    /// clients refused, see `audit_log::Denial`
+    pub denied: AtomicU64,
+}
+
+/// `ListenerStats` summed over the workers.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ListenerTotals {
+    pub accepted: u64,
+    pub active_sessions: usize,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub denied: u64,
+}
+
+/// Totals over all workers at one point in time.
+#[derive(Debug, Default, Clone)]
+pub struct Summary {
+    pub active_sessions: usize,
+    pub head_sessions: usize,
+    pub sessions_opened: u64,
+    pub sessions_closed: u64,
+    pub closed_by: [u64; CloseReason::ALL.len()],
+    pub polls: u64,
+    pub events: u64,
+    pub full_polls: u64,
+    /// indexed like `Config::listener_names`
+    pub listeners: Vec<ListenerTotals>,
+    pub accept_cap_hits: u64,
+    pub accepts_shed: u64,
+    pub accept_pauses: u64,
+    pub clients_refused: u64,
+    pub conn_rate_limited: u64,
+    pub banned_dropped: u64,
+    pub sni_mismatches: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub dns_hits: u64,
+    pub dns_misses: u64,
+    pub dns_failures: u64,
+    pub dns_latency: Buckets,
+    pub connect_latency: Buckets,
+    pub establish_latency: [Buckets; Phase::ALL.len()],
+    pub connect_failures: [u64; ConnectFailure::ALL.len()],
+    pub mptcp_negotiated: u64,
+    pub mptcp_fallbacks: u64,
+    pub errors: [u64; ErrorCategory::ALL.len()],
+    pub class_sessions: [u64; TrafficClass::ALL.len()],
+    pub class_bytes_up: [u64; TrafficClass::ALL.len()],
+    pub class_bytes_down: [u64; TrafficClass::ALL.len()],
+    pub loop_latency: Buckets,
+    pub event_latency: [Buckets; EventKind::ALL.len()],
+    pub pipes: usize,
+    pub pending_bytes: u64,
+    /// longest gap between two ticks of any worker loop
+    pub max_tick_gap: Duration,
+}
+
+impl Summary {
+    pub fn merge<'a>(stats: impl Iterator<Item = &'a WorkerStats>) -> Summary {
+        stats.fold(Summary::default(), |mut acc, s| {
+            acc.active_sessions += s.active_sessions.load(Ordering::Relaxed);
+            acc.head_sessions += s.head_sessions.load(Ordering::Relaxed);
+            acc.sessions_opened += s.sessions_opened.load(Ordering::Relaxed);
+            acc.sessions_closed += s.sessions_closed.load(Ordering::Relaxed);
+            for (a, c) in acc.closed_by.iter_mut().zip(&s.closed_by) {
+                *a += c.load(Ordering::Relaxed);
+            }
+            acc.polls += s.polls.load(Ordering::Relaxed);
+            acc.events += s.events.load(Ordering::Relaxed);
+            acc.full_polls += s.full_polls.load(Ordering::Relaxed);
+            if acc.listeners.len() < s.listeners.len() {
+                acc.listeners.resize(s.listeners.len(), ListenerTotals::default());
+            }
+            for (a, l) in acc.listeners.iter_mut().zip(&s.listeners) {
+                a.accepted += l.accepted.load(Ordering::Relaxed);
+                a.active_sessions += l.active_sessions.load(Ordering::Relaxed);
+                a.bytes_up += l.bytes_up.load(Ordering::Relaxed);
+                a.bytes_down += l.bytes_down.load(Ordering::Relaxed);
+                a.denied += l.denied.load(Ordering::Relaxed);
+            }
+            acc.accept_cap_hits += s.accept_cap_hits.load(Ordering::Relaxed);
+            acc.accepts_shed += s.accepts_shed.load(Ordering::Relaxed);
+            acc.accept_pauses += s.accept_pauses.load(Ordering::Relaxed);
+            acc.clients_refused += s.clients_refused.load(Ordering::Relaxed);
+            acc.conn_rate_limited += s.conn_rate_limited.load(Ordering::Relaxed);
+            acc.banned_dropped += s.banned_dropped.load(Ordering::Relaxed);
+            acc.sni_mismatches += s.sni_mismatches.load(Ordering::Relaxed);
+            acc.bytes_up += s.bytes_up.load(Ordering::Relaxed);
+            acc.bytes_down += s.bytes_down.load(Ordering::Relaxed);
+            acc.dns_hits += s.dns_hits.load(Ordering::Relaxed);
+            acc.dns_misses += s.dns_misses.load(Ordering::Relaxed);
+            acc.dns_failures += s.dns_failures.load(Ordering::Relaxed);
+            acc.dns_latency.add(&s.dns_latency.load());
+            acc.connect_latency.add(&s.connect_latency.load());
+            for (a, h) in acc.establish_latency.iter_mut().zip(&s.establish_latency) {
+                a.add(&h.load());
+            }
+            for (a, c) in acc.connect_failures.iter_mut().zip(&s.connect_failures) {
+                *a += c.load(Ordering::Relaxed);
+            }
+            acc.mptcp_negotiated += s.mptcp_negotiated.load(Ordering::Relaxed);
+            acc.mptcp_fallbacks += s.mptcp_fallbacks.load(Ordering::Relaxed);
+            for (a, c) in acc.errors.iter_mut().zip(&s.errors) {
+                *a += c.load(Ordering::Relaxed);
+            }
+            for (a, c) in acc.class_sessions.iter_mut().zip(&s.class_sessions) {
+                *a += c.load(Ordering::Relaxed);
+            }
+            for (a, c) in acc.class_bytes_up.iter_mut().zip(&s.class_bytes_up) {
+                *a += c.load(Ordering::Relaxed);
+            }
+            for (a, c) in acc.class_bytes_down.iter_mut().zip(&s.class_bytes_down) {
+                *a += c.load(Ordering::Relaxed);
+            }
+            acc.loop_latency.add(&s.loop_latency.load());
+            for (a, h) in acc.event_latency.iter_mut().zip(&s.event_latency) {
+                a.add(&h.load());
+            }
+            acc.pipes += s.pipes.load(Ordering::Relaxed);
+            acc.pending_bytes += s.pending_bytes.load(Ordering::Relaxed);
+            acc.max_tick_gap = acc
+                .max_tick_gap
+                .max(Duration::from_millis(s.max_tick_gap_ms.load(Ordering::Relaxed)));
+            acc
+        })
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    fmt::{Display, Write as _},
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use log::warn;
+
+use crate::{
+    config::Config,
+    metrics::{self, Labels},
+    stats::Summary,
+    usage::Usage,
+};
+
+/// Datagram payload cap, what fits a 1500 byte MTU with room for IPv6 and
+/// tunnel headers.
+const MAX_DATAGRAM: usize = 1432;
+
+/// At most one warning per this long while sends keep failing.
+const WARN_EVERY: Duration = Duration::from_secs(60);
+
+/// Pushes the counters to `Config::statsd` over UDP from the supervisor's
+/// stats tick: counters as their change since the previous tick, gauges as
+/// they are. Nothing here runs on a worker, and a failing send only costs
+/// the metrics of that tick.
+pub struct Statsd {
+    target: String,
+    /// resolved and bound on first use, dropped again after a send error
+    /// so a moved server is looked up anew
+    sock: Option<(UdpSocket, SocketAddr)>,
+    prefix: String,
+    /// `Config::statsd_tags`, Some switches to DogStatsD lines with the
+    /// labels as tags
+    tags: Option<Vec<String>>,
+    /// failures not warned about since the last warning
+    failures: u64,
+    warned: Option<Instant>,
+}
+
+impl Statsd {
+    /// The emitter `config` asks for, None when the export is off.
+    pub fn new(config: &Config) -> Option<Statsd> {
+        if !config.metrics.statsd() {
+            return None;
+        }
+        Some(Statsd {
+            target: config.statsd.clone()?,
+            sock: None,
+            prefix: config.statsd_prefix.clone(),
+            tags: config.statsd_tags.clone(),
+            failures: 0,
+            warned: None,
+        })
+    }
+
+    /// Sends one tick's worth: `now` against the `last` tick's summary.
+    /// `listeners` label the per-listener series, see `metrics::counters`.
+    pub fn emit(&mut self, now: &Summary, last: &Summary, usage: &Usage, nofile: u64, listeners: &[(String, String)]) {
+        let mut lines = Vec::new();
+        let counters = metrics::counters(now, listeners);
+        for (family, previous) in counters.iter().zip(metrics::counters(last, listeners)) {
+            // the first tick's `last` has no listeners yet
+            for (i, (labels, value)) in family.samples.iter().enumerate() {
+                let before = previous.samples.get(i).map_or(0, |(_, v)| *v);
+                lines.push(self.line(family.name, labels, value.saturating_sub(before), "c"));
+            }
+        }
+        for family in metrics::gauges(now, usage, nofile, listeners) {
+            for (labels, value) in &family.samples {
+                lines.push(self.line(family.name, labels, value, "g"));
+            }
+        }
+        for datagram in pack(&lines) {
+            if let Err(e) = self.send(datagram.as_bytes()) {
+                self.sock = None;
+                self.failed(e);
+                return;
+            }
+        }
+    }
+
+    /// `prefix.name:value|kind`; labels become DogStatsD tags, or last
+    /// name components for plain statsd.
+    fn line(&self, name: &str, labels: &Labels, value: impl Display, kind: &str) -> String {
+        let mut line = self.prefix.clone();
+        if !line.is_empty() {
+            line.push('.');
+        }
+        line.push_str(name.strip_suffix("_total").unwrap_or(name));
+        match &self.tags {
+            None => {
+                for (_, v) in labels {
+                    let _ = write!(line, ".{}", sanitize(v, |c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+                }
+                let _ = write!(line, ":{}|{}", value, kind);
+            }
+            Some(tags) => {
+                let _ = write!(line, ":{}|{}", value, kind);
+                let mut all = labels
+                    .iter()
+                    .map(|(k, v)| format!("{}:{}", k, sanitize(v, |c| !matches!(c, ',' | '|' | '#') && !c.is_whitespace())))
+                    .chain(tags.iter().cloned());
+                if let Some(first) = all.next() {
+                    let _ = write!(line, "|#{}", first);
+                    all.for_each(|t| {
+                        let _ = write!(line, ",{}", t);
+                    });
+                }
+            }
+        }
+        line
+    }
+
+    fn send(&mut self, datagram: &[u8]) -> io::Result<()> {
+        if self.sock.is_none() {
+            let addr = self
+                .target
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+            let local: SocketAddr = if addr.is_ipv4() {
+                "0.0.0.0:0".parse().unwrap()
+            } else {
+                "[::]:0".parse().unwrap()
+            };
+            let sock = UdpSocket::bind(local)?;
+            sock.set_nonblocking(true)?;
+            self.sock = Some((sock, addr));
+        }
+        let (sock, addr) = self.sock.as_ref().unwrap();
+        sock.send_to(datagram, addr).map(|_| ())
+    }
+
+    fn failed(&mut self, e: io::Error) {
+        if self.warned.is_some_and(|t| t.elapsed() < WARN_EVERY) {
+            self.failures += 1;
+            return;
+        }
+        warn!(
+            "statsd {}: {}, {} more failures since the last warning",
+            self.target, e, self.failures
+        );
+        self.failures = 0;
+        self.warned = Some(Instant::now());
+    }
+}
+
+/// `v` with the characters not passing `ok` made `_`, for listener names
+/// in a statsd name or tag.
+fn sanitize(v: &str, ok: impl Fn(char) -> bool) -> String {
+    v.chars().map(|c| if ok(c) { c } else { '_' }).collect()
+}
+
+/// `lines` joined by newlines into as few datagrams as fit `MAX_DATAGRAM`.
+fn pack(lines: &[String]) -> Vec<String> {
+    let mut datagrams = Vec::new();
+    let mut current = String::with_capacity(MAX_DATAGRAM);
+    for line in lines {
+        if !current.is_empty() && current.len() + 1 + line.len() > MAX_DATAGRAM {
+            datagrams.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(line);
+    }
+    if !current.is_empty() {
+        datagrams.push(current);
+    }
+    datagrams
+}
@@ -0,0 +1,454 @@
+use std::{
+    fmt::{self, Display, Write as _},
+    io::{self, Write},
+    net::{TcpStream, ToSocketAddrs, UdpSocket},
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::config::Config;
+
+/// Largest message sent over UDP, what RFC 5426 says receivers should take.
+const MAX_UDP: usize = 2048;
+
+/// Largest message sent to a local socket or over TCP, the default limit
+/// of rsyslog and syslog-ng.
+const MAX_STREAM: usize = 8192;
+
+/// What ends a message cut to fit.
+const TRUNCATED: &str = "[truncated]";
+
+/// A target that could not be reached is tried again after this long;
+/// messages in between are dropped and counted.
+const RETRY: Duration = Duration::from_secs(1);
+
+/// Connect and write timeout for TCP targets; a target that is slow holds
+/// up the syslog thread for at most this long, never the one logging.
+const TCP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Messages waiting for the syslog thread; past this they are dropped
+/// rather than holding up the thread logging them.
+const QUEUE: usize = 4096;
+
+/// The syslog thread `configure` started, None while no stream goes there.
+static SYSLOG: Mutex<Option<Sink>> = Mutex::new(None);
+
+/// Messages dropped on a full queue, reported by the syslog thread with
+/// what it drops itself.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Where `Config::syslog` sends messages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// a local datagram socket, `/dev/log` by default
+    Unix(PathBuf),
+    /// `udp://host:port`
+    Udp(String),
+    /// `tcp://host:port`, messages framed by octet counting (RFC 6587)
+    Tcp(String),
+}
+
+impl Target {
+    /// The socket of a local target.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Target::Unix(path) => Some(path),
+            Target::Udp(_) | Target::Tcp(_) => None,
+        }
+    }
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Target::Unix(PathBuf::from("/dev/log"))
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let host_port = |rest: &str| match rest.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(rest.to_owned()),
+            _ => Err(format!("syslog target {:?} needs host:port", s)),
+        };
+        if let Some(rest) = s.strip_prefix("udp://") {
+            host_port(rest).map(Target::Udp)
+        } else if let Some(rest) = s.strip_prefix("tcp://") {
+            host_port(rest).map(Target::Tcp)
+        } else if s.is_empty() || s.contains("://") {
+            Err(format!("unknown syslog target {:?}, expected a socket path, udp://host:port or tcp://host:port", s))
+        } else {
+            Ok(Target::Unix(PathBuf::from(s)))
+        }
+    }
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Unix(path) => write!(f, "{}", path.display()),
+            Target::Udp(addr) => write!(f, "udp://{}", addr),
+            Target::Tcp(addr) => write!(f, "tcp://{}", addr),
+        }
+    }
+}
+
+/// The syslog facilities, by their usual names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Facility(u8);
+
+const FACILITIES: [&str; 24] = [
+    "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron", "authpriv", "ftp", "ntp",
+    "security", "console", "solaris-cron", "local0", "local1", "local2", "local3", "local4", "local5", "local6",
+    "local7",
+];
+
+impl FromStr for Facility {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FACILITIES
+            .iter()
+            .position(|&name| name == s)
+            .map(|code| Facility(code as u8))
+            .ok_or_else(|| format!("unknown syslog facility {:?}, expected daemon, local0 .. local7, ...", s))
+    }
+}
+
+impl Display for Facility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(FACILITIES[self.0 as usize])
+    }
+}
+
+/// RFC 5424 severities the proxy logs at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error = 3,
+    Warning = 4,
+    Notice = 5,
+    Info = 6,
+    Debug = 7,
+}
+
+/// What is sent to syslog, each at the facility configured for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    /// the log, `Config::log_facility`
+    Log = 0,
+    /// access lines, `Config::access_log_facility`
+    Access = 1,
+    /// audit lines, `Config::audit_log_facility`
+    Audit = 2,
+}
+
+impl Stream {
+    /// The MSGID field, `-` for the log.
+    fn msgid(self) -> &'static str {
+        match self {
+            Stream::Log => "-",
+            Stream::Access => "access",
+            Stream::Audit => "audit",
+        }
+    }
+}
+
+enum Conn {
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+
+impl Conn {
+    fn open(target: &Target) -> io::Result<Conn> {
+        match target {
+            Target::Unix(path) => {
+                let sock = UnixDatagram::unbound()?;
+                sock.connect(path)?;
+                // a daemon that does not keep up costs messages, not time
+                sock.set_nonblocking(true)?;
+                Ok(Conn::Unix(sock))
+            }
+            Target::Udp(addr) => {
+                let addr = resolve(addr)?;
+                let sock = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+                sock.connect(addr)?;
+                Ok(Conn::Udp(sock))
+            }
+            Target::Tcp(addr) => {
+                let stream = TcpStream::connect_timeout(&resolve(addr)?, TCP_TIMEOUT)?;
+                stream.set_write_timeout(Some(TCP_TIMEOUT))?;
+                Ok(Conn::Tcp(stream))
+            }
+        }
+    }
+
+    /// The longest message this kind of connection takes.
+    fn max(&self) -> usize {
+        match self {
+            Conn::Udp(_) => MAX_UDP,
+            Conn::Unix(_) | Conn::Tcp(_) => MAX_STREAM,
+        }
+    }
+
+    fn send(&mut self, message: &str) -> io::Result<()> {
+        match self {
+            Conn::Unix(sock) => sock.send(message.as_bytes()).map(drop),
+            Conn::Udp(sock) => sock.send(message.as_bytes()).map(drop),
+            Conn::Tcp(stream) => write!(stream, "{} {}", message.len(), message),
+        }
+    }
+}
+
+fn resolve(addr: &str) -> io::Result<std::net::SocketAddr> {
+    addr.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", addr)))
+}
+
+/// The handle on the syslog thread.
+struct Sink {
+    target: Target,
+    app_name: String,
+    /// per `Stream`, None sends nothing for it
+    facilities: [Option<Facility>; 3],
+    tx: SyncSender<Message>,
+    thread: JoinHandle<()>,
+}
+
+/// One message for the syslog thread, stamped when it was logged.
+struct Message {
+    facility: Facility,
+    severity: Severity,
+    stream: Stream,
+    time: SystemTime,
+    msg: String,
+}
+
+/// RFC 5424 messages to `Config::syslog`, sent by a thread of their own.
+/// The target is connected on first use, so a local syslog daemon that is
+/// not up yet at startup only costs the messages until it is; after a
+/// failed connect or send it is tried again at most once per `RETRY`.
+struct Syslog {
+    target: Target,
+    app_name: String,
+    hostname: String,
+    pid: u32,
+    conn: Option<Conn>,
+    retry_at: Option<Instant>,
+    /// messages dropped while the target was unreachable or behind,
+    /// reported once it takes them again
+    dropped: u64,
+}
+
+impl Syslog {
+    /// Sends what arrives until the queue is dropped.
+    fn run(mut self, rx: Receiver<Message>) {
+        for message in rx {
+            self.send(&message);
+        }
+    }
+
+    fn send(&mut self, m: &Message) {
+        self.dropped += DROPPED.swap(0, Ordering::Relaxed);
+        if self.dropped > 0 && self.connect() {
+            let note = format!("{} messages dropped while {} was unreachable or behind", self.dropped, self.target);
+            if self.write(m.facility, Severity::Warning, Stream::Log, SystemTime::now(), &note) {
+                self.dropped = 0;
+            }
+        }
+        // a local syslog daemon that restarted has a new socket, so a send
+        // that lost the connection is retried on a fresh one
+        let mut sent = self.connect() && self.write(m.facility, m.severity, m.stream, m.time, &m.msg);
+        if !sent && self.conn.is_none() {
+            sent = self.connect() && self.write(m.facility, m.severity, m.stream, m.time, &m.msg);
+        }
+        if !sent {
+            self.dropped += 1;
+        }
+    }
+
+    /// Whether there is a connection, making one unless a recent attempt
+    /// failed.
+    fn connect(&mut self) -> bool {
+        if self.conn.is_some() {
+            return true;
+        }
+        let now = Instant::now();
+        if self.retry_at.is_some_and(|at| now < at) {
+            return false;
+        }
+        match Conn::open(&self.target) {
+            Ok(conn) => {
+                self.conn = Some(conn);
+                self.retry_at = None;
+                true
+            }
+            Err(e) => {
+                if self.retry_at.is_none() && self.dropped == 0 {
+                    eprintln!("syslog {} err {}, retrying", self.target, e);
+                }
+                self.retry_at = Some(now + RETRY);
+                false
+            }
+        }
+    }
+
+    /// Sends one message on the connection, dropping it on error and the
+    /// connection with it unless the local socket was only full.
+    fn write(&mut self, facility: Facility, severity: Severity, stream: Stream, time: SystemTime, msg: &str) -> bool {
+        let Some(conn) = self.conn.as_mut() else {
+            return false;
+        };
+        let mut message = String::with_capacity(64 + msg.len());
+        let _ = write!(
+            message,
+            "<{}>1 {} {} {} {} {} - {}",
+            facility.0 as u32 * 8 + severity as u32,
+            humantime::format_rfc3339_micros(time),
+            self.hostname,
+            self.app_name,
+            self.pid,
+            stream.msgid(),
+            msg
+        );
+        truncate(&mut message, conn.max());
+        match conn.send(&message) {
+            Ok(()) => true,
+            Err(e) => {
+                if self.dropped == 0 {
+                    eprintln!("syslog {} send err {}", self.target, e);
+                }
+                if e.kind() != io::ErrorKind::WouldBlock {
+                    self.conn = None;
+                }
+                false
+            }
+        }
+    }
+}
+
+/// Cuts `message` to at most `max` bytes, on a character boundary and
+/// ending in `TRUNCATED`.
+fn truncate(message: &mut String, max: usize) {
+    if message.len() <= max {
+        return;
+    }
+    let mut cut = max - TRUNCATED.len();
+    while !message.is_char_boundary(cut) {
+        cut -= 1;
+    }
+    message.truncate(cut);
+    message.push_str(TRUNCATED);
+}
+
+/// Starts sending to `config.syslog` for the streams with a facility, or
+/// stops when none has one. Runs at startup and again on reload; nothing
+/// is connected until the first message. A previous thread sends what it
+/// has and exits once its queue is dropped.
+pub fn configure(config: &Config) -> io::Result<()> {
+    let facilities = [config.log_facility, config.access_log_facility, config.audit_log_facility];
+    let mut sink = SYSLOG.lock().unwrap();
+    if facilities.iter().all(Option::is_none) {
+        *sink = None;
+        return Ok(());
+    }
+    // the thread and its connection carry over when the target and the
+    // app name stay the same
+    if let Some(old) = sink
+        .as_mut()
+        .filter(|old| old.target == config.syslog && old.app_name == config.syslog_app_name)
+    {
+        old.facilities = facilities;
+        return Ok(());
+    }
+    let syslog = Syslog {
+        target: config.syslog.clone(),
+        app_name: config.syslog_app_name.clone(),
+        hostname: hostname(),
+        pid: std::process::id(),
+        conn: None,
+        retry_at: None,
+        dropped: 0,
+    };
+    let (tx, rx) = mpsc::sync_channel(QUEUE);
+    let thread = thread::Builder::new()
+        .name("syslog".to_owned())
+        .spawn(move || syslog.run(rx))?;
+    *sink = Some(Sink {
+        target: config.syslog.clone(),
+        app_name: config.syslog_app_name.clone(),
+        facilities,
+        tx,
+        thread,
+    });
+    Ok(())
+}
+
+/// Whether `stream` goes to syslog, so its lines are worth formatting.
+pub fn wants(stream: Stream) -> bool {
+    SYSLOG
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|s| s.facilities[stream as usize].is_some())
+}
+
+/// Queues `msg` for `stream`, nothing when it has no facility.
+pub fn send(stream: Stream, severity: Severity, msg: &str) {
+    let sink = SYSLOG.lock().unwrap();
+    let Some(sink) = sink.as_ref() else {
+        return;
+    };
+    let Some(facility) = sink.facilities[stream as usize] else {
+        return;
+    };
+    let message = Message {
+        facility,
+        severity,
+        stream,
+        time: SystemTime::now(),
+        msg: msg.to_owned(),
+    };
+    match sink.tx.try_send(message) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(TrySendError::Disconnected(_)) => {}
+    }
+}
+
+/// Waits for the syslog thread to send what is queued, at exit.
+pub fn flush() {
+    let sink = SYSLOG.lock().unwrap().take();
+    if let Some(sink) = sink {
+        drop(sink.tx);
+        let _ = sink.thread.join();
+    }
+}
+
+/// The HOSTNAME field, `-` when it cannot be had or is not printable
+/// ASCII.
+fn hostname() -> String {
+    nix::unistd::gethostname()
+        .ok()
+        .and_then(|h| h.into_string().ok())
+        .filter(|h| !h.is_empty() && h.len() <= 255 && h.bytes().all(|b| b.is_ascii_graphic()))
+        .unwrap_or_else(|| "-".to_owned())
+}
+
+/// Whether `name` can be the APP-NAME field: 1 to 48 printable ASCII
+/// characters.
+pub fn valid_app_name(name: &str) -> bool {
+    (1..=48).contains(&name.len()) && name.bytes().all(|b| b.is_ascii_graphic())
+}
@@ -0,0 +1,177 @@
+use std::{
+    env,
+    path::Path,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+use nix::{
+    time::{clock_gettime, ClockId},
+    unistd::{getpid, getppid},
+};
+use socket2::{Domain, SockAddr, Socket, Type};
+
+const NOTIFY_SOCKET_ENV: &str = "NOTIFY_SOCKET";
+const WATCHDOG_USEC_ENV: &str = "WATCHDOG_USEC";
+const WATCHDOG_PID_ENV: &str = "WATCHDOG_PID";
+
+/// The service manager connection, set up once by `init`.
+static NOTIFY: OnceLock<Notify> = OnceLock::new();
+
+struct Notify {
+    /// None when not started by systemd with `Type=notify`
+    socket: Option<(Socket, SockAddr)>,
+    /// whether we took over from an upgraded parent, see `ready`
+    successor: bool,
+    watchdog: Option<Watchdog>,
+}
+
+/// `WATCHDOG=1` is sent only once every event loop checked in since the
+/// last one, so a single wedged loop is enough for systemd to restart us.
+struct Watchdog {
+    /// how often each loop checks in
+    interval: Duration,
+    loops: Mutex<Vec<Slot>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    Pending,
+    Seen,
+    /// the loop exited and no longer holds up a round
+    Gone,
+}
+
+/// Reads what systemd passed in the environment. `loops` is the number of
+/// event loops reporting to the watchdog, `successor` whether the process
+/// was started by a SIGUSR2 upgrade. Call once before the loops start.
+pub fn init(loops: usize, successor: bool) {
+    let socket = match env::var_os(NOTIFY_SOCKET_ENV) {
+        Some(path) => match connect(Path::new(&path)) {
+            Ok(s) => Some(s),
+            Err(e) => {
+                warn!("systemd: cannot use {}={}: {}", NOTIFY_SOCKET_ENV, path.to_string_lossy(), e);
+                None
+            }
+        },
+        None => None,
+    };
+    let watchdog = socket.as_ref().and(watchdog_interval(successor)).map(|interval| {
+        info!("systemd: watchdog ping every {:?} from {} event loops", interval, loops);
+        Watchdog {
+            interval,
+            loops: Mutex::new(vec![Slot::Pending; loops]),
+        }
+    });
+    let _ = NOTIFY.set(Notify {
+        socket,
+        successor,
+        watchdog,
+    });
+}
+
+/// Listeners are up and the config is valid. A successor also tells
+/// systemd it is the main process now, which needs `NotifyAccess=all`.
+pub fn ready() {
+    if NOTIFY.get().is_some_and(|n| n.successor) {
+        notify(&format!("MAINPID={}\nREADY=1", getpid()));
+    } else {
+        notify("READY=1");
+    }
+}
+
+/// A SIGHUP reload starts, `ready` again once it is done or refused.
+pub fn reloading() {
+    let usec = clock_gettime(ClockId::CLOCK_MONOTONIC)
+        .map(|t| Duration::from(t).as_micros())
+        .unwrap_or_default();
+    notify(&format!("RELOADING=1\nMONOTONIC_USEC={}", usec));
+}
+
+/// We are shutting down, sessions may still be draining.
+pub fn stopping() {
+    notify("STOPPING=1");
+}
+
+/// The watchdog place of event loop `slot`, None when systemd has no
+/// watchdog for us.
+pub fn watchdog_slot(slot: usize) -> Option<WatchdogSlot> {
+    let watchdog = NOTIFY.get()?.watchdog.as_ref()?;
+    Some(WatchdogSlot {
+        slot,
+        interval: watchdog.interval,
+    })
+}
+
+/// One event loop's place in the watchdog rounds. Dropping it, when the
+/// loop exits, takes the loop out of the rounds.
+pub struct WatchdogSlot {
+    slot: usize,
+    pub interval: Duration,
+}
+
+impl WatchdogSlot {
+    /// Checks the loop in; the last one in a round sends the ping itself,
+    /// so it only ever comes from a loop that is turning.
+    pub fn alive(&self) {
+        self.mark(Slot::Seen);
+    }
+
+    fn mark(&self, state: Slot) {
+        let Some(watchdog) = NOTIFY.get().and_then(|n| n.watchdog.as_ref()) else {
+            return;
+        };
+        let mut loops = watchdog.loops.lock().unwrap();
+        if loops[self.slot] != Slot::Gone {
+            loops[self.slot] = state;
+        }
+        if loops.iter().all(|s| *s != Slot::Pending) && loops.contains(&Slot::Seen) {
+            notify("WATCHDOG=1");
+            for s in loops.iter_mut().filter(|s| **s == Slot::Seen) {
+                *s = Slot::Pending;
+            }
+        }
+    }
+}
+
+impl Drop for WatchdogSlot {
+    fn drop(&mut self) {
+        self.mark(Slot::Gone);
+    }
+}
+
+fn notify(state: &str) {
+    let Some((socket, addr)) = NOTIFY.get().and_then(|n| n.socket.as_ref()) else {
+        return;
+    };
+    match socket.send_to(state.as_bytes(), addr) {
+        Ok(_) => debug!("systemd: {}", state.replace('\n', " ")),
+        Err(e) => warn!("systemd: notify {:?} err {}", state, e),
+    }
+}
+
+/// A datagram socket for `path`; a leading `@` names an abstract socket.
+fn connect(path: &Path) -> std::io::Result<(Socket, SockAddr)> {
+    let addr = match path.to_str().and_then(|p| p.strip_prefix('@')) {
+        Some(name) => SockAddr::unix(format!("\0{}", name))?,
+        None => SockAddr::unix(path)?,
+    };
+    let socket = Socket::new(Domain::UNIX, Type::DGRAM.cloexec(), None)?;
+    Ok((socket, addr))
+}
+
+/// Check-in interval for `WATCHDOG_USEC`. A third of the timeout, as a
+/// round can take up to two intervals when the loops check in out of step.
+/// The watchdog is ours when `WATCHDOG_PID` is unset or names us, or our
+/// parent if we are its upgrade successor.
+fn watchdog_interval(successor: bool) -> Option<Duration> {
+    let usec: u64 = env::var(WATCHDOG_USEC_ENV).ok()?.parse().ok().filter(|&u| u > 0)?;
+    if let Some(pid) = env::var(WATCHDOG_PID_ENV).ok().and_then(|p| p.parse::<i32>().ok()) {
+        let ours = pid == getpid().as_raw() || (successor && pid == getppid().as_raw());
+        if !ours {
+            return None;
+        }
+    }
+    Some(Duration::from_micros(usec) / 3)
+}
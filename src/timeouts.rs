@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::{host_pattern::HostPattern, ser};
+
+/// `Config::timeouts`: the global values and the `[[timeouts.override]]`
+/// entries, in file order.
+#[derive(Debug, Clone, Serialize)]
+pub struct Timeouts {
+    /// close a session after this long without bytes moving
+    #[serde(serialize_with = "ser::duration")]
+    pub idle: Duration,
+    /// close an established session this long after it was, however busy;
+    /// None for no cap
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "ser::duration_opt")]
+    pub lifetime: Option<Duration>,
+    #[serde(rename = "override", skip_serializing_if = "Vec::is_empty")]
+    pub overrides: Vec<TimeoutOverride>,
+}
+
+/// Timeouts for destinations matching any of `hosts`; unset ones keep
+/// the global value.
+#[derive(Debug, Clone, Serialize)]
+pub struct TimeoutOverride {
+    pub hosts: Vec<HostPattern>,
+    #[serde(serialize_with = "ser::duration_opt")]
+    pub idle: Option<Duration>,
+}
+
+/// What one session runs with, fixed when its CONNECT is resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionTimeouts {
+    pub idle: Duration,
+    pub lifetime: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            idle: Duration::from_secs(300),
+            lifetime: None,
+            overrides: Vec::new(),
+        }
+    }
+}
+
+impl Timeouts {
+    /// Adds what is wrong to `errors`.
+    pub fn validate(&self, errors: &mut Vec<String>) {
+        if self.idle.is_zero() {
+            errors.push("idle timeout must be above zero".to_owned());
+        }
+        if self.lifetime.is_some_and(|d| d.is_zero()) {
+            errors.push("session lifetime must be above zero, \"none\" for no cap".to_owned());
+        }
+        validate_overrides(&self.overrides, errors);
+    }
+}
+
+/// The first of `overrides` matching `host:port` applied over `idle`;
+/// with no destination yet just `idle`. The lifetime is not overridden
+/// by destination.
+pub fn resolve(
+    idle: Duration,
+    lifetime: Option<Duration>,
+    overrides: &[TimeoutOverride],
+    destination: Option<(&str, u16)>,
+) -> SessionTimeouts {
+    let mut timeouts = SessionTimeouts { idle, lifetime };
+    let Some((host, port)) = destination else {
+        return timeouts;
+    };
+    if let Some(o) = overrides
+        .iter()
+        .find(|o| o.hosts.iter().any(|p| p.matches(host, port)))
+    {
+        timeouts.idle = o.idle.unwrap_or(idle);
+    }
+    timeouts
+}
+
+pub fn validate_overrides(overrides: &[TimeoutOverride], errors: &mut Vec<String>) {
+    for o in overrides {
+        let hosts = o.hosts.iter().map(HostPattern::to_string).collect::<Vec<_>>().join(", ");
+        if o.hosts.is_empty() {
+            errors.push("a timeouts override needs at least one host pattern".to_owned());
+        } else if o.idle.is_none() {
+            errors.push(format!("timeouts override for {} sets no timeout", hosts));
+        } else if o.idle.is_some_and(|d| d.is_zero()) {
+            errors.push(format!("idle timeout for {} must be above zero", hosts));
+        }
+    }
+}
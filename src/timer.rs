@@ -0,0 +1,197 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    time::{Duration, Instant},
+};
+
+use mio::Token;
+
+pub type TimerId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerKind {
+    Idle,
+    /// check in with `systemd::WatchdogSlot`
+    Watchdog,
+    /// `WorkerStats::ticked`, for `/healthz`
+    Heartbeat,
+    /// registers the listener of the token again, paused by
+    /// `Config::accept_rate`
+    AcceptResume,
+    /// pumps the session of the token again, its acl entry's `limit`
+    /// having bytes for it
+    Throttle,
+    /// closes the session of the token once `Session::lifetime_ends`
+    Lifetime,
+}
+
+/// An expired timer handed back to the loop. Timers are never removed from
+/// the heap on cancel; the owner remembers the id it armed and ignores any
+/// fired timer whose id no longer matches (or whose token is gone).
+#[derive(Debug, Clone, Copy)]
+pub struct Timer {
+    pub id: TimerId,
+    pub kind: TimerKind,
+    pub token: Token,
+}
+
+struct Entry {
+    deadline: Instant,
+    timer: Timer,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.deadline
+            .cmp(&other.deadline)
+            .then(self.timer.id.cmp(&other.timer.id))
+    }
+}
+
+pub struct Timers {
+    heap: BinaryHeap<Reverse<Entry>>,
+    next_id: TimerId,
+}
+
+impl Timers {
+    pub fn new() -> Timers {
+        Timers {
+            heap: BinaryHeap::new(),
+            next_id: 1,
+        }
+    }
+
+    pub fn add(&mut self, deadline: Instant, kind: TimerKind, token: Token) -> TimerId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.heap.push(Reverse(Entry {
+            deadline,
+            timer: Timer { id, kind, token },
+        }));
+        id
+    }
+
+    /// Time until the nearest deadline, zero if one already passed.
+    pub fn next_timeout(&self, now: Instant) -> Option<Duration> {
+        self.heap
+            .peek()
+            .map(|Reverse(e)| e.deadline.saturating_duration_since(now))
+    }
+
+    /// Pops the next timer whose deadline is at or before `now`.
+    pub fn pop_expired(&mut self, now: Instant) -> Option<Timer> {
+        match self.heap.peek() {
+            Some(Reverse(e)) if e.deadline <= now => self.heap.pop().map(|Reverse(e)| e.timer),
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn drain(timers: &mut Timers, now: Instant) -> Vec<Timer> {
+        std::iter::from_fn(|| timers.pop_expired(now)).collect()
+    }
+
+    #[test]
+    fn pops_in_deadline_order() {
+        let mut timers = Timers::new();
+        let now = Instant::now();
+        let late = timers.add(now + Duration::from_secs(3), TimerKind::Idle, Token(12));
+        let early = timers.add(now + Duration::from_secs(1), TimerKind::Lifetime, Token(11));
+        let middle = timers.add(now + Duration::from_secs(2), TimerKind::Throttle, Token(13));
+        let ids: Vec<_> = drain(&mut timers, now + Duration::from_secs(5)).iter().map(|t| t.id).collect();
+        assert_eq!(ids, [early, middle, late]);
+        assert_eq!(timers.len(), 0);
+    }
+
+    #[test]
+    fn equal_deadlines_pop_in_arming_order() {
+        let mut timers = Timers::new();
+        let at = Instant::now();
+        let ids: Vec<_> = (0..4).map(|n| timers.add(at, TimerKind::Idle, Token(10 + n))).collect();
+        let popped: Vec<_> = drain(&mut timers, at).iter().map(|t| t.id).collect();
+        assert_eq!(popped, ids);
+    }
+
+    #[test]
+    fn pop_expired_at_the_boundary() {
+        let mut timers = Timers::new();
+        let now = Instant::now();
+        let at = now + Duration::from_millis(100);
+        let id = timers.add(at, TimerKind::Idle, Token(10));
+        assert!(timers.pop_expired(at - Duration::from_nanos(1)).is_none());
+        let timer = timers.pop_expired(at).expect("due exactly at its deadline");
+        assert_eq!((timer.id, timer.kind, timer.token), (id, TimerKind::Idle, Token(10)));
+        assert!(timers.pop_expired(at).is_none());
+    }
+
+    #[test]
+    fn next_timeout_is_the_nearest_deadline() {
+        let mut timers = Timers::new();
+        let now = Instant::now();
+        assert_eq!(timers.next_timeout(now), None);
+        timers.add(now + Duration::from_secs(10), TimerKind::Heartbeat, Token(1));
+        timers.add(now + Duration::from_secs(4), TimerKind::Watchdog, Token(1));
+        assert_eq!(timers.next_timeout(now), Some(Duration::from_secs(4)));
+        // a deadline already passed is a zero timeout, not a negative one
+        assert_eq!(timers.next_timeout(now + Duration::from_secs(6)), Some(Duration::ZERO));
+    }
+
+    /// The owner side of a cancel: a session remembers the id it armed
+    /// last, as `Session::idle_timer` does, and drops every other one.
+    #[test]
+    fn cancelled_and_rearmed_ids_do_not_fire() {
+        let mut timers = Timers::new();
+        let now = Instant::now();
+        let mut armed: HashMap<Token, TimerId> = HashMap::new();
+        // re-armed: the first deadline is stale once the second is set
+        timers.add(now + Duration::from_secs(1), TimerKind::Idle, Token(10));
+        let rearmed = timers.add(now + Duration::from_secs(2), TimerKind::Idle, Token(10));
+        armed.insert(Token(10), rearmed);
+        // cancelled: the session forgot its timer, 0 is never an id
+        timers.add(now + Duration::from_secs(1), TimerKind::Idle, Token(11));
+        armed.insert(Token(11), 0);
+        // closed: the session is gone with its timer outstanding
+        timers.add(now + Duration::from_secs(1), TimerKind::Idle, Token(12));
+        let fired: Vec<_> = drain(&mut timers, now + Duration::from_secs(3))
+            .into_iter()
+            .filter(|t| armed.get(&t.token) == Some(&t.id))
+            .collect();
+        assert_eq!(fired.len(), 1);
+        assert_eq!((fired[0].id, fired[0].token), (rearmed, Token(10)));
+    }
+
+    #[test]
+    fn ids_are_never_reused() {
+        let mut timers = Timers::new();
+        let now = Instant::now();
+        let first = timers.add(now, TimerKind::Idle, Token(10));
+        drain(&mut timers, now);
+        let second = timers.add(now, TimerKind::Idle, Token(10));
+        assert_ne!(first, 0);
+        assert!(second > first);
+    }
+}
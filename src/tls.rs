@@ -0,0 +1,678 @@
+use std::{
+    fmt, fs,
+    io::{self, ErrorKind, Read, Write},
+    mem::MaybeUninit,
+    iter,
+    path::Path,
+    sync::Arc,
+};
+
+use mio::net::TcpStream;
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    server::{
+        danger::{ClientCertVerified, ClientCertVerifier},
+        WebPkiClientVerifier,
+    },
+    CertificateError, ClientConfig, ClientConnection, Connection, DigitallySignedStruct, DistinguishedName,
+    RootCertStore, ServerConfig, ServerConnection, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+
+/// ALPN protocols offered: HTTP/1.1, and h2 first on a listener with
+/// `http2` for the CONNECT tunnels `h2` serves.
+const ALPN: &[&[u8]] = &[b"http/1.1"];
+const ALPN_H2: &[&[u8]] = &[b"h2", b"http/1.1"];
+
+/// Decrypted bytes `TlsStream::peek` holds at most, as much as any caller
+/// peeks for.
+const PEEK_MAX: usize = 16 * 1024;
+
+/// Plaintext one `TlsStream::write` takes at most, a record's worth.
+const WRITE_MAX: usize = 16 * 1024;
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// The certificate and key of a TLS listener, loaded from `tls_cert` and
+/// `tls_key`; the sessions accepted on it share it across the workers.
+pub struct ServerContext(Arc<ServerConfig>);
+
+/// What a TLS listener asks of its clients' certificates, from its
+/// `client_ca`, `require_client_cert` and `client_cert_deny`.
+pub struct ClientAuth<'a> {
+    pub ca: &'a Path,
+    /// refuse a client without a certificate, rather than let it in to
+    /// authenticate some other way
+    pub required: bool,
+    /// SHA-256 fingerprints in hex, colons allowed, of certificates
+    /// refused though they chain to `ca`
+    pub deny: &'a [String],
+}
+
+impl ServerContext {
+    /// Loads a PEM certificate chain, leaf first, and its private key;
+    /// `http2` offers h2 as well, `client` asks clients for certificates
+    /// chaining to its CA. TLS 1.2 and 1.3.
+    pub fn load(cert: &Path, key: &Path, http2: bool, client: Option<&ClientAuth>) -> Result<ServerContext, String> {
+        let chain = certificates(cert)?;
+        let private = PrivateKeyDer::from_pem_file(key).map_err(|e| format!("cannot load {}: {}", key.display(), e))?;
+        let builder = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?;
+        let builder = match client {
+            Some(client) => builder.with_client_cert_verifier(client_verifier(client)?),
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder
+            .with_single_cert(chain, private)
+            .map_err(|e| match e {
+                rustls::Error::InconsistentKeys(_) => format!("{} is not the key of {}", key.display(), cert.display()),
+                e => format!("cannot load {}: {}", key.display(), e),
+            })?;
+        let protocols = if http2 { ALPN_H2 } else { ALPN };
+        config.alpn_protocols = protocols.iter().map(|p| p.to_vec()).collect();
+        Ok(ServerContext(Arc::new(config)))
+    }
+}
+
+/// A `WebPkiClientVerifier` for the CA of `client`, behind its deny list.
+fn client_verifier(client: &ClientAuth) -> Result<Arc<dyn ClientCertVerifier>, String> {
+    let mut roots = RootCertStore::empty();
+    for cert in certificates(client.ca)? {
+        roots.add(cert).map_err(|e| format!("cannot load {}: {}", client.ca.display(), e))?;
+    }
+    let builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider());
+    let builder = if client.required { builder } else { builder.allow_unauthenticated() };
+    let inner = builder.build().map_err(|e| format!("cannot load {}: {}", client.ca.display(), e))?;
+    let deny = client
+        .deny
+        .iter()
+        .map(|hex| fingerprint(hex).ok_or_else(|| format!("{:?} is no SHA-256 fingerprint", hex)))
+        .collect::<Result<_, _>>()?;
+    Ok(Arc::new(DenyList { inner, deny }))
+}
+
+/// The 32 bytes of a SHA-256 fingerprint written in hex, pairs of digits
+/// separated by colons or not.
+fn fingerprint(hex: &str) -> Option<[u8; 32]> {
+    let digits = hex.bytes().filter(|&b| b != b':').collect::<Vec<_>>();
+    if digits.len() != 64 {
+        return None;
+    }
+    let mut bytes = [0u8; 32];
+    for (byte, pair) in bytes.iter_mut().zip(digits.chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Checks client certificates like `inner`, turning away those of `deny`
+/// first as revoked.
+#[derive(Debug)]
+struct DenyList {
+    inner: Arc<dyn ClientCertVerifier>,
+    deny: Vec<[u8; 32]>,
+}
+
+impl ClientCertVerifier for DenyList {
+    fn offer_client_auth(&self) -> bool {
+        self.inner.offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        self.inner.client_auth_mandatory()
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        self.inner.root_hint_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        let print: [u8; 32] = Sha256::digest(end_entity).into();
+        if self.deny.contains(&print) {
+            return Err(rustls::Error::InvalidCertificate(CertificateError::Revoked));
+        }
+        self.inner.verify_client_cert(end_entity, intermediates, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// The audit rule of a handshake that failed over the client's
+/// certificate: none, one that does not chain to `client_ca`, or one of
+/// `client_cert_deny`. None for any other failure.
+pub fn client_cert_refused(e: &io::Error) -> Option<&'static str> {
+    match e.get_ref()?.downcast_ref::<rustls::Error>()? {
+        rustls::Error::NoCertificatesPresented => Some("client_cert:missing"),
+        rustls::Error::InvalidCertificate(CertificateError::Revoked) => Some("client_cert:denied"),
+        rustls::Error::InvalidCertificate(_) => Some("client_cert:untrusted"),
+        _ => None,
+    }
+}
+
+impl fmt::Debug for ServerContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ServerContext")
+    }
+}
+
+/// The certificates a `wrap_tls` route checks its destinations against:
+/// the system's, or those of its `tls_ca`. Shared by every route with
+/// the same ones, across the workers.
+pub struct ClientContext {
+    verified: Arc<ClientConfig>,
+    /// for routes with `tls_verify = false`
+    unverified: Arc<ClientConfig>,
+}
+
+impl ClientContext {
+    /// Loads the PEM certificates of `ca`, or the system's default ones
+    /// when None.
+    pub fn load(ca: Option<&Path>) -> Result<ClientContext, String> {
+        let mut roots = RootCertStore::empty();
+        match ca {
+            Some(path) => {
+                for cert in certificates(path)? {
+                    roots.add(cert).map_err(|e| format!("cannot load {}: {}", path.display(), e))?;
+                }
+            }
+            None => {
+                let found = rustls_native_certs::load_native_certs();
+                let (added, _) = roots.add_parsable_certificates(found.certs);
+                if added == 0 {
+                    let why = found.errors.first().map_or("none found".to_owned(), |e| e.to_string());
+                    return Err(format!("cannot load system certificates: {}", why));
+                }
+            }
+        }
+        let builder = || {
+            ClientConfig::builder_with_provider(provider())
+                .with_safe_default_protocol_versions()
+                .map_err(|e| e.to_string())
+        };
+        let verified = builder()?.with_root_certificates(roots).with_no_client_auth();
+        let unverified = builder()?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerify(provider())))
+            .with_no_client_auth();
+        Ok(ClientContext { verified: Arc::new(verified), unverified: Arc::new(unverified) })
+    }
+}
+
+impl fmt::Debug for ClientContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ClientContext")
+    }
+}
+
+/// The PEM certificates of `path`, at least one.
+fn certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let cannot = |e: &dyn fmt::Display| format!("cannot load {}: {}", path.display(), e);
+    let text = fs::read(path).map_err(|e| cannot(&e))?;
+    let certs = CertificateDer::pem_slice_iter(&text).collect::<Result<Vec<_>, _>>().map_err(|e| cannot(&e))?;
+    if certs.is_empty() {
+        return Err(cannot(&"no certificate in it"));
+    }
+    Ok(certs)
+}
+
+/// Takes any certificate, for `tls_verify = false`; the handshake's
+/// signatures are still checked, so the peer has the certificate's key.
+#[derive(Debug)]
+struct NoVerify(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerify {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// A client connection of a TLS listener, or an upstream one of a
+/// `wrap_tls` route. Until `handshake` is first called it is plain TCP,
+/// so a PROXY protocol header ahead of the handshake is read, or
+/// written, as it is.
+pub struct TlsStream {
+    conn: Connection,
+    sock: TcpStream,
+    started: bool,
+    /// decrypted and taken out of the session by `peek`, not read yet
+    peeked: Vec<u8>,
+    /// plaintext of the last `write` taken into the session whose records
+    /// did not all reach the socket; the write is retried with the same
+    /// bytes first, like an SSL_write wanting to write, and answered with
+    /// this once they did
+    taken: usize,
+}
+
+impl TlsStream {
+    pub fn new(ctx: &ServerContext, sock: TcpStream) -> io::Result<TlsStream> {
+        let conn = ServerConnection::new(Arc::clone(&ctx.0)).map_err(tls_error)?;
+        Ok(TlsStream::with_connection(conn.into(), sock))
+    }
+
+    /// Our end of a connection to a `wrap_tls` destination, `name` sent
+    /// as SNI unless it is an IP address; with `verify` the handshake
+    /// fails unless the certificate chains to `ctx`'s and is for `name`.
+    pub fn connect(ctx: &ClientContext, sock: TcpStream, name: &str, verify: bool) -> io::Result<TlsStream> {
+        let server = ServerName::try_from(name.to_owned())
+            .map_err(|_| io::Error::new(ErrorKind::InvalidInput, format!("{:?}: bad server name", name)))?;
+        let config = if verify { &ctx.verified } else { &ctx.unverified };
+        let conn = ClientConnection::new(Arc::clone(config), server).map_err(tls_error)?;
+        Ok(TlsStream::with_connection(conn.into(), sock))
+    }
+
+    fn with_connection(conn: Connection, sock: TcpStream) -> TlsStream {
+        TlsStream { conn, sock, started: false, peeked: Vec::new(), taken: 0 }
+    }
+
+    pub fn tcp(&self) -> &TcpStream {
+        &self.sock
+    }
+
+    pub fn tcp_mut(&mut self) -> &mut TcpStream {
+        &mut self.sock
+    }
+
+    /// Takes the handshake as far as the socket lets it: Ok once it is
+    /// complete, WouldBlock until then.
+    pub fn handshake(&mut self) -> io::Result<()> {
+        self.started = true;
+        loop {
+            self.flush_records()?;
+            if !self.conn.is_handshaking() {
+                return Ok(());
+            }
+            if self.fill()? == 0 {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "tls handshake cut short"));
+            }
+        }
+    }
+
+    /// Whether the handshake settled on h2 by ALPN.
+    pub fn is_h2(&self) -> bool {
+        self.conn.alpn_protocol() == Some(b"h2")
+    }
+
+    /// Who the peer's certificate says it is, see `identity`; None
+    /// without one.
+    pub fn peer_identity(&self) -> Option<String> {
+        identity(self.conn.peer_certificates()?.first()?)
+    }
+
+    /// Copies the decrypted bytes there are to read into `buf`, as much as
+    /// fits, leaving them to be read; 0 at end of file. Reads the socket
+    /// to WouldBlock, so the next bytes to come fire an edge.
+    pub fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.started {
+            return socket2::SockRef::from(&self.sock).peek(as_uninit(buf));
+        }
+        let want = buf.len().min(PEEK_MAX);
+        let mut chunk = [0u8; 4096];
+        while self.peeked.len() < want {
+            match self.tls_read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => self.peeked.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock && !self.peeked.is_empty() => break,
+                Err(e) => return Err(e),
+            }
+        }
+        let n = self.peeked.len().min(buf.len());
+        buf[..n].copy_from_slice(&self.peeked[..n]);
+        Ok(n)
+    }
+
+    /// Decrypted bytes, reading records off the socket until there are
+    /// some; 0 at end of file, with or without close_notify.
+    fn tls_read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.conn.reader().read(buf) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(0),
+                r => return r,
+            }
+            if self.fill()? == 0 {
+                return Ok(0);
+            }
+            // a key update, say, to answer
+            match self.flush_records() {
+                Err(e) if e.kind() != ErrorKind::WouldBlock => return Err(e),
+                _ => {}
+            }
+        }
+    }
+
+    /// Reads records off the socket into the session and processes them,
+    /// returning the bytes read, 0 at end of file. A bad record gets its
+    /// alert sent, if the socket takes it, and is InvalidData.
+    fn fill(&mut self) -> io::Result<usize> {
+        let n = self.conn.read_tls(&mut self.sock)?;
+        if let Err(e) = self.conn.process_new_packets() {
+            let _ = self.conn.write_tls(&mut self.sock);
+            return Err(tls_error(e));
+        }
+        Ok(n)
+    }
+
+    /// Writes the records the session has queued, WouldBlock while the
+    /// socket takes no more of them.
+    fn flush_records(&mut self) -> io::Result<()> {
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut self.sock)?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.started {
+            return self.sock.read(buf);
+        }
+        if !self.peeked.is_empty() {
+            let n = self.peeked.len().min(buf.len());
+            buf[..n].copy_from_slice(&self.peeked[..n]);
+            self.peeked.drain(..n);
+            return Ok(n);
+        }
+        self.tls_read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.started {
+            return self.sock.write(buf);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.taken == 0 {
+            self.taken = self.conn.writer().write(&buf[..buf.len().min(WRITE_MAX)])?;
+        }
+        self.flush_records()?;
+        Ok(std::mem::take(&mut self.taken))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for TlsStream {
+    fn drop(&mut self) {
+        // close_notify is sent if the socket takes it, the connection
+        // closes either way
+        if self.started {
+            self.conn.send_close_notify();
+            let _ = self.flush_records();
+        }
+    }
+}
+
+fn as_uninit(buf: &mut [u8]) -> &mut [MaybeUninit<u8>] {
+    // SAFETY: same layout, and recv only ever writes into the buffer
+    unsafe { &mut *(buf as *mut [u8] as *mut [MaybeUninit<u8>]) }
+}
+
+fn tls_error(e: rustls::Error) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, e)
+}
+
+/// The common name of a DER certificate's subject, or failing one the
+/// first email address, then DNS name, of its subject alternative names.
+/// Only read from certificates the handshake checked already.
+fn identity(cert: &[u8]) -> Option<String> {
+    let (_, certificate) = elements(cert).next()?;
+    let (_, tbs) = elements(certificate).next()?;
+    let fields = elements(tbs).collect::<Vec<_>>();
+    // the version comes first as [0], unless it is v1
+    let skip = usize::from(fields.first()?.0 == 0xa0);
+    // serial, signature algorithm, issuer, validity, subject
+    let subject = fields.get(skip + 4)?.1;
+    let common_name = elements(subject)
+        .flat_map(|(_, set)| elements(set))
+        .filter_map(|(_, attribute)| {
+            let mut parts = elements(attribute);
+            match (parts.next()?, parts.next()?) {
+                ((0x06, [0x55, 0x04, 0x03]), (_, value)) => String::from_utf8(value.to_vec()).ok(),
+                _ => None,
+            }
+        })
+        .next();
+    common_name.or_else(|| {
+        let (_, extensions) = fields.iter().find(|(tag, _)| *tag == 0xa3)?;
+        let (_, extensions) = elements(extensions).next()?;
+        let names = elements(extensions).find_map(|(_, extension)| {
+            let mut parts = elements(extension);
+            // critical or not, the value is the last part
+            match (parts.next()?, parts.last()?) {
+                ((0x06, [0x55, 0x1d, 0x11]), (0x04, value)) => Some(elements(value).next()?.1),
+                _ => None,
+            }
+        })?;
+        let of = |tag| elements(names).find(|(t, _)| *t == tag).map(|(_, name)| name);
+        // rfc822Name [1], dNSName [2]
+        String::from_utf8(of(0x81).or_else(|| of(0x82))?.to_vec()).ok()
+    })
+}
+
+/// The tag and contents of each DER element in `der`, ending at the first
+/// that does not fit.
+fn elements(mut der: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    iter::from_fn(move || {
+        let (&tag, rest) = der.split_first()?;
+        let (&first, rest) = rest.split_first()?;
+        let (len, rest) = match first {
+            0..=0x7f => (usize::from(first), rest),
+            0x81..=0x84 => {
+                let n = usize::from(first & 0x7f);
+                let len = rest.get(..n)?.iter().fold(0, |len, &b| len << 8 | usize::from(b));
+                (len, &rest[n..])
+            }
+            _ => return None,
+        };
+        let contents = rest.get(..len)?;
+        der = &rest[len..];
+        Some((tag, contents))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, path::PathBuf, thread, time::Duration};
+
+    use super::*;
+
+    /// A self-signed certificate for localhost with its key, PEM files in
+    /// a directory of their own.
+    fn localhost_cert(tag: &str) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("thin_proxy-tls-{}-{}", std::process::id(), tag));
+        fs::create_dir_all(&dir).unwrap();
+        let names = ["localhost".to_owned()];
+        let rcgen::CertifiedKey { cert, signing_key } = rcgen::generate_simple_self_signed(names).unwrap();
+        let (cert_file, key_file) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::write(&cert_file, cert.pem()).unwrap();
+        fs::write(&key_file, signing_key.serialize_pem()).unwrap();
+        (cert_file, key_file)
+    }
+
+    /// Both ends of a loopback connection, handshaken: ours as the server
+    /// with `cert`, the client checking it against itself.
+    fn pair(cert: &Path, key: &Path) -> (TlsStream, TlsStream) {
+        let server_ctx = ServerContext::load(cert, key, false, None).unwrap();
+        let client_ctx = ClientContext::load(Some(cert)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        accepted.set_nonblocking(true).unwrap();
+        let mut server = TlsStream::new(&server_ctx, TcpStream::from_std(accepted)).unwrap();
+        let mut client = TlsStream::connect(&client_ctx, client, "localhost", true).unwrap();
+        let (mut server_done, mut client_done) = (false, false);
+        for _ in 0..1000 {
+            server_done = server_done || done(server.handshake());
+            client_done = client_done || done(client.handshake());
+            if server_done && client_done {
+                return (server, client);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        panic!("handshake did not finish");
+    }
+
+    fn done(r: io::Result<()>) -> bool {
+        match r {
+            Ok(()) => true,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => false,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn the_key_has_to_be_the_certificates() {
+        let (cert, _) = localhost_cert("mismatch-a");
+        let (_, other_key) = localhost_cert("mismatch-b");
+        let e = ServerContext::load(&cert, &other_key, false, None).unwrap_err();
+        assert!(e.contains("is not the key of"), "{}", e);
+        assert!(ServerContext::load(&cert, &cert, false, None).unwrap_err().contains("cannot load"));
+    }
+
+    #[test]
+    fn a_write_the_socket_cut_short_is_retried_with_its_bytes() {
+        let (cert, key) = localhost_cert("retry");
+        let (mut server, mut client) = pair(&cert, &key);
+        socket2::SockRef::from(client.tcp()).set_send_buffer_size(4096).unwrap();
+        // the client writes until the socket is full, the server not reading
+        let data: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let (mut sent, mut received) = (0, Vec::new());
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut blocked = 0;
+        while received.len() < data.len() {
+            match client.write(&data[sent..]) {
+                Ok(n) => sent += n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => blocked += 1,
+                Err(e) => panic!("{}", e),
+            }
+            // now and then the server catches up
+            if blocked % 4 == 3 || sent == data.len() {
+                loop {
+                    match server.read(&mut buf) {
+                        Ok(0) => panic!("eof"),
+                        Ok(n) => received.extend_from_slice(&buf[..n]),
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                        Err(e) => panic!("{}", e),
+                    }
+                }
+            }
+            assert!(received.len() <= sent, "more came than was said to be written");
+        }
+        assert!(blocked > 0, "the socket never filled");
+        assert_eq!(sent, data.len());
+        assert!(received == data, "the bytes arrived out of order or twice");
+    }
+
+    #[test]
+    fn close_notify_and_a_bare_hang_up_are_both_an_end_of_file() {
+        let (cert, key) = localhost_cert("eof");
+        let (mut server, client) = pair(&cert, &key);
+        drop(client);
+        let mut buf = [0u8; 16];
+        for _ in 0..1000 {
+            match server.read(&mut buf) {
+                Ok(n) => return assert_eq!(n, 0),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(1)),
+                Err(e) => panic!("{}", e),
+            }
+        }
+        panic!("no end of file");
+    }
+
+    #[test]
+    fn the_identity_is_the_common_name_or_else_an_alternative_name() {
+        let cert = |cn: Option<&str>, sans: Vec<rcgen::SanType>| {
+            let mut params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.distinguished_name = rcgen::DistinguishedName::new();
+            if let Some(cn) = cn {
+                params.distinguished_name.push(rcgen::DnType::OrganizationName, "thin_proxy");
+                params.distinguished_name.push(rcgen::DnType::CommonName, cn);
+            }
+            params.subject_alt_names = sans;
+            let key = rcgen::KeyPair::generate().unwrap();
+            params.self_signed(&key).unwrap().der().to_vec()
+        };
+        let dns = |name: &str| rcgen::SanType::DnsName(name.try_into().unwrap());
+        let email = |name: &str| rcgen::SanType::Rfc822Name(name.try_into().unwrap());
+        assert_eq!(identity(&cert(Some("alice"), vec![dns("alice.example")])).as_deref(), Some("alice"));
+        assert_eq!(identity(&cert(None, vec![dns("bob.example")])).as_deref(), Some("bob.example"));
+        let both = cert(None, vec![dns("carol.example"), email("carol@example.com")]);
+        assert_eq!(identity(&both).as_deref(), Some("carol@example.com"));
+        assert_eq!(identity(&cert(None, vec![])), None);
+        assert_eq!(identity(&both[..both.len() / 2]), None);
+    }
+
+    #[test]
+    fn fingerprints_are_hex_with_or_without_colons() {
+        let bare = "3a".repeat(32);
+        assert_eq!(fingerprint(&bare), Some([0x3a; 32]));
+        assert_eq!(fingerprint(&["3A"; 32].join(":")), Some([0x3a; 32]));
+        assert_eq!(fingerprint(&bare[2..]), None);
+        assert_eq!(fingerprint(&"zz".repeat(32)), None);
+    }
+}
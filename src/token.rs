@@ -0,0 +1,125 @@
+use std::os::fd::RawFd;
+
+use mio::Token;
+
+/// How the loops carve up the token space. Session sockets use their fd as
+/// token, which the kernel keeps unique among open descriptors; control
+/// sockets take fixed tokens at the very top of the range where no fd can
+/// reach.
+pub struct TokenSpace;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    /// listener number n
+    Listener(usize),
+    /// admin client connection in slot n
+    AdminConn(usize),
+    Waker,
+    Admin,
+    Signals,
+    /// the relay socket of a SOCKS5 UDP association, carrying its fd
+    Udp(usize),
+    /// an HTTP/2 client connection or our end of one of its streams,
+    /// carrying the fd
+    Http2(usize),
+    /// a session socket, carrying its fd
+    Session(usize),
+}
+
+impl TokenSpace {
+    pub const WAKER: Token = Token(usize::MAX);
+    pub const ADMIN: Token = Token(usize::MAX - 1);
+    pub const SIGNALS: Token = Token(usize::MAX - 2);
+    pub const MAX_LISTENERS: usize = 64;
+    const LISTENER_BASE: usize = usize::MAX - 2 - Self::MAX_LISTENERS;
+    pub const MAX_ADMIN_CONNS: usize = 16;
+    const ADMIN_CONN_BASE: usize = Self::LISTENER_BASE - Self::MAX_ADMIN_CONNS;
+    /// far above any fd, and as far below the control tokens
+    const UDP_BASE: usize = Self::ADMIN_CONN_BASE / 2;
+    const HTTP2_BASE: usize = Self::UDP_BASE / 2;
+
+    pub fn session(fd: RawFd) -> Token {
+        Token(fd as usize)
+    }
+
+    pub fn udp(fd: RawFd) -> Token {
+        Token(Self::UDP_BASE + fd as usize)
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn http2(fd: RawFd) -> Token {
+        Token(Self::HTTP2_BASE + fd as usize)
+    }
+
+    pub fn listener(n: usize) -> Token {
+        debug_assert!(n < Self::MAX_LISTENERS);
+        Token(Self::LISTENER_BASE + n)
+    }
+
+    pub fn admin_conn(slot: usize) -> Token {
+        debug_assert!(slot < Self::MAX_ADMIN_CONNS);
+        Token(Self::ADMIN_CONN_BASE + slot)
+    }
+
+    pub fn classify(token: Token) -> TokenKind {
+        match token {
+            Self::WAKER => TokenKind::Waker,
+            Self::ADMIN => TokenKind::Admin,
+            Self::SIGNALS => TokenKind::Signals,
+            Token(t) if t >= Self::LISTENER_BASE => TokenKind::Listener(t - Self::LISTENER_BASE),
+            Token(t) if t >= Self::ADMIN_CONN_BASE => TokenKind::AdminConn(t - Self::ADMIN_CONN_BASE),
+            Token(t) if t >= Self::UDP_BASE => TokenKind::Udp(t - Self::UDP_BASE),
+            Token(t) if t >= Self::HTTP2_BASE => TokenKind::Http2(t - Self::HTTP2_BASE),
+            Token(t) => TokenKind::Session(t),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserved_tokens() {
+        assert_eq!(TokenSpace::classify(TokenSpace::WAKER), TokenKind::Waker);
+        assert_eq!(TokenSpace::classify(TokenSpace::ADMIN), TokenKind::Admin);
+        assert_eq!(TokenSpace::classify(TokenSpace::SIGNALS), TokenKind::Signals);
+    }
+
+    #[test]
+    fn listener_range() {
+        let last = TokenSpace::MAX_LISTENERS - 1;
+        assert_eq!(TokenSpace::classify(TokenSpace::listener(0)), TokenKind::Listener(0));
+        assert_eq!(TokenSpace::classify(TokenSpace::listener(last)), TokenKind::Listener(last));
+        // the last listener sits right below the control tokens
+        assert_eq!(TokenSpace::listener(last).0 + 1, TokenSpace::SIGNALS.0);
+    }
+
+    #[test]
+    fn admin_conn_range() {
+        let last = TokenSpace::MAX_ADMIN_CONNS - 1;
+        assert_eq!(TokenSpace::classify(TokenSpace::admin_conn(0)), TokenKind::AdminConn(0));
+        assert_eq!(TokenSpace::classify(TokenSpace::admin_conn(last)), TokenKind::AdminConn(last));
+        assert_eq!(TokenSpace::admin_conn(last).0 + 1, TokenSpace::listener(0).0);
+    }
+
+    #[test]
+    fn udp_and_http2_ranges() {
+        assert_eq!(TokenSpace::classify(TokenSpace::udp(0)), TokenKind::Udp(0));
+        assert_eq!(TokenSpace::classify(TokenSpace::udp(1 << 20)), TokenKind::Udp(1 << 20));
+        let (udp, http2) = (TokenSpace::UDP_BASE, TokenSpace::HTTP2_BASE);
+        let last_udp = TokenSpace::ADMIN_CONN_BASE - 1;
+        assert_eq!(TokenSpace::classify(Token(last_udp)), TokenKind::Udp(last_udp - udp));
+        assert_eq!(TokenSpace::classify(Token(http2)), TokenKind::Http2(0));
+        assert_eq!(TokenSpace::classify(Token(udp - 1)), TokenKind::Http2(udp - 1 - http2));
+    }
+
+    #[test]
+    fn first_and_last_session_token() {
+        assert_eq!(TokenSpace::classify(TokenSpace::session(0)), TokenKind::Session(0));
+        let fd = RawFd::MAX;
+        assert_eq!(TokenSpace::classify(TokenSpace::session(fd)), TokenKind::Session(fd as usize));
+        let last = TokenSpace::HTTP2_BASE - 1;
+        assert_eq!(TokenSpace::classify(Token(last)), TokenKind::Session(last));
+    }
+}
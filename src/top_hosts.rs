@@ -0,0 +1,109 @@
+use std::{collections::HashMap, fmt::Write as _, sync::Arc};
+
+use crate::{admin::json_str, stats::WorkerStats};
+
+/// Destinations a worker keeps apart; past it the one with the fewest
+/// bytes is folded into `other` to make room.
+pub const MAX_HOSTS: usize = 1024;
+
+/// Traffic of the sessions to one destination host, counted as each
+/// session closes.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HostTraffic {
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub sessions: u64,
+    /// sessions whose destination was never reached
+    pub failures: u64,
+}
+
+impl HostTraffic {
+    pub fn bytes(&self) -> u64 {
+        self.bytes_up + self.bytes_down
+    }
+
+    fn add(&mut self, other: &HostTraffic) {
+        self.bytes_up += other.bytes_up;
+        self.bytes_down += other.bytes_down;
+        self.sessions += other.sessions;
+        self.failures += other.failures;
+    }
+}
+
+/// One worker's per-host totals, behind a mutex in `WorkerStats` that
+/// only the owning worker's session close and the readers take.
+#[derive(Debug, Default)]
+pub struct HostTable {
+    hosts: HashMap<String, HostTraffic>,
+    /// hosts evicted to stay within `MAX_HOSTS`
+    other: HostTraffic,
+}
+
+impl HostTable {
+    pub fn record(&mut self, host: &str, traffic: HostTraffic) {
+        if let Some(t) = self.hosts.get_mut(host) {
+            t.add(&traffic);
+            return;
+        }
+        if self.hosts.len() >= MAX_HOSTS {
+            let smallest = self
+                .hosts
+                .iter()
+                .min_by_key(|(_, t)| t.bytes())
+                .map(|(h, _)| h.clone());
+            if let Some(t) = smallest.and_then(|h| self.hosts.remove(&h)) {
+                self.other.add(&t);
+            }
+        }
+        self.hosts.insert(host.to_owned(), traffic);
+    }
+}
+
+/// The workers' tables merged: the `n` hosts with the most bytes, largest
+/// first, and everything else summed.
+pub fn top(stats: &[Arc<WorkerStats>], n: usize) -> (Vec<(String, HostTraffic)>, HostTraffic) {
+    let mut merged: HashMap<String, HostTraffic> = HashMap::new();
+    let mut other = HostTraffic::default();
+    for s in stats {
+        let table = s.hosts.lock().unwrap();
+        for (host, t) in &table.hosts {
+            merged.entry(host.clone()).or_default().add(t);
+        }
+        other.add(&table.other);
+    }
+    let mut hosts = merged.into_iter().collect::<Vec<_>>();
+    hosts.sort_by(|a, b| b.1.bytes().cmp(&a.1.bytes()).then_with(|| a.0.cmp(&b.0)));
+    for (_, t) in hosts.iter().skip(n) {
+        other.add(t);
+    }
+    hosts.truncate(n);
+    (hosts, other)
+}
+
+/// The `/top-hosts` body.
+pub fn json(hosts: &[(String, HostTraffic)], other: &HostTraffic) -> String {
+    let entry = |t: &HostTraffic| {
+        format!(
+            r#""bytes_up":{},"bytes_down":{},"sessions":{},"failures":{}"#,
+            t.bytes_up, t.bytes_down, t.sessions, t.failures
+        )
+    };
+    let mut out = String::from(r#"{"hosts":["#);
+    for (i, (host, t)) in hosts.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, r#"{{"host":{},{}}}"#, json_str(host), entry(t));
+    }
+    let _ = write!(out, r#"],"other":{{{}}}}}"#, entry(other));
+    out
+}
+
+/// `host up/down sessions/failures` for the periodic summary line.
+pub fn summary(hosts: &[(String, HostTraffic)]) -> String {
+    hosts
+        .iter()
+        .map(|(host, t)| format!("{} {}/{} {}/{}", host, t.bytes_up, t.bytes_down, t.sessions, t.failures))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,168 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    os::fd::AsRawFd,
+};
+
+use mio::net::TcpStream;
+use nix::libc;
+use socket2::{Domain, Socket, Type};
+
+/// How a transparent listener learns where its connections were headed,
+/// see `Config::listener_transparent` and `Config::listener_tproxy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transparent {
+    /// an iptables REDIRECT rewrote the destination to the listener,
+    /// SO_ORIGINAL_DST remembers it
+    Redirect,
+    /// a TPROXY rule handed the connection over untouched, its local
+    /// address is the destination; `spoof_source` dials the upstream from
+    /// the client's address
+    Tproxy { spoof_source: bool },
+}
+
+/// Sets IP_TRANSPARENT, or IPV6_TRANSPARENT on an IPv6 socket: a listener
+/// with it takes connections to any address TPROXY sends it, an upstream
+/// socket can bind to an address that is not ours. Needs CAP_NET_ADMIN.
+pub fn set_ip_transparent(sock: &impl AsRawFd, v6: bool) -> io::Result<()> {
+    let (level, name) = match v6 {
+        true => (libc::SOL_IPV6, libc::IPV6_TRANSPARENT),
+        false => (libc::SOL_IP, libc::IP_TRANSPARENT),
+    };
+    let on: libc::c_int = 1;
+    // SAFETY: valid fd for the lifetime of `sock`, the option value is a
+    // c_int living on the stack for the duration of the call
+    let r = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            level,
+            name,
+            &on as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if r != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Starts a non-blocking connect to `to` from `source`, an address of the
+/// client's the upstream answers to; policy routing outside the proxy has
+/// to bring those answers back to this host.
+pub fn connect_from(source: IpAddr, to: SocketAddr) -> io::Result<TcpStream> {
+    let socket = Socket::new(Domain::for_address(to), Type::STREAM, None)?;
+    socket.set_nonblocking(true)?;
+    set_ip_transparent(&socket, to.is_ipv6())?;
+    socket.bind(&SocketAddr::new(source, 0).into())?;
+    match socket.connect(&to.into()) {
+        Err(e) if e.raw_os_error() != Some(libc::EINPROGRESS) => return Err(e),
+        _ => {}
+    }
+    Ok(TcpStream::from_std(socket.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{Ipv4Addr, TcpListener},
+        process::Command,
+        thread,
+        time::Duration,
+    };
+
+    use nix::sched::{unshare, CloneFlags};
+
+    use super::*;
+    use crate::client::ClientStream;
+
+    /// Runs `test` on a thread of its own in a new network namespace,
+    /// loopback up and 198.51.100.0/24 routed to it as local: addresses
+    /// of no interface that TPROXY'd connections would have. False when
+    /// namespaces are out of reach, no root.
+    fn in_netns(test: impl FnOnce() + Send + 'static) -> bool {
+        thread::spawn(move || {
+            if unshare(CloneFlags::CLONE_NEWNET).is_err() {
+                return false;
+            }
+            // children forked now share the thread's namespace
+            let ip = |args: &[&str]| Command::new("ip").args(args).status().is_ok_and(|s| s.success());
+            assert!(ip(&["link", "set", "lo", "up"]));
+            assert!(ip(&["route", "add", "local", "198.51.100.0/24", "dev", "lo"]));
+            test();
+            true
+        })
+        .join()
+        .unwrap()
+    }
+
+    fn bind_to(addr: SocketAddr, transparent: bool) -> io::Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        if transparent {
+            set_ip_transparent(&socket, addr.is_ipv6())?;
+        }
+        socket.bind(&addr.into())?;
+        socket.listen(8)?;
+        Ok(socket.into())
+    }
+
+    #[test]
+    fn the_local_address_is_the_destination() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let listen = listener.local_addr().unwrap();
+        let _client = std::net::TcpStream::connect(listen).unwrap();
+        let accepted = ClientStream::Tcp(TcpStream::from_std(listener.accept().unwrap().0));
+        // one that came for the listener itself, by its address or a wildcard
+        assert_eq!(accepted.tproxy_dst(listen).unwrap_err().kind(), io::ErrorKind::NotFound);
+        let wildcard = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), listen.port());
+        assert_eq!(accepted.tproxy_dst(wildcard).unwrap_err().kind(), io::ErrorKind::NotFound);
+        // TPROXY hands over connections for other ports and addresses
+        let elsewhere = SocketAddr::new(listen.ip(), listen.port().wrapping_add(1));
+        assert_eq!(accepted.tproxy_dst(elsewhere).unwrap(), listen);
+        let other_ip = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 2).into(), listen.port());
+        assert_eq!(accepted.tproxy_dst(other_ip).unwrap(), listen);
+    }
+
+    #[test]
+    fn ip_transparent_binds_what_is_not_ours() {
+        let ran = in_netns(|| {
+            // off the lo route: no interface has it
+            let foreign: SocketAddr = "203.0.113.7:443".parse().unwrap();
+            assert_eq!(bind_to(foreign, false).unwrap_err().raw_os_error(), Some(libc::EADDRNOTAVAIL));
+            bind_to(foreign, true).unwrap();
+        });
+        if !ran {
+            eprintln!("no network namespace, skipped");
+        }
+    }
+
+    #[test]
+    fn connect_from_dials_with_the_clients_address() {
+        let ran = in_netns(|| {
+            let origin = TcpListener::bind("198.51.100.1:0").unwrap();
+            let client: IpAddr = "198.51.100.9".parse().unwrap();
+            let mut up = connect_from(client, origin.local_addr().unwrap()).unwrap();
+            let (mut sock, peer) = origin.accept().unwrap();
+            // the origin sees the client, not us
+            assert_eq!(peer.ip(), client);
+            sock.write_all(b"hello").unwrap();
+            let mut buf = [0u8; 5];
+            let mut got = 0;
+            for _ in 0..500 {
+                match up.read(&mut buf[got..]) {
+                    Ok(n) => got += n,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(10)),
+                    Err(e) => panic!("{}", e),
+                }
+                if got == buf.len() {
+                    break;
+                }
+            }
+            assert_eq!(&buf, b"hello");
+        });
+        if !ran {
+            eprintln!("no network namespace, skipped");
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::fd::AsRawFd,
+    time::{Duration, Instant},
+};
+
+use mio::{net::UdpSocket, Token};
+
+use crate::token::TokenSpace;
+
+/// Room for the largest datagram, header included.
+pub const MAX_DATAGRAM: usize = 65_535;
+
+/// Destinations one association keeps a decision for; a datagram to
+/// another one past it is dropped until one expires.
+const MAX_TARGETS: usize = 1024;
+
+/// How long the decision on a destination stands before the acl is asked
+/// again, as its windows open and close on the minute.
+const DECISION_TTL: Duration = Duration::from_secs(60);
+
+/// The relay of a SOCKS5 UDP ASSOCIATE: one socket the client sends its
+/// datagrams to and their destinations answer on, and the NAT-style state
+/// that tells which answers go back to the client. It lives as long as
+/// the client's TCP connection.
+pub struct Association {
+    pub socket: UdpSocket,
+    /// the socket is IPv6, dual-stack
+    v6: bool,
+    /// the TCP client's address, the only one datagrams to relay come from
+    client_ip: IpAddr,
+    /// the port the client sends from, named by its request or else taken
+    /// from its first datagram
+    client_port: Option<u16>,
+    /// by the `host:port` the client named: where its datagrams go, None
+    /// when policy drops them, and when that was decided
+    targets: HashMap<(String, u16), (Option<SocketAddr>, Instant)>,
+    /// the destination addresses datagrams went to and when last; only
+    /// theirs are relayed back, until they were idle for `idle`
+    peers: HashMap<SocketAddr, Instant>,
+    pruned: Instant,
+    /// datagrams not relayed: fragmented, malformed, refused, or from
+    /// neither the client nor a peer
+    pub dropped: u64,
+}
+
+impl Association {
+    /// Binds the relay socket for `client`, on every address: dual-stack
+    /// when the host has IPv6, to reach destinations of both families. A
+    /// `port` of 0 means the client did not say where it sends from.
+    pub fn bind(client: SocketAddr, port: u16) -> io::Result<Association> {
+        let socket = UdpSocket::bind(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0))
+            .or_else(|_| UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)))?;
+        Ok(Association {
+            v6: socket.local_addr()?.is_ipv6(),
+            socket,
+            client_ip: client.ip(),
+            client_port: (port != 0).then_some(port),
+            targets: HashMap::new(),
+            peers: HashMap::new(),
+            pruned: Instant::now(),
+            dropped: 0,
+        })
+    }
+
+    pub fn token(&self) -> Token {
+        TokenSpace::udp(self.socket.as_raw_fd())
+    }
+
+    /// Whether a datagram from `from` is the client's, pinning its port
+    /// on the first one.
+    pub fn is_client(&mut self, from: SocketAddr) -> bool {
+        if from.ip() != self.client_ip {
+            return false;
+        }
+        *self.client_port.get_or_insert(from.port()) == from.port()
+    }
+
+    pub fn client(&self) -> Option<SocketAddr> {
+        Some(SocketAddr::new(self.client_ip, self.client_port?))
+    }
+
+    /// The decision on `host:port` taken within `DECISION_TTL`, if any.
+    pub fn target(&self, host: &str, port: u16, now: Instant) -> Option<Option<SocketAddr>> {
+        let &(addr, decided) = self.targets.get(&(host.to_owned(), port))?;
+        (now - decided < DECISION_TTL).then_some(addr)
+    }
+
+    /// Keeps the decision on `host:port`, false when there is no room
+    /// for it.
+    pub fn decided(&mut self, host: &str, port: u16, addr: Option<SocketAddr>, now: Instant) -> bool {
+        let key = (host.to_owned(), port);
+        if self.targets.len() >= MAX_TARGETS && !self.targets.contains_key(&key) {
+            return false;
+        }
+        self.targets.insert(key, (addr, now));
+        true
+    }
+
+    /// Records a datagram sent to `peer`, whose answers go to the client
+    /// from now on.
+    pub fn sent(&mut self, peer: SocketAddr, now: Instant) {
+        self.peers.insert(peer, now);
+    }
+
+    /// Whether `from` is a peer the client sent to within `idle`.
+    pub fn is_peer(&self, from: SocketAddr, idle: Duration, now: Instant) -> bool {
+        self.peers.get(&from).is_some_and(|&last| now - last < idle)
+    }
+
+    /// Forgets the peers idle for `idle` and decisions past their time,
+    /// at most once a second.
+    pub fn expire(&mut self, idle: Duration, now: Instant) {
+        if now - self.pruned < Duration::from_secs(1) {
+            return;
+        }
+        self.pruned = now;
+        self.peers.retain(|_, &mut last| now - last < idle);
+        self.targets.retain(|_, &mut (_, decided)| now - decided < DECISION_TTL);
+    }
+
+    /// `to` in the family of the socket: an IPv4 address as IPv6-mapped
+    /// on a dual-stack one.
+    pub fn destination(&self, to: SocketAddr) -> SocketAddr {
+        match to.ip() {
+            IpAddr::V4(ip) if self.v6 => SocketAddr::new(ip.to_ipv6_mapped().into(), to.port()),
+            _ => to,
+        }
+    }
+}
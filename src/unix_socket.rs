@@ -0,0 +1,113 @@
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    os::unix::{
+        fs::{FileTypeExt, MetadataExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+use nix::unistd::{chown, Gid, Uid};
+use socket2::{Domain, SockAddr, Socket, Type};
+
+use crate::{err::Fatal, privileges, upgrade};
+
+/// The filesystem entry of our unix listener, unlinked again on drop
+/// unless a successor took the listener over or another process has put
+/// its own socket there since.
+pub struct SocketPath {
+    path: PathBuf,
+    dev: u64,
+    ino: u64,
+}
+
+impl SocketPath {
+    /// Remembers which file `path` is right now, bound by us or inherited.
+    pub fn new(path: &Path) -> io::Result<SocketPath> {
+        let meta = fs::symlink_metadata(path)?;
+        Ok(SocketPath {
+            path: path.to_owned(),
+            dev: meta.dev(),
+            ino: meta.ino(),
+        })
+    }
+}
+
+impl Drop for SocketPath {
+    fn drop(&mut self) {
+        if upgrade::handed_off() {
+            return;
+        }
+        match fs::symlink_metadata(&self.path) {
+            Ok(m) if (m.dev(), m.ino()) == (self.dev, self.ino) => {
+                if let Err(e) = fs::remove_file(&self.path) {
+                    warn!("remove socket {} err {}", self.path.display(), e);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Binds a unix listener on `path`, replacing a stale socket file a crashed
+/// run left behind. `mode` and `owner` are applied before `listen`, so no
+/// client can connect while the default permissions are in place.
+pub fn bind(path: &Path, mode: Option<u32>, owner: Option<&str>, backlog: u32) -> Result<UnixListener, Fatal> {
+    let fail = |e: io::Error| Fatal::Config(format!("cannot listen on unix:{}: {}", path.display(), e));
+    remove_stale(path)?;
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None).map_err(fail)?;
+    socket.bind(&SockAddr::unix(path).map_err(fail)?).map_err(fail)?;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).map_err(fail)?;
+    }
+    if let Some(owner) = owner {
+        let (uid, gid) = resolve_owner(owner).map_err(Fatal::Config)?;
+        chown(path, uid, gid).map_err(|e| fail(e.into()))?;
+    }
+    socket.listen(backlog as i32).map_err(fail)?;
+    socket.set_nonblocking(true).map_err(fail)?;
+    Ok(socket.into())
+}
+
+/// Unlinks a socket file nobody accepts on. A live one means another
+/// instance is running and is left alone.
+fn remove_stale(path: &Path) -> Result<(), Fatal> {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(m) => m,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(Fatal::Config(format!("cannot inspect {}: {}", path.display(), e))),
+    };
+    if !meta.file_type().is_socket() {
+        return Err(Fatal::Config(format!("{} exists and is not a socket", path.display())));
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(Fatal::Runtime(format!(
+            "cannot listen on unix:{}: another process accepts there, is an instance already running?",
+            path.display()
+        )));
+    }
+    info!("removing stale socket {}", path.display());
+    fs::remove_file(path).map_err(|e| Fatal::Config(format!("cannot remove stale {}: {}", path.display(), e)))
+}
+
+/// Resolves `user`, `user:group` or `:group`, as names or numeric ids.
+pub fn resolve_owner(owner: &str) -> Result<(Option<Uid>, Option<Gid>), String> {
+    let (user, group) = match owner.split_once(':') {
+        Some((user, group)) => (user, group),
+        None => (owner, ""),
+    };
+    let uid = match user {
+        "" => None,
+        u => Some(privileges::lookup_user(u)?.uid),
+    };
+    let gid = match group {
+        "" => None,
+        g => Some(privileges::lookup_group(g)?),
+    };
+    if uid.is_none() && gid.is_none() {
+        return Err(format!("owner {:?} names neither a user nor a group", owner));
+    }
+    Ok((uid, gid))
+}
@@ -0,0 +1,117 @@
+use std::{
+    env, io,
+    os::fd::{FromRawFd, RawFd},
+    process::{Child, Command},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+use log::{info, warn};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use socket2::Socket;
+
+/// Comma separated listener fds a successor inherits from its parent.
+pub const LISTEN_FDS_ENV: &str = "THIN_PROXY_LISTEN_FDS";
+
+/// How long a successor has to survive before the parent hands over.
+const GRACE: Duration = Duration::from_secs(1);
+
+/// Set once a successor owns the listeners.
+static HANDED_OFF: AtomicBool = AtomicBool::new(false);
+
+/// Takes over the listeners passed down by a parent process, in order,
+/// TCP and unix alike; the caller tells them apart by local address.
+/// Returns an empty list when not started by an upgrade.
+pub fn inherited_listeners() -> Vec<Socket> {
+    let fds = match env::var(LISTEN_FDS_ENV) {
+        Ok(v) => v,
+        Err(_) => return Vec::new(),
+    };
+    // must not leak into our own successor or any other child
+    env::remove_var(LISTEN_FDS_ENV);
+
+    let mut listeners = Vec::new();
+    for fd in fds.split(',').filter(|s| !s.is_empty()) {
+        match fd.parse::<RawFd>() {
+            Ok(fd) => {
+                if let Err(e) = set_cloexec(fd, true) {
+                    warn!("inherited fd {} unusable: {}", fd, e);
+                    continue;
+                }
+                // SAFETY: the parent passed this fd to us for exactly this
+                // purpose and nothing else in this process owns it
+                let socket = unsafe { Socket::from_raw_fd(fd) };
+                if let Err(e) = socket.set_nonblocking(true) {
+                    warn!("inherited fd {} unusable: {}", fd, e);
+                    continue;
+                }
+                info!("inherited listener fd {} {}", fd, describe(&socket));
+                listeners.push(socket);
+            }
+            Err(_) => warn!("ignore bad {} entry {:?}", LISTEN_FDS_ENV, fd),
+        }
+    }
+    listeners
+}
+
+/// Re-executes the current binary with the same arguments, passing it
+/// `fds`. Returns the child once it survived `GRACE`; a successor that
+/// died by then is reported as an error and nothing is handed over.
+pub fn spawn_successor(fds: &[RawFd]) -> io::Result<Child> {
+    let exe = env::current_exe()?;
+    let list = fds.iter().map(|fd| fd.to_string()).collect::<Vec<_>>().join(",");
+
+    // only the successor may inherit the listeners, clear CLOEXEC just
+    // around the spawn
+    for &fd in fds {
+        set_cloexec(fd, false)?;
+    }
+    let spawned = Command::new(&exe)
+        .args(env::args_os().skip(1))
+        .env(LISTEN_FDS_ENV, &list)
+        .spawn();
+    for &fd in fds {
+        if let Err(e) = set_cloexec(fd, true) {
+            warn!("restore CLOEXEC on fd {} err {}", fd, e);
+        }
+    }
+
+    let mut child = spawned?;
+    info!("spawned successor {} pid {} with listeners {}", exe.display(), child.id(), list);
+    thread::sleep(GRACE);
+    if let Some(status) = child.try_wait()? {
+        return Err(io::Error::other(format!("successor exited early: {}", status)));
+    }
+    HANDED_OFF.store(true, Ordering::Relaxed);
+    Ok(child)
+}
+
+/// Whether we were started by an upgrade. Such a process is already
+/// detached and must not daemonize again, our parent waits on its pid.
+pub fn is_successor() -> bool {
+    env::var_os(LISTEN_FDS_ENV).is_some()
+}
+
+/// Whether a successor took over, so shared resources such as the unix
+/// socket path are its to clean up now.
+pub fn handed_off() -> bool {
+    HANDED_OFF.load(Ordering::Relaxed)
+}
+
+fn describe(socket: &Socket) -> String {
+    match socket.local_addr() {
+        Ok(a) => match (a.as_socket(), a.as_pathname()) {
+            (Some(addr), _) => addr.to_string(),
+            (None, Some(path)) => format!("unix:{}", path.display()),
+            (None, None) => "unnamed".to_owned(),
+        },
+        Err(e) => e.to_string(),
+    }
+}
+
+fn set_cloexec(fd: RawFd, on: bool) -> io::Result<()> {
+    let flags = if on { FdFlag::FD_CLOEXEC } else { FdFlag::empty() };
+    fcntl(fd, FcntlArg::F_SETFD(flags))?;
+    Ok(())
+}
@@ -0,0 +1,145 @@
+use std::{
+    io::{self, Read, Write},
+    net::SocketAddr,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd},
+};
+
+use mio::{
+    event::Source,
+    net::{TcpStream, UnixStream},
+    Interest, Registry, Token,
+};
+
+#[cfg(feature = "tls")]
+use crate::tls::TlsStream;
+
+/// The upstream side of a session: the destination or the parent proxy
+/// over TCP, or the unix socket a route sends its destinations to. Both
+/// are stream fds the data path splices alike; a destination its route
+/// wraps in TLS is copied through the TLS session.
+pub enum UpStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+}
+
+impl UpStream {
+    /// SO_ERROR, which a connect that failed in the background leaves.
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        match self {
+            UpStream::Tcp(s) => s.take_error(),
+            UpStream::Unix(s) => s.take_error(),
+            #[cfg(feature = "tls")]
+            UpStream::Tls(s) => s.tcp().take_error(),
+        }
+    }
+
+    /// Ok once the connect went through, NotConnected while it is still
+    /// under way.
+    pub fn connected(&self) -> io::Result<()> {
+        match self {
+            UpStream::Tcp(s) => s.peer_addr().map(drop),
+            UpStream::Unix(s) => s.peer_addr().map(drop),
+            #[cfg(feature = "tls")]
+            UpStream::Tls(s) => s.tcp().peer_addr().map(drop),
+        }
+    }
+
+    /// The address we connect from, None over a unix socket.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            UpStream::Tcp(s) => s.local_addr().ok(),
+            UpStream::Unix(_) => None,
+            #[cfg(feature = "tls")]
+            UpStream::Tls(s) => s.tcp().local_addr().ok(),
+        }
+    }
+
+    /// Takes the TLS handshake of a wrapped destination as far as the
+    /// socket lets it, Ok once it is complete or for a plain upstream.
+    pub fn handshake(&mut self) -> io::Result<()> {
+        match self {
+            #[cfg(feature = "tls")]
+            UpStream::Tls(s) => s.handshake(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Read for UpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            UpStream::Tcp(s) => s.read(buf),
+            UpStream::Unix(s) => s.read(buf),
+            #[cfg(feature = "tls")]
+            UpStream::Tls(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for UpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            UpStream::Tcp(s) => s.write(buf),
+            UpStream::Unix(s) => s.write(buf),
+            #[cfg(feature = "tls")]
+            UpStream::Tls(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            UpStream::Tcp(s) => s.flush(),
+            UpStream::Unix(s) => s.flush(),
+            #[cfg(feature = "tls")]
+            UpStream::Tls(s) => s.flush(),
+        }
+    }
+}
+
+impl AsFd for UpStream {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            UpStream::Tcp(s) => s.as_fd(),
+            UpStream::Unix(s) => s.as_fd(),
+            #[cfg(feature = "tls")]
+            UpStream::Tls(s) => s.tcp().as_fd(),
+        }
+    }
+}
+
+impl AsRawFd for UpStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.as_fd().as_raw_fd()
+    }
+}
+
+impl Source for UpStream {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            UpStream::Tcp(s) => s.register(registry, token, interests),
+            UpStream::Unix(s) => s.register(registry, token, interests),
+            #[cfg(feature = "tls")]
+            UpStream::Tls(s) => s.tcp_mut().register(registry, token, interests),
+        }
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            UpStream::Tcp(s) => s.reregister(registry, token, interests),
+            UpStream::Unix(s) => s.reregister(registry, token, interests),
+            #[cfg(feature = "tls")]
+            UpStream::Tls(s) => s.tcp_mut().reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            UpStream::Tcp(s) => s.deregister(registry),
+            UpStream::Unix(s) => s.deregister(registry),
+            #[cfg(feature = "tls")]
+            UpStream::Tls(s) => s.tcp_mut().deregister(registry),
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use std::{
+    fs,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::stats::Summary;
+
+/// Highest `Resources::fds`, `pipes` and `pending_bytes` seen by `sample`.
+static PEAK_FDS: AtomicU64 = AtomicU64::new(0);
+static PEAK_PIPES: AtomicU64 = AtomicU64::new(0);
+static PEAK_PENDING: AtomicU64 = AtomicU64::new(0);
+
+/// What the process holds at one point.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Resources {
+    /// resident set size, 0 where /proc cannot be read
+    pub rss_bytes: u64,
+    /// open file descriptors, 0 where /proc cannot be read
+    pub fds: u64,
+    /// splice pipes of the sessions, each two fds
+    pub pipes: u64,
+    /// bytes read from one side not yet written to the other, in the pipes
+    /// and the buffers of captured sessions
+    pub pending_bytes: u64,
+}
+
+/// `Resources` now and the high-water marks since startup.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Usage {
+    pub now: Resources,
+    pub peak: Resources,
+}
+
+/// Reads RSS and fds from /proc and takes pipes and pending bytes from the
+/// workers' last heartbeat in `s`. Runs on the stats tick and for every
+/// `/metrics`, so the peaks of fds, pipes and pending bytes are the
+/// highest seen then; the RSS peak is the kernel's.
+pub fn sample(s: &Summary) -> Usage {
+    let (rss_bytes, peak_rss) = memory();
+    let now = Resources {
+        rss_bytes,
+        fds: fds(),
+        pipes: s.pipes as u64,
+        pending_bytes: s.pending_bytes,
+    };
+    let peak = |p: &AtomicU64, v: u64| p.fetch_max(v, Ordering::Relaxed).max(v);
+    Usage {
+        now,
+        peak: Resources {
+            rss_bytes: peak_rss.max(rss_bytes),
+            fds: peak(&PEAK_FDS, now.fds),
+            pipes: peak(&PEAK_PIPES, now.pipes),
+            pending_bytes: peak(&PEAK_PENDING, now.pending_bytes),
+        },
+    }
+}
+
+/// VmRSS and VmHWM of /proc/self/status in bytes.
+fn memory() -> (u64, u64) {
+    let Ok(status) = fs::read_to_string("/proc/self/status") else {
+        return (0, 0);
+    };
+    let kb = |key: &str| {
+        status
+            .lines()
+            .find_map(|l| l.strip_prefix(key))
+            .and_then(|v| v.trim().strip_suffix("kB"))
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map_or(0, |kb| kb * 1024)
+    };
+    (kb("VmRSS:"), kb("VmHWM:"))
+}
+
+/// Entries of /proc/self/fd, less the one reading it.
+fn fds() -> u64 {
+    fs::read_dir("/proc/self/fd").map_or(0, |d| d.count().saturating_sub(1) as u64)
+}
+
+/// `bytes` in MiB with one decimal, for the log line.
+pub fn mib(bytes: u64) -> String {
+    format!("{:.1}MiB", bytes as f64 / (1 << 20) as f64)
+}
@@ -0,0 +1,31 @@
+use std::{collections::HashMap, sync::Arc};
+
+use serde::Serialize;
+
+use crate::{acl::AclSet, ser};
+
+/// A `[users.<name>]` table: policy for the clients authenticated as
+/// `name`, see `Config::auth_file`. Users without one get the top-level
+/// settings.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UserPolicy {
+    #[serde(skip)]
+    pub name: String,
+    /// `[acl_set.<name>]` checked instead of `acl` and `acl_default`;
+    /// `allowed_ports` still applies
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acl_set: Option<String>,
+    /// bytes the user may move a day, both directions summed, see
+    /// `quota`
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "ser::display_opt")]
+    pub daily_quota: Option<u64>,
+}
+
+impl UserPolicy {
+    /// Adds what is wrong to `errors`, each prefixed with the user.
+    pub fn validate(&self, acl_sets: &HashMap<String, Arc<AclSet>>, errors: &mut Vec<String>) {
+        if let Some(set) = self.acl_set.as_ref().filter(|s| !acl_sets.contains_key(*s)) {
+            errors.push(format!("user {}: unknown acl set {}", self.name, set));
+        }
+    }
+}
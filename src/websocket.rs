@@ -0,0 +1,796 @@
+use std::io::{self, ErrorKind, Read, Write};
+
+use crate::{
+    auth::base64_decode,
+    client::ClientStream,
+    parent::base64,
+    session::{split_host_port, CloseReason},
+};
+
+/// What RFC 6455 appends to a client's Sec-WebSocket-Key before hashing
+/// it into our Sec-WebSocket-Accept.
+const GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Header names an upgrade request gets read with at most.
+const MAX_HEADERS: usize = 32;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+/// a control frame's payload at most, and theirs are never fragmented
+const CONTROL_MAX: u64 = 125;
+
+/// Close codes of RFC 6455 section 7.4.1.
+pub const NORMAL: u16 = 1000;
+pub const GOING_AWAY: u16 = 1001;
+pub const PROTOCOL_ERROR: u16 = 1002;
+pub const UNSUPPORTED_DATA: u16 = 1003;
+/// never on the wire, what a close frame without a code counts as
+pub const NO_STATUS: u16 = 1005;
+pub const POLICY_VIOLATION: u16 = 1008;
+pub const TOO_BIG: u16 = 1009;
+pub const INTERNAL_ERROR: u16 = 1011;
+pub const TRY_AGAIN_LATER: u16 = 1013;
+
+/// Payload of one binary frame we send, of one write at most.
+const OUT_FRAME: usize = 16 * 1024;
+/// Decoded payload read ahead for `WsStream::read` and `peek`, past
+/// `sni::MAX_HELLO`; the socket is left alone once this much waits.
+const PAYLOAD_MAX: usize = 64 * 1024;
+
+/// A websocket listener's upgrade request, as `upgrade` takes it.
+#[derive(Debug)]
+pub struct Upgrade {
+    /// the destination, from the X-Target header or else the path
+    pub host: String,
+    pub port: u16,
+    /// what our 101 answers the client's key with
+    pub accept: String,
+}
+
+/// Why an upgrade request is not one, and the response it gets.
+#[derive(Debug)]
+pub struct Refused {
+    pub response: &'static [u8],
+    pub why: String,
+}
+
+const BAD_REQUEST: &[u8] = b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+const UPGRADE_REQUIRED: &[u8] =
+    b"HTTP/1.1 426 Upgrade Required\r\nSec-WebSocket-Version: 13\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+/// Reads the complete request head `head` as a websocket upgrade: a GET
+/// with Upgrade: websocket, Connection: upgrade, version 13 and a key,
+/// naming a `host:port` in an X-Target header or as its path, `/host:port`.
+pub fn upgrade(head: &[u8]) -> Result<Upgrade, Refused> {
+    let bad = |why: &str| Refused { response: BAD_REQUEST, why: format!("bad websocket upgrade: {}", why) };
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    match request.parse(head) {
+        Ok(httparse::Status::Complete(_)) => {}
+        Ok(httparse::Status::Partial) => return Err(bad("incomplete head")),
+        Err(e) => return Err(bad(&e.to_string())),
+    }
+    let header = |name: &str| {
+        let h = request.headers.iter().find(|h| h.name.eq_ignore_ascii_case(name))?;
+        std::str::from_utf8(h.value).ok().map(str::trim)
+    };
+    let has_token = |name: &str, token: &str| {
+        header(name).is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    };
+    if request.method != Some("GET") || request.version != Some(1) {
+        return Err(bad("not an HTTP/1.1 GET"));
+    }
+    if !has_token("upgrade", "websocket") || !has_token("connection", "upgrade") {
+        return Err(bad("no Upgrade: websocket"));
+    }
+    if header("sec-websocket-version") != Some("13") {
+        let why = format!("bad websocket upgrade: version {:?}, 13 is the one", header("sec-websocket-version"));
+        return Err(Refused { response: UPGRADE_REQUIRED, why });
+    }
+    let key = header("sec-websocket-key").ok_or_else(|| bad("no Sec-WebSocket-Key"))?;
+    if base64_decode(key).is_none_or(|k| k.len() != 16) {
+        return Err(bad("Sec-WebSocket-Key is not 16 bytes of base64"));
+    }
+    let path = request.path.unwrap_or_default();
+    let target = match header("x-target") {
+        Some(target) => target,
+        None => path.strip_prefix('/').unwrap_or(path).split('?').next().unwrap_or_default(),
+    };
+    let (host, port) = split_host_port(target);
+    let port = port.and_then(|p| p.parse().ok()).filter(|_| !host.is_empty());
+    let port = port.ok_or_else(|| bad(&format!("target {:?} needs host:port", target)))?;
+    let mut keyed = key.as_bytes().to_vec();
+    keyed.extend_from_slice(GUID);
+    Ok(Upgrade { host: host.to_owned(), port, accept: base64(&sha1(&keyed)) })
+}
+
+/// Our answer to an upgrade whose tunnel is up.
+pub fn switching(accept: &str) -> String {
+    format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept
+    )
+}
+
+/// The close code a session ending for `reason` sends its websocket
+/// client.
+pub fn close_code(reason: CloseReason) -> u16 {
+    match reason {
+        CloseReason::ClientClosed | CloseReason::UpstreamClosed => NORMAL,
+        CloseReason::Idle | CloseReason::Shutdown => GOING_AWAY,
+        CloseReason::Denied | CloseReason::AclWindow | CloseReason::LifetimeExceeded | CloseReason::PolicyDenied => {
+            POLICY_VIOLATION
+        }
+        CloseReason::MaxSessions => TRY_AGAIN_LATER,
+        CloseReason::Error | CloseReason::Http2 => INTERNAL_ERROR,
+    }
+}
+
+/// What a client closing with `code` ends its session as: a clean close
+/// when it says all went well, or says nothing, an error otherwise.
+pub fn close_reason(code: u16) -> CloseReason {
+    match code {
+        NORMAL | GOING_AWAY | NO_STATUS => CloseReason::ClientClosed,
+        _ => CloseReason::Error,
+    }
+}
+
+/// How the client's side of a `WsStream` ended, for reads to report once
+/// the payload before it is out.
+#[derive(Debug)]
+enum Ended {
+    /// end of file without a close frame
+    Eof,
+    /// its close frame, with the code and reason in it
+    Closed(u16, String),
+    /// it broke the protocol, we closed with the code for it
+    Failed(String),
+}
+
+/// The data frame being read, its payload partly in.
+#[derive(Debug)]
+struct Incoming {
+    left: u64,
+    mask: [u8; 4],
+    /// payload bytes unmasked so far, where in `mask` the next one is
+    at: usize,
+}
+
+/// The client of a websocket listener. Bytes pass as they are until
+/// `start`, for the upgrade request and our 101; from then on reads give
+/// the unmasked payload of the client's binary frames, continuations
+/// included, and each write goes out as a binary frame of its own. Pings
+/// are answered as they are read.
+pub struct WsStream {
+    sock: ClientStream,
+    started: bool,
+    /// `Config::websocket_max_frame`
+    max_frame: u64,
+    /// read from the socket and not decoded yet: at most the start of a
+    /// frame header or of a control frame, or what came behind
+    /// `PAYLOAD_MAX`
+    raw: Vec<u8>,
+    frame: Option<Incoming>,
+    /// a message of several frames is being read, its continuations come
+    /// next
+    fragmented: bool,
+    /// decoded and not read yet
+    payload: Vec<u8>,
+    /// whole frames the socket has not taken yet: at most one binary
+    /// frame, then the control frames queued behind it; first the 101,
+    /// see `start`
+    out: Vec<u8>,
+    close_sent: bool,
+    ended: Option<Ended>,
+}
+
+impl WsStream {
+    pub fn new(sock: ClientStream, max_frame: usize) -> WsStream {
+        WsStream {
+            sock,
+            started: false,
+            max_frame: max_frame as u64,
+            raw: Vec::new(),
+            frame: None,
+            fragmented: false,
+            payload: Vec::new(),
+            out: Vec::new(),
+            close_sent: false,
+            ended: None,
+        }
+    }
+
+    pub fn sock(&self) -> &ClientStream {
+        &self.sock
+    }
+
+    pub fn sock_mut(&mut self) -> &mut ClientStream {
+        &mut self.sock
+    }
+
+    /// Starts framing, with our `switching` 101 ahead of the first frame:
+    /// what the socket does not take of it waits in `out` like a frame.
+    pub fn start(&mut self, switching: &[u8]) {
+        self.out.extend_from_slice(switching);
+        self.started = true;
+        let _ = self.send();
+    }
+
+    /// Sends our close frame with `code`, as far as the socket takes it;
+    /// once, and only to a client that was upgraded.
+    pub fn close(&mut self, code: u16) {
+        if !self.started || self.close_sent {
+            return;
+        }
+        self.close_sent = true;
+        frame(&mut self.out, CLOSE, &code.to_be_bytes());
+        let _ = self.send();
+    }
+
+    /// Like `ClientStream::peek`: the decoded payload past `start`.
+    pub fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.started {
+            return self.sock.peek(buf);
+        }
+        if self.payload.len() < buf.len() {
+            self.fill()?;
+        }
+        if self.payload.is_empty() {
+            return self.end();
+        }
+        let n = self.payload.len().min(buf.len());
+        buf[..n].copy_from_slice(&self.payload[..n]);
+        Ok(n)
+    }
+
+    /// Decodes what the socket has into `payload`, reading it until it
+    /// would block or `PAYLOAD_MAX` is in. WouldBlock only with nothing
+    /// decoded and the client's side not ended.
+    fn fill(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; 16 * 1024];
+        while self.ended.is_none() && self.payload.len() < PAYLOAD_MAX {
+            if self.decode() {
+                continue;
+            }
+            match self.sock.read(&mut buf) {
+                Ok(0) => self.ended = Some(Ended::Eof),
+                Ok(n) => self.raw.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock && !self.payload.is_empty() => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes the next step through `raw`: payload of the data frame being
+    /// read, the next frame header, or a whole control frame, answered.
+    /// False when `raw` holds too little for any.
+    fn decode(&mut self) -> bool {
+        if let Some(f) = &mut self.frame {
+            if self.raw.is_empty() {
+                return false;
+            }
+            let n = self.raw.len().min(usize::try_from(f.left).unwrap_or(usize::MAX));
+            let unmasked = self.raw.drain(..n).enumerate().map(|(i, b)| b ^ f.mask[(f.at + i) % 4]);
+            self.payload.extend(unmasked);
+            f.at = (f.at + n) % 4;
+            f.left -= n as u64;
+            if f.left == 0 {
+                self.frame = None;
+            }
+            return true;
+        }
+        let header = match parse_header(&self.raw) {
+            Ok(Some(header)) => header,
+            Ok(None) => return false,
+            Err(why) => return self.fail(PROTOCOL_ERROR, why),
+        };
+        match header.opcode {
+            CONTINUATION if !self.fragmented => self.fail(PROTOCOL_ERROR, "continuation outside a message"),
+            BINARY if self.fragmented => self.fail(PROTOCOL_ERROR, "new message inside a fragmented one"),
+            TEXT => self.fail(UNSUPPORTED_DATA, "text frame, the tunnel takes binary ones"),
+            CONTINUATION | BINARY if header.len > self.max_frame => {
+                let why = format!("frame of {} bytes, past websocket_max_frame", header.len);
+                self.fail(TOO_BIG, &why)
+            }
+            CONTINUATION | BINARY => {
+                self.fragmented = !header.fin;
+                self.raw.drain(..header.size);
+                if header.len > 0 {
+                    self.frame = Some(Incoming { left: header.len, mask: header.mask, at: 0 });
+                }
+                true
+            }
+            CLOSE | PING | PONG if !header.fin || header.len > CONTROL_MAX => {
+                self.fail(PROTOCOL_ERROR, "fragmented or oversized control frame")
+            }
+            opcode @ (CLOSE | PING | PONG) => {
+                let end = header.size + header.len as usize;
+                if self.raw.len() < end {
+                    return false;
+                }
+                let body = self.raw.drain(..end).skip(header.size).enumerate().map(|(i, b)| b ^ header.mask[i % 4]);
+                let body = body.collect::<Vec<_>>();
+                match opcode {
+                    PING => self.pong(&body),
+                    CLOSE => self.closed(&body),
+                    _ => {}
+                }
+                true
+            }
+            opcode => self.fail(PROTOCOL_ERROR, &format!("unknown opcode {:#x}", opcode)),
+        }
+    }
+
+    /// Answers a ping with its payload, unless pongs pile up unread.
+    fn pong(&mut self, body: &[u8]) {
+        if self.close_sent || self.out.len() > PAYLOAD_MAX {
+            return;
+        }
+        frame(&mut self.out, PONG, body);
+        let _ = self.send();
+    }
+
+    /// The client's close frame: echoed with its code, and reads end.
+    fn closed(&mut self, body: &[u8]) {
+        let code = match body {
+            [a, b, ..] => u16::from_be_bytes([*a, *b]),
+            _ => NO_STATUS,
+        };
+        let reason = String::from_utf8_lossy(body.get(2..).unwrap_or_default()).into_owned();
+        if code == NO_STATUS {
+            // a close without a code is answered without one
+            if !self.close_sent {
+                self.close_sent = true;
+                frame(&mut self.out, CLOSE, &[]);
+                let _ = self.send();
+            }
+        } else {
+            self.close(code);
+        }
+        self.ended = Some(Ended::Closed(code, reason));
+    }
+
+    /// Closes with `code` for a client that broke the protocol; always
+    /// true, `decode` made its step.
+    fn fail(&mut self, code: u16, why: &str) -> bool {
+        self.close(code);
+        self.ended = Some(Ended::Failed(why.to_owned()));
+        true
+    }
+
+    /// What a read past the last payload gets.
+    fn end(&self) -> io::Result<usize> {
+        match &self.ended {
+            None => Err(ErrorKind::WouldBlock.into()),
+            Some(Ended::Eof) => Ok(0),
+            Some(Ended::Closed(code, _)) if close_reason(*code) == CloseReason::ClientClosed => Ok(0),
+            Some(Ended::Closed(code, reason)) => Err(io::Error::new(
+                ErrorKind::ConnectionAborted,
+                format!("client closed the websocket with {} {:?}", code, reason),
+            )),
+            Some(Ended::Failed(why)) => Err(io::Error::new(ErrorKind::InvalidData, why.clone())),
+        }
+    }
+
+    /// Writes `out` on as far as the socket takes it; WouldBlock with
+    /// some left.
+    fn send(&mut self) -> io::Result<()> {
+        while !self.out.is_empty() {
+            match self.sock.write(&self.out) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "write zero")),
+                Ok(n) => {
+                    self.out.drain(..n);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for WsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.started {
+            return self.sock.read(buf);
+        }
+        if self.payload.is_empty() {
+            self.fill()?;
+        }
+        if self.payload.is_empty() {
+            return self.end();
+        }
+        let n = self.payload.len().min(buf.len());
+        buf[..n].copy_from_slice(&self.payload[..n]);
+        self.payload.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for WsStream {
+    /// Takes up to `OUT_FRAME` of `buf` as one frame once the one before
+    /// is out, WouldBlock while it is not: what the socket does not take
+    /// right away waits in `out`, for the next write or `flush`.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.started {
+            return self.sock.write(buf);
+        }
+        self.send()?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.close_sent {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "websocket closed"));
+        }
+        let n = buf.len().min(OUT_FRAME);
+        frame(&mut self.out, BINARY, &buf[..n]);
+        match self.send() {
+            Err(e) if e.kind() != ErrorKind::WouldBlock => Err(e),
+            _ => Ok(n),
+        }
+    }
+
+    /// Sends what `out` still holds, WouldBlock until it is all out.
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.started {
+            return self.sock.flush();
+        }
+        self.send()
+    }
+}
+
+/// A client frame's header.
+#[derive(Debug)]
+struct Header {
+    fin: bool,
+    opcode: u8,
+    mask: [u8; 4],
+    /// of the payload
+    len: u64,
+    /// of the header itself
+    size: usize,
+}
+
+/// The header `raw` starts with, None while it is not all in. Client
+/// frames come masked, without the reserved bits of extensions.
+fn parse_header(raw: &[u8]) -> Result<Option<Header>, &'static str> {
+    let [b0, b1, ..] = *raw else {
+        return Ok(None);
+    };
+    if b0 & 0x70 != 0 {
+        return Err("reserved bits set, no extension was agreed");
+    }
+    if b1 & 0x80 == 0 {
+        return Err("client frame not masked");
+    }
+    let (len, at) = match b1 & 0x7f {
+        126 if raw.len() >= 4 => (u64::from(u16::from_be_bytes([raw[2], raw[3]])), 4),
+        127 if raw.len() >= 10 => (u64::from_be_bytes(raw[2..10].try_into().unwrap_or_default()), 10),
+        126 | 127 => return Ok(None),
+        n => (u64::from(n), 2),
+    };
+    let Some(mask) = raw.get(at..at + 4) else {
+        return Ok(None);
+    };
+    Ok(Some(Header {
+        fin: b0 & 0x80 != 0,
+        opcode: b0 & 0x0f,
+        mask: mask.try_into().unwrap_or_default(),
+        len,
+        size: at + 4,
+    }))
+}
+
+/// Appends a frame of ours, unmasked as a server's are.
+fn frame(out: &mut Vec<u8>, opcode: u8, payload: &[u8]) {
+    out.push(0x80 | opcode);
+    match payload.len() {
+        n if n < 126 => out.push(n as u8),
+        n if n <= usize::from(u16::MAX) => {
+            out.push(126);
+            out.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        n => {
+            out.push(127);
+            out.extend_from_slice(&(n as u64).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(payload);
+}
+
+/// SHA-1 of `data`, which Sec-WebSocket-Accept takes; nothing secret
+/// hangs on it.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    for block in msg.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (w, word) in w.iter_mut().zip(block.chunks_exact(4)) {
+            *w = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &wi) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let t = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(wi);
+            (e, d, c, b, a) = (d, c, b.rotate_left(30), a, t);
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+    let mut out = [0u8; 20];
+    for (out, h) in out.chunks_exact_mut(4).zip(h) {
+        out.copy_from_slice(&h.to_be_bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::net::UnixStream, time::Duration};
+
+    use super::*;
+
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    /// A client frame: masked with `MASK`, FIN as `fin` says.
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut out = vec![if fin { 0x80 } else { 0 } | opcode];
+        match payload.len() {
+            n if n < 126 => out.push(0x80 | n as u8),
+            n if n <= usize::from(u16::MAX) => {
+                out.push(0x80 | 126);
+                out.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+            n => {
+                out.push(0x80 | 127);
+                out.extend_from_slice(&(n as u64).to_be_bytes());
+            }
+        }
+        out.extend_from_slice(&MASK);
+        out.extend(payload.iter().enumerate().map(|(i, b)| b ^ MASK[i % 4]));
+        out
+    }
+
+    /// A started `WsStream` taking frames of `max_frame` at most, and the
+    /// client's end of its socket.
+    fn pair(max_frame: usize) -> (WsStream, UnixStream) {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        ours.set_nonblocking(true).unwrap();
+        theirs.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let mut ws = WsStream::new(ClientStream::Unix(mio::net::UnixStream::from_std(ours)), max_frame);
+        ws.start(b"");
+        (ws, theirs)
+    }
+
+    /// All `ws` has to read, until it would block or ends.
+    fn read_all(ws: &mut WsStream) -> (Vec<u8>, io::Result<usize>) {
+        let mut got = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            match ws.read(&mut buf) {
+                Ok(n @ 1..) => got.extend_from_slice(&buf[..n]),
+                end => return (got, end),
+            }
+        }
+    }
+
+    /// The frames we sent the client, opcodes and payloads; all of them
+    /// FIN and short.
+    fn sent(theirs: &mut UnixStream) -> Vec<(u8, Vec<u8>)> {
+        let mut frames = Vec::new();
+        let mut h = [0u8; 2];
+        while theirs.read_exact(&mut h).is_ok() {
+            assert_eq!(h[0] & 0xf0, 0x80, "{:?}", h);
+            assert!(h[1] < 126, "unmasked and short: {:?}", h);
+            let mut payload = vec![0u8; h[1] as usize];
+            theirs.read_exact(&mut payload).unwrap();
+            frames.push((h[0] & 0x0f, payload));
+        }
+        frames
+    }
+
+    fn request(extra: &str) -> Vec<u8> {
+        format!(
+            "GET /example.com:443 HTTP/1.1\r\nHost: proxy\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n{}\r\n",
+            extra
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn sha1_of_the_fips_180_examples() {
+        let hex = |digest: [u8; 20]| digest.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        for (message, digest) in [
+            (&b""[..], "da39a3ee5e6b4b0d3255bfef95601890afd80709"),
+            (b"abc", "a9993e364706816aba3e25717850c26c9cd0d89d"),
+            // 448 bits, the length no longer fits the first block
+            (b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq", "84983e441c3bd26ebaae4aa1f95129e5e54670f1"),
+            (
+                b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmnhijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu",
+                "a49b2446a02c645bf419f995b67091253a04a259",
+            ),
+        ] {
+            assert_eq!(hex(sha1(message)), digest, "{:?}", String::from_utf8_lossy(message));
+        }
+        assert_eq!(hex(sha1(&vec![b'a'; 1_000_000])), "34aa973cd4c4daa4f61eeb2bdbad27316534016f");
+    }
+
+    #[test]
+    fn an_upgrade_names_its_target_and_gets_the_accept_of_its_key() {
+        let up = upgrade(&request("Sec-WebSocket-Version: 13\r\n")).unwrap();
+        // the key and accept of RFC 6455 section 1.3
+        assert_eq!((up.host.as_str(), up.port, up.accept.as_str()), ("example.com", 443, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        let up = upgrade(&request("Sec-WebSocket-Version: 13\r\nX-Target: [::1]:22\r\n")).unwrap();
+        assert_eq!((up.host.as_str(), up.port), ("::1", 22));
+        assert_eq!(upgrade(&request("Sec-WebSocket-Version: 8\r\n")).unwrap_err().response, UPGRADE_REQUIRED);
+        let no_key = request("Sec-WebSocket-Version: 13\r\n");
+        let no_key = String::from_utf8(no_key).unwrap().replace("Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n", "");
+        assert_eq!(upgrade(no_key.as_bytes()).unwrap_err().why, "bad websocket upgrade: no Sec-WebSocket-Key");
+        let no_port = request("Sec-WebSocket-Version: 13\r\nX-Target: example.com\r\n");
+        assert!(upgrade(&no_port).unwrap_err().why.contains("needs host:port"));
+    }
+
+    #[test]
+    fn frame_headers_of_each_length_form() {
+        let header = |raw: &[u8]| parse_header(raw).map(|h| h.map(|h| (h.fin, h.opcode, h.len, h.size)));
+        assert_eq!(header(&client_frame(true, BINARY, &[0; 125])), Ok(Some((true, BINARY, 125, 6))));
+        assert_eq!(header(&client_frame(false, BINARY, &[0; 126])), Ok(Some((false, BINARY, 126, 8))));
+        assert_eq!(header(&client_frame(true, CONTINUATION, &[0; 65536])), Ok(Some((true, CONTINUATION, 65536, 14))));
+        // not all in yet
+        let long = client_frame(true, BINARY, &[0; 65536]);
+        for n in 0..14 {
+            assert_eq!(header(&long[..n]), Ok(None), "{} bytes", n);
+        }
+        assert_eq!(header(&[0x82, 0x05]), Err("client frame not masked"));
+        assert_eq!(header(&[0xc2, 0x85]), Err("reserved bits set, no extension was agreed"));
+    }
+
+    #[test]
+    fn masked_payload_is_unmasked_across_reads() {
+        let (mut ws, mut theirs) = pair(1024);
+        let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let frame = client_frame(true, BINARY, &data);
+        // split inside the header and then at an offset the mask does not
+        // start over at
+        for piece in [&frame[..3], &frame[3..13], &frame[13..]] {
+            theirs.write_all(piece).unwrap();
+            let _ = ws.read(&mut []);
+        }
+        let (got, end) = read_all(&mut ws);
+        assert_eq!(got, data);
+        assert_eq!(end.unwrap_err().kind(), ErrorKind::WouldBlock);
+        // and what we write goes out unmasked, a frame per write
+        assert_eq!(ws.write(b"back").unwrap(), 4);
+        assert_eq!(sent(&mut theirs), [(BINARY, b"back".to_vec())]);
+    }
+
+    #[test]
+    fn an_unmasked_frame_closes_with_1002() {
+        let (mut ws, mut theirs) = pair(1024);
+        theirs.write_all(&client_frame(true, BINARY, b"fine")).unwrap();
+        theirs.write_all(&[0x82, 0x03, b'b', b'a', b'd']).unwrap();
+        let (got, end) = read_all(&mut ws);
+        assert_eq!(got, b"fine");
+        assert_eq!(end.unwrap_err().to_string(), "client frame not masked");
+        assert_eq!(sent(&mut theirs), [(CLOSE, PROTOCOL_ERROR.to_be_bytes().to_vec())]);
+        assert_eq!(ws.write(b"late").unwrap_err().kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn control_frames_come_between_fragments() {
+        let (mut ws, mut theirs) = pair(1024);
+        theirs.write_all(&client_frame(false, BINARY, b"frag")).unwrap();
+        theirs.write_all(&client_frame(true, PING, b"are you there")).unwrap();
+        theirs.write_all(&client_frame(false, CONTINUATION, b"men")).unwrap();
+        theirs.write_all(&client_frame(true, PONG, b"unasked")).unwrap();
+        theirs.write_all(&client_frame(true, CONTINUATION, b"ted")).unwrap();
+        assert_eq!(read_all(&mut ws).0, b"fragmented");
+        assert_eq!(sent(&mut theirs), [(PONG, b"are you there".to_vec())]);
+        // a control frame may not be fragmented itself, nor a message
+        // start inside another
+        for broken in [client_frame(false, PING, b"half"), client_frame(true, BINARY, b"new")] {
+            let (mut ws, mut theirs) = pair(1024);
+            theirs.write_all(&client_frame(false, BINARY, b"frag")).unwrap();
+            theirs.write_all(&broken).unwrap();
+            let (got, end) = read_all(&mut ws);
+            assert_eq!(got, b"frag");
+            assert_eq!(end.unwrap_err().kind(), ErrorKind::InvalidData);
+            assert_eq!(sent(&mut theirs), [(CLOSE, PROTOCOL_ERROR.to_be_bytes().to_vec())]);
+        }
+    }
+
+    #[test]
+    fn the_size_limit_is_per_frame_of_a_fragmented_message() {
+        // fragments are relayed as they come, never put together: a
+        // message longer than the limit passes in frames under it
+        let (mut ws, mut theirs) = pair(16);
+        theirs.write_all(&client_frame(false, BINARY, &[1; 16])).unwrap();
+        theirs.write_all(&client_frame(true, CONTINUATION, &[2; 16])).unwrap();
+        assert_eq!(read_all(&mut ws).0, [[1; 16], [2; 16]].concat());
+        theirs.write_all(&client_frame(false, BINARY, &[3; 16])).unwrap();
+        theirs.write_all(&client_frame(true, CONTINUATION, &[4; 17])).unwrap();
+        let (got, end) = read_all(&mut ws);
+        assert_eq!(got, [3; 16]);
+        assert_eq!(end.unwrap_err().to_string(), "frame of 17 bytes, past websocket_max_frame");
+        assert_eq!(sent(&mut theirs), [(CLOSE, TOO_BIG.to_be_bytes().to_vec())]);
+        // a text frame is refused whatever its size
+        let (mut ws, mut theirs) = pair(16);
+        theirs.write_all(&client_frame(true, TEXT, b"hi")).unwrap();
+        assert_eq!(read_all(&mut ws).1.unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(sent(&mut theirs), [(CLOSE, UNSUPPORTED_DATA.to_be_bytes().to_vec())]);
+    }
+
+    #[test]
+    fn a_client_close_is_echoed_and_ends_reads_by_its_code() {
+        let close = |code: Option<u16>| {
+            let (mut ws, mut theirs) = pair(1024);
+            let body = code.map(|c| [&c.to_be_bytes()[..], b"bye"].concat()).unwrap_or_default();
+            theirs.write_all(&client_frame(true, BINARY, b"last")).unwrap();
+            theirs.write_all(&client_frame(true, CLOSE, &body)).unwrap();
+            let (got, end) = read_all(&mut ws);
+            assert_eq!(got, b"last");
+            (end, sent(&mut theirs))
+        };
+        let (end, sent) = close(Some(NORMAL));
+        assert_eq!(end.unwrap(), 0);
+        assert_eq!(sent, [(CLOSE, NORMAL.to_be_bytes().to_vec())]);
+        // without a code, answered without one
+        let (end, sent) = close(None);
+        assert_eq!(end.unwrap(), 0);
+        assert_eq!(sent, [(CLOSE, vec![])]);
+        let (end, sent) = close(Some(4000));
+        let e = end.unwrap_err();
+        assert_eq!((e.kind(), e.to_string()), (ErrorKind::ConnectionAborted, "client closed the websocket with 4000 \"bye\"".into()));
+        assert_eq!(sent, [(CLOSE, 4000u16.to_be_bytes().to_vec())]);
+    }
+
+    #[test]
+    fn close_codes_both_ways() {
+        for (reason, code) in [
+            (CloseReason::ClientClosed, NORMAL),
+            (CloseReason::UpstreamClosed, NORMAL),
+            (CloseReason::Idle, GOING_AWAY),
+            (CloseReason::Shutdown, GOING_AWAY),
+            (CloseReason::Denied, POLICY_VIOLATION),
+            (CloseReason::LifetimeExceeded, POLICY_VIOLATION),
+            (CloseReason::MaxSessions, TRY_AGAIN_LATER),
+            (CloseReason::Error, INTERNAL_ERROR),
+        ] {
+            assert_eq!(close_code(reason), code, "{:?}", reason);
+        }
+        for (code, reason) in [
+            (NORMAL, CloseReason::ClientClosed),
+            (GOING_AWAY, CloseReason::ClientClosed),
+            (NO_STATUS, CloseReason::ClientClosed),
+            (PROTOCOL_ERROR, CloseReason::Error),
+            (INTERNAL_ERROR, CloseReason::Error),
+            (4000, CloseReason::Error),
+        ] {
+            assert_eq!(close_reason(code), reason, "{}", code);
+        }
+        // a close we send goes once
+        let (mut ws, mut theirs) = pair(1024);
+        ws.close(GOING_AWAY);
+        ws.close(INTERNAL_ERROR);
+        assert_eq!(sent(&mut theirs), [(CLOSE, GOING_AWAY.to_be_bytes().to_vec())]);
+    }
+}
@@ -0,0 +1,2161 @@
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    io::{self, ErrorKind, Write},
+    net::{IpAddr, SocketAddr},
+    os::fd::AsRawFd,
+    rc::Rc,
+    thread,
+    sync::{
+        atomic::Ordering,
+        mpsc::{Receiver, TryRecvError},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use log::{debug, error, info, log_enabled, warn, Level};
+use mio::{event::Event, Events, Interest, Poll, Token};
+#[cfg(feature = "tls")]
+use mio::net::UnixStream;
+#[cfg(feature = "tls")]
+use std::collections::HashMap;
+
+use crate::{
+    accept_rate::{self, Excess},
+    access_log, acl, bans, conn_rate,
+    audit_log::{self, Denial},
+    admin::{Admin, SessionInfo},
+    busy_poll::{self, PollMode},
+    client::{ClientListener, ClientStream, Peer},
+    command::Command,
+    config::Config,
+    detect::{self, FirstBytes, Protocol},
+    dns::DNS,
+    err::{self, ErrorCategory, ErrorLog, Side},
+    failing_hosts,
+    hexdump,
+    loop_sampler::Laps,
+    profile::Profile,
+    schedule::WallTime,
+    session::{self, CloseReason, Drain, Outcome, Session, SessionRegistry},
+    stall::Activity,
+    stats::{EventKind, WorkerStats},
+    systemd::{self, WatchdogSlot},
+    timer::{Timer, TimerKind, Timers},
+    token::{TokenKind, TokenSpace},
+    top_hosts::HostTraffic,
+    transparent::Transparent,
+};
+#[cfg(feature = "tls")]
+use crate::{h2, tls::ServerContext};
+
+/// Minimum spacing of the per-worker loop summary log line.
+const LOOP_SUMMARY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Sessions a SIGUSR1 dump logs per loop iteration, so a large table does
+/// not hold up the events behind it.
+const DUMP_CHUNK: usize = 256;
+
+/// How often a loop records that it is turning, for `/healthz`; an idle
+/// loop wakes up for it.
+pub const HEARTBEAT: Duration = Duration::from_secs(1);
+
+/// Consecutive poll failures tolerated before a loop gives up.
+const MAX_POLL_FAILURES: u32 = 20;
+/// Pause between retries of a failed poll, so a persistent error does not
+/// spin the core.
+const POLL_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// Polls once. EINTR is retried right away and other errors are logged and
+/// retried after a pause; only `MAX_POLL_FAILURES` in a row are returned.
+/// Returns false when nothing was polled and the caller should loop again.
+pub fn poll_events(
+    poll: &mut Poll,
+    events: &mut Events,
+    timeout: Option<Duration>,
+    failures: &mut u32,
+) -> io::Result<bool> {
+    match poll.poll(events, timeout) {
+        Ok(()) => {
+            *failures = 0;
+            Ok(true)
+        }
+        Err(e) if e.kind() == ErrorKind::Interrupted => Ok(false),
+        Err(e) => {
+            *failures += 1;
+            if *failures >= MAX_POLL_FAILURES {
+                error!("poll failed {} times in a row, giving up: {:?}", failures, e);
+                return Err(e);
+            }
+            warn!("poll err {:?} ({} in a row), retrying", e, failures);
+            thread::sleep(POLL_RETRY_DELAY);
+            Ok(false)
+        }
+    }
+}
+
+/// Where a worker gets its client connections from.
+pub enum Intake {
+    /// the worker accepts on its own listeners, one per
+    /// `Config::listener_names` entry (SO_REUSEPORT mode; the unix socket
+    /// is shared by all workers)
+    Listener(Vec<ClientListener>),
+    /// an acceptor thread hands sockets over as `Command::Adopt`
+    Handoff,
+}
+
+/// One event loop. Each worker owns its poll, sessions, DNS cache and
+/// timers; sessions never move between workers, the only shared state is
+/// the stats it publishes. Other threads talk to it through `commands`.
+pub struct Worker {
+    id: usize,
+    poll: Poll,
+    intake: Intake,
+    session_registry: SessionRegistry,
+    dns: DNS,
+    timers: Timers,
+    // sessions whose pump stopped on the budget; edge-triggered readiness will
+    // not report them again, so they are pumped once more after the next poll
+    requeue: Vec<Token>,
+    stats: Arc<WorkerStats>,
+    commands: Receiver<Command>,
+    config: Arc<Config>,
+    /// `config.profiles_by_listener()`, swapped together with `config`
+    profiles: Vec<Arc<Profile>>,
+    /// by listener, `config.listener_proxy_protocol` and
+    /// `config.transparent_by_listener()`, which a reload cannot change
+    proxy_protocol: Vec<bool>,
+    transparent: Vec<Option<Transparent>>,
+    /// how a `Transparent::Redirect` listener's connection tells where it
+    /// was headed, the kernel's SO_ORIGINAL_DST but in tests
+    original_dst: fn(&ClientStream) -> io::Result<SocketAddr>,
+    /// `config.upstream_by_listener()`, swapped together with `config`
+    upstreams: Vec<Option<(String, u16)>>,
+    /// `config.protocol_by_listener()`, swapped together with `config`
+    protocols: Vec<Option<Protocol>>,
+    /// `config.tls_by_listener()`, swapped together with `config`
+    #[cfg(feature = "tls")]
+    tls: Vec<Option<Arc<ServerContext>>>,
+    /// the HTTP/2 connections, under the token of their own socket and
+    /// those of their streams' socketpair ends
+    #[cfg(feature = "tls")]
+    http2: HashMap<Token, Rc<RefCell<h2::Connection>>>,
+    /// this worker's share of `config.max_sessions`
+    max_sessions: usize,
+    /// set by `Command::Drain`: no more intake, exit with the last session
+    draining: bool,
+    /// listeners whose last accept batch hit the cap and may hold more
+    accept_pending: Vec<usize>,
+    /// the minute `enforce_acl_windows` last looked at the sessions
+    acl_minute: Option<WallTime>,
+    /// listeners deregistered until their `TimerKind::AcceptResume`, the
+    /// `Config::accept_rate` bucket being empty
+    accept_paused: Vec<usize>,
+    /// stats endpoint, hosted by one worker only
+    admin: Option<Admin>,
+    /// session tokens closed in the current batch, see `handle_session_event`
+    closed: HashSet<Token>,
+    /// keeps a flapping upstream from flooding the log, see `session_error`
+    error_log: ErrorLog,
+    /// client tokens and ids of the sessions a running dump has yet to
+    /// log, last first, see `dump_chunk`
+    dump: Vec<(Token, u64)>,
+    last_summary: LoopSummary,
+    /// checked in from `TimerKind::Watchdog` when systemd watches us
+    watchdog: Option<WatchdogSlot>,
+    /// loop iterations, counted while `config.loop_sample_every` is set
+    iterations: u64,
+}
+
+/// Counters as of the last loop summary log line.
+struct LoopSummary {
+    at: Instant,
+    opened: u64,
+    closed: u64,
+    bytes_up: u64,
+    bytes_down: u64,
+}
+
+impl LoopSummary {
+    fn take(stats: &WorkerStats) -> LoopSummary {
+        LoopSummary {
+            at: Instant::now(),
+            opened: stats.sessions_opened.load(Ordering::Relaxed),
+            closed: stats.sessions_closed.load(Ordering::Relaxed),
+            bytes_up: stats.bytes_up.load(Ordering::Relaxed),
+            bytes_down: stats.bytes_down.load(Ordering::Relaxed),
+        }
+    }
+
+    fn same_counts(&self, other: &LoopSummary) -> bool {
+        (self.opened, self.closed, self.bytes_up, self.bytes_down)
+            == (other.opened, other.closed, other.bytes_up, other.bytes_down)
+    }
+}
+
+/// The readiness of one session event, what `handle_session_event` goes
+/// by; a test forges combinations the kernel delivers only now and then.
+#[derive(Debug, Clone, Copy, Default)]
+struct Readiness {
+    readable: bool,
+    writable: bool,
+    read_closed: bool,
+    write_closed: bool,
+    error: bool,
+}
+
+impl From<&Event> for Readiness {
+    fn from(evt: &Event) -> Readiness {
+        Readiness {
+            readable: evt.is_readable(),
+            writable: evt.is_writable(),
+            read_closed: evt.is_read_closed(),
+            write_closed: evt.is_write_closed(),
+            error: evt.is_error(),
+        }
+    }
+}
+
+impl Worker {
+    pub fn new(
+        id: usize,
+        poll: Poll,
+        mut intake: Intake,
+        stats: Arc<WorkerStats>,
+        commands: Receiver<Command>,
+        config: Arc<Config>,
+        mut admin: Option<Admin>,
+    ) -> io::Result<Worker> {
+        if let Intake::Listener(listeners) = &mut intake {
+            for (n, l) in listeners.iter_mut().enumerate() {
+                poll.registry()
+                    .register(l, TokenSpace::listener(n), Interest::READABLE)?;
+            }
+        }
+        if let Some(admin) = &mut admin {
+            admin.register(poll.registry())?;
+        }
+        let max_sessions = worker_share(&config);
+        let last_summary = LoopSummary::take(&stats);
+        let mut timers = Timers::new();
+        timers.add(Instant::now() + HEARTBEAT, TimerKind::Heartbeat, TokenSpace::WAKER);
+        let watchdog = systemd::watchdog_slot(id);
+        if let Some(w) = &watchdog {
+            timers.add(Instant::now() + w.interval, TimerKind::Watchdog, TokenSpace::WAKER);
+        }
+        Ok(Worker {
+            id,
+            poll,
+            intake,
+            session_registry: SessionRegistry::new(),
+            dns: DNS::new(&config, Arc::clone(&stats)),
+            timers,
+            requeue: Vec::new(),
+            stats,
+            commands,
+            profiles: config.profiles_by_listener(),
+            proxy_protocol: config.listeners_in(&config.listener_proxy_protocol),
+            transparent: config.transparent_by_listener(),
+            original_dst: ClientStream::original_dst,
+            upstreams: config.upstream_by_listener(),
+            protocols: config.protocol_by_listener(),
+            #[cfg(feature = "tls")]
+            tls: config.tls_by_listener(),
+            #[cfg(feature = "tls")]
+            http2: HashMap::new(),
+            config,
+            max_sessions,
+            draining: false,
+            accept_pending: Vec::new(),
+            accept_paused: Vec::new(),
+            acl_minute: None,
+            admin,
+            closed: HashSet::new(),
+            error_log: ErrorLog::default(),
+            dump: Vec::new(),
+            last_summary,
+            watchdog,
+            iterations: 0,
+        })
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(self.config.events_capacity);
+        let mut poll_failures = 0;
+        loop {
+            let spin = match self.config.poll_mode {
+                PollMode::Spin { yield_cpu } => Some(yield_cpu),
+                PollMode::Block => None,
+            };
+            let timeout = if self.requeue.is_empty()
+                && self.accept_pending.is_empty()
+                && self.dump.is_empty()
+                && spin.is_none()
+            {
+                self.timers.next_timeout(Instant::now())
+            } else {
+                Some(Duration::ZERO)
+            };
+            self.stats.busy(Activity::Polling, 0);
+            if !poll_events(&mut self.poll, &mut events, timeout, &mut poll_failures)? {
+                continue;
+            }
+            if let Some(yield_cpu) = spin {
+                let idle = events.is_empty()
+                    && self.requeue.is_empty()
+                    && self.accept_pending.is_empty()
+                    && self.dump.is_empty();
+                let timer_due = self
+                    .timers
+                    .next_timeout(Instant::now())
+                    .is_some_and(|d| d.is_zero());
+                if idle && !timer_due {
+                    busy_poll::spin_hint(yield_cpu);
+                    continue;
+                }
+            }
+            // sampled iterations run a copy of `turn` that times every
+            // handler, the others cost nothing more than this match
+            let sample_every = self.config.loop_sample_every;
+            let stop = match sample_every {
+                Some(every) if self.sample_due(every) => self.turn::<true>(&events),
+                _ => self.turn::<false>(&events),
+            };
+
+            if stop {
+                info!("worker {} shutting down with {} sessions", self.id, self.session_registry.len());
+                self.close_all(CloseReason::Shutdown);
+                self.stats.stopped_ticking();
+                return Ok(());
+            }
+            if self.draining && self.session_registry.is_empty() {
+                info!("worker {} drained", self.id);
+                self.stats.stopped_ticking();
+                return Ok(());
+            }
+        }
+    }
+
+    /// Counts a loop iteration, true for every `every`th.
+    fn sample_due(&mut self, every: u64) -> bool {
+        self.iterations += 1;
+        self.iterations.is_multiple_of(every)
+    }
+
+    /// One loop iteration past the poll: timers, `events`, the accepts and
+    /// pumps left over from the last one, a dump chunk. True once a
+    /// `Command::Shutdown` came. With `SAMPLED` the time taken is added up
+    /// per `Activity` and the heaviest one counted in `loop_samples`.
+    fn turn<const SAMPLED: bool>(&mut self, events: &Events) -> bool {
+        self.stats
+            .polled(events.iter().count(), self.config.events_capacity);
+        let st = Instant::now();
+        let mut laps = Laps::default();
+        self.closed.clear();
+        let pending = std::mem::take(&mut self.requeue);
+        let accept_again = std::mem::take(&mut self.accept_pending);
+        let mut listeners_seen = Vec::new();
+
+        while let Some(timer) = self.timers.pop_expired(st) {
+            let activity = match timer.kind {
+                TimerKind::Heartbeat => Activity::Heartbeat,
+                TimerKind::Idle
+                | TimerKind::Watchdog
+                | TimerKind::AcceptResume
+                | TimerKind::Throttle
+                | TimerKind::Lifetime => {
+                    Activity::Timers
+                }
+            };
+            self.stats.busy(activity, 0);
+            let lap = SAMPLED.then(Instant::now);
+            match timer.kind {
+                TimerKind::Idle => self.handle_idle_timer(timer, st),
+                TimerKind::Watchdog => self.handle_watchdog_timer(st),
+                TimerKind::Heartbeat => self.handle_heartbeat_timer(st),
+                TimerKind::AcceptResume => self.handle_accept_resume_timer(timer),
+                TimerKind::Throttle => self.handle_throttle_timer(timer),
+                TimerKind::Lifetime => self.handle_lifetime_timer(timer, st),
+            }
+            if let Some(t) = lap {
+                laps.add(activity, t.elapsed());
+            }
+        }
+
+        let mut stop = false;
+        for evt in events.iter() {
+            let st = Instant::now();
+            let token = evt.token();
+            let kind = match TokenSpace::classify(token) {
+                TokenKind::Listener(n) => {
+                    listeners_seen.push(n);
+                    self.stats.busy(Activity::Event(EventKind::Accept), 0);
+                    self.accept_batch(n);
+                    Some(EventKind::Accept)
+                }
+                TokenKind::Waker => {
+                    self.stats.busy(Activity::Commands, 0);
+                    stop |= self.drain_commands();
+                    None
+                }
+                TokenKind::Admin => {
+                    self.stats.busy(Activity::Admin, 0);
+                    if let Some(admin) = &mut self.admin {
+                        admin.accept(self.poll.registry());
+                    }
+                    None
+                }
+                TokenKind::AdminConn(slot) => {
+                    self.stats.busy(Activity::Admin, 0);
+                    if let Some(admin) = &mut self.admin {
+                        admin.handle(self.poll.registry(), slot, evt);
+                    }
+                    None
+                }
+                TokenKind::Signals => {
+                    debug!("event for unregistered control token {:?}", token);
+                    None
+                }
+                TokenKind::Udp(_) => self.handle_udp_event(evt),
+                #[cfg(feature = "tls")]
+                TokenKind::Http2(_) => self.handle_http2_event(token),
+                #[cfg(not(feature = "tls"))]
+                TokenKind::Http2(_) => None,
+                TokenKind::Session(_) => self.handle_session_event(token, evt.into()),
+            };
+            if let Some(kind) = kind {
+                let took = st.elapsed();
+                self.stats.event_handled(kind, took);
+                if took >= self.config.slow_event {
+                    let session = self.session_registry.get(&token).map(|s| s.borrow().id);
+                    info!("worker {} slow {} event on session {:?} took {:?}", self.id, kind.name(), session, took);
+                }
+            }
+            if SAMPLED {
+                let activity = match (kind, TokenSpace::classify(token)) {
+                    (Some(kind), _) => Activity::Event(kind),
+                    (None, TokenKind::Waker) => Activity::Commands,
+                    (None, _) => Activity::Admin,
+                };
+                laps.add(activity, st.elapsed());
+            }
+        }
+
+        for n in accept_again {
+            if listeners_seen.contains(&n) {
+                continue;
+            }
+            let st = Instant::now();
+            self.stats.busy(Activity::Event(EventKind::Accept), 0);
+            self.accept_batch(n);
+            self.stats.event_handled(EventKind::Accept, st.elapsed());
+            if SAMPLED {
+                laps.add(Activity::Event(EventKind::Accept), st.elapsed());
+            }
+        }
+
+        let lap = SAMPLED.then(Instant::now);
+        for token in pending {
+            if self.closed.contains(&token) {
+                continue;
+            }
+            match self.handle_requeued(token) {
+                Ok(Drain::Again) => self.requeue_token(token),
+                Ok(Drain::Wait(at)) => self.throttle(token, at),
+                Ok(Drain::Done) => {}
+                Err(e) => {
+                    if e.kind() != ErrorKind::WouldBlock {
+                        self.session_error(token, e, "requeued pump");
+                    }
+                }
+            }
+        }
+        if let Some(t) = lap {
+            laps.add(Activity::Requeued, t.elapsed());
+        }
+
+        self.stats.busy(Activity::Dump, 0);
+        let lap = SAMPLED.then(Instant::now);
+        self.dump_chunk();
+        if let Some(t) = lap {
+            laps.add(Activity::Dump, t.elapsed());
+        }
+        self.stats.busy(Activity::Logging, 0);
+        let lap = SAMPLED.then(Instant::now);
+        self.error_log.flush(Instant::now());
+        self.stats.loop_latency.record(st.elapsed());
+        self.log_loop_summary();
+        if let Some(t) = lap {
+            laps.add(Activity::Logging, t.elapsed());
+            laps.finish(&self.stats.loop_samples);
+        }
+        stop
+    }
+
+    /// Logs the session counts when sessions opened or closed or bytes moved
+    /// since the last summary, at most once per `LOOP_SUMMARY_INTERVAL`.
+    fn log_loop_summary(&mut self) {
+        if self.last_summary.at.elapsed() < LOOP_SUMMARY_INTERVAL {
+            return;
+        }
+        let now = LoopSummary::take(&self.stats);
+        if now.same_counts(&self.last_summary) {
+            return;
+        }
+        info!(
+            "worker {} sessions {} timers {} opened {} closed {} bytes up {} down {} in the last {:?}",
+            self.id,
+            self.stats.active_sessions.load(Ordering::Relaxed),
+            self.timers.len(),
+            now.opened - self.last_summary.opened,
+            now.closed - self.last_summary.closed,
+            now.bytes_up - self.last_summary.bytes_up,
+            now.bytes_down - self.last_summary.bytes_down,
+            self.last_summary.at.elapsed()
+        );
+        if log_enabled!(Level::Debug) {
+            for k in &self.session_registry {
+                debug!("remaining session {}", k.1.borrow())
+            }
+        }
+        self.last_summary = now;
+    }
+
+    /// Handles one readiness event and reports what it was spent on. The
+    /// order is fixed: stale tokens are skipped, error/hang-up closes the
+    /// session outright, then readable, then writable, so no handler ever
+    /// runs for a session closed earlier in the same batch.
+    fn handle_session_event(&mut self, token: Token, evt: Readiness) -> Option<EventKind> {
+        // closed earlier in this batch; the fd may already belong to a
+        // session accepted since, whose own readiness comes with next poll
+        if self.closed.contains(&token) {
+            return None;
+        }
+        let (state, id) = self.session_registry.get(&token).map(|s| {
+            let s = s.borrow();
+            (s.state, s.id)
+        })?;
+        if (evt.error || evt.write_closed) && self.redial(token, None) {
+            return Some(if self.closed.contains(&token) { EventKind::Close } else { EventKind::Write });
+        }
+        if evt.error || evt.write_closed {
+            self.stats.busy(Activity::Event(EventKind::Close), id);
+            let reason = if evt.error {
+                CloseReason::Error
+            } else {
+                self.hung_up(token)
+            };
+            self.close_session(token, reason);
+            return Some(EventKind::Close);
+        }
+        if evt.read_closed && self.side(token) == Side::Client {
+            if let Some(s) = self.session_registry.get(&token) {
+                s.borrow_mut().client_eof = true;
+            }
+        }
+        let kind = match state {
+            session::State::ProxyHeader
+            | session::State::TlsHandshake
+            | session::State::Head(_)
+            | session::State::ParentHandshake
+                if evt.readable =>
+            {
+                EventKind::HeadRead
+            }
+            session::State::Piping | session::State::Associated if evt.readable => EventKind::Pipe,
+            _ => EventKind::Write,
+        };
+        self.stats.busy(Activity::Event(kind), id);
+        if evt.readable {
+            match self.handle_read(token) {
+                Ok(Drain::Again) => self.requeue_token(token),
+                Ok(Drain::Wait(at)) => self.throttle(token, at),
+                Ok(Drain::Done) => {}
+                Err(e) => {
+                    if e.kind() != ErrorKind::WouldBlock {
+                        self.session_error(token, e, "read");
+                    }
+                }
+            }
+        }
+
+        if evt.writable && !self.closed.contains(&token) {
+            match self.handle_write(token) {
+                Ok(Drain::Again) => self.requeue_token(token),
+                Ok(Drain::Wait(at)) => self.throttle(token, at),
+                Ok(Drain::Done) => {}
+                Err(e) => {
+                    if e.kind() != ErrorKind::WouldBlock && !self.redial(token, Some(&e)) {
+                        self.session_error(token, e, "write");
+                    }
+                }
+            }
+        }
+
+        // a piping session ends when its pump reads EOF, which only
+        // happens once everything read before it has been flushed
+        if evt.read_closed && !self.is_piping(token) {
+            let reason = self.hung_up(token);
+            self.close_session(token, reason);
+        }
+        if self.closed.contains(&token) {
+            return Some(EventKind::Close);
+        }
+        Some(kind)
+    }
+
+    /// Relays what waits on the socket of a SOCKS5 UDP association, see
+    /// `Session::relay`.
+    fn handle_udp_event(&mut self, evt: &Event) -> Option<EventKind> {
+        let token = evt.token();
+        // the fd may belong to a socket of a newer session by now
+        if self.closed.contains(&token) {
+            return None;
+        }
+        let session = self.session_registry.get(&token).map(Rc::clone)?;
+        self.stats.busy(Activity::Event(EventKind::Pipe), session.borrow().id);
+        let relayed = session.borrow_mut().relay(&mut self.dns);
+        match relayed {
+            Ok(Drain::Again) => self.requeue_token(token),
+            Ok(_) => {}
+            Err(e) => self.session_error(token, e, "relay"),
+        }
+        Some(if self.closed.contains(&token) { EventKind::Close } else { EventKind::Pipe })
+    }
+
+    /// Accepts up to `config.accept_batch` connections on listener `n`. When
+    /// the cap is hit the listener is not drained, so `accept_pending` makes
+    /// the next loop iteration come back for the rest after the other
+    /// events ran.
+    fn accept_batch(&mut self, n: usize) {
+        if self.accept_paused.contains(&n) {
+            return;
+        }
+        for _ in 0..self.config.accept_batch {
+            let over = accept_rate::take(Instant::now()).err();
+            if let Some(Excess::Pause(wait)) = over {
+                return self.pause_listener(n, wait);
+            }
+            let listen_sock = match &self.intake {
+                Intake::Listener(l) if n < l.len() => &l[n],
+                _ => return,
+            };
+            match listen_sock.accept() {
+                Ok((_, addr)) if over.is_some() => {
+                    debug!("worker {} shed connection from {}, accept_rate used up", self.id, addr);
+                    self.stats.accept_shed();
+                }
+                Ok((sock, addr)) => {
+                    if let Err(e) = self.add_session(sock, addr, n) {
+                        error!("add session {} err {:?}", addr, e);
+                    }
+                }
+                Err(e) => {
+                    if over.is_none() {
+                        accept_rate::give_back();
+                    }
+                    // anything but WouldBlock (EMFILE, ...) waits for the
+                    // next readiness edge rather than spinning on the error
+                    if e.kind() != ErrorKind::WouldBlock {
+                        error!("accept err {:?}", e);
+                    }
+                    return;
+                }
+            }
+        }
+        self.accept_pending.push(n);
+        self.stats.accept_cap_hit();
+    }
+
+    /// Leaves listener `n` alone for `wait`, its connections queued in the
+    /// kernel backlog meanwhile.
+    fn pause_listener(&mut self, n: usize, wait: Duration) {
+        let Intake::Listener(listeners) = &mut self.intake else {
+            return;
+        };
+        if let Err(e) = self.poll.registry().deregister(&mut listeners[n]) {
+            error!("deregister listener err {:?}", e);
+        }
+        self.accept_paused.push(n);
+        self.stats.accept_paused();
+        self.timers.add(Instant::now() + wait, TimerKind::AcceptResume, TokenSpace::listener(n));
+    }
+
+    /// Registers a paused listener again; connections already waiting
+    /// make it readable right away.
+    fn handle_accept_resume_timer(&mut self, timer: Timer) {
+        let TokenKind::Listener(n) = TokenSpace::classify(timer.token) else {
+            return;
+        };
+        let Some(i) = self.accept_paused.iter().position(|&p| p == n) else {
+            return;
+        };
+        self.accept_paused.swap_remove(i);
+        if let Intake::Listener(listeners) = &mut self.intake {
+            if let Err(e) = self.poll.registry().register(&mut listeners[n], timer.token, Interest::READABLE) {
+                error!("register listener err {:?}", e);
+            }
+        }
+    }
+
+    /// Runs the commands queued since the last wake. Returns true when the
+    /// worker was asked to shut down.
+    fn drain_commands(&mut self) -> bool {
+        let mut stop = false;
+        loop {
+            match self.commands.try_recv() {
+                Ok(Command::Adopt {
+                    sock,
+                    addr,
+                    listener,
+                }) => {
+                    self.stats.handoff_done();
+                    if let Err(e) = self.add_session(sock, addr, listener) {
+                        error!("adopt sock {} err {:?}", addr, e);
+                    }
+                }
+                Ok(Command::DumpSessions) => self.dump_sessions(),
+                Ok(Command::ListSessions {
+                    request,
+                    after,
+                    limit,
+                    reply,
+                }) => {
+                    let (sessions, more) = self.session_infos(after, limit);
+                    if let Err(e) = reply.send(Command::SessionList { request, sessions, more }) {
+                        debug!("session list reply err {:?}", e);
+                    }
+                }
+                Ok(Command::SessionList { request, sessions, more }) => {
+                    if let Some(admin) = &mut self.admin {
+                        admin.session_list(self.poll.registry(), request, sessions, more);
+                    }
+                }
+                Ok(Command::Capture {
+                    request,
+                    id,
+                    dir,
+                    limit,
+                    reply,
+                }) => {
+                    let session = self.session_registry.values().find(|s| s.borrow().id == id);
+                    let result = session.map(|s| s.borrow_mut().start_capture(&dir, limit));
+                    if let Err(e) = reply.send(Command::CaptureReply { request, result }) {
+                        debug!("capture reply err {:?}", e);
+                    }
+                }
+                Ok(Command::CaptureReply { request, result }) => {
+                    if let Some(admin) = &mut self.admin {
+                        admin.capture_reply(self.poll.registry(), request, result);
+                    }
+                }
+                Ok(Command::ListDnsCache {
+                    request,
+                    after,
+                    limit,
+                    reply,
+                }) => {
+                    let (entries, more) = self.dns.entries(self.id, &after, limit);
+                    if let Err(e) = reply.send(Command::DnsCacheList { request, entries, more }) {
+                        debug!("dns cache list reply err {:?}", e);
+                    }
+                }
+                Ok(Command::DnsCacheList { request, entries, more }) => {
+                    if let Some(admin) = &mut self.admin {
+                        admin.dns_cache_list(self.poll.registry(), request, entries, more);
+                    }
+                }
+                Ok(Command::EvictDns { request, host, reply }) => {
+                    let found = self.dns.evict(&host);
+                    if let Err(e) = reply.send(Command::DnsEvicted { request, found }) {
+                        debug!("dns evict reply err {:?}", e);
+                    }
+                }
+                Ok(Command::DnsEvicted { request, found }) => {
+                    if let Some(admin) = &mut self.admin {
+                        admin.dns_evicted(self.poll.registry(), request, found);
+                    }
+                }
+                Ok(Command::CloseClient { request, ip, reply }) => {
+                    let closed = self.close_client(ip);
+                    if let Err(e) = reply.send(Command::ClientClosed { request, closed }) {
+                        debug!("close client reply err {:?}", e);
+                    }
+                }
+                Ok(Command::ClientClosed { request, closed }) => {
+                    if let Some(admin) = &mut self.admin {
+                        admin.client_closed(self.poll.registry(), request, closed);
+                    }
+                }
+                Ok(Command::Drain { handoff }) => self.start_drain(handoff),
+                Ok(Command::Reload(config)) => self.reload(config),
+                Ok(Command::Shutdown) => stop = true,
+                Err(TryRecvError::Empty) => return stop,
+                Err(TryRecvError::Disconnected) => {
+                    debug!("worker {} command senders gone", self.id);
+                    return stop;
+                }
+            }
+        }
+    }
+
+    /// Takes over reloaded settings. Sessions already running keep the
+    /// config they were created with.
+    fn reload(&mut self, config: Arc<Config>) {
+        self.max_sessions = worker_share(&config);
+        self.dns.set_keep(config.dns_cache);
+        self.profiles = config.profiles_by_listener();
+        self.upstreams = config.upstream_by_listener();
+        self.protocols = config.protocol_by_listener();
+        #[cfg(feature = "tls")]
+        {
+            self.tls = config.tls_by_listener();
+        }
+        if let Some(admin) = &mut self.admin {
+            admin.reload(&config);
+        }
+        self.config = config;
+        debug!("worker {} reloaded, max sessions {}", self.id, self.max_sessions);
+    }
+
+    /// Drops the listeners, the successor accepts on its copies from now on.
+    fn start_drain(&mut self, handoff: bool) {
+        if let Intake::Listener(listeners) = std::mem::replace(&mut self.intake, Intake::Handoff) {
+            for (n, mut l) in listeners.into_iter().enumerate() {
+                if self.accept_paused.contains(&n) {
+                    continue;
+                }
+                if let Err(e) = self.poll.registry().deregister(&mut l) {
+                    error!("deregister listener err {:?}", e);
+                }
+            }
+        }
+        if handoff {
+            if let Some(admin) = self.admin.take() {
+                admin.close(self.poll.registry());
+            }
+        } else if let Some(admin) = &mut self.admin {
+            admin.start_drain();
+        }
+        self.draining = true;
+        // h2 clients are told to open no more streams on their connections
+        #[cfg(feature = "tls")]
+        for (token, conn) in &self.http2 {
+            if *token == conn.borrow().token() {
+                let _ = conn.borrow_mut().go_away();
+            }
+        }
+        self.accept_pending.clear();
+        self.accept_paused.clear();
+        info!(
+            "worker {} draining {} sessions",
+            self.id,
+            self.stats.active_sessions.load(Ordering::Relaxed)
+        );
+    }
+
+    /// Up to `limit` of the sessions with an id above `after`, lowest id
+    /// first, and whether there were more.
+    fn session_infos(&self, after: u64, limit: usize) -> (Vec<SessionInfo>, bool) {
+        let mut page = self
+            .session_registry
+            .iter()
+            .filter_map(|(token, s)| {
+                let s = s.borrow();
+                (token.0 == s.down_sock_id && s.id > after).then_some((s.id, *token))
+            })
+            .collect::<Vec<_>>();
+        let more = page.len() > limit;
+        if more {
+            page.select_nth_unstable(limit);
+            page.truncate(limit);
+        }
+        page.sort_unstable();
+        let now = Instant::now();
+        let listeners = self.config.listener_names();
+        let infos = page
+            .iter()
+            .map(|(_, token)| self.session_info(&self.session_registry[token].borrow(), &listeners, now))
+            .collect();
+        (infos, more)
+    }
+
+    fn session_info(&self, s: &Session, listeners: &[String], now: Instant) -> SessionInfo {
+        let (pending_up, pending_down) = s.pending();
+        SessionInfo {
+            worker: self.id,
+            id: s.id,
+            client: s.client,
+            listener: listeners[s.listener].clone(),
+            profile: s.profile.name.clone(),
+            host: s.host.clone(),
+            state: format!("{:?}", s.state),
+            bytes_up: s.bytes_up,
+            bytes_down: s.bytes_down,
+            age: now.duration_since(s.created),
+            idle: now.saturating_duration_since(s.last_active),
+            pending_up,
+            pending_down,
+            fd: s.down_sock_id,
+            up_fd: s.up_sock.is_some().then_some(s.up_sock_id),
+            mptcp: s.mptcp_negotiated,
+        }
+    }
+
+    /// With the upstream connect of the session of `token` (either of its
+    /// sockets) failed, has it dial the next address, see
+    /// `Session::redial`, and moves it to the new upstream token. False
+    /// when it has none left and is to close. `e` is what the upstream
+    /// failed with, None for an error event on either socket.
+    fn redial(&mut self, token: Token, e: Option<&io::Error>) -> bool {
+        let Some(session) = self.session_registry.get(&token).map(Rc::clone) else {
+            return false;
+        };
+        let old = Token(session.borrow().up_sock_id);
+        let why = match e {
+            Some(e) => Some(e.to_string()),
+            // the client's socket failing says nothing of a parent proxy
+            None if self.side(token) == Side::Client => None,
+            None => Some(session.borrow().up_error()),
+        };
+        let redialed = session.borrow_mut().redial(self.poll.registry(), &mut self.dns, why.as_deref());
+        match redialed {
+            None => false,
+            Some(Ok(fd)) => {
+                self.session_registry.remove(&old);
+                self.session_registry.insert(TokenSpace::session(fd), session);
+                true
+            }
+            Some(Err(e)) => {
+                self.session_error(token, e, "connect");
+                true
+            }
+        }
+    }
+
+    /// Fails a session over to the next parent of its route when the one
+    /// it dialed has not answered within `Config::parent_timeout`.
+    fn redial_late(&mut self, session: &Rc<RefCell<Session>>) -> bool {
+        let up = Token(session.borrow().up_sock_id);
+        let timeout = session.borrow().idle_timeout();
+        let e = io::Error::new(ErrorKind::TimedOut, format!("no answer within {}s", timeout.as_secs()));
+        self.redial(up, Some(&e))
+    }
+
+    /// Closes the session a handler of `token` failed on with `e`, counting
+    /// the error by category. `during` names the handler; only the first
+    /// error of a category per minute is logged, see `ErrorLog`.
+    fn session_error(&mut self, token: Token, e: io::Error, during: &str) {
+        // a tunnel refused by `Session::check_sni`, audited already
+        if self.session_registry.get(&token).is_some_and(|s| s.borrow().close_reason == Some(CloseReason::Denied)) {
+            return self.close_session(token, CloseReason::Denied);
+        }
+        let side = self.side(token);
+        if let Some(s) = self.session_registry.get(&token) {
+            let mut s = s.borrow_mut();
+            let category = err::classify(&e, side);
+            s.error = Some(category);
+            self.stats.session_error(category);
+            if category == ErrorCategory::ParseError {
+                let authority = s.authority();
+                self.stats.denied_on(s.listener);
+                audit_log::denied(&s.client, authority.as_deref(), Denial::BadRequest, None, Some(s.id));
+            }
+            if self.error_log.admit(category, Instant::now()) {
+                error!(
+                    session = s.id, client:% = s.client, host = s.host.as_str(), category = category.name(), err:% = e;
+                    "{} error", during
+                );
+                if category == ErrorCategory::ParseError && self.config.dump_bad_heads {
+                    warn!(
+                        session = s.id, client:% = s.client, bytes = s.connect_header_buf.len();
+                        "unparsable request head:\n{}", hexdump::head(&s.connect_header_buf)
+                    );
+                }
+            }
+        }
+        self.close_session(token, CloseReason::Error);
+    }
+
+    /// Starts logging every session, `DUMP_CHUNK` per loop iteration from
+    /// now on; a dump still running is left to finish.
+    fn dump_sessions(&mut self) {
+        if !self.dump.is_empty() {
+            info!("worker {} session dump running, {} sessions left", self.id, self.dump.len());
+            return;
+        }
+        // every connected session is registered under both of its fds
+        self.dump = self
+            .session_registry
+            .iter()
+            .filter_map(|(token, s)| {
+                let s = s.borrow();
+                (token.0 == s.down_sock_id).then_some((*token, s.id))
+            })
+            .collect();
+        self.dump.sort_unstable_by_key(|&(_, id)| std::cmp::Reverse(id));
+        info!(
+            "worker {} session dump, {} sessions {} tokens {} timers",
+            self.id,
+            self.dump.len(),
+            self.session_registry.len(),
+            self.timers.len()
+        );
+        if self.dump.is_empty() {
+            info!("worker {} session dump done", self.id);
+        }
+    }
+
+    /// Logs the next `DUMP_CHUNK` sessions of a running dump. Sessions
+    /// closed since it started are skipped, as are newer ones that got
+    /// their fd.
+    fn dump_chunk(&mut self) {
+        if self.dump.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        let listeners = self.config.listener_names();
+        for _ in 0..DUMP_CHUNK {
+            let Some((token, id)) = self.dump.pop() else {
+                break;
+            };
+            if let Some(s) = self.session_registry.get(&token) {
+                let s = s.borrow();
+                if s.id == id {
+                    info!("{}", self.session_info(&s, &listeners, now));
+                }
+            }
+        }
+        if self.dump.is_empty() {
+            info!("worker {} session dump done", self.id);
+        }
+    }
+
+    /// Whether the client at `addr` may have a session on `listener`: not
+    /// banned, not refused by `deny_clients` or `allow_clients` and within
+    /// `conn_rate`. A client turned away is counted, and told why where
+    /// the config says so.
+    fn admit(&self, sock: &mut ClientStream, addr: Peer, listener: usize) -> bool {
+        let Peer::Ip(ip) = addr else {
+            return true;
+        };
+        if bans::banned(ip.ip()) {
+            self.stats.banned_drop();
+            self.stats.denied_on(listener);
+            return false;
+        }
+        if let Err(refusal) = acl::check_client(&self.config.deny_clients, &self.config.allow_clients, ip.ip()) {
+            debug!(client:% = addr, rule:% = refusal; "client refused");
+            self.stats.client_refused();
+            self.stats.denied_on(listener);
+            audit_log::denied(&addr, None, Denial::Client, Some(&refusal.to_string()), None);
+            if self.config.deny_clients_403 && self.speaks_http(sock, listener) {
+                let _ = sock.write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+            }
+            return false;
+        }
+        if !conn_rate::admit(ip.ip(), Instant::now()) {
+            self.stats.conn_rate_limit();
+            self.stats.denied_on(listener);
+            if self.config.conn_rate_429 && self.speaks_http(sock, listener) {
+                let _ =
+                    sock.write_all(b"HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+            }
+            return false;
+        }
+        true
+    }
+
+    /// Whether a client turned away by `admit` may be told why in HTTP: on
+    /// a plain listener, without TLS, an upstream or a protocol other than
+    /// http, once its first bytes are in and start a request. Nothing is
+    /// waited for; any other client is closed without a word.
+    fn speaks_http(&self, sock: &mut ClientStream, listener: usize) -> bool {
+        #[cfg(feature = "tls")]
+        if self.tls[listener].is_some() {
+            return false;
+        }
+        if !matches!(self.protocols[listener], None | Some(Protocol::Http)) || self.upstreams[listener].is_some() {
+            return false;
+        }
+        let mut first = [0u8; detect::FIRST_BYTES];
+        match sock.peek(&mut first) {
+            Ok(n) => detect::classify(&first[..n]) == Some(FirstBytes::Http),
+            Err(_) => false,
+        }
+    }
+
+    /// Takes ownership of an accepted client socket and starts its session.
+    /// Behind a `proxy_protocol` listener `addr` is the load balancer's, the
+    /// client is checked by `admit` once its header named it. On a
+    /// transparent one the session dials the original destination right
+    /// away, from the client's address when the listener spoofs it, and
+    /// on a tunnel one its upstream.
+    pub fn add_session(&mut self, mut sock: ClientStream, addr: Peer, listener: usize) -> io::Result<()> {
+        self.stats.accepted_on(listener);
+        let proxied = self.proxy_protocol.get(listener).copied().unwrap_or(false);
+        if !proxied && !self.admit(&mut sock, addr, listener) {
+            return Ok(());
+        }
+        let transparent = self.transparent.get(listener).copied().flatten();
+        let found = transparent.map(|how| match how {
+            Transparent::Redirect => (self.original_dst)(&sock),
+            Transparent::Tproxy { .. } => sock.tproxy_dst(self.config.listen[listener]),
+        });
+        let original_dst = match found {
+            Some(Ok(to)) => Some(to),
+            Some(Err(e)) => {
+                info!(client:% = addr, err:% = e; "no original destination on transparent listener {}", listener);
+                self.stats.denied_on(listener);
+                return Ok(());
+            }
+            None => None,
+        };
+        if self.stats.active_sessions.load(Ordering::Relaxed) >= self.max_sessions {
+            // dropping the socket closes it before any fds are spent on it
+            warn!(
+                client:% = addr, reason = CloseReason::MaxSessions.name();
+                "worker {} at max sessions {}, session denied", self.id, self.max_sessions
+            );
+            self.stats.session_rejected();
+            self.stats.denied_on(listener);
+            access_log::denied(&addr, CloseReason::MaxSessions);
+            audit_log::denied(&addr, None, Denial::MaxSessions, Some("max_sessions"), None);
+            return Ok(());
+        }
+        if let (Some(usecs), Some(tcp)) = (self.config.so_busy_poll, sock.as_tcp()) {
+            busy_poll::set_socket_busy_poll(tcp, usecs);
+        }
+        #[cfg(feature = "tls")]
+        let sock = match &self.tls[listener] {
+            Some(ctx) => match sock.into_tls(ctx) {
+                Ok(sock) => sock,
+                Err(e) => {
+                    warn!(client:% = addr, err:% = e; "cannot start tls on listener {}", listener);
+                    return Ok(());
+                }
+            },
+            None => sock,
+        };
+        // over TLS, for a wss:// URL, when the listener has tls_cert too
+        let sock = match self.protocols[listener] {
+            Some(Protocol::WebSocket) => sock.into_websocket(self.config.websocket_max_frame),
+            _ => sock,
+        };
+        let down_sock_id = sock.as_raw_fd();
+        let sock_id = (down_sock_id).try_into().unwrap();
+        let session = Rc::new(RefCell::new(Session::new(
+            sock_id,
+            sock,
+            addr,
+            listener,
+            Arc::clone(&self.profiles[listener]),
+            Arc::clone(&self.stats),
+            Arc::clone(&self.config),
+        )));
+        {
+            let mut s = session.borrow_mut();
+            if proxied {
+                s.state = session::State::ProxyHeader;
+            }
+            s.original_dst = original_dst;
+            s.spoof_source = transparent == Some(Transparent::Tproxy { spoof_source: true });
+            s.tunnel = self.upstreams[listener].clone();
+            s.protocol = self.protocols[listener];
+            debug!(session = s.id, fd = down_sock_id, client:% = s.client, listener; "session open");
+        }
+        let token = match self.register_session(&session) {
+            Ok(token) => token,
+            Err(e) => {
+                error!("register sock errr {:?}", e);
+                return Err(e);
+            }
+        };
+        if original_dst.is_some() || self.upstreams[listener].is_some() {
+            match self.open_destination(token, &session) {
+                Err(e) if e.kind() != ErrorKind::WouldBlock => self.session_error(token, e, "connect"),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Registers a new session's client socket, arms its idle timer and
+    /// counts it open, returning its token.
+    fn register_session(&mut self, session: &Rc<RefCell<Session>>) -> io::Result<Token> {
+        let token = TokenSpace::session(session.borrow().down_sock.as_raw_fd());
+        // mio registrations are edge-triggered: every handler has to drain
+        // to WouldBlock (or requeue) or the session stalls
+        self.poll.registry().register(
+            &mut session.borrow_mut().down_sock,
+            token,
+            Interest::READABLE | Interest::WRITABLE,
+        )?;
+        let idle = session.borrow().idle_timeout();
+        session.borrow_mut().idle_timer = self.timers.add(Instant::now() + idle, TimerKind::Idle, token);
+        self.session_registry.insert(token, Rc::clone(session));
+        self.stats.session_opened(session.borrow().listener);
+        Ok(token)
+    }
+
+    /// Hands a TLS client that settled on h2 over to an `h2::Connection`.
+    /// The handshake's session ends there, closed as `CloseReason::Http2`
+    /// without an access log line; each CONNECT on the connection is a
+    /// session of its own.
+    #[cfg(feature = "tls")]
+    fn start_http2(&mut self, token: Token, session: Rc<RefCell<Session>>) -> io::Result<Drain> {
+        self.session_registry.remove(&token);
+        self.closed.insert(token);
+        let (client, listener) = (session.borrow().client, session.borrow().listener);
+        self.stats.head_done();
+        self.stats.session_closed(listener, CloseReason::Http2);
+        self.poll.registry().deregister(&mut session.borrow_mut().down_sock)?;
+        let session = Rc::try_unwrap(session).map_err(|_| io::Error::other("h2 session still shared"))?;
+        let mut conn = h2::Connection::new(session.into_inner().into_client(), client, listener, self.poll.registry())?;
+        debug!(client:% = client, listener; "h2 connection open");
+        conn.idle_timer = self.timers.add(Instant::now() + self.http2_idle(listener), TimerKind::Idle, conn.token());
+        let conn = Rc::new(RefCell::new(conn));
+        self.http2.insert(conn.borrow().token(), Rc::clone(&conn));
+        // the preface may have come with the handshake's last flight
+        self.drive_http2(&conn);
+        Ok(Drain::Done)
+    }
+
+    #[cfg(feature = "tls")]
+    fn handle_http2_event(&mut self, token: Token) -> Option<EventKind> {
+        let conn = self.http2.get(&token).map(Rc::clone)?;
+        self.stats.busy(Activity::Event(EventKind::Pipe), 0);
+        self.drive_http2(&conn);
+        Some(EventKind::Pipe)
+    }
+
+    /// Runs `h2::Connection::drive` with the room left under max_sessions,
+    /// none while draining, opening a session for each CONNECT it took.
+    #[cfg(feature = "tls")]
+    fn drive_http2(&mut self, conn: &Rc<RefCell<h2::Connection>>) {
+        let active = self.stats.active_sessions.load(Ordering::Relaxed);
+        let room = if self.draining { 0 } else { self.max_sessions.saturating_sub(active) };
+        let driven = conn.borrow_mut().drive(self.poll.registry(), room);
+        for token in conn.borrow_mut().take_closed() {
+            self.http2.remove(&token);
+        }
+        let opened = match driven {
+            Ok(opened) => opened,
+            Err(e) => {
+                debug!(client:% = conn.borrow().client, err:% = e; "h2 connection closed");
+                return self.close_http2(conn);
+            }
+        };
+        for token in conn.borrow().stream_tokens() {
+            self.http2.insert(token, Rc::clone(conn));
+        }
+        let (client, listener) = (conn.borrow().client, conn.borrow().listener);
+        let user = conn.borrow().user.clone();
+        for sock in opened {
+            if let Err(e) = self.open_http2_stream(sock, client, listener, user.clone()) {
+                error!("register sock errr {:?}", e);
+            }
+        }
+        if conn.borrow().finished() {
+            self.close_http2(conn);
+        }
+    }
+
+    /// Opens the session of an h2 stream on `sock`, its socketpair end,
+    /// with the CONNECT waiting in it, as the user of the connection's
+    /// client certificate.
+    #[cfg(feature = "tls")]
+    fn open_http2_stream(
+        &mut self,
+        sock: UnixStream,
+        client: Peer,
+        listener: usize,
+        user: Option<String>,
+    ) -> io::Result<()> {
+        let sock = ClientStream::Unix(sock);
+        let fd = sock.as_raw_fd();
+        let session = Rc::new(RefCell::new(Session::new(
+            fd.try_into().unwrap(),
+            sock,
+            client,
+            listener,
+            Arc::clone(&self.profiles[listener]),
+            Arc::clone(&self.stats),
+            Arc::clone(&self.config),
+        )));
+        session.borrow_mut().certified(user);
+        debug!(session = session.borrow().id, fd, client:% = client, listener; "h2 stream session open");
+        self.register_session(&session)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "tls")]
+    fn close_http2(&mut self, conn: &Rc<RefCell<h2::Connection>>) {
+        let mut c = conn.borrow_mut();
+        c.close(self.poll.registry());
+        for token in c.stream_tokens().into_iter().chain(c.take_closed()) {
+            self.http2.remove(&token);
+        }
+        self.http2.remove(&c.token());
+    }
+
+    /// How long an h2 connection without streams is kept: the idle timeout
+    /// of its listener's profile.
+    #[cfg(feature = "tls")]
+    fn http2_idle(&self, listener: usize) -> Duration {
+        self.profiles[listener].idle_timeout.unwrap_or(self.config.timeouts.idle)
+    }
+
+    #[cfg(feature = "tls")]
+    fn handle_http2_idle_timer(&mut self, conn: &Rc<RefCell<h2::Connection>>, timer: Timer, now: Instant) {
+        if conn.borrow().idle_timer != timer.id {
+            return;
+        }
+        let idle = self.http2_idle(conn.borrow().listener);
+        let deadline = conn.borrow().last_active + idle;
+        if conn.borrow().has_streams() || deadline > now {
+            let at = if conn.borrow().has_streams() { now + idle } else { deadline };
+            conn.borrow_mut().idle_timer = self.timers.add(at, TimerKind::Idle, timer.token);
+            return;
+        }
+        debug!(client:% = conn.borrow().client; "h2 connection idle timeout");
+        let _ = conn.borrow_mut().go_away();
+        self.close_http2(conn);
+    }
+
+    /// Closes the session `token` belongs to and writes its access log
+    /// line; `reason` applies unless the session recorded its own.
+    fn close_session(&mut self, token: Token, reason: CloseReason) {
+        let side = self.side(token);
+        let poll = self.poll.registry();
+        let session_registry = &mut self.session_registry;
+        if let Some(s) = session_registry.remove(&token) {
+            let sock_id = token.0;
+            debug!("close {}", s.borrow());
+            if sock_id == s.borrow().down_sock_id {
+                session_registry.remove(&Token(s.borrow().up_sock_id));
+            } else {
+                session_registry.remove(&Token(s.borrow().down_sock_id));
+            }
+            s.borrow_mut().route_failed();
+            self.closed.insert(Token(s.borrow().down_sock_id));
+            if s.borrow().up_sock.is_some() {
+                self.closed.insert(Token(s.borrow().up_sock_id));
+            }
+
+            let rr = poll.deregister(&mut s.borrow_mut().down_sock);
+            if let Err(e) = rr {
+                error!("session {} deregister client err {:?}", s.borrow().id, e);
+            }
+            let id = s.borrow().id;
+            s.borrow_mut().up_sock.iter_mut().for_each(|s| {
+                let rr = poll.deregister(s);
+                if let Err(e) = rr {
+                    error!("session {} deregister upstream err {:?}", id, e);
+                }
+            });
+            if let Some(udp) = s.borrow_mut().udp.as_mut() {
+                debug!("session {} udp association closed, {} datagrams dropped", id, udp.dropped);
+                session_registry.remove(&udp.token());
+                self.closed.insert(udp.token());
+                if let Err(e) = poll.deregister(&mut udp.socket) {
+                    error!("session {} deregister udp err {:?}", id, e);
+                }
+            }
+            let reason = *s.borrow_mut().close_reason.get_or_insert(reason);
+            if matches!(
+                s.borrow().state,
+                session::State::ProxyHeader | session::State::TlsHandshake | session::State::Head(_)
+            ) {
+                self.stats.head_done();
+            }
+            let failure = s.borrow_mut().connect_failure(reason);
+            s.borrow_mut().socks_failed(failure);
+            s.borrow_mut().websocket_closed(reason, failure);
+            if let Some(kind) = failure {
+                self.stats.connect_failed(kind);
+            }
+            let listener = s.borrow().listener;
+            self.stats.session_closed(listener, reason);
+            s.borrow_mut().charge_quota();
+            s.borrow_mut().stop_capture();
+            s.borrow_mut().classify_unsniffed();
+            let s = s.borrow();
+            // not counted by `session_error`: a connect that failed without
+            // a handler error, or an error event, a reset by that socket's peer
+            if s.error.is_none() && (failure.is_some() || reason == CloseReason::Error) {
+                let category = failure.map_or(ErrorCategory::reset(side), ErrorCategory::from_connect);
+                self.stats.session_error(category);
+                if self.error_log.admit(category, Instant::now()) {
+                    match failure {
+                        Some(kind) => warn!(
+                            session = s.id, client:% = s.client, host = s.host.as_str(),
+                            category = category.name(), err_kind = kind.name();
+                            "upstream connect failed"
+                        ),
+                        None => error!(
+                            session = s.id, client:% = s.client, host = s.host.as_str(), category = category.name();
+                            "error event"
+                        ),
+                    }
+                }
+            }
+            debug!(
+                session = s.id, client:% = s.client, host = s.host.as_str(),
+                bytes_up = s.bytes_up, bytes_down = s.bytes_down, reason = reason.name();
+                "session closed"
+            );
+            if !s.host.is_empty() {
+                let traffic = HostTraffic {
+                    bytes_up: s.bytes_up,
+                    bytes_down: s.bytes_down,
+                    sessions: 1,
+                    failures: u64::from(matches!(s.outcome(), Outcome::Failed(_))),
+                };
+                self.stats.hosts.lock().unwrap().record(&s.host, traffic);
+                if failure.is_some() || s.outcome() == Outcome::Established {
+                    let minute = failing_hosts::minute();
+                    self.stats.failing_hosts.lock().unwrap().record(&s.host, failure.is_some(), minute);
+                }
+            }
+            access_log::session(&s, reason);
+            #[cfg(feature = "otlp")]
+            crate::otlp::session(&s, reason);
+        }
+    }
+
+    fn close_all(&mut self, reason: CloseReason) {
+        let tokens = self
+            .session_registry
+            .iter()
+            .filter(|(token, s)| token.0 == s.borrow().down_sock_id)
+            .map(|(token, _)| *token)
+            .collect::<Vec<_>>();
+        for token in tokens {
+            self.close_session(token, reason);
+        }
+    }
+
+    /// The reason for a hang-up seen on `token`, by which side it is.
+    fn hung_up(&self, token: Token) -> CloseReason {
+        match self.side(token) {
+            Side::Upstream => CloseReason::UpstreamClosed,
+            Side::Client => CloseReason::ClientClosed,
+        }
+    }
+
+    /// Which socket of its session `token` is.
+    fn side(&self, token: Token) -> Side {
+        match self.session_registry.get(&token) {
+            Some(s) if token.0 != s.borrow().down_sock_id => Side::Upstream,
+            _ => Side::Client,
+        }
+    }
+
+    fn handle_watchdog_timer(&mut self, now: Instant) {
+        if let Some(w) = &self.watchdog {
+            w.alive();
+            self.timers.add(now + w.interval, TimerKind::Watchdog, TokenSpace::WAKER);
+        }
+    }
+
+    fn handle_heartbeat_timer(&mut self, now: Instant) {
+        self.stats.ticked();
+        // every connected session is registered under both of its fds
+        let (pipes, pending) = self
+            .session_registry
+            .iter()
+            .filter_map(|(token, s)| {
+                let s = s.borrow();
+                (token.0 == s.down_sock_id).then(|| (s.pipes(), s.pending()))
+            })
+            .fold((0, 0), |(pipes, pending), (p, (up, down))| (pipes + p, pending + (up + down) as u64));
+        self.stats.buffers_counted(pipes, pending);
+        self.enforce_quotas();
+        self.enforce_acl_windows();
+        if self.id == 0 {
+            bans::expire(SystemTime::now());
+        }
+        self.timers.add(now + HEARTBEAT, TimerKind::Heartbeat, TokenSpace::WAKER);
+    }
+
+    /// Charges what the sessions of authenticated users moved, closing
+    /// those whose user went past their daily quota with it.
+    fn enforce_quotas(&mut self) {
+        let mut over = Vec::new();
+        for (token, s) in &self.session_registry {
+            let mut s = s.borrow_mut();
+            if token.0 != s.down_sock_id || s.user.is_none() {
+                continue;
+            }
+            s.charge_quota();
+            if let Some(rule) = s.over_quota() {
+                over.push((*token, rule));
+            }
+        }
+        for (token, rule) in over {
+            if let Some(s) = self.session_registry.get(&token) {
+                let s = s.borrow();
+                info!(session = s.id, user = s.user.as_deref().unwrap_or_default(); "closing session, {}", rule);
+                audit_log::denied(&s.client, s.authority().as_deref(), Denial::Quota, Some(&rule), Some(s.id));
+            }
+            self.close_session(token, CloseReason::Denied);
+        }
+    }
+
+    /// Closes the sessions `Session::acl_recheck` refuses, once a minute,
+    /// as windows open and close on the minute.
+    fn enforce_acl_windows(&mut self) {
+        let now = SystemTime::now();
+        let minute = self.config.acl_timezone.wall(now);
+        if self.acl_minute.replace(minute) == Some(minute) {
+            return;
+        }
+        let mut refused = Vec::new();
+        for (token, s) in &self.session_registry {
+            let s = s.borrow();
+            if token.0 != s.down_sock_id {
+                continue;
+            }
+            if let Some(rule) = s.acl_recheck(now) {
+                refused.push((*token, rule));
+            }
+        }
+        for (token, rule) in refused {
+            if let Some(s) = self.session_registry.get(&token) {
+                let s = s.borrow();
+                info!(
+                    session = s.id, client:% = s.client, host = s.host.as_str();
+                    "closing session, {} no longer allows it", rule
+                );
+                audit_log::denied(&s.client, s.authority().as_deref(), Denial::Acl, Some(&rule), Some(s.id));
+            }
+            self.close_session(token, CloseReason::AclWindow);
+        }
+    }
+
+    /// Closes the sessions of the client `ip`, banned on the admin
+    /// endpoint. Returns how many there were.
+    fn close_client(&mut self, ip: IpAddr) -> usize {
+        let tokens = self
+            .session_registry
+            .iter()
+            .filter(|(token, s)| {
+                let s = s.borrow();
+                token.0 == s.down_sock_id && matches!(s.client, Peer::Ip(addr) if addr.ip() == ip)
+            })
+            .map(|(token, _)| *token)
+            .collect::<Vec<_>>();
+        for &token in &tokens {
+            if let Some(s) = self.session_registry.get(&token) {
+                let s = s.borrow();
+                info!(session = s.id, client:% = s.client, host = s.host.as_str(); "closing session, client banned");
+                audit_log::denied(&s.client, s.authority().as_deref(), Denial::Client, Some("ban"), Some(s.id));
+            }
+            self.close_session(token, CloseReason::PolicyDenied);
+        }
+        tokens.len()
+    }
+
+    fn handle_idle_timer(&mut self, timer: Timer, now: Instant) {
+        #[cfg(feature = "tls")]
+        if let Some(conn) = self.http2.get(&timer.token).map(Rc::clone) {
+            return self.handle_http2_idle_timer(&conn, timer, now);
+        }
+        let session = match self.session_registry.get(&timer.token) {
+            Some(s) => Rc::clone(s),
+            None => return,
+        };
+        // the fd may have been reused by a newer session with its own timer
+        if session.borrow().idle_timer != timer.id {
+            return;
+        }
+
+        let deadline = session.borrow().last_active + session.borrow().idle_timeout();
+        if deadline <= now && session.borrow().waits_for_hello() {
+            // no hello in time, the original address will do
+            session.borrow_mut().hello_waited = true;
+            if let Err(e) = self.open_destination(timer.token, &session) {
+                self.session_error(timer.token, e, "connect");
+            }
+        } else if deadline <= now && session.borrow().awaits_parent() && self.redial_late(&session) {
+            let mut s = session.borrow_mut();
+            s.idle_timer = self.timers.add(s.last_active + s.idle_timeout(), TimerKind::Idle, timer.token);
+        } else if deadline <= now {
+            let s = session.borrow();
+            info!(session = s.id, client:% = s.client, host = s.host.as_str(); "idle timeout");
+            drop(s);
+            self.close_session(timer.token, CloseReason::Idle);
+        } else {
+            session.borrow_mut().idle_timer = self.timers.add(deadline, TimerKind::Idle, timer.token);
+        }
+    }
+
+    fn handle_lifetime_timer(&mut self, timer: Timer, now: Instant) {
+        let Some(session) = self.session_registry.get(&timer.token).map(Rc::clone) else {
+            return;
+        };
+        // the fd may have been reused by a newer session with its own timer
+        if session.borrow().lifetime_timer != timer.id {
+            return;
+        }
+        let (lifetime, ends) = (session.borrow().lifetime(), session.borrow().lifetime_ends());
+        let Some(lifetime) = lifetime else {
+            return;
+        };
+        match ends {
+            Some(ends) if ends <= now => {
+                let s = session.borrow();
+                info!(
+                    session = s.id, client:% = s.client, host = s.host.as_str(), lifetime:? = lifetime;
+                    "session lifetime exceeded"
+                );
+                drop(s);
+                self.close_session(timer.token, CloseReason::LifetimeExceeded);
+            }
+            // still being established: it lives at least `lifetime` more
+            ends => {
+                let at = ends.unwrap_or(now + lifetime);
+                session.borrow_mut().lifetime_timer = self.timers.add(at, TimerKind::Lifetime, timer.token);
+            }
+        }
+    }
+
+    fn is_piping(&self, token: Token) -> bool {
+        self.session_registry
+            .get(&token)
+            .is_some_and(|s| matches!(s.borrow().state, session::State::Piping))
+    }
+
+    fn requeue_token(&mut self, token: Token) {
+        if !self.requeue.contains(&token) {
+            self.requeue.push(token);
+        }
+    }
+
+    /// Pumps the session of `token` again at `at`, when the limit of its
+    /// acl entry has bytes for it again. One timer per session, however
+    /// many of its pumps ran dry meanwhile.
+    fn throttle(&mut self, token: Token, at: Instant) {
+        if let Some(s) = self.session_registry.get(&token) {
+            let mut s = s.borrow_mut();
+            if s.throttle_timer == 0 {
+                s.throttle_timer = self.timers.add(at, TimerKind::Throttle, token);
+            }
+        }
+    }
+
+    fn handle_throttle_timer(&mut self, timer: Timer) {
+        let Some(s) = self.session_registry.get(&timer.token) else {
+            return;
+        };
+        // the fd may have been reused by a newer session
+        if s.borrow().throttle_timer != timer.id {
+            return;
+        }
+        s.borrow_mut().throttle_timer = 0;
+        self.requeue_token(timer.token);
+    }
+
+    fn handle_requeued(&mut self, token: Token) -> io::Result<Drain> {
+        // the session may have been closed by an event in this batch
+        if let Some(sess) = self.session_registry.get(&token) {
+            let (state, id) = (sess.borrow().state, sess.borrow().id);
+            self.stats.busy(Activity::Requeued, id);
+            match state {
+                session::State::Piping => return sess.borrow_mut().pump(),
+                session::State::Associated => return sess.borrow_mut().relay(&mut self.dns),
+                _ => {}
+            }
+        }
+
+        Ok(Drain::Done)
+    }
+
+    fn handle_write(&mut self, token: Token) -> io::Result<Drain> {
+        if let Some(sess) = self.session_registry.get(&token) {
+            // the handshake goes on as the socket takes what it writes too
+            if matches!(sess.borrow().state, session::State::TlsHandshake) {
+                return self.handle_read(token);
+            }
+            return sess.borrow_mut().handle_write(token);
+        }
+
+        Ok(Drain::Done)
+    }
+
+    /// Reads the session's request and dials its destination, see
+    /// `Session::connect`, registering the upstream once it is dialed.
+    fn open_destination(&mut self, token: Token, session: &Rc<RefCell<Session>>) -> io::Result<Drain> {
+        let connected = session.borrow_mut().connect(self.poll.registry(), &mut self.dns);
+        match connected {
+            Ok(up) => {
+                self.stats.head_done();
+                self.session_registry.insert(up, Rc::clone(session));
+                // the destination may have a shorter idle timeout than the
+                // global one the timer was armed with
+                let mut s = session.borrow_mut();
+                s.idle_timer = self.timers.add(s.last_active + s.idle_timeout(), TimerKind::Idle, token);
+                // established no earlier than now, the timer moves on to
+                // `lifetime_ends` when it fires
+                if let Some(lifetime) = s.lifetime() {
+                    s.lifetime_timer = self.timers.add(Instant::now() + lifetime, TimerKind::Lifetime, token);
+                }
+                Ok(Drain::Done)
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(Drain::Done),
+            // answered and audited already, not an error
+            Err(_) if session.borrow().outcome() == Outcome::Denied => {
+                self.close_session(token, CloseReason::Denied);
+                Ok(Drain::Done)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn handle_read(&mut self, token: Token) -> io::Result<Drain> {
+        let session = match self.session_registry.get(&token) {
+            Some(s) => Rc::clone(s),
+            None => return Ok(Drain::Done),
+        };
+
+        debug!("readable event {}", session.borrow());
+        let state = session.borrow().state;
+        match state {
+            session::State::ProxyHeader => {
+                let read = session.borrow_mut().proxy_header();
+                match read {
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(Drain::Done),
+                    Err(e) => return Err(e),
+                    Ok(()) => {}
+                }
+                let (client, listener) = (session.borrow().client, session.borrow().listener);
+                if !self.admit(&mut session.borrow_mut().down_sock, client, listener) {
+                    self.close_session(token, CloseReason::Denied);
+                    return Ok(Drain::Done);
+                }
+                // what the client sent behind the header fired no edge of its own
+                self.handle_read(token)
+            }
+            session::State::TlsHandshake => {
+                let done = session.borrow_mut().tls_handshake();
+                match done {
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(Drain::Done),
+                    Err(e) => return Err(e),
+                    Ok(()) => {}
+                }
+                #[cfg(feature = "tls")]
+                if session.borrow().down_sock.is_h2() {
+                    return self.start_http2(token, session);
+                }
+                // the request may have come with the handshake's last flight
+                self.handle_read(token)
+            }
+            session::State::Head(_) => self.open_destination(token, &session),
+            // the TLS handshake of a wrapped upstream reads the server's flights
+            session::State::Connecting if token.0 == session.borrow().up_sock_id && session.borrow().wraps_tls() => {
+                let done = session.borrow_mut().handle_write(token);
+                match done {
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(Drain::Done),
+                    r => r,
+                }
+            }
+            // data waits in the kernel buffer until the tunnel is established
+            session::State::Connecting => Ok(Drain::Done),
+            session::State::ParentHandshake if token.0 == session.borrow().up_sock_id => {
+                let answered = session.borrow_mut().parent_response();
+                match answered {
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(Drain::Done),
+                    // a parent that broke off rather than refused, the next is tried
+                    Err(e) if self.redial(token, Some(&e)) => Ok(Drain::Done),
+                    r => r,
+                }
+            }
+            session::State::ParentHandshake => Ok(Drain::Done),
+            session::State::Associated => session.borrow_mut().drain_control(),
+            session::State::Piping => {
+                debug!("piping..");
+                match session.borrow_mut().pipe(token.0) {
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(Drain::Done),
+                    r => r,
+                }
+            }
+        }
+    }
+}
+
+/// One worker's part of `config.max_sessions`.
+fn worker_share(config: &Config) -> usize {
+    config
+        .max_sessions
+        .map_or(usize::MAX, |m| m.div_ceil(config.workers))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        io::Write as _,
+        net::{Shutdown, TcpListener},
+    };
+
+    use nix::{
+        sys::{
+            pthread::{pthread_kill, pthread_self},
+            signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
+        },
+        unistd::dup2,
+    };
+
+    use super::*;
+    use crate::command;
+
+    extern "C" fn ignore(_: nix::libc::c_int) {}
+
+    #[test]
+    fn an_interrupted_poll_is_retried_without_counting() {
+        // a handler without SA_RESTART, so the signal fails the epoll_wait
+        let action = SigAction::new(SigHandler::Handler(ignore), SaFlags::empty(), SigSet::empty());
+        // SAFETY: the handler does nothing, and nothing else uses SIGURG
+        unsafe { sigaction(Signal::SIGURG, &action) }.unwrap();
+        let mut poll = Poll::new().unwrap();
+        let mut events = Events::with_capacity(8);
+        let me = pthread_self();
+        let kicker = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            pthread_kill(me, Signal::SIGURG).unwrap();
+        });
+        let mut failures = 3;
+        let start = Instant::now();
+        let polled = poll_events(&mut poll, &mut events, Some(Duration::from_secs(10)), &mut failures);
+        kicker.join().unwrap();
+        assert!(!polled.unwrap(), "the poll was not interrupted");
+        assert!(start.elapsed() < Duration::from_secs(5));
+        // neither a failure nor a success, the next poll goes on as before
+        assert_eq!(failures, 3);
+    }
+
+    #[test]
+    fn a_failing_poll_is_retried_until_the_cap() {
+        let mut poll = Poll::new().unwrap();
+        let mut events = Events::with_capacity(8);
+        // the poll's fd is no epoll instance any more, every wait fails
+        let null = File::open("/dev/null").unwrap();
+        dup2(null.as_raw_fd(), poll.as_raw_fd()).unwrap();
+        let mut failures = 0;
+        for n in 1..MAX_POLL_FAILURES {
+            assert!(!poll_events(&mut poll, &mut events, Some(Duration::ZERO), &mut failures).unwrap());
+            assert_eq!(failures, n);
+        }
+        assert!(poll_events(&mut poll, &mut events, Some(Duration::ZERO), &mut failures).is_err());
+    }
+
+    #[test]
+    fn a_good_poll_resets_the_count() {
+        let mut poll = Poll::new().unwrap();
+        let mut events = Events::with_capacity(8);
+        let mut failures = MAX_POLL_FAILURES - 1;
+        assert!(poll_events(&mut poll, &mut events, Some(Duration::ZERO), &mut failures).unwrap());
+        assert_eq!(failures, 0);
+    }
+
+    fn worker() -> Worker {
+        worker_with(Config::default())
+    }
+
+    fn worker_with(config: Config) -> Worker {
+        let poll = Poll::new().unwrap();
+        let (_, commands) = command::channel(&poll).unwrap();
+        let config = Arc::new(config);
+        let stats = Arc::new(WorkerStats::new(config.listener_names().len()));
+        Worker::new(0, poll, Intake::Handoff, stats, commands, config, None).unwrap()
+    }
+
+    /// A client of a new session of `worker`, and the session's token.
+    fn client(worker: &mut Worker, listener: &TcpListener) -> (std::net::TcpStream, Token) {
+        let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (client, adopt(worker, listener))
+    }
+
+    /// Accepts the next client of `listener` as a session of `worker`.
+    fn adopt(worker: &mut Worker, listener: &TcpListener) -> Token {
+        let (sock, addr) = listener.accept().unwrap();
+        sock.set_nonblocking(true).unwrap();
+        let token = TokenSpace::session(sock.as_raw_fd());
+        let sock = ClientStream::Tcp(mio::net::TcpStream::from_std(sock));
+        worker.add_session(sock, Peer::Ip(addr), 0).unwrap();
+        assert!(worker.session_registry.contains_key(&token));
+        token
+    }
+
+    fn closed(worker: &Worker) -> u64 {
+        worker.stats.sessions_closed.load(Ordering::Relaxed)
+    }
+
+    /// The socket `worker` accepts from `listener` once the client sent
+    /// `first`.
+    fn accepted_after(listener: &TcpListener, first: &[u8]) -> (std::net::TcpStream, ClientStream) {
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(first).unwrap();
+        let (sock, _) = listener.accept().unwrap();
+        sock.set_nonblocking(true).unwrap();
+        // in by the time the worker looks, as on a loaded proxy
+        thread::sleep(Duration::from_millis(20));
+        (client, ClientStream::Tcp(mio::net::TcpStream::from_std(sock)))
+    }
+
+    #[test]
+    fn only_a_client_that_started_a_request_is_answered_in_http() {
+        let mut worker = worker();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        for (first, http) in [
+            (&b"CONNECT example.com:443 HTTP/1.1\r\n"[..], true),
+            (&b"GET"[..], true),
+            (&b""[..], false),
+            (&b"\x16\x03\x01\x02\x00"[..], false),
+            (&b"\x05\x01\x00"[..], false),
+            (&b"\x04\x01\x00\x50"[..], false),
+        ] {
+            let (_client, mut sock) = accepted_after(&listener, first);
+            assert_eq!(worker.speaks_http(&mut sock, 0), http, "{:?}", first);
+        }
+        // the same request on a listener whose clients expect something else
+        worker.protocols = vec![Some(Protocol::WebSocket)];
+        let (_client, mut sock) = accepted_after(&listener, b"GET / HTTP/1.1\r\n");
+        assert!(!worker.speaks_http(&mut sock, 0));
+        worker.protocols = vec![None];
+        worker.upstreams = vec![Some(("127.0.0.1".to_owned(), 22))];
+        let (_client, mut sock) = accepted_after(&listener, b"GET / HTTP/1.1\r\n");
+        assert!(!worker.speaks_http(&mut sock, 0));
+    }
+
+    #[test]
+    fn a_denied_client_gets_a_403_only_when_it_speaks_http() {
+        let mut worker = worker_with(Config {
+            deny_clients: vec!["127.0.0.0/8".parse().unwrap()],
+            deny_clients_403: true,
+            ..Config::default()
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        for (first, answer) in [
+            (&b"GET / HTTP/1.1\r\n"[..], &b"HTTP/1.1 403 Forbidden\r\n"[..]),
+            (&b"\x16\x03\x01\x02\x00"[..], &b""[..]),
+            (&b""[..], &b""[..]),
+        ] {
+            let (mut client, sock) = accepted_after(&listener, first);
+            let addr = client.local_addr().unwrap();
+            worker.add_session(sock, Peer::Ip(addr), 0).unwrap();
+            assert!(worker.session_registry.is_empty());
+            client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            // closed with the request unread, so a reset ends what came
+            let mut got = Vec::new();
+            let mut buf = [0u8; 256];
+            while let Ok(n @ 1..) = std::io::Read::read(&mut client, &mut buf) {
+                got.extend_from_slice(&buf[..n]);
+            }
+            assert!(got.starts_with(answer) && got.is_empty() == answer.is_empty(), "{:?}", got);
+        }
+    }
+
+    #[test]
+    fn hang_up_with_data_and_room_closes_once() {
+        let mut worker = worker();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut client, token) = client(&mut worker, &listener);
+        client.write_all(b"CONNECT exa").unwrap();
+        client.shutdown(Shutdown::Both).unwrap();
+        let everything = Readiness {
+            readable: true,
+            writable: true,
+            read_closed: true,
+            write_closed: true,
+            error: false,
+        };
+        assert_eq!(worker.handle_session_event(token, everything), Some(EventKind::Close));
+        assert!(!worker.session_registry.contains_key(&token));
+        assert_eq!(closed(&worker), 1);
+        // the same readiness again later in the batch finds nothing to do
+        assert_eq!(worker.handle_session_event(token, everything), None);
+        assert_eq!(closed(&worker), 1);
+    }
+
+    #[test]
+    fn an_error_goes_before_the_data_with_it() {
+        let mut worker = worker();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut client, token) = client(&mut worker, &listener);
+        client.write_all(b"CONNECT example.com:443 HTTP/1.1\r\n\r\n").unwrap();
+        let failed = Readiness { readable: true, writable: true, error: true, ..Readiness::default() };
+        assert_eq!(worker.handle_session_event(token, failed), Some(EventKind::Close));
+        assert_eq!(closed(&worker), 1);
+    }
+
+    #[test]
+    fn a_token_reused_in_the_batch_waits_for_the_next_poll() {
+        let mut worker = worker();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (_first, token) = client(&mut worker, &listener);
+        let mut second = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let hung_up = Readiness { readable: true, read_closed: true, write_closed: true, ..Readiness::default() };
+        assert_eq!(worker.handle_session_event(token, hung_up), Some(EventKind::Close));
+        // accepted in the same batch, and given the fd just closed
+        let reused = adopt(&mut worker, &listener);
+        if reused != token {
+            eprintln!("{:?} taken by another thread meanwhile, skipped", token);
+            return;
+        }
+        assert_eq!(worker.handle_session_event(token, hung_up), None);
+        assert!(worker.session_registry.contains_key(&reused));
+        assert_eq!(closed(&worker), 1);
+        // its own readiness, with the next poll, is handled
+        worker.closed.clear();
+        second.write_all(b"CON").unwrap();
+        let readable = Readiness { readable: true, ..Readiness::default() };
+        assert_eq!(worker.handle_session_event(reused, readable), Some(EventKind::HeadRead));
+        assert!(worker.session_registry.contains_key(&reused));
+    }
+
+    thread_local! {
+        /// where the stub says a redirected connection was headed
+        static HEADED: std::cell::Cell<Option<SocketAddr>> = const { std::cell::Cell::new(None) };
+    }
+
+    fn redirected(_: &ClientStream) -> io::Result<SocketAddr> {
+        HEADED.get().ok_or_else(|| io::Error::new(ErrorKind::NotFound, "not redirected"))
+    }
+
+    /// A worker whose listener 0 takes REDIRECTed connections, headed
+    /// where `HEADED` says, loopback among them.
+    fn redirect_worker() -> Worker {
+        redirect_worker_with(Config { block_internal: false, ..Config::default() })
+    }
+
+    fn redirect_worker_with(config: Config) -> Worker {
+        let mut worker = worker_with(config);
+        worker.transparent = vec![Some(Transparent::Redirect)];
+        worker.original_dst = redirected;
+        worker
+    }
+
+    /// Runs the worker's loop until `done`, for ten seconds at most.
+    fn turn_until(worker: &mut Worker, mut done: impl FnMut(&mut Worker) -> bool) {
+        let mut events = Events::with_capacity(64);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done(worker) {
+            assert!(Instant::now() < deadline, "gave up waiting");
+            worker.poll.poll(&mut events, Some(Duration::from_millis(10))).unwrap();
+            worker.turn::<false>(&events);
+        }
+    }
+
+    #[test]
+    fn a_redirected_connection_goes_where_it_was_headed() {
+        let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+        HEADED.set(Some(origin.local_addr().unwrap()));
+        let mut worker = redirect_worker();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut client, token) = client(&mut worker, &listener);
+        // no head to read: the session dials as it is accepted
+        origin.set_nonblocking(true).unwrap();
+        let mut upstream = None;
+        turn_until(&mut worker, |_| {
+            upstream = upstream.take().or_else(|| origin.accept().ok());
+            upstream.is_some()
+        });
+        let (mut upstream, _) = upstream.unwrap();
+        client.set_nonblocking(true).unwrap();
+        client.write_all(b"TLS bytes, say").unwrap();
+        upstream.write_all(b"and back").unwrap();
+        let (mut up, mut down) = (Vec::new(), Vec::new());
+        upstream.set_nonblocking(true).unwrap();
+        turn_until(&mut worker, |_| {
+            let mut buf = [0u8; 64];
+            if let Ok(n) = std::io::Read::read(&mut upstream, &mut buf) {
+                up.extend_from_slice(&buf[..n]);
+            }
+            if let Ok(n) = std::io::Read::read(&mut client, &mut buf) {
+                down.extend_from_slice(&buf[..n]);
+            }
+            up.len() == 14 && down.len() == 8
+        });
+        assert_eq!((&up[..], &down[..]), (&b"TLS bytes, say"[..], &b"and back"[..]));
+        let session = worker.session_registry[&token].borrow();
+        assert_eq!(session.original_dst, Some(origin.local_addr().unwrap()));
+        assert_eq!(session.host, "127.0.0.1");
+    }
+
+    #[test]
+    fn a_connection_not_redirected_is_turned_away() {
+        HEADED.set(None);
+        let mut worker = redirect_worker();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (sock, addr) = listener.accept().unwrap();
+        sock.set_nonblocking(true).unwrap();
+        let sock = ClientStream::Tcp(mio::net::TcpStream::from_std(sock));
+        worker.add_session(sock, Peer::Ip(addr), 0).unwrap();
+        assert!(worker.session_registry.is_empty());
+    }
+
+    #[test]
+    fn the_original_destination_is_checked_like_any_other() {
+        let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+        HEADED.set(Some(origin.local_addr().unwrap()));
+        // block_internal, on by default, refuses loopback
+        let mut worker = redirect_worker_with(Config::default());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut client, _) = client(&mut worker, &listener);
+        client.write_all(b"\x16\x03\x01").unwrap();
+        turn_until(&mut worker, |w| w.session_registry.is_empty());
+        origin.set_nonblocking(true).unwrap();
+        assert!(origin.accept().is_err(), "dialed a refused destination");
+    }
+
+    /// Loop overhead of a batch of 1000 events: walking them in order, as
+    /// `turn` does, against the old shuffle, `choose_multiple` taking them
+    /// all, which counted the events, collected them into a Vec, permuted
+    /// it and walked that; a fixed xorshift stands in for its rng. The
+    /// dispatch itself is left out, the same either way.
+    /// `cargo test --release worker::tests::bench -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_event_iteration() {
+        let mut poll = Poll::new().unwrap();
+        let mut events = Events::with_capacity(1024);
+        let mut pairs = Vec::new();
+        for i in 0..1000 {
+            let (mut a, b) = mio::net::UnixStream::pair().unwrap();
+            poll.registry().register(&mut a, Token(i), Interest::READABLE).unwrap();
+            (&b).write_all(b"x").unwrap();
+            pairs.push((a, b));
+        }
+        poll.poll(&mut events, Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(events.iter().count(), 1000);
+        let rounds = 100_000;
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let start = Instant::now();
+        for _ in 0..rounds {
+            let n = events.iter().count();
+            let mut batch = Vec::with_capacity(n);
+            batch.extend(events.iter());
+            for i in (1..n).rev() {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                batch.swap(i, (state % (i as u64 + 1)) as usize);
+            }
+            for evt in batch {
+                std::hint::black_box(evt.token());
+            }
+        }
+        println!("shuffled: {:?} per 1k events", start.elapsed() / rounds);
+        let start = Instant::now();
+        for _ in 0..rounds {
+            for evt in events.iter() {
+                std::hint::black_box(evt.token());
+            }
+        }
+        println!("in order: {:?} per 1k events", start.elapsed() / rounds);
+    }
+}
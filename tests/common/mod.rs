@@ -0,0 +1,282 @@
+//! Runs the proxy binary against a config written for one test, with
+//! small servers to tunnel to. Each test file pulls in what it needs.
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long a test waits for the proxy, or an answer, before failing.
+pub const WAIT: Duration = Duration::from_secs(10);
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// A scratch directory, removed with it.
+pub struct Scratch(pub PathBuf);
+
+impl Scratch {
+    pub fn new() -> Scratch {
+        let n = NEXT_DIR.fetch_add(1, Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!("thin_proxy-test-{}-{}", std::process::id(), n));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Scratch(dir)
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Moves the calling thread, and the processes and threads it starts from
+/// then on, into a network namespace of its own with loopback up, where
+/// a test may change the firewall; false where it cannot.
+pub fn own_network() -> bool {
+    nix::sched::unshare(nix::sched::CloneFlags::CLONE_NEWNET).is_ok()
+        && Command::new("ip").args(["link", "set", "lo", "up"]).status().is_ok_and(|s| s.success())
+}
+
+/// A port free on loopback right now, for a listener the proxy binds.
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// A running proxy, killed when dropped. Its log goes to `proxy.log` in
+/// its scratch directory.
+pub struct Proxy {
+    pub child: Child,
+    pub addr: SocketAddr,
+    pub dir: Scratch,
+}
+
+impl Proxy {
+    /// Starts the proxy on a free loopback port with `config` after the
+    /// settings every test wants: one worker, loopback destinations let
+    /// through unless `config` says otherwise, debug logging. A `config`
+    /// with `[[listener]]` tables puts the port where it says `{addr}`.
+    pub fn start(config: &str) -> Proxy {
+        Proxy::start_with(config, &[])
+    }
+
+    /// `start` with more command line arguments.
+    pub fn start_with(config: &str, args: &[&str]) -> Proxy {
+        let addr = format!("127.0.0.1:{}", free_port()).parse().unwrap();
+        Proxy::start_on(addr, config, args)
+    }
+
+    /// `start_with` listening on `addr` rather than a loopback port.
+    pub fn start_on(addr: SocketAddr, config: &str, args: &[&str]) -> Proxy {
+        let dir = Scratch::new();
+        // a test about block_internal sets it itself
+        let block = if config.contains("block_internal") { "" } else { "block_internal = false\n" };
+        // with listener tables, {addr} is the address of the one to wait for
+        let listen = match config.contains("[[listener]]") {
+            true => String::new(),
+            false => format!("listen = [\"{}\"]\n", addr),
+        };
+        let config = config.replace("{addr}", &addr.to_string());
+        let config = format!("{}workers = 1\n{}log_level = \"debug\"\n{}", listen, block, config);
+        fs::write(dir.path("proxy.toml"), config).unwrap();
+        let child = spawn(&dir, args);
+        let proxy = Proxy { child, addr, dir };
+        proxy.wait_listening(proxy.addr);
+        proxy
+    }
+
+    /// Waits until `addr`, one of the proxy's listeners, takes connections.
+    pub fn wait_listening(&self, addr: SocketAddr) {
+        let deadline = Instant::now() + WAIT;
+        while TcpStream::connect(addr).is_err() {
+            assert!(Instant::now() < deadline, "proxy not listening on {}:\n{}", addr, self.log());
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    pub fn config_path(&self) -> PathBuf {
+        self.dir.path("proxy.toml")
+    }
+
+    pub fn log(&self) -> String {
+        fs::read_to_string(self.dir.path("proxy.log")).unwrap_or_default()
+    }
+
+    /// Waits for a line containing `needle` in the log.
+    pub fn wait_log(&self, needle: &str) {
+        let deadline = Instant::now() + WAIT;
+        while !self.log().contains(needle) {
+            assert!(Instant::now() < deadline, "no {:?} in the log:\n{}", needle, self.log());
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    pub fn signal(&self, signal: &str) {
+        let status = Command::new("kill").args(["-s", signal, &self.child.id().to_string()]).status().unwrap();
+        assert!(status.success());
+    }
+
+    /// Opens a tunnel to `target` with a CONNECT, returning the status
+    /// line and the socket.
+    pub fn connect(&self, target: &str) -> (String, TcpStream) {
+        connect_via(self.addr, target, &[])
+    }
+
+    /// A tunnel that must open.
+    pub fn tunnel(&self, target: &str) -> TcpStream {
+        let (status, sock) = self.connect(target);
+        assert!(status.starts_with("HTTP/1.1 200"), "CONNECT {}: {}\n{}", target, status, self.log());
+        sock
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        if thread::panicking() {
+            eprintln!("proxy log:\n{}", self.log());
+        }
+    }
+}
+
+/// Runs the proxy binary on the `proxy.toml` of `dir`.
+pub fn spawn(dir: &Scratch, args: &[&str]) -> Child {
+    let log = fs::OpenOptions::new().create(true).append(true).open(dir.path("proxy.log")).unwrap();
+    Command::new(env!("CARGO_BIN_EXE_thin_proxy"))
+        .arg("--config")
+        .arg(dir.path("proxy.toml"))
+        .args(args)
+        .current_dir(&dir.0)
+        .stdin(Stdio::null())
+        .stdout(log.try_clone().unwrap())
+        .stderr(log)
+        .spawn()
+        .unwrap()
+}
+
+/// Sends a CONNECT for `target` to `proxy` with `headers`, reading the
+/// answer's head; the socket is left right behind it.
+pub fn connect_via(proxy: SocketAddr, target: &str, headers: &[&str]) -> (String, TcpStream) {
+    let mut head = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+    for h in headers {
+        head.push_str(h);
+        head.push_str("\r\n");
+    }
+    head.push_str("\r\n");
+    request(proxy, head.as_bytes())
+}
+
+/// Sends `head` to `proxy` as it is, returning the status line of the
+/// answer and the socket right behind its head.
+pub fn request(proxy: SocketAddr, head: &[u8]) -> (String, TcpStream) {
+    let mut sock = TcpStream::connect(proxy).unwrap();
+    sock.set_read_timeout(Some(WAIT)).unwrap();
+    sock.write_all(head).unwrap();
+    let status = read_head(&mut sock).unwrap_or_default();
+    (status.lines().next().unwrap_or_default().to_owned(), sock)
+}
+
+/// Reads a response head byte by byte, so nothing behind it is taken.
+pub fn read_head(sock: &mut impl Read) -> io::Result<String> {
+    let mut head = Vec::new();
+    let mut b = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if sock.read(&mut b)? == 0 {
+            break;
+        }
+        head.push(b[0]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+/// Writes `data` to a tunnel and reads as many bytes back.
+pub fn echo_through(sock: &mut TcpStream, data: &[u8]) -> Vec<u8> {
+    sock.write_all(data).unwrap();
+    let mut back = vec![0u8; data.len()];
+    sock.read_exact(&mut back).unwrap();
+    back
+}
+
+/// A TCP echo server on a free loopback port, one thread per client.
+pub fn echo_server() -> SocketAddr {
+    echo_server_on("127.0.0.1:0")
+}
+
+/// `echo_server` bound to `bind`.
+pub fn echo_server_on(bind: &str) -> SocketAddr {
+    serve_on(bind, |mut sock| {
+        let mut buf = [0u8; 16 * 1024];
+        loop {
+            match sock.read(&mut buf) {
+                Ok(0) | Err(_) => return,
+                Ok(n) => {
+                    if sock.write_all(&buf[..n]).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// A server on a free loopback port running `handle` for each client on
+/// a thread of its own.
+pub fn serve(handle: impl Fn(TcpStream) + Send + Sync + Clone + 'static) -> SocketAddr {
+    serve_on("127.0.0.1:0", handle)
+}
+
+/// `serve` bound to `bind`.
+pub fn serve_on(bind: &str, handle: impl Fn(TcpStream) + Send + Sync + Clone + 'static) -> SocketAddr {
+    let listener = TcpListener::bind(bind).unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for sock in listener.incoming().flatten() {
+            let handle = handle.clone();
+            thread::spawn(move || handle(sock));
+        }
+    });
+    addr
+}
+
+/// Reads until the peer closes (or resets) the socket, true if it did
+/// within `WAIT`.
+pub fn closed(sock: &mut TcpStream) -> bool {
+    sock.set_read_timeout(Some(WAIT)).unwrap();
+    let mut buf = [0u8; 1024];
+    loop {
+        match sock.read(&mut buf) {
+            Ok(0) => return true,
+            Ok(_) => continue,
+            Err(e) if e.kind() == io::ErrorKind::ConnectionReset => return true,
+            Err(_) => return false,
+        }
+    }
+}
+
+pub fn half_close(sock: &TcpStream) {
+    sock.shutdown(Shutdown::Write).unwrap();
+}
+
+/// Whether `path` exists, waiting up to `WAIT` for it to.
+pub fn wait_path(path: &Path) -> bool {
+    let deadline = Instant::now() + WAIT;
+    while !path.exists() {
+        if Instant::now() > deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    true
+}
@@ -0,0 +1,119 @@
+//! HTTP CONNECT tunnels and the destination checks before them.
+
+mod common;
+
+use std::{
+    fs,
+    io::{Read, Write},
+    net::TcpStream,
+};
+
+use common::{echo_server, echo_through, Proxy};
+
+#[test]
+fn connect_tunnels_whatever_the_port() {
+    let proxy = Proxy::start("");
+    let echo = echo_server();
+    // the echo server sits on an ephemeral port, far from 443
+    let mut sock = proxy.tunnel(&echo.to_string());
+    assert_eq!(echo_through(&mut sock, b"hello"), b"hello");
+}
+
+#[test]
+fn plain_request_to_port_443_is_forwarded() {
+    // a port below 1024 needs root, or CAP_NET_BIND_SERVICE
+    let Ok(listener) = std::net::TcpListener::bind("127.0.0.44:443") else {
+        eprintln!("cannot bind port 443, skipped");
+        return;
+    };
+    let origin = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let (mut sock, _) = listener.accept().unwrap();
+        let head = common::read_head(&mut sock).unwrap();
+        let line = head.lines().next().unwrap().to_owned();
+        let body = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", line.len(), line);
+        sock.write_all(body.as_bytes()).unwrap();
+    });
+    let proxy = Proxy::start("");
+    let mut sock = TcpStream::connect(proxy.addr).unwrap();
+    sock.set_read_timeout(Some(common::WAIT)).unwrap();
+    let request = format!("GET http://{}/x HTTP/1.1\r\nHost: {}\r\n\r\n", origin, origin);
+    sock.write_all(request.as_bytes()).unwrap();
+    let mut answer = String::new();
+    let _ = sock.read_to_string(&mut answer);
+    // the origin saw the request, not a tunnel's 200 first
+    assert!(answer.starts_with("HTTP/1.1 200 OK"), "{}", answer);
+    assert!(answer.ends_with(&format!("GET http://{}/x HTTP/1.1", origin)), "{}", answer);
+}
+
+#[test]
+fn allowed_ports_refuse_others_with_403() {
+    let echo = echo_server();
+    let config = format!("allowed_ports = \"443, {}\"\n", echo.port());
+    let proxy = Proxy::start(&config);
+    let mut sock = proxy.tunnel(&echo.to_string());
+    assert_eq!(echo_through(&mut sock, b"ok"), b"ok");
+    let (status, _) = proxy.connect(&format!("127.0.0.1:{}", common::free_port()));
+    assert!(status.starts_with("HTTP/1.1 403"), "{}", status);
+}
+
+#[test]
+fn block_internal_refuses_internal_literals() {
+    let echo = echo_server();
+    let proxy = Proxy::start("block_internal = true\n");
+    let port = echo.port();
+    // loopback itself, as IPv4-mapped, NAT64 and 6to4 addresses
+    for target in [
+        format!("127.0.0.1:{}", port),
+        format!("[::ffff:127.0.0.1]:{}", port),
+        format!("[64:ff9b::7f00:1]:{}", port),
+        format!("[2002:7f00:1::]:{}", port),
+    ] {
+        let (status, _) = proxy.connect(&target);
+        assert!(status.starts_with("HTTP/1.1 403"), "{}: {}", target, status);
+    }
+}
+
+#[test]
+fn allow_internal_overrides_block_internal() {
+    let echo = echo_server();
+    let proxy = Proxy::start("block_internal = true\nallow_internal = [\"127.0.0.0/8\"]\n");
+    let mut sock = proxy.tunnel(&echo.to_string());
+    assert_eq!(echo_through(&mut sock, b"in"), b"in");
+    let (status, _) = proxy.connect(&format!("[::1]:{}", echo.port()));
+    assert!(status.starts_with("HTTP/1.1 403"), "{}", status);
+}
+
+#[test]
+fn host_check_enforce() {
+    let echo = echo_server();
+    let proxy = Proxy::start("host_check = \"enforce\"\n");
+    let status = |head: String| common::request(proxy.addr, head.as_bytes()).0;
+    let ok = status(format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", echo, echo));
+    assert!(ok.starts_with("HTTP/1.1 200"), "{}", ok);
+    let missing = status(format!("CONNECT {} HTTP/1.1\r\n\r\n", echo));
+    assert!(missing.starts_with("HTTP/1.1 200"), "{}", missing);
+    let other = status(format!("CONNECT {} HTTP/1.1\r\nHost: example.com:{}\r\n\r\n", echo, echo.port()));
+    assert!(other.starts_with("HTTP/1.1 400"), "{}", other);
+    let port = status(format!("CONNECT {} HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n", echo));
+    assert!(port.starts_with("HTTP/1.1 400"), "{}", port);
+}
+
+#[test]
+fn host_check_reads_the_header_in_any_case_and_refuses_two() {
+    let echo = echo_server();
+    let proxy = Proxy::start("host_check = \"enforce\"\naudit_log = \"audit.log\"\n");
+    let status = |head: String| common::request(proxy.addr, head.as_bytes()).0;
+    let lower = status(format!("CONNECT {} HTTP/1.1\r\nhost: example.com:{}\r\n\r\n", echo, echo.port()));
+    assert!(lower.starts_with("HTTP/1.1 400"), "{}", lower);
+    let upper = status(format!("CONNECT {} HTTP/1.1\r\nHOST: {}\r\n\r\n", echo, echo));
+    assert!(upper.starts_with("HTTP/1.1 200"), "{}", upper);
+    // the same twice is no better, the second may have been smuggled in
+    for second in [echo.to_string(), format!("example.com:{}", echo.port())] {
+        let two = status(format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\nhost: {}\r\n\r\n", echo, echo, second));
+        assert!(two.starts_with("HTTP/1.1 400"), "{}: {}", second, two);
+    }
+    let audit = proxy.dir.path("audit.log");
+    assert!(common::wait_path(&audit));
+    assert_eq!(fs::read_to_string(audit).unwrap().matches("host_check:duplicate").count(), 2);
+}
@@ -0,0 +1,77 @@
+//! `--daemon`: the process started returns at once and a detached child
+//! serves.
+
+mod common;
+
+use std::{
+    fs,
+    net::SocketAddr,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{echo_server, echo_through, free_port, spawn, wait_path, Scratch, WAIT};
+
+/// The daemonized process, killed with the test.
+struct Daemon(String);
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = Command::new("kill").args(["-s", "KILL", &self.0]).stderr(Stdio::null()).status();
+    }
+}
+
+fn alive(pid: &str) -> bool {
+    Command::new("kill").args(["-0", pid]).stderr(Stdio::null()).status().unwrap().success()
+}
+
+#[test]
+fn the_parent_returns_and_the_child_serves() {
+    let dir = Scratch::new();
+    let addr: SocketAddr = format!("127.0.0.1:{}", free_port()).parse().unwrap();
+    let config = format!(
+        "listen = [\"{}\"]\nworkers = 1\nblock_internal = false\nlog_file = \"{}\"\npidfile = \"{}\"\n",
+        addr,
+        dir.path("daemon.log").display(),
+        dir.path("pid").display()
+    );
+    fs::write(dir.path("proxy.toml"), config).unwrap();
+    let started = Instant::now();
+    let mut parent = spawn(&dir, &["--daemon"]);
+    let status = parent.wait().unwrap();
+    assert!(status.success(), "{}", fs::read_to_string(dir.path("proxy.log")).unwrap_or_default());
+    assert!(started.elapsed() < Duration::from_secs(5), "took {:?}", started.elapsed());
+
+    assert!(wait_path(&dir.path("pid")), "no pidfile");
+    // written whole, but maybe not yet when it first shows up
+    let deadline = Instant::now() + WAIT;
+    let pid = loop {
+        let pid = fs::read_to_string(dir.path("pid")).unwrap().trim().to_owned();
+        if !pid.is_empty() {
+            break pid;
+        }
+        assert!(Instant::now() < deadline, "empty pidfile");
+        thread::sleep(Duration::from_millis(20));
+    };
+    let daemon = Daemon(pid.clone());
+    assert_ne!(pid, parent.id().to_string());
+    assert!(alive(&pid));
+    // detached from where it was started
+    assert_eq!(fs::read_link(format!("/proc/{}/cwd", pid)).unwrap(), std::path::Path::new("/"));
+
+    let echo = echo_server();
+    let (status, mut sock) = common::connect_via(addr, &echo.to_string(), &[]);
+    assert!(status.starts_with("HTTP/1.1 200"), "{}", status);
+    assert_eq!(echo_through(&mut sock, b"detached"), b"detached");
+    drop(sock);
+
+    // a SIGTERM has it exit and take its pidfile along
+    Command::new("kill").args(["-s", "TERM", &pid]).status().unwrap();
+    let deadline = Instant::now() + WAIT;
+    while alive(&pid) || dir.path("pid").exists() {
+        assert!(Instant::now() < deadline, "daemon still up:\n{}", fs::read_to_string(dir.path("daemon.log")).unwrap());
+        thread::sleep(Duration::from_millis(20));
+    }
+    drop(daemon);
+}
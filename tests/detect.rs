@@ -0,0 +1,105 @@
+//! Listeners with `protocol = "auto"` telling clients apart by their
+//! first bytes, however slowly they come.
+
+mod common;
+
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    thread,
+    time::Duration,
+};
+
+use common::{echo_server, echo_through, Proxy, WAIT};
+
+fn auto_listener() -> Proxy {
+    Proxy::start("socks = true\n[[listener]]\naddress = \"{addr}\"\nprotocol = \"auto\"\n")
+}
+
+/// Writes `bytes` one at a time with a pause after each, so every one
+/// arrives as a readiness event of its own.
+fn trickle(sock: &mut TcpStream, bytes: &[u8]) {
+    sock.set_nodelay(true).unwrap();
+    for b in bytes {
+        sock.write_all(&[*b]).unwrap();
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+fn socks5_connect(echo: std::net::SocketAddr) -> Vec<u8> {
+    let std::net::IpAddr::V4(ip) = echo.ip() else { unreachable!() };
+    let mut request = vec![5, 1, 0, 5, 1, 0, 1];
+    request.extend_from_slice(&ip.octets());
+    request.extend_from_slice(&echo.port().to_be_bytes());
+    request
+}
+
+#[test]
+fn http_one_byte_at_a_time() {
+    let proxy = auto_listener();
+    let echo = echo_server();
+    let mut sock = TcpStream::connect(proxy.addr).unwrap();
+    sock.set_read_timeout(Some(WAIT)).unwrap();
+    trickle(&mut sock, format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", echo, echo).as_bytes());
+    let head = common::read_head(&mut sock).unwrap();
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    assert_eq!(echo_through(&mut sock, b"slow"), b"slow");
+}
+
+#[test]
+fn socks5_one_byte_at_a_time() {
+    let proxy = auto_listener();
+    let echo = echo_server();
+    let mut sock = TcpStream::connect(proxy.addr).unwrap();
+    sock.set_read_timeout(Some(WAIT)).unwrap();
+    let request = socks5_connect(echo);
+    trickle(&mut sock, &request[..3]);
+    let mut choice = [0u8; 2];
+    sock.read_exact(&mut choice).unwrap();
+    assert_eq!(choice, [5, 0]);
+    trickle(&mut sock, &request[3..]);
+    let mut reply = [0u8; 10];
+    sock.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[..2], [5, 0]);
+    assert_eq!(echo_through(&mut sock, b"socks"), b"socks");
+}
+
+#[test]
+fn socks4a_one_byte_at_a_time() {
+    let proxy = auto_listener();
+    let echo = echo_server();
+    let mut sock = TcpStream::connect(proxy.addr).unwrap();
+    sock.set_read_timeout(Some(WAIT)).unwrap();
+    // 0.0.0.1 says the host name follows the user id
+    let mut request = vec![4, 1];
+    request.extend_from_slice(&echo.port().to_be_bytes());
+    request.extend_from_slice(&[0, 0, 0, 1]);
+    request.extend_from_slice(b"me\0localhost\0");
+    trickle(&mut sock, &request);
+    let mut reply = [0u8; 8];
+    sock.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[1], 90, "{:?}", reply);
+    assert_eq!(echo_through(&mut sock, b"four"), b"four");
+}
+
+#[test]
+fn tls_and_proxy_headers_are_refused_with_a_reason() {
+    let proxy = auto_listener();
+    let clients: [(&[u8], &str); 3] = [
+        (b"\x16\x03\x01\x00\x05hello", "https:// by mistake"),
+        (b"PROXY TCP4 10.0.0.1 10.0.0.2 5555 7788\r\n", "proxy_protocol missing"),
+        (b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x00", "proxy_protocol missing"),
+    ];
+    for (first, why) in clients {
+        let mut sock = TcpStream::connect(proxy.addr).unwrap();
+        // the proxy may hang up as soon as it has seen enough
+        for b in first {
+            if sock.write_all(&[*b]).is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(common::closed(&mut sock), "{:?} left open", first);
+        proxy.wait_log(why);
+    }
+}
@@ -0,0 +1,62 @@
+//! Readiness that comes all at once: data, the peer's reset and room to
+//! write in one event, which must close the session exactly once.
+
+mod common;
+
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{echo_server, echo_through, serve, Proxy, WAIT};
+use socket2::SockRef;
+
+/// Drops `sock` with a reset rather than a FIN.
+fn reset(sock: TcpStream) {
+    SockRef::from(&sock).set_linger(Some(Duration::ZERO)).unwrap();
+}
+
+fn wait_closed(proxy: &Proxy, n: usize) {
+    let deadline = Instant::now() + WAIT;
+    while proxy.log().matches("session closed").count() < n {
+        assert!(Instant::now() < deadline, "not all {} sessions closed:\n{}", n, proxy.log());
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn data_and_reset_together_close_once() {
+    let proxy = Proxy::start("");
+    // each upstream resets as soon as it got something, while the client
+    // resets right behind what it sent
+    let resetting = serve(|mut sock| {
+        let mut buf = [0u8; 64];
+        let _ = sock.read(&mut buf);
+        let _ = sock.write_all(b"parting words");
+        reset(sock);
+    });
+    let rounds = 100;
+    for _ in 0..rounds {
+        let mut sock = proxy.tunnel(&resetting.to_string());
+        sock.set_nodelay(true).unwrap();
+        sock.write_all(b"hello").unwrap();
+        reset(sock);
+    }
+    wait_closed(&proxy, rounds);
+    let log = proxy.log();
+    assert!(!log.contains("deregister"), "{}", log);
+    let mut ids = log
+        .lines()
+        .filter_map(|l| l.split("session closed session=").nth(1))
+        .map(|rest| rest.split(' ').next().unwrap())
+        .collect::<Vec<_>>();
+    let all = ids.len();
+    ids.sort_unstable();
+    ids.dedup();
+    assert_eq!(ids.len(), all, "a session closed twice:\n{}", log);
+    // the loop is none the worse for it
+    let mut sock = proxy.tunnel(&echo_server().to_string());
+    assert_eq!(echo_through(&mut sock, b"fine"), b"fine");
+}
@@ -0,0 +1,26 @@
+//! SIGUSR1 getting through the command channel to the worker, the first
+//! consumer of that channel.
+
+mod common;
+
+use common::{echo_server, echo_through, Proxy};
+
+#[test]
+fn sigusr1_dumps_the_open_sessions() {
+    let proxy = Proxy::start("");
+    let echo = echo_server();
+    let mut first = proxy.tunnel(&echo.to_string());
+    let second = proxy.tunnel(&echo.to_string());
+    proxy.signal("USR1");
+    proxy.wait_log("worker 0 session dump, 2 sessions");
+    proxy.wait_log("worker 0 session dump done");
+    let dumped = proxy.log().lines().filter(|l| l.contains("state Piping")).count();
+    assert_eq!(dumped, 2, "{}", proxy.log());
+
+    // a session gone before the next dump is left out of it
+    drop(second);
+    proxy.wait_log("reason=client-closed");
+    proxy.signal("USR1");
+    proxy.wait_log("worker 0 session dump, 1 sessions");
+    assert_eq!(echo_through(&mut first, b"still up"), b"still up");
+}
@@ -0,0 +1,125 @@
+//! Data patterns that would leave a session stuck if a handler stopped
+//! before draining its socket, under edge-triggered readiness.
+
+mod common;
+
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use common::{echo_server, echo_through, serve, Proxy, WAIT};
+
+/// A pattern that is easy to check but makes misplaced bytes show.
+fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+#[test]
+fn two_messages_between_polls() {
+    let proxy = Proxy::start("");
+    let echo = echo_server();
+    let mut sock = proxy.tunnel(&echo.to_string());
+    sock.set_nodelay(true).unwrap();
+    for _ in 0..50 {
+        // both in before the proxy wakes up, one edge for the two
+        sock.write_all(b"first ").unwrap();
+        sock.write_all(b"second").unwrap();
+        let mut back = [0u8; 12];
+        sock.read_exact(&mut back).unwrap();
+        assert_eq!(&back, b"first second");
+    }
+}
+
+#[test]
+fn more_than_the_budget_in_one_go() {
+    // a budget far below what arrives, so every direction has to queue
+    // itself again with bytes still in the socket
+    let proxy = Proxy::start("pipe_budget = 4096\n");
+    let echo = echo_server();
+    let mut sock = proxy.tunnel(&echo.to_string());
+    let data = payload(4 << 20);
+    let mut writer = sock.try_clone().unwrap();
+    let sent = data.clone();
+    let write = thread::spawn(move || writer.write_all(&sent).unwrap());
+    let mut back = vec![0u8; data.len()];
+    sock.read_exact(&mut back).unwrap();
+    write.join().unwrap();
+    assert!(back == data, "echoed bytes differ");
+}
+
+#[test]
+fn bytes_and_close_in_one_edge() {
+    let proxy = Proxy::start("");
+    // tells how many bytes came before the proxy closed its side
+    let (counts, counted) = mpsc::channel();
+    let counter = serve(move |mut sock| {
+        let mut all = Vec::new();
+        let _ = sock.read_to_end(&mut all);
+        let _ = counts.clone().send(all.len());
+    });
+    for len in [1, 100, 70_000] {
+        let mut sock = proxy.tunnel(&counter.to_string());
+        sock.write_all(&payload(len)).unwrap();
+        // the client goes away right behind its last bytes
+        drop(sock);
+        assert_eq!(counted.recv_timeout(WAIT).unwrap(), len);
+    }
+}
+
+#[test]
+fn a_reader_that_comes_back_late() {
+    let proxy = Proxy::start("");
+    let len = 8 << 20;
+    // far more than the socket buffers hold, the proxy has to wait for
+    // the client to drain them and pick up where it stopped
+    let source = serve(move |mut sock| {
+        let _ = sock.write_all(&payload(len));
+    });
+    let mut sock = proxy.tunnel(&source.to_string());
+    thread::sleep(Duration::from_millis(500));
+    let mut back = Vec::with_capacity(len);
+    sock.set_read_timeout(Some(WAIT)).unwrap();
+    sock.read_to_end(&mut back).unwrap();
+    assert_eq!(back.len(), len);
+    assert!(back == payload(len), "bytes differ");
+    // the tunnel itself still works the other way
+    let mut sock = proxy.tunnel(&echo_server().to_string());
+    assert_eq!(echo_through(&mut sock, b"after"), b"after");
+}
+
+#[test]
+fn a_forwarded_head_larger_than_the_upstream_takes() {
+    let proxy = Proxy::start("");
+    // a small receive buffer the accepted socket inherits, an upstream
+    // that reads nothing for a while, and a head of 8MB, more than the
+    // buffers on the way hold: the head goes up in pieces, on writable
+    // edges
+    let listener = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+    listener.set_recv_buffer_size(4096).unwrap();
+    listener.bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap().into()).unwrap();
+    listener.listen(8).unwrap();
+    let listener = TcpListener::from(listener);
+    let origin = listener.local_addr().unwrap();
+    let filler: String = (0..8 << 20).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+    let head = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\nX-Filler: {}\r\n\r\n", origin, origin, filler);
+    let expected = head.clone().into_bytes();
+    let upstream = thread::spawn(move || {
+        let (mut sock, _) = listener.accept().unwrap();
+        thread::sleep(Duration::from_millis(500));
+        sock.set_read_timeout(Some(WAIT)).unwrap();
+        let mut got = vec![0u8; expected.len()];
+        sock.read_exact(&mut got).unwrap();
+        sock.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok").unwrap();
+        got == expected
+    });
+    let (status, mut sock) = common::request(proxy.addr, head.as_bytes());
+    assert!(status.starts_with("HTTP/1.1 200"), "{}", status);
+    let mut body = String::new();
+    let _ = sock.read_to_string(&mut body);
+    assert_eq!(body, "ok");
+    assert!(upstream.join().unwrap(), "the head reached the upstream cut or out of order");
+}
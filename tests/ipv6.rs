@@ -0,0 +1,64 @@
+//! IPv6 listeners, dual-stack or v6-only, and IPv6 clients end to end.
+
+mod common;
+
+use std::{
+    fs,
+    net::{SocketAddr, TcpStream},
+    process::Command,
+};
+
+use common::{echo_server, echo_server_on, echo_through, free_port, Proxy, Scratch};
+
+fn on(addr: &str, config: &str) -> Proxy {
+    let addr: SocketAddr = addr.replace("PORT", &free_port().to_string()).parse().unwrap();
+    Proxy::start_on(addr, config, &[])
+}
+
+#[test]
+fn an_ipv6_client_tunnels_to_an_ipv6_destination() {
+    let proxy = on("[::1]:PORT", "");
+    let echo = echo_server_on("[::1]:0");
+    let mut sock = proxy.tunnel(&echo.to_string());
+    assert_eq!(echo_through(&mut sock, b"six"), b"six");
+    proxy.wait_log("client=[::1]:");
+}
+
+#[test]
+fn dual_stack_takes_ipv4_clients_as_plain_ipv4() {
+    let proxy = on("[::]:PORT", "ipv6_only = false\n");
+    let echo = echo_server();
+    let port = proxy.addr.port();
+    for client in [format!("127.0.0.1:{}", port), format!("[::1]:{}", port)] {
+        let (status, mut sock) = common::connect_via(client.parse().unwrap(), &echo.to_string(), &[]);
+        assert!(status.starts_with("HTTP/1.1 200"), "over {}: {}", client, status);
+        assert_eq!(echo_through(&mut sock, b"both"), b"both");
+    }
+    proxy.wait_log("client=127.0.0.1:");
+    assert!(!proxy.log().contains("::ffff:"), "{}", proxy.log());
+}
+
+#[test]
+fn v6_only_leaves_ipv4_clients_out() {
+    let proxy = on("[::]:PORT", "ipv6_only = true\n");
+    let port = proxy.addr.port();
+    assert!(TcpStream::connect(format!("[::1]:{}", port)).is_ok());
+    assert!(TcpStream::connect(format!("127.0.0.1:{}", port)).is_err());
+}
+
+#[test]
+fn a_dual_stack_wildcard_and_its_ipv4_twin_are_refused() {
+    let dir = Scratch::new();
+    let port = free_port();
+    let config = format!("listen = [\"[::]:{}\", \"0.0.0.0:{}\"]\nipv6_only = false\n", port, port);
+    fs::write(dir.path("proxy.toml"), config).unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_thin_proxy"))
+        .arg("--config")
+        .arg(dir.path("proxy.toml"))
+        .arg("--check-config")
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    let both = String::from_utf8_lossy(&out.stdout) + String::from_utf8_lossy(&out.stderr);
+    assert!(both.contains("ipv6_only"), "{}", both);
+}
@@ -0,0 +1,78 @@
+//! Round-trip latency through a tunnel under the placement and polling
+//! options meant to lower it.
+
+mod common;
+
+use std::{
+    io::{Read, Write},
+    time::{Duration, Instant},
+};
+
+use common::{echo_server, echo_through, Proxy};
+
+/// Round trips of `size` bytes through a tunnel to an echo server, each
+/// one timed, after a warm-up; sorted.
+fn round_trips(proxy: &Proxy, n: usize, size: usize) -> Vec<Duration> {
+    let mut sock = proxy.tunnel(&echo_server().to_string());
+    sock.set_nodelay(true).unwrap();
+    let data = vec![7u8; size];
+    let mut back = vec![0u8; size];
+    let mut took = Vec::with_capacity(n);
+    for i in 0..n + n / 10 {
+        let start = Instant::now();
+        sock.write_all(&data).unwrap();
+        sock.read_exact(&mut back).unwrap();
+        if i >= n / 10 {
+            took.push(start.elapsed());
+        }
+    }
+    took.sort();
+    took
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    sorted[((sorted.len() as f64 * p) as usize).min(sorted.len() - 1)]
+}
+
+/// The echo-through-tunnel benchmark: p50, p99 and p99.9 of 64-byte round
+/// trips for each setting.
+/// `cargo test --release --test latency -- --ignored --nocapture`
+#[test]
+#[ignore]
+fn bench_echo_through_tunnel() {
+    let settings = [
+        ("block, affinity off", ""),
+        ("block, affinity auto", "worker_affinity = \"auto\"\n"),
+        ("spin", "poll_mode = \"spin\"\n"),
+        ("spin-yield", "poll_mode = \"spin-yield\"\n"),
+    ];
+    for (name, config) in settings {
+        // a debug line per event would be most of what is measured
+        let proxy = Proxy::start_with(config, &["--log-level", "warn"]);
+        let took = round_trips(&proxy, 20_000, 64);
+        println!(
+            "{:<24} p50 {:>9.1?} p99 {:>9.1?} p99.9 {:>9.1?}",
+            name,
+            percentile(&took, 0.5),
+            percentile(&took, 0.99),
+            percentile(&took, 0.999)
+        );
+    }
+}
+
+#[test]
+fn timers_fire_with_busy_poll() {
+    for mode in ["spin", "spin-yield"] {
+        // the loop never sleeps in the poll, deadlines are its to check
+        let proxy = Proxy::start(&format!("poll_mode = \"{}\"\n[timeouts]\nidle = \"1s\"\n", mode));
+        proxy.wait_log("poll Spin");
+        let mut sock = proxy.tunnel(&echo_server().to_string());
+        assert_eq!(echo_through(&mut sock, b"awake"), b"awake");
+        let start = Instant::now();
+        assert!(common::closed(&mut sock), "{}: the idle tunnel was never closed", mode);
+        let took = start.elapsed();
+        assert!(took >= Duration::from_millis(900), "{}: closed after {:?}", mode, took);
+        assert!(took < Duration::from_secs(3), "{}: closed after {:?}", mode, took);
+        proxy.wait_log("reason=idle-timeout");
+    }
+}
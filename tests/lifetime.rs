@@ -0,0 +1,80 @@
+//! The cap on how long a session lives, busy or not.
+
+mod common;
+
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{connect_via, echo_server, echo_through, free_port, Proxy};
+
+/// Echoes through `sock` every 50ms until the proxy closes it, returning
+/// how long that took.
+fn keep_busy(mut sock: TcpStream) -> Duration {
+    let start = Instant::now();
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut back = [0u8; 4];
+    loop {
+        let closed = sock.write_all(b"busy").is_err()
+            || match sock.read_exact(&mut back) {
+                Ok(()) => false,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => panic!("no echo nor close in 5s"),
+                Err(_) => true,
+            };
+        if closed {
+            return start.elapsed();
+        }
+        assert!(start.elapsed() < Duration::from_secs(10), "never closed");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn a_busy_tunnel_is_closed_at_its_lifetime() {
+    let proxy = Proxy::start("[timeouts]\nlifetime = \"1s\"\n");
+    let took = keep_busy(proxy.tunnel(&echo_server().to_string()));
+    assert!(took >= Duration::from_millis(900), "closed after {:?}", took);
+    assert!(took < Duration::from_secs(3), "closed after {:?}", took);
+    proxy.wait_log("reason=lifetime-exceeded");
+}
+
+#[test]
+fn waiting_on_the_head_does_not_count() {
+    let proxy = Proxy::start("[timeouts]\nlifetime = \"1s\"\n");
+    let echo = echo_server();
+    let mut sock = TcpStream::connect(proxy.addr).unwrap();
+    // longer than the lifetime, before the session is established
+    thread::sleep(Duration::from_millis(1500));
+    sock.write_all(format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", echo, echo).as_bytes()).unwrap();
+    sock.set_read_timeout(Some(common::WAIT)).unwrap();
+    let head = common::read_head(&mut sock).unwrap();
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    let took = keep_busy(sock);
+    assert!(took >= Duration::from_millis(900), "closed after {:?}", took);
+}
+
+#[test]
+fn profiles_set_their_own() {
+    let public: SocketAddr = format!("127.0.0.1:{}", free_port()).parse().unwrap();
+    let config = format!(
+        "[[listener]]\naddress = \"{{addr}}\"\nprofile = \"internal\"\n\
+         [[listener]]\naddress = \"{}\"\nprofile = \"public\"\n\
+         [profile.internal.timeouts]\nlifetime = \"none\"\n\
+         [profile.public.timeouts]\nlifetime = \"1s\"\n\
+         [timeouts]\nlifetime = \"12h\"\n",
+        public
+    );
+    let proxy = Proxy::start(&config);
+    proxy.wait_listening(public);
+    let echo = echo_server();
+    let mut internal = proxy.tunnel(&echo.to_string());
+    let (status, sock) = connect_via(public, &echo.to_string(), &[]);
+    assert!(status.starts_with("HTTP/1.1 200"), "{}", status);
+    let took = keep_busy(sock);
+    assert!(took < Duration::from_secs(3), "closed after {:?}", took);
+    // the internal one, as old by now, lives on
+    assert_eq!(echo_through(&mut internal, b"unlimited"), b"unlimited");
+}
@@ -0,0 +1,85 @@
+//! What the proxy gives up once started: its capabilities, and with
+//! Landlock every file but the configured ones.
+
+mod common;
+
+use std::fs;
+
+use common::{echo_server, echo_through, Proxy, Scratch};
+
+/// The capability sets of `pid` as /proc shows them, CapEff and so on.
+fn caps(pid: u32) -> Vec<(String, u64)> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).unwrap();
+    status
+        .lines()
+        .filter(|l| l.starts_with("Cap"))
+        .map(|l| {
+            let (name, hex) = l.split_once(":\t").unwrap();
+            (name.to_owned(), u64::from_str_radix(hex.trim(), 16).unwrap())
+        })
+        .collect()
+}
+
+fn ours_effective() -> u64 {
+    caps(std::process::id()).into_iter().find(|(n, _)| n == "CapEff").unwrap().1
+}
+
+#[test]
+fn caps_drops_every_capability() {
+    let proxy = Proxy::start("lockdown = \"caps\"\n");
+    proxy.wait_log("lockdown:");
+    // threads are what /proc/<pid>/status speaks for, the workers included
+    for (set, bits) in caps(proxy.child.id()) {
+        assert_eq!(bits, 0, "{} {:#x}\n{}", set, bits, proxy.log());
+    }
+    let mut sock = proxy.tunnel(&echo_server().to_string());
+    assert_eq!(echo_through(&mut sock, b"bare"), b"bare");
+}
+
+#[test]
+fn off_keeps_them() {
+    if ours_effective() == 0 {
+        eprintln!("no capabilities to keep, skipped");
+        return;
+    }
+    let proxy = Proxy::start("lockdown = \"off\"\n");
+    proxy.wait_log("lockdown: off");
+    let effective = caps(proxy.child.id()).into_iter().find(|(n, _)| n == "CapEff").unwrap().1;
+    assert_eq!(effective, ours_effective());
+}
+
+/// Starts a proxy with `lockdown` and has it reload with an auth_file
+/// in a directory of its own, which it never had a reason to read.
+fn reload_an_outside_file(lockdown: &str) -> (Proxy, Scratch) {
+    let outside = Scratch::new();
+    fs::write(outside.path("users"), "# nobody yet\n").unwrap();
+    let proxy = Proxy::start(&format!("lockdown = \"{}\"\n", lockdown));
+    // the landlock line, whichever way it went, comes after the caps one
+    proxy.wait_log(if lockdown == "landlock" { "landlock" } else { "lockdown:" });
+    let mut config = fs::read_to_string(proxy.config_path()).unwrap();
+    config.push_str(&format!("auth_file = \"{}\"\n", outside.path("users").display()));
+    fs::write(proxy.config_path(), config).unwrap();
+    proxy.signal("HUP");
+    (proxy, outside)
+}
+
+#[test]
+fn landlock_keeps_other_paths_out_of_reach() {
+    let (proxy, _outside) = reload_an_outside_file("landlock");
+    if proxy.log().contains("file access is not restricted") {
+        eprintln!("no landlock here, skipped");
+        return;
+    }
+    proxy.wait_log("reload refused");
+    proxy.wait_log("Permission denied");
+    // what it was configured with is still within reach
+    let mut sock = proxy.tunnel(&echo_server().to_string());
+    assert_eq!(echo_through(&mut sock, b"fenced"), b"fenced");
+}
+
+#[test]
+fn without_landlock_the_same_reload_goes_through() {
+    let (proxy, _outside) = reload_an_outside_file("caps");
+    proxy.wait_log("worker 0 reloaded");
+    assert!(!proxy.log().contains("reload refused"), "{}", proxy.log());
+}
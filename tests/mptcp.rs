@@ -0,0 +1,87 @@
+//! upstream_mptcp: upstream connections over multipath TCP where the
+//! kernel and the destination have it, over TCP where not.
+
+mod common;
+
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    thread,
+};
+
+use common::{echo_server, echo_through, Proxy, WAIT};
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// A proxy with upstream_mptcp and an admin endpoint, returned with it.
+fn mptcp_proxy() -> (Proxy, SocketAddr) {
+    let admin: SocketAddr = format!("127.0.0.1:{}", common::free_port()).parse().unwrap();
+    let proxy = Proxy::start(&format!("upstream_mptcp = true\nadmin = \"{}\"\n", admin));
+    proxy.wait_listening(admin);
+    (proxy, admin)
+}
+
+/// The upstream_mptcp_total sample of `result` on the admin endpoint.
+fn connects(admin: SocketAddr, result: &str) -> u64 {
+    let mut sock = TcpStream::connect(admin).unwrap();
+    sock.set_read_timeout(Some(WAIT)).unwrap();
+    sock.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").unwrap();
+    let mut metrics = String::new();
+    sock.read_to_string(&mut metrics).unwrap();
+    let sample = format!("upstream_mptcp_total{{result=\"{}\"}} ", result);
+    let line = metrics.lines().find(|l| l.contains(&sample)).unwrap_or_else(|| panic!("no {}:\n{}", sample, metrics));
+    line.rsplit(' ').next().unwrap().parse().unwrap()
+}
+
+/// An echo server taking MPTCP connections.
+fn mptcp_echo() -> SocketAddr {
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::MPTCP)).unwrap();
+    socket.bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap().into()).unwrap();
+    socket.listen(16).unwrap();
+    let listener = std::net::TcpListener::from(socket);
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for sock in listener.incoming().flatten() {
+            thread::spawn(move || {
+                let (mut from, mut to) = (&sock, &sock);
+                let _ = std::io::copy(&mut from, &mut to);
+            });
+        }
+    });
+    addr
+}
+
+#[test]
+fn without_mptcp_in_the_kernel_upstream_connections_go_over_tcp() {
+    // net.mptcp.enabled is the namespace's own
+    if !common::own_network() || fs::write("/proc/sys/net/mptcp/enabled", "0").is_err() {
+        eprintln!("no network namespace with MPTCP to switch off, skipped");
+        return;
+    }
+    let (proxy, admin) = mptcp_proxy();
+    let echo = echo_server();
+    for data in [&b"first"[..], b"second"] {
+        let mut sock = proxy.tunnel(&echo.to_string());
+        assert_eq!(echo_through(&mut sock, data), data);
+    }
+    // noted once, not for every dial
+    proxy.wait_log("MPTCP unavailable");
+    assert_eq!(proxy.log().matches("MPTCP unavailable").count(), 1, "{}", proxy.log());
+    assert_eq!((connects(admin, "negotiated"), connects(admin, "fallback")), (0, 2));
+}
+
+#[test]
+fn mptcp_is_negotiated_with_a_destination_that_speaks_it() {
+    if fs::read_to_string("/proc/sys/net/mptcp/enabled").map_or(true, |v| v.trim() != "1") {
+        eprintln!("no MPTCP in this kernel, skipped");
+        return;
+    }
+    let (proxy, admin) = mptcp_proxy();
+    let mut sock = proxy.tunnel(&mptcp_echo().to_string());
+    assert_eq!(echo_through(&mut sock, b"multipath"), b"multipath");
+    // a destination without it has the kernel fall back
+    let mut sock = proxy.tunnel(&echo_server().to_string());
+    assert_eq!(echo_through(&mut sock, b"single path"), b"single path");
+    assert_eq!((connects(admin, "negotiated"), connects(admin, "fallback")), (1, 1));
+    assert!(!proxy.log().contains("MPTCP unavailable"));
+}
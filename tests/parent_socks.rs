@@ -0,0 +1,73 @@
+//! Routes `via-socks-parent`, chained through a second proxy taking
+//! SOCKS5 clients.
+
+mod common;
+
+use std::{fs, net::SocketAddr};
+
+use common::{echo_server, echo_through, Proxy, Scratch};
+
+/// A proxy routing localhost through the SOCKS5 parent at `parent`, with
+/// `config` after.
+fn front(parent: SocketAddr, config: &str) -> Proxy {
+    Proxy::start(&format!(
+        "parent_socks = \"{}\"\n{}[[route]]\nhosts = [\"localhost\"]\naction = \"via-socks-parent\"\n",
+        parent, config
+    ))
+}
+
+#[test]
+fn a_tunnel_through_the_parent_names_the_host() {
+    let parent = Proxy::start("");
+    let proxy = front(parent.addr, "");
+    let echo = echo_server();
+    let mut sock = proxy.tunnel(&format!("localhost:{}", echo.port()));
+    assert_eq!(echo_through(&mut sock, b"chained"), b"chained");
+    // in the domain form: the parent did the lookup
+    parent.wait_log(&format!("socks5 CONNECT localhost:{}", echo.port()));
+}
+
+#[test]
+fn credentials_for_the_parent() {
+    let users = Scratch::new();
+    let hash = bcrypt::hash("secret", 4).unwrap();
+    fs::write(users.path("users"), format!("svc:{}\n", hash)).unwrap();
+    let parent = Proxy::start(&format!("auth_file = \"{}\"\n", users.path("users").display()));
+    let echo = echo_server();
+    let target = format!("localhost:{}", echo.port());
+
+    let proxy = front(parent.addr, "parent_socks_user = \"svc\"\nparent_socks_password = \"secret\"\n");
+    let mut sock = proxy.tunnel(&target);
+    assert_eq!(echo_through(&mut sock, b"svc"), b"svc");
+
+    let proxy = front(parent.addr, "parent_socks_user = \"svc\"\nparent_socks_password = \"guess\"\n");
+    let (status, _) = proxy.connect(&target);
+    assert!(status.starts_with("HTTP/1.1 502"), "{}", status);
+    // without any, the parent offers nothing we can take
+    let proxy = front(parent.addr, "");
+    let (status, _) = proxy.connect(&target);
+    assert!(status.starts_with("HTTP/1.1 502"), "{}", status);
+}
+
+#[test]
+fn a_parent_that_fails_is_502() {
+    let parent = Proxy::start("");
+    let proxy = front(parent.addr, "");
+    // the parent cannot reach it
+    let (status, _) = proxy.connect(&format!("localhost:{}", common::free_port()));
+    assert!(status.starts_with("HTTP/1.1 502"), "{}", status);
+    // nor can we reach the parent
+    let gone = format!("127.0.0.1:{}", common::free_port()).parse().unwrap();
+    let proxy = front(gone, "");
+    let (status, _) = proxy.connect(&format!("localhost:{}", echo_server().port()));
+    assert!(status.starts_with("HTTP/1.1 502"), "{}", status);
+}
+
+#[test]
+fn other_destinations_go_direct() {
+    let gone = format!("127.0.0.1:{}", common::free_port()).parse().unwrap();
+    let proxy = front(gone, "");
+    let echo = echo_server();
+    let mut sock = proxy.tunnel(&echo.to_string());
+    assert_eq!(echo_through(&mut sock, b"direct"), b"direct");
+}
@@ -0,0 +1,53 @@
+//! A route's `parents`: new tunnels fail over from a parent that went
+//! away to the next, and come back once the health check finds it up.
+
+mod common;
+
+use std::net::SocketAddr;
+
+use common::{echo_server, echo_through, Proxy};
+
+/// A proxy routing localhost through `parents` in that order, with the
+/// health check asking them for a tunnel to `probe` every 200ms; the
+/// backoff is long enough for only the check to bring a parent back.
+fn front(parents: [SocketAddr; 2], probe: SocketAddr) -> Proxy {
+    Proxy::start(&format!(
+        "parent_backoff = \"10m\"\nparent_probe = \"{}\"\nparent_probe_every = \"200ms\"\n\
+         [[route]]\nhosts = [\"localhost\"]\naction = \"via-parent\"\nparents = [\"{}\", \"{}\"]\n",
+        probe, parents[0], parents[1]
+    ))
+}
+
+/// A tunnel through `proxy` to an echo server of its own, and the
+/// target, which the log of the parent it went through names.
+fn tunnel(proxy: &Proxy) -> (std::net::TcpStream, String) {
+    let target = format!("localhost:{}", echo_server().port());
+    let mut sock = proxy.tunnel(&target);
+    assert_eq!(echo_through(&mut sock, b"through"), b"through");
+    (sock, target)
+}
+
+#[test]
+fn tunnels_fail_over_to_the_second_parent_and_come_back_to_the_first() {
+    let (first, second) = (Proxy::start(""), Proxy::start(""));
+    let first_addr = first.addr;
+    let proxy = front([first.addr, second.addr], echo_server());
+    let (mut open, target) = tunnel(&proxy);
+    first.wait_log(&target);
+    // the first goes away with a tunnel through it open
+    drop(first);
+    assert!(common::closed(&mut open));
+    let (_, target) = tunnel(&proxy);
+    second.wait_log(&target);
+    proxy.wait_log(&format!("parent proxy {} down for 600s", first_addr));
+    // passed over from then on, not failed again
+    let (_, target) = tunnel(&proxy);
+    second.wait_log(&target);
+    assert_eq!(proxy.log().matches(&format!("parent proxy {} down", first_addr)).count(), 1, "{}", proxy.log());
+    // back where it was, the check finds it up long before the backoff
+    let first = Proxy::start_on(first_addr, "", &[]);
+    proxy.wait_log(&format!("parent proxy {} is back", first_addr));
+    let (_, target) = tunnel(&proxy);
+    first.wait_log(&target);
+    assert!(!second.log().contains(&target));
+}
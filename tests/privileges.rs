@@ -0,0 +1,68 @@
+//! `user`: what the proxy still does with its files once it runs as
+//! someone other than root.
+
+mod common;
+
+use std::{fs, time::Instant};
+
+use common::{echo_server, echo_through, free_port, spawn, Proxy, Scratch, WAIT};
+use nix::unistd::{chown, geteuid, Gid, Uid};
+
+#[test]
+fn the_log_is_reopened_and_rotated_after_dropping_root() {
+    if !geteuid().is_root() {
+        eprintln!("not root, skipped");
+        return;
+    }
+    let files = Scratch::new();
+    let logs = files.path("logs");
+    fs::create_dir(&logs).unwrap();
+    chown(&logs, Some(Uid::from_raw(65534)), Some(Gid::from_raw(65534))).unwrap();
+    // next to the log directory, in one only root may change, like /run
+    let pidfile = files.path("proxy.pid");
+    let mut proxy = Proxy::start(&format!(
+        "user = \"nobody\"\nlog_file = \"{}\"\nlog_max_size = \"4K\"\nlog_keep = 2\npidfile = \"{}\"\n",
+        logs.join("proxy.log").display(),
+        pidfile.display()
+    ));
+    // the file root created at startup
+    proxy.signal("USR1");
+    let echo = echo_server();
+    let deadline = Instant::now() + WAIT;
+    while !logs.join("proxy.log.1").exists() {
+        assert!(Instant::now() < deadline, "never rotated:\n{}", proxy.log());
+        let mut sock = proxy.tunnel(&echo.to_string());
+        assert_eq!(echo_through(&mut sock, b"rotate"), b"rotate");
+    }
+    proxy.signal("TERM");
+    assert!(proxy.child.wait().unwrap().success(), "{}", proxy.log());
+
+    let written: String = ["proxy.log", "proxy.log.1", "proxy.log.2"]
+        .iter()
+        .map(|name| fs::read_to_string(logs.join(name)).unwrap_or_default())
+        .collect();
+    assert!(written.contains("privileges: dropped to nobody"), "{}", written);
+    assert!(written.contains("log file reopened"), "{}", written);
+    assert!(!proxy.log().contains("rotate log"), "{}", proxy.log());
+    // it could not be removed, but names no one
+    assert_eq!(fs::read_to_string(&pidfile).unwrap(), "");
+}
+
+#[test]
+fn rotating_needs_a_log_directory_the_user_can_write() {
+    if !geteuid().is_root() {
+        eprintln!("not root, skipped");
+        return;
+    }
+    let dir = Scratch::new();
+    let config = format!(
+        "listen = [\"127.0.0.1:{}\"]\nuser = \"nobody\"\nlog_file = \"{}\"\nlog_max_size = \"1M\"\n",
+        free_port(),
+        dir.path("out.log").display()
+    );
+    fs::write(dir.path("proxy.toml"), config).unwrap();
+    let status = spawn(&dir, &[]).wait().unwrap();
+    let log = fs::read_to_string(dir.path("proxy.log")).unwrap();
+    assert_eq!(status.code(), Some(78), "{}", log);
+    assert!(log.contains("where user \"nobody\" cannot create files"), "{}", log);
+}
@@ -0,0 +1,99 @@
+//! The PROXY protocol v2 header `proxy_protocol` routes send upstream,
+//! read by an origin that echoes the addresses in it.
+
+mod common;
+
+use std::{
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream},
+    process::Command,
+};
+
+use common::{echo_through, Proxy};
+
+/// An origin that reads a v2 header of the INET family and answers with
+/// the source and destination in it, `source destination\n`, then echoes.
+fn origin() -> SocketAddr {
+    common::serve(|mut sock| {
+        let mut fixed = [0u8; 16];
+        sock.read_exact(&mut fixed).unwrap();
+        assert_eq!(&fixed[..12], b"\r\n\r\n\0\r\nQUIT\n");
+        assert_eq!((fixed[12], fixed[13]), (0x21, 0x11), "v2 PROXY, TCP over IPv4");
+        let mut block = vec![0u8; usize::from(u16::from_be_bytes([fixed[14], fixed[15]]))];
+        sock.read_exact(&mut block).unwrap();
+        let at = |ip: &[u8], port: &[u8]| {
+            let ip = Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]);
+            SocketAddr::new(IpAddr::V4(ip), u16::from_be_bytes([port[0], port[1]]))
+        };
+        let source = at(&block[0..4], &block[8..10]);
+        let destination = at(&block[4..8], &block[10..12]);
+        sock.write_all(format!("{} {}\n", source, destination).as_bytes()).unwrap();
+        let mut buf = [0u8; 1024];
+        while let Ok(n @ 1..) = sock.read(&mut buf) {
+            sock.write_all(&buf[..n]).unwrap();
+        }
+    })
+}
+
+fn route(proxy_protocol: &str) -> String {
+    format!("[[route]]\nhosts = [\"localhost\"]\naction = \"direct\"\nproxy_protocol = {}\n", proxy_protocol)
+}
+
+fn read_line(sock: &mut TcpStream) -> String {
+    let mut line = Vec::new();
+    let mut b = [0u8; 1];
+    while sock.read(&mut b).unwrap() == 1 && b[0] != b'\n' {
+        line.push(b[0]);
+    }
+    String::from_utf8(line).unwrap()
+}
+
+#[test]
+fn the_origin_learns_who_connected() {
+    let origin = origin();
+    let proxy = Proxy::start(&route("true"));
+    let mut sock = proxy.tunnel(&format!("localhost:{}", origin.port()));
+    let expected = format!("{} {}", sock.local_addr().unwrap(), proxy.addr);
+    assert_eq!(read_line(&mut sock), expected);
+    // and the tunnel goes on after it
+    assert_eq!(echo_through(&mut sock, b"behind"), b"behind");
+}
+
+#[test]
+fn a_socks_client_too() {
+    let origin = origin();
+    let proxy = Proxy::start(&route("true"));
+    let mut sock = TcpStream::connect(proxy.addr).unwrap();
+    sock.set_read_timeout(Some(common::WAIT)).unwrap();
+    sock.write_all(&[5, 1, 0]).unwrap();
+    let mut choice = [0u8; 2];
+    sock.read_exact(&mut choice).unwrap();
+    let mut request = vec![5, 1, 0, 3, 9];
+    request.extend_from_slice(b"localhost");
+    request.extend_from_slice(&origin.port().to_be_bytes());
+    sock.write_all(&request).unwrap();
+    let mut reply = [0u8; 10];
+    sock.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[1], 0);
+    let expected = format!("{} {}", sock.local_addr().unwrap(), proxy.addr);
+    assert_eq!(read_line(&mut sock), expected);
+}
+
+#[test]
+fn a_route_through_a_parent_cannot_ask_for_it() {
+    let dir = common::Scratch::new();
+    let config = format!(
+        "listen = [\"127.0.0.1:{}\"]\nparent_proxy = \"127.0.0.1:3128\"\n\
+         [[route]]\nhosts = [\"localhost\"]\naction = \"via-parent\"\nproxy_protocol = true\n",
+        common::free_port()
+    );
+    std::fs::write(dir.path("proxy.toml"), config).unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_thin_proxy"))
+        .arg("--config")
+        .arg(dir.path("proxy.toml"))
+        .arg("--check-config")
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("proxy_protocol"), "{:?}", out);
+}
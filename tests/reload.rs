@@ -0,0 +1,50 @@
+//! SIGHUP reloads: new sessions get the new settings, running ones keep
+//! theirs, and a reload that cannot be taken changes nothing.
+
+mod common;
+
+use std::fs;
+
+use common::{echo_server, echo_through, Proxy};
+
+/// Rewrites the proxy's config file with `edit` and has it reload.
+fn reload_with(proxy: &Proxy, edit: impl Fn(String) -> String) {
+    let config = fs::read_to_string(proxy.config_path()).unwrap();
+    fs::write(proxy.config_path(), edit(config)).unwrap();
+    proxy.signal("HUP");
+}
+
+const DENY_LOOPBACK: &str = "[[acl]]\nhosts = [\"127.0.0.1\"]\naction = \"deny\"\n";
+
+#[test]
+fn a_deny_entry_takes_for_the_next_connect_only() {
+    let proxy = Proxy::start("");
+    let echo = echo_server();
+    let mut running = proxy.tunnel(&echo.to_string());
+    reload_with(&proxy, |config| config + DENY_LOOPBACK);
+    proxy.wait_log("worker 0 reloaded");
+    let (status, _) = proxy.connect(&echo.to_string());
+    assert!(status.starts_with("HTTP/1.1 403"), "{}", status);
+    assert_eq!(echo_through(&mut running, b"still"), b"still");
+}
+
+#[test]
+fn a_fixed_setting_refuses_the_whole_reload() {
+    let proxy = Proxy::start("");
+    let echo = echo_server();
+    // the acl entry alone would be taken, with it the reload is not
+    reload_with(&proxy, |config| config.replace("workers = 1", "workers = 2") + DENY_LOOPBACK);
+    proxy.wait_log("cannot change without a restart");
+    let mut sock = proxy.tunnel(&echo.to_string());
+    assert_eq!(echo_through(&mut sock, b"as before"), b"as before");
+}
+
+#[test]
+fn a_broken_file_refuses_the_reload() {
+    let proxy = Proxy::start("");
+    let echo = echo_server();
+    reload_with(&proxy, |config| config + "[[acl\n");
+    proxy.wait_log("reload refused, keep running settings");
+    let mut sock = proxy.tunnel(&echo.to_string());
+    assert_eq!(echo_through(&mut sock, b"as before"), b"as before");
+}
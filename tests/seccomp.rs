@@ -0,0 +1,39 @@
+//! A proxy under its seccomp filter still does its job. Built and run
+//! with `--features seccomp` only.
+#![cfg(feature = "seccomp")]
+
+mod common;
+
+use std::io::{Read, Write};
+
+use common::{echo_server, echo_through, serve, Proxy};
+
+#[test]
+fn tunnels_and_forwards_under_the_filter() {
+    let proxy = Proxy::start("seccomp = true\n");
+    proxy.wait_log("seccomp: filter of");
+    let echo = echo_server();
+    let mut sock = proxy.tunnel(&echo.to_string());
+    assert_eq!(echo_through(&mut sock, b"filtered"), b"filtered");
+
+    let origin = serve(|mut sock| {
+        let _ = common::read_head(&mut sock);
+        let _ = sock.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok");
+    });
+    let head = format!("GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n", origin, origin);
+    let (status, mut sock) = common::request(proxy.addr, head.as_bytes());
+    assert!(status.starts_with("HTTP/1.1 200"), "{}", status);
+    let mut body = String::new();
+    let _ = sock.read_to_string(&mut body);
+    assert_eq!(body, "ok");
+
+    // a reload reads files and swaps settings, all on the list
+    proxy.signal("HUP");
+    proxy.wait_log("worker 0 reloaded");
+    // starting a successor would need execve, so it is not even tried
+    proxy.signal("USR2");
+    proxy.wait_log("seccomp leaves no way to start a successor");
+    let mut sock = proxy.tunnel(&echo.to_string());
+    assert_eq!(echo_through(&mut sock, b"still"), b"still");
+    assert!(!proxy.log().contains("is not allowed, killing the proxy"), "{}", proxy.log());
+}
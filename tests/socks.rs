@@ -0,0 +1,331 @@
+//! SOCKS5 clients on the HTTP listener: the handshake, each address type,
+//! the reply a failed connect maps to, username/password auth against
+//! the auth_file users, and the datagrams of a UDP ASSOCIATE.
+
+mod common;
+
+use std::{
+    io::{ErrorKind, Read, Write},
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, UdpSocket},
+    process::Command,
+    sync::mpsc,
+    thread,
+};
+
+use common::{echo_server, echo_server_on, echo_through, Proxy, Scratch, WAIT};
+
+/// The address of a request: type, then the bytes of it.
+fn address(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => [&[0x01][..], &ip.octets()].concat(),
+        IpAddr::V6(ip) => [&[0x04][..], &ip.octets()].concat(),
+    }
+}
+
+fn name(host: &str) -> Vec<u8> {
+    [&[0x03, host.len() as u8][..], host.as_bytes()].concat()
+}
+
+/// Greets `proxy` offering no authentication and sends `command` for
+/// `addr` and `port`, returning the reply field and the socket right
+/// behind the reply.
+fn request(proxy: &Proxy, command: u8, addr: &[u8], port: u16) -> (u8, TcpStream) {
+    let mut sock = TcpStream::connect(proxy.addr).unwrap();
+    sock.set_read_timeout(Some(WAIT)).unwrap();
+    sock.write_all(&[5, 1, 0]).unwrap();
+    let mut choice = [0u8; 2];
+    sock.read_exact(&mut choice).unwrap();
+    assert_eq!(choice, [5, 0]);
+    sock.write_all(&[&[5, command, 0][..], addr, &port.to_be_bytes()].concat()).unwrap();
+    let mut head = [0u8; 4];
+    sock.read_exact(&mut head).unwrap();
+    assert_eq!((head[0], head[2]), (5, 0), "{:?}", head);
+    // the bound address, as long as its type says
+    let mut bound = vec![0u8; if head[3] == 0x04 { 16 + 2 } else { 4 + 2 }];
+    sock.read_exact(&mut bound).unwrap();
+    (head[1], sock)
+}
+
+fn connect(proxy: &Proxy, addr: &[u8], port: u16) -> (u8, TcpStream) {
+    request(proxy, 1, addr, port)
+}
+
+/// A proxy whose auth_file has alice, password secret, with `config`
+/// after. The file is in a scratch directory of its own, returned with it.
+fn with_alice(config: &str) -> (Proxy, Scratch) {
+    let users = Scratch::new();
+    let hash = bcrypt::hash("secret", 4).unwrap();
+    fs::write(users.path("users"), format!("alice:{}\n", hash)).unwrap();
+    let auth_file = format!("auth_file = \"{}\"\n", users.path("users").display());
+    let logs = "access_log = \"access.log\"\naudit_log = \"audit.log\"\n";
+    let proxy = Proxy::start(&format!("{}{}{}", auth_file, logs, config));
+    (proxy, users)
+}
+
+/// Offers both methods, has username/password picked and sends `user`
+/// and `password`, returning the status and the socket.
+fn log_in(proxy: &Proxy, user: &str, password: &str) -> (u8, TcpStream) {
+    let mut sock = TcpStream::connect(proxy.addr).unwrap();
+    sock.set_read_timeout(Some(WAIT)).unwrap();
+    sock.write_all(&[5, 2, 0, 2]).unwrap();
+    let mut choice = [0u8; 2];
+    sock.read_exact(&mut choice).unwrap();
+    assert_eq!(choice, [5, 2]);
+    let msg = [&[1, user.len() as u8][..], user.as_bytes(), &[password.len() as u8], password.as_bytes()].concat();
+    sock.write_all(&msg).unwrap();
+    let mut status = [0u8; 2];
+    sock.read_exact(&mut status).unwrap();
+    assert_eq!(status[0], 1);
+    (status[1], sock)
+}
+
+#[test]
+fn connect_by_ipv4_ipv6_and_name() {
+    let proxy = Proxy::start("");
+    let echo = echo_server();
+    let (reply, mut sock) = connect(&proxy, &address(echo.ip()), echo.port());
+    assert_eq!(reply, 0);
+    assert_eq!(echo_through(&mut sock, b"four"), b"four");
+
+    let (reply, mut sock) = connect(&proxy, &name("localhost"), echo.port());
+    assert_eq!(reply, 0);
+    assert_eq!(echo_through(&mut sock, b"name"), b"name");
+
+    if std::net::TcpListener::bind("[::1]:0").is_err() {
+        eprintln!("no IPv6 loopback, skipped the IPv6 request");
+        return;
+    }
+    let echo6 = echo_server_on("[::1]:0");
+    let (reply, mut sock) = connect(&proxy, &address(echo6.ip()), echo6.port());
+    assert_eq!(reply, 0);
+    assert_eq!(echo_through(&mut sock, b"six"), b"six");
+}
+
+#[test]
+fn failures_have_their_reply_codes() {
+    let proxy = Proxy::start("");
+    let closed: SocketAddr = format!("127.0.0.1:{}", common::free_port()).parse().unwrap();
+    assert_eq!(connect(&proxy, &address(closed.ip()), closed.port()).0, 5, "connection refused");
+    // .invalid never resolves, RFC 6761
+    assert_eq!(connect(&proxy, &name("nonexistent.invalid"), 80).0, 4, "host unreachable");
+    let echo = echo_server();
+    assert_eq!(request(&proxy, 2, &address(echo.ip()), echo.port()).0, 7, "BIND: command not supported");
+}
+
+#[test]
+fn the_acl_applies_as_to_a_connect() {
+    let echo = echo_server();
+    let proxy = Proxy::start(&format!("allowed_ports = \"{}\"\n", echo.port()));
+    assert_eq!(connect(&proxy, &address(echo.ip()), echo.port()).0, 0);
+    assert_eq!(connect(&proxy, &address(echo.ip()), common::free_port()).0, 2, "not allowed");
+}
+
+#[test]
+fn a_client_offering_nothing_we_take_hears_0xff() {
+    let proxy = Proxy::start("");
+    let mut sock = TcpStream::connect(proxy.addr).unwrap();
+    sock.set_read_timeout(Some(WAIT)).unwrap();
+    // GSSAPI only
+    sock.write_all(&[5, 1, 1]).unwrap();
+    let mut choice = [0u8; 2];
+    sock.read_exact(&mut choice).unwrap();
+    assert_eq!(choice, [5, 0xff]);
+    assert!(common::closed(&mut sock));
+}
+
+#[test]
+fn curl_through_socks5_hostname() {
+    let origin = common::serve(|mut sock| {
+        let _ = common::read_head(&mut sock);
+        let _ = sock.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nsocks");
+    });
+    let proxy = Proxy::start("");
+    let out = Command::new("curl")
+        .args(["-sS", "--max-time", "10", "--socks5-hostname", &proxy.addr.to_string()])
+        .arg(format!("http://localhost:{}/", origin.port()))
+        .output();
+    let Ok(out) = out else {
+        eprintln!("no curl, skipped");
+        return;
+    };
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(out.stdout, b"socks");
+    proxy.wait_log("socks5 CONNECT localhost:");
+}
+
+#[test]
+fn the_right_password_makes_the_session_theirs() {
+    let (mut proxy, _users) = with_alice("");
+    let echo = echo_server();
+    let (status, mut sock) = log_in(&proxy, "alice", "secret");
+    assert_eq!(status, 0);
+    sock.write_all(&[&[5, 1, 0][..], &address(echo.ip()), &echo.port().to_be_bytes()].concat()).unwrap();
+    let mut reply = [0u8; 10];
+    sock.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[1], 0);
+    assert_eq!(echo_through(&mut sock, b"alice"), b"alice");
+    drop(sock);
+    // the access log, flushed at exit, ends its lines with the user
+    proxy.signal("TERM");
+    proxy.child.wait().unwrap();
+    let access = fs::read_to_string(proxy.dir.path("access.log")).unwrap();
+    let line = access.lines().find(|l| l.contains(" SOCKS5 ")).unwrap_or_else(|| panic!("{}", access));
+    assert_eq!(line.split_whitespace().last(), Some("alice"), "{}", line);
+}
+
+#[test]
+fn their_acl_set_applies_to_their_socks_sessions() {
+    let (proxy, _users) = with_alice("[acl_set.nowhere]\ndefault = \"deny\"\n[users.alice]\nacl_set = \"nowhere\"\n");
+    let echo = echo_server();
+    let (status, mut sock) = log_in(&proxy, "alice", "secret");
+    assert_eq!(status, 0);
+    sock.write_all(&[&[5, 1, 0][..], &address(echo.ip()), &echo.port().to_be_bytes()].concat()).unwrap();
+    let mut reply = [0u8; 10];
+    sock.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[1], 2, "not allowed");
+}
+
+#[test]
+fn a_wrong_password_gets_status_1_and_a_hang_up() {
+    let (proxy, _users) = with_alice("");
+    for (user, password) in [("alice", "guess"), ("mallory", "secret")] {
+        let (status, mut sock) = log_in(&proxy, user, password);
+        assert_eq!(status, 1, "{}", user);
+        assert!(common::closed(&mut sock));
+    }
+    let audit = proxy.dir.path("audit.log");
+    assert!(common::wait_path(&audit));
+    assert_eq!(fs::read_to_string(audit).unwrap().matches("auth_file:bad-credentials").count(), 2);
+}
+
+#[test]
+fn a_client_without_credentials_hears_0xff_where_they_are_needed() {
+    let (proxy, _users) = with_alice("");
+    let mut sock = TcpStream::connect(proxy.addr).unwrap();
+    sock.set_read_timeout(Some(WAIT)).unwrap();
+    sock.write_all(&[5, 1, 0]).unwrap();
+    let mut choice = [0u8; 2];
+    sock.read_exact(&mut choice).unwrap();
+    assert_eq!(choice, [5, 0xff]);
+    assert!(common::closed(&mut sock));
+    let audit = proxy.dir.path("audit.log");
+    assert!(common::wait_path(&audit));
+    assert!(fs::read_to_string(audit).unwrap().contains("auth_file:missing"));
+}
+
+#[test]
+fn a_profile_without_auth_offers_no_authentication() {
+    let config = "[[listener]]\naddress = \"{addr}\"\nprofile = \"internal\"\n[profile.internal]\nauth = false\n";
+    let (proxy, _users) = with_alice(config);
+    let echo = echo_server();
+    let (reply, mut sock) = connect(&proxy, &address(echo.ip()), echo.port());
+    assert_eq!(reply, 0);
+    assert_eq!(echo_through(&mut sock, b"inside"), b"inside");
+}
+
+/// A UDP echo server on loopback, each datagram it takes also passed on
+/// to the receiver.
+fn udp_echo() -> (SocketAddr, mpsc::Receiver<Vec<u8>>) {
+    let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = sock.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0u8; 2048];
+        while let Ok((n, from)) = sock.recv_from(&mut buf) {
+            let _ = sock.send_to(&buf[..n], from);
+            if tx.send(buf[..n].to_vec()).is_err() {
+                return;
+            }
+        }
+    });
+    (addr, rx)
+}
+
+/// A UDP socket on loopback `ip`, with the usual read timeout.
+fn udp_client(ip: &str) -> UdpSocket {
+    let sock = UdpSocket::bind((ip, 0)).unwrap();
+    sock.set_read_timeout(Some(WAIT)).unwrap();
+    sock
+}
+
+/// A UDP ASSOCIATE for datagrams from `client`'s port: the address of
+/// the relay and the TCP connection that keeps it.
+fn associate(proxy: &Proxy, client: &UdpSocket) -> (SocketAddr, TcpStream) {
+    let port = client.local_addr().unwrap().port();
+    let mut sock = TcpStream::connect(proxy.addr).unwrap();
+    sock.set_read_timeout(Some(WAIT)).unwrap();
+    sock.write_all(&[5, 1, 0]).unwrap();
+    let mut choice = [0u8; 2];
+    sock.read_exact(&mut choice).unwrap();
+    assert_eq!(choice, [5, 0]);
+    sock.write_all(&[&[5, 3, 0][..], &address(Ipv4Addr::UNSPECIFIED.into()), &port.to_be_bytes()].concat()).unwrap();
+    let mut reply = [0u8; 10];
+    sock.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[..4], [5, 0, 0, 1], "{:?}", reply);
+    let ip = Ipv4Addr::new(reply[4], reply[5], reply[6], reply[7]);
+    (SocketAddr::new(ip.into(), u16::from_be_bytes([reply[8], reply[9]])), sock)
+}
+
+/// A datagram for the relay: the header for `to`, fragment `frag`, and
+/// `payload`.
+fn datagram(frag: u8, to: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    [&[0, 0, frag][..], &address(to.ip()), &to.port().to_be_bytes(), payload].concat()
+}
+
+#[test]
+fn a_udp_associate_relays_datagrams_both_ways() {
+    let proxy = Proxy::start("");
+    let (echo, received) = udp_echo();
+    let client = udp_client("127.0.0.1");
+    let (relay, _control) = associate(&proxy, &client);
+    for payload in [&b"ping"[..], &[7u8; 1400][..]] {
+        client.send_to(&datagram(0, echo, payload), relay).unwrap();
+        let mut buf = [0u8; 2048];
+        let (n, from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(from, relay);
+        // the answer comes behind a header naming who sent it
+        assert_eq!(&buf[..n], &datagram(0, echo, payload)[..]);
+        assert_eq!(received.recv_timeout(WAIT).unwrap(), payload);
+    }
+}
+
+#[test]
+fn fragments_and_datagrams_of_others_are_dropped() {
+    let proxy = Proxy::start("");
+    let (echo, received) = udp_echo();
+    let client = udp_client("127.0.0.1");
+    let (relay, control) = associate(&proxy, &client);
+    client.send_to(&datagram(1, echo, b"fragment"), relay).unwrap();
+    // another port of the client's address, and another address
+    udp_client("127.0.0.1").send_to(&datagram(0, echo, b"other port"), relay).unwrap();
+    udp_client("127.0.0.2").send_to(&datagram(0, echo, b"other host"), relay).unwrap();
+    client.send_to(&datagram(0, echo, b"whole"), relay).unwrap();
+    // datagrams on loopback keep their order, the ones before went nowhere
+    assert_eq!(received.recv_timeout(WAIT).unwrap(), b"whole");
+    let mut buf = [0u8; 2048];
+    let (n, _) = client.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..n], &datagram(0, echo, b"whole")[..]);
+    drop(control);
+    proxy.wait_log("udp association closed, 3 datagrams dropped");
+}
+
+#[test]
+fn the_relay_goes_when_the_control_connection_closes() {
+    let proxy = Proxy::start("");
+    let (echo, received) = udp_echo();
+    let client = udp_client("127.0.0.1");
+    let (relay, control) = associate(&proxy, &client);
+    client.connect(relay).unwrap();
+    client.send(&datagram(0, echo, b"before")).unwrap();
+    assert_eq!(received.recv_timeout(WAIT).unwrap(), b"before");
+    let mut buf = [0u8; 2048];
+    client.recv(&mut buf).unwrap();
+    drop(control);
+    proxy.wait_log("udp association closed");
+    // nothing listens on the relay's port any more: the ICMP port
+    // unreachable comes back as a refused connection
+    client.send(&datagram(0, echo, b"after")).unwrap();
+    assert_eq!(client.recv(&mut buf).unwrap_err().kind(), ErrorKind::ConnectionRefused);
+    assert!(received.try_recv().is_err());
+}
@@ -0,0 +1,93 @@
+//! SOCKS4 and 4a clients: granted or not, names resolved on our side, and
+//! the user id that only goes in the logs.
+
+mod common;
+
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{IpAddr, TcpStream},
+};
+
+use common::{echo_server, echo_through, Proxy, Scratch, WAIT};
+
+/// Sends a SOCKS4 `command` for `port` with `user_id`, to `ip`, or to
+/// `name` in the 4a form, returning the reply code and the socket.
+fn request(proxy: &Proxy, command: u8, port: u16, to: Result<IpAddr, &str>, user_id: &str) -> (u8, TcpStream) {
+    let mut sock = TcpStream::connect(proxy.addr).unwrap();
+    sock.set_read_timeout(Some(WAIT)).unwrap();
+    let mut msg = vec![4, command];
+    msg.extend_from_slice(&port.to_be_bytes());
+    match to {
+        Ok(IpAddr::V4(ip)) => msg.extend_from_slice(&ip.octets()),
+        Ok(IpAddr::V6(_)) => unreachable!("SOCKS4 is IPv4 only"),
+        Err(_) => msg.extend_from_slice(&[0, 0, 0, 1]),
+    }
+    msg.extend_from_slice(user_id.as_bytes());
+    msg.push(0);
+    if let Err(name) = to {
+        msg.extend_from_slice(name.as_bytes());
+        msg.push(0);
+    }
+    sock.write_all(&msg).unwrap();
+    let mut reply = [0u8; 8];
+    sock.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[0], 0, "{:?}", reply);
+    (reply[1], sock)
+}
+
+#[test]
+fn connect_by_address_and_by_4a_name() {
+    let proxy = Proxy::start("");
+    let echo = echo_server();
+    let (reply, mut sock) = request(&proxy, 1, echo.port(), Ok(echo.ip()), "");
+    assert_eq!(reply, 90);
+    assert_eq!(echo_through(&mut sock, b"four"), b"four");
+    // the client has no address for it, we resolve the name
+    let (reply, mut sock) = request(&proxy, 1, echo.port(), Err("localhost"), "me");
+    assert_eq!(reply, 90);
+    assert_eq!(echo_through(&mut sock, b"four-a"), b"four-a");
+    proxy.wait_log(&format!("socks4 CONNECT localhost:{} user id \"me\"", echo.port()));
+}
+
+#[test]
+fn anything_else_is_91() {
+    let echo = echo_server();
+    let proxy = Proxy::start(&format!("allowed_ports = \"{}\"\n", echo.port()));
+    let refused = common::free_port();
+    assert_eq!(request(&proxy, 1, refused, Ok(echo.ip()), "").0, 91, "not allowed");
+    assert_eq!(request(&proxy, 2, echo.port(), Ok(echo.ip()), "").0, 91, "BIND");
+    assert_eq!(request(&proxy, 1, echo.port(), Err("nonexistent.invalid"), "").0, 91, "no such name");
+}
+
+#[test]
+fn the_user_id_goes_in_the_access_log() {
+    let mut proxy = Proxy::start("access_log = \"access.log\"\n");
+    let echo = echo_server();
+    let (reply, mut sock) = request(&proxy, 1, echo.port(), Ok(echo.ip()), "ident-bob");
+    assert_eq!(reply, 90);
+    assert_eq!(echo_through(&mut sock, b"hi"), b"hi");
+    drop(sock);
+    // flushed at exit
+    proxy.signal("TERM");
+    proxy.child.wait().unwrap();
+    let access = fs::read_to_string(proxy.dir.path("access.log")).unwrap();
+    let line = access.lines().find(|l| l.contains(" SOCKS4 ")).unwrap_or_else(|| panic!("{}", access));
+    assert_eq!(line.split_whitespace().last(), Some("ident-bob"), "{}", line);
+}
+
+#[test]
+fn a_user_id_does_not_get_past_auth_file() {
+    let users = Scratch::new();
+    let hash = bcrypt::hash("secret", 4).unwrap();
+    fs::write(users.path("users"), format!("alice:{}\n", hash)).unwrap();
+    let config = format!("auth_file = \"{}\"\naudit_log = \"audit.log\"\n", users.path("users").display());
+    let proxy = Proxy::start(&config);
+    let echo = echo_server();
+    let (reply, mut sock) = request(&proxy, 1, echo.port(), Ok(echo.ip()), "alice");
+    assert_eq!(reply, 91);
+    assert!(common::closed(&mut sock));
+    let audit = proxy.dir.path("audit.log");
+    assert!(common::wait_path(&audit));
+    assert!(fs::read_to_string(audit).unwrap().contains("auth_file:missing"));
+}
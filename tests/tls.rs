@@ -0,0 +1,425 @@
+//! TLS listeners: clients reaching the proxy over an https:// proxy URL,
+//! the certificate reloaded on SIGHUP, clients known by certificates of
+//! their own, CONNECTs as HTTP/2 streams, and routes that speak TLS to
+//! their destinations for the client.
+#![cfg(feature = "tls")]
+
+mod common;
+
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    process::Command,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{echo_server, Proxy, Scratch, WAIT};
+use rcgen::{CertificateParams, CertifiedIssuer, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName},
+    ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned,
+};
+use sha2::{Digest, Sha256};
+
+/// A client's certificate and its key.
+type Identity = (CertificateDer<'static>, PrivateKeyDer<'static>);
+
+/// Writes a new self-signed certificate for localhost and its key to
+/// `cert.pem` and `key.pem` in `dir`, returning the certificate.
+fn new_cert(dir: &Scratch) -> CertificateDer<'static> {
+    let names = ["localhost".to_owned()];
+    let rcgen::CertifiedKey { cert, signing_key } = rcgen::generate_simple_self_signed(names).unwrap();
+    fs::write(dir.path("cert.pem"), cert.pem()).unwrap();
+    fs::write(dir.path("key.pem"), signing_key.serialize_pem()).unwrap();
+    cert.der().clone()
+}
+
+/// A proxy with one TLS listener on the certificate in `certs`.
+fn tls_proxy(certs: &Scratch, config: &str) -> Proxy {
+    Proxy::start(&format!(
+        "{}[[listener]]\naddress = \"{{addr}}\"\ntls_cert = \"{}\"\ntls_key = \"{}\"\n",
+        config,
+        certs.path("cert.pem").display(),
+        certs.path("key.pem").display()
+    ))
+}
+
+/// A CA of its own, its certificate written to `file` in `dir`.
+fn new_ca(dir: &Scratch, file: &str) -> CertifiedIssuer<'static, KeyPair> {
+    let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    params.is_ca = IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    params.distinguished_name.push(DnType::CommonName, "thin_proxy test CA");
+    let ca = CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap();
+    fs::write(dir.path(file), ca.pem()).unwrap();
+    ca
+}
+
+/// A client certificate whose subject is `name`, signed by `ca`.
+fn client_cert(ca: &CertifiedIssuer<'static, KeyPair>, name: &str) -> Identity {
+    let key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    params.distinguished_name.push(DnType::CommonName, name);
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    let cert = params.signed_by(&key, ca).unwrap();
+    (cert.der().clone(), PrivatePkcs8KeyDer::from(key.serialize_der()).into())
+}
+
+/// A proxy whose TLS listener takes client certificates of the CA in
+/// `ca.pem` of `certs`, with `listener` added to its entry.
+fn mtls_proxy(certs: &Scratch, config: &str, listener: &str) -> Proxy {
+    Proxy::start(&format!(
+        "audit_log = \"audit.log\"\n{}[[listener]]\naddress = \"{{addr}}\"\ntls_cert = \"{}\"\ntls_key = \"{}\"\n\
+         client_ca = \"{}\"\n{}",
+        config,
+        certs.path("cert.pem").display(),
+        certs.path("key.pem").display(),
+        certs.path("ca.pem").display(),
+        listener
+    ))
+}
+
+/// CONNECT to `target` over a TLS connection presenting `identity`: the
+/// status line, empty when the handshake failed.
+fn connect_as(proxy: &Proxy, cert: &CertificateDer<'static>, identity: Option<Identity>, target: &str) -> String {
+    let mut tls = client_as(proxy, cert, identity, &[]);
+    let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target);
+    if tls.write_all(request.as_bytes()).is_err() {
+        return String::new();
+    }
+    common::read_head(&mut tls).unwrap_or_default()
+}
+
+/// Waits for `rule` in the audit log of `proxy`.
+fn wait_audit(proxy: &Proxy, rule: &str) {
+    let deadline = Instant::now() + WAIT;
+    let audit = || fs::read_to_string(proxy.dir.path("audit.log")).unwrap_or_default();
+    while !audit().contains(rule) {
+        assert!(Instant::now() < deadline, "no {:?} in the audit log:\n{}", rule, audit());
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// A TLS connection to `proxy` trusting `cert`, offering `alpn`.
+fn client(proxy: &Proxy, cert: &CertificateDer<'static>, alpn: &[&[u8]]) -> StreamOwned<ClientConnection, TcpStream> {
+    client_as(proxy, cert, None, alpn)
+}
+
+/// `client` presenting `identity`, when there is one.
+fn client_as(
+    proxy: &Proxy,
+    cert: &CertificateDer<'static>,
+    identity: Option<Identity>,
+    alpn: &[&[u8]],
+) -> StreamOwned<ClientConnection, TcpStream> {
+    let mut roots = RootCertStore::empty();
+    roots.add(cert.clone()).unwrap();
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    let mut config = match identity {
+        Some((cert, key)) => builder.with_client_auth_cert(vec![cert], key).unwrap(),
+        None => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+    let conn = ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap()).unwrap();
+    let sock = TcpStream::connect(proxy.addr).unwrap();
+    sock.set_read_timeout(Some(WAIT)).unwrap();
+    StreamOwned::new(conn, sock)
+}
+
+#[test]
+fn connect_over_tls() {
+    let certs = Scratch::new();
+    let cert = new_cert(&certs);
+    let proxy = tls_proxy(&certs, "");
+    let echo = echo_server();
+    let mut tls = client(&proxy, &cert, &[b"h2", b"http/1.1"]);
+    tls.write_all(format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", echo, echo).as_bytes()).unwrap();
+    let head = common::read_head(&mut tls).unwrap();
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    // without http2 on the listener, h2 is not picked
+    assert_eq!(tls.conn.alpn_protocol(), Some(&b"http/1.1"[..]));
+    // the tunnel's bytes go through the TLS session, in sizes past a record
+    let data: Vec<u8> = (0..256 * 1024).map(|i| (i % 253) as u8).collect();
+    tls.write_all(&data).unwrap();
+    let mut back = vec![0u8; data.len()];
+    tls.read_exact(&mut back).unwrap();
+    assert!(back == data);
+}
+
+#[test]
+fn curl_with_an_https_proxy_url() {
+    let certs = Scratch::new();
+    new_cert(&certs);
+    let origin = common::serve(|mut sock| {
+        let _ = common::read_head(&mut sock);
+        let _ = sock.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\nConnection: close\r\n\r\nsecure");
+    });
+    let proxy = tls_proxy(&certs, "");
+    let out = Command::new("curl")
+        .args(["-sS", "--max-time", "10", "-p", "--proxy-cacert"])
+        .arg(certs.path("cert.pem"))
+        .args(["--proxy", &format!("https://localhost:{}", proxy.addr.port())])
+        .arg(format!("http://{}/", origin))
+        .output();
+    let Ok(out) = out else {
+        eprintln!("no curl, skipped");
+        return;
+    };
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(out.stdout, b"secure");
+}
+
+#[test]
+fn a_reload_brings_in_the_new_certificate() {
+    let certs = Scratch::new();
+    let old = new_cert(&certs);
+    let proxy = tls_proxy(&certs, "");
+    let peer = |cert: &CertificateDer<'static>| {
+        let mut tls = client(&proxy, cert, &[]);
+        while tls.conn.is_handshaking() {
+            tls.conn.complete_io(&mut tls.sock).unwrap();
+        }
+        tls.conn.peer_certificates().unwrap()[0].clone()
+    };
+    assert_eq!(peer(&old), old);
+    let new = new_cert(&certs);
+    proxy.signal("HUP");
+    proxy.wait_log("worker 0 reloaded");
+    assert_eq!(peer(&new), new);
+}
+
+#[test]
+fn a_client_speaking_plain_http_is_closed() {
+    let certs = Scratch::new();
+    new_cert(&certs);
+    let proxy = tls_proxy(&certs, "");
+    let (status, mut sock) = common::connect_via(proxy.addr, "example.com:443", &[]);
+    assert!(!status.starts_with("HTTP/1.1 200"), "{}", status);
+    assert!(common::closed(&mut sock));
+}
+
+#[test]
+fn check_config_refuses_a_key_of_another_certificate() {
+    let (a, b) = (Scratch::new(), Scratch::new());
+    new_cert(&a);
+    new_cert(&b);
+    fs::write(
+        a.path("proxy.toml"),
+        format!(
+            "[[listener]]\naddress = \"127.0.0.1:{}\"\ntls_cert = \"{}\"\ntls_key = \"{}\"\n",
+            common::free_port(),
+            a.path("cert.pem").display(),
+            b.path("key.pem").display()
+        ),
+    )
+    .unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_thin_proxy"))
+        .arg("--config")
+        .arg(a.path("proxy.toml"))
+        .arg("--check-config")
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("is not the key of"), "{:?}", out);
+}
+
+#[test]
+fn a_client_certificate_names_the_user() {
+    let certs = Scratch::new();
+    let cert = new_cert(&certs);
+    let ca = new_ca(&certs, "ca.pem");
+    // alice may go nowhere, others anywhere
+    let users = "access_log = \"access.log\"\n[acl_set.nowhere]\ndefault = \"deny\"\n\
+                 [users.alice]\nacl_set = \"nowhere\"\n";
+    let mut proxy = mtls_proxy(&certs, users, "");
+    let echo = echo_server().to_string();
+    let status = connect_as(&proxy, &cert, Some(client_cert(&ca, "alice")), &echo);
+    assert!(status.starts_with("HTTP/1.1 403"), "{}", status);
+    let status = connect_as(&proxy, &cert, Some(client_cert(&ca, "bob")), &echo);
+    assert!(status.starts_with("HTTP/1.1 200"), "{}", status);
+    // without require_client_cert a client without one gets in as nobody
+    let status = connect_as(&proxy, &cert, None, &echo);
+    assert!(status.starts_with("HTTP/1.1 200"), "{}", status);
+    proxy.wait_log("user=bob");
+    // flushed at exit
+    proxy.signal("TERM");
+    proxy.child.wait().unwrap();
+    let access = fs::read_to_string(proxy.dir.path("access.log")).unwrap();
+    let users = access.lines().map(|l| l.split_whitespace().last().unwrap()).collect::<Vec<_>>();
+    assert!(users.contains(&"alice") && users.contains(&"bob"), "{}", access);
+}
+
+#[test]
+fn a_missing_certificate_fails_the_handshake_when_one_is_required() {
+    let certs = Scratch::new();
+    let cert = new_cert(&certs);
+    new_ca(&certs, "ca.pem");
+    let proxy = mtls_proxy(&certs, "", "require_client_cert = true\n");
+    assert_eq!(connect_as(&proxy, &cert, None, &echo_server().to_string()), "");
+    wait_audit(&proxy, "client_cert:missing");
+    let log = proxy.log();
+    let line = log.lines().find(|l| l.contains("tls handshake failed")).unwrap_or_else(|| panic!("{}", log));
+    assert!(line.contains("client=127.0.0.1:"), "{}", line);
+}
+
+#[test]
+fn a_certificate_of_another_ca_is_refused() {
+    let certs = Scratch::new();
+    let cert = new_cert(&certs);
+    new_ca(&certs, "ca.pem");
+    let other = new_ca(&certs, "other-ca.pem");
+    let proxy = mtls_proxy(&certs, "", "require_client_cert = true\n");
+    let status = connect_as(&proxy, &cert, Some(client_cert(&other, "mallory")), &echo_server().to_string());
+    assert_eq!(status, "");
+    wait_audit(&proxy, "client_cert:untrusted");
+    assert!(proxy.log().contains("tls handshake failed"));
+}
+
+#[test]
+fn a_certificate_on_the_deny_list_is_refused() {
+    let certs = Scratch::new();
+    let cert = new_cert(&certs);
+    let ca = new_ca(&certs, "ca.pem");
+    let (revoked, good) = (client_cert(&ca, "alice"), client_cert(&ca, "bob"));
+    // the way openssl x509 -fingerprint prints it
+    let print = Sha256::digest(&revoked.0).iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(":");
+    let proxy = mtls_proxy(&certs, "", &format!("client_cert_deny = [\"{}\"]\n", print));
+    let echo = echo_server().to_string();
+    assert_eq!(connect_as(&proxy, &cert, Some(revoked), &echo), "");
+    wait_audit(&proxy, "client_cert:denied");
+    let status = connect_as(&proxy, &cert, Some(good), &echo);
+    assert!(status.starts_with("HTTP/1.1 200"), "{}", status);
+}
+
+/// An HTTP/2 frame as it goes over the wire.
+fn h2_frame(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+    frame.extend([kind, flags]);
+    frame.extend(stream.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// HEADERS for a CONNECT to `target` on `stream`, with `extra` headers;
+/// all literals without indexing, none Huffman coded.
+fn h2_connect(stream: u32, target: &str, extra: &[(&str, &str)]) -> Vec<u8> {
+    // :method and :authority by their static index
+    let mut block = [&[0x02, 7][..], b"CONNECT", &[0x01, target.len() as u8], target.as_bytes()].concat();
+    for (name, value) in extra {
+        block.extend([0x00, name.len() as u8]);
+        block.extend_from_slice(name.as_bytes());
+        block.push(value.len() as u8);
+        block.extend_from_slice(value.as_bytes());
+    }
+    h2_frame(0x1, 0x4, stream, &block)
+}
+
+/// The next frame on `stream`, those of other streams and of the
+/// connection skipped: kind, flags and payload.
+fn h2_read(tls: &mut impl Read, stream: u32) -> (u8, u8, Vec<u8>) {
+    loop {
+        let mut h = [0u8; 9];
+        tls.read_exact(&mut h).unwrap();
+        let mut payload = vec![0u8; u32::from_be_bytes([0, h[0], h[1], h[2]]) as usize];
+        tls.read_exact(&mut payload).unwrap();
+        if u32::from_be_bytes([h[5], h[6], h[7], h[8]]) == stream {
+            return (h[3], h[4], payload);
+        }
+    }
+}
+
+#[test]
+fn connect_over_h2_asks_for_credentials_and_tunnels() {
+    let certs = Scratch::new();
+    let cert = new_cert(&certs);
+    fs::write(certs.path("users"), format!("alice:{}\n", bcrypt::hash("secret", 4).unwrap())).unwrap();
+    let proxy = Proxy::start(&format!(
+        "auth_file = \"{}\"\n[[listener]]\naddress = \"{{addr}}\"\ntls_cert = \"{}\"\ntls_key = \"{}\"\nhttp2 = true\n",
+        certs.path("users").display(),
+        certs.path("cert.pem").display(),
+        certs.path("key.pem").display()
+    ));
+    let echo = echo_server().to_string();
+    let mut tls = client(&proxy, &cert, &[b"h2", b"http/1.1"]);
+    tls.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n").unwrap();
+    tls.write_all(&h2_frame(0x4, 0, 0, &[])).unwrap();
+    tls.write_all(&h2_connect(1, &echo, &[])).unwrap();
+    assert_eq!(tls.conn.alpn_protocol(), Some(&b"h2"[..]));
+    // 407 is no static entry: a literal of the :status name, index 8
+    let (kind, flags, block) = h2_read(&mut tls, 1);
+    assert_eq!(kind, 0x1);
+    assert!(block.starts_with(b"\x08\x03407"), "{:?}", String::from_utf8_lossy(&block));
+    assert!(block.windows(18).any(|w| w == b"proxy-authenticate"), "{:?}", String::from_utf8_lossy(&block));
+    // the body of the refusal, if it has one, and the end of the stream
+    let mut ended = flags & 0x1 != 0;
+    while !ended {
+        let (kind, flags, _) = h2_read(&mut tls, 1);
+        ended = kind == 0x3 || flags & 0x1 != 0;
+    }
+    // "alice:secret" in base64
+    tls.write_all(&h2_connect(3, &echo, &[("proxy-authorization", "Basic YWxpY2U6c2VjcmV0")])).unwrap();
+    assert_eq!(h2_read(&mut tls, 3), (0x1, 0x4, vec![0x88]));
+    tls.write_all(&h2_frame(0x0, 0, 3, b"over h2")).unwrap();
+    assert_eq!(h2_read(&mut tls, 3), (0x0, 0, b"over h2".to_vec()));
+}
+
+/// A TLS echo server for localhost, its self-signed certificate written
+/// to `origin.pem` in `dir`.
+fn tls_echo(dir: &Scratch) -> SocketAddr {
+    let names = ["localhost".to_owned()];
+    let rcgen::CertifiedKey { cert, signing_key } = rcgen::generate_simple_self_signed(names).unwrap();
+    fs::write(dir.path("origin.pem"), cert.pem()).unwrap();
+    let key = PrivatePkcs8KeyDer::from(signing_key.serialize_der()).into();
+    let config = ServerConfig::builder().with_no_client_auth().with_single_cert(vec![cert.der().clone()], key).unwrap();
+    let config = Arc::new(config);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for sock in listener.incoming().flatten() {
+            let config = Arc::clone(&config);
+            thread::spawn(move || {
+                let mut tls = StreamOwned::new(ServerConnection::new(config).unwrap(), sock);
+                let mut buf = [0u8; 16 * 1024];
+                while let Ok(n @ 1..) = tls.read(&mut buf) {
+                    if tls.write_all(&buf[..n]).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    addr
+}
+
+/// A proxy with a wrap_tls route for localhost, `keys` added to it.
+fn wrapping_proxy(keys: &str) -> Proxy {
+    Proxy::start(&format!("[[route]]\nhosts = [\"localhost\"]\naction = \"direct\"\nwrap_tls = true\n{}", keys))
+}
+
+#[test]
+fn wrap_tls_checks_the_destination_against_tls_ca() {
+    let dir = Scratch::new();
+    let origin = tls_echo(&dir);
+    let target = format!("localhost:{}", origin.port());
+    let proxy = wrapping_proxy(&format!("tls_ca = \"{}\"\n", dir.path("origin.pem").display()));
+    let mut sock = proxy.tunnel(&target);
+    // plaintext this side, TLS that side
+    assert_eq!(common::echo_through(&mut sock, b"wrapped"), b"wrapped");
+    // the system's CA certificates know nothing of it
+    let proxy = wrapping_proxy("");
+    let (status, _) = proxy.connect(&target);
+    assert!(status.starts_with("HTTP/1.1 502"), "{}", status);
+    proxy.wait_log(&format!("tls handshake with {} failed", target));
+}
+
+#[test]
+fn wrap_tls_without_tls_verify_takes_any_certificate() {
+    let dir = Scratch::new();
+    let origin = tls_echo(&dir);
+    let proxy = wrapping_proxy("tls_verify = false\n");
+    proxy.wait_log("tls_verify = false, its destinations' certificates are not checked");
+    let mut sock = proxy.tunnel(&format!("localhost:{}", origin.port()));
+    assert_eq!(common::echo_through(&mut sock, b"unchecked"), b"unchecked");
+}
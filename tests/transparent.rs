@@ -0,0 +1,189 @@
+//! Transparent and tproxy listeners with the kernel's lookups. Without an
+//! iptables REDIRECT or TPROXY rule every connection comes for the
+//! listener itself, which is what the first ones check; those of
+//! transparent_sni redirect clients of 127.0.0.3 to the proxy in a network
+//! namespace of their own, where iptables is there to do it.
+
+mod common;
+
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    process::Command,
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+use common::{echo_server, echo_through, Proxy, WAIT};
+use socket2::{Domain, Socket, Type};
+
+/// A network namespace of the test's own with connections from 127.0.0.3
+/// to `port` redirected to the proxy's `to`; false, and the test skipped,
+/// where there is no such namespace or iptables.
+fn redirect(port: u16, to: u16) -> bool {
+    if !common::own_network() {
+        eprintln!("no network namespace, skipped");
+        return false;
+    }
+    let (port, to) = (port.to_string(), to.to_string());
+    let rule = ["-t", "nat", "-A", "OUTPUT", "-p", "tcp", "-s", "127.0.0.3", "--dport", &port];
+    match Command::new("iptables").args(rule).args(["-j", "REDIRECT", "--to-ports", &to]).status() {
+        Ok(status) if status.success() => true,
+        _ => {
+            eprintln!("no iptables, skipped");
+            false
+        }
+    }
+}
+
+/// A ClientHello naming `name`, in one record.
+fn client_hello(name: &str) -> Vec<u8> {
+    let mut names = vec![0];
+    names.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    names.extend_from_slice(name.as_bytes());
+    let mut extension = vec![0, 0];
+    extension.extend_from_slice(&(names.len() as u16 + 2).to_be_bytes());
+    extension.extend_from_slice(&(names.len() as u16).to_be_bytes());
+    extension.extend(names);
+    let mut body = vec![3, 3];
+    body.extend([0x5a; 32]);
+    body.extend([0, 0, 2, 0x13, 0x01, 1, 0]);
+    body.extend_from_slice(&(extension.len() as u16).to_be_bytes());
+    body.extend(extension);
+    let mut record = vec![0x16, 3, 1];
+    record.extend_from_slice(&(body.len() as u16 + 4).to_be_bytes());
+    record.extend([1, 0]);
+    record.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    record.extend(body);
+    record
+}
+
+/// An origin on `addr` that takes connections one at a time, sends what
+/// each one's client wrote first and answers it "served".
+fn origin(addr: &str) -> (SocketAddr, Receiver<Vec<u8>>) {
+    let listener = TcpListener::bind(addr).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for mut sock in listener.incoming().flatten() {
+            let mut buf = [0u8; 1024];
+            let n = sock.read(&mut buf).unwrap_or(0);
+            let _ = tx.send(buf[..n].to_vec());
+            let _ = sock.write_all(b"served");
+        }
+    });
+    (addr, rx)
+}
+
+/// A client of 127.0.0.3 that writes `first` to `to`, by way of the
+/// redirect; what it gets back before the close.
+fn client(to: SocketAddr, first: &[u8]) -> Vec<u8> {
+    let sock = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+    sock.bind(&"127.0.0.3:0".parse::<SocketAddr>().unwrap().into()).unwrap();
+    sock.connect(&to.into()).unwrap();
+    let mut sock = TcpStream::from(sock);
+    sock.set_read_timeout(Some(WAIT)).unwrap();
+    sock.write_all(first).unwrap();
+    let mut back = Vec::new();
+    let _ = sock.read_to_end(&mut back);
+    back
+}
+
+#[test]
+fn a_connection_not_redirected_is_closed_without_a_word() {
+    let http = common::free_port();
+    let config = format!(
+        "[[listener]]\naddress = \"{{addr}}\"\ntransparent = true\n\
+         [[listener]]\naddress = \"127.0.0.1:{}\"\n",
+        http
+    );
+    let proxy = Proxy::start(&config);
+    let mut sock = TcpStream::connect(proxy.addr).unwrap();
+    let _ = sock.write_all(b"\x16\x03\x01\x00\x05hello");
+    assert!(common::closed(&mut sock));
+    proxy.wait_log("no original destination on transparent listener 0");
+    // dialing it would have looped back to us; the other listener is fine
+    let echo = echo_server();
+    let http = format!("127.0.0.1:{}", http).parse().unwrap();
+    let (status, mut sock) = common::connect_via(http, &echo.to_string(), &[]);
+    assert!(status.starts_with("HTTP/1.1 200"), "{}", status);
+    assert_eq!(echo_through(&mut sock, b"http"), b"http");
+}
+
+#[test]
+fn check_config_refuses_a_transparent_listener_that_needs_credentials() {
+    let dir = common::Scratch::new();
+    std::fs::write(dir.path("users"), "").unwrap();
+    let config = format!(
+        "auth_file = \"{}\"\n[[listener]]\naddress = \"127.0.0.1:{}\"\ntransparent = true\n",
+        dir.path("users").display(),
+        common::free_port()
+    );
+    std::fs::write(dir.path("proxy.toml"), config).unwrap();
+    let out = std::process::Command::new(env!("CARGO_BIN_EXE_thin_proxy"))
+        .arg("--config")
+        .arg(dir.path("proxy.toml"))
+        .arg("--check-config")
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("cannot authenticate"), "{:?}", out);
+}
+
+#[test]
+fn a_tproxy_listener_refuses_a_connection_for_itself() {
+    // IP_TRANSPARENT needs CAP_NET_ADMIN, which the tests run with
+    let proxy = Proxy::start("[[listener]]\naddress = \"{addr}\"\ntproxy = true\n");
+    let mut sock = TcpStream::connect(proxy.addr).unwrap();
+    assert!(common::closed(&mut sock));
+    proxy.wait_log("no original destination on transparent listener 0");
+}
+
+#[test]
+fn the_server_name_is_the_host_of_a_redirected_connection() {
+    let (port, proxy_port) = (common::free_port(), common::free_port());
+    if !redirect(port, proxy_port) {
+        return;
+    }
+    let (to, seen) = origin(&format!("127.0.0.2:{}", port));
+    let config = "access_log = \"access.log\"\n[[listener]]\naddress = \"{addr}\"\ntransparent = true\n\
+                  [[acl]]\nhosts = [\"blocked.test\"]\naction = \"deny\"\n";
+    let addr = format!("127.0.0.1:{}", proxy_port).parse().unwrap();
+    let mut proxy = Proxy::start_on(addr, config, &[]);
+    let hello = client_hello("allowed.test");
+    assert_eq!(client(to, &hello), b"served");
+    // the original address is dialed, with the hello as it was
+    assert_eq!(seen.recv_timeout(WAIT).unwrap(), hello);
+    assert_eq!(client(to, &client_hello("blocked.test")), b"");
+    assert_eq!(client(to, b"GET / HTTP/1.0\r\n\r\n"), b"served");
+    assert_eq!(seen.recv_timeout(WAIT).unwrap(), b"GET / HTTP/1.0\r\n\r\n");
+    proxy.signal("TERM");
+    proxy.child.wait().unwrap();
+    let access = fs::read_to_string(proxy.dir.path("access.log")).unwrap();
+    for (host, outcome) in [("allowed.test", "established"), ("blocked.test", "denied"), ("127.0.0.2", "established")] {
+        let line = format!(" TRANSPARENT {}:{} {} ", host, port, outcome);
+        assert!(access.contains(&line), "no {:?} in\n{}", line, access);
+    }
+}
+
+#[test]
+fn the_server_name_is_dialed_when_transparent_sni_is_dial() {
+    let (port, proxy_port) = (common::free_port(), common::free_port());
+    if !redirect(port, proxy_port) {
+        return;
+    }
+    // what the client dials, 127.0.0.2, is not where the name resolves to
+    let (_, by_name) = origin(&format!("127.0.0.1:{}", port));
+    let (to, by_address) = origin(&format!("127.0.0.2:{}", port));
+    let config = "transparent_sni = \"dial\"\n[[listener]]\naddress = \"{addr}\"\ntransparent = true\n";
+    let addr = format!("127.0.0.1:{}", proxy_port).parse().unwrap();
+    let _proxy = Proxy::start_on(addr, config, &[]);
+    let hello = client_hello("localhost");
+    assert_eq!(client(to, &hello), b"served");
+    assert_eq!(by_name.recv_timeout(WAIT).unwrap(), hello);
+    // bytes that are no hello keep the address
+    assert_eq!(client(to, b"SSH-2.0-test\r\n"), b"served");
+    assert_eq!(by_address.recv_timeout(WAIT).unwrap(), b"SSH-2.0-test\r\n");
+    assert!(by_name.try_recv().is_err() && by_address.try_recv().is_err());
+}
@@ -0,0 +1,99 @@
+//! Tunnel listeners, forwarding every connection to their upstream: one
+//! that does not resolve, one that goes away and comes back.
+
+mod common;
+
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+use common::{Proxy, WAIT};
+
+/// A server on `addr` greeting each client with its banner and closing,
+/// until dropped; the port is free again once it is.
+struct Banner {
+    addr: String,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Banner {
+    fn new(addr: &str, banner: &'static str) -> Banner {
+        let listener = TcpListener::bind(addr).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let thread = thread::spawn(move || {
+            for mut sock in listener.incoming().flatten() {
+                if stopped.load(Ordering::Relaxed) {
+                    break;
+                }
+                let _ = sock.write_all(banner.as_bytes());
+            }
+        });
+        Banner { addr: addr.to_owned(), stop, thread: Some(thread) }
+    }
+}
+
+impl Drop for Banner {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // the accept it waits in has to return to see it
+        let _ = TcpStream::connect(&self.addr);
+        let _ = self.thread.take().unwrap().join();
+    }
+}
+
+/// What a client of `proxy` gets before its connection closes.
+fn greeting(proxy: &Proxy) -> Vec<u8> {
+    let mut sock = TcpStream::connect(proxy.addr).unwrap();
+    sock.set_read_timeout(Some(WAIT)).unwrap();
+    let mut got = Vec::new();
+    let _ = sock.read_to_end(&mut got);
+    got
+}
+
+/// The access log of `proxy`, once it exited.
+fn access_log(mut proxy: Proxy) -> String {
+    proxy.signal("TERM");
+    proxy.child.wait().unwrap();
+    fs::read_to_string(proxy.dir.path("access.log")).unwrap()
+}
+
+#[test]
+fn an_upstream_that_does_not_resolve_closes_the_client() {
+    let proxy = Proxy::start(
+        "access_log = \"access.log\"\n[[listener]]\naddress = \"{addr}\"\nupstream = \"nowhere.invalid:5432\"\n",
+    );
+    let mut sock = TcpStream::connect(proxy.addr).unwrap();
+    sock.write_all(b"anyone there?").unwrap();
+    assert!(common::closed(&mut sock));
+    proxy.wait_log("nowhere.invalid");
+    let access = access_log(proxy);
+    let line = access.lines().find(|l| l.contains(" TUNNEL ")).unwrap_or_else(|| panic!("{}", access));
+    assert!(line.contains(" TUNNEL nowhere.invalid:5432 failed "), "{}", line);
+}
+
+#[test]
+fn an_upstream_restarted_on_its_port_is_reached_again() {
+    let upstream = format!("127.0.0.1:{}", common::free_port());
+    let first = Banner::new(&upstream, "first");
+    let config = format!("access_log = \"access.log\"\n[[listener]]\naddress = \"{{addr}}\"\nupstream = \"{}\"\n", upstream);
+    let proxy = Proxy::start(&config);
+    assert_eq!(greeting(&proxy), b"first");
+    drop(first);
+    // nothing there in between: the client's connection is just closed
+    assert_eq!(greeting(&proxy), b"");
+    let _second = Banner::new(&upstream, "second");
+    assert_eq!(greeting(&proxy), b"second");
+    let access = access_log(proxy);
+    // past the harness's own connection, gone whenever its dial was
+    let outcomes: Vec<_> = access.lines().skip(1).filter_map(|l| l.split(' ').nth(4)).collect();
+    assert_eq!(outcomes, ["established", "failed", "established"], "{}", access);
+}
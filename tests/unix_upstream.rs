@@ -0,0 +1,68 @@
+//! Direct routes with a `unix_socket`: their destinations are the local
+//! daemon on it.
+
+mod common;
+
+use std::{
+    io::{Read, Write},
+    os::unix::net::UnixListener,
+    path::Path,
+    thread,
+};
+
+use common::{echo_through, Proxy, Scratch};
+
+/// A proxy routing internal.service:80 to the unix socket at `path`.
+fn routed_to(path: &Path) -> Proxy {
+    Proxy::start(&format!(
+        "[[route]]\nhosts = [\"internal.service:80\"]\naction = \"direct\"\nunix_socket = \"{}\"\n",
+        path.display()
+    ))
+}
+
+/// An echo server on the unix socket at `path`.
+fn unix_echo(path: &Path) {
+    let listener = UnixListener::bind(path).unwrap();
+    thread::spawn(move || {
+        for sock in listener.incoming().flatten() {
+            thread::spawn(move || {
+                let (mut from, mut to) = (&sock, &sock);
+                let _ = std::io::copy(&mut from, &mut to);
+            });
+        }
+    });
+}
+
+#[test]
+fn a_routed_name_reaches_the_unix_socket() {
+    let dir = Scratch::new();
+    unix_echo(&dir.path("echo.sock"));
+    let proxy = routed_to(&dir.path("echo.sock"));
+    let mut sock = proxy.tunnel("internal.service:80");
+    assert_eq!(echo_through(&mut sock, b"local"), b"local");
+    // past what any buffer on the way holds, written while read
+    let data: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let mut writer = sock.try_clone().unwrap();
+    let sent = data.clone();
+    let writing = thread::spawn(move || writer.write_all(&sent).unwrap());
+    let mut back = vec![0u8; data.len()];
+    sock.read_exact(&mut back).unwrap();
+    writing.join().unwrap();
+    assert!(back == data);
+    // another port of the name is not the route's
+    let (status, _) = proxy.connect("internal.service:81");
+    assert!(!status.starts_with("HTTP/1.1 200"), "{}", status);
+}
+
+#[test]
+fn a_missing_socket_answers_502() {
+    let dir = Scratch::new();
+    let proxy = routed_to(&dir.path("none.sock"));
+    let (status, _) = proxy.connect("internal.service:80");
+    assert!(status.starts_with("HTTP/1.1 502"), "{}\n{}", status, proxy.log());
+    // and so does a socket file nothing listens on any more
+    drop(UnixListener::bind(dir.path("gone.sock")).unwrap());
+    let proxy = routed_to(&dir.path("gone.sock"));
+    let (status, _) = proxy.connect("internal.service:80");
+    assert!(status.starts_with("HTTP/1.1 502"), "{}\n{}", status, proxy.log());
+}
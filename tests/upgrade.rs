@@ -0,0 +1,61 @@
+//! SIGUSR2 upgrades: the listeners go to a successor process while the
+//! old one drains the tunnels it has.
+
+mod common;
+
+use std::{
+    process::Command,
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{echo_server, echo_through, Proxy, WAIT};
+
+/// The successor an upgrade started, killed with the test.
+struct Successor(u32);
+
+impl Drop for Successor {
+    fn drop(&mut self) {
+        let _ = Command::new("kill").args(["-s", "KILL", &self.0.to_string()]).status();
+    }
+}
+
+fn successor(proxy: &Proxy) -> Successor {
+    proxy.wait_log("took over, draining");
+    let log = proxy.log();
+    let line = log.lines().find(|l| l.contains("took over, draining")).unwrap();
+    let pid = line.split("successor pid ").nth(1).and_then(|rest| rest.split(' ').next()).unwrap();
+    Successor(pid.parse().unwrap())
+}
+
+#[test]
+fn a_tunnel_outlives_an_upgrade() {
+    let mut proxy = Proxy::start("");
+    let echo = echo_server();
+    let mut long = proxy.tunnel(&echo.to_string());
+    assert_eq!(echo_through(&mut long, b"before"), b"before");
+    proxy.signal("USR2");
+    let successor = successor(&proxy);
+    assert_ne!(successor.0, proxy.child.id());
+    proxy.wait_log("inherited listener fd");
+
+    // new clients get the successor, the old process keeps what it has
+    let mut fresh = proxy.tunnel(&echo.to_string());
+    assert_eq!(echo_through(&mut fresh, b"fresh"), b"fresh");
+    assert_eq!(echo_through(&mut long, b"across"), b"across");
+
+    // it exits once its last tunnel is gone, well before drain_timeout
+    drop(long);
+    let deadline = Instant::now() + WAIT;
+    let status = loop {
+        if let Some(status) = proxy.child.try_wait().unwrap() {
+            break status;
+        }
+        assert!(Instant::now() < deadline, "old process still up:\n{}", proxy.log());
+        thread::sleep(Duration::from_millis(20));
+    };
+    assert!(status.success(), "{}", status);
+    assert_eq!(echo_through(&mut fresh, b"after"), b"after");
+    let mut later = proxy.tunnel(&echo.to_string());
+    assert_eq!(echo_through(&mut later, b"later"), b"later");
+}
@@ -0,0 +1,91 @@
+//! Websocket listeners: tunnels whose client speaks binary frames after
+//! an upgrade.
+
+mod common;
+
+use std::{
+    fs,
+    io::{Read, Write},
+    net::TcpStream,
+};
+
+use common::{echo_server, Proxy, WAIT};
+
+const MASK: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+
+/// A client frame, masked, with FIN as `fin` says; short payloads only.
+fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut out = vec![if fin { 0x80 } else { 0 } | opcode, 0x80 | payload.len() as u8];
+    out.extend_from_slice(&MASK);
+    out.extend(payload.iter().enumerate().map(|(i, b)| b ^ MASK[i % 4]));
+    out
+}
+
+/// The next frame from the proxy: opcode and payload.
+fn next_frame(sock: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut h = [0u8; 2];
+    sock.read_exact(&mut h).unwrap();
+    let len = match h[1] {
+        126 => {
+            let mut len = [0u8; 2];
+            sock.read_exact(&mut len).unwrap();
+            u16::from_be_bytes(len) as usize
+        }
+        n => n as usize,
+    };
+    let mut payload = vec![0u8; len];
+    sock.read_exact(&mut payload).unwrap();
+    (h[0] & 0x0f, payload)
+}
+
+/// Upgrades a connection to `proxy` for a tunnel to `target`: the
+/// response head and the socket.
+fn upgrade(proxy: &Proxy, target: &str) -> (String, TcpStream) {
+    let mut sock = TcpStream::connect(proxy.addr).unwrap();
+    sock.set_read_timeout(Some(WAIT)).unwrap();
+    let request = format!(
+        "GET /{} HTTP/1.1\r\nHost: proxy\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        target
+    );
+    sock.write_all(request.as_bytes()).unwrap();
+    (common::read_head(&mut sock).unwrap(), sock)
+}
+
+#[test]
+fn a_tunnel_through_a_websocket_listener() {
+    let mut proxy =
+        Proxy::start("access_log = \"access.log\"\n[[listener]]\naddress = \"{addr}\"\nprotocol = \"websocket\"\n");
+    let echo = echo_server();
+    let (head, mut sock) = upgrade(&proxy, &echo.to_string());
+    assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+    assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"), "{}", head);
+    // a message in fragments, a ping between them
+    sock.write_all(&frame(false, 0x2, b"over ")).unwrap();
+    sock.write_all(&frame(true, 0x9, b"ping")).unwrap();
+    sock.write_all(&frame(true, 0x0, b"websocket")).unwrap();
+    // the pong and the echo, in whatever order they cross
+    let (mut echoed, mut pongs) = (Vec::new(), Vec::new());
+    while echoed.len() < 14 || pongs.is_empty() {
+        match next_frame(&mut sock) {
+            (0x2, payload) => echoed.extend(payload),
+            (0xa, payload) => pongs.push(payload),
+            other => panic!("{:?}", other),
+        }
+    }
+    assert_eq!(echoed, b"over websocket");
+    assert_eq!(pongs, [b"ping"]);
+    // a clean close is echoed and the connection ends
+    sock.write_all(&frame(true, 0x8, &1000u16.to_be_bytes())).unwrap();
+    assert_eq!(next_frame(&mut sock), (0x8, 1000u16.to_be_bytes().to_vec()));
+    assert!(common::closed(&mut sock));
+    // a destination that is not there is answered before any upgrade
+    let closed = common::free_port();
+    let (head, _) = upgrade(&proxy, &format!("127.0.0.1:{}", closed));
+    assert!(head.starts_with("HTTP/1.1 502"), "{}", head);
+    proxy.signal("TERM");
+    proxy.child.wait().unwrap();
+    let access = fs::read_to_string(proxy.dir.path("access.log")).unwrap();
+    let line = format!(" WEBSOCKET {} established 14 14 ", echo);
+    assert!(access.contains(&line), "no {:?} in\n{}", line, access);
+}